@@ -1,8 +1,57 @@
 import { NextRequest, NextResponse } from 'next/server';
+import { readFileSync } from 'fs';
+import https from 'https';
 
 const FORTRESS_URL = process.env.FORTRESS_API_URL || 'http://127.0.0.1:9090';
 const FORTRESS_KEY = process.env.FORTRESS_API_KEY || '';
 
+// When the admin API serves HTTPS with its own CA and/or requires client
+// certificates (admin_api.tls_cert / client_ca), point these at PEM files.
+const FORTRESS_CA = process.env.FORTRESS_API_CA;
+const FORTRESS_CLIENT_CERT = process.env.FORTRESS_CLIENT_CERT;
+const FORTRESS_CLIENT_KEY = process.env.FORTRESS_CLIENT_KEY;
+
+const tlsAgent =
+  FORTRESS_CA || FORTRESS_CLIENT_CERT
+    ? new https.Agent({
+        ca: FORTRESS_CA ? readFileSync(FORTRESS_CA) : undefined,
+        cert: FORTRESS_CLIENT_CERT ? readFileSync(FORTRESS_CLIENT_CERT) : undefined,
+        key: FORTRESS_CLIENT_KEY ? readFileSync(FORTRESS_CLIENT_KEY) : undefined,
+        keepAlive: true,
+      })
+    : null;
+
+interface UpstreamResponse {
+  status: number;
+  contentType: string | null;
+  body: string;
+}
+
+async function send(url: URL, method: string, headers: Record<string, string>, body?: string): Promise<UpstreamResponse> {
+  if (!tlsAgent || url.protocol !== 'https:') {
+    const res = await fetch(url.toString(), { method, headers, body });
+    return { status: res.status, contentType: res.headers.get('Content-Type'), body: await res.text() };
+  }
+  // fetch() cannot take a custom CA or client certificate, so use https directly.
+  return new Promise((resolve, reject) => {
+    const req = https.request(url, { method, headers, agent: tlsAgent }, (res) => {
+      const chunks: Buffer[] = [];
+      res.on('data', (chunk: Buffer) => chunks.push(chunk));
+      res.on('end', () =>
+        resolve({
+          status: res.statusCode || 502,
+          contentType: (res.headers['content-type'] as string | undefined) ?? null,
+          body: Buffer.concat(chunks).toString('utf8'),
+        })
+      );
+      res.on('error', reject);
+    });
+    req.on('error', reject);
+    if (body) req.write(body);
+    req.end();
+  });
+}
+
 async function proxyRequest(req: NextRequest, params: Promise<{ path: string[] }>) {
   const { path } = await params;
   const fortressPath = '/api/fortress/' + path.join('/');
@@ -18,28 +67,22 @@ async function proxyRequest(req: NextRequest, params: Promise<{ path: string[] }
     'Content-Type': 'application/json',
   };
 
-  const fetchOptions: RequestInit = {
-    method: req.method,
-    headers,
-  };
-
+  let body: string | undefined;
   if (req.method !== 'GET' && req.method !== 'HEAD') {
     try {
-      const body = await req.text();
-      if (body) fetchOptions.body = body;
+      body = (await req.text()) || undefined;
     } catch {
       // no body
     }
   }
 
   try {
-    const res = await fetch(url.toString(), fetchOptions);
-    const data = await res.text();
+    const res = await send(url, req.method, headers, body);
 
-    return new NextResponse(data, {
+    return new NextResponse(res.body, {
       status: res.status,
       headers: {
-        'Content-Type': res.headers.get('Content-Type') || 'application/json',
+        'Content-Type': res.contentType || 'application/json',
       },
     });
   } catch (err) {
@@ -2,7 +2,7 @@
 
 import { useCallback, useEffect, useState } from 'react';
 import { fortressGet, fortressPost, fortressDelete } from '@/lib/api';
-import type { BlockedIp, BlockedAsn, BlockedCountry } from '@/lib/types';
+import type { BlockedIp, BlockedAsn, BlockedCountry, BlockedJa3 } from '@/lib/types';
 import {
   Shield,
   Plus,
@@ -14,10 +14,11 @@ import {
   Save,
   AlertTriangle,
   Ban,
+  Fingerprint,
 } from 'lucide-react';
 import { CountryFlag } from '@/components/country-flag';
 
-type Tab = 'ip' | 'asn' | 'country';
+type Tab = 'ip' | 'asn' | 'country' | 'ja3';
 
 export default function BlocklistPage() {
   const [activeTab, setActiveTab] = useState<Tab>('ip');
@@ -40,6 +41,12 @@ export default function BlocklistPage() {
   const [showCountryForm, setShowCountryForm] = useState(false);
   const [countryForm, setCountryForm] = useState({ code: '', reason: '' });
 
+  // --------------- JA3 state ---------------
+  const [ja3s, setJa3s] = useState<BlockedJa3[]>([]);
+  const [ja3Loading, setJa3Loading] = useState(false);
+  const [showJa3Form, setShowJa3Form] = useState(false);
+  const [ja3Form, setJa3Form] = useState({ hash: '', action: 'block', reason: '', ttl: '' });
+
   // --------------- Fetchers ---------------
   const fetchIps = useCallback(async () => {
     setIpLoading(true);
@@ -77,11 +84,27 @@ export default function BlocklistPage() {
     }
   }, []);
 
+  const fetchJa3s = useCallback(async () => {
+    setJa3Loading(true);
+    try {
+      const [blocked, allowed] = await Promise.all([
+        fortressGet<{ entries: BlockedJa3[] }>('/api/fortress/blocklist?type=ja3'),
+        fortressGet<{ entries: BlockedJa3[] }>('/api/fortress/blocklist?type=ja3&mode=allow'),
+      ]);
+      setJa3s([...blocked.entries, ...allowed.entries]);
+    } catch (err) {
+      console.error('Failed to fetch JA3 rules', err);
+    } finally {
+      setJa3Loading(false);
+    }
+  }, []);
+
   useEffect(() => {
     if (activeTab === 'ip') fetchIps();
     else if (activeTab === 'asn') fetchAsns();
+    else if (activeTab === 'ja3') fetchJa3s();
     else fetchCountries();
-  }, [activeTab, fetchIps, fetchAsns, fetchCountries]);
+  }, [activeTab, fetchIps, fetchAsns, fetchCountries, fetchJa3s]);
 
   // --------------- Add handlers ---------------
   const addIp = async () => {
@@ -133,6 +156,25 @@ export default function BlocklistPage() {
     }
   };
 
+  const addJa3 = async () => {
+    if (!ja3Form.hash) return;
+    try {
+      await fortressPost('/api/fortress/blocklist', {
+        value: ja3Form.hash,
+        type: 'ja3',
+        mode: ja3Form.action === 'allow' ? 'allow' : 'block',
+        action: ja3Form.action === 'allow' ? undefined : ja3Form.action,
+        reason: ja3Form.reason,
+        ttl_secs: ja3Form.ttl ? Number(ja3Form.ttl) : undefined,
+      });
+      setJa3Form({ hash: '', action: 'block', reason: '', ttl: '' });
+      setShowJa3Form(false);
+      fetchJa3s();
+    } catch (err) {
+      console.error('Failed to add JA3 rule', err);
+    }
+  };
+
   // --------------- Delete handlers ---------------
   const deleteIp = async (id: number) => {
     if (!confirm('Remove this IP from the blocklist?')) return;
@@ -164,6 +206,16 @@ export default function BlocklistPage() {
     }
   };
 
+  const deleteJa3 = async (id: number) => {
+    if (!confirm('Remove this JA3 rule?')) return;
+    try {
+      await fortressDelete('/api/fortress/blocklist/' + id + '?type=ja3');
+      fetchJa3s();
+    } catch (err) {
+      console.error('Failed to remove JA3 rule', err);
+    }
+  };
+
   // --------------- Shared styles ---------------
   const tabClass = (tab: Tab) => {
     const isActive = activeTab === tab;
@@ -460,9 +512,19 @@ export default function BlocklistPage() {
                   </td>
                   <td className={tdClass}>
                     {entry.reason ?? <span className="text-zinc-600">--</span>}
+                    {entry.source === 'auto_escalation' && (
+                      <span className="ml-2 inline-block bg-zinc-800 border border-zinc-700 text-zinc-400 rounded-md px-2 py-0.5 text-xs font-medium">
+                        auto
+                      </span>
+                    )}
                   </td>
                   <td className={tdClass}>
                     <span className="tabular-nums">{formatDate(entry.created_at)}</span>
+                    {entry.expires_at && (
+                      <span className="block text-xs text-zinc-500 tabular-nums">
+                        until {formatDate(entry.expires_at)}
+                      </span>
+                    )}
                   </td>
                   <td className={tdClass}>
                     <button className={dangerBtn} onClick={() => deleteAsn(entry.id)}>
@@ -582,9 +644,19 @@ export default function BlocklistPage() {
                   </td>
                   <td className={tdClass}>
                     {entry.reason ?? <span className="text-zinc-600">--</span>}
+                    {entry.source === 'auto_escalation' && (
+                      <span className="ml-2 inline-block bg-zinc-800 border border-zinc-700 text-zinc-400 rounded-md px-2 py-0.5 text-xs font-medium">
+                        auto
+                      </span>
+                    )}
                   </td>
                   <td className={tdClass}>
                     <span className="tabular-nums">{formatDate(entry.created_at)}</span>
+                    {entry.expires_at && (
+                      <span className="block text-xs text-zinc-500 tabular-nums">
+                        until {formatDate(entry.expires_at)}
+                      </span>
+                    )}
                   </td>
                   <td className={tdClass}>
                     <button className={dangerBtn} onClick={() => deleteCountry(entry.id)}>
@@ -601,6 +673,154 @@ export default function BlocklistPage() {
     </>
   );
 
+  // --------------- JA3 Tab ---------------
+  const renderJa3Tab = () => (
+    <>
+      <div className="flex items-center justify-between mb-5">
+        <div>
+          <h2 className="text-lg font-semibold text-zinc-100">TLS Fingerprint Rules (JA3)</h2>
+          <p className="text-xs text-zinc-500 mt-0.5">
+            Block or challenge TLS client fingerprints, or allowlist one so it is never challenged
+          </p>
+        </div>
+        <button
+          className={showJa3Form ? secondaryBtn : primaryBtn}
+          onClick={() => setShowJa3Form((v) => !v)}
+        >
+          {showJa3Form ? (
+            <>
+              <X className="h-3.5 w-3.5" />
+              Cancel
+            </>
+          ) : (
+            <>
+              <Plus className="h-3.5 w-3.5" />
+              Add Rule
+            </>
+          )}
+        </button>
+      </div>
+
+      {showJa3Form && (
+        <div className="rounded-xl border border-zinc-700 bg-zinc-900/50 p-5 mb-5 space-y-4">
+          <div className="grid grid-cols-1 gap-4 sm:grid-cols-4">
+            <div>
+              <label className="block text-xs font-medium text-zinc-400 mb-1.5">
+                JA3 Hash
+              </label>
+              <input
+                type="text"
+                className={inputClass}
+                placeholder="e7d705a3286e19ea42f587b344ee6865"
+                value={ja3Form.hash}
+                onChange={(e) => setJa3Form({ ...ja3Form, hash: e.target.value })}
+              />
+            </div>
+            <div>
+              <label className="block text-xs font-medium text-zinc-400 mb-1.5">
+                Action
+              </label>
+              <select
+                className={inputClass}
+                value={ja3Form.action}
+                onChange={(e) => setJa3Form({ ...ja3Form, action: e.target.value })}
+              >
+                <option value="block">Block</option>
+                <option value="challenge">Challenge</option>
+                <option value="allow">Allow (never challenge)</option>
+              </select>
+            </div>
+            <div>
+              <label className="block text-xs font-medium text-zinc-400 mb-1.5">
+                Reason
+              </label>
+              <input
+                type="text"
+                className={inputClass}
+                placeholder="Headless scraper"
+                value={ja3Form.reason}
+                onChange={(e) => setJa3Form({ ...ja3Form, reason: e.target.value })}
+              />
+            </div>
+            <div>
+              <label className="block text-xs font-medium text-zinc-400 mb-1.5">
+                TTL (seconds)
+                <span className="ml-1 text-zinc-600 font-normal">optional</span>
+              </label>
+              <input
+                type="number"
+                className={inputClass}
+                placeholder="3600 (1 hour)"
+                value={ja3Form.ttl}
+                onChange={(e) => setJa3Form({ ...ja3Form, ttl: e.target.value })}
+              />
+            </div>
+          </div>
+          <div className="flex justify-end">
+            <button className={primaryBtn} onClick={addJa3}>
+              <Save className="h-3.5 w-3.5" />
+              Save Rule
+            </button>
+          </div>
+        </div>
+      )}
+
+      {ja3Loading ? (
+        renderLoading()
+      ) : ja3s.length === 0 ? (
+        renderEmpty('JA3')
+      ) : (
+        <div className="overflow-x-auto">
+          <table className="w-full">
+            <thead className="border-b border-zinc-800">
+              <tr>
+                <th className={thClass}>JA3</th>
+                <th className={thClass}>Action</th>
+                <th className={thClass}>Reason</th>
+                <th className={thClass}>Created</th>
+                <th className={thClass}>Expires</th>
+                <th className={thClass}>Actions</th>
+              </tr>
+            </thead>
+            <tbody className="divide-y divide-zinc-800/50">
+              {ja3s.map((entry) => (
+                <tr key={entry.id} className="hover:bg-zinc-800/30 transition-colors">
+                  <td className={tdClass}>
+                    <span className="font-mono font-medium text-zinc-200">{entry.ja3}</span>
+                  </td>
+                  <td className={tdClass}>
+                    <span className="inline-block bg-zinc-800 border border-zinc-700 text-zinc-400 rounded-md px-2 py-0.5 text-xs font-medium uppercase">
+                      {entry.action}
+                    </span>
+                  </td>
+                  <td className={tdClass}>
+                    {entry.reason ?? <span className="text-zinc-600">--</span>}
+                  </td>
+                  <td className={tdClass}>
+                    <span className="tabular-nums">{formatDate(entry.created_at)}</span>
+                  </td>
+                  <td className={tdClass}>
+                    {entry.expires_at ? (
+                      <span className="tabular-nums">{formatDate(entry.expires_at)}</span>
+                    ) : (
+                      <span className="text-zinc-600">Never</span>
+                    )}
+                  </td>
+                  <td className={tdClass}>
+                    <button className={dangerBtn} onClick={() => deleteJa3(entry.id)}>
+                      <Trash2 className="h-3 w-3" />
+                      Remove
+                    </button>
+                  </td>
+                </tr>
+              ))}
+            </tbody>
+          </table>
+        </div>
+      )}
+    </>
+  );
+
   // --------------- Tab config ---------------
   const tabConfig: { key: Tab; label: string; icon: React.ReactNode; count: number }[] = [
     {
@@ -621,6 +841,12 @@ export default function BlocklistPage() {
       icon: <Globe className="h-3.5 w-3.5" />,
       count: countries.length,
     },
+    {
+      key: 'ja3',
+      label: 'TLS Fingerprints (JA3)',
+      icon: <Fingerprint className="h-3.5 w-3.5" />,
+      count: ja3s.length,
+    },
   ];
 
   // --------------- Main render ---------------
@@ -633,7 +859,7 @@ export default function BlocklistPage() {
           <h1 className="text-2xl font-bold text-zinc-100">Blocklist Engine</h1>
         </div>
         <p className="mt-1 text-sm text-zinc-500 ml-9">
-          IP, ASN, and geographic access control
+          IP, ASN, TLS fingerprint and geographic access control
         </p>
       </div>
 
@@ -642,7 +868,7 @@ export default function BlocklistPage() {
         <AlertTriangle className="h-4 w-4 text-yellow-500 mt-0.5 shrink-0" />
         <p className="text-xs text-zinc-500 leading-relaxed">
           Rules take effect immediately across all edge nodes. IP and CIDR blocks support optional
-          TTL for automatic expiration. ASN and country restrictions are permanent until manually removed.
+          TTL for automatic expiration, as do JA3 rules. ASN and country restrictions are permanent until manually removed.
         </p>
       </div>
 
@@ -670,6 +896,7 @@ export default function BlocklistPage() {
         {activeTab === 'ip' && renderIpTab()}
         {activeTab === 'asn' && renderAsnTab()}
         {activeTab === 'country' && renderCountryTab()}
+        {activeTab === 'ja3' && renderJa3Tab()}
       </div>
     </div>
   );
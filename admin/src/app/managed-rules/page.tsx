@@ -23,6 +23,27 @@ interface ManagedRule {
   name: string;
   description: string;
   enabled: boolean;
+  overridden?: boolean;
+  /** Effective parameters; set with `PUT { params }` */
+  params?: ManagedRuleParams;
+}
+
+interface ManagedRuleParams {
+  limit?: number;
+  window_secs?: number;
+  max_bytes?: number;
+  paths?: string[];
+  score?: number;
+}
+
+function formatParams(params?: ManagedRuleParams): string {
+  if (!params) return "";
+  const parts: string[] = [];
+  if (params.limit !== undefined) parts.push(`${params.limit}/${params.window_secs ?? 60}s`);
+  if (params.max_bytes !== undefined) parts.push(`max ${params.max_bytes} bytes`);
+  if (params.score !== undefined) parts.push(`+${params.score}`);
+  if (params.paths?.length) parts.push(params.paths.join(", "));
+  return parts.join(" · ");
 }
 
 // --------------- Constants ---------------
@@ -439,9 +460,17 @@ export default function ManagedRulesPage() {
                           >
                             {ACTION_LABELS[action]}
                           </span>
+                          {rule.overridden && (
+                            <span className="inline-flex items-center rounded-md px-2 py-0.5 text-[11px] font-medium bg-blue-600/20 text-blue-400">
+                              Overridden by custom rule
+                            </span>
+                          )}
                         </div>
                         <p className="text-xs text-zinc-500 mt-0.5 truncate">
                           {rule.description}
+                          {formatParams(rule.params) && (
+                            <span className="ml-1 text-zinc-600">({formatParams(rule.params)})</span>
+                          )}
                         </p>
                       </div>
                     </div>
@@ -2,7 +2,7 @@
 
 import { useCallback, useEffect, useState } from 'react';
 import { fortressGet, fortressPost, fortressPut, fortressDelete } from '@/lib/api';
-import type { ProtectionRule } from '@/lib/types';
+import type { EntrySchedule, ProtectionRule, RuleMatches } from '@/lib/types';
 import {
   ScrollText,
   Plus,
@@ -11,9 +11,10 @@ import {
   Trash2,
   Save,
   Search,
+  Activity,
 } from 'lucide-react';
 
-const ACTION_OPTIONS = ['Pass', 'Challenge', 'Block', 'Tarpit'] as const;
+const ACTION_OPTIONS = ['Pass', 'Challenge', 'Block', 'Tarpit', 'Score'] as const;
 type Action = (typeof ACTION_OPTIONS)[number];
 
 const ACTION_COLORS: Record<string, string> = {
@@ -21,6 +22,7 @@ const ACTION_COLORS: Record<string, string> = {
   Challenge: 'bg-yellow-600/20 text-yellow-400',
   Block: 'bg-red-600/20 text-red-400',
   Tarpit: 'bg-purple-600/20 text-purple-400',
+  Score: 'bg-blue-600/20 text-blue-400',
 };
 
 interface RuleFormState {
@@ -28,6 +30,7 @@ interface RuleFormState {
   priority: string;
   conditions: string;
   action: Action;
+  logOnly: boolean;
 }
 
 const emptyForm: RuleFormState = {
@@ -35,8 +38,16 @@ const emptyForm: RuleFormState = {
   priority: '0',
   conditions: '{}',
   action: 'Block',
+  logOnly: false,
 };
 
+/** Updates replace the whole rule, so edits carry the schedule over. */
+const scheduleOf = (rule: ProtectionRule): EntrySchedule => ({
+  active_from: rule.active_from,
+  active_to: rule.active_to,
+  active_days: rule.active_days,
+});
+
 export default function RulesPage() {
   const [rules, setRules] = useState<ProtectionRule[]>([]);
   const [loading, setLoading] = useState(false);
@@ -51,6 +62,9 @@ export default function RulesPage() {
   const [editForm, setEditForm] = useState<RuleFormState>({ ...emptyForm });
   const [editError, setEditError] = useState('');
 
+  // Match log
+  const [matchesFor, setMatchesFor] = useState<RuleMatches | null>(null);
+
   // --------------- Styles ---------------
   const inputClass =
     'bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 w-full focus:outline-none focus:border-zinc-500 transition-colors';
@@ -103,6 +117,7 @@ export default function RulesPage() {
         condition: parsedConditions,
         action: addForm.action,
         priority: Number(addForm.priority),
+        log_only: addForm.logOnly,
       });
       setAddForm({ ...emptyForm });
       setShowAddForm(false);
@@ -128,6 +143,7 @@ export default function RulesPage() {
         }
       })(),
       action: rule.action as Action,
+      logOnly: rule.log_only,
     });
   };
 
@@ -154,6 +170,8 @@ export default function RulesPage() {
         action: editForm.action,
         priority: Number(editForm.priority),
         enabled: rule.enabled,
+        log_only: editForm.logOnly,
+        ...scheduleOf(rule),
       });
       setEditingId(null);
       fetchRules();
@@ -179,6 +197,8 @@ export default function RulesPage() {
         action: rule.action,
         priority: rule.priority,
         enabled: !rule.enabled,
+        log_only: rule.log_only,
+        ...scheduleOf(rule),
       });
       fetchRules();
     } catch (err) {
@@ -186,6 +206,20 @@ export default function RulesPage() {
     }
   };
 
+  // --------------- Matches ---------------
+  const showMatches = async (id: number) => {
+    if (matchesFor?.rule_id === id) {
+      setMatchesFor(null);
+      return;
+    }
+    try {
+      const data = await fortressGet<RuleMatches>('/api/fortress/rules/' + id + '/matches');
+      setMatchesFor(data);
+    } catch (err) {
+      console.error('Failed to fetch rule matches', err);
+    }
+  };
+
   // --------------- Delete ---------------
   const deleteRule = async (id: number) => {
     if (!confirm('Are you sure you want to delete this rule? This action cannot be undone.')) return;
@@ -282,8 +316,23 @@ export default function RulesPage() {
               value={addForm.conditions}
               onChange={(e) => setAddForm({ ...addForm, conditions: e.target.value })}
             />
+            <p className="text-xs text-zinc-500 mt-1">
+              Fields: path, path_regex, query, query_regex, method, country, asn, ja3, ip, host,
+              user_agent, header, header_present, header_regex. Nest with all / any / not.
+              Rate limits: add &quot;kind&quot;: &quot;rate_limit&quot;, limit, window_secs, key (ip or
+              ip_path) and optionally overrides (managed rule 5, 6, 7 or 19). Score actions read score.
+            </p>
           </div>
 
+          <label className="flex items-center gap-2 text-sm text-zinc-300">
+            <input
+              type="checkbox"
+              checked={addForm.logOnly}
+              onChange={(e) => setAddForm({ ...addForm, logOnly: e.target.checked })}
+            />
+            Log only (dry run: record matches without applying the action)
+          </label>
+
           <div>
             <label className="block text-xs text-zinc-400 mb-1">Response Action</label>
             <select
@@ -373,6 +422,16 @@ export default function RulesPage() {
                           {editError && (
                             <p className="text-xs text-red-400 mt-1">{editError}</p>
                           )}
+                          <label className="flex items-center gap-2 text-xs text-zinc-400 mt-2">
+                            <input
+                              type="checkbox"
+                              checked={editForm.logOnly}
+                              onChange={(e) =>
+                                setEditForm({ ...editForm, logOnly: e.target.checked })
+                              }
+                            />
+                            Log only
+                          </label>
                         </td>
                         <td className={tdClass}>
                           <select
@@ -433,7 +492,14 @@ export default function RulesPage() {
                             {formatConditions(rule.conditions_json)}
                           </pre>
                         </td>
-                        <td className={tdClass}>{actionBadge(rule.action)}</td>
+                        <td className={tdClass}>
+                          {actionBadge(rule.action)}
+                          {rule.log_only && (
+                            <span className="ml-2 inline-block rounded-full px-2.5 py-0.5 text-xs font-medium bg-blue-600/20 text-blue-400">
+                              Log only
+                            </span>
+                          )}
+                        </td>
                         <td className={tdClass}>
                           <button
                             onClick={() => toggleEnabled(rule)}
@@ -455,6 +521,13 @@ export default function RulesPage() {
                               <Pencil className="h-3 w-3" />
                               Edit
                             </button>
+                            <button
+                              className="inline-flex items-center gap-1 bg-zinc-700/50 text-zinc-300 hover:bg-zinc-700 rounded-lg px-3 py-1 text-xs font-medium transition-colors"
+                              onClick={() => showMatches(rule.id)}
+                            >
+                              <Activity className="h-3 w-3" />
+                              Matches
+                            </button>
                             <button
                               className={`inline-flex items-center gap-1 ${dangerBtn}`}
                               onClick={() => deleteRule(rule.id)}
@@ -473,6 +546,51 @@ export default function RulesPage() {
           </div>
         )}
       </div>
+
+      {/* Match log */}
+      {matchesFor && (
+        <div className="rounded-xl border border-zinc-800 bg-zinc-900 p-5 space-y-3">
+          <div className="flex items-center justify-between">
+            <h2 className="text-lg font-semibold text-zinc-100">
+              Matches for rule #{matchesFor.rule_id}
+              <span className="ml-2 text-sm font-normal text-zinc-500">
+                {matchesFor.match_count.toLocaleString()} since startup
+              </span>
+            </h2>
+            <button className="text-zinc-500 hover:text-zinc-300" onClick={() => setMatchesFor(null)}>
+              <X className="h-4 w-4" />
+            </button>
+          </div>
+          {matchesFor.recent.length === 0 ? (
+            <p className="text-sm text-zinc-500">No matches recorded yet.</p>
+          ) : (
+            <table className="w-full">
+              <thead className="border-b border-zinc-800">
+                <tr>
+                  <th className={thClass}>Time</th>
+                  <th className={thClass}>IP</th>
+                  <th className={thClass}>Country</th>
+                  <th className={thClass}>Request</th>
+                  <th className={thClass}>Enforced</th>
+                </tr>
+              </thead>
+              <tbody className="divide-y divide-zinc-800/50">
+                {matchesFor.recent.map((m, i) => (
+                  <tr key={i}>
+                    <td className={tdClass}>{new Date(m.timestamp).toLocaleString()}</td>
+                    <td className={`${tdClass} font-mono`}>{m.ip}</td>
+                    <td className={tdClass}>{m.country ?? '-'}</td>
+                    <td className={`${tdClass} font-mono text-xs`}>
+                      {m.method} {m.host}{m.path}
+                    </td>
+                    <td className={tdClass}>{m.enforced ? 'Yes' : 'No'}</td>
+                  </tr>
+                ))}
+              </tbody>
+            </table>
+          )}
+        </div>
+      )}
     </div>
   );
 }
@@ -3,9 +3,9 @@
 import { useEffect, useState, useCallback } from 'react';
 import { useParams, useRouter } from 'next/navigation';
 import Link from 'next/link';
-import { Server, Zap } from 'lucide-react';
-import { fortressGet, fortressPut, fortressDelete } from '@/lib/api';
-import { ServiceConfig } from '@/lib/types';
+import { Construction, Server, Zap } from 'lucide-react';
+import { fortressGet, fortressPost, fortressPut, fortressDelete } from '@/lib/api';
+import { ExemptPath, ServiceConfig } from '@/lib/types';
 import { PROTECTION_LEVELS_LIST } from '@/lib/constants';
 
 interface ServiceFormData {
@@ -14,27 +14,92 @@ interface ServiceFormData {
   upstream_address: string;
   protection_level_override: string;
   rate_limit_multiplier: string;
+  max_requests_per_ip_10s: string;
   max_connections: string;
   connect_timeout_ms: string;
   response_timeout_ms: string;
+  upstream_tls_verify: string;
+  upstream_http2: string;
+  body_inspection: string;
+  always_online: string;
+  upstream_sni_host: string;
+  clearance_cookie_domain: string;
+  clearance_ttl_secs: string;
+  max_body_size_mb: string;
+  cors_allowed_origins: string;
+  blocked_countries: string;
+  challenged_countries: string;
+  country_exceptions: string;
+  exempt_paths: string;
+  maintenance_html_path: string;
 }
 
 function serviceToForm(service: ServiceConfig): ServiceFormData {
   return {
     name: service.name,
     domains: service.domains.join(', '),
-    upstream_address: service.upstream_address,
+    upstream_address: service.upstream_address.join(', '),
     protection_level_override:
       service.protection_level_override === null
         ? ''
         : String(service.protection_level_override),
     rate_limit_multiplier: String(service.rate_limit_multiplier),
+    max_requests_per_ip_10s:
+      service.max_requests_per_ip_10s === null
+        ? ''
+        : String(service.max_requests_per_ip_10s),
     max_connections: String(service.max_connections),
     connect_timeout_ms: String(service.connect_timeout_ms),
     response_timeout_ms: String(service.response_timeout_ms),
+    upstream_tls_verify: String(service.upstream_tls_verify),
+    upstream_http2: String(service.upstream_http2 ?? false),
+    body_inspection: String(service.body_inspection ?? false),
+    always_online: !service.always_online
+      ? 'off'
+      : service.always_online_banner
+        ? 'banner'
+        : 'on',
+    upstream_sni_host: service.upstream_sni_host ?? '',
+    clearance_cookie_domain: service.clearance_cookie_domain ?? '',
+    clearance_ttl_secs:
+      service.clearance_ttl_secs === null ? '' : String(service.clearance_ttl_secs),
+    max_body_size_mb:
+      service.max_body_size_mb == null ? '' : String(service.max_body_size_mb),
+    cors_allowed_origins: (service.cors_allowed_origins ?? []).join(', '),
+    blocked_countries: (service.blocked_countries ?? []).join(', '),
+    challenged_countries: (service.challenged_countries ?? []).join(', '),
+    country_exceptions: (service.country_exceptions ?? []).join(', '),
+    exempt_paths: (service.exempt_paths ?? []).map(formatExemptPath).join(', '),
+    maintenance_html_path: service.maintenance_html_path ?? '',
   };
 }
 
+/** Comma-separated country codes, upper-cased. */
+function countryList(value: string): string[] {
+  return value
+    .split(',')
+    .map((c) => c.trim().toUpperCase())
+    .filter(Boolean);
+}
+
+/** `/health` or, limited to some methods, `POST|PUT /webhooks/*`. */
+function formatExemptPath(entry: ExemptPath): string {
+  if (typeof entry === 'string') return entry;
+  return entry.methods.length > 0 ? `${entry.methods.join('|')} ${entry.path}` : entry.path;
+}
+
+function parseExemptPaths(value: string): ExemptPath[] {
+  return value
+    .split(',')
+    .map((p) => p.trim())
+    .filter(Boolean)
+    .map((p) => {
+      const [first, rest] = p.split(/\s+/, 2);
+      if (rest === undefined) return p;
+      return { path: rest, methods: first.split('|').map((m) => m.toUpperCase()) };
+    });
+}
+
 export default function ServiceDetailPage() {
   const params = useParams();
   const router = useRouter();
@@ -47,6 +112,7 @@ export default function ServiceDetailPage() {
   const [deleting, setDeleting] = useState(false);
   const [confirmDelete, setConfirmDelete] = useState(false);
   const [challengeToggling, setChallengeToggling] = useState(false);
+  const [maintenanceToggling, setMaintenanceToggling] = useState(false);
   const [error, setError] = useState<string | null>(null);
   const [success, setSuccess] = useState(false);
 
@@ -94,15 +160,51 @@ export default function ServiceDetailPage() {
           .split(',')
           .map((d) => d.trim())
           .filter(Boolean),
-        upstream_address: formData.upstream_address.trim(),
+        upstream_address: formData.upstream_address.split(',').map((a) => a.trim()).filter(Boolean),
         protection_level_override:
           formData.protection_level_override === ''
             ? null
             : Number(formData.protection_level_override),
         rate_limit_multiplier: Number(formData.rate_limit_multiplier),
+        max_requests_per_ip_10s:
+          formData.max_requests_per_ip_10s === ''
+            ? null
+            : Number(formData.max_requests_per_ip_10s),
         max_connections: Number(formData.max_connections),
         connect_timeout_ms: Number(formData.connect_timeout_ms),
         response_timeout_ms: Number(formData.response_timeout_ms),
+        upstream_tls_verify: formData.upstream_tls_verify === 'true',
+        upstream_http2: formData.upstream_http2 === 'true',
+        body_inspection: formData.body_inspection === 'true',
+        always_online: formData.always_online !== 'off',
+        always_online_banner: formData.always_online === 'banner',
+        upstream_sni_host: formData.upstream_sni_host.trim() || null,
+        clearance_cookie_domain: formData.clearance_cookie_domain.trim() || null,
+        clearance_ttl_secs:
+          formData.clearance_ttl_secs === ''
+            ? null
+            : Number(formData.clearance_ttl_secs),
+        max_body_size_mb:
+          formData.max_body_size_mb === ''
+            ? null
+            : Number(formData.max_body_size_mb),
+        cors_allowed_origins: formData.cors_allowed_origins
+          .split(',')
+          .map((o) => o.trim())
+          .filter(Boolean),
+        blocked_countries: countryList(formData.blocked_countries),
+        challenged_countries: countryList(formData.challenged_countries),
+        country_exceptions: countryList(formData.country_exceptions),
+        exempt_paths: parseExemptPaths(formData.exempt_paths),
+        maintenance_mode: service?.maintenance_mode ?? false,
+        maintenance_html_path: formData.maintenance_html_path.trim() || null,
+        // Header rules are edited through the API; keep them on save.
+        add_request_headers: service?.add_request_headers ?? {},
+        remove_request_headers: service?.remove_request_headers ?? [],
+        add_response_headers: service?.add_response_headers ?? {},
+        remove_response_headers: service?.remove_response_headers ?? [],
+        allowed_countries: service?.allowed_countries ?? [],
+        allowed_asns: service?.allowed_asns ?? [],
       };
 
       await fortressPut(
@@ -159,6 +261,25 @@ export default function ServiceDetailPage() {
     }
   };
 
+  const handleMaintenanceToggle = async () => {
+    if (!service) return;
+    setMaintenanceToggling(true);
+    setError(null);
+
+    try {
+      await fortressPost(`/api/fortress/services/${id}/maintenance`, {
+        enabled: !service.maintenance_mode,
+      });
+      const refreshed = await fortressGet<ServiceConfig>(`/api/fortress/services/${id}`);
+      setService(refreshed);
+      setFormData(serviceToForm(refreshed));
+    } catch (err) {
+      setError(err instanceof Error ? err.message : 'Failed to toggle maintenance mode.');
+    } finally {
+      setMaintenanceToggling(false);
+    }
+  };
+
   const getProtectionLabel = (level: number | null): string => {
     if (level === null) return 'Inherit Global DEFCON';
     const found = PROTECTION_LEVELS_LIST.find((pl) => pl.level === level);
@@ -266,7 +387,7 @@ export default function ServiceDetailPage() {
             <div>
               <span className="text-zinc-500">Origin Server</span>
               <p className="text-zinc-100 font-mono text-xs mt-0.5">
-                {service?.upstream_address}
+                {service?.upstream_address.join(', ')}
               </p>
             </div>
             <div>
@@ -281,6 +402,12 @@ export default function ServiceDetailPage() {
                 {service?.rate_limit_multiplier}x
               </p>
             </div>
+            <div>
+              <span className="text-zinc-500">Max Requests per IP (10s)</span>
+              <p className="text-zinc-100 mt-0.5">
+                {service?.max_requests_per_ip_10s ?? 'Level default'}
+              </p>
+            </div>
             <div>
               <span className="text-zinc-500">Max Concurrent Connections</span>
               <p className="text-zinc-100 mt-0.5">
@@ -349,6 +476,40 @@ export default function ServiceDetailPage() {
               </button>
             </div>
           </div>
+
+          {/* Maintenance Mode Toggle */}
+          <div className="mt-4 pt-4 border-t border-zinc-800">
+            <div className="flex items-center justify-between">
+              <div className="flex items-center gap-2">
+                <Construction className={`w-4 h-4 ${service?.maintenance_mode ? 'text-orange-400' : 'text-zinc-500'}`} />
+                <div>
+                  <span className="text-sm font-medium text-zinc-200">Maintenance Mode</span>
+                  <p className="text-xs text-zinc-500 mt-0.5">
+                    {service?.maintenance_mode
+                      ? 'Visitors get a 503 maintenance page; exempt paths still reach the origin'
+                      : 'Requests are proxied to the origin'}
+                  </p>
+                </div>
+              </div>
+              <button
+                onClick={handleMaintenanceToggle}
+                disabled={maintenanceToggling}
+                className="relative inline-flex h-6 w-11 items-center rounded-full transition-colors focus:outline-none disabled:opacity-50"
+                style={{
+                  backgroundColor: service?.maintenance_mode
+                    ? 'rgb(251 146 60)'
+                    : 'rgb(63 63 70)',
+                }}
+                title={service?.maintenance_mode ? 'Disable maintenance mode' : 'Enable maintenance mode'}
+              >
+                <span
+                  className={`inline-block h-4 w-4 transform rounded-full bg-white transition-transform ${
+                    service?.maintenance_mode ? 'translate-x-[22px]' : 'translate-x-[3px]'
+                  }`}
+                />
+              </button>
+            </div>
+          </div>
         </div>
 
         {/* Alerts */}
@@ -455,6 +616,239 @@ export default function ServiceDetailPage() {
                   />
                 </div>
 
+                {/* Max Requests per IP */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Max Requests per IP (10s)
+                  </label>
+                  <input
+                    type="number"
+                    name="max_requests_per_ip_10s"
+                    min="10"
+                    placeholder="Level default"
+                    value={formData.max_requests_per_ip_10s}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Upstream TLS verification */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Upstream TLS Verification
+                  </label>
+                  <select
+                    name="upstream_tls_verify"
+                    value={formData.upstream_tls_verify}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  >
+                    <option value="true">Verify certificate</option>
+                    <option value="false">Skip (self-signed)</option>
+                  </select>
+                </div>
+
+                {/* Upstream protocol */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Upstream Protocol
+                  </label>
+                  <select
+                    name="upstream_http2"
+                    value={formData.upstream_http2}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  >
+                    <option value="false">HTTP/1.1</option>
+                    <option value="true">HTTP/2 (h2c / ALPN)</option>
+                  </select>
+                </div>
+
+                {/* Body inspection */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Body Inspection
+                  </label>
+                  <select
+                    name="body_inspection"
+                    value={formData.body_inspection}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  >
+                    <option value="false">Off</option>
+                    <option value="true">Scan form/JSON bodies (SQLi, XSS)</option>
+                  </select>
+                </div>
+
+                {/* Always online */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Always Online
+                  </label>
+                  <select
+                    name="always_online"
+                    value={formData.always_online}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  >
+                    <option value="off">Off</option>
+                    <option value="on">Serve saved pages while the upstream is down</option>
+                    <option value="banner">Serve saved pages with a banner</option>
+                  </select>
+                </div>
+
+                {/* Upstream SNI host */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Upstream SNI Host
+                  </label>
+                  <input
+                    type="text"
+                    name="upstream_sni_host"
+                    placeholder="Upstream host"
+                    value={formData.upstream_sni_host}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Clearance cookie domain */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Clearance Cookie Domain
+                  </label>
+                  <input
+                    type="text"
+                    name="clearance_cookie_domain"
+                    placeholder="Requesting host only"
+                    value={formData.clearance_cookie_domain}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Clearance TTL */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Clearance TTL (seconds)
+                  </label>
+                  <input
+                    type="number"
+                    name="clearance_ttl_secs"
+                    min="60"
+                    placeholder="Global default"
+                    value={formData.clearance_ttl_secs}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Body size limit */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Max Body Size (MiB, 0 = unlimited)
+                  </label>
+                  <input
+                    type="number"
+                    name="max_body_size_mb"
+                    min="0"
+                    placeholder="Global default"
+                    value={formData.max_body_size_mb}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Exempt paths */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Exempt Paths (challenge and maintenance)
+                  </label>
+                  <input
+                    type="text"
+                    name="exempt_paths"
+                    placeholder="/health, POST /webhooks/*"
+                    value={formData.exempt_paths}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Maintenance page */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Maintenance Page (HTML file)
+                  </label>
+                  <input
+                    type="text"
+                    name="maintenance_html_path"
+                    placeholder="Built-in page"
+                    value={formData.maintenance_html_path}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* CORS preflight origins */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    CORS Preflight Origins
+                  </label>
+                  <input
+                    type="text"
+                    name="cors_allowed_origins"
+                    placeholder="Forward preflights upstream"
+                    value={formData.cors_allowed_origins}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Blocked Countries */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Blocked Countries
+                  </label>
+                  <input
+                    type="text"
+                    name="blocked_countries"
+                    placeholder="In addition to the global list, e.g. KP, IR"
+                    value={formData.blocked_countries}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Challenged Countries */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Challenged Countries
+                  </label>
+                  <input
+                    type="text"
+                    name="challenged_countries"
+                    placeholder="In addition to the global list"
+                    value={formData.challenged_countries}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
+                {/* Country Exceptions */}
+                <div>
+                  <label className="block text-sm font-medium text-zinc-400 mb-1">
+                    Country Exceptions
+                  </label>
+                  <input
+                    type="text"
+                    name="country_exceptions"
+                    placeholder="Never blocked or challenged by country"
+                    value={formData.country_exceptions}
+                    onChange={handleInputChange}
+                    className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
+                  />
+                </div>
+
                 {/* Max Connections */}
                 <div>
                   <label className="block text-sm font-medium text-zinc-400 mb-1">
@@ -76,7 +76,7 @@ export default function ServicesPage() {
           .split(',')
           .map((d) => d.trim())
           .filter(Boolean),
-        upstream_address: formData.upstream_address.trim(),
+        upstream_address: formData.upstream_address.split(',').map((a) => a.trim()).filter(Boolean),
         protection_level_override:
           formData.protection_level_override === ''
             ? null
@@ -212,7 +212,7 @@ export default function ServicesPage() {
                   required
                   value={formData.upstream_address}
                   onChange={handleInputChange}
-                  placeholder="http://127.0.0.1:8080"
+                  placeholder="127.0.0.1:8080, 127.0.0.1:8081"
                   className="w-full bg-zinc-800 border border-zinc-700 rounded-lg px-3 py-2 text-zinc-100 focus:ring-blue-500 focus:border-blue-500 outline-none transition-colors"
                 />
               </div>
@@ -394,7 +394,7 @@ export default function ServicesPage() {
                     {/* Upstream */}
                     <td className="px-5 py-3">
                       <span className="text-zinc-400 font-mono text-xs">
-                        {service.upstream_address}
+                        {service.upstream_address.join(', ')}
                       </span>
                     </td>
 
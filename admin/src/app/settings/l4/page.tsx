@@ -4,7 +4,7 @@ import { useEffect, useState, useCallback, useRef } from 'react';
 import { fortressGet } from '@/lib/api';
 import { L4Metrics, L4Event } from '@/lib/types';
 import { formatNumber } from '@/lib/constants';
-import { Network, ShieldCheck, ShieldOff, Anchor, Activity } from 'lucide-react';
+import { Network, ShieldCheck, ShieldOff, Anchor, Activity, Lock } from 'lucide-react';
 
 /* ---------- stat card ---------- */
 interface StatCardProps {
@@ -128,8 +128,8 @@ export default function L4Page() {
 
   const fetchEvents = useCallback(async () => {
     try {
-      const data = await fortressGet<L4Event[]>('/api/fortress/l4/events?limit=50');
-      setEvents(data);
+      const data = await fortressGet<{ events: L4Event[] }>('/api/fortress/l4/events?per_page=50');
+      setEvents(data.events);
     } catch {
       // Silently handle event fetch errors since metrics error is shown
     } finally {
@@ -192,7 +192,7 @@ export default function L4Page() {
         )}
 
         {/* ===== STAT CARDS ===== */}
-        <div className="grid grid-cols-2 lg:grid-cols-4 gap-4 mb-8">
+        <div className="grid grid-cols-2 lg:grid-cols-5 gap-4 mb-8">
           <StatCard
             label="Total Connections Processed"
             value={formatNumber(metrics?.total_allowed ?? 0)}
@@ -221,6 +221,13 @@ export default function L4Page() {
             icon={Activity}
             bg="bg-amber-500/5"
           />
+          <StatCard
+            label="TLS Handshake Failures/s"
+            value={(metrics?.handshake_failure_rate ?? 0).toFixed(2)}
+            color="text-orange-400"
+            icon={Lock}
+            bg="bg-orange-500/5"
+          />
         </div>
 
         {/* ===== EVENTS TABLE ===== */}
@@ -672,6 +672,16 @@ export default function SystemConfigurationPage() {
     }
   };
 
+  const handleReloadConfig = async () => {
+    try {
+      await fortressPost('/api/fortress/config/reload');
+      await fetchData();
+      showMessage('success', 'fortress.toml reloaded.');
+    } catch {
+      showMessage('error', 'Failed to reload fortress.toml.');
+    }
+  };
+
   const handleModuleToggle = async (configKey: string) => {
     const newValue = !moduleStates[configKey];
     setTogglingModules((prev) => new Set(prev).add(configKey));
@@ -1104,8 +1114,14 @@ export default function SystemConfigurationPage() {
             <div className="mt-2 mb-6 flex items-center gap-2">
               <ChevronRight className="w-4 h-4 text-zinc-600" />
               <p className="text-xs text-zinc-500">
-                The following parameters are loaded from <code className="text-zinc-400 bg-zinc-800/50 px-1.5 py-0.5 rounded font-mono text-[11px]">fortress.toml</code> and require a service restart to modify.
+                The following parameters are loaded from <code className="text-zinc-400 bg-zinc-800/50 px-1.5 py-0.5 rounded font-mono text-[11px]">fortress.toml</code>. Edit the file, then reload it to apply changes.
               </p>
+              <button
+                onClick={handleReloadConfig}
+                className="ml-auto text-xs font-mono text-zinc-400 hover:text-zinc-200 border border-zinc-700 rounded px-2 py-1 transition-colors"
+              >
+                Reload
+              </button>
             </div>
 
             <BunkerSection title="Threat Scoring Engine" icon={Activity} badge="READ-ONLY">
@@ -1169,13 +1185,19 @@ export default function SystemConfigurationPage() {
 
               <div className="mt-4 border-t border-zinc-800 pt-4">
                 <SettingRow label="Cookie TTL" value={`${settings.challenge.cookie_max_age_secs}s`} />
+                <SettingRow
+                  label="Unanswered Challenge Limit"
+                  value={settings.challenge.max_unanswered_challenges > 0
+                    ? `${settings.challenge.max_unanswered_challenges} / ${settings.challenge.unanswered_window_secs}s, then ${settings.challenge.challenge_flood_action}`
+                    : 'Disabled'}
+                />
               </div>
 
               {settings.challenge.exempt_paths.length > 0 && (
                 <div className="mt-4 border-t border-zinc-800 pt-4">
                   <h3 className="text-xs font-bold text-zinc-500 uppercase tracking-wider mb-3 font-mono">Challenge-Exempt Paths</h3>
                   <div className="flex flex-wrap gap-2">
-                    {settings.challenge.exempt_paths.map((p) => (
+                    {settings.challenge.exempt_paths.map((e) => typeof e === 'string' ? e : `${e.methods.join('|')} ${e.path}`.trim()).map((p) => (
                       <span key={p} className="px-2.5 py-1 rounded-md bg-zinc-800 text-zinc-300 text-xs font-mono border border-zinc-700">{p}</span>
                     ))}
                   </div>
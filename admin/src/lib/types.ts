@@ -7,12 +7,53 @@
 // Core Status & Metrics
 // ---------------------------------------------------------------------------
 
+/** A path pattern (`*` wildcards), optionally for some methods only */
+export type ExemptPath = string | { path: string; methods: string[] };
+
+export interface GeoIpDbStatus {
+  path: string | null;
+  loaded: boolean;
+  database_type: string | null;
+  build_epoch: number | null;
+}
+
+export interface GeoIpStatus {
+  active: boolean;
+  mode: "ok" | "missing_city" | "missing_asn" | "missing";
+  city: GeoIpDbStatus;
+  asn: GeoIpDbStatus;
+}
+
+export interface ServiceLevel {
+  service: string;
+  level: string;
+  value: number;
+}
+
 export interface FortressStatus {
   active_connections: number;
   protection_level: string;
+  /** Services escalated independently of the global level */
+  service_levels: ServiceLevel[];
   total_requests_today: number;
   uptime_secs: number;
   version: string;
+  geoip: GeoIpStatus;
+}
+
+export interface GeoIpCacheStats {
+  enabled: boolean;
+  entries: number;
+  hits: number;
+  misses: number;
+}
+
+/** Lookups since startup; misses are lookups that found nothing */
+export interface GeoIpCoverage {
+  country_lookups: number;
+  country_misses: number;
+  asn_lookups: number;
+  asn_misses: number;
 }
 
 export interface FortressMetrics {
@@ -25,6 +66,10 @@ export interface FortressMetrics {
   total_requests: number;
   total_blocked: number;
   uptime_secs: number;
+  geoip_cache: GeoIpCacheStats;
+  geoip_coverage: GeoIpCoverage;
+  /** Tarpitted responses currently being dripped */
+  tarpitted: number;
 }
 
 export interface SecondSnapshot {
@@ -73,7 +118,16 @@ export interface LiveTrafficEvent {
 // Blocklist Entities
 // ---------------------------------------------------------------------------
 
-export interface BlockedIp {
+/** UTC window outside which a rule or blocklist entry is dormant. */
+export interface EntrySchedule {
+  /** `HH:MM` */
+  active_from: string | null;
+  active_to: string | null;
+  /** e.g. `mon,tue,wed`; null means every day */
+  active_days: string | null;
+}
+
+export interface BlockedIp extends EntrySchedule {
   id: number;
   ip: string;
   cidr: string | null;
@@ -83,36 +137,70 @@ export interface BlockedIp {
   expires_at: string | null;
 }
 
-export interface BlockedAsn {
+export interface BlockedAsn extends EntrySchedule {
   id: number;
   asn: number;
   name: string | null;
   action: string;
   reason: string | null;
   created_at: string;
+  /** `config`, `admin_api` or `auto_escalation` */
+  source: string;
+  expires_at: string | null;
 }
 
-export interface BlockedCountry {
+export interface BlockedJa3 extends EntrySchedule {
+  id: number;
+  ja3: string;
+  /** `block`, `challenge` or `allow` */
+  action: string;
+  reason: string | null;
+  created_at: string;
+  expires_at: string | null;
+}
+
+export interface BlockedCountry extends EntrySchedule {
   id: number;
   country_code: string;
   country_name: string | null;
   action: string;
   reason: string | null;
   created_at: string;
+  source: string;
+  expires_at: string | null;
 }
 
 // ---------------------------------------------------------------------------
 // Rules & Policies
 // ---------------------------------------------------------------------------
 
-export interface ProtectionRule {
+export interface ProtectionRule extends EntrySchedule {
   id: number;
   name: string;
   priority: number;
   conditions_json: string;
   action: string;
   enabled: boolean;
+  log_only: boolean;
   created_at: string;
+  /** False while a scheduled rule is outside its window */
+  active_now: boolean;
+}
+
+export interface RuleMatch {
+  timestamp: string;
+  ip: string;
+  method: string;
+  host: string;
+  path: string;
+  country: string | null;
+  enforced: boolean;
+}
+
+export interface RuleMatches {
+  rule_id: number;
+  match_count: number;
+  recent: RuleMatch[];
 }
 
 export interface ManagedRule {
@@ -120,6 +208,10 @@ export interface ManagedRule {
   name: string;
   description: string;
   enabled: boolean;
+  /** Replaced by a custom rate-limit rule with `overrides` */
+  overridden?: boolean;
+  /** Effective `limit`, `window_secs`, `max_bytes`, `paths` and `score`, where the rule has them */
+  params?: Record<string, number | string[]>;
 }
 
 // ---------------------------------------------------------------------------
@@ -143,18 +235,91 @@ export interface Attack {
 // Service Configuration
 // ---------------------------------------------------------------------------
 
+export interface UpstreamStatus {
+  address: string;
+  healthy: boolean;
+  active_connections: number;
+}
+
+export interface UpstreamPoolStats {
+  open_connections: number;
+  connections_opened: number;
+  requests: number;
+  /** Share of requests sent on an already open connection */
+  reuse_ratio: number | null;
+  /** Seconds until HTTP/2 is tried again after the upstream failed it */
+  http1_fallback_secs: number | null;
+}
+
+export interface UpstreamHealth extends UpstreamStatus {
+  consecutive_failures: number;
+  consecutive_successes: number;
+  last_error: string | null;
+  last_check: string | null;
+  pool: UpstreamPoolStats;
+}
+
+export interface CircuitStatus {
+  state: 'closed' | 'open' | 'half_open';
+  consecutive_failures: number;
+  retry_in_secs: number | null;
+}
+
+export interface ServiceHealth {
+  service_id: string;
+  healthy: boolean;
+  last_check: string | null;
+  in_flight: number | null;
+  max_connections: number | null;
+  circuit: CircuitStatus | null;
+  upstreams: UpstreamHealth[];
+}
+
 export interface ServiceConfig {
   id: string;
   name: string;
   domains: string[];
-  upstream_address: string;
+  upstream_address: string[];
+  lb_strategy: 'round_robin' | 'least_connections';
+  upstreams?: UpstreamStatus[];
   enabled: boolean;
   protection_level_override: number | null;
   always_challenge: boolean;
   rate_limit_multiplier: number;
+  max_requests_per_ip_10s: number | null;
   max_connections: number;
   connect_timeout_ms: number;
   response_timeout_ms: number;
+  upstream_tls_verify: boolean;
+  upstream_sni_host: string | null;
+  upstream_http2: boolean;
+  add_request_headers: Record<string, string>;
+  remove_request_headers: string[];
+  add_response_headers: Record<string, string>;
+  remove_response_headers: string[];
+  allowed_countries: string[];
+  allowed_asns: number[];
+  /** Added to the global country lists for this service */
+  blocked_countries: string[];
+  challenged_countries: string[];
+  /** Countries exempt from every country block/challenge/allowlist */
+  country_exceptions: string[];
+  clearance_cookie_domain: string | null;
+  clearance_ttl_secs: number | null;
+  /** Origins whose CORS preflights Fortress answers itself */
+  cors_allowed_origins: string[];
+  /** Paths never challenged and bypassing maintenance mode */
+  exempt_paths: ExemptPath[];
+  maintenance_mode: boolean;
+  maintenance_html_path: string | null;
+  /** Run the body rules on form/JSON request bodies */
+  body_inspection: boolean;
+  /** Request body limit in MiB (0 = unlimited); null uses the global one */
+  max_body_size_mb: number | null;
+  /** Serve the last good copy of HTML pages while the upstream is down */
+  always_online: boolean;
+  /** Add the stale-page banner to those copies */
+  always_online_banner: boolean;
 }
 
 // ---------------------------------------------------------------------------
@@ -166,6 +331,12 @@ export interface L4Metrics {
   total_dropped: number;
   total_tarpitted: number;
   tracked_ips: number;
+  tracked_subnets: number;
+  handshake_failures: number;
+  handshake_timeouts: number;
+  /** Failures per second over handshake_failure_window_secs. */
+  handshake_failure_rate: number;
+  handshake_blocked_ips: number;
 }
 
 export interface L4Event {
@@ -192,6 +363,8 @@ export interface AnalyticsData {
 
 export interface MetricsHistoryResponse {
   granularity: string;
+  from: string;
+  to: string;
   data: SecondSnapshot[];
 }
 
@@ -230,23 +403,38 @@ export interface FortressSettings {
     l3_to_l4_rps: number;
   };
   challenge: {
+    /** pow, interactive, or auto (interactive at L0-L1, PoW from L2) */
+    mode: 'pow' | 'interactive' | 'auto';
     cookie_subnet_binding: boolean;
     nojs_fallback_enabled: boolean;
     pow_difficulty_l1: number;
     pow_difficulty_l2: number;
     pow_difficulty_l3: number;
     cookie_max_age_secs: number;
-    exempt_paths: string[];
+    exempt_paths: ExemptPath[];
+    max_unanswered_challenges: number;
+    unanswered_window_secs: number;
+    challenge_flood_action: string;
   };
   blocklist: {
     country_challenge_score: number;
     challenged_countries: string[];
     blocked_countries: string[];
+    allowed_countries: string[];
+    allowed_asns: number[];
+    allowlist_action: string;
+    allowlist_unknown_action: string;
   };
   protection: {
     default_level: number;
     auto_escalation: boolean;
     ipv4_subnet_mask: number;
+    exempt_cors_preflight: boolean;
+    tarpit: {
+      delay_secs: number;
+      drip_interval_ms: number;
+      max_concurrent: number;
+    };
   };
 }
 
@@ -1,17 +1,67 @@
+use std::sync::Arc;
+
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
+    http::{Method, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::storage::sqlite::SqliteStore;
 
-/// Holds the expected API key for the admin interface.
+/// Keys accepted by the admin interface.
+///
+/// `api_key` grants full access. `read_only_key`, when configured, is only
+/// accepted for `GET`/`HEAD` requests so dashboards can read metrics without
+/// being able to ban IPs or edit services.
+///
+/// With `client_cert_only`, set when clients must present a certificate
+/// signed by `admin_api.client_ca` and `api_key` is empty, a request
+/// without a key is let in as admin on the strength of its certificate.
+///
+/// State-changing requests are also written to the SQLite audit log in
+/// `audit`, so the key that made them is kept past the process logs.
+#[derive(Clone)]
 pub struct ApiKeyAuth {
     pub api_key: String,
+    pub read_only_key: Option<String>,
+    pub client_cert_only: bool,
+    pub audit: Arc<SqliteStore>,
+}
+
+/// Fingerprint of the client certificate a connection presented, inserted
+/// as a request extension on mTLS connections.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub String);
+
+/// Identity of the key a request authenticated with, in the form
+/// `admin_api:<key id>`. Inserted as a request extension so handlers can
+/// attribute audit log entries.
+#[derive(Debug, Clone)]
+pub struct AdminActor(pub String);
+
+/// Access level of the key a request authenticated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyRole {
+    Admin,
+    ReadOnly,
+}
+
+impl KeyRole {
+    fn as_str(&self) -> &'static str {
+        match self {
+            KeyRole::Admin => "admin",
+            KeyRole::ReadOnly => "read_only",
+        }
+    }
 }
 
 /// Constant-time byte comparison to prevent timing attacks on API key validation.
-fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
     }
@@ -22,23 +72,101 @@ fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
     result == 0
 }
 
-/// Axum middleware that validates requests carry a valid
-/// `X-Fortress-Key` header before forwarding them to the inner handler.
+/// Extract the presented key from `Authorization: Bearer <key>`,
+/// `X-Api-Key`, or the legacy `X-Fortress-Key` header.
+fn presented_key(req: &Request) -> Option<&str> {
+    let headers = req.headers();
+    if let Some(bearer) = headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        return Some(bearer.trim());
+    }
+    headers
+        .get("X-Api-Key")
+        .or_else(|| headers.get("X-Fortress-Key"))
+        .and_then(|v| v.to_str().ok())
+}
+
+/// Short, non-reversible identifier for a key, for audit logs.
+fn key_fingerprint(key: &str) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+    hash[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+/// Axum middleware that validates requests carry a valid admin or
+/// read-only key before forwarding them to the inner handler, and writes
+/// an audit log entry for every state-changing request.
 ///
-/// The expected key is passed via Axum `State` so it can be shared across
-/// all routes without capturing anything by value.
+/// The expected keys are passed via Axum `State` so they can be shared
+/// across all routes without capturing anything by value.
 pub async fn auth_middleware(
-    State(api_key): State<String>,
-    req: Request,
+    State(auth): State<ApiKeyAuth>,
+    mut req: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
-    let provided_key = req
-        .headers()
-        .get("X-Fortress-Key")
-        .and_then(|v| v.to_str().ok());
-
-    match provided_key {
-        Some(key) if constant_time_eq(key.as_bytes(), api_key.as_bytes()) => Ok(next.run(req).await),
-        _ => Err(StatusCode::UNAUTHORIZED),
+) -> Response {
+    let (role, key_id) = match presented_key(&req) {
+        Some(key) => {
+            // Compare against both keys so timing does not reveal which one matched.
+            let is_admin = !auth.api_key.is_empty() && constant_time_eq(key.as_bytes(), auth.api_key.as_bytes());
+            let is_read_only = auth
+                .read_only_key
+                .as_deref()
+                .is_some_and(|ro| !ro.is_empty() && constant_time_eq(key.as_bytes(), ro.as_bytes()));
+            if is_admin {
+                (KeyRole::Admin, key_fingerprint(key))
+            } else if is_read_only {
+                (KeyRole::ReadOnly, key_fingerprint(key))
+            } else {
+                return error_response(StatusCode::UNAUTHORIZED, "Invalid API key");
+            }
+        }
+        None => match req.extensions().get::<ClientCertificate>() {
+            Some(cert) if auth.client_cert_only => (KeyRole::Admin, format!("cert:{}", cert.0)),
+            _ => return error_response(StatusCode::UNAUTHORIZED, "Missing API key"),
+        },
+    };
+
+    let method = req.method().clone();
+    let is_read = method == Method::GET || method == Method::HEAD;
+    if role == KeyRole::ReadOnly && !is_read {
+        return error_response(StatusCode::FORBIDDEN, "Read-only API key");
+    }
+
+    let actor = format!("admin_api:{}", key_id);
+    req.extensions_mut().insert(AdminActor(actor.clone()));
+    let path = req.uri().path().to_string();
+    let response = next.run(req).await;
+    let status = response.status().as_u16();
+
+    if is_read {
+        debug!(
+            target: "fortress::admin_audit",
+            key_role = role.as_str(),
+            key_id = %key_id,
+            method = %method,
+            path = %path,
+            status = status,
+            "Admin API request"
+        );
+    } else {
+        info!(
+            target: "fortress::admin_audit",
+            key_role = role.as_str(),
+            key_id = %key_id,
+            method = %method,
+            path = %path,
+            status = status,
+            "Admin API request"
+        );
+        let reason = format!("{} key, status {}", role.as_str(), status);
+        auth.audit.audit(&actor, method.as_str(), "admin_api", &path, Some(&reason));
     }
+
+    response
 }
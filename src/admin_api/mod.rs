@@ -2,3 +2,5 @@ pub mod server;
 pub mod routes;
 pub mod websocket;
 pub mod auth;
+pub mod prometheus;
+pub mod tls;
@@ -0,0 +1,229 @@
+use std::fmt::Write;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::admin_api::auth::constant_time_eq;
+use crate::admin_api::routes::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct ScrapeParams {
+    pub token: Option<String>,
+}
+
+/// `GET /metrics`
+///
+/// Prometheus text exposition (format 0.0.4). Served outside the
+/// API key middleware; when `admin_api.metrics_token` is set the
+/// scraper must present it as `Authorization: Bearer <token>` or `?token=`.
+pub async fn get_prometheus_metrics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ScrapeParams>,
+) -> Response {
+    let settings = state.settings.load();
+    if let Some(expected) = settings.admin_api.metrics_token.as_deref() {
+        if !expected.is_empty() {
+            let provided = headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .or(params.token.as_deref());
+            match provided {
+                Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {}
+                _ => return StatusCode::UNAUTHORIZED.into_response(),
+            }
+        }
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        render(&state),
+    )
+        .into_response()
+}
+
+/// Render every exported metric family into a single exposition body.
+fn render(state: &AppState) -> String {
+    let mut out = String::with_capacity(4096);
+    let snapshot = state.metrics.get_snapshot();
+    let (passed, challenged, blocked) = state.metrics.action_totals();
+
+    // ---- Request counters ----
+    family(&mut out, "fortress_requests_total", "counter", "Requests processed by the protection pipeline, by action.");
+    sample(&mut out, "fortress_requests_total", &[("action", "passed")], passed as f64);
+    sample(&mut out, "fortress_requests_total", &[("action", "challenged")], challenged as f64);
+    sample(&mut out, "fortress_requests_total", &[("action", "blocked")], blocked as f64);
+
+    let bypassed = state.metrics.bypass_totals();
+    family(&mut out, "fortress_bypassed_requests_total", "counter", "Passed requests let through without inspection, by reason.");
+    for (reason, value) in [
+        ("static", bypassed.bypassed_static),
+        ("preflight", bypassed.bypassed_preflight),
+        ("whitelisted", bypassed.whitelisted),
+    ] {
+        sample(&mut out, "fortress_bypassed_requests_total", &[("reason", reason)], value as f64);
+    }
+
+    let service_counts = state.metrics.get_service_counts();
+    family(&mut out, "fortress_service_requests_total", "counter", "Requests per protected service, by action.");
+    for (service_id, counters) in &service_counts {
+        for (action, value) in [
+            ("passed", counters.passed),
+            ("challenged", counters.challenged),
+            ("blocked", counters.blocked),
+        ] {
+            sample(
+                &mut out,
+                "fortress_service_requests_total",
+                &[("service", service_id), ("action", action)],
+                value as f64,
+            );
+        }
+    }
+
+    // ---- Point-in-time gauges ----
+    gauge(&mut out, "fortress_requests_per_second", "Requests during the last completed second.", snapshot.rps);
+    gauge(&mut out, "fortress_blocked_per_second", "Blocked requests during the last completed second.", snapshot.blocked_per_sec);
+    gauge(&mut out, "fortress_challenged_per_second", "Challenged requests during the last completed second.", snapshot.challenged_per_sec);
+    gauge(&mut out, "fortress_unique_ips", "Unique client IPs seen in the current hour.", snapshot.unique_ips as f64);
+    gauge(&mut out, "fortress_avg_latency_ms", "Average request latency in the current hour (ms).", snapshot.avg_latency_ms);
+    gauge(&mut out, "fortress_uptime_seconds", "Seconds since the process started.", state.start_time.elapsed().as_secs() as f64);
+    gauge(&mut out, "fortress_protection_level", "Current global protection level (0-4).", state.escalation.level_as_u8() as f64);
+    gauge(&mut out, "fortress_active_connections", "Currently open client connections.", state.connections.active_count() as f64);
+    gauge(&mut out, "fortress_auto_bans_active", "IPs currently auto-banned.", state.auto_ban.active_ban_count() as f64);
+    gauge(&mut out, "fortress_ip_reputation_tracked", "IPs tracked by the reputation system.", state.ip_reputation.tracked_count() as f64);
+
+    // ---- GeoIP cache ----
+    let geo = state.geoip.cache_stats();
+    family(&mut out, "fortress_geoip_cache_lookups_total", "counter", "GeoIP lookups answered from the cache (hit) or the database (miss).");
+    sample(&mut out, "fortress_geoip_cache_lookups_total", &[("result", "hit")], geo.hits as f64);
+    sample(&mut out, "fortress_geoip_cache_lookups_total", &[("result", "miss")], geo.misses as f64);
+    gauge(&mut out, "fortress_geoip_cache_entries", "Entries in the GeoIP lookup cache.", geo.entries as f64);
+    let coverage = state.geoip.coverage();
+    family(&mut out, "fortress_geoip_lookups_total", "counter", "GeoIP country and ASN lookups.");
+    sample(&mut out, "fortress_geoip_lookups_total", &[("kind", "country")], coverage.country_lookups as f64);
+    sample(&mut out, "fortress_geoip_lookups_total", &[("kind", "asn")], coverage.asn_lookups as f64);
+    family(&mut out, "fortress_geoip_lookup_misses_total", "counter", "GeoIP lookups that returned nothing.");
+    sample(&mut out, "fortress_geoip_lookup_misses_total", &[("kind", "country")], coverage.country_misses as f64);
+    sample(&mut out, "fortress_geoip_lookup_misses_total", &[("kind", "asn")], coverage.asn_misses as f64);
+
+    gauge(&mut out, "fortress_tarpitted_connections", "Tarpitted responses currently being dripped.", state.tarpit.active_count() as f64);
+
+    // ---- Service health ----
+    family(&mut out, "fortress_service_healthy", "gauge", "Whether the service upstream passed its last health check.");
+    for svc in state.service_router.list_services() {
+        let healthy = state.service_router.is_healthy(&svc.id);
+        sample(
+            &mut out,
+            "fortress_service_healthy",
+            &[("service", &svc.id), ("name", &svc.name)],
+            if healthy { 1.0 } else { 0.0 },
+        );
+    }
+
+    // ---- Upstream retries ----
+    family(&mut out, "fortress_upstream_retries_total", "counter", "Upstream requests retried after a connection-level error, by upstream.");
+    for (upstream, retries) in state.upstream_clients.retry_counts() {
+        sample(&mut out, "fortress_upstream_retries_total", &[("upstream", &upstream)], retries as f64);
+    }
+
+    // ---- Encoded request bodies ----
+    let (encoded_rejected, encoded_aborted) = state.metrics.encoded_body_totals();
+    family(&mut out, "fortress_encoded_bodies_total", "counter", "Request bodies blocked for their Content-Encoding: refused, or decoding aborted.");
+    sample(&mut out, "fortress_encoded_bodies_total", &[("outcome", "rejected")], encoded_rejected as f64);
+    sample(&mut out, "fortress_encoded_bodies_total", &[("outcome", "aborted")], encoded_aborted as f64);
+
+    // ---- HTTP -> HTTPS redirects ----
+    let (http_redirects, redirect_drops) = state.metrics.http_redirect_totals();
+    family(&mut out, "fortress_http_redirects_total", "counter", "Requests answered with a redirect to HTTPS by the HTTP listener.");
+    sample(&mut out, "fortress_http_redirects_total", &[], http_redirects as f64);
+    family(&mut out, "fortress_http_redirect_drops_total", "counter", "Connections the HTTP redirect listener closed without a redirect, by reason.");
+    for (reason, n) in redirect_drops {
+        sample(&mut out, "fortress_http_redirect_drops_total", &[("reason", reason.as_str())], n as f64);
+    }
+
+    // ---- Credential stuffing ----
+    let stuffing = state.pipeline.credential_stuffing.stats();
+    family(&mut out, "fortress_login_attempts_total", "counter", "Login attempts seen by credential stuffing detection: counted, on a username under attack, or over the tracking cap.");
+    sample(&mut out, "fortress_login_attempts_total", &[("outcome", "counted")], stuffing.attempts as f64);
+    sample(&mut out, "fortress_login_attempts_total", &[("outcome", "flagged")], stuffing.flagged as f64);
+    sample(&mut out, "fortress_login_attempts_total", &[("outcome", "untracked")], stuffing.untracked as f64);
+    family(&mut out, "fortress_login_usernames_tracked", "gauge", "Usernames with login attempts in the credential stuffing window.");
+    sample(&mut out, "fortress_login_usernames_tracked", &[], stuffing.tracked_usernames as f64);
+
+    // ---- Security events ----
+    let events = state.pipeline.events.stats();
+    family(&mut out, "fortress_security_events_published_total", "counter", "Security events handed to the configured sinks.");
+    sample(&mut out, "fortress_security_events_published_total", &[], events.published as f64);
+    family(&mut out, "fortress_security_events_dropped_total", "counter", "Security events lost because a sink fell behind or the HTTP spool was full.");
+    sample(&mut out, "fortress_security_events_dropped_total", &[], events.dropped_events as f64);
+    gauge(&mut out, "fortress_security_events_spooled_bytes", "Security events waiting in the HTTP forwarder's spool, in bytes.", events.spooled_bytes as f64);
+
+    // ---- Periodic cleanup ----
+    let (_, cleanup) = state.metrics.cleanup_metrics();
+    if !cleanup.is_empty() {
+        family(&mut out, "fortress_cleanup_duration_seconds_total", "counter", "Time spent in the periodic cleanup, by component.");
+        for (name, m) in &cleanup {
+            sample(&mut out, "fortress_cleanup_duration_seconds_total", &[("component", name)], m.total_duration_ms / 1000.0);
+        }
+        family(&mut out, "fortress_cleanup_evicted_total", "counter", "Entries removed by the periodic cleanup, by component.");
+        for (name, m) in &cleanup {
+            sample(&mut out, "fortress_cleanup_evicted_total", &[("component", name)], m.evicted as f64);
+        }
+    }
+
+    // ---- L4 ----
+    if let Some(ref l4) = state.l4_tracker {
+        let m = l4.get_metrics();
+        family(&mut out, "fortress_l4_connections_total", "counter", "TCP connections evaluated by L4 protection, by action.");
+        sample(&mut out, "fortress_l4_connections_total", &[("action", "allowed")], m.total_allowed as f64);
+        sample(&mut out, "fortress_l4_connections_total", &[("action", "dropped")], m.total_dropped as f64);
+        sample(&mut out, "fortress_l4_connections_total", &[("action", "tarpitted")], m.total_tarpitted as f64);
+        gauge(&mut out, "fortress_l4_tracked_ips", "IPs with live L4 tracking state.", m.tracked_ips as f64);
+        gauge(&mut out, "fortress_l4_tracked_subnets", "Subnets with live L4 tracking state.", m.tracked_subnets as f64);
+        family(&mut out, "fortress_l4_handshake_failures_total", "counter", "Failed TLS handshakes, by kind.");
+        sample(&mut out, "fortress_l4_handshake_failures_total", &[("kind", "error")], (m.handshake_failures - m.handshake_timeouts) as f64);
+        sample(&mut out, "fortress_l4_handshake_failures_total", &[("kind", "timeout")], m.handshake_timeouts as f64);
+        gauge(&mut out, "fortress_l4_handshake_blocked_ips", "IPs refused after too many failed TLS handshakes.", m.handshake_blocked_ips as f64);
+    }
+
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    family(out, name, "gauge", help);
+    sample(out, name, &[], value);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (k, v)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", k, escape_label(v));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// Escape a label value per the exposition format (`\`, `"` and newline).
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,26 +1,62 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
 
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    body::Bytes,
+    extract::{Extension, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
     response::IntoResponse,
     Json,
 };
-use chrono::{Duration as ChronoDuration, Utc};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use crate::analytics::collector::MetricsCollector;
-use crate::models::threat::ProtectionLevel;
+use crate::admin_api::auth::{constant_time_eq, AdminActor};
+use crate::analytics::alerting::AlertManager;
+use crate::analytics::capture::{self, CaptureSpec, RequestCapture};
+use crate::analytics::challenge_stats::{ChallengeStats, HOURS_KEPT};
+use crate::analytics::collector::{MetricsCollector, ServiceMetrics};
+use crate::analytics::history;
+use crate::analytics::live_tail::{LiveTail, TailEvent, TailFilter};
+use crate::analytics::request_samples::SampleDimension;
+use crate::config::reload::ConfigReloader;
+use crate::config::service::{encode_json_column, encode_upstreams, normalize_routes, LoadBalanceStrategy, RequestEncodingPolicy, ServiceHealthCheck, ServiceRoute, WaitingRoomConfig};
+use crate::models::request::RequestContext;
+use crate::models::schedule::ScheduleFields;
+use crate::models::threat::{ProtectionLevel, ThreatAction};
+use crate::protection::api_tokens::{TokenAction, TokenSpec};
+use crate::protection::ip_policies::IpPolicy;
+use crate::protection::asn::AsnType;
+use crate::protection::challenge::{host_in_domain, ExemptPath};
+use crate::protection::custom_rules::{compile_conditions, CustomRulesEngine};
 use crate::protection::escalation::EscalationEngine;
+use crate::protection::firewall::FirewallOffload;
 use crate::protection::l4_tracker::L4Tracker;
-use crate::proxy::connection::ConnectionTracker;
+use crate::protection::managed_rules::RuleParams;
+use crate::protection::pipeline::ProtectionPipeline;
+use crate::proxy::access_log::AccessLogger;
+use crate::proxy::connection::{ConnectionTracker, BANDWIDTH_WINDOW_SECS};
+use crate::proxy::header_rules;
+use crate::proxy::health_check::HealthCheckPlan;
+use crate::proxy::response_cache::ResponseCache;
 use crate::proxy::service_router::ServiceRouter;
+use crate::proxy::tarpit::Tarpit;
+use crate::proxy::tls::{certificate_not_after, parse_certificate_upload, store_certificate, FortressCertResolver};
+use crate::proxy::upstream::UpstreamClients;
+use crate::proxy::waiting_room::{QueueMode, WaitingRoom};
 use crate::protection::geoip::GeoIpLookup;
-use crate::storage::blocklist::BlocklistManager;
+use crate::storage::blocklist::{parse_bulk_entry, parse_list_action, BlocklistManager, ALLOW};
+use crate::storage::cluster::{self, ClusterOp, ClusterSync, SyncBatch};
+use crate::storage::feeds::parse_entries;
+use crate::storage::ip_ranges::parse_ip_or_cidr;
 use crate::storage::memory::MemoryStore;
-use crate::storage::sqlite::SqliteStore;
+use crate::storage::sqlite::{
+    AsnOverrideRow, AuditFilter, BlockedIpRow, BlocklistFilter, ExpiryStatus, ImportMode, IpFilter, L4EventFilter, NewBlocklistEntry,
+    SqliteStore, StateSections, STATE_SCHEMA_VERSION, STATE_SECTIONS,
+};
 
 // ---------------------------------------------------------------------------
 // Shared application state
@@ -40,12 +76,29 @@ pub struct AppState {
     pub api_key: String,
     pub service_router: Arc<ServiceRouter>,
     pub l4_tracker: Option<Arc<L4Tracker>>,
-    pub settings: Arc<crate::config::settings::Settings>,
+    pub settings: crate::config::settings::SharedSettings,
+    pub reloader: Arc<ConfigReloader>,
     pub ip_reputation: Arc<crate::protection::ip_reputation::IpReputationManager>,
     pub auto_ban: Arc<crate::protection::auto_ban::AutoBanManager>,
     pub distributed: Arc<crate::protection::distributed::DistributedDetector>,
     pub managed_rules: Arc<crate::protection::managed_rules::ManagedRulesEngine>,
+    pub custom_rules: Arc<CustomRulesEngine>,
     pub geoip: Arc<GeoIpLookup>,
+    pub cluster: Arc<ClusterSync>,
+    pub access_log: Option<Arc<AccessLogger>>,
+    pub tarpit: Arc<Tarpit>,
+    pub alerting: Arc<AlertManager>,
+    pub pipeline: Arc<ProtectionPipeline>,
+    pub upstream_clients: Arc<UpstreamClients>,
+    pub response_cache: Arc<ResponseCache>,
+    /// `None` when HTTPS is not served.
+    pub cert_resolver: Option<Arc<FortressCertResolver>>,
+    pub request_capture: Arc<RequestCapture>,
+    pub live_tail: Arc<LiveTail>,
+    pub waiting_room: Arc<WaitingRoom>,
+    pub firewall: Arc<FirewallOffload>,
+    /// Last `GET /api/fortress/public/status` body and when it was built.
+    pub public_status: Arc<tokio::sync::Mutex<Option<(Instant, Value)>>>,
 }
 
 // ---------------------------------------------------------------------------
@@ -63,8 +116,23 @@ pub struct HistoryParams {
 pub struct BlocklistParams {
     #[serde(rename = "type")]
     pub list_type: Option<String>,
+    /// `block` (default) or `allow`; only meaningful for `asn` and `country`.
+    pub mode: Option<String>,
     pub page: Option<u64>,
     pub per_page: Option<u64>,
+    /// IP, CIDR or text prefix; only meaningful for `ip`.
+    pub ip: Option<String>,
+    pub source: Option<String>,
+    /// Substring of the reason.
+    pub reason: Option<String>,
+    /// RFC 3339 bounds on `created_at`.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// `active` or `expired`.
+    pub status: Option<String>,
+    /// Country code and ASN stored with the entry; only meaningful for `ip`.
+    pub country: Option<String>,
+    pub asn: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -73,6 +141,39 @@ pub struct BlocklistTypeParam {
     pub list_type: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    pub reason: Option<String>,
+    pub ttl_secs: Option<u64>,
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StateExportParams {
+    /// Leave out expiring and auto-ban blocklist entries.
+    #[serde(default)]
+    pub persistent_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StateImportParams {
+    #[serde(default)]
+    pub mode: ImportMode,
+}
+
+/// Body of `GET /api/fortress/export` and `POST /api/fortress/import`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateDocument {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub sections: StateSections,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AddBlocklistRequest {
     pub value: String,
@@ -80,6 +181,35 @@ pub struct AddBlocklistRequest {
     pub list_type: String,
     pub reason: Option<String>,
     pub ttl_secs: Option<u64>,
+    /// `block` (default) or `allow`. Allow entries put the country/ASN on
+    /// the allowlist; IPs can only be blocked.
+    pub mode: Option<String>,
+    /// For block entries: `block` (default), `challenge` or `tarpit`.
+    pub action: Option<String>,
+    /// Optional UTC window (`active_from`, `active_to`, `active_days`)
+    /// outside which a block entry is dormant.
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkBlocklistEntry {
+    pub value: String,
+    /// `ip` (default), `asn`, `country` or `ja3`.
+    #[serde(rename = "type")]
+    pub list_type: Option<String>,
+    pub reason: Option<String>,
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkBlocklistRequest {
+    pub entries: Vec<BulkBlocklistEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnbanParams {
+    pub subnet: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -89,6 +219,10 @@ pub struct CreateRuleRequest {
     pub action: String,
     pub priority: Option<i32>,
     pub enabled: Option<bool>,
+    pub log_only: Option<bool>,
+    /// Optional UTC window outside which the rule is skipped.
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -98,11 +232,21 @@ pub struct UpdateRuleRequest {
     pub action: Option<String>,
     pub priority: Option<i32>,
     pub enabled: Option<bool>,
+    pub log_only: Option<bool>,
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SetLevelRequest {
     pub level: String,
+    /// Set this service's own level instead of the global one.
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LevelParams {
+    pub service: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -110,6 +254,87 @@ pub struct TopParams {
     pub limit: Option<usize>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ChallengeStatsParams {
+    pub limit: Option<usize>,
+    /// Hours of the per-hour breakdown, up to 24.
+    pub hours: Option<u64>,
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct L4EventsParams {
+    /// Page size when `per_page` is not given, as before pagination.
+    pub limit: Option<u64>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+    /// Exact client IP or text prefix.
+    pub ip: Option<String>,
+    pub action: Option<String>,
+    /// Substring of the reason.
+    pub reason: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServiceMetricsParams {
+    /// Per-second snapshots to return (default 60, at most 300).
+    pub seconds: Option<usize>,
+    /// Hours of stored hourly rows to return (default 24).
+    pub hours: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct L4TopParams {
+    pub by: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SampleParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SampleTopParams {
+    /// `ip`, `country`, `asn` or `path`.
+    pub dimension: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub target_type: Option<String>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectionsParams {
+    pub ip: Option<String>,
+    /// Substring of the Host header.
+    pub host: Option<String>,
+    /// Only connections open at least this many seconds.
+    pub min_age: Option<u64>,
+    pub min_requests: Option<u64>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloseConnectionsParams {
+    pub ip: Option<String>,
+}
+
 // ---------------------------------------------------------------------------
 // Handlers
 // ---------------------------------------------------------------------------
@@ -119,88 +344,152 @@ pub async fn get_status(State(state): State<AppState>) -> Json<Value> {
     let uptime = state.start_time.elapsed().as_secs();
     let snapshot = state.metrics.get_snapshot();
     let level = state.escalation.current_level();
-
-    let level_name = match level {
-        ProtectionLevel::L0 => "Normal",
-        ProtectionLevel::L1 => "High",
-        ProtectionLevel::L2 => "UnderAttack",
-        ProtectionLevel::L3 => "Severe",
-        ProtectionLevel::L4 => "Emergency",
-    };
+    let service_levels: Vec<Value> = state
+        .escalation
+        .service_levels()
+        .into_iter()
+        .filter_map(|(id, value)| {
+            let level = ProtectionLevel::from_u8(value)?;
+            Some(json!({ "service": id, "level": level_name(level), "value": value }))
+        })
+        .collect();
+    let services = state.service_router.list_services();
+    let unhealthy: Vec<&str> = services
+        .iter()
+        .filter(|svc| !svc.upstream_address.is_empty() && !state.service_router.is_healthy(&svc.id))
+        .map(|svc| svc.id.as_str())
+        .collect();
+    let origin = state.escalation.origin_health();
 
     Json(json!({
         "version": env!("CARGO_PKG_VERSION"),
         "uptime_secs": uptime,
-        "protection_level": level_name,
+        "protection_level": level_name(level),
+        "protection_level_mode": state.escalation.level_mode().as_str(),
+        "escalation_signal": state.escalation.escalation_signal().map(|s| s.as_str()),
+        "origin": {
+            "responses": origin.responses,
+            "avg_latency_ms": origin.avg_latency_ms(),
+            "error_ratio": origin.error_ratio(),
+        },
+        "service_levels": service_levels,
         "active_connections": state.connections.active_count(),
         "total_requests_today": snapshot.total_requests,
+        "geoip": state.geoip.status(),
+        "services_health": {
+            "total": services.len(),
+            "healthy": services.len() - unhealthy.len(),
+            "unhealthy": unhealthy,
+        },
     }))
 }
 
 /// `GET /api/fortress/metrics`
 pub async fn get_metrics(State(state): State<AppState>) -> Json<Value> {
     let snapshot = state.metrics.get_snapshot();
+    let (encoded_rejected, encoded_aborted) = state.metrics.encoded_body_totals();
+    let (tail_clients, tail_lagged) = state.live_tail.stats();
+    let (http_redirects, redirect_drops) = state.metrics.http_redirect_totals();
+    let (cleanup_interval_ms, cleanup) = state.metrics.cleanup_metrics();
+    let cleanup_components: serde_json::Map<String, Value> = cleanup
+        .into_iter()
+        .map(|(name, m)| {
+            (name.to_string(), json!({
+                "runs": m.runs,
+                "cleanup_duration_ms": m.last_duration_ms,
+                "max_duration_ms": m.max_duration_ms,
+                "total_duration_ms": m.total_duration_ms,
+                "visited": m.visited,
+                "evicted": m.evicted,
+                "tracked": m.tracked,
+            }))
+        })
+        .collect();
 
     Json(json!({
         "rps": snapshot.rps,
         "blocked_per_sec": snapshot.blocked_per_sec,
         "challenged_per_sec": snapshot.challenged_per_sec,
         "passed_per_sec": snapshot.passed_per_sec,
+        "bypassed_per_sec": state.metrics.get_current_bypassed(),
         "unique_ips": snapshot.unique_ips,
         "avg_latency_ms": snapshot.avg_latency_ms,
         "total_requests": snapshot.total_requests,
         "total_blocked": snapshot.total_blocked,
+        "total_bypassed": state.metrics.bypass_totals(),
         "uptime_secs": snapshot.uptime_secs,
+        "geoip_cache": state.geoip.cache_stats(),
+        "geoip_coverage": state.geoip.coverage(),
+        "tarpitted": state.tarpit.active_count(),
+        "tracking": state.memory.tracking_stats(),
+        "upstream_retries_total": state.upstream_clients.retry_counts().iter().map(|(_, n)| n).sum::<u64>(),
+        "encoded_bodies": {
+            "rejected": encoded_rejected,
+            "aborted": encoded_aborted,
+        },
+        "http_redirect": {
+            "redirects": http_redirects,
+            "dropped": redirect_drops
+                .iter()
+                .map(|(reason, n)| (reason.as_str().to_string(), json!(n)))
+                .collect::<serde_json::Map<String, Value>>(),
+        },
+        "credential_stuffing": state.pipeline.credential_stuffing.stats(),
+        "security_events": state.pipeline.events.stats(),
+        "log_stream": {
+            "clients": tail_clients,
+            "lagged": tail_lagged,
+        },
+        "cleanup": {
+            "interval_ms": cleanup_interval_ms,
+            "components": cleanup_components,
+        },
     }))
 }
 
 /// `GET /api/fortress/metrics/history`
+///
+/// Request counts between `from` and `to` (RFC 3339; by default the last 5
+/// minutes, hour or day) in `second`, `minute` or `hour` buckets. Data
+/// older than the in-memory hour comes from the SQLite rollups and so is
+/// not available per second.
 pub async fn get_metrics_history(
     State(state): State<AppState>,
     Query(params): Query<HistoryParams>,
-) -> Json<Value> {
-    let granularity = params.granularity.as_deref().unwrap_or("second");
-
-    // Determine how many raw seconds to fetch.
-    let seconds_to_fetch: usize = match granularity {
-        "minute" => 3600, // last hour at minute granularity
-        "hour" => 3600,   // full ring
-        _ => 300,         // last 5 minutes at second granularity
+) -> impl IntoResponse {
+    let (granularity, bucket_secs, default_span) = match params.granularity.as_deref() {
+        Some("minute") => ("minute", 60, ChronoDuration::hours(1)),
+        Some("hour") => ("hour", 3600, ChronoDuration::days(1)),
+        _ => ("second", 1, ChronoDuration::minutes(5)),
     };
 
-    let history = state.metrics.get_second_history(seconds_to_fetch);
-
-    let data: Vec<Value> = match granularity {
-        "minute" => {
-            // Aggregate into 60-second buckets.
-            aggregate_snapshots(&history, 60)
-        }
-        "hour" => {
-            aggregate_snapshots(&history, 3600)
-        }
-        _ => {
-            // Raw per-second data.
-            history
-                .iter()
-                .map(|s| {
-                    json!({
-                        "timestamp": s.timestamp,
-                        "requests": s.requests,
-                        "blocked": s.blocked,
-                        "challenged": s.challenged,
-                        "passed": s.passed,
-                    })
-                })
-                .collect()
+    let (from, to) = match (parse_rfc3339(&params.from), parse_rfc3339(&params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
         }
     };
+    let to = to.unwrap_or_else(Utc::now);
+    let from = from.unwrap_or(to - default_span);
+    if from > to {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "from is after to" }))).into_response();
+    }
 
-    Json(json!({
-        "granularity": granularity,
-        "from": params.from,
-        "to": params.to,
-        "data": data,
-    }))
+    let (from_secs, to_secs) = (from.timestamp().max(0) as u64, to.timestamp().max(0) as u64);
+    match history::load_history(&state.metrics, &state.sqlite, from_secs, to_secs, bucket_secs).await {
+        Ok(data) => Json(json!({
+            "granularity": granularity,
+            "from": from.to_rfc3339(),
+            "to": to.to_rfc3339(),
+            "data": data,
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to load metrics history: {}", e) })),
+        )
+            .into_response(),
+    }
 }
 
 /// `GET /api/fortress/threats`
@@ -210,7 +499,7 @@ pub async fn get_metrics_history(
 pub async fn get_threats(State(state): State<AppState>) -> Json<Value> {
     let now = Utc::now();
     let from = now - ChronoDuration::hours(24);
-    match state.sqlite.get_attacks(from, now) {
+    match state.sqlite.get_attacks(from, now).await {
         Ok(attacks) => Json(json!({ "threats": attacks })),
         Err(e) => Json(json!({ "error": format!("Failed to load threats: {}", e) })),
     }
@@ -223,50 +512,166 @@ pub async fn get_threats(State(state): State<AppState>) -> Json<Value> {
 /// `GET /api/fortress/blocklist`
 ///
 /// Returns blocklist entries from the SQLite store based on the requested type.
+/// `mode=allow` returns the country/ASN/JA3 allowlist instead. IP, ASN and
+/// country entries come in pages (`page`, `per_page`: default 100, at most
+/// 1000), newest first, filtered by `ip` (IP, CIDR or prefix), `source`,
+/// `reason` (substring), `from`/`to` (RFC 3339, on `created_at`) and
+/// `status` (`active` or `expired`).
 pub async fn get_blocklist(
     State(state): State<AppState>,
     Query(params): Query<BlocklistParams>,
 ) -> Json<Value> {
     let list_type = params.list_type.as_deref().unwrap_or("ip");
+    let allow = match params.mode.as_deref().unwrap_or("block") {
+        "block" => false,
+        "allow" => true,
+        other => return Json(json!({ "error": format!("Unknown mode: {}", other) })),
+    };
+    let mut filter = match blocklist_filter(&params) {
+        Ok(filter) => filter,
+        Err(e) => return Json(json!({ "error": e })),
+    };
+    if list_type != "ip" && (filter.country.is_some() || filter.asn.is_some()) {
+        return Json(json!({ "error": "country and asn filters only apply to ip entries" }));
+    }
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(100).clamp(1, 1000);
+    let (limit, offset) = (per_page as usize, ((page - 1) * per_page) as usize);
+    let page_json = |entries: Value, total: u64| {
+        json!({
+            "type": list_type,
+            "mode": if allow { "allow" } else { "block" },
+            "entries": entries,
+            "total": total,
+            "page": page,
+            "per_page": per_page,
+            "pages": total.div_ceil(per_page),
+        })
+    };
 
     match list_type {
-        "ip" => match state.sqlite.get_blocked_ips() {
-            Ok(entries) => Json(json!({
-                "type": list_type,
-                "entries": entries,
-            })),
-            Err(e) => Json(json!({ "error": format!("{}", e) })),
-        },
-        "asn" => match state.sqlite.get_blocked_asns() {
-            Ok(entries) => Json(json!({
-                "type": list_type,
-                "entries": entries,
-            })),
+        "ip" => match state.sqlite.get_blocked_ips_page(&filter, limit, offset).await {
+            Ok((entries, total)) => {
+                let entries = entries.iter().map(|row| located_blocked_ip(&state.geoip, row)).collect();
+                Json(page_json(Value::Array(entries), total))
+            }
             Err(e) => Json(json!({ "error": format!("{}", e) })),
         },
-        "country" => match state.sqlite.get_blocked_countries() {
-            Ok(entries) => Json(json!({
-                "type": list_type,
-                "entries": entries,
-            })),
+        "asn" => {
+            filter.allow = Some(allow);
+            match state.sqlite.get_blocked_asns_page(&filter, limit, offset).await {
+                Ok((entries, total)) => Json(page_json(json!(entries), total)),
+                Err(e) => Json(json!({ "error": format!("{}", e) })),
+            }
+        }
+        "country" => {
+            filter.allow = Some(allow);
+            match state.sqlite.get_blocked_countries_page(&filter, limit, offset).await {
+                Ok((entries, total)) => Json(page_json(json!(entries), total)),
+                Err(e) => Json(json!({ "error": format!("{}", e) })),
+            }
+        }
+        "ja3" => match state.sqlite.get_blocked_ja3().await {
+            Ok(mut entries) => {
+                entries.retain(|e| (e.action == ALLOW) == allow);
+                Json(json!({
+                    "type": list_type,
+                    "mode": if allow { "allow" } else { "block" },
+                    "entries": entries,
+                }))
+            }
             Err(e) => Json(json!({ "error": format!("{}", e) })),
         },
         _ => Json(json!({ "error": format!("Unknown list type: {}", list_type) })),
     }
 }
 
+/// A blocked-IP row with its location: the stored country and ASN (looked
+/// up now if the row has none) and the city and AS organisation, which are
+/// not stored.
+fn located_blocked_ip(geoip: &GeoIpLookup, row: &BlockedIpRow) -> Value {
+    let mut value = json!(row);
+    if let Some(addr) = parse_ip_or_cidr(&row.ip).map(|net| net.network()) {
+        let asn = geoip.lookup_asn(addr);
+        value["country"] = json!(row.geo.country.clone().or_else(|| geoip.lookup_country(addr)));
+        value["asn"] = json!(row.geo.asn.or(asn.as_ref().map(|(number, _)| *number)));
+        value["city"] = json!(geoip.lookup_city(addr));
+        value["asn_org"] = json!(asn.map(|(_, org)| org));
+    }
+    value
+}
+
+/// Listing filters from the blocklist query string.
+fn blocklist_filter(params: &BlocklistParams) -> Result<BlocklistFilter, String> {
+    let (from, to) = (parse_rfc3339(&params.from)?, parse_rfc3339(&params.to)?);
+    let status = match params.status.as_deref() {
+        None | Some("all") => None,
+        Some("active") => Some(ExpiryStatus::Active),
+        Some("expired") => Some(ExpiryStatus::Expired),
+        Some(other) => return Err(format!("Unknown status: {}", other)),
+    };
+    Ok(BlocklistFilter {
+        ip: params.ip.as_deref().filter(|ip| !ip.is_empty()).map(IpFilter::parse),
+        source: params.source.clone().filter(|s| !s.is_empty()),
+        reason: params.reason.clone().filter(|r| !r.is_empty()),
+        from,
+        to,
+        status,
+        allow: None,
+        country: params.country.as_deref().filter(|c| !c.is_empty()).map(|c| c.trim().to_ascii_uppercase()),
+        asn: match params.asn.as_deref().map(str::trim).filter(|a| !a.is_empty()) {
+            Some(asn) => Some(
+                asn.trim_start_matches("AS")
+                    .parse()
+                    .map_err(|_| format!("Invalid ASN number: {}", asn))?,
+            ),
+            None => None,
+        },
+    })
+}
+
 /// `POST /api/fortress/blocklist`
 pub async fn add_to_blocklist(
     State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
     Json(body): Json<AddBlocklistRequest>,
 ) -> Json<Value> {
     let reason = body.reason.as_deref().unwrap_or("manual");
     let duration = body.ttl_secs.map(std::time::Duration::from_secs);
+    let allow = match body.mode.as_deref().unwrap_or("block") {
+        "block" => false,
+        "allow" if body.list_type != "ip" => true,
+        "allow" => return Json(json!({ "error": "IPs cannot be allowlisted; use the whitelist setting" })),
+        other => return Json(json!({ "error": format!("Unknown mode: {}", other) })),
+    };
+    let schedule = match body.schedule.parse() {
+        Ok(schedule) => schedule,
+        Err(e) => return Json(json!({ "error": e })),
+    };
+    if allow && schedule.is_some() {
+        return Json(json!({ "error": "Schedules only apply to block entries" }));
+    }
+    let action = match body.action.as_deref() {
+        None => ThreatAction::Block,
+        Some(name) => match parse_list_action(name) {
+            Some(action) => action,
+            None => return Json(json!({ "error": format!("Unknown action: {}", name) })),
+        },
+    };
 
     match body.list_type.as_str() {
         "ip" => {
-            match state.blocklist.add_ip(&body.value, reason, "admin_api", duration) {
-                Ok(()) => Json(json!({ "status": "added" })),
+            match state.blocklist.add_ip(&body.value, action, reason, "admin_api", &actor, duration, schedule).await {
+                Ok(value) => {
+                    state.cluster.publish(ClusterOp::Block {
+                        value,
+                        action: (action != ThreatAction::Block).then_some(action),
+                        reason: reason.to_string(),
+                        expires_at: duration.map(|d| Utc::now() + ChronoDuration::seconds(d.as_secs() as i64)),
+                        schedule: schedule.map(|s| s.fields()).unwrap_or_default(),
+                    });
+                    Json(json!({ "status": "added" }))
+                }
                 Err(e) => Json(json!({ "error": format!("{}", e) })),
             }
         }
@@ -275,21 +680,32 @@ pub async fn add_to_blocklist(
                 Ok(v) => v,
                 Err(_) => return Json(json!({ "error": "Invalid ASN number" })),
             };
-            match state.sqlite.add_blocked_asn(asn, None, "block", Some(reason)) {
-                Ok(id) => {
-                    // Reload blocklist from db to pick up the new entry
-                    let _ = state.blocklist.load_from_db();
-                    Json(json!({ "id": id, "status": "added" }))
-                }
+            let result = if allow {
+                state.blocklist.allow_asn(asn, reason, &actor).await
+            } else {
+                state.blocklist.add_asn(asn, action, reason, &actor, schedule).await
+            };
+            match result {
+                Ok(id) => Json(json!({ "id": id, "status": "added" })),
                 Err(e) => Json(json!({ "error": format!("{}", e) })),
             }
         }
         "country" => {
-            match state.sqlite.add_blocked_country(&body.value, None, "block", Some(reason)) {
-                Ok(id) => {
-                    let _ = state.blocklist.load_from_db();
-                    Json(json!({ "id": id, "status": "added" }))
-                }
+            let result = if allow {
+                let code = body.value.trim().to_ascii_uppercase();
+                state.blocklist.allow_country(&code, reason, &actor).await
+            } else {
+                state.blocklist.add_country(&body.value, action, reason, &actor, schedule).await
+            };
+            match result {
+                Ok(id) => Json(json!({ "id": id, "status": "added" })),
+                Err(e) => Json(json!({ "error": format!("{}", e) })),
+            }
+        }
+        "ja3" => {
+            let action = if allow { ALLOW.to_string() } else { action.to_string() };
+            match state.blocklist.add_ja3(&body.value, &action, reason, &actor, duration, schedule).await {
+                Ok(id) => Json(json!({ "id": id, "status": "added" })),
                 Err(e) => Json(json!({ "error": format!("{}", e) })),
             }
         }
@@ -300,14 +716,20 @@ pub async fn add_to_blocklist(
 /// `DELETE /api/fortress/blocklist/:id`
 pub async fn remove_from_blocklist(
     State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
     Path(id): Path<i64>,
     Query(params): Query<BlocklistTypeParam>,
 ) -> StatusCode {
     let list_type = params.list_type.as_deref().unwrap_or("ip");
     let result = match list_type {
-        "ip" => state.blocklist.remove_ip(id),
-        "asn" => state.blocklist.remove_asn(id),
-        "country" => state.blocklist.remove_country(id),
+        "ip" => state.blocklist.remove_ip(id, &actor).await.map(|removed| {
+            if let Some(value) = removed {
+                state.cluster.publish(ClusterOp::Unblock { value });
+            }
+        }),
+        "asn" => state.blocklist.remove_asn(id, &actor).await,
+        "country" => state.blocklist.remove_country(id, &actor).await,
+        "ja3" => state.blocklist.remove_ja3(id, &actor).await,
         _ => return StatusCode::BAD_REQUEST,
     };
     match result {
@@ -316,32 +738,301 @@ pub async fn remove_from_blocklist(
     }
 }
 
+/// Entries accepted by one `POST /api/fortress/blocklist/bulk` request.
+const MAX_BULK_ENTRIES: usize = 10_000;
+
+/// `POST /api/fortress/blocklist/bulk`
+///
+/// Blocks IPs/CIDRs, ASNs, countries and JA3 hashes given as
+/// `{"entries": [{"value", "type", "reason", "ttl_secs"}]}`. Valid entries
+/// are written in one transaction; invalid ones are reported in `results`
+/// without failing the rest of the batch.
+pub async fn bulk_add_to_blocklist(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Json(body): Json<BulkBlocklistRequest>,
+) -> impl IntoResponse {
+    if body.entries.is_empty() || body.entries.len() > MAX_BULK_ENTRIES {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Expected 1 to {} entries", MAX_BULK_ENTRIES) })),
+        );
+    }
+
+    let mut results: Vec<Value> = Vec::with_capacity(body.entries.len());
+    let mut valid: Vec<(usize, NewBlocklistEntry)> = Vec::new();
+    for (index, entry) in body.entries.iter().enumerate() {
+        let list_type = entry.list_type.as_deref().unwrap_or("ip");
+        let reason = entry.reason.as_deref().unwrap_or("manual");
+        let ttl = entry.ttl_secs.map(std::time::Duration::from_secs);
+        match parse_bulk_entry(list_type, &entry.value, reason, ttl) {
+            Ok(parsed) => {
+                valid.push((index, parsed));
+                results.push(json!({ "index": index, "value": entry.value, "type": list_type }));
+            }
+            Err(e) => results.push(json!({
+                "index": index,
+                "value": entry.value,
+                "type": list_type,
+                "status": "error",
+                "error": e,
+            })),
+        }
+    }
+
+    let entries: Vec<NewBlocklistEntry> = valid.iter().map(|(_, e)| e.clone()).collect();
+    if !entries.is_empty() {
+        let ids = match state.blocklist.add_entries(&entries, "admin_api", &actor).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": format!("{}", e) })),
+                );
+            }
+        };
+        for ((index, entry), id) in valid.iter().zip(ids) {
+            results[*index]["status"] = json!("added");
+            results[*index]["id"] = json!(id);
+            if let NewBlocklistEntry::Ip(row) = entry {
+                state.cluster.publish(ClusterOp::Block {
+                    value: row.ip.clone(),
+                    action: None,
+                    reason: row.reason.clone(),
+                    expires_at: row.expires_at,
+                    schedule: ScheduleFields::default(),
+                });
+            }
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "added": entries.len(),
+            "failed": results.len() - entries.len(),
+            "results": results,
+        })),
+    )
+}
+
+/// `POST /api/fortress/blocklist/backfill-geo`
+///
+/// Store the country and ASN of blocked IPs that were added without them,
+/// so the `country` and `asn` listing filters find them.
+pub async fn backfill_blocklist_geo(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+) -> impl IntoResponse {
+    match state.blocklist.backfill_geo(&actor).await {
+        Ok(result) => (StatusCode::OK, Json(json!(result))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))),
+    }
+}
+
+/// Request body limit for `POST /api/fortress/blocklist/import` and
+/// `POST /api/fortress/import`; 100k entries with reasons fit comfortably.
+pub const MAX_IMPORT_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+/// At most this many parse errors are echoed back from an import.
+const MAX_REPORTED_IMPORT_ERRORS: usize = 100;
+
+/// `POST /api/fortress/blocklist/import`
+///
+/// Body is newline-delimited IPs/CIDRs or CSV rows of `ip,reason,ttl_secs`.
+/// `reason`, `ttl_secs` and `source` query parameters set defaults for rows
+/// that don't carry their own. All rows are written in one transaction.
+pub async fn import_blocklist(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Query(params): Query<ImportParams>,
+    body: String,
+) -> impl IntoResponse {
+    let source = params.source.unwrap_or_else(|| "import".to_string());
+    if source.starts_with("feed:") {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Sources starting with 'feed:' are managed by the feed fetcher" })),
+        );
+    }
+
+    let parsed = parse_entries(&body);
+    let errors: Vec<&String> = parsed.errors.iter().take(MAX_REPORTED_IMPORT_ERRORS).collect();
+    if parsed.entries.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "No valid entries", "invalid": parsed.errors.len(), "errors": errors })),
+        );
+    }
+
+    let reason = params.reason.unwrap_or_else(|| "import".to_string());
+    let ttl = params.ttl_secs.map(std::time::Duration::from_secs);
+    let result = state
+        .blocklist
+        .import_ips(&parsed.entries, &reason, ttl, &source, &actor)
+        .await
+        .map_err(|e| e.to_string());
+
+    match result {
+        Ok(r) => (
+            StatusCode::OK,
+            Json(json!({
+                "imported": r.added,
+                "invalid": parsed.errors.len(),
+                "errors": errors,
+            })),
+        ),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e }))),
+    }
+}
+
+/// Quote a CSV field if it contains a delimiter, quote, or newline.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `GET /api/fortress/blocklist/export?format=txt|csv|json`
+///
+/// Exports the active (unexpired) IP and CIDR entries. `txt` is one entry
+/// per line and can be fed straight back into the import endpoint.
+pub async fn export_blocklist(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    let format = params.format.as_deref().unwrap_or("txt");
+    if !matches!(format, "txt" | "csv" | "json") {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Unknown export format: {}", format) })),
+        )
+            .into_response();
+    }
+
+    let rows = match state.blocklist.active_ips().await {
+        Ok(rows) => rows,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{}", e) })),
+            )
+                .into_response();
+        }
+    };
+
+    let disposition = format!("attachment; filename=\"fortress-blocklist.{}\"", format);
+    let (content_type, body) = match format {
+        "json" => (
+            "application/json",
+            serde_json::to_string(&rows).unwrap_or_else(|_| "[]".to_string()),
+        ),
+        "csv" => {
+            let mut out = String::from("ip,reason,source,created_at,expires_at\n");
+            for row in &rows {
+                out.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    csv_field(row.cidr.as_deref().unwrap_or(&row.ip)),
+                    csv_field(&row.reason),
+                    csv_field(&row.source),
+                    csv_field(&row.created_at),
+                    csv_field(row.expires_at.as_deref().unwrap_or("")),
+                ));
+            }
+            ("text/csv; charset=utf-8", out)
+        }
+        _ => {
+            let mut out = String::with_capacity(rows.len() * 16);
+            for row in &rows {
+                out.push_str(row.cidr.as_deref().unwrap_or(&row.ip));
+                out.push('\n');
+            }
+            ("text/plain; charset=utf-8", out)
+        }
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
 // ---------------------------------------------------------------------------
 // Rules CRUD
 // ---------------------------------------------------------------------------
 
 /// `GET /api/fortress/rules`
+///
+/// `active_now` is false while a scheduled rule is outside its window.
 pub async fn get_rules(State(state): State<AppState>) -> Json<Value> {
-    match state.sqlite.get_rules() {
-        Ok(rules) => Json(json!({ "rules": rules })),
+    match state.sqlite.get_rules().await {
+        Ok(rules) => {
+            let rules: Vec<Value> = rules
+                .into_iter()
+                .map(|rule| {
+                    let active_now = match rule.schedule.parse() {
+                        Ok(schedule) => schedule.is_none_or(|s| s.is_active()),
+                        Err(_) => false,
+                    };
+                    let mut value = json!(rule);
+                    value["active_now"] = json!(active_now);
+                    value
+                })
+                .collect();
+            Json(json!({ "rules": rules }))
+        }
         Err(e) => Json(json!({ "error": format!("{}", e) })),
     }
 }
 
+/// Validate a rule's schedule into the canonical columns to store.
+fn rule_schedule(fields: &ScheduleFields) -> Result<ScheduleFields, (StatusCode, Json<Value>)> {
+    match fields.parse() {
+        Ok(schedule) => Ok(schedule.map(|s| s.fields()).unwrap_or_default()),
+        Err(e) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Invalid schedule: {}", e) })),
+        )),
+    }
+}
+
 /// `POST /api/fortress/rules`
+///
+/// The condition is compiled before saving; an invalid condition or regex
+/// is rejected with 400.
 pub async fn create_rule(
     State(state): State<AppState>,
     Json(body): Json<CreateRuleRequest>,
-) -> Json<Value> {
+) -> impl IntoResponse {
     let priority = body.priority.unwrap_or(0);
     let conditions_str = serde_json::to_string(&body.condition).unwrap_or_default();
+    if let Err(e) = compile_conditions(&conditions_str) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Invalid condition: {}", e) })),
+        );
+    }
+    let schedule = match rule_schedule(&body.schedule) {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
 
-    match state
-        .sqlite
-        .add_rule(&body.name, priority, &conditions_str, &body.action)
-    {
-        Ok(id) => Json(json!({ "id": id, "status": "created" })),
-        Err(e) => Json(json!({ "error": format!("{}", e) })),
+    match state.sqlite.add_rule(
+        &body.name,
+        priority,
+        &conditions_str,
+        &body.action,
+        body.log_only.unwrap_or(false),
+        &schedule,
+    ).await {
+        Ok(id) => (StatusCode::OK, Json(json!({ "id": id, "status": "created" }))),
+        Err(e) => (StatusCode::OK, Json(json!({ "error": format!("{}", e) }))),
     }
 }
 
@@ -350,7 +1041,7 @@ pub async fn update_rule(
     State(state): State<AppState>,
     Path(id): Path<i64>,
     Json(body): Json<UpdateRuleRequest>,
-) -> Json<Value> {
+) -> impl IntoResponse {
     let name = body.name.as_deref().unwrap_or("");
     let priority = body.priority.unwrap_or(0);
     let conditions_str = body
@@ -358,23 +1049,54 @@ pub async fn update_rule(
         .as_ref()
         .map(|c| serde_json::to_string(c).unwrap_or_default())
         .unwrap_or_default();
+    if let Err(e) = compile_conditions(&conditions_str) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Invalid condition: {}", e) })),
+        );
+    }
+    let schedule = match rule_schedule(&body.schedule) {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
     let action = body.action.as_deref().unwrap_or("");
     let enabled = body.enabled.unwrap_or(true);
+    let log_only = body.log_only.unwrap_or(false);
 
-    match state.sqlite.update_rule(id, name, priority, &conditions_str, action, enabled) {
-        Ok(_) => Json(json!({ "id": id, "status": "updated" })),
-        Err(e) => Json(json!({ "error": format!("{}", e) })),
+    match state
+        .sqlite
+        .update_rule(id, name, priority, &conditions_str, action, enabled, log_only, &schedule)
+        .await
+    {
+        Ok(_) => (StatusCode::OK, Json(json!({ "id": id, "status": "updated" }))),
+        Err(e) => (StatusCode::OK, Json(json!({ "error": format!("{}", e) }))),
     }
 }
 
-/// `DELETE /api/fortress/rules/:id`
-pub async fn delete_rule(
+/// `GET /api/fortress/rules/:id/matches`
+///
+/// Match count and most recent matches for a rule since startup. Log-only
+/// rules are recorded here without their action being applied.
+pub async fn get_rule_matches(
     State(state): State<AppState>,
     Path(id): Path<i64>,
-) -> StatusCode {
-    match state.sqlite.delete_rule(id) {
-        Ok(_) => StatusCode::NO_CONTENT,
-        Err(_) => StatusCode::NOT_FOUND,
+) -> Json<Value> {
+    let (count, recent) = state.custom_rules.get_matches(id);
+    Json(json!({
+        "rule_id": id,
+        "match_count": count,
+        "recent": recent,
+    }))
+}
+
+/// `DELETE /api/fortress/rules/:id`
+pub async fn delete_rule(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match state.sqlite.delete_rule(id).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
     }
 }
 
@@ -394,7 +1116,7 @@ pub async fn get_config(State(state): State<AppState>) -> Json<Value> {
     ];
     let mut config = serde_json::Map::new();
     for key in &known_keys {
-        if let Ok(Some(value)) = state.sqlite.get_config(key) {
+        if let Ok(Some(value)) = state.sqlite.get_config(key).await {
             config.insert(key.to_string(), Value::String(value));
         }
     }
@@ -404,13 +1126,15 @@ pub async fn get_config(State(state): State<AppState>) -> Json<Value> {
 /// `GET /api/fortress/settings`
 ///
 /// Returns the current running settings (read-only). Changes to these values
-/// require editing fortress.toml and restarting the service.
+/// require editing fortress.toml and calling `POST /api/fortress/config/reload`.
 pub async fn get_settings(State(state): State<AppState>) -> Json<Value> {
-    let s = &state.settings;
+    let s = state.settings.load();
     Json(json!({
         "bot_whitelist": {
             "enabled": s.bot_whitelist.enabled,
             "verify_ip": s.bot_whitelist.verify_ip,
+            "crawlers": s.bot_whitelist.crawlers.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            "pending_score": s.bot_whitelist.pending_score,
         },
         "mobile_proxy": {
             "min_signals": s.mobile_proxy.min_signals,
@@ -431,8 +1155,13 @@ pub async fn get_settings(State(state): State<AppState>) -> Json<Value> {
             "l3_to_l4_rps": s.escalation.l3_to_l4_rps,
         },
         "challenge": {
+            "mode": s.challenge.mode,
             "cookie_subnet_binding": s.challenge.cookie_subnet_binding,
             "nojs_fallback_enabled": s.challenge.nojs_fallback_enabled,
+            "nojs_max_attempts_per_minute": s.challenge.nojs_max_attempts_per_minute,
+            "max_unanswered_challenges": s.challenge.max_unanswered_challenges,
+            "unanswered_window_secs": s.challenge.unanswered_window_secs,
+            "challenge_flood_action": s.challenge.challenge_flood_action,
             "pow_difficulty_l1": s.challenge.pow_difficulty_l1,
             "pow_difficulty_l2": s.challenge.pow_difficulty_l2,
             "pow_difficulty_l3": s.challenge.pow_difficulty_l3,
@@ -443,15 +1172,104 @@ pub async fn get_settings(State(state): State<AppState>) -> Json<Value> {
             "country_challenge_score": s.blocklist.country_challenge_score,
             "challenged_countries": s.blocklist.challenged_countries,
             "blocked_countries": s.blocklist.blocked_countries,
+            "allowed_countries": s.blocklist.allowed_countries,
+            "allowed_asns": s.blocklist.allowed_asns,
+            "allowlist_action": s.blocklist.allowlist_action,
+            "allowlist_unknown_action": s.blocklist.allowlist_unknown_action,
         },
         "protection": {
             "default_level": s.protection.default_level,
             "auto_escalation": s.protection.auto_escalation,
             "ipv4_subnet_mask": s.protection.ipv4_subnet_mask,
+            "exempt_cors_preflight": s.protection.exempt_cors_preflight,
+            "tarpit": {
+                "delay_secs": s.protection.tarpit.delay_secs,
+                "drip_interval_ms": s.protection.tarpit.drip_interval_ms,
+                "max_concurrent": s.protection.tarpit.max_concurrent,
+            },
+            "body_inspection": {
+                "max_bytes": s.protection.body_inspection.max_bytes,
+                "read_timeout_secs": s.protection.body_inspection.read_timeout_secs,
+                "content_types": s.protection.body_inspection.content_types,
+                "exempt_content_types": s.protection.body_inspection.exempt_content_types,
+                "exempt_paths": s.protection.body_inspection.exempt_paths,
+                "max_decompressed_bytes": s.protection.body_inspection.max_decompressed_bytes,
+                "max_expansion_ratio": s.protection.body_inspection.max_expansion_ratio,
+            },
         },
     }))
 }
 
+/// `POST /api/fortress/config/reload`
+///
+/// Re-reads fortress.toml and applies it without restarting the proxy.
+pub async fn reload_config(State(state): State<AppState>) -> impl IntoResponse {
+    match state.reloader.reload().await {
+        Ok(()) => (StatusCode::OK, Json(json!({ "status": "reloaded" }))),
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": format!("{:#}", e) })),
+        ),
+    }
+}
+
+/// `POST /api/fortress/logs/reopen`
+///
+/// Closes and reopens the access log file, like SIGUSR1. Use after moving
+/// the file away with an external rotation tool.
+pub async fn reopen_access_log(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.access_log {
+        Some(logger) => {
+            logger.reopen();
+            (StatusCode::OK, Json(json!({ "status": "reopened" })))
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Access log is disabled" })),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogStreamQuery {
+    pub action: Option<String>,
+    /// IP prefix, e.g. `203.0.113.`.
+    pub ip: Option<String>,
+    pub host: Option<String>,
+    pub min_score: Option<f64>,
+}
+
+/// `GET /api/fortress/logs/stream`
+///
+/// Server-Sent Events with each access-log entry, as JSON, from the moment
+/// the stream opens; `action`, `ip` (prefix), `host` and `min_score`
+/// filter them. A client that reads too slowly skips entries and gets a
+/// `lagged` event with how many. At most `logging.live_tail_max_clients`
+/// streams are open at once; further ones get a 429.
+pub async fn stream_access_log(
+    State(state): State<AppState>,
+    Query(q): Query<LogStreamQuery>,
+) -> axum::response::Response {
+    let filter = TailFilter {
+        action: q.action.filter(|s| !s.is_empty()),
+        ip_prefix: q.ip.filter(|s| !s.is_empty()),
+        host: q.host.filter(|s| !s.is_empty()),
+        min_score: q.min_score,
+    };
+    let Some(subscription) = state.live_tail.subscribe() else {
+        let msg = "Too many log streams open; close one first";
+        return (StatusCode::TOO_MANY_REQUESTS, Json(json!({ "error": msg }))).into_response();
+    };
+    let events = futures_util::stream::unfold((subscription, filter), |(mut subscription, filter)| async move {
+        let event = match subscription.next(&filter).await? {
+            TailEvent::Entry(entry) => Event::default().json_data(&*entry).unwrap_or_default(),
+            TailEvent::Lagged(skipped) => Event::default().event("lagged").data(skipped.to_string()),
+        };
+        Some((Ok::<_, std::convert::Infallible>(event), (subscription, filter)))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
 /// `PUT /api/fortress/config`
 ///
 /// Accepts a JSON object of key-value pairs and stores each in the config table.
@@ -465,7 +1283,7 @@ pub async fn update_config(
                 Value::String(s) => s.clone(),
                 other => other.to_string(),
             };
-            if let Err(e) = state.sqlite.set_config(key, &value_str) {
+            if let Err(e) = state.sqlite.set_config(key, &value_str).await {
                 return Json(json!({ "error": format!("Failed to set {}: {}", key, e) }));
             }
         }
@@ -479,28 +1297,121 @@ pub async fn update_config(
 // Protection level
 // ---------------------------------------------------------------------------
 
+/// Display name of a protection level.
+fn level_name(level: ProtectionLevel) -> &'static str {
+    match level {
+        ProtectionLevel::L0 => "Normal",
+        ProtectionLevel::L1 => "High",
+        ProtectionLevel::L2 => "UnderAttack",
+        ProtectionLevel::L3 => "Severe",
+        ProtectionLevel::L4 => "Emergency",
+    }
+}
+
+/// `GET /api/fortress/level`
+///
+/// Returns the global level, or with `?service=<id>` the level that applies
+/// to that service and where it comes from (`override`, `service` or
+/// `global`).
+pub async fn get_level(
+    State(state): State<AppState>,
+    Query(params): Query<LevelParams>,
+) -> impl IntoResponse {
+    let Some(id) = params.service else {
+        let level = state.escalation.current_level();
+        return Json(json!({
+            "level": level_name(level),
+            "value": level.as_u8(),
+            "mode": state.escalation.level_mode().as_str(),
+            "pinned_level": state.escalation.pinned_level(),
+            "changed_at": state.escalation.level_changed_at().to_rfc3339(),
+            "escalation_signal": state.escalation.escalation_signal().map(|s| s.as_str()),
+        }))
+        .into_response();
+    };
+    let Some(svc) = state.service_router.get_service(&id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Unknown service" }))).into_response();
+    };
+
+    let (level, source) = match svc.protection_level_override.and_then(ProtectionLevel::from_u8) {
+        Some(level) => (level, "override"),
+        None => match state.escalation.service_level(&id) {
+            Some(level) => (level, "service"),
+            None => (state.escalation.current_level(), "global"),
+        },
+    };
+    Json(json!({
+        "service": id,
+        "level": level_name(level),
+        "value": level.as_u8(),
+        "source": source,
+    }))
+    .into_response()
+}
+
 /// `POST /api/fortress/level`
+///
+/// Sets the global level, or a service's own level when `service` is given
+/// (requires `escalation.per_service`). A global level set here is pinned:
+/// auto-escalation won't lower it until `DELETE /api/fortress/level/pin`.
 pub async fn set_level(
     State(state): State<AppState>,
     Json(body): Json<SetLevelRequest>,
-) -> Json<Value> {
-    match ProtectionLevel::from_str_name(&body.level) {
-        Some(level) => {
-            state.escalation.set_level(level);
-            let level_name = match level {
-                ProtectionLevel::L0 => "Normal",
-                ProtectionLevel::L1 => "High",
-                ProtectionLevel::L2 => "UnderAttack",
-                ProtectionLevel::L3 => "Severe",
-                ProtectionLevel::L4 => "Emergency",
-            };
-            Json(json!({
-                "status": "ok",
-                "level": level_name,
-            }))
+) -> impl IntoResponse {
+    let Some(level) = ProtectionLevel::from_str_name(&body.level) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Unknown protection level: {}", body.level) })),
+        );
+    };
+
+    match body.service {
+        Some(id) => {
+            if state.service_router.get_service(&id).is_none() {
+                return (StatusCode::NOT_FOUND, Json(json!({ "error": "Unknown service" })));
+            }
+            if !state.escalation.set_service_level(&id, level) {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({ "error": "Per-service levels are disabled (escalation.per_service)" })),
+                );
+            }
+            state.cluster.publish(ClusterOp::Level { level: level.as_u8(), service: Some(id.clone()) });
+            (
+                StatusCode::OK,
+                Json(json!({ "status": "ok", "service": id, "level": level_name(level) })),
+            )
         }
-        None => Json(json!({ "error": format!("Unknown protection level: {}", body.level) })),
+        None => {
+            state.escalation.pin_level(level);
+            state.escalation.save(&state.sqlite).await;
+            state.cluster.publish(ClusterOp::Level { level: level.as_u8(), service: None });
+            (
+                StatusCode::OK,
+                Json(json!({ "status": "ok", "level": level_name(level), "mode": "pinned" })),
+            )
+        }
+    }
+}
+
+/// `DELETE /api/fortress/level/pin`
+///
+/// Hands a manually set global level back to auto-escalation, which
+/// de-escalates from it as usual.
+pub async fn release_level_pin(State(state): State<AppState>) -> impl IntoResponse {
+    if !state.escalation.release_pin() {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Protection level is not pinned" })));
     }
+    state.escalation.save(&state.sqlite).await;
+    let level = state.escalation.current_level();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "ok",
+            "level": level_name(level),
+            "mode": state.escalation.level_mode().as_str(),
+        })),
+    )
 }
 
 // ---------------------------------------------------------------------------
@@ -514,6 +1425,7 @@ pub async fn get_analytics(State(state): State<AppState>) -> Json<Value> {
     let top_countries = state.metrics.get_top_countries(10);
     let top_asns = state.metrics.get_top_asns(10);
     let top_fingerprints = state.metrics.get_top_fingerprints(10);
+    let per_service: Vec<Value> = state.metrics.get_all_service_metrics().iter().map(service_metrics_json).collect();
 
     Json(json!({
         "snapshot": {
@@ -521,10 +1433,12 @@ pub async fn get_analytics(State(state): State<AppState>) -> Json<Value> {
             "blocked_per_sec": snapshot.blocked_per_sec,
             "challenged_per_sec": snapshot.challenged_per_sec,
             "passed_per_sec": snapshot.passed_per_sec,
+            "bypassed_per_sec": state.metrics.get_current_bypassed(),
             "unique_ips": snapshot.unique_ips,
             "avg_latency_ms": snapshot.avg_latency_ms,
             "total_requests": snapshot.total_requests,
             "total_blocked": snapshot.total_blocked,
+            "total_bypassed": state.metrics.bypass_totals(),
         },
         "top_ips": top_ips.iter().map(|(ip, count)| json!({
             "ip": ip.to_string(),
@@ -542,6 +1456,7 @@ pub async fn get_analytics(State(state): State<AppState>) -> Json<Value> {
             "fingerprint": fp,
             "count": count,
         })).collect::<Vec<_>>(),
+        "per_service": per_service,
     }))
 }
 
@@ -561,6 +1476,97 @@ pub async fn get_top_ips(
     }))
 }
 
+/// `GET /api/fortress/analytics/top?dimension=ip|country|asn|path`
+///
+/// Most frequent values among the sampled requests between `from` and `to`
+/// (RFC 3339; by default the last 24 hours). Counts are samples, not
+/// requests: divide by `sample_rate` for an estimate.
+pub async fn get_sampled_top(
+    State(state): State<AppState>,
+    Query(params): Query<SampleTopParams>,
+) -> impl IntoResponse {
+    let dimension = params.dimension.as_deref().unwrap_or("ip");
+    let Some(column) = SampleDimension::parse(dimension).map(SampleDimension::column) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "dimension must be ip, country, asn or path" })),
+        )
+            .into_response();
+    };
+    let (from, to) = match sample_range(&params.from, &params.to, ChronoDuration::hours(24)) {
+        Ok(range) => range,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response(),
+    };
+    let limit = params.limit.unwrap_or(20).clamp(1, 500);
+
+    match state.sqlite.get_top_request_samples(column, from, to, limit).await {
+        Ok(top) => Json(json!({
+            "dimension": dimension,
+            "from": from.to_rfc3339(),
+            "to": to.to_rfc3339(),
+            "sample_rate": state.settings.load().storage.request_sample_rate,
+            "top": top,
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to load request samples: {}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+/// `GET /api/fortress/ips/{ip}/requests`
+///
+/// Sampled requests of one client between `from` and `to` (RFC 3339; by
+/// default the last hour), newest first.
+pub async fn get_ip_requests(
+    State(state): State<AppState>,
+    Path(ip): Path<String>,
+    Query(params): Query<SampleParams>,
+) -> impl IntoResponse {
+    let Ok(ip) = ip.parse::<std::net::IpAddr>() else {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Invalid IP address" }))).into_response();
+    };
+    let (from, to) = match sample_range(&params.from, &params.to, ChronoDuration::hours(1)) {
+        Ok(range) => range,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response(),
+    };
+    let limit = params.limit.unwrap_or(100).clamp(1, 1000);
+
+    match state.sqlite.get_request_samples(&ip.to_string(), from, to, limit).await {
+        Ok(requests) => Json(json!({
+            "ip": ip.to_string(),
+            "from": from.to_rfc3339(),
+            "to": to.to_rfc3339(),
+            "sample_rate": state.settings.load().storage.request_sample_rate,
+            "requests": requests,
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to load request samples: {}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+/// `from`/`to` query range; `to` defaults to now and `from` to
+/// `default_span` before it.
+fn sample_range(
+    from: &Option<String>,
+    to: &Option<String>,
+    default_span: ChronoDuration,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    let (from, to) = (parse_rfc3339(from)?, parse_rfc3339(to)?);
+    let to = to.unwrap_or_else(Utc::now);
+    let from = from.unwrap_or(to - default_span);
+    if from > to {
+        return Err("from is after to".to_string());
+    }
+    Ok((from, to))
+}
+
 /// `GET /api/fortress/top-countries`
 pub async fn get_top_countries(State(state): State<AppState>) -> Json<Value> {
     let top = state.metrics.get_top_countries(50);
@@ -589,6 +1595,130 @@ pub async fn get_fingerprints(State(state): State<AppState>) -> Json<Value> {
 // Services
 // -----------------------------------------------------------------------
 
+/// Per-backend health and load for a service's upstreams.
+fn upstream_status(state: &AppState, service_id: &str) -> Vec<serde_json::Value> {
+    state
+        .service_router
+        .backend_status(service_id)
+        .into_iter()
+        .map(|b| {
+            serde_json::json!({
+                "address": b.address,
+                "healthy": b.healthy,
+                "active_connections": b.active_connections,
+            })
+        })
+        .collect()
+}
+
+/// `GET /api/fortress/services/{id}/health`
+///
+/// Health and load of each upstream with the result of its last probe,
+/// and the health check settings in effect for the service.
+pub async fn get_service_health(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let Some(svc) = state.service_router.get_service(&id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": "Service not found"})),
+        );
+    };
+    let check = HealthCheckPlan::for_service(&svc, &state.settings.load().upstream.health_check);
+
+    let upstreams = state.service_router.backend_status(&id);
+    let last_check = upstreams.iter().filter_map(|b| b.last_check).max();
+    let load = state.service_router.upstream_load(&id);
+    let upstreams: Vec<Value> = upstreams
+        .into_iter()
+        .map(|b| {
+            let pool = state.upstream_clients.pool_stats(&b.address);
+            let mut value = serde_json::to_value(b).unwrap_or_default();
+            value["pool"] = json!(pool);
+            value
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "service_id": id,
+            "healthy": state.service_router.is_healthy(&id),
+            "last_check": last_check,
+            "in_flight": load.as_ref().map(|l| l.in_flight),
+            "max_connections": load.as_ref().and_then(|l| l.max_connections),
+            "circuit": load.map(|l| l.circuit),
+            "health_check": check,
+            "upstreams": upstreams,
+        })),
+    )
+}
+
+fn service_metrics_json(metrics: &ServiceMetrics) -> Value {
+    json!({
+        "service_id": metrics.service_id,
+        "rps": metrics.rps,
+        "blocked_per_sec": metrics.blocked_per_sec,
+        "challenged_per_sec": metrics.challenged_per_sec,
+        "passed_per_sec": metrics.passed_per_sec,
+        "avg_latency_ms": metrics.avg_latency_ms,
+        "total_requests": metrics.totals.passed + metrics.totals.challenged + metrics.totals.blocked,
+        "total_passed": metrics.totals.passed,
+        "total_challenged": metrics.totals.challenged,
+        "total_blocked": metrics.totals.blocked,
+        "total_bypassed": metrics.totals.bypassed,
+    })
+}
+
+/// `GET /api/fortress/services/{id}/metrics`
+///
+/// Live counters of one service with its last `seconds` per-second
+/// snapshots, and the hourly rollups stored for it over the last `hours`.
+/// Latency is the time Fortress took to answer, averaged over this hour.
+pub async fn get_service_metrics(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<ServiceMetricsParams>,
+) -> impl IntoResponse {
+    if state.service_router.get_service(&id).is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Service not found"})));
+    }
+
+    let seconds = params.seconds.unwrap_or(60).min(300);
+    let live = state.metrics.get_service_metrics(&id, seconds);
+    let to = Utc::now();
+    let from = to - ChronoDuration::hours(params.hours.unwrap_or(24) as i64);
+    let hourly = match state.sqlite.get_service_metrics_history(&id, from, to).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": format!("Failed to load metrics history: {}", e)})),
+            );
+        }
+    };
+
+    let mut body = match &live {
+        Some(metrics) => service_metrics_json(metrics),
+        None => json!({ "service_id": id }),
+    };
+    body["history"] = json!(live
+        .map(|m| m.history)
+        .unwrap_or_default()
+        .iter()
+        .map(|s| json!({
+            "timestamp": s.timestamp,
+            "requests": s.requests,
+            "blocked": s.blocked,
+            "challenged": s.challenged,
+            "passed": s.passed,
+            "bypassed": s.bypassed,
+        }))
+        .collect::<Vec<_>>());
+    body["hourly"] = json!(hourly);
+    (StatusCode::OK, Json(body))
+}
+
 pub async fn list_services(
     State(state): State<AppState>,
 ) -> impl IntoResponse {
@@ -599,13 +1729,43 @@ pub async fn list_services(
             "name": svc.name,
             "domains": svc.domains,
             "upstream_address": svc.upstream_address,
+            "lb_strategy": svc.lb_strategy,
+            "upstreams": upstream_status(&state, &svc.id),
             "enabled": svc.enabled,
             "protection_level_override": svc.protection_level_override,
             "always_challenge": svc.always_challenge,
             "rate_limit_multiplier": svc.rate_limit_multiplier,
+            "max_requests_per_ip_10s": svc.max_requests_per_ip_10s,
             "max_connections": svc.max_connections,
             "connect_timeout_ms": svc.connect_timeout_ms,
             "response_timeout_ms": svc.response_timeout_ms,
+            "upstream_tls_verify": svc.upstream_tls_verify,
+            "upstream_sni_host": svc.upstream_sni_host,
+            "upstream_http2": svc.upstream_http2,
+            "add_request_headers": svc.add_request_headers,
+            "remove_request_headers": svc.remove_request_headers,
+            "add_response_headers": svc.add_response_headers,
+            "remove_response_headers": svc.remove_response_headers,
+            "allowed_countries": svc.allowed_countries,
+            "allowed_asns": svc.allowed_asns,
+            "blocked_countries": svc.blocked_countries,
+            "challenged_countries": svc.challenged_countries,
+            "country_exceptions": svc.country_exceptions,
+            "clearance_cookie_domain": svc.clearance_cookie_domain,
+            "clearance_ttl_secs": svc.clearance_ttl_secs,
+            "cors_allowed_origins": svc.cors_allowed_origins,
+            "exempt_paths": svc.exempt_paths,
+            "maintenance_mode": svc.maintenance_mode,
+            "maintenance_html_path": svc.maintenance_html_path,
+            "body_inspection": svc.body_inspection,
+            "login_username_field": svc.login_username_field,
+            "max_body_size_mb": svc.max_body_size_mb,
+            "always_online": svc.always_online,
+            "always_online_banner": svc.always_online_banner,
+            "request_encoding": svc.request_encoding,
+            "routes": svc.routes,
+            "waiting_room": svc.waiting_room,
+            "health_check": svc.health_check,
         })
     }).collect();
     Json(result)
@@ -621,13 +1781,43 @@ pub async fn get_service(
             "name": svc.name,
             "domains": svc.domains,
             "upstream_address": svc.upstream_address,
+            "lb_strategy": svc.lb_strategy,
+            "upstreams": upstream_status(&state, &svc.id),
             "enabled": svc.enabled,
             "protection_level_override": svc.protection_level_override,
             "always_challenge": svc.always_challenge,
             "rate_limit_multiplier": svc.rate_limit_multiplier,
+            "max_requests_per_ip_10s": svc.max_requests_per_ip_10s,
             "max_connections": svc.max_connections,
             "connect_timeout_ms": svc.connect_timeout_ms,
             "response_timeout_ms": svc.response_timeout_ms,
+            "upstream_tls_verify": svc.upstream_tls_verify,
+            "upstream_sni_host": svc.upstream_sni_host,
+            "upstream_http2": svc.upstream_http2,
+            "add_request_headers": svc.add_request_headers,
+            "remove_request_headers": svc.remove_request_headers,
+            "add_response_headers": svc.add_response_headers,
+            "remove_response_headers": svc.remove_response_headers,
+            "allowed_countries": svc.allowed_countries,
+            "allowed_asns": svc.allowed_asns,
+            "blocked_countries": svc.blocked_countries,
+            "challenged_countries": svc.challenged_countries,
+            "country_exceptions": svc.country_exceptions,
+            "clearance_cookie_domain": svc.clearance_cookie_domain,
+            "clearance_ttl_secs": svc.clearance_ttl_secs,
+            "cors_allowed_origins": svc.cors_allowed_origins,
+            "exempt_paths": svc.exempt_paths,
+            "maintenance_mode": svc.maintenance_mode,
+            "maintenance_html_path": svc.maintenance_html_path,
+            "body_inspection": svc.body_inspection,
+            "login_username_field": svc.login_username_field,
+            "max_body_size_mb": svc.max_body_size_mb,
+            "always_online": svc.always_online,
+            "always_online_banner": svc.always_online_banner,
+            "request_encoding": svc.request_encoding,
+            "routes": svc.routes,
+            "waiting_room": svc.waiting_room,
+            "health_check": svc.health_check,
         })).into_response(),
         None => StatusCode::NOT_FOUND.into_response(),
     }
@@ -638,23 +1828,107 @@ pub struct CreateServiceRequest {
     pub id: Option<String>,
     pub name: String,
     pub domains: Vec<String>,
-    pub upstream_address: String,
+    /// A single `host:port` / `https://host:port` URL, or a list of them.
+    #[serde(deserialize_with = "crate::config::service::string_or_list")]
+    pub upstream_address: Vec<String>,
+    pub lb_strategy: Option<LoadBalanceStrategy>,
     pub enabled: Option<bool>,
     pub protection_level_override: Option<u8>,
     pub always_challenge: Option<bool>,
     pub rate_limit_multiplier: Option<f64>,
+    pub max_requests_per_ip_10s: Option<u64>,
     pub max_connections: Option<usize>,
     pub connect_timeout_ms: Option<u64>,
     pub response_timeout_ms: Option<u64>,
+    pub upstream_tls_verify: Option<bool>,
+    pub upstream_sni_host: Option<String>,
+    pub upstream_http2: Option<bool>,
+    #[serde(default)]
+    pub add_request_headers: HashMap<String, String>,
+    #[serde(default)]
+    pub remove_request_headers: Vec<String>,
+    #[serde(default)]
+    pub add_response_headers: HashMap<String, String>,
+    #[serde(default)]
+    pub remove_response_headers: Vec<String>,
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+    #[serde(default)]
+    pub allowed_asns: Vec<u32>,
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+    #[serde(default)]
+    pub challenged_countries: Vec<String>,
+    #[serde(default)]
+    pub country_exceptions: Vec<String>,
+    pub clearance_cookie_domain: Option<String>,
+    pub clearance_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub exempt_paths: Vec<ExemptPath>,
+    pub maintenance_mode: Option<bool>,
+    pub maintenance_html_path: Option<String>,
+    pub body_inspection: Option<bool>,
+    pub login_username_field: Option<String>,
+    pub max_body_size_mb: Option<u64>,
+    pub always_online: Option<bool>,
+    pub always_online_banner: Option<bool>,
+    pub request_encoding: Option<RequestEncodingPolicy>,
+    #[serde(default)]
+    pub routes: Vec<ServiceRoute>,
+    pub waiting_room: Option<WaitingRoomConfig>,
+    pub health_check: Option<ServiceHealthCheck>,
+}
+
+impl CreateServiceRequest {
+    /// Check the request, naming unnamed routes.
+    fn validate(&mut self) -> Result<(), String> {
+        normalize_routes(&mut self.routes)?;
+        header_rules::validate(&self.remove_request_headers, &self.add_request_headers)?;
+        header_rules::validate(&self.remove_response_headers, &self.add_response_headers)?;
+        // A shared clearance cookie must cover every domain of the service,
+        // or browsers drop it on the hosts outside it
+        if let Some(domain) = normalize_cookie_domain(self.clearance_cookie_domain.as_deref()) {
+            if let Some(host) = self.domains.iter().find(|d| !host_in_domain(d, &domain)) {
+                return Err(format!("domain {:?} is not within clearance_cookie_domain {:?}", host, domain));
+            }
+        }
+        for origin in normalize_origins(&self.cors_allowed_origins) {
+            let rest = origin
+                .strip_prefix("https://")
+                .or_else(|| origin.strip_prefix("http://"));
+            if origin != "*" && rest.is_none_or(|r| r.is_empty() || r.contains('/')) {
+                return Err(format!("invalid CORS origin {:?} (use scheme://host[:port] or *)", origin));
+            }
+        }
+        if let Some(path) = self.maintenance_html_path.as_deref().filter(|p| !p.is_empty()) {
+            if !std::path::Path::new(path).is_file() {
+                return Err(format!("maintenance_html_path {:?} is not a readable file", path));
+            }
+        }
+        if let Some(room) = &self.waiting_room {
+            room.validate()?;
+        }
+        if let Some(check) = &self.health_check {
+            check.validate()?;
+        }
+        Ok(())
+    }
 }
 
 pub async fn create_service(
     State(state): State<AppState>,
-    Json(body): Json<CreateServiceRequest>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Json(mut body): Json<CreateServiceRequest>,
 ) -> impl IntoResponse {
     use crate::config::service::ServiceConfig;
     use crate::storage::sqlite::ServiceRow;
 
+    if let Err(e) = body.validate() {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e})));
+    }
+
     let id = body.id.unwrap_or_else(|| {
         format!("svc-{}", &uuid_simple())
     });
@@ -664,14 +1938,42 @@ pub async fn create_service(
         name: body.name.clone(),
         domains: body.domains.clone(),
         upstream_address: body.upstream_address.clone(),
+        lb_strategy: body.lb_strategy.unwrap_or_default(),
         enabled: body.enabled.unwrap_or(true),
         protection_level_override: body.protection_level_override,
         always_challenge: body.always_challenge.unwrap_or(false),
         rate_limit_multiplier: body.rate_limit_multiplier.unwrap_or(1.0),
+        max_requests_per_ip_10s: body.max_requests_per_ip_10s,
         max_connections: body.max_connections.unwrap_or(10_000),
         connect_timeout_ms: body.connect_timeout_ms.unwrap_or(5_000),
         response_timeout_ms: body.response_timeout_ms.unwrap_or(60_000),
-        exempt_paths: Vec::new(),
+        exempt_paths: body.exempt_paths.clone(),
+        upstream_tls_verify: body.upstream_tls_verify.unwrap_or(true),
+        upstream_sni_host: body.upstream_sni_host.clone().filter(|h| !h.is_empty()),
+        upstream_http2: body.upstream_http2.unwrap_or(false),
+        add_request_headers: body.add_request_headers.clone(),
+        remove_request_headers: body.remove_request_headers.clone(),
+        add_response_headers: body.add_response_headers.clone(),
+        remove_response_headers: body.remove_response_headers.clone(),
+        allowed_countries: normalize_countries(&body.allowed_countries),
+        allowed_asns: body.allowed_asns.clone(),
+        blocked_countries: normalize_countries(&body.blocked_countries),
+        challenged_countries: normalize_countries(&body.challenged_countries),
+        country_exceptions: normalize_countries(&body.country_exceptions),
+        clearance_cookie_domain: normalize_cookie_domain(body.clearance_cookie_domain.as_deref()),
+        clearance_ttl_secs: body.clearance_ttl_secs.filter(|&t| t > 0),
+        cors_allowed_origins: normalize_origins(&body.cors_allowed_origins),
+        maintenance_mode: body.maintenance_mode.unwrap_or(false),
+        maintenance_html_path: body.maintenance_html_path.clone().filter(|p| !p.is_empty()),
+        body_inspection: body.body_inspection.unwrap_or(false),
+        login_username_field: body.login_username_field.clone().filter(|f| !f.is_empty()),
+        max_body_size_mb: body.max_body_size_mb,
+        always_online: body.always_online.unwrap_or(false),
+        always_online_banner: body.always_online_banner.unwrap_or(false),
+        request_encoding: body.request_encoding.unwrap_or_default(),
+        routes: body.routes.clone(),
+        waiting_room: body.waiting_room.clone(),
+        health_check: body.health_check.clone(),
         created_at: None,
         updated_at: None,
     };
@@ -681,19 +1983,48 @@ pub async fn create_service(
         id: config.id.clone(),
         name: config.name.clone(),
         domains: serde_json::to_string(&config.domains).unwrap_or_default(),
-        upstream_address: config.upstream_address.clone(),
+        upstream_address: encode_upstreams(&config.upstream_address),
         enabled: config.enabled,
         protection_level_override: config.protection_level_override.map(|v| v as i32),
         always_challenge: config.always_challenge,
         rate_limit_multiplier: config.rate_limit_multiplier,
+        max_requests_per_ip_10s: config.max_requests_per_ip_10s.map(|v| v as i64),
         max_connections: config.max_connections as i64,
         connect_timeout_ms: config.connect_timeout_ms as i64,
         response_timeout_ms: config.response_timeout_ms as i64,
-        exempt_paths: None,
+        exempt_paths: encode_json_column(&config.exempt_paths),
+        lb_strategy: config.lb_strategy.as_str().to_string(),
+        upstream_tls_verify: config.upstream_tls_verify,
+        upstream_sni_host: config.upstream_sni_host.clone(),
+        upstream_http2: config.upstream_http2,
+        add_request_headers: encode_json_column(&config.add_request_headers),
+        remove_request_headers: encode_json_column(&config.remove_request_headers),
+        add_response_headers: encode_json_column(&config.add_response_headers),
+        remove_response_headers: encode_json_column(&config.remove_response_headers),
+        allowed_countries: encode_json_column(&config.allowed_countries),
+        allowed_asns: encode_json_column(&config.allowed_asns),
+        blocked_countries: encode_json_column(&config.blocked_countries),
+        challenged_countries: encode_json_column(&config.challenged_countries),
+        country_exceptions: encode_json_column(&config.country_exceptions),
+        clearance_cookie_domain: config.clearance_cookie_domain.clone(),
+        clearance_ttl_secs: config.clearance_ttl_secs.map(|v| v as i64),
+        cors_allowed_origins: encode_json_column(&config.cors_allowed_origins),
+        maintenance_mode: config.maintenance_mode,
+        maintenance_html_path: config.maintenance_html_path.clone(),
+        body_inspection: config.body_inspection,
+        login_username_field: config.login_username_field.clone(),
+        max_body_size_mb: config.max_body_size_mb.map(|v| v as i64),
+        always_online: config.always_online,
+        always_online_banner: config.always_online_banner,
+        request_encoding: config.request_encoding.as_str().to_string(),
+        routes: (!config.routes.is_empty()).then(|| encode_json_column(&config.routes)).flatten(),
+        waiting_room: config.waiting_room.as_ref().and_then(encode_json_column),
+        health_check: config.health_check.as_ref().and_then(encode_json_column),
         created_at: String::new(),
         updated_at: String::new(),
     };
-    let _ = state.sqlite.add_service(&row);
+    let _ = state.sqlite.add_service(&row).await;
+    state.sqlite.audit(&actor, "create", "service", &id, None);
 
     // Register in router
     state.service_router.add_service(config);
@@ -703,25 +2034,58 @@ pub async fn create_service(
 
 pub async fn update_service(
     State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
     Path(id): Path<String>,
-    Json(body): Json<CreateServiceRequest>,
+    Json(mut body): Json<CreateServiceRequest>,
 ) -> impl IntoResponse {
     use crate::config::service::ServiceConfig;
     use crate::storage::sqlite::ServiceRow;
 
+    if let Err(e) = body.validate() {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e}))).into_response();
+    }
+
     let config = ServiceConfig {
         id: id.clone(),
         name: body.name.clone(),
         domains: body.domains.clone(),
         upstream_address: body.upstream_address.clone(),
+        lb_strategy: body.lb_strategy.unwrap_or_default(),
         enabled: body.enabled.unwrap_or(true),
         protection_level_override: body.protection_level_override,
         always_challenge: body.always_challenge.unwrap_or(false),
         rate_limit_multiplier: body.rate_limit_multiplier.unwrap_or(1.0),
+        max_requests_per_ip_10s: body.max_requests_per_ip_10s,
         max_connections: body.max_connections.unwrap_or(10_000),
         connect_timeout_ms: body.connect_timeout_ms.unwrap_or(5_000),
         response_timeout_ms: body.response_timeout_ms.unwrap_or(60_000),
-        exempt_paths: Vec::new(),
+        exempt_paths: body.exempt_paths.clone(),
+        upstream_tls_verify: body.upstream_tls_verify.unwrap_or(true),
+        upstream_sni_host: body.upstream_sni_host.clone().filter(|h| !h.is_empty()),
+        upstream_http2: body.upstream_http2.unwrap_or(false),
+        add_request_headers: body.add_request_headers.clone(),
+        remove_request_headers: body.remove_request_headers.clone(),
+        add_response_headers: body.add_response_headers.clone(),
+        remove_response_headers: body.remove_response_headers.clone(),
+        allowed_countries: normalize_countries(&body.allowed_countries),
+        allowed_asns: body.allowed_asns.clone(),
+        blocked_countries: normalize_countries(&body.blocked_countries),
+        challenged_countries: normalize_countries(&body.challenged_countries),
+        country_exceptions: normalize_countries(&body.country_exceptions),
+        clearance_cookie_domain: normalize_cookie_domain(body.clearance_cookie_domain.as_deref()),
+        clearance_ttl_secs: body.clearance_ttl_secs.filter(|&t| t > 0),
+        cors_allowed_origins: normalize_origins(&body.cors_allowed_origins),
+        maintenance_mode: body.maintenance_mode.unwrap_or(false),
+        maintenance_html_path: body.maintenance_html_path.clone().filter(|p| !p.is_empty()),
+        body_inspection: body.body_inspection.unwrap_or(false),
+        login_username_field: body.login_username_field.clone().filter(|f| !f.is_empty()),
+        max_body_size_mb: body.max_body_size_mb,
+        always_online: body.always_online.unwrap_or(false),
+        always_online_banner: body.always_online_banner.unwrap_or(false),
+        request_encoding: body.request_encoding.unwrap_or_default(),
+        routes: body.routes.clone(),
+        waiting_room: body.waiting_room.clone(),
+        health_check: body.health_check.clone(),
         created_at: None,
         updated_at: None,
     };
@@ -730,35 +2094,67 @@ pub async fn update_service(
         id: config.id.clone(),
         name: config.name.clone(),
         domains: serde_json::to_string(&config.domains).unwrap_or_default(),
-        upstream_address: config.upstream_address.clone(),
+        upstream_address: encode_upstreams(&config.upstream_address),
         enabled: config.enabled,
         protection_level_override: config.protection_level_override.map(|v| v as i32),
         always_challenge: config.always_challenge,
         rate_limit_multiplier: config.rate_limit_multiplier,
+        max_requests_per_ip_10s: config.max_requests_per_ip_10s.map(|v| v as i64),
         max_connections: config.max_connections as i64,
         connect_timeout_ms: config.connect_timeout_ms as i64,
         response_timeout_ms: config.response_timeout_ms as i64,
-        exempt_paths: None,
+        exempt_paths: encode_json_column(&config.exempt_paths),
+        lb_strategy: config.lb_strategy.as_str().to_string(),
+        upstream_tls_verify: config.upstream_tls_verify,
+        upstream_sni_host: config.upstream_sni_host.clone(),
+        upstream_http2: config.upstream_http2,
+        add_request_headers: encode_json_column(&config.add_request_headers),
+        remove_request_headers: encode_json_column(&config.remove_request_headers),
+        add_response_headers: encode_json_column(&config.add_response_headers),
+        remove_response_headers: encode_json_column(&config.remove_response_headers),
+        allowed_countries: encode_json_column(&config.allowed_countries),
+        allowed_asns: encode_json_column(&config.allowed_asns),
+        blocked_countries: encode_json_column(&config.blocked_countries),
+        challenged_countries: encode_json_column(&config.challenged_countries),
+        country_exceptions: encode_json_column(&config.country_exceptions),
+        clearance_cookie_domain: config.clearance_cookie_domain.clone(),
+        clearance_ttl_secs: config.clearance_ttl_secs.map(|v| v as i64),
+        cors_allowed_origins: encode_json_column(&config.cors_allowed_origins),
+        maintenance_mode: config.maintenance_mode,
+        maintenance_html_path: config.maintenance_html_path.clone(),
+        body_inspection: config.body_inspection,
+        login_username_field: config.login_username_field.clone(),
+        max_body_size_mb: config.max_body_size_mb.map(|v| v as i64),
+        always_online: config.always_online,
+        always_online_banner: config.always_online_banner,
+        request_encoding: config.request_encoding.as_str().to_string(),
+        routes: (!config.routes.is_empty()).then(|| encode_json_column(&config.routes)).flatten(),
+        waiting_room: config.waiting_room.as_ref().and_then(encode_json_column),
+        health_check: config.health_check.as_ref().and_then(encode_json_column),
         created_at: String::new(),
         updated_at: String::new(),
     };
-    let _ = state.sqlite.update_service(&row);
+    let _ = state.sqlite.update_service(&row).await;
     state.service_router.update_service(config);
+    state.sqlite.audit(&actor, "update", "service", &id, None);
 
-    Json(serde_json::json!({"status": "updated"}))
+    Json(serde_json::json!({"status": "updated"})).into_response()
 }
 
 pub async fn delete_service(
     State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     state.service_router.remove_service(&id);
-    let _ = state.sqlite.delete_service(&id);
+    let _ = state.sqlite.delete_service(&id).await;
+    state.sqlite.audit(&actor, "delete", "service", &id, None);
     Json(serde_json::json!({"status": "deleted"}))
 }
 
 pub async fn toggle_service(
     State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     if let Some(svc) = state.service_router.get_service(&id) {
@@ -770,11 +2166,13 @@ pub async fn toggle_service(
         state.service_router.update_service(updated);
 
         // Persist to DB
-        if let Ok(Some(row)) = state.sqlite.get_service(&id) {
+        if let Ok(Some(row)) = state.sqlite.get_service(&id).await {
             let mut row = row;
             row.enabled = new_enabled;
-            let _ = state.sqlite.update_service(&row);
+            let _ = state.sqlite.update_service(&row).await;
         }
+        let action = if new_enabled { "enable" } else { "disable" };
+        state.sqlite.audit(&actor, action, "service", &id, None);
 
         Json(serde_json::json!({"status": "toggled", "enabled": new_enabled}))
     } else {
@@ -782,6 +2180,100 @@ pub async fn toggle_service(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceRequest {
+    /// Omit to flip the current state.
+    pub enabled: Option<bool>,
+}
+
+/// `POST /api/fortress/services/{id}/maintenance`
+///
+/// Turn maintenance mode on or off without touching the rest of the
+/// service configuration.
+pub async fn set_service_maintenance(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<String>,
+    Json(body): Json<MaintenanceRequest>,
+) -> impl IntoResponse {
+    let Some(svc) = state.service_router.get_service(&id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "not found"})));
+    };
+    let mut updated = (*svc).clone();
+    updated.maintenance_mode = body.enabled.unwrap_or(!svc.maintenance_mode);
+    let enabled = updated.maintenance_mode;
+
+    state.service_router.update_service(updated);
+    if let Ok(Some(mut row)) = state.sqlite.get_service(&id).await {
+        row.maintenance_mode = enabled;
+        let _ = state.sqlite.update_service(&row).await;
+    }
+    let action = if enabled { "maintenance_on" } else { "maintenance_off" };
+    state.sqlite.audit(&actor, action, "service", &id, None);
+
+    (StatusCode::OK, Json(json!({"status": "updated", "maintenance_mode": enabled})))
+}
+
+/// `GET /api/fortress/services/{id}/queue`
+///
+/// Waiting room state of a service: whether it is letting visitors in
+/// directly, how many are queued and how fast they are admitted.
+pub async fn get_service_queue(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let Some(svc) = state.service_router.get_service(&id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "not found"})));
+    };
+    let level = state.pipeline.level_for(Some(&svc));
+    let status = state.waiting_room.status(&svc, level);
+    let in_flight = state.service_router.upstream_load(&id).map_or(0, |load| load.in_flight);
+    let config = svc.waiting_room.as_ref();
+    (StatusCode::OK, Json(json!({
+        "service_id": id,
+        "configured": config.is_some(),
+        "mode": status.mode,
+        "active": status.active,
+        "queue_length": status.queue_length,
+        "issued": status.issued,
+        "admitted": status.admitted,
+        "level": level_name(level),
+        "in_flight": in_flight,
+        "max_in_flight": config.map(|c| c.max_in_flight),
+        "admit_per_sec": config.map(|c| c.admit_per_sec),
+        "activate_at_level": config.and_then(|c| c.activate_at_level),
+        "refresh_secs": config.map(|c| c.refresh_secs),
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueModeRequest {
+    pub mode: QueueMode,
+}
+
+/// `PUT /api/fortress/services/{id}/queue`
+///
+/// Open (`on`) or close (`off`) a service's waiting room by hand, or hand
+/// it back to the protection level (`auto`). Not persisted across restarts.
+pub async fn set_service_queue(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<String>,
+    Json(body): Json<QueueModeRequest>,
+) -> impl IntoResponse {
+    let Some(svc) = state.service_router.get_service(&id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "not found"})));
+    };
+    if svc.waiting_room.is_none() && body.mode != QueueMode::Off {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": "service has no waiting_room configured"})));
+    }
+    state.waiting_room.set_mode(&id, body.mode);
+    let mode = serde_json::to_value(body.mode).unwrap_or_default();
+    state.sqlite.audit(&actor, "update", "waiting_room", &id, mode.as_str());
+
+    (StatusCode::OK, Json(json!({"status": "updated", "mode": mode})))
+}
+
 // -----------------------------------------------------------------------
 // L4 Protection
 // -----------------------------------------------------------------------
@@ -795,13 +2287,75 @@ pub async fn get_l4_metrics(
     }
 }
 
+/// `GET /api/fortress/l4/top?by=concurrent|rate&limit=`
+///
+/// Tracked IPs with the most concurrent connections (default) or the
+/// highest connection rate, with their drop and tarpit counts.
+pub async fn get_l4_top(
+    State(state): State<AppState>,
+    Query(params): Query<L4TopParams>,
+) -> impl IntoResponse {
+    let by_rate = match params.by.as_deref() {
+        None | Some("concurrent") => false,
+        Some("rate") => true,
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("Unknown sort order: {} (concurrent or rate)", other) })),
+            )
+                .into_response();
+        }
+    };
+    match &state.l4_tracker {
+        Some(l4) => Json(json!(l4.top_ips(by_rate, params.limit.unwrap_or(50).min(1000)))).into_response(),
+        None => Json(json!({"error": "L4 protection not enabled"})).into_response(),
+    }
+}
+
+/// `GET /api/fortress/l4/events`
+///
+/// Pages through L4 events, newest first, filtered by `ip` (exact or
+/// prefix), `action`, `reason` (substring) and `from`/`to` (RFC 3339).
 pub async fn get_l4_events(
     State(state): State<AppState>,
-    Query(params): Query<TopParams>,
+    Query(params): Query<L4EventsParams>,
 ) -> impl IntoResponse {
-    let limit = params.limit.unwrap_or(100) as usize;
-    match state.sqlite.get_l4_events(limit) {
-        Ok(events) => Json(serde_json::json!(events)).into_response(),
+    let (from, to) = match (parse_rfc3339(&params.from), parse_rfc3339(&params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
+        }
+    };
+    if params.ip.as_deref().is_some_and(|ip| ip.contains('/')) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "CIDR search is only available for the IP blocklist" })),
+        )
+            .into_response();
+    }
+
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.or(params.limit).unwrap_or(100).clamp(1, 1000);
+    let filter = L4EventFilter {
+        ip: params.ip.filter(|ip| !ip.is_empty()),
+        action: params.action.filter(|a| !a.is_empty()),
+        reason: params.reason.filter(|r| !r.is_empty()),
+        from,
+        to,
+    };
+    match state
+        .sqlite
+        .get_l4_events_page(&filter, per_page as usize, ((page - 1) * per_page) as usize)
+        .await
+    {
+        Ok((events, total)) => Json(json!({
+            "events": events,
+            "total": total,
+            "page": page,
+            "per_page": per_page,
+            "pages": total.div_ceil(per_page),
+        }))
+        .into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": e.to_string()}))).into_response(),
     }
 }
@@ -813,6 +2367,33 @@ fn uuid_simple() -> String {
     format!("{:x}{:08x}", ts, rand_part as u32)
 }
 
+/// Trim and upper-case country codes, dropping empty ones.
+fn normalize_countries(codes: &[String]) -> Vec<String> {
+    codes
+        .iter()
+        .map(|c| c.trim().to_uppercase())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// Trim a configured cookie domain, dropping a leading dot (browsers ignore
+/// it) and treating an empty value as unset.
+fn normalize_cookie_domain(domain: Option<&str>) -> Option<String> {
+    domain
+        .map(|d| d.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+}
+
+/// Origins are compared with the browser's `Origin` header, which never
+/// has a trailing slash and uses a lowercase scheme and host.
+fn normalize_origins(origins: &[String]) -> Vec<String> {
+    origins
+        .iter()
+        .map(|o| o.trim().trim_end_matches('/').to_ascii_lowercase())
+        .filter(|o| !o.is_empty())
+        .collect()
+}
+
 // ---------------------------------------------------------------------------
 // Helpers
 // ---------------------------------------------------------------------------
@@ -822,6 +2403,54 @@ fn uuid_simple() -> String {
 // ---------------------------------------------------------------------------
 
 /// `GET /api/fortress/ip-reputation`
+/// `GET /api/fortress/challenges/stats`
+///
+/// Challenge pages issued vs solved since startup, the IPs currently
+/// holding the most unanswered challenges, and the last `hours` (default
+/// 24) of issues, verification attempts, solves, failures and time to
+/// solve per protection level and service, summed per level in `levels`.
+/// `service` narrows the breakdown to one service.
+pub async fn get_challenge_stats(
+    State(state): State<AppState>,
+    Query(params): Query<ChallengeStatsParams>,
+) -> Json<Value> {
+    let limit = params.limit.unwrap_or(50);
+    let (issued, solved, suppressed) = state.memory.challenge_counters();
+    let solve_rate = if issued > 0 {
+        solved as f64 / issued as f64
+    } else {
+        0.0
+    };
+
+    let ips: Vec<Value> = state
+        .memory
+        .top_unanswered_challenges(limit)
+        .into_iter()
+        .map(|(ip, unanswered)| {
+            json!({
+                "ip": ip.to_string(),
+                "unanswered": unanswered,
+            })
+        })
+        .collect();
+
+    let hourly = state
+        .metrics
+        .challenges()
+        .hourly(params.hours.unwrap_or(HOURS_KEPT), params.service.as_deref());
+    let levels = ChallengeStats::totals_by_level(&hourly);
+
+    Json(json!({
+        "issued": issued,
+        "solved": solved,
+        "suppressed": suppressed,
+        "solve_rate": solve_rate,
+        "ips": ips,
+        "levels": levels,
+        "hourly": hourly,
+    }))
+}
+
 pub async fn get_ip_reputation(
     State(state): State<AppState>,
     Query(params): Query<TopParams>,
@@ -853,6 +2482,17 @@ pub async fn get_ip_reputation(
     }))
 }
 
+/// `GET /api/fortress/ip-reputation/feeds`: size and last refresh of the
+/// Tor exit list and each proxy feed.
+pub async fn get_ip_reputation_feeds(State(state): State<AppState>) -> Json<Value> {
+    let config = &state.settings.load().ip_reputation;
+    Json(json!({
+        "offline": config.offline,
+        "refresh_interval_secs": config.feed_refresh_secs,
+        "feeds": state.ip_reputation.feed_status(),
+    }))
+}
+
 // ---------------------------------------------------------------------------
 // Auto-Ban
 // ---------------------------------------------------------------------------
@@ -884,6 +2524,33 @@ pub async fn get_auto_bans(State(state): State<AppState>) -> Json<Value> {
     }))
 }
 
+/// `DELETE /api/fortress/auto-bans?subnet=1.2.3.0/24`
+///
+/// Lifts every active auto-ban within the range.
+pub async fn unban_subnet(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Query(params): Query<UnbanParams>,
+) -> impl IntoResponse {
+    let Some(subnet) = params.subnet.as_deref() else {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": "Missing subnet parameter"})));
+    };
+    let net = match subnet.trim().parse::<ipnet::IpNet>() {
+        Ok(net) => net.trunc(),
+        Err(_) => return (StatusCode::BAD_REQUEST, Json(json!({"error": "Invalid CIDR range"}))),
+    };
+
+    let unbanned = state.auto_ban.unban_subnet(&net, &actor);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "subnet": net.to_string(),
+            "unbanned": unbanned.len(),
+            "ips": unbanned.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+        })),
+    )
+}
+
 /// `GET /api/fortress/ip-lookup/{ip}`
 pub async fn get_ip_info(
     State(state): State<AppState>,
@@ -895,7 +2562,17 @@ pub async fn get_ip_info(
             let city = state.geoip.lookup_city(addr);
             let asn_info = state.geoip.lookup_asn(addr);
             let reputation = state.ip_reputation.get_score(&addr);
+            let categories = state.ip_reputation.get_categories(&addr);
             let ban_reason = state.auto_ban.is_banned(&addr);
+            let policy = state.pipeline.memory.ip_policy_entry(&addr).map(|(network, entry)| {
+                json!({
+                    "id": entry.id,
+                    "network": network.to_string(),
+                    "policy": entry.policy,
+                    "reason": entry.reason,
+                    "expires_at": entry.expires_at,
+                })
+            });
 
             (StatusCode::OK, Json(json!({
                 "ip": ip,
@@ -904,8 +2581,10 @@ pub async fn get_ip_info(
                 "asn": asn_info.as_ref().map(|(asn, _)| *asn),
                 "asn_org": asn_info.as_ref().map(|(_, org)| org.clone()),
                 "reputation_score": reputation,
+                "reputation_categories": categories,
                 "is_banned": ban_reason.is_some(),
                 "ban_reason": ban_reason,
+                "ip_policy": policy,
             }))).into_response()
         }
         Err(_) => {
@@ -915,13 +2594,22 @@ pub async fn get_ip_info(
 }
 
 /// `DELETE /api/fortress/auto-bans/{ip}`
+///
+/// Also lifts an offload to the kernel firewall set after repeated L4
+/// drops, which comes without an auto-ban.
 pub async fn unban_ip(
     State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
     Path(ip): Path<String>,
 ) -> impl IntoResponse {
     match ip.parse::<std::net::IpAddr>() {
         Ok(addr) => {
-            if state.auto_ban.unban(&addr) {
+            let unbanned = state.auto_ban.unban(&addr, &actor);
+            let lifted = !unbanned && state.firewall.remove(&addr);
+            if lifted {
+                state.sqlite.audit(&actor, "unban", "firewall", &addr.to_string(), None);
+            }
+            if unbanned || lifted {
                 (StatusCode::OK, Json(json!({"message": "IP unbanned"}))).into_response()
             } else {
                 (StatusCode::NOT_FOUND, Json(json!({"error": "IP not found in ban list"}))).into_response()
@@ -933,51 +2621,532 @@ pub async fn unban_ip(
     }
 }
 
+/// `GET /api/fortress/firewall/status`
+///
+/// How many IPs Fortress currently manages in the kernel firewall set,
+/// with its command counters and last error.
+pub async fn get_firewall_status(State(state): State<AppState>) -> Json<Value> {
+    let settings = state.settings.load();
+    let config = &settings.firewall;
+    Json(json!({
+        "status": state.firewall.status(),
+        "max_commands_per_sec": config.max_commands_per_sec,
+        "l4_drop_threshold": config.l4_drop_threshold,
+        "l4_drop_window_secs": config.l4_drop_window_secs,
+    }))
+}
+
 // ---------------------------------------------------------------------------
-// Managed Rules
+// GeoIP
 // ---------------------------------------------------------------------------
 
-/// `GET /api/fortress/managed-rules`
-pub async fn get_managed_rules(State(state): State<AppState>) -> Json<Value> {
-    let rules = state.managed_rules.get_rules();
-
-    let rules_list: Vec<Value> = rules.iter().map(|(id, name, desc, enabled)| {
-        json!({
-            "id": id,
-            "name": name,
-            "description": desc,
-            "enabled": enabled,
-        })
-    }).collect();
-
-    Json(json!({ "rules": rules_list }))
+/// `POST /api/fortress/geoip/reload`
+///
+/// Reopens the MaxMind databases from the configured paths and returns the
+/// resulting load state. A file that fails to open keeps its previous reader.
+pub async fn reload_geoip(State(state): State<AppState>) -> impl IntoResponse {
+    let config = state.settings.load().geoip.clone();
+    let geoip = state.geoip.clone();
+    match tokio::task::spawn_blocking(move || geoip.reload(&config.city_db, &config.asn_db)).await {
+        Ok(status) => (StatusCode::OK, Json(json!(status))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("GeoIP reload failed: {}", e) })),
+        ),
+    }
 }
 
-/// `PUT /api/fortress/managed-rules/{id}`
-pub async fn toggle_managed_rule(
+// ---------------------------------------------------------------------------
+// Alerting
+// ---------------------------------------------------------------------------
+
+/// `POST /api/fortress/alerts/test`
+///
+/// Sends a test alert to every configured channel, even when alerting is
+/// disabled, and reports the outcome per channel.
+pub async fn test_alerts(
     State(state): State<AppState>,
-    Path(id): Path<u32>,
-    Json(body): Json<Value>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
 ) -> impl IntoResponse {
-    let enabled = body.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true);
-
-    if state.managed_rules.set_rule_enabled(id, enabled) {
-        (StatusCode::OK, Json(json!({"message": "Rule updated", "id": id, "enabled": enabled}))).into_response()
-    } else {
-        (StatusCode::BAD_REQUEST, Json(json!({"error": "Invalid rule ID"}))).into_response()
+    let results = state.alerting.send_test(&actor).await;
+    if results.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "No alert channels configured" })),
+        );
     }
+    (StatusCode::OK, Json(json!({ "results": results })))
 }
 
 // ---------------------------------------------------------------------------
-// Distributed Attack Detection
+// Cluster sync
 // ---------------------------------------------------------------------------
 
-/// `GET /api/fortress/distributed-attacks`
-pub async fn get_distributed_attacks(State(state): State<AppState>) -> Json<Value> {
-    let (total, unique_ips, new_ips, active) = state.distributed.get_stats();
-    let last_attack = state.distributed.get_last_attack();
+/// `POST /api/fortress/cluster/sync`
+///
+/// Receives a batch of bans, blocks and level changes from a peer. Not
+/// covered by the API key: batches are authenticated with an HMAC of the
+/// body under `cluster.shared_secret`. Events older than what this node
+/// already has for the same IP (or level) are ignored.
+pub async fn cluster_sync(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let config = state.settings.load().cluster.clone();
+    if !config.enabled {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Clustering is disabled" })));
+    }
+    if config.shared_secret.is_empty() {
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "No cluster secret configured" })));
+    }
 
-    let attack_info = last_attack.map(|a| {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let timestamp = match header(cluster::TIMESTAMP_HEADER).and_then(|v| v.parse::<i64>().ok()) {
+        Some(ts) if (Utc::now().timestamp() - ts).abs() <= cluster::MAX_CLOCK_SKEW_SECS => ts,
+        _ => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Missing or stale timestamp" })));
+        }
+    };
+    let expected = cluster::sign(&config.shared_secret, timestamp, &body);
+    let signature = header(cluster::SIGNATURE_HEADER).unwrap_or("");
+    if !constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid signature" })));
+    }
+
+    let batch: SyncBatch = match serde_json::from_slice(&body) {
+        Ok(b) => b,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": format!("Invalid batch: {}", e) })));
+        }
+    };
+
+    let mut applied = 0;
+    let mut ignored = 0;
+    let mut errors = Vec::new();
+    for event in &batch.events {
+        if !state.cluster.accept(event) {
+            ignored += 1;
+            continue;
+        }
+        match cluster::apply(event, &state.auto_ban, &state.blocklist, &state.escalation).await {
+            Ok(()) => applied += 1,
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        tracing::warn!(peer = %batch.node_id, errors = errors.len(), "Some cluster events failed to apply");
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "node_id": state.cluster.node_id(),
+            "applied": applied,
+            "ignored": ignored,
+            "errors": errors,
+        })),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// TLS certificates
+// ---------------------------------------------------------------------------
+
+/// Certificates expiring within this many days are flagged in the listing.
+const CERT_EXPIRY_WARNING_DAYS: i64 = 14;
+
+/// Body of `POST /api/fortress/services/:id/certificate`.
+#[derive(Debug, Deserialize)]
+pub struct CertificateUploadRequest {
+    /// PEM certificate chain, leaf first.
+    pub certificate: String,
+    /// PEM private key of the leaf certificate.
+    pub private_key: String,
+    /// One of the service's domains; its first domain when omitted.
+    pub domain: Option<String>,
+}
+
+/// `GET /api/fortress/certificates`
+///
+/// Domains with a loaded certificate and when it expires. Private keys are
+/// never returned.
+pub async fn list_certificates(State(state): State<AppState>) -> Json<Value> {
+    let now = Utc::now();
+    let certificates: Vec<Value> = state
+        .cert_resolver
+        .as_ref()
+        .map(|resolver| resolver.certificates())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(domain, expires_at)| {
+            let days_left = expires_at.map(|t| (t - now).num_days());
+            json!({
+                "domain": domain,
+                "expires_at": expires_at,
+                "days_left": days_left,
+                "expiring_soon": days_left.is_some_and(|days| days < CERT_EXPIRY_WARNING_DAYS),
+            })
+        })
+        .collect();
+    Json(json!({ "certificates": certificates }))
+}
+
+/// `POST /api/fortress/services/:id/certificate`
+///
+/// Install a certificate for one of the service's domains. It is written
+/// to `tls.cert_dir/<domain>/` and served from the next handshake on.
+pub async fn upload_service_certificate(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<String>,
+    Json(body): Json<CertificateUploadRequest>,
+) -> impl IntoResponse {
+    let error = |status: StatusCode, msg: String| (status, Json(json!({ "error": msg })));
+    let Some(resolver) = state.cert_resolver.clone() else {
+        return error(StatusCode::CONFLICT, "HTTPS is not served (server.mode = \"http\")".to_string());
+    };
+    let Some(service) = state.service_router.get_service(&id) else {
+        return error(StatusCode::NOT_FOUND, "Service not found".to_string());
+    };
+
+    let domain = body
+        .domain
+        .as_deref()
+        .or(service.domains.first().map(String::as_str))
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if !service.domains.iter().any(|d| d.eq_ignore_ascii_case(&domain)) {
+        return error(StatusCode::BAD_REQUEST, format!("'{}' is not a domain of this service", domain));
+    }
+    // The domain names a directory under tls.cert_dir.
+    let valid_name = domain.split('.').all(|label| {
+        !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if !valid_name {
+        return error(StatusCode::BAD_REQUEST, format!("'{}' is not a valid certificate domain", domain));
+    }
+
+    let key = match parse_certificate_upload(&body.certificate, &body.private_key, Utc::now()) {
+        Ok(key) => key,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let expires_at = key.end_entity_cert().ok().and_then(|der| certificate_not_after(der));
+
+    let cert_dir = state.settings.load().tls.cert_dir.clone();
+    let (dir_domain, certificate, private_key) = (domain.clone(), body.certificate, body.private_key);
+    let stored = tokio::task::spawn_blocking(move || {
+        store_certificate(&cert_dir, &dir_domain, &certificate, &private_key)
+    })
+    .await;
+    match stored {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store certificate: {}", e))
+        }
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+
+    resolver.insert(&domain, key);
+    state.sqlite.audit(&actor, "upload", "certificate", &domain, Some(&id));
+    (
+        StatusCode::OK,
+        Json(json!({
+            "domain": domain,
+            "service_id": id,
+            "expires_at": expires_at,
+        })),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// Connections
+// ---------------------------------------------------------------------------
+
+/// Connections per IP in the `top_ips` summary.
+const TOP_CONNECTION_IPS: usize = 10;
+
+/// `GET /api/fortress/connections`
+///
+/// Pages through the active client connections, oldest first, filtered by
+/// `ip`, `host`, `min_age` and `min_requests`. `top_ips` ranks IPs by
+/// connection count across all connections, ignoring the filters.
+pub async fn get_connections(
+    State(state): State<AppState>,
+    Query(params): Query<ConnectionsParams>,
+) -> impl IntoResponse {
+    let ip_filter = match params.ip.as_deref().map(str::parse::<std::net::IpAddr>).transpose() {
+        Ok(ip) => ip,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Invalid IP address" })));
+        }
+    };
+
+    let all = state.connections.get_all();
+
+    let mut per_ip: HashMap<std::net::IpAddr, (u64, u64)> = HashMap::new();
+    for conn in &all {
+        let entry = per_ip.entry(conn.client_ip).or_default();
+        entry.0 += 1;
+        entry.1 += conn.requests;
+    }
+    let mut top_ips: Vec<_> = per_ip.into_iter().collect();
+    top_ips.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(b.1 .1.cmp(&a.1 .1)));
+    top_ips.truncate(TOP_CONNECTION_IPS);
+
+    let mut matching: Vec<_> = all
+        .into_iter()
+        .filter(|c| ip_filter.is_none_or(|ip| c.client_ip == ip))
+        .filter(|c| {
+            params.host.as_deref().is_none_or(|host| {
+                c.host.as_deref().is_some_and(|h| h.contains(host))
+            })
+        })
+        .filter(|c| c.connected_at_secs_ago >= params.min_age.unwrap_or(0))
+        .filter(|c| c.requests >= params.min_requests.unwrap_or(0))
+        .collect();
+    matching.sort_by_key(|c| c.id);
+
+    let total = matching.len();
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(50).clamp(1, 500);
+    let connections: Vec<Value> = matching
+        .iter()
+        .skip(((page - 1) * per_page) as usize)
+        .take(per_page as usize)
+        .map(|c| {
+            json!({
+                "id": c.id,
+                "client_ip": c.client_ip.to_string(),
+                "connected_at": c.connected_at.to_rfc3339(),
+                "age_secs": c.connected_at_secs_ago,
+                "idle_secs": c.idle_secs,
+                "requests": c.requests,
+                "bytes_in": c.bytes_received,
+                "bytes_out": c.bytes_sent,
+                "ja3": c.ja3_hash,
+                "host": c.host,
+                "tls": c.tls,
+                "upgraded": c.upgraded,
+            })
+        })
+        .collect();
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "connections": connections,
+            "total": total,
+            "page": page,
+            "per_page": per_page,
+            "top_ips": top_ips.iter().map(|(ip, (count, requests))| json!({
+                "ip": ip.to_string(),
+                "connections": count,
+                "requests": requests,
+            })).collect::<Vec<_>>(),
+        })),
+    )
+}
+
+/// `GET /api/fortress/connections/top-bandwidth`
+///
+/// IPs ranked by their transfer rate over the last few seconds, summed
+/// across their connections, with whether `protection.max_bytes_per_ip_per_sec`
+/// applies to them.
+pub async fn get_top_bandwidth(
+    State(state): State<AppState>,
+    Query(params): Query<TopParams>,
+) -> Json<Value> {
+    let limit = params.limit.unwrap_or(50);
+    let settings = state.settings.load();
+    let cap = settings.protection.max_bytes_per_ip_per_sec;
+
+    let ips: Vec<Value> = state
+        .connections
+        .top_bandwidth(limit)
+        .iter()
+        .map(|entry| {
+            let exempt = settings.protection.whitelist.contains(&entry.client_ip);
+            json!({
+                "ip": entry.client_ip.to_string(),
+                "bytes_out_per_sec": entry.bytes_sent_per_sec,
+                "bytes_in_per_sec": entry.bytes_received_per_sec,
+                "connections": entry.connections,
+                "capped": cap > 0 && !exempt,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "window_secs": BANDWIDTH_WINDOW_SECS,
+        "max_bytes_per_ip_per_sec": cap,
+        "ips": ips,
+    }))
+}
+
+/// `DELETE /api/fortress/connections/{id}`
+///
+/// Forcibly closes one connection, including an upgraded WebSocket.
+pub async fn close_connection(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    if !state.connections.close(id) {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Connection not found" })));
+    }
+    state.sqlite.audit(&actor, "close", "connection", &id.to_string(), None);
+    (StatusCode::OK, Json(json!({ "status": "closed", "closed": 1 })))
+}
+
+/// `DELETE /api/fortress/connections?ip=x.x.x.x`
+///
+/// Forcibly closes every connection from an IP.
+pub async fn close_connections(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Query(params): Query<CloseConnectionsParams>,
+) -> impl IntoResponse {
+    let Some(ip) = params.ip.as_deref().and_then(|ip| ip.parse::<std::net::IpAddr>().ok()) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Missing or invalid ip" })));
+    };
+    let closed = state.connections.close_by_ip(&ip);
+    if closed > 0 {
+        state.sqlite.audit(&actor, "close", "connection", &ip.to_string(), Some(&format!("{} connections", closed)));
+    }
+    (StatusCode::OK, Json(json!({ "status": "closed", "closed": closed })))
+}
+
+// ---------------------------------------------------------------------------
+// Audit log
+// ---------------------------------------------------------------------------
+
+/// `GET /api/fortress/audit`
+///
+/// Pages through ban/unban, blocklist and service changes, newest first.
+/// `from`/`to` are RFC 3339 timestamps; `action`, `target` and
+/// `target_type` match exactly.
+pub async fn get_audit(
+    State(state): State<AppState>,
+    Query(params): Query<AuditParams>,
+) -> impl IntoResponse {
+    let (from, to) = match (parse_rfc3339(&params.from), parse_rfc3339(&params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
+        }
+    };
+
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(50).clamp(1, 500);
+    let filter = AuditFilter {
+        from,
+        to,
+        action: params.action,
+        target_type: params.target_type,
+        target: params.target,
+    };
+
+    match state
+        .sqlite
+        .get_audit(&filter, per_page as usize, ((page - 1) * per_page) as usize)
+        .await
+    {
+        Ok((entries, total)) => Json(json!({
+            "entries": entries,
+            "total": total,
+            "page": page,
+            "per_page": per_page,
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to load audit log: {}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Managed Rules
+// ---------------------------------------------------------------------------
+
+/// `GET /api/fortress/managed-rules`
+///
+/// Lists every rule with its effective parameters (defaults included).
+pub async fn get_managed_rules(State(state): State<AppState>) -> Json<Value> {
+    let rules_list: Vec<Value> = state
+        .managed_rules
+        .get_rules()
+        .into_iter()
+        .map(|rule| {
+            let overridden = state.managed_rules.is_overridden(rule.id);
+            let mut value = json!(rule);
+            value["overridden"] = json!(overridden);
+            value
+        })
+        .collect();
+
+    Json(json!({ "rules": rules_list }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateManagedRuleRequest {
+    /// Kept as is when omitted.
+    pub enabled: Option<bool>,
+    /// Replaces the rule's parameter overrides; fields left out go back to
+    /// their defaults. Kept as is when omitted.
+    pub params: Option<RuleParams>,
+}
+
+/// `PUT /api/fortress/managed-rules/{id}`
+///
+/// Changes are saved and survive restarts.
+pub async fn toggle_managed_rule(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<u32>,
+    Json(body): Json<UpdateManagedRuleRequest>,
+) -> impl IntoResponse {
+    let (enabled, params) = match state.managed_rules.update_rule(id, body.enabled, body.params) {
+        Ok(updated) => updated,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response(),
+    };
+    let params_json = serde_json::to_string(&params).unwrap_or_else(|_| "{}".to_string());
+    if let Err(e) = state.sqlite.set_managed_rule_setting(id, enabled, &params_json).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to save rule settings: {}", e) })),
+        )
+            .into_response();
+    }
+    let action = if enabled { "enable" } else { "disable" };
+    state.sqlite.audit(&actor, action, "managed_rule", &id.to_string(), Some(&params_json));
+
+    let rule = state.managed_rules.get_rules().into_iter().find(|r| r.id == id);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "message": "Rule updated",
+            "id": id,
+            "enabled": enabled,
+            "params": rule.map(|r| r.params),
+        })),
+    )
+        .into_response()
+}
+
+// ---------------------------------------------------------------------------
+// Distributed Attack Detection
+// ---------------------------------------------------------------------------
+
+/// `GET /api/fortress/distributed-attacks`
+pub async fn get_distributed_attacks(State(state): State<AppState>) -> Json<Value> {
+    let (total, unique_ips, new_ips, active) = state.distributed.get_stats();
+    let last_attack = state.distributed.get_last_attack();
+
+    let attack_info = last_attack.map(|a| {
         json!({
             "signals": a.signals,
             "top_path": a.top_path,
@@ -995,9 +3164,396 @@ pub async fn get_distributed_attacks(State(state): State<AppState>) -> Json<Valu
             "attack_active": active,
         },
         "last_attack": attack_info,
+        "mitigations": state.distributed.list_mitigations(),
+    }))
+}
+
+/// `GET /api/fortress/distributed/mitigations`
+pub async fn list_mitigations(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({ "mitigations": state.distributed.list_mitigations() }))
+}
+
+/// `DELETE /api/fortress/distributed/mitigations`
+///
+/// Lifts every mitigation; none are re-created until the attack subsides.
+pub async fn clear_mitigations(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+) -> Json<Value> {
+    let removed = state.distributed.clear_mitigations();
+    state.sqlite.audit(&actor, "delete", "distributed_mitigation", "*", None);
+    Json(json!({ "removed": removed }))
+}
+
+/// `DELETE /api/fortress/distributed/mitigations/{id}`
+pub async fn delete_mitigation(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    if !state.distributed.remove_mitigation(id) {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Mitigation not found"})));
+    }
+    state.sqlite.audit(&actor, "delete", "distributed_mitigation", &id.to_string(), None);
+    (StatusCode::OK, Json(json!({"status": "deleted"})))
+}
+
+// ---------------------------------------------------------------------------
+// Response cache
+// ---------------------------------------------------------------------------
+
+/// `GET /api/fortress/cache/stats`
+pub async fn get_cache_stats(State(state): State<AppState>) -> Json<Value> {
+    Json(json!(state.response_cache.stats()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CachePurgeParams {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+}
+
+/// `DELETE /api/fortress/cache?host=&path_prefix=`
+///
+/// Without parameters the whole cache is purged.
+pub async fn purge_cache(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Query(params): Query<CachePurgeParams>,
+) -> Json<Value> {
+    let host = params.host.as_deref().filter(|h| !h.is_empty());
+    let path_prefix = params.path_prefix.as_deref().filter(|p| !p.is_empty());
+    let removed = state.response_cache.purge(host, path_prefix);
+    let target = format!("{}{}", host.unwrap_or("*"), path_prefix.unwrap_or(""));
+    state.sqlite.audit(&actor, "purge", "cache", &target, Some(&format!("{} entries", removed)));
+    Json(json!({ "removed": removed }))
+}
+
+// ---------------------------------------------------------------------------
+// API tokens
+// ---------------------------------------------------------------------------
+
+/// Body of `POST` and `PUT /api/fortress/tokens`. On update, omitted
+/// fields keep their value and `ttl_secs: 0` removes the expiry.
+#[derive(Debug, Deserialize)]
+pub struct ApiTokenRequest {
+    pub name: Option<String>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub allowed_paths: Option<Vec<String>>,
+    /// `bypass_challenge` (default) or `bypass_pipeline`.
+    pub action: Option<String>,
+    pub exempt_rate_limit: Option<bool>,
+    pub ttl_secs: Option<u64>,
+}
+
+impl ApiTokenRequest {
+    /// Apply the request on top of `base` (the defaults when minting).
+    fn spec(self, base: TokenSpec) -> Result<TokenSpec, (StatusCode, Json<Value>)> {
+        let bad_request = |msg: String| (StatusCode::BAD_REQUEST, Json(json!({ "error": msg })));
+        let action = match self.action.as_deref() {
+            Some(name) => TokenAction::from_str_name(name)
+                .ok_or_else(|| bad_request(format!("Unknown action: {}", name)))?,
+            None => base.action,
+        };
+        let name = self.name.map(|n| n.trim().to_string()).unwrap_or(base.name);
+        if name.is_empty() {
+            return Err(bad_request("name is required".to_string()));
+        }
+        let allowed_paths = self.allowed_paths.unwrap_or(base.allowed_paths);
+        if let Some(path) = allowed_paths.iter().find(|p| !p.starts_with('/')) {
+            return Err(bad_request(format!("Path prefix must start with '/': {}", path)));
+        }
+        let expires_at = match self.ttl_secs {
+            Some(0) => None,
+            Some(secs) => Some(Utc::now() + ChronoDuration::seconds(secs.min(i64::MAX as u64) as i64)),
+            None => base.expires_at,
+        };
+        Ok(TokenSpec {
+            name,
+            allowed_hosts: self
+                .allowed_hosts
+                .map(|hosts| hosts.iter().map(|h| h.trim().to_ascii_lowercase()).filter(|h| !h.is_empty()).collect())
+                .unwrap_or(base.allowed_hosts),
+            allowed_paths,
+            action,
+            exempt_rate_limit: self.exempt_rate_limit.unwrap_or(base.exempt_rate_limit),
+            expires_at,
+        })
+    }
+}
+
+/// `GET /api/fortress/tokens`
+pub async fn list_api_tokens(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({ "tokens": state.pipeline.api_tokens.list() }))
+}
+
+/// `POST /api/fortress/tokens`
+///
+/// The response carries the token itself; it is not stored and cannot be
+/// retrieved again.
+pub async fn create_api_token(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Json(body): Json<ApiTokenRequest>,
+) -> impl IntoResponse {
+    let base = TokenSpec {
+        name: String::new(),
+        allowed_hosts: Vec::new(),
+        allowed_paths: Vec::new(),
+        action: TokenAction::BypassChallenge,
+        exempt_rate_limit: false,
+        expires_at: None,
+    };
+    let spec = match body.spec(base) {
+        Ok(spec) => spec,
+        Err(response) => return response,
+    };
+    match state.pipeline.api_tokens.mint(spec).await {
+        Ok((summary, token)) => {
+            let detail = format!("{} ({})", summary.name, summary.action.as_str());
+            state.sqlite.audit(&actor, "create", "api_token", &summary.id, Some(&detail));
+            let mut value = json!(summary);
+            value["token"] = json!(token);
+            (StatusCode::OK, Json(value))
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("{}", e) }))),
+    }
+}
+
+/// `PUT /api/fortress/tokens/:id`
+pub async fn update_api_token(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<String>,
+    Json(body): Json<ApiTokenRequest>,
+) -> impl IntoResponse {
+    let Some(current) = state.pipeline.api_tokens.get(&id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Token not found" })));
+    };
+    let base = TokenSpec {
+        name: current.name,
+        allowed_hosts: current.allowed_hosts,
+        allowed_paths: current.allowed_paths,
+        action: current.action,
+        exempt_rate_limit: current.exempt_rate_limit,
+        expires_at: current.expires_at,
+    };
+    let spec = match body.spec(base) {
+        Ok(spec) => spec,
+        Err(response) => return response,
+    };
+    match state.pipeline.api_tokens.update(&id, spec).await {
+        Ok(Some(summary)) => {
+            let detail = format!("{} ({})", summary.name, summary.action.as_str());
+            state.sqlite.audit(&actor, "update", "api_token", &id, Some(&detail));
+            (StatusCode::OK, Json(json!(summary)))
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({ "error": "Token not found" }))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("{}", e) }))),
+    }
+}
+
+/// `DELETE /api/fortress/tokens/:id`
+///
+/// Revocation is immediate: the next request with the token is treated as
+/// if it carried none.
+pub async fn revoke_api_token(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    match state.pipeline.api_tokens.revoke(&id).await {
+        Ok(true) => {
+            state.sqlite.audit(&actor, "revoke", "api_token", &id, None);
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// `GET /api/fortress/tokens/:id/usage`
+pub async fn get_api_token_usage(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.pipeline.api_tokens.get(&id) {
+        Some(token) => (
+            StatusCode::OK,
+            Json(json!({
+                "id": token.id,
+                "name": token.name,
+                "requests": token.requests,
+                "last_used_at": token.last_used_at,
+                "expires_at": token.expires_at,
+                "expired": token.expires_at.is_some_and(|exp| exp <= Utc::now()),
+            })),
+        ),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "Token not found" }))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// IP policies
+// ---------------------------------------------------------------------------
+
+/// Body of `POST /api/fortress/ip-policies`.
+#[derive(Debug, Deserialize)]
+pub struct IpPolicyRequest {
+    /// IP address or CIDR range. Setting a policy for a network that has
+    /// one replaces it.
+    pub value: String,
+    /// `never_challenge`, `always_challenge` or `bypass_rate_limit`.
+    pub policy: String,
+    pub reason: Option<String>,
+    pub ttl_secs: Option<u64>,
+}
+
+/// `GET /api/fortress/ip-policies`
+pub async fn list_ip_policies(State(state): State<AppState>) -> impl IntoResponse {
+    match state.pipeline.ip_policies.list().await {
+        Ok(policies) => (StatusCode::OK, Json(json!({ "policies": policies }))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))),
+    }
+}
+
+/// `POST /api/fortress/ip-policies`
+pub async fn set_ip_policy(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Json(body): Json<IpPolicyRequest>,
+) -> impl IntoResponse {
+    let Some(policy) = IpPolicy::from_str_name(&body.policy) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Unknown policy '{}'", body.policy) })),
+        );
+    };
+    if parse_ip_or_cidr(&body.value).is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Invalid IP address or CIDR range: {}", body.value) })),
+        );
+    }
+    let reason = body.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    let ttl = body.ttl_secs.filter(|&secs| secs > 0).map(std::time::Duration::from_secs);
+    match state.pipeline.ip_policies.set(&body.value, policy, reason, ttl, &actor).await {
+        Ok(row) => (StatusCode::OK, Json(json!(row))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))),
+    }
+}
+
+/// `DELETE /api/fortress/ip-policies/:id`
+pub async fn delete_ip_policy(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match state.pipeline.ip_policies.remove(id, &actor).await {
+        Ok(Some(_)) => StatusCode::NO_CONTENT,
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ASN classification
+// ---------------------------------------------------------------------------
+
+/// Body of `PUT /api/fortress/asn/:number/override`.
+#[derive(Debug, Deserialize)]
+pub struct AsnOverrideRequest {
+    /// `datacenter`, `vpn`, `residential_proxy`, `mobile`, `residential` or
+    /// `unknown`.
+    pub category: String,
+    pub reason: Option<String>,
+}
+
+/// `GET /api/fortress/asn`
+pub async fn get_asn_classification(State(state): State<AppState>) -> Json<Value> {
+    let classifier = &state.pipeline.asn_classifier;
+    Json(json!({
+        "dataset": classifier.dataset_status(),
+        "overrides": classifier.overrides(),
+    }))
+}
+
+/// `GET /api/fortress/asn/:number`
+///
+/// Effective classification of one ASN and what it adds to the threat score.
+pub async fn get_asn(State(state): State<AppState>, Path(asn): Path<u32>) -> Json<Value> {
+    let classifier = &state.pipeline.asn_classifier;
+    let (asn_type, source) = classifier.lookup(asn);
+    let score = classifier.suspicion_score(asn, &state.settings.load().asn_scoring);
+    Json(json!({
+        "asn": asn,
+        "category": asn_type.as_str(),
+        "source": source,
+        "score": score,
+        "override": classifier.get_override(asn),
     }))
 }
 
+/// `POST /api/fortress/asn/reload`
+///
+/// Reload `asn_scoring.dataset` now instead of waiting for the next refresh.
+pub async fn reload_asn_dataset(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+) -> impl IntoResponse {
+    let source = state.settings.load().asn_scoring.dataset.clone();
+    let status = state.pipeline.asn_classifier.refresh_dataset(&source).await;
+    state.sqlite.audit(&actor, "reload", "asn_dataset", &source, status.last_error.as_deref());
+    let code = if status.last_error.is_some() { StatusCode::BAD_GATEWAY } else { StatusCode::OK };
+    (code, Json(json!(status)))
+}
+
+/// `PUT /api/fortress/asn/:number/override`
+pub async fn set_asn_override(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(asn): Path<u32>,
+    Json(body): Json<AsnOverrideRequest>,
+) -> impl IntoResponse {
+    let Some(asn_type) = AsnType::from_str_name(&body.category) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Unknown category '{}'", body.category) })),
+        );
+    };
+    let row = AsnOverrideRow {
+        asn,
+        category: asn_type.as_str().to_string(),
+        reason: body.reason.filter(|r| !r.trim().is_empty()),
+        created_at: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+    };
+    if let Err(e) = state.sqlite.upsert_asn_override(row.clone()).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() })));
+    }
+    let _ = state.pipeline.asn_classifier.set_override(row.clone());
+    state
+        .sqlite
+        .audit(&actor, "override", "asn", &asn.to_string(), Some(&row.category));
+    (StatusCode::OK, Json(json!(row)))
+}
+
+/// `DELETE /api/fortress/asn/:number/override`
+pub async fn delete_asn_override(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(asn): Path<u32>,
+) -> StatusCode {
+    match state.sqlite.delete_asn_override(asn).await {
+        Ok(true) => {
+            state.pipeline.asn_classifier.remove_override(asn);
+            state.sqlite.audit(&actor, "delete", "asn_override", &asn.to_string(), None);
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Threat Summary
 // ---------------------------------------------------------------------------
@@ -1029,55 +3585,372 @@ pub async fn get_threat_summary(State(state): State<AppState>) -> Json<Value> {
     }))
 }
 
+/// Seconds browsers and CDNs may cache the public status response.
+const PUBLIC_STATUS_MAX_AGE_SECS: u64 = 10;
+
+/// `GET /api/fortress/public/status`
+///
+/// Unauthenticated summary for status pages, served only when
+/// `admin_api.public_status_enabled` is set. Request and block counts cover
+/// the last 24 hours, summed from the metrics history. Deliberately leaves
+/// out IPs, countries, rules and services.
+///
+/// The body is built at most once per `PUBLIC_STATUS_MAX_AGE_SECS` and
+/// served from memory in between, since every status page visitor may
+/// fetch it directly.
+pub async fn get_public_status(State(state): State<AppState>) -> impl IntoResponse {
+    if !state.settings.load().admin_api.public_status_enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    // Held across the build so concurrent misses wait for one load.
+    let mut cached = state.public_status.lock().await;
+    let max_age = std::time::Duration::from_secs(PUBLIC_STATUS_MAX_AGE_SECS);
+    let body = match cached.as_ref().filter(|(built, _)| built.elapsed() < max_age) {
+        Some((_, body)) => body.clone(),
+        None => match public_status_body(&state).await {
+            Ok(body) => {
+                *cached = Some((Instant::now(), body.clone()));
+                body
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to load metrics history for the public status");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+    };
+    drop(cached);
+    (
+        [(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", PUBLIC_STATUS_MAX_AGE_SECS),
+        )],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// The public status as of now, with the last 24 hours summed in hourly
+/// buckets.
+async fn public_status_body(state: &AppState) -> rusqlite::Result<Value> {
+    let to = history::unix_now();
+    let from = to.saturating_sub(24 * 3600);
+    let buckets = history::load_history(&state.metrics, &state.sqlite, from, to, 3600).await?;
+    let snapshot = state.metrics.get_snapshot();
+    Ok(json!({
+        "protection_level": level_name(state.escalation.current_level()),
+        "attack_active": state.distributed.is_attack_active(),
+        "rps": snapshot.rps.round() as u64,
+        "requests_24h": buckets.iter().map(|b| b.requests).sum::<u64>(),
+        "blocked_24h": buckets.iter().map(|b| b.blocked).sum::<u64>(),
+    }))
+}
+
 // ---------------------------------------------------------------------------
-// Helpers
+// Request capture
 // ---------------------------------------------------------------------------
 
-fn aggregate_snapshots(
-    snapshots: &[crate::analytics::collector::SecondSnapshot],
-    bucket_secs: u64,
-) -> Vec<Value> {
-    if snapshots.is_empty() {
-        return Vec::new();
-    }
-
-    let mut buckets: Vec<Value> = Vec::new();
-    let mut bucket_start = snapshots[0].timestamp / bucket_secs * bucket_secs;
-    let mut acc_requests: u64 = 0;
-    let mut acc_blocked: u64 = 0;
-    let mut acc_challenged: u64 = 0;
-    let mut acc_passed: u64 = 0;
-
-    for snap in snapshots {
-        let snap_bucket = snap.timestamp / bucket_secs * bucket_secs;
-        if snap_bucket != bucket_start {
-            buckets.push(json!({
-                "timestamp": bucket_start,
-                "requests": acc_requests,
-                "blocked": acc_blocked,
-                "challenged": acc_challenged,
-                "passed": acc_passed,
-            }));
-            bucket_start = snap_bucket;
-            acc_requests = 0;
-            acc_blocked = 0;
-            acc_challenged = 0;
-            acc_passed = 0;
+/// Body of `POST /api/fortress/capture`. Every filter given must match.
+#[derive(Debug, Deserialize)]
+pub struct CaptureRequest {
+    pub ip: Option<String>,
+    pub subnet: Option<String>,
+    pub path_prefix: Option<String>,
+    /// `passed`, `challenged` or `blocked`.
+    pub action: Option<String>,
+    /// Default 100.
+    pub max_requests: Option<usize>,
+    /// Default 60.
+    pub max_seconds: Option<u64>,
+    /// Default 4096; 0 keeps no body.
+    pub max_body_bytes: Option<usize>,
+    /// Default true: `Authorization`, `Proxy-Authorization` and the
+    /// clearance cookie are replaced.
+    pub redact: Option<bool>,
+}
+
+impl CaptureRequest {
+    fn spec(self) -> Result<CaptureSpec, (StatusCode, Json<Value>)> {
+        let bad_request = |msg: String| (StatusCode::BAD_REQUEST, Json(json!({ "error": msg })));
+        let ip = match self.ip.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            Some(value) => Some(value.parse::<std::net::IpAddr>().map_err(|_| bad_request(format!("Invalid IP: {}", value)))?),
+            None => None,
+        };
+        let subnet = match self.subnet.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            Some(value) => Some(value.parse::<ipnet::IpNet>().map_err(|_| bad_request(format!("Invalid subnet: {}", value)))?),
+            None => None,
+        };
+        let path_prefix = self.path_prefix.filter(|p| !p.is_empty());
+        if let Some(prefix) = path_prefix.as_deref().filter(|p| !p.starts_with('/')) {
+            return Err(bad_request(format!("Path prefix must start with '/': {}", prefix)));
+        }
+        let action = self.action.filter(|a| !a.is_empty());
+        if let Some(action) = action.as_deref().filter(|a| !matches!(*a, "passed" | "challenged" | "blocked")) {
+            return Err(bad_request(format!("Unknown action: {}", action)));
+        }
+        let max_requests = self.max_requests.unwrap_or(100);
+        if max_requests == 0 || max_requests > capture::MAX_REQUESTS {
+            return Err(bad_request(format!("max_requests must be between 1 and {}", capture::MAX_REQUESTS)));
         }
-        acc_requests += snap.requests;
-        acc_blocked += snap.blocked;
-        acc_challenged += snap.challenged;
-        acc_passed += snap.passed;
-    }
-
-    // Flush the final bucket.
-    buckets.push(json!({
-        "timestamp": bucket_start,
-        "requests": acc_requests,
-        "blocked": acc_blocked,
-        "challenged": acc_challenged,
-        "passed": acc_passed,
-    }));
-
-    buckets
+        let max_seconds = self.max_seconds.unwrap_or(60);
+        if max_seconds == 0 || max_seconds > capture::MAX_DURATION_SECS {
+            return Err(bad_request(format!("max_seconds must be between 1 and {}", capture::MAX_DURATION_SECS)));
+        }
+        let max_body_bytes = self.max_body_bytes.unwrap_or(4096);
+        if max_body_bytes > capture::MAX_BODY_BYTES {
+            return Err(bad_request(format!("max_body_bytes must be at most {}", capture::MAX_BODY_BYTES)));
+        }
+        Ok(CaptureSpec {
+            ip,
+            subnet,
+            path_prefix,
+            action,
+            max_requests,
+            duration: std::time::Duration::from_secs(max_seconds),
+            max_body_bytes,
+            redact: self.redact.unwrap_or(true),
+        })
+    }
+}
+
+/// `GET /api/fortress/capture`
+pub async fn list_captures(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({ "captures": state.request_capture.list() }))
+}
+
+/// `POST /api/fortress/capture`
+///
+/// Copies matching requests, with their headers and the start of the
+/// body, into memory until `max_requests` or `max_seconds` is reached.
+pub async fn start_capture(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Json(body): Json<CaptureRequest>,
+) -> impl IntoResponse {
+    let spec = match body.spec() {
+        Ok(spec) => spec,
+        Err(response) => return response,
+    };
+    let redact = spec.redact;
+    let Some(summary) = state.request_capture.start(spec) else {
+        let msg = format!("At most {} captures are kept; delete one first", capture::MAX_CAPTURES);
+        return (StatusCode::CONFLICT, Json(json!({ "error": msg })));
+    };
+    let detail = format!("{} requests, {}s{}", summary.max_requests, summary.max_seconds, if redact { "" } else { ", unredacted" });
+    state.sqlite.audit(&actor, "start", "capture", &summary.id.to_string(), Some(&detail));
+    (StatusCode::CREATED, Json(json!(summary)))
+}
+
+/// `GET /api/fortress/capture/:id`
+pub async fn get_capture(State(state): State<AppState>, Path(id): Path<u64>) -> impl IntoResponse {
+    match state.request_capture.get(id) {
+        Some((summary, requests)) => (StatusCode::OK, Json(json!({ "capture": summary, "requests": requests }))),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "Capture not found" }))),
+    }
+}
+
+/// `DELETE /api/fortress/capture/:id`
+///
+/// Stops the capture if it is still running and discards what it holds.
+pub async fn delete_capture(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    if !state.request_capture.remove(id) {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Capture not found" })));
+    }
+    state.sqlite.audit(&actor, "delete", "capture", &id.to_string(), None);
+    (StatusCode::OK, Json(json!({ "status": "deleted" })))
+}
+
+// ---------------------------------------------------------------------------
+// State export / import
+// ---------------------------------------------------------------------------
+
+/// `GET /api/fortress/export?persistent_only=true`
+///
+/// Services, custom rules, blocklist entries, managed rule settings and
+/// config keys as one JSON document, for backups and for copying the
+/// configuration to another instance.
+pub async fn export_state(
+    State(state): State<AppState>,
+    Query(params): Query<StateExportParams>,
+) -> impl IntoResponse {
+    match state.sqlite.export_state(params.persistent_only).await {
+        Ok(sections) => (
+            StatusCode::OK,
+            [(header::CONTENT_DISPOSITION, "attachment; filename=\"fortress-state.json\"")],
+            Json(json!(StateDocument { schema_version: STATE_SCHEMA_VERSION, sections })),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+/// `POST /api/fortress/import?mode=merge|replace`
+///
+/// Apply a document from `GET /api/fortress/export` in one transaction,
+/// then reload services, blocklists and rules. `merge` (the default)
+/// overwrites entries with the same key and keeps the rest; `replace`
+/// first empties every section present in the document.
+pub async fn import_state(
+    State(state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    Query(params): Query<StateImportParams>,
+    Json(doc): Json<StateDocument>,
+) -> impl IntoResponse {
+    if doc.schema_version > STATE_SCHEMA_VERSION {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!(
+                    "Export schema version {} is newer than the supported version {}",
+                    doc.schema_version, STATE_SCHEMA_VERSION
+                ),
+            })),
+        );
+    }
+    if let Some(section) = doc.sections.keys().find(|s| !STATE_SECTIONS.iter().any(|(name, _, _)| name == s)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Unknown section: {}", section) })),
+        );
+    }
+
+    let written = match state.sqlite.import_state(doc.sections, params.mode).await {
+        Ok(written) => written,
+        Err(e) => {
+            // Bad documents fail on columns, values or constraints; anything
+            // else is the database's fault
+            let status = match &e {
+                rusqlite::Error::InvalidColumnName(_) | rusqlite::Error::ToSqlConversionFailure(_) => StatusCode::BAD_REQUEST,
+                rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::ConstraintViolation => {
+                    StatusCode::BAD_REQUEST
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return (status, Json(json!({ "error": e.to_string() })));
+        }
+    };
+
+    let mode = match params.mode {
+        ImportMode::Merge => "merge",
+        ImportMode::Replace => "replace",
+    };
+    let summary = serde_json::to_string(&written).unwrap_or_default();
+    state.sqlite.audit(&actor, "import", "state", mode, Some(&summary));
+
+    let services = state.settings.load().services.clone();
+    let mut reload_errors = Vec::new();
+    if let Err(e) = state.service_router.reload_from_db(&state.sqlite, &services).await {
+        reload_errors.push(format!("services: {}", e));
+    }
+    if let Err(e) = state.blocklist.reload_from_db().await {
+        reload_errors.push(format!("blocklist: {}", e));
+    }
+    if let Err(e) = state.managed_rules.reload_from_db(&state.sqlite).await {
+        reload_errors.push(format!("managed rules: {}", e));
+    }
+    state.custom_rules.reload_rules().await;
+
+    if !reload_errors.is_empty() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Imported, but reloading failed", "imported": written, "reload_errors": reload_errors })),
+        );
+    }
+    (StatusCode::OK, Json(json!({ "mode": mode, "imported": written })))
+}
+
+// ---------------------------------------------------------------------------
+// Debug
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+pub struct EvaluateRequest {
+    pub ip: String,
+    #[serde(default = "default_evaluate_method")]
+    pub method: String,
+    /// May include a query string.
+    pub path: String,
+    pub host: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub ja3: Option<String>,
+    /// Overrides the `user-agent` header.
+    pub user_agent: Option<String>,
+}
+
+fn default_evaluate_method() -> String {
+    "GET".to_string()
+}
+
+/// `POST /api/fortress/debug/evaluate`
+///
+/// Run a hypothetical request through the protection pipeline without
+/// recording it, and report the decision with each stage's contribution.
+pub async fn evaluate_request(
+    State(state): State<AppState>,
+    Json(body): Json<EvaluateRequest>,
+) -> impl IntoResponse {
+    let Ok(ip) = body.ip.parse::<std::net::IpAddr>() else {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": "Invalid IP address"})));
+    };
+    let settings = state.settings.load();
+    let headers: HashMap<String, String> =
+        body.headers.into_iter().map(|(k, v)| (k.to_ascii_lowercase(), v)).collect();
+    let (path, query) = match body.path.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (body.path, None),
+    };
+
+    let mut ctx = match RequestContext::from_raw(ip, body.method.to_ascii_uppercase(), &path, &body.host) {
+        Ok(ctx) => ctx,
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({"error": format!("Invalid path: {}", err.as_str())}))),
+    };
+    ctx.is_behind_cloudflare =
+        settings.cloudflare.enabled && crate::protection::cloudflare::is_cloudflare_ip(ip);
+    ctx.ja3_hash = body.ja3.filter(|ja3| !ja3.is_empty());
+    ctx.query = query;
+    ctx.user_agent = body
+        .user_agent
+        .or_else(|| headers.get("user-agent").cloned())
+        .filter(|ua| !ua.is_empty());
+    if ctx.is_behind_cloudflare {
+        if let Some(cf_country) = headers.get("cf-ipcountry") {
+            if cf_country.len() == 2 && cf_country != "XX" {
+                ctx.country_code = Some(cf_country.to_uppercase());
+            }
+        }
+    }
+    ctx.headers = headers;
+
+    let service = state.service_router.resolve(&body.host);
+    let (result, trace) = state.pipeline.evaluate(&mut ctx, &settings, service.as_deref());
+
+    (StatusCode::OK, Json(json!({
+        "action": result.action.to_string(),
+        "reason": result.reason.map(|r| r.to_string()),
+        "score": result.score,
+        "service": service.as_ref().map(|s| s.id.clone()),
+        "country": ctx.country_code,
+        "asn": ctx.asn,
+        "stages": trace.stages,
+    })))
+}
+
+// ---------------------------------------------------------------------------
+// Helpers
+// ---------------------------------------------------------------------------
+
+/// Parse an optional RFC 3339 query parameter.
+fn parse_rfc3339(value: &Option<String>) -> Result<Option<DateTime<Utc>>, String> {
+    value
+        .as_deref()
+        .map(|v| {
+            DateTime::parse_from_rfc3339(v)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|_| format!("Invalid timestamp: {}", v))
+        })
+        .transpose()
 }
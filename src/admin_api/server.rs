@@ -1,37 +1,65 @@
 use axum::{
+    extract::DefaultBodyLimit,
+    http::Method,
     middleware,
     routing::{delete, get, post, put},
     Router,
 };
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
 use tower_http::cors::{Any, AllowOrigin, CorsLayer};
-use tracing::info;
+use tracing::{debug, info, warn};
 
-use crate::admin_api::{auth, routes, websocket};
+use crate::admin_api::{auth, prometheus, routes, websocket};
+use crate::admin_api::auth::ClientCertificate;
 use crate::admin_api::routes::AppState;
+use crate::admin_api::tls::client_cert_fingerprint;
+
+/// Connections that have not finished the TLS handshake by then are closed.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// The admin/dashboard HTTP server.
 pub struct AdminApiServer {
     state: AppState,
     bind_addr: String,
+    /// From [`crate::admin_api::tls::build_admin_tls_config`]; `None` serves
+    /// plain HTTP.
+    tls: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl AdminApiServer {
-    pub fn new(state: AppState, bind_addr: String) -> Self {
-        Self { state, bind_addr }
+    pub fn new(state: AppState, bind_addr: String, tls: Option<Arc<rustls::ServerConfig>>) -> Self {
+        Self { state, bind_addr, tls }
     }
 
     /// Start listening and serve requests until the process is shut down.
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let state = self.state.clone();
-        let api_key = state.api_key.clone();
+        let settings = state.settings.load_full();
+        let mtls = self.tls.is_some() && settings.admin_api.client_ca.is_some();
+        let keys = auth::ApiKeyAuth {
+            api_key: state.api_key.clone(),
+            read_only_key: settings.admin_api.read_only_api_key.clone(),
+            client_cert_only: mtls && state.api_key.is_empty(),
+            audit: state.sqlite.clone(),
+        };
 
         // CORS: restrict to localhost origins since admin API binds to 127.0.0.1:9090
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
         let cors = CorsLayer::new()
             .allow_origin(AllowOrigin::list([
                 "http://localhost:3100".parse().unwrap(),
                 "http://127.0.0.1:3100".parse().unwrap(),
-                "http://localhost:9090".parse().unwrap(),
-                "http://127.0.0.1:9090".parse().unwrap(),
+                format!("{}://localhost:9090", scheme).parse().unwrap(),
+                format!("{}://127.0.0.1:9090", scheme).parse().unwrap(),
             ]))
             .allow_methods(Any)
             .allow_headers(Any);
@@ -53,6 +81,20 @@ impl AdminApiServer {
                 "/api/fortress/blocklist",
                 get(routes::get_blocklist).post(routes::add_to_blocklist),
             )
+            .route(
+                "/api/fortress/blocklist/import",
+                post(routes::import_blocklist)
+                    .layer(DefaultBodyLimit::max(routes::MAX_IMPORT_BODY_SIZE)),
+            )
+            .route("/api/fortress/blocklist/export", get(routes::export_blocklist))
+            // Whole-state backup and restore
+            .route("/api/fortress/export", get(routes::export_state))
+            .route(
+                "/api/fortress/import",
+                post(routes::import_state).layer(DefaultBodyLimit::max(routes::MAX_IMPORT_BODY_SIZE)),
+            )
+            .route("/api/fortress/blocklist/bulk", post(routes::bulk_add_to_blocklist))
+            .route("/api/fortress/blocklist/backfill-geo", post(routes::backfill_blocklist_geo))
             .route(
                 "/api/fortress/blocklist/{id}",
                 delete(routes::remove_from_blocklist),
@@ -66,6 +108,7 @@ impl AdminApiServer {
                 "/api/fortress/rules/{id}",
                 put(routes::update_rule).delete(routes::delete_rule),
             )
+            .route("/api/fortress/rules/{id}/matches", get(routes::get_rule_matches))
             // Configuration
             .route(
                 "/api/fortress/config",
@@ -73,8 +116,12 @@ impl AdminApiServer {
             )
             // Settings (read-only from fortress.toml)
             .route("/api/fortress/settings", get(routes::get_settings))
+            .route("/api/fortress/config/reload", post(routes::reload_config))
+            .route("/api/fortress/logs/reopen", post(routes::reopen_access_log))
+            .route("/api/fortress/logs/stream", get(routes::stream_access_log))
             // Protection level
-            .route("/api/fortress/level", post(routes::set_level))
+            .route("/api/fortress/level", get(routes::get_level).post(routes::set_level))
+            .route("/api/fortress/level/pin", delete(routes::release_level_pin))
             // Analytics
             .route("/api/fortress/analytics", get(routes::get_analytics))
             .route("/api/fortress/top-ips", get(routes::get_top_ips))
@@ -83,18 +130,45 @@ impl AdminApiServer {
                 get(routes::get_top_countries),
             )
             .route("/api/fortress/fingerprints", get(routes::get_fingerprints))
+            .route("/api/fortress/analytics/top", get(routes::get_sampled_top))
+            .route("/api/fortress/ips/{ip}/requests", get(routes::get_ip_requests))
             // Services
             .route("/api/fortress/services", get(routes::list_services).post(routes::create_service))
             .route("/api/fortress/services/{id}", get(routes::get_service).put(routes::update_service).delete(routes::delete_service))
             .route("/api/fortress/services/{id}/toggle", post(routes::toggle_service))
+            .route("/api/fortress/services/{id}/maintenance", post(routes::set_service_maintenance))
+            .route("/api/fortress/services/{id}/queue", get(routes::get_service_queue).put(routes::set_service_queue))
+            .route("/api/fortress/services/{id}/health", get(routes::get_service_health))
+            .route("/api/fortress/services/{id}/metrics", get(routes::get_service_metrics))
+            .route("/api/fortress/services/{id}/certificate", post(routes::upload_service_certificate))
+            .route("/api/fortress/certificates", get(routes::list_certificates))
             // L4 protection
             .route("/api/fortress/l4/metrics", get(routes::get_l4_metrics))
             .route("/api/fortress/l4/events", get(routes::get_l4_events))
+            .route("/api/fortress/l4/top", get(routes::get_l4_top))
+            // Challenges
+            .route("/api/fortress/challenges/stats", get(routes::get_challenge_stats))
             // IP Reputation
             .route("/api/fortress/ip-reputation", get(routes::get_ip_reputation))
+            .route("/api/fortress/ip-reputation/feeds", get(routes::get_ip_reputation_feeds))
             // Auto-Ban
-            .route("/api/fortress/auto-bans", get(routes::get_auto_bans))
+            .route("/api/fortress/auto-bans", get(routes::get_auto_bans).delete(routes::unban_subnet))
             .route("/api/fortress/auto-bans/{ip}", delete(routes::unban_ip))
+            // Firewall offload
+            .route("/api/fortress/firewall/status", get(routes::get_firewall_status))
+            // GeoIP
+            .route("/api/fortress/geoip/reload", post(routes::reload_geoip))
+            // Alerting
+            .route("/api/fortress/alerts/test", post(routes::test_alerts))
+            // Audit log
+            .route("/api/fortress/audit", get(routes::get_audit))
+            // Connections
+            .route(
+                "/api/fortress/connections",
+                get(routes::get_connections).delete(routes::close_connections),
+            )
+            .route("/api/fortress/connections/top-bandwidth", get(routes::get_top_bandwidth))
+            .route("/api/fortress/connections/{id}", delete(routes::close_connection))
             // IP Lookup
             .route("/api/fortress/ip-lookup/{ip}", get(routes::get_ip_info))
             // Managed Rules
@@ -102,20 +176,126 @@ impl AdminApiServer {
             .route("/api/fortress/managed-rules/{id}", put(routes::toggle_managed_rule))
             // Distributed Attacks
             .route("/api/fortress/distributed-attacks", get(routes::get_distributed_attacks))
+            .route(
+                "/api/fortress/distributed/mitigations",
+                get(routes::list_mitigations).delete(routes::clear_mitigations),
+            )
+            .route("/api/fortress/distributed/mitigations/{id}", delete(routes::delete_mitigation))
+            .route("/api/fortress/cache/stats", get(routes::get_cache_stats))
+            .route("/api/fortress/cache", delete(routes::purge_cache))
+            // API tokens
+            .route("/api/fortress/tokens", get(routes::list_api_tokens).post(routes::create_api_token))
+            .route(
+                "/api/fortress/tokens/{id}",
+                put(routes::update_api_token).delete(routes::revoke_api_token),
+            )
+            .route("/api/fortress/tokens/{id}/usage", get(routes::get_api_token_usage))
+            // IP policies
+            .route("/api/fortress/ip-policies", get(routes::list_ip_policies).post(routes::set_ip_policy))
+            .route("/api/fortress/ip-policies/{id}", delete(routes::delete_ip_policy))
+            // ASN classification
+            .route("/api/fortress/asn", get(routes::get_asn_classification))
+            .route("/api/fortress/asn/reload", post(routes::reload_asn_dataset))
+            .route("/api/fortress/asn/{number}", get(routes::get_asn))
+            .route(
+                "/api/fortress/asn/{number}/override",
+                put(routes::set_asn_override).delete(routes::delete_asn_override),
+            )
             // Threat Summary
             .route("/api/fortress/threat-summary", get(routes::get_threat_summary))
+            // Request capture
+            .route(
+                "/api/fortress/capture",
+                get(routes::list_captures).post(routes::start_capture),
+            )
+            .route(
+                "/api/fortress/capture/{id}",
+                get(routes::get_capture).delete(routes::delete_capture),
+            )
+            // Debug
+            .route("/api/fortress/debug/evaluate", post(routes::evaluate_request))
             // Middleware layers (outermost = first to run)
             .layer(middleware::from_fn_with_state(
-                api_key,
+                keys,
                 auth::auth_middleware,
             ))
+            // Prometheus scrape endpoint (registered after the auth layer so it
+            // is not subject to the API key; see `admin_api.metrics_token`)
+            .route("/metrics", get(prometheus::get_prometheus_metrics))
+            // Cluster sync is authenticated by HMAC signature, not API key
+            .route("/api/fortress/cluster/sync", post(routes::cluster_sync))
             .layer(cors)
+            // Public status is fetched by status pages on other origins
+            .merge(
+                Router::new()
+                    .route("/api/fortress/public/status", get(routes::get_public_status))
+                    .layer(CorsLayer::new().allow_origin(Any).allow_methods([Method::GET])),
+            )
             .with_state(state);
 
-        let listener = tokio::net::TcpListener::bind(&self.bind_addr).await?;
-        info!("Admin API listening on {}", self.bind_addr);
-        axum::serve(listener, app).await?;
+        let listener = TcpListener::bind(&self.bind_addr).await?;
+        match &self.tls {
+            None => {
+                info!("Admin API listening on http://{}", self.bind_addr);
+                axum::serve(listener, app).await?;
+            }
+            Some(tls) => {
+                info!(mtls, "Admin API listening on https://{}", self.bind_addr);
+                serve_tls(listener, app, TlsAcceptor::from(Arc::clone(tls))).await;
+            }
+        }
 
         Ok(())
     }
 }
+
+/// Serve `app` over TLS, one task per connection. Requests on connections
+/// that presented a client certificate carry its [`ClientCertificate`].
+async fn serve_tls(listener: TcpListener, app: Router, acceptor: TlsAcceptor) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Usually out of file descriptors; give connections time to close
+                warn!("Admin API accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!(peer = %peer, error = %e, "Admin API TLS handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    debug!(peer = %peer, "Admin API TLS handshake timed out");
+                    return;
+                }
+            };
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| ClientCertificate(client_cert_fingerprint(cert)));
+
+            let service = service_fn(move |mut req: hyper::Request<Incoming>| {
+                if let Some(ref cert) = client_cert {
+                    req.extensions_mut().insert(cert.clone());
+                }
+                app.clone().oneshot(req)
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                debug!(peer = %peer, error = %e, "Admin API connection error");
+            }
+        });
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::version::{TLS12, TLS13};
+use rustls::{RootCertStore, ServerConfig};
+use sha2::{Digest, Sha256};
+
+use crate::config::settings::AdminApiConfig;
+
+/// Build the admin API's TLS config from `admin_api.tls_cert`/`tls_key`,
+/// requiring client certificates signed by `admin_api.client_ca` when that
+/// is set. `None` when the admin API is plain HTTP. Errors name the
+/// setting and file at fault.
+pub fn build_admin_tls_config(config: &AdminApiConfig) -> Result<Option<Arc<ServerConfig>>, String> {
+    let (cert_path, key_path) = match (config.tls_cert.as_deref(), config.tls_key.as_deref()) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.client_ca.is_some() => {
+            return Err("admin_api.client_ca needs admin_api.tls_cert and admin_api.tls_key".to_string());
+        }
+        (None, None) => return Ok(None),
+        (Some(_), None) => return Err("admin_api.tls_cert is set but admin_api.tls_key is not".to_string()),
+        (None, Some(_)) => return Err("admin_api.tls_key is set but admin_api.tls_cert is not".to_string()),
+    };
+
+    let certs = read_certs("admin_api.tls_cert", cert_path)?;
+    let key = read_key(key_path)?;
+
+    let builder = ServerConfig::builder_with_protocol_versions(&[&TLS13, &TLS12]);
+    let builder = match config.client_ca.as_deref() {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs("admin_api.client_ca", ca_path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("admin_api.client_ca: invalid CA certificate in {}: {}", ca_path, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| format!("admin_api.client_ca: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("admin_api.tls_key {} does not fit {}: {}", key_path, cert_path, e))?;
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(tls)))
+}
+
+/// Short SHA-256 fingerprint of a client certificate, for audit logs.
+pub fn client_cert_fingerprint(der: &[u8]) -> String {
+    let hash = Sha256::digest(der);
+    hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_certs(setting: &str, path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("{}: cannot read {}: {}", setting, path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: invalid PEM in {}: {}", setting, path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found in {}", setting, path));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("admin_api.tls_key: cannot read {}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("admin_api.tls_key: invalid PEM in {}: {}", path, e))?
+        .ok_or_else(|| format!("admin_api.tls_key: no private key found in {}", path))
+}
@@ -57,6 +57,7 @@ async fn handle_live_traffic(socket: WebSocket, state: AppState) {
                     "blocked_per_sec": snapshot.blocked_per_sec,
                     "challenged_per_sec": snapshot.challenged_per_sec,
                     "passed_per_sec": snapshot.passed_per_sec,
+                    "bypassed_per_sec": latest.map(|s| s.bypassed).unwrap_or_default(),
                     "unique_ips": snapshot.unique_ips,
                     "avg_latency_ms": snapshot.avg_latency_ms,
                     "total_requests": snapshot.total_requests,
@@ -67,6 +68,7 @@ async fn handle_live_traffic(socket: WebSocket, state: AppState) {
                         "blocked": s.blocked,
                         "challenged": s.challenged,
                         "passed": s.passed,
+                        "bypassed": s.bypassed,
                     })),
                 });
 
@@ -1,86 +1,335 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tracing::{error, info, warn};
+use bytes::Bytes;
+use chrono::Utc;
+use dashmap::DashMap;
+use http_body_util::Full;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
 
-/// Simple webhook-based alerting system.
+use crate::config::settings::{AlertChannelConfig, SharedSettings};
+use crate::proxy::tls::certificate_expiries;
+
+/// How urgent an alert is. Channels skip alerts below their
+/// `min_severity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn from_str_name(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "info" => Some(Self::Info),
+            "warning" => Some(Self::Warning),
+            "critical" => Some(Self::Critical),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// A single alert as handed to the channels.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub event: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Outcome of delivering an alert to one channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelResult {
+    pub channel: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+type AlertClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+const ALERT_TIMEOUT: Duration = Duration::from_secs(10);
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// Dedup keys kept before expired ones are pruned.
+const MAX_TRACKED_KEYS: usize = 1024;
+
+/// Sends alerts to the channels listed under `[[alerting.channels]]` (and
+/// the legacy `alerting.webhook_url`): generic JSON webhooks, Slack
+/// incoming webhooks and Telegram bots.
 ///
-/// Sends JSON POST requests to a configured webhook URL when significant
-/// events occur (attack start, attack end, escalation changes).
+/// Alerts carry a dedup key, e.g. `upstream_down:<service>:<addr>`; an
+/// alert whose key was sent within `alerting.cooldown_secs` is dropped.
+/// Channels and cooldown are read from the live settings, so a config
+/// reload applies to the next alert.
 pub struct AlertManager {
-    webhook_url: Option<String>,
-    enabled: bool,
+    settings: SharedSettings,
+    client: AlertClient,
+    last_sent: DashMap<String, Instant>,
 }
 
 impl AlertManager {
-    pub fn new(webhook_url: Option<String>, enabled: bool) -> Self {
+    pub fn new(settings: SharedSettings) -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
         Self {
-            webhook_url,
-            enabled,
+            settings,
+            client: Client::builder(TokioExecutor::new())
+                .pool_idle_timeout(Duration::from_secs(30))
+                .build(https),
+            last_sent: DashMap::new(),
         }
     }
 
-    /// Send an alert to the configured webhook.
-    pub async fn send_alert(&self, event_type: &str, message: &str) {
-        if !self.enabled {
+    /// Send an alert in the background unless alerting is disabled or an
+    /// alert with the same `key` went out within the cooldown.
+    pub fn notify(self: &Arc<Self>, event: &str, key: &str, severity: Severity, message: String) {
+        let settings = self.settings.load();
+        if !settings.alerting.enabled {
             return;
         }
 
-        let url = match &self.webhook_url {
-            Some(u) if !u.is_empty() => u.clone(),
-            _ => {
-                info!(event = event_type, msg = message, "Alert (no webhook configured)");
-                return;
+        let cooldown = Duration::from_secs(settings.alerting.cooldown_secs);
+        let now = Instant::now();
+        if self.last_sent.len() > MAX_TRACKED_KEYS {
+            self.last_sent.retain(|_, sent| now.duration_since(*sent) < cooldown);
+        }
+        match self.last_sent.entry(key.to_string()) {
+            dashmap::mapref::entry::Entry::Occupied(mut e) => {
+                if now.duration_since(*e.get()) < cooldown {
+                    info!(event = event, key = key, "Alert suppressed (cooldown)");
+                    return;
+                }
+                e.insert(now);
             }
-        };
+            dashmap::mapref::entry::Entry::Vacant(e) => {
+                e.insert(now);
+            }
+        }
 
-        let payload = serde_json::json!({
-            "event": event_type,
-            "message": message,
-            "timestamp": chrono::Utc::now().to_rfc3339(),
-            "source": "fortress"
+        let alert = Alert {
+            event: event.to_string(),
+            severity,
+            message,
+        };
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            manager.dispatch(&alert, false).await;
         });
+    }
 
-        let body = payload.to_string();
-
-        // Use a simple hyper client to POST the alert
-        use hyper_util::client::legacy::Client;
-        use hyper_util::client::legacy::connect::HttpConnector;
-        use hyper_util::rt::TokioExecutor;
-        use http_body_util::Full;
-        use bytes::Bytes;
+    /// Send a test alert to every channel, regardless of `enabled`,
+    /// cooldown and `min_severity`.
+    pub async fn send_test(&self, actor: &str) -> Vec<ChannelResult> {
+        let alert = Alert {
+            event: "test".to_string(),
+            severity: Severity::Info,
+            message: format!("Test alert from Fortress (requested by {})", actor),
+        };
+        self.dispatch(&alert, true).await
+    }
 
-        let client: Client<HttpConnector, Full<Bytes>> = Client::builder(TokioExecutor::new())
-            .pool_idle_timeout(Duration::from_secs(5))
-            .build_http();
+    /// Auto-banned IPs in one subnet that trigger a `subnet_banned` alert.
+    pub fn subnet_ban_threshold(&self) -> u32 {
+        self.settings.load().alerting.subnet_ban_threshold
+    }
 
-        let req = match hyper::Request::builder()
-            .method(hyper::Method::POST)
-            .uri(&url)
-            .header("Content-Type", "application/json")
-            .body(Full::new(Bytes::from(body)))
-        {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Failed to build alert request: {}", e);
-                return;
+    /// Check the certificates in `tls.cert_dir` every six hours and alert
+    /// on those expiring within `alerting.cert_expiry_days`.
+    pub async fn run_cert_expiry_checks(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CERT_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let settings = self.settings.load_full();
+            let days = settings.alerting.cert_expiry_days as i64;
+            if !settings.alerting.enabled || days == 0 {
+                continue;
             }
-        };
 
-        match tokio::time::timeout(Duration::from_secs(10), client.request(req)).await {
-            Ok(Ok(resp)) => {
-                info!(
-                    status = resp.status().as_u16(),
-                    event = event_type,
-                    "Alert sent to webhook"
-                );
+            let cert_dir = settings.tls.cert_dir.clone();
+            let expiries = tokio::task::spawn_blocking(move || certificate_expiries(&cert_dir))
+                .await
+                .unwrap_or_default();
+            let now = Utc::now();
+            for (domain, not_after) in expiries {
+                let days_left = (not_after - now).num_days();
+                if days_left >= days {
+                    continue;
+                }
+                let date = not_after.format("%Y-%m-%d");
+                let (severity, msg) = if not_after <= now {
+                    (Severity::Critical, format!("TLS certificate for {} expired on {}", domain, date))
+                } else {
+                    let severity = if days_left < 3 { Severity::Critical } else { Severity::Warning };
+                    (severity, format!("TLS certificate for {} expires on {} ({} days left)", domain, date, days_left))
+                };
+                self.notify("cert_expiry", &format!("cert_expiry:{}", domain), severity, msg);
             }
-            Ok(Err(e)) => {
-                warn!(error = %e, "Failed to send alert to webhook");
+        }
+    }
+
+    async fn dispatch(&self, alert: &Alert, force: bool) -> Vec<ChannelResult> {
+        let channels = self.channels();
+        if channels.is_empty() {
+            info!(event = %alert.event, msg = %alert.message, "Alert (no channels configured)");
+            return Vec::new();
+        }
+
+        let mut results = Vec::with_capacity(channels.len());
+        for channel in &channels {
+            let min = Severity::from_str_name(&channel.min_severity).unwrap_or(Severity::Info);
+            if !force && alert.severity < min {
+                continue;
             }
-            Err(_) => {
-                warn!("Alert webhook request timed out");
+            let result = self.deliver(channel, alert).await;
+            match &result {
+                Ok(()) => info!(event = %alert.event, channel = %channel.kind, "Alert sent"),
+                Err(e) => warn!(event = %alert.event, channel = %channel.kind, error = %e, "Failed to send alert"),
             }
+            results.push(ChannelResult {
+                channel: channel.kind.clone(),
+                ok: result.is_ok(),
+                error: result.err(),
+            });
+        }
+        results
+    }
+
+    /// Configured channels, with the legacy `webhook_url` as a webhook.
+    fn channels(&self) -> Vec<AlertChannelConfig> {
+        let settings = self.settings.load();
+        let mut channels = settings.alerting.channels.clone();
+        if let Some(url) = settings.alerting.webhook_url.as_ref().filter(|u| !u.is_empty()) {
+            channels.push(AlertChannelConfig {
+                kind: "webhook".to_string(),
+                url: Some(url.clone()),
+                bot_token: None,
+                chat_id: None,
+                min_severity: "info".to_string(),
+            });
+        }
+        channels
+    }
+
+    async fn deliver(&self, channel: &AlertChannelConfig, alert: &Alert) -> Result<(), String> {
+        let (url, payload) = channel_request(channel, alert)?;
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(&url)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(payload.to_string())))
+            .map_err(|e| format!("invalid request: {}", e))?;
+
+        let resp = tokio::time::timeout(ALERT_TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| "request timed out".to_string())?
+            .map_err(|e| format!("request failed: {}", e))?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+/// URL and JSON body for delivering `alert` to `channel`.
+fn channel_request(channel: &AlertChannelConfig, alert: &Alert) -> Result<(String, Value), String> {
+    let severity = alert.severity.as_str();
+    match channel.kind.as_str() {
+        "webhook" => {
+            let url = channel.url.clone().ok_or("webhook channel needs `url`")?;
+            let payload = json!({
+                "event": alert.event,
+                "severity": severity,
+                "message": alert.message,
+                "timestamp": Utc::now().to_rfc3339(),
+                "source": "fortress"
+            });
+            Ok((url, payload))
         }
+        "slack" => {
+            let url = channel.url.clone().ok_or("slack channel needs `url`")?;
+            let icon = match alert.severity {
+                Severity::Info => ":information_source:",
+                Severity::Warning => ":warning:",
+                Severity::Critical => ":rotating_light:",
+            };
+            let payload = json!({
+                "text": format!("{} *[{}] {}*\n{}", icon, severity.to_uppercase(), alert.event, alert.message),
+            });
+            Ok((url, payload))
+        }
+        "telegram" => {
+            let token = channel.bot_token.as_deref().ok_or("telegram channel needs `bot_token`")?;
+            let chat_id = channel.chat_id.clone().ok_or("telegram channel needs `chat_id`")?;
+            let payload = json!({
+                "chat_id": chat_id,
+                "text": format!("[{}] {}\n{}", severity.to_uppercase(), alert.event, alert.message),
+                "disable_web_page_preview": true,
+            });
+            Ok((format!("https://api.telegram.org/bot{}/sendMessage", token), payload))
+        }
+        other => Err(format!("unknown channel type `{}`", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(kind: &str) -> AlertChannelConfig {
+        AlertChannelConfig {
+            kind: kind.to_string(),
+            url: Some("https://hooks.example.com/x".to_string()),
+            bot_token: Some("123:abc".to_string()),
+            chat_id: Some("-100".to_string()),
+            min_severity: "info".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_channel_payloads() {
+        let alert = Alert {
+            event: "upstream_down".to_string(),
+            severity: Severity::Critical,
+            message: "api: 10.0.0.1:80 is down".to_string(),
+        };
+
+        let (url, body) = channel_request(&channel("webhook"), &alert).unwrap();
+        assert_eq!(url, "https://hooks.example.com/x");
+        assert_eq!(body["severity"], "critical");
+
+        let (_, body) = channel_request(&channel("slack"), &alert).unwrap();
+        assert!(body["text"].as_str().unwrap().contains("*[CRITICAL] upstream_down*"));
+
+        let (url, body) = channel_request(&channel("telegram"), &alert).unwrap();
+        assert_eq!(url, "https://api.telegram.org/bot123:abc/sendMessage");
+        assert_eq!(body["chat_id"], "-100");
+
+        assert!(channel_request(&channel("pager"), &alert).is_err());
+    }
+
+    #[test]
+    fn test_severity_order() {
+        assert!(Severity::Critical > Severity::Warning);
+        assert_eq!(Severity::from_str_name("WARNING"), Some(Severity::Warning));
     }
 }
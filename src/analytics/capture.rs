@@ -0,0 +1,454 @@
+//! On-demand capture of whole requests (headers and the start of the body)
+//! for forensics during an attack. A capture is started through
+//! `POST /api/fortress/capture`, keeps the matching requests in memory and
+//! stops on its own after a number of requests or seconds.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderMap, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::config::settings::SharedSettings;
+use crate::models::threat::ThreatReason;
+use crate::proxy::access_log::AccessLogEntry;
+
+/// Most requests one capture may keep.
+pub const MAX_REQUESTS: usize = 10_000;
+/// Longest a capture may run.
+pub const MAX_DURATION_SECS: u64 = 3_600;
+/// Most body bytes kept per request.
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Captures held at once, running or finished.
+pub const MAX_CAPTURES: usize = 16;
+
+/// Rough per-request cost besides the captured strings, for the budget.
+const ENTRY_OVERHEAD: usize = 256;
+
+const REDACTED: &str = "[redacted]";
+
+/// What a capture records and for how long.
+#[derive(Debug, Clone)]
+pub struct CaptureSpec {
+    pub ip: Option<IpAddr>,
+    pub subnet: Option<IpNet>,
+    pub path_prefix: Option<String>,
+    /// `passed`, `challenged` or `blocked`, as in the access log.
+    pub action: Option<String>,
+    pub max_requests: usize,
+    pub duration: Duration,
+    pub max_body_bytes: usize,
+    /// Replace `Authorization`, `Proxy-Authorization` and the clearance
+    /// cookie with a placeholder.
+    pub redact: bool,
+}
+
+impl CaptureSpec {
+    /// Whether a request matches the filters known before the pipeline
+    /// has run, that is all but `action`.
+    fn matches_request(&self, ip: IpAddr, path: &str) -> bool {
+        self.ip.is_none_or(|want| want == ip)
+            && self.subnet.is_none_or(|net| net.contains(&ip))
+            && self.path_prefix.as_deref().is_none_or(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// One captured request.
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedRequest {
+    pub timestamp: DateTime<Utc>,
+    pub client_ip: IpAddr,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub query: Option<String>,
+    /// Every header field in the order received, repeats included.
+    pub headers: Vec<(String, String)>,
+    /// At most `max_body_bytes` of the body, decoded lossily as UTF-8.
+    pub body: Option<String>,
+    pub action: String,
+    pub reason: Option<String>,
+    pub status: u16,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub ja3: Option<String>,
+    pub ray_id: String,
+}
+
+impl CapturedRequest {
+    /// Approximate memory held by this request.
+    fn size(&self) -> usize {
+        let strings = self.method.len()
+            + self.host.len()
+            + self.path.len()
+            + self.query.as_ref().map_or(0, String::len)
+            + self.body.as_ref().map_or(0, String::len)
+            + self.action.len()
+            + self.ray_id.len()
+            + self.ja3.as_ref().map_or(0, String::len);
+        let headers: usize = self.headers.iter().map(|(name, value)| name.len() + value.len() + 48).sum();
+        ENTRY_OVERHEAD + strings + headers
+    }
+}
+
+/// Summary of a capture, without its requests.
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureSummary {
+    pub id: u64,
+    /// `running` or `finished`.
+    pub status: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub ip: Option<IpAddr>,
+    pub subnet: Option<String>,
+    pub path_prefix: Option<String>,
+    pub action: Option<String>,
+    pub max_requests: usize,
+    pub max_seconds: u64,
+    pub max_body_bytes: usize,
+    pub redact: bool,
+    pub captured: usize,
+    pub bytes: usize,
+    /// Matching requests left out because the memory budget was full.
+    pub dropped: u64,
+}
+
+struct Capture {
+    id: u64,
+    spec: CaptureSpec,
+    started_at: DateTime<Utc>,
+    ends: Instant,
+    /// When the capture stopped taking requests.
+    finished: Option<Instant>,
+    requests: Vec<CapturedRequest>,
+    bytes: usize,
+    dropped: u64,
+}
+
+impl Capture {
+    /// Mark the capture finished once it is full or out of time.
+    fn finish_if_done(&mut self, now: Instant) {
+        if self.finished.is_none() && (now >= self.ends || self.requests.len() >= self.spec.max_requests) {
+            self.finished = Some(now);
+        }
+    }
+
+    fn is_running(&self, now: Instant) -> bool {
+        self.finished.is_none() && now < self.ends
+    }
+
+    fn summary(&self) -> CaptureSummary {
+        CaptureSummary {
+            id: self.id,
+            status: if self.finished.is_none() { "running" } else { "finished" },
+            started_at: self.started_at,
+            ip: self.spec.ip,
+            subnet: self.spec.subnet.map(|net| net.to_string()),
+            path_prefix: self.spec.path_prefix.clone(),
+            action: self.spec.action.clone(),
+            max_requests: self.spec.max_requests,
+            max_seconds: self.spec.duration.as_secs(),
+            max_body_bytes: self.spec.max_body_bytes,
+            redact: self.spec.redact,
+            captured: self.requests.len(),
+            bytes: self.bytes,
+            dropped: self.dropped,
+        }
+    }
+}
+
+/// The running and recently finished captures.
+///
+/// The request path only loads [`active`](Self::active) unless a capture
+/// is running, so an idle capture costs one atomic read per request.
+pub struct RequestCapture {
+    settings: SharedSettings,
+    active: AtomicBool,
+    captures: Mutex<Vec<Capture>>,
+    next_id: AtomicU64,
+}
+
+impl RequestCapture {
+    pub fn new(settings: SharedSettings) -> Self {
+        Self {
+            settings,
+            active: AtomicBool::new(false),
+            captures: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Start a capture. `None` when [`MAX_CAPTURES`] are already held.
+    pub fn start(&self, spec: CaptureSpec) -> Option<CaptureSummary> {
+        let mut captures = self.captures.lock();
+        self.expire(&mut captures, Instant::now());
+        if captures.len() >= MAX_CAPTURES {
+            return None;
+        }
+        let capture = Capture {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            ends: Instant::now() + spec.duration,
+            spec,
+            started_at: Utc::now(),
+            finished: None,
+            requests: Vec::new(),
+            bytes: 0,
+            dropped: 0,
+        };
+        let summary = capture.summary();
+        captures.push(capture);
+        self.active.store(true, Ordering::Relaxed);
+        Some(summary)
+    }
+
+    pub fn list(&self) -> Vec<CaptureSummary> {
+        let mut captures = self.captures.lock();
+        self.expire(&mut captures, Instant::now());
+        captures.iter().map(Capture::summary).collect()
+    }
+
+    /// A capture and the requests it holds.
+    pub fn get(&self, id: u64) -> Option<(CaptureSummary, Vec<CapturedRequest>)> {
+        let mut captures = self.captures.lock();
+        self.expire(&mut captures, Instant::now());
+        captures
+            .iter()
+            .find(|c| c.id == id)
+            .map(|c| (c.summary(), c.requests.clone()))
+    }
+
+    /// Stop and discard a capture. Returns false if there was none.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut captures = self.captures.lock();
+        let before = captures.len();
+        captures.retain(|c| c.id != id);
+        self.expire(&mut captures, Instant::now());
+        captures.len() != before
+    }
+
+    /// Body bytes to read ahead for a request from `ip` to `path`, if a
+    /// running capture may keep it.
+    pub fn body_bytes_wanted(&self, ip: IpAddr, path: &str) -> Option<usize> {
+        if !self.active.load(Ordering::Relaxed) {
+            return None;
+        }
+        let now = Instant::now();
+        self.captures
+            .lock()
+            .iter()
+            .filter(|c| c.is_running(now) && c.spec.matches_request(ip, path))
+            .map(|c| c.spec.max_body_bytes)
+            .max()
+            .filter(|&bytes| bytes > 0)
+    }
+
+    /// Copy a finished request into every running capture it matches.
+    /// `body` is what was read ahead of the pipeline, if anything.
+    pub fn record(
+        &self,
+        entry: &AccessLogEntry<'_>,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        body: Option<&[u8]>,
+        reason: Option<ThreatReason>,
+    ) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let settings = self.settings.load();
+        let budget = settings.storage.capture_memory_bytes;
+        let cookie_name = settings.challenge.cookie_name.as_str();
+        let now = Instant::now();
+        let mut captures = self.captures.lock();
+        let mut used: usize = captures.iter().map(|c| c.bytes).sum();
+        for capture in captures.iter_mut() {
+            capture.finish_if_done(now);
+            if !capture.is_running(now)
+                || !capture.spec.matches_request(entry.client_ip, entry.path)
+                || capture.spec.action.as_deref().is_some_and(|action| action != entry.action)
+            {
+                continue;
+            }
+            let request = CapturedRequest {
+                timestamp: Utc::now(),
+                client_ip: entry.client_ip,
+                method: entry.method.to_string(),
+                host: entry.host.to_string(),
+                path: entry.path.to_string(),
+                query: query.map(str::to_string),
+                headers: header_fields(headers, capture.spec.redact, cookie_name),
+                body: body.map(|b| String::from_utf8_lossy(&b[..b.len().min(capture.spec.max_body_bytes)]).into_owned()),
+                action: entry.action.to_string(),
+                reason: reason.map(|r| r.to_string()),
+                status: entry.status,
+                country: entry.country.map(str::to_string),
+                asn: entry.asn,
+                ja3: entry.ja3.map(str::to_string),
+                ray_id: entry.ray_id.to_string(),
+            };
+            let size = request.size();
+            if used + size > budget {
+                capture.dropped += 1;
+                continue;
+            }
+            used += size;
+            capture.bytes += size;
+            capture.requests.push(request);
+            capture.finish_if_done(now);
+        }
+        self.update_active(&captures, now);
+    }
+
+    /// Finish captures that ran out of time and drop the ones finished
+    /// more than `storage.capture_retention_secs` ago.
+    pub fn cleanup(&self) {
+        let mut captures = self.captures.lock();
+        self.expire(&mut captures, Instant::now());
+    }
+
+    fn expire(&self, captures: &mut Vec<Capture>, now: Instant) {
+        let retention = Duration::from_secs(self.settings.load().storage.capture_retention_secs);
+        for capture in captures.iter_mut() {
+            capture.finish_if_done(now);
+        }
+        captures.retain(|c| c.finished.is_none_or(|at| now.duration_since(at) < retention));
+        self.update_active(captures, now);
+    }
+
+    fn update_active(&self, captures: &[Capture], now: Instant) {
+        self.active.store(captures.iter().any(|c| c.is_running(now)), Ordering::Relaxed);
+    }
+}
+
+/// The request's header fields, with credentials replaced when `redact`
+/// is set.
+fn header_fields(headers: &HeaderMap, redact: bool, cookie_name: &str) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = if !redact {
+                value.into_owned()
+            } else if name == AUTHORIZATION || name == PROXY_AUTHORIZATION {
+                REDACTED.to_string()
+            } else if name == COOKIE {
+                redact_cookie(&value, cookie_name)
+            } else {
+                value.into_owned()
+            };
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+/// `header` with the value of the `name` cookie replaced.
+fn redact_cookie(header: &str, name: &str) -> String {
+    header
+        .split(';')
+        .map(|pair| match pair.trim().split_once('=') {
+            Some((key, _)) if key == name => format!("{}={}", key, REDACTED),
+            _ => pair.trim().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::config::settings::Settings;
+
+    fn entry<'a>(ip: IpAddr, path: &'a str, action: &'a str) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            client_ip: ip,
+            method: "POST",
+            path,
+            host: "example.com",
+            protocol: "HTTP/1.1",
+            status: 403,
+            action,
+            reason: None,
+            latency_us: 100,
+            bytes: 10,
+            country: None,
+            asn: None,
+            ja3: None,
+            user_agent: "curl/8.5.0",
+            referer: None,
+            ray_id: "ray",
+            request_id: None,
+            service_id: None,
+            route: None,
+        }
+    }
+
+    fn spec(ip: IpAddr) -> CaptureSpec {
+        CaptureSpec {
+            ip: Some(ip),
+            subnet: None,
+            path_prefix: Some("/login".to_string()),
+            action: Some("blocked".to_string()),
+            max_requests: 2,
+            duration: Duration::from_secs(60),
+            max_body_bytes: 4,
+            redact: true,
+        }
+    }
+
+    #[test]
+    fn test_captures_matching_requests_within_limits() {
+        let mut settings = Settings::default();
+        let shared: SharedSettings = Arc::new(ArcSwap::from_pointee(settings.clone()));
+        let capture = RequestCapture::new(Arc::clone(&shared));
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        headers.append(COOKIE, "theme=dark; __fortress_clearance=abc".parse().unwrap());
+        headers.append("x-seen", "1".parse().unwrap());
+        headers.append("x-seen", "2".parse().unwrap());
+
+        // Nothing is read ahead or kept while no capture runs
+        assert_eq!(capture.body_bytes_wanted(ip, "/login"), None);
+        let id = capture.start(spec(ip)).unwrap().id;
+        assert_eq!(capture.body_bytes_wanted(ip, "/login"), Some(4));
+        assert_eq!(capture.body_bytes_wanted(ip, "/"), None);
+
+        capture.record(&entry(ip, "/login", "passed"), None, &headers, None, None);
+        capture.record(&entry(ip, "/login", "blocked"), Some("a=1"), &headers, Some(b"user=x"), Some(ThreatReason::RateLimit));
+        let (summary, requests) = capture.get(id).unwrap();
+        assert_eq!((summary.status, summary.captured), ("running", 1));
+        let request = &requests[0];
+        assert_eq!(request.body.as_deref(), Some("user"));
+        assert_eq!(request.headers[0], ("authorization".to_string(), REDACTED.to_string()));
+        assert_eq!(request.headers[1].1, "theme=dark; __fortress_clearance=[redacted]");
+        assert_eq!(request.headers.iter().filter(|(name, _)| name == "x-seen").count(), 2);
+
+        // Full after max_requests
+        capture.record(&entry(ip, "/login", "blocked"), None, &headers, None, None);
+        assert_eq!(capture.get(id).unwrap().0.status, "finished");
+        assert!(!capture.active.load(Ordering::Relaxed));
+
+        // The budget is shared by all captures and never exceeded
+        settings.storage.capture_memory_bytes = capture.get(id).unwrap().0.bytes + 100;
+        shared.store(Arc::new(settings.clone()));
+        let second = capture.start(spec(ip)).unwrap().id;
+        capture.record(&entry(ip, "/login", "blocked"), None, &headers, None, None);
+        let (summary, _) = capture.get(second).unwrap();
+        assert_eq!((summary.captured, summary.dropped), (0, 1));
+
+        // Finished captures expire after the retention period
+        settings.storage.capture_retention_secs = 0;
+        shared.store(Arc::new(settings));
+        capture.cleanup();
+        assert!(capture.get(id).is_none());
+        assert!(capture.remove(second));
+        assert!(capture.list().is_empty());
+    }
+}
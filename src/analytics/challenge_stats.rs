@@ -0,0 +1,249 @@
+//! Challenge outcomes per hour, protection level and service, for telling
+//! a PoW difficulty that humans give up on from one bots get through.
+//!
+//! Counts are kept in memory for the last `HOURS_KEPT` hours. Issued
+//! challenges are counted at the level they were served at; verifications
+//! at the level in force when the solution arrives, which is the same
+//! unless the level changed in between. Time to solve is measured from the
+//! timestamp signed into the challenge token.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::analytics::history::unix_now;
+use crate::models::threat::ProtectionLevel;
+
+/// Hours of counts kept, the current one included.
+pub const HOURS_KEPT: u64 = 24;
+
+/// Upper bounds (seconds) of the time-to-solve buckets. Challenges expire
+/// after 300 seconds, so the last bucket holds everything slower.
+const SOLVE_BUCKETS: [u64; 16] = [1, 2, 3, 4, 5, 7, 10, 15, 20, 30, 45, 60, 90, 120, 180, 300];
+
+/// How the client answered the challenge page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeFlow {
+    Pow,
+    Interactive,
+    /// The meta-refresh fallback for clients without JavaScript.
+    Nojs,
+}
+
+/// Result of one verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// Passed; `issued_at` is the Unix time signed into the token.
+    Solved { issued_at: i64 },
+    /// Bad PoW solution, or an invalid, expired or replayed token.
+    Invalid,
+    /// Valid solution, refused for the page's headless browser score.
+    Headless,
+}
+
+/// Where a challenge was served or answered.
+#[derive(Debug, Clone, Copy)]
+pub struct ChallengeSlot<'a> {
+    pub level: ProtectionLevel,
+    pub service: Option<&'a str>,
+}
+
+/// Counts for one hour, level and service, or a sum of them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChallengeCounts {
+    pub issued: u64,
+    /// Solutions and tokens posted, by every flow.
+    pub attempts: u64,
+    pub solved: u64,
+    pub failed_invalid: u64,
+    pub failed_headless: u64,
+    /// The nojs fallback's share of `attempts` and `solved`.
+    pub nojs_attempts: u64,
+    pub nojs_solved: u64,
+    /// Solved over issued; `None` before any challenge was issued.
+    pub solve_rate: Option<f64>,
+    /// Upper bound of the time-to-solve bucket holding the median, in
+    /// seconds.
+    pub median_solve_secs: Option<u64>,
+    #[serde(skip)]
+    solve_times: [u64; SOLVE_BUCKETS.len()],
+}
+
+impl ChallengeCounts {
+    fn add(&mut self, other: &ChallengeCounts) {
+        self.issued += other.issued;
+        self.attempts += other.attempts;
+        self.solved += other.solved;
+        self.failed_invalid += other.failed_invalid;
+        self.failed_headless += other.failed_headless;
+        self.nojs_attempts += other.nojs_attempts;
+        self.nojs_solved += other.nojs_solved;
+        for (sum, n) in self.solve_times.iter_mut().zip(other.solve_times) {
+            *sum += n;
+        }
+    }
+
+    /// Fill in the derived fields.
+    fn finish(mut self) -> Self {
+        self.solve_rate = (self.issued > 0).then(|| self.solved as f64 / self.issued as f64);
+        let timed: u64 = self.solve_times.iter().sum();
+        let mut seen = 0;
+        self.median_solve_secs = self.solve_times.iter().zip(SOLVE_BUCKETS).find_map(|(&n, bound)| {
+            seen += n;
+            (timed > 0 && seen * 2 >= timed).then_some(bound)
+        });
+        self
+    }
+}
+
+/// One row of [`ChallengeStats::hourly`].
+#[derive(Debug, Clone, Serialize)]
+pub struct HourlyChallengeCounts {
+    /// Start of the hour.
+    pub hour: DateTime<Utc>,
+    pub level: u8,
+    pub service_id: Option<String>,
+    #[serde(flatten)]
+    pub counts: ChallengeCounts,
+}
+
+type SlotKey = (u64, u8, Option<String>);
+
+/// Rolling challenge counters, held by the
+/// [`MetricsCollector`](super::collector::MetricsCollector).
+#[derive(Default)]
+pub struct ChallengeStats {
+    slots: Mutex<BTreeMap<SlotKey, ChallengeCounts>>,
+}
+
+impl ChallengeStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a challenge page served.
+    pub fn record_issued(&self, slot: ChallengeSlot<'_>) {
+        self.update(slot, unix_now(), |counts| counts.issued += 1);
+    }
+
+    /// Record a solution or token posted through `flow`, and its result.
+    pub fn record_verification(&self, slot: ChallengeSlot<'_>, flow: ChallengeFlow, result: Verification) {
+        self.record_verification_at(slot, flow, result, unix_now());
+    }
+
+    fn record_verification_at(&self, slot: ChallengeSlot<'_>, flow: ChallengeFlow, result: Verification, now: u64) {
+        self.update(slot, now, |counts| {
+            counts.attempts += 1;
+            if flow == ChallengeFlow::Nojs {
+                counts.nojs_attempts += 1;
+            }
+            match result {
+                Verification::Solved { issued_at } => {
+                    counts.solved += 1;
+                    if flow == ChallengeFlow::Nojs {
+                        counts.nojs_solved += 1;
+                    }
+                    let secs = (now as i64 - issued_at).max(0) as u64;
+                    let bucket = SOLVE_BUCKETS.iter().position(|&bound| secs <= bound).unwrap_or(SOLVE_BUCKETS.len() - 1);
+                    counts.solve_times[bucket] += 1;
+                }
+                Verification::Invalid => counts.failed_invalid += 1,
+                Verification::Headless => counts.failed_headless += 1,
+            }
+        });
+    }
+
+    fn update(&self, slot: ChallengeSlot<'_>, now: u64, f: impl FnOnce(&mut ChallengeCounts)) {
+        let hour = now - now % 3600;
+        let mut slots = self.slots.lock();
+        // Drop the hours that fell out of the window when a new one starts
+        if slots.last_key_value().is_some_and(|((last, _, _), _)| *last < hour) {
+            let oldest = hour.saturating_sub((HOURS_KEPT - 1) * 3600);
+            slots.retain(|(h, _, _), _| *h >= oldest);
+        }
+        let key = (hour, slot.level.as_u8(), slot.service.map(str::to_string));
+        f(slots.entry(key).or_default());
+    }
+
+    /// Counts of the last `hours` hours, oldest first, optionally only for
+    /// one service.
+    pub fn hourly(&self, hours: u64, service: Option<&str>) -> Vec<HourlyChallengeCounts> {
+        let now = unix_now();
+        let from = (now - now % 3600).saturating_sub(hours.clamp(1, HOURS_KEPT).saturating_sub(1) * 3600);
+        let slots = self.slots.lock();
+        slots
+            .range((from, 0, None)..)
+            .filter(|((_, _, svc), _)| service.is_none_or(|s| svc.as_deref() == Some(s)))
+            .map(|((hour, level, svc), counts)| HourlyChallengeCounts {
+                hour: DateTime::from_timestamp(*hour as i64, 0).unwrap_or_default(),
+                level: *level,
+                service_id: svc.clone(),
+                counts: counts.clone().finish(),
+            })
+            .collect()
+    }
+
+    /// Sum of `rows` per protection level.
+    pub fn totals_by_level(rows: &[HourlyChallengeCounts]) -> BTreeMap<u8, ChallengeCounts> {
+        let mut totals: BTreeMap<u8, ChallengeCounts> = BTreeMap::new();
+        for row in rows {
+            totals.entry(row.level).or_default().add(&row.counts);
+        }
+        totals.into_iter().map(|(level, counts)| (level, counts.finish())).collect()
+    }
+
+    /// `(issued, solved)` in the hour starting at `hour`, for every
+    /// service or only `service`.
+    pub fn hour_totals(&self, hour: u64, service: Option<&str>) -> (u64, u64) {
+        let slots = self.slots.lock();
+        slots
+            .range((hour, 0, None)..(hour + 1, 0, None))
+            .filter(|((_, _, svc), _)| service.is_none() || svc.as_deref() == service)
+            .fold((0, 0), |(issued, solved), (_, c)| (issued + c.issued, solved + c.solved))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_per_level_and_service_with_median_solve_time() {
+        let stats = ChallengeStats::new();
+        let now = unix_now();
+        let hour = now - now % 3600;
+        let shop = ChallengeSlot { level: ProtectionLevel::L2, service: Some("shop") };
+        let global = ChallengeSlot { level: ProtectionLevel::L1, service: None };
+
+        for _ in 0..4 {
+            stats.record_issued(shop);
+        }
+        stats.record_issued(global);
+        let solved = |secs: i64| Verification::Solved { issued_at: now as i64 - secs };
+        stats.record_verification_at(shop, ChallengeFlow::Pow, solved(2), now);
+        stats.record_verification_at(shop, ChallengeFlow::Pow, solved(8), now);
+        stats.record_verification_at(shop, ChallengeFlow::Nojs, solved(40), now);
+        stats.record_verification_at(shop, ChallengeFlow::Pow, Verification::Invalid, now);
+        stats.record_verification_at(shop, ChallengeFlow::Interactive, Verification::Headless, now);
+
+        let rows = stats.hourly(1, Some("shop"));
+        assert_eq!(rows.len(), 1);
+        let c = &rows[0].counts;
+        assert_eq!((rows[0].hour.timestamp() as u64, rows[0].level), (hour, 2));
+        assert_eq!((c.issued, c.attempts, c.solved), (4, 5, 3));
+        assert_eq!((c.failed_invalid, c.failed_headless, c.nojs_attempts, c.nojs_solved), (1, 1, 1, 1));
+        assert_eq!(c.solve_rate, Some(0.75));
+        assert_eq!(c.median_solve_secs, Some(10));
+
+        let all = stats.hourly(24, None);
+        assert_eq!(all.len(), 2);
+        let totals = ChallengeStats::totals_by_level(&all);
+        assert_eq!(totals[&1].issued, 1);
+        assert_eq!(totals[&1].median_solve_secs, None);
+        assert_eq!(stats.hour_totals(hour, None), (5, 3));
+        assert_eq!(stats.hour_totals(hour, Some("shop")), (4, 3));
+        assert_eq!(stats.hour_totals(hour - 3600, None), (0, 0));
+    }
+}
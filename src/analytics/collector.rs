@@ -1,11 +1,17 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
 use parking_lot::RwLock;
+use serde::Serialize;
 
 use crate::models::metrics::MetricsSnapshot;
+use crate::storage::sweep::SweepStats;
+
+use super::challenge_stats::ChallengeStats;
+use super::sketch::{HyperLogLog, TopIps};
 
 /// Per-second snapshot of request metrics.
 #[derive(Clone, Debug)]
@@ -15,6 +21,193 @@ pub struct SecondSnapshot {
     pub blocked: u64,
     pub challenged: u64,
     pub passed: u64,
+    /// The share of `passed` let through without inspection.
+    pub bypassed: BypassCounts,
+}
+
+/// Cumulative per-action request counts for a single service.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceCounters {
+    pub passed: u64,
+    pub challenged: u64,
+    pub blocked: u64,
+    /// The share of `passed` let through without inspection.
+    pub bypassed: BypassCounts,
+}
+
+/// Why a request was let through without running the whole protection
+/// pipeline. Bypassed requests are counted as passed, and on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bypass {
+    /// A GET or HEAD for a static asset.
+    Static,
+    /// A CORS preflight with `protection.exempt_cors_preflight`.
+    Preflight,
+    /// A whitelisted IP or verified search engine crawler.
+    Whitelisted,
+}
+
+impl Bypass {
+    pub const ALL: [Bypass; 3] = [Bypass::Static, Bypass::Preflight, Bypass::Whitelisted];
+
+    /// Action label given to [`MetricsCollector::record_request`].
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Bypass::Static => "bypassed_static",
+            Bypass::Preflight => "bypassed_preflight",
+            Bypass::Whitelisted => "whitelisted",
+        }
+    }
+
+    fn from_action(action: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.as_str() == action)
+    }
+}
+
+/// Request counts per [`Bypass`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BypassCounts {
+    pub bypassed_static: u64,
+    pub bypassed_preflight: u64,
+    pub whitelisted: u64,
+}
+
+impl BypassCounts {
+    pub fn get_mut(&mut self, bypass: Bypass) -> &mut u64 {
+        match bypass {
+            Bypass::Static => &mut self.bypassed_static,
+            Bypass::Preflight => &mut self.bypassed_preflight,
+            Bypass::Whitelisted => &mut self.whitelisted,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.bypassed_static + self.bypassed_preflight + self.whitelisted
+    }
+
+    pub fn add(&mut self, other: &BypassCounts) {
+        self.bypassed_static += other.bypassed_static;
+        self.bypassed_preflight += other.bypassed_preflight;
+        self.whitelisted += other.whitelisted;
+    }
+
+    pub fn saturating_sub(&self, other: &BypassCounts) -> BypassCounts {
+        BypassCounts {
+            bypassed_static: self.bypassed_static.saturating_sub(other.bypassed_static),
+            bypassed_preflight: self.bypassed_preflight.saturating_sub(other.bypassed_preflight),
+            whitelisted: self.whitelisted.saturating_sub(other.whitelisted),
+        }
+    }
+
+    fn from_atomics(counters: &[AtomicU64; 3], take: bool) -> BypassCounts {
+        let mut counts = BypassCounts::default();
+        for (bypass, counter) in Bypass::ALL.into_iter().zip(counters) {
+            *counts.get_mut(bypass) = if take { counter.swap(0, Ordering::Relaxed) } else { counter.load(Ordering::Relaxed) };
+        }
+        counts
+    }
+}
+
+/// Live metrics of one service, see [`MetricsCollector::get_service_metrics`].
+#[derive(Clone, Debug)]
+pub struct ServiceMetrics {
+    pub service_id: String,
+    /// Lifetime counts.
+    pub totals: ServiceCounters,
+    pub rps: f64,
+    pub blocked_per_sec: f64,
+    pub challenged_per_sec: f64,
+    pub passed_per_sec: f64,
+    /// Average handling time this hour.
+    pub avg_latency_ms: f64,
+    /// Per-second snapshots, most recent last.
+    pub history: Vec<SecondSnapshot>,
+}
+
+/// Counters of one service; updated under its `DashMap` shard lock.
+#[derive(Default)]
+struct ServiceStats {
+    totals: ServiceCounters,
+    // ---- reset every second ----
+    second: ServiceCounters,
+    // ---- reset every hour ----
+    latency_us: u64,
+    latency_count: u64,
+    /// Per-second snapshots (last `MAX_SERVICE_SNAPSHOTS`).
+    snapshots: VecDeque<SecondSnapshot>,
+}
+
+impl ServiceStats {
+    fn metrics(&self, service_id: &str, last_n: usize) -> ServiceMetrics {
+        let last = self.snapshots.back();
+        let per_sec = |f: fn(&SecondSnapshot) -> u64| last.map(|s| f(s) as f64).unwrap_or(0.0);
+        ServiceMetrics {
+            service_id: service_id.to_string(),
+            totals: self.totals.clone(),
+            rps: per_sec(|s| s.requests),
+            blocked_per_sec: per_sec(|s| s.blocked),
+            challenged_per_sec: per_sec(|s| s.challenged),
+            passed_per_sec: per_sec(|s| s.passed),
+            avg_latency_ms: if self.latency_count > 0 {
+                self.latency_us as f64 / self.latency_count as f64 / 1000.0
+            } else {
+                0.0
+            },
+            history: self.snapshots.iter().skip(self.snapshots.len().saturating_sub(last_n)).cloned().collect(),
+        }
+    }
+}
+
+/// Lifetime totals of requests sent to an upstream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UpstreamTotals {
+    pub responses: u64,
+    /// Timeouts, connection failures and 5xx responses.
+    pub errors: u64,
+    /// Summed time to the response headers.
+    pub latency_us: u64,
+}
+
+/// Cost of the periodic cleanup of one component, see
+/// [`MetricsCollector::record_cleanup`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CleanupMetrics {
+    pub runs: u64,
+    pub last_duration_ms: f64,
+    pub max_duration_ms: f64,
+    pub total_duration_ms: f64,
+    /// Lifetime entries looked at and removed.
+    pub visited: u64,
+    pub evicted: u64,
+    /// Entries in the component's largest map after the last run.
+    pub tracked: usize,
+}
+
+/// Why the HTTP -> HTTPS redirect listener closed a connection without
+/// answering it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectDrop {
+    /// Blocklisted or auto-banned.
+    Blocked,
+    /// Refused or tarpitted by L4 protection.
+    L4,
+    /// `server.max_redirect_connections` were being served.
+    Capacity,
+    /// No complete, valid request head within the header timeout.
+    Malformed,
+}
+
+impl RedirectDrop {
+    pub const ALL: [RedirectDrop; 4] = [RedirectDrop::Blocked, RedirectDrop::L4, RedirectDrop::Capacity, RedirectDrop::Malformed];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RedirectDrop::Blocked => "blocked",
+            RedirectDrop::L4 => "l4",
+            RedirectDrop::Capacity => "capacity",
+            RedirectDrop::Malformed => "malformed",
+        }
+    }
 }
 
 /// Real-time metrics collector with per-second granularity.
@@ -29,12 +222,13 @@ pub struct MetricsCollector {
     current_second_blocked: AtomicU64,
     current_second_challenged: AtomicU64,
     current_second_passed: AtomicU64,
+    current_second_bypassed: [AtomicU64; 3],
 
     // Rolling per-second snapshots (last 3600 = 1 hour)
     second_snapshots: RwLock<Vec<SecondSnapshot>>,
 
-    // Per-IP request count (for top-IPs)
-    ip_counts: DashMap<IpAddr, u64>,
+    // Heaviest IPs by request count this hour (bounded, for top-IPs)
+    ip_counts: TopIps,
 
     // Per-country counts
     country_counts: DashMap<String, u64>,
@@ -49,17 +243,52 @@ pub struct MetricsCollector {
     total_latency_us: AtomicU64,
     latency_count: AtomicU64,
 
-    // Unique IPs seen this hour
-    unique_ips: DashMap<IpAddr, ()>,
+    // Upstream outcomes and time to response headers, never reset; the
+    // escalation check works on the change between checks
+    upstream_responses: AtomicU64,
+    upstream_errors: AtomicU64,
+    upstream_latency_us: AtomicU64,
+
+    // Request bodies refused for their Content-Encoding, and bodies whose
+    // decoding was aborted (too large, too expansive, corrupt)
+    encoded_bodies_rejected: AtomicU64,
+    encoded_bodies_aborted: AtomicU64,
+
+    // Redirects sent by the HTTP -> HTTPS listener, and connections it
+    // dropped, indexed by `RedirectDrop`
+    http_redirects: AtomicU64,
+    http_redirect_drops: [AtomicU64; 4],
+
+    // Challenges issued and verified, per hour, level and service
+    challenges: ChallengeStats,
+
+    // Periodic cleanup cost per component, and the current cleanup interval
+    cleanup: RwLock<BTreeMap<&'static str, CleanupMetrics>>,
+    cleanup_interval_ms: AtomicU64,
+
+    // Estimated unique IPs seen this hour
+    unique_ips: HyperLogLog,
+
+    // Per-service counters and snapshots (keyed by service id), for at
+    // most MAX_SERVICES services
+    services: DashMap<String, ServiceStats>,
 
     // Total counters (never reset, used for lifetime stats)
     total_requests: AtomicU64,
     total_blocked: AtomicU64,
+    total_challenged: AtomicU64,
+    total_passed: AtomicU64,
+    total_bypassed: [AtomicU64; 3],
 
     start_time: Instant,
 }
 
 const MAX_SNAPSHOTS: usize = 3600;
+/// Per-second snapshots kept per service (5 minutes).
+const MAX_SERVICE_SNAPSHOTS: usize = 300;
+/// Services tracked at most; requests of services beyond this are only
+/// counted globally.
+const MAX_SERVICES: usize = 1024;
 
 impl MetricsCollector {
     /// Create a new, zeroed-out collector.
@@ -69,10 +298,11 @@ impl MetricsCollector {
             current_second_blocked: AtomicU64::new(0),
             current_second_challenged: AtomicU64::new(0),
             current_second_passed: AtomicU64::new(0),
+            current_second_bypassed: Default::default(),
 
             second_snapshots: RwLock::new(Vec::with_capacity(MAX_SNAPSHOTS)),
 
-            ip_counts: DashMap::new(),
+            ip_counts: TopIps::new(),
             country_counts: DashMap::new(),
             asn_counts: DashMap::new(),
             ja3_counts: DashMap::new(),
@@ -80,10 +310,30 @@ impl MetricsCollector {
             total_latency_us: AtomicU64::new(0),
             latency_count: AtomicU64::new(0),
 
-            unique_ips: DashMap::new(),
+            upstream_responses: AtomicU64::new(0),
+            upstream_errors: AtomicU64::new(0),
+            upstream_latency_us: AtomicU64::new(0),
+
+            encoded_bodies_rejected: AtomicU64::new(0),
+            encoded_bodies_aborted: AtomicU64::new(0),
+
+            http_redirects: AtomicU64::new(0),
+            http_redirect_drops: Default::default(),
+
+            challenges: ChallengeStats::new(),
+
+            cleanup: RwLock::new(BTreeMap::new()),
+            cleanup_interval_ms: AtomicU64::new(0),
+
+            unique_ips: HyperLogLog::new(),
+
+            services: DashMap::new(),
 
             total_requests: AtomicU64::new(0),
             total_blocked: AtomicU64::new(0),
+            total_challenged: AtomicU64::new(0),
+            total_passed: AtomicU64::new(0),
+            total_bypassed: Default::default(),
 
             start_time: Instant::now(),
         }
@@ -91,7 +341,8 @@ impl MetricsCollector {
 
     /// Record a single request on the hot path.
     ///
-    /// `action` must be one of `"blocked"`, `"challenged"`, or `"passed"`.
+    /// `action` must be one of `"blocked"`, `"challenged"`, `"passed"`, or
+    /// a [`Bypass`] label, which also counts as passed.
     pub fn record_request(
         &self,
         ip: IpAddr,
@@ -112,20 +363,23 @@ impl MetricsCollector {
             }
             "challenged" => {
                 self.current_second_challenged.fetch_add(1, Ordering::Relaxed);
+                self.total_challenged.fetch_add(1, Ordering::Relaxed);
             }
             _ => {
                 self.current_second_passed.fetch_add(1, Ordering::Relaxed);
+                self.total_passed.fetch_add(1, Ordering::Relaxed);
+                if let Some(bypass) = Bypass::from_action(action) {
+                    self.current_second_bypassed[bypass as usize].fetch_add(1, Ordering::Relaxed);
+                    self.total_bypassed[bypass as usize].fetch_add(1, Ordering::Relaxed);
+                }
             }
         }
 
         // Per-IP
-        self.ip_counts
-            .entry(ip)
-            .and_modify(|c| *c += 1)
-            .or_insert(1);
+        self.ip_counts.insert(ip);
 
         // Unique IP tracking
-        self.unique_ips.entry(ip).or_insert(());
+        self.unique_ips.insert(ip);
 
         // Per-country
         if let Some(cc) = country {
@@ -156,6 +410,119 @@ impl MetricsCollector {
         self.latency_count.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Record one request sent to an upstream: how long the response
+    /// headers took and whether it failed. Unlike the latency given to
+    /// `record_request` this leaves out the protection pipeline and any
+    /// time spent reading the request body for inspection.
+    pub fn record_upstream(&self, latency_us: u64, error: bool) {
+        self.upstream_responses.fetch_add(1, Ordering::Relaxed);
+        self.upstream_latency_us.fetch_add(latency_us, Ordering::Relaxed);
+        if error {
+            self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn upstream_totals(&self) -> UpstreamTotals {
+        UpstreamTotals {
+            responses: self.upstream_responses.load(Ordering::Relaxed),
+            errors: self.upstream_errors.load(Ordering::Relaxed),
+            latency_us: self.upstream_latency_us.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record a request body blocked for its `Content-Encoding`: refused
+    /// outright, or `aborted` while decoding it for inspection.
+    pub fn record_encoded_body(&self, aborted: bool) {
+        if aborted {
+            self.encoded_bodies_aborted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.encoded_bodies_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Lifetime `(rejected, aborted)` encoded body counts.
+    pub fn encoded_body_totals(&self) -> (u64, u64) {
+        (
+            self.encoded_bodies_rejected.load(Ordering::Relaxed),
+            self.encoded_bodies_aborted.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Record one answer of the HTTP -> HTTPS redirect listener.
+    pub fn record_http_redirect(&self) {
+        self.http_redirects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a connection the redirect listener closed unanswered.
+    pub fn record_http_redirect_drop(&self, reason: RedirectDrop) {
+        self.http_redirect_drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Lifetime redirects sent, and connections dropped by reason.
+    pub fn http_redirect_totals(&self) -> (u64, [(RedirectDrop, u64); 4]) {
+        (
+            self.http_redirects.load(Ordering::Relaxed),
+            RedirectDrop::ALL.map(|reason| (reason, self.http_redirect_drops[reason as usize].load(Ordering::Relaxed))),
+        )
+    }
+
+    pub fn challenges(&self) -> &ChallengeStats {
+        &self.challenges
+    }
+
+    /// Record one cleanup run of `component` and what it did.
+    pub fn record_cleanup(&self, component: &'static str, elapsed: Duration, stats: SweepStats) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let mut cleanup = self.cleanup.write();
+        let m = cleanup.entry(component).or_default();
+        m.runs += 1;
+        m.last_duration_ms = ms;
+        m.max_duration_ms = m.max_duration_ms.max(ms);
+        m.total_duration_ms += ms;
+        m.visited += stats.visited as u64;
+        m.evicted += stats.removed as u64;
+        m.tracked = stats.largest;
+    }
+
+    /// Note the delay until the next cleanup run.
+    pub fn set_cleanup_interval(&self, interval: Duration) {
+        self.cleanup_interval_ms.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Current cleanup interval in milliseconds (0 before the first run)
+    /// and the cost of each component's cleanup so far.
+    pub fn cleanup_metrics(&self) -> (u64, Vec<(&'static str, CleanupMetrics)>) {
+        let components = self.cleanup.read().iter().map(|(name, m)| (*name, *m)).collect();
+        (self.cleanup_interval_ms.load(Ordering::Relaxed), components)
+    }
+
+    /// Attribute a request outcome to a service. Called alongside
+    /// `record_request` when the Host header resolved to a known service.
+    pub fn record_service_request(&self, service_id: &str, action: &str, latency_us: u64) {
+        let mut stats = match self.services.get_mut(service_id) {
+            Some(stats) => stats,
+            None if self.services.len() < MAX_SERVICES => {
+                self.services.entry(service_id.to_string()).or_default()
+            }
+            None => return,
+        };
+        let stats = &mut *stats;
+        for counters in [&mut stats.totals, &mut stats.second] {
+            match action {
+                "blocked" => counters.blocked += 1,
+                "challenged" => counters.challenged += 1,
+                _ => {
+                    counters.passed += 1;
+                    if let Some(bypass) = Bypass::from_action(action) {
+                        *counters.bypassed.get_mut(bypass) += 1;
+                    }
+                }
+            }
+        }
+        stats.latency_us += latency_us;
+        stats.latency_count += 1;
+    }
+
     /// Called every second by the reporter.  Snapshots current counters into
     /// the rolling ring buffer and resets the per-second atomics.
     pub fn tick(&self) {
@@ -163,6 +530,7 @@ impl MetricsCollector {
         let blocked = self.current_second_blocked.swap(0, Ordering::Relaxed);
         let challenged = self.current_second_challenged.swap(0, Ordering::Relaxed);
         let passed = self.current_second_passed.swap(0, Ordering::Relaxed);
+        let bypassed = BypassCounts::from_atomics(&self.current_second_bypassed, true);
 
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -175,6 +543,7 @@ impl MetricsCollector {
             blocked,
             challenged,
             passed,
+            bypassed,
         };
 
         let mut snapshots = self.second_snapshots.write();
@@ -182,6 +551,22 @@ impl MetricsCollector {
             snapshots.remove(0);
         }
         snapshots.push(snapshot);
+        drop(snapshots);
+
+        for mut stats in self.services.iter_mut() {
+            let second = std::mem::take(&mut stats.second);
+            if stats.snapshots.len() >= MAX_SERVICE_SNAPSHOTS {
+                stats.snapshots.pop_front();
+            }
+            stats.snapshots.push_back(SecondSnapshot {
+                timestamp: now,
+                requests: second.passed + second.challenged + second.blocked,
+                blocked: second.blocked,
+                challenged: second.challenged,
+                passed: second.passed,
+                bypassed: second.bypassed,
+            });
+        }
     }
 
     /// Requests recorded during the most recent completed second.
@@ -213,7 +598,7 @@ impl MetricsCollector {
                 let snaps = self.second_snapshots.read();
                 snaps.last().map(|s| s.passed as f64).unwrap_or(0.0)
             },
-            unique_ips: self.unique_ips.len() as u64,
+            unique_ips: self.unique_ips.estimate(),
             avg_latency_ms: avg_latency_us / 1000.0,
             total_requests: self.total_requests.load(Ordering::Relaxed),
             total_blocked: self.total_blocked.load(Ordering::Relaxed),
@@ -221,16 +606,10 @@ impl MetricsCollector {
         }
     }
 
-    /// Return the top N IPs by request count, sorted descending.
+    /// Return the top N IPs by request count, sorted descending. Counts
+    /// are upper bounds, see [`TopIps`].
     pub fn get_top_ips(&self, limit: usize) -> Vec<(IpAddr, u64)> {
-        let mut entries: Vec<(IpAddr, u64)> = self
-            .ip_counts
-            .iter()
-            .map(|entry| (*entry.key(), *entry.value()))
-            .collect();
-        entries.sort_by(|a, b| b.1.cmp(&a.1));
-        entries.truncate(limit);
-        entries
+        self.ip_counts.top(limit)
     }
 
     /// Return the top N countries by request count, sorted descending.
@@ -280,7 +659,21 @@ impl MetricsCollector {
         }
     }
 
+    /// Per-second snapshots with `from <= timestamp < to` (oldest first).
+    pub fn get_second_range(&self, from: u64, to: u64) -> Vec<SecondSnapshot> {
+        let snapshots = self.second_snapshots.read();
+        let start = snapshots.partition_point(|s| s.timestamp < from);
+        let end = snapshots.partition_point(|s| s.timestamp < to);
+        snapshots[start..end.max(start)].to_vec()
+    }
+
+    /// Timestamp of the oldest per-second snapshot still held in memory.
+    pub fn oldest_second(&self) -> Option<u64> {
+        self.second_snapshots.read().first().map(|s| s.timestamp)
+    }
+
     /// Clear all per-hour aggregates. Called by the reporter every hour.
+    /// The per-second ring is capped on its own and is kept.
     pub fn reset_hourly(&self) {
         self.ip_counts.clear();
         self.country_counts.clear();
@@ -289,7 +682,10 @@ impl MetricsCollector {
         self.unique_ips.clear();
         self.total_latency_us.store(0, Ordering::Relaxed);
         self.latency_count.store(0, Ordering::Relaxed);
-        self.second_snapshots.write().clear();
+        for mut stats in self.services.iter_mut() {
+            stats.latency_us = 0;
+            stats.latency_count = 0;
+        }
     }
 
     /// Total requests recorded since the collector was created.
@@ -302,6 +698,51 @@ impl MetricsCollector {
         self.total_blocked.load(Ordering::Relaxed)
     }
 
+    /// Lifetime `(passed, challenged, blocked)` totals.
+    pub fn action_totals(&self) -> (u64, u64, u64) {
+        (
+            self.total_passed.load(Ordering::Relaxed),
+            self.total_challenged.load(Ordering::Relaxed),
+            self.total_blocked.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Lifetime counts of bypassed requests.
+    pub fn bypass_totals(&self) -> BypassCounts {
+        BypassCounts::from_atomics(&self.total_bypassed, false)
+    }
+
+    /// Bypassed requests during the most recent completed second.
+    pub fn get_current_bypassed(&self) -> BypassCounts {
+        self.second_snapshots.read().last().map(|s| s.bypassed).unwrap_or_default()
+    }
+
+    /// Lifetime per-service counters, sorted by service id.
+    pub fn get_service_counts(&self) -> Vec<(String, ServiceCounters)> {
+        let mut entries: Vec<(String, ServiceCounters)> = self
+            .services
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().totals.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Live metrics of a service with the last `last_n` per-second
+    /// snapshots; `None` if it has not had any requests.
+    pub fn get_service_metrics(&self, service_id: &str, last_n: usize) -> Option<ServiceMetrics> {
+        self.services.get(service_id).map(|stats| stats.metrics(service_id, last_n))
+    }
+
+    /// Live metrics of every service, without per-second history, sorted
+    /// by service id.
+    pub fn get_all_service_metrics(&self) -> Vec<ServiceMetrics> {
+        let mut entries: Vec<ServiceMetrics> =
+            self.services.iter().map(|entry| entry.value().metrics(entry.key(), 0)).collect();
+        entries.sort_by(|a, b| a.service_id.cmp(&b.service_id));
+        entries
+    }
+
     /// Uptime in seconds.
     pub fn uptime_secs(&self) -> u64 {
         self.start_time.elapsed().as_secs()
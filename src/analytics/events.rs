@@ -0,0 +1,486 @@
+//! Structured security events for SIEM ingestion.
+//!
+//! The pipeline, auto-ban and the escalation check publish a
+//! [`SecurityEvent`] for every block, challenge, ban and level change on
+//! the [`EventBus`]. Two sinks subscribe to it: an NDJSON file, rotated
+//! like the access log, and an HTTP forwarder that POSTs batches as JSON
+//! arrays and spools them to disk while the endpoint is down.
+//!
+//! Publishing never waits: each sink reads from its own bounded queue, and
+//! a sink that falls behind loses the oldest events, which are counted in
+//! `dropped_events`.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::Full;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tracing::{info, warn};
+
+use crate::config::settings::{SecurityEventsFileConfig, SecurityEventsHttpConfig};
+use crate::proxy::access_log::LogWriter;
+
+type ForwardClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// First retry delay of a failed POST; doubled on every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    Block,
+    Challenge,
+    AutoBan,
+    Escalation,
+}
+
+/// One security event, serialized as a flat JSON object. Fields that do
+/// not apply to an event type are `null`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityEvent {
+    /// RFC 3339, UTC, milliseconds.
+    pub timestamp: String,
+    pub event_type: EventType,
+    pub ip: Option<IpAddr>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub service_id: Option<String>,
+    /// Managed rule that matched, if any.
+    pub rule: Option<String>,
+    pub reason: Option<String>,
+    pub score: Option<f64>,
+    /// `block`, `challenge`, `tarpit`, `ban`, `escalate` or `deescalate`.
+    pub action: String,
+    pub ray_id: Option<String>,
+    /// Protection level after an escalation.
+    pub level: Option<u8>,
+}
+
+impl SecurityEvent {
+    /// An event stamped now, with only its type and action set.
+    pub fn new(event_type: EventType, action: impl Into<String>) -> Self {
+        Self {
+            timestamp: Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            event_type,
+            ip: None,
+            country: None,
+            asn: None,
+            service_id: None,
+            rule: None,
+            reason: None,
+            score: None,
+            action: action.into(),
+            ray_id: None,
+            level: None,
+        }
+    }
+}
+
+/// Fan-out of security events to the sinks.
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<SecurityEvent>>,
+    published: AtomicU64,
+    dropped: AtomicU64,
+    /// Bytes waiting in the HTTP forwarder's spool.
+    spooled_bytes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventBusStats {
+    pub published: u64,
+    pub dropped_events: u64,
+    pub spooled_bytes: u64,
+}
+
+impl EventBus {
+    /// A bus whose sinks each queue up to `capacity` events.
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        Self {
+            tx,
+            published: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            spooled_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether any sink is listening. Publishers check this before
+    /// building an event.
+    pub fn is_active(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    /// Hand `event` to every sink without waiting.
+    pub fn publish(&self, event: SecurityEvent) {
+        if self.tx.send(Arc::new(event)).is_ok() {
+            self.published.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<SecurityEvent>> {
+        self.tx.subscribe()
+    }
+
+    fn record_dropped(&self, events: u64) {
+        self.dropped.fetch_add(events, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> EventBusStats {
+        EventBusStats {
+            published: self.published.load(Ordering::Relaxed),
+            dropped_events: self.dropped.load(Ordering::Relaxed),
+            spooled_bytes: self.spooled_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn to_line(event: &SecurityEvent) -> String {
+    serde_json::to_string(event).unwrap_or_default()
+}
+
+/// Write events to `config.path`, one JSON object per line, from a
+/// dedicated thread.
+pub fn start_file_sink(bus: &Arc<EventBus>, config: &SecurityEventsFileConfig) -> io::Result<()> {
+    let path = PathBuf::from(&config.path);
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let mut writer = LogWriter::open(path, config.max_size_mb * 1024 * 1024, config.max_files.max(1))?;
+    let mut rx = bus.subscribe();
+    let bus = bus.clone();
+    std::thread::Builder::new().name("security-events".to_string()).spawn(move || {
+        loop {
+            match rx.blocking_recv() {
+                Ok(event) => writer.write_line(&to_line(&event)),
+                Err(RecvError::Lagged(n)) => bus.record_dropped(n),
+                Err(RecvError::Closed) => break,
+            }
+            loop {
+                match rx.try_recv() {
+                    Ok(event) => writer.write_line(&to_line(&event)),
+                    Err(TryRecvError::Lagged(n)) => bus.record_dropped(n),
+                    Err(_) => break,
+                }
+            }
+            writer.flush();
+        }
+    })?;
+    Ok(())
+}
+
+/// Forward events to `config.url` from a background task.
+pub fn start_http_forwarder(bus: &Arc<EventBus>, config: &SecurityEventsHttpConfig) -> tokio::task::JoinHandle<()> {
+    let rx = bus.subscribe();
+    tokio::spawn(HttpForwarder::new(bus.clone(), config.clone()).run(rx))
+}
+
+/// POSTs events to `config.url` in batches of up to `batch_size`, at
+/// least every `flush_interval_ms`.
+///
+/// A batch still failing after `max_retries` is appended to the spool
+/// file. While the spool holds events, each flush first tries to resend
+/// it, once and in order, so events reach the endpoint in the order they
+/// happened; new batches join the spool until that succeeds.
+struct HttpForwarder {
+    config: SecurityEventsHttpConfig,
+    client: ForwardClient,
+    bus: Arc<EventBus>,
+    spool: PathBuf,
+    failing: bool,
+}
+
+impl HttpForwarder {
+    fn new(bus: Arc<EventBus>, config: SecurityEventsHttpConfig) -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let spool = PathBuf::from(&config.spool_path);
+        if let Some(parent) = spool.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        // Events spooled before a restart are sent first
+        let spooled = std::fs::metadata(&spool).map(|m| m.len()).unwrap_or(0);
+        bus.spooled_bytes.store(spooled, Ordering::Relaxed);
+        Self {
+            config,
+            client: Client::builder(TokioExecutor::new())
+                .pool_idle_timeout(Duration::from_secs(30))
+                .build(https),
+            bus,
+            spool,
+            failing: false,
+        }
+    }
+
+    /// Forward events until the bus goes away.
+    async fn run(mut self, mut rx: broadcast::Receiver<Arc<SecurityEvent>>) {
+        let batch_size = self.config.batch_size.max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut tick = tokio::time::interval(Duration::from_millis(self.config.flush_interval_ms.max(10)));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Ok(event) => {
+                        batch.push(to_line(&event));
+                        if batch.len() >= batch_size {
+                            self.flush(std::mem::take(&mut batch)).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => self.bus.record_dropped(n),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick.tick() => {
+                    if !batch.is_empty() || self.spooled() > 0 {
+                        self.flush(std::mem::take(&mut batch)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    fn spooled(&self) -> u64 {
+        self.bus.spooled_bytes.load(Ordering::Relaxed)
+    }
+
+    async fn flush(&mut self, batch: Vec<String>) {
+        if self.spooled() > 0 && !self.drain_spool().await {
+            self.spool_lines(&batch).await;
+            return;
+        }
+        if batch.is_empty() {
+            return;
+        }
+        let mut result = self.post(&batch).await;
+        let mut delay = RETRY_BACKOFF;
+        for _ in 0..self.config.max_retries {
+            if result.is_ok() {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            result = self.post(&batch).await;
+        }
+        if self.record(result) {
+            self.spool_lines(&batch).await;
+        }
+    }
+
+    /// Resend the spool in batches. Returns whether it was emptied; what
+    /// could not be sent is kept.
+    async fn drain_spool(&mut self) -> bool {
+        let content = match tokio::fs::read_to_string(&self.spool).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                warn!(path = %self.spool.display(), "Failed to read security event spool: {}", e);
+                return false;
+            }
+        };
+        let lines: Vec<String> = content.lines().filter(|l| !l.is_empty()).map(str::to_string).collect();
+        for (i, chunk) in lines.chunks(self.config.batch_size.max(1)).enumerate() {
+            let result = self.post(chunk).await;
+            if self.record(result) {
+                let sent = i * self.config.batch_size.max(1);
+                self.rewrite_spool(&lines[sent..]).await;
+                return false;
+            }
+        }
+        let _ = tokio::fs::remove_file(&self.spool).await;
+        self.bus.spooled_bytes.store(0, Ordering::Relaxed);
+        true
+    }
+
+    async fn rewrite_spool(&self, lines: &[String]) {
+        let content: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        match tokio::fs::write(&self.spool, &content).await {
+            Ok(()) => self.bus.spooled_bytes.store(content.len() as u64, Ordering::Relaxed),
+            Err(e) => warn!(path = %self.spool.display(), "Failed to rewrite security event spool: {}", e),
+        }
+    }
+
+    /// Append `lines` to the spool, or drop them if it is full.
+    async fn spool_lines(&self, lines: &[String]) {
+        if lines.is_empty() {
+            return;
+        }
+        let content: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        if self.spooled() + content.len() as u64 > self.config.spool_max_mb * 1024 * 1024 {
+            self.bus.record_dropped(lines.len() as u64);
+            return;
+        }
+        let written = async {
+            let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.spool).await?;
+            file.write_all(content.as_bytes()).await?;
+            file.flush().await
+        };
+        match written.await {
+            Ok(()) => {
+                self.bus.spooled_bytes.fetch_add(content.len() as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!(path = %self.spool.display(), "Failed to spool security events: {}", e);
+                self.bus.record_dropped(lines.len() as u64);
+            }
+        }
+    }
+
+    async fn post(&self, lines: &[String]) -> Result<(), String> {
+        let body = format!("[{}]", lines.join(","));
+        let mut req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(&self.config.url)
+            .header("Content-Type", "application/json");
+        for (name, value) in &self.config.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let req = req
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| format!("invalid request: {}", e))?;
+        let timeout = Duration::from_secs(self.config.timeout_secs.max(1));
+        let resp = tokio::time::timeout(timeout, self.client.request(req))
+            .await
+            .map_err(|_| "request timed out".to_string())?
+            .map_err(|e| format!("request failed: {}", e))?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP {}", resp.status()));
+        }
+        Ok(())
+    }
+
+    /// Log the endpoint going down or coming back. Returns whether the
+    /// request failed.
+    fn record(&mut self, result: Result<(), String>) -> bool {
+        match result {
+            Ok(()) => {
+                if std::mem::take(&mut self.failing) {
+                    info!(url = %self.config.url, "Security event endpoint recovered");
+                }
+                false
+            }
+            Err(error) => {
+                if !std::mem::replace(&mut self.failing, true) {
+                    warn!(url = %self.config.url, error = %error, "Security event endpoint failing; spooling events");
+                }
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::BodyExt;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::config::defaults::default_security_events_http_config;
+
+    fn block(n: u8) -> SecurityEvent {
+        SecurityEvent { ip: Some(IpAddr::from([10, 0, 0, n])), ..SecurityEvent::new(EventType::Block, "block") }
+    }
+
+    #[tokio::test]
+    async fn test_forwarder_spools_while_the_endpoint_is_down() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let spool = std::env::temp_dir().join(format!("fortress-events-{}.spool", std::process::id()));
+        let _ = std::fs::remove_file(&spool);
+
+        let mut config = default_security_events_http_config();
+        config.url = format!("http://{}/events", addr);
+        config.max_retries = 0;
+        config.spool_path = spool.to_string_lossy().into_owned();
+        let bus = Arc::new(EventBus::new(16));
+        let mut forwarder = HttpForwarder::new(bus.clone(), config);
+
+        // Down: the endpoint answers 503, so the batches are spooled
+        let up = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (server_up, server_received) = (up.clone(), received.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (up, received) = (server_up.clone(), server_received.clone());
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let (up, received) = (up.clone(), received.clone());
+                        async move {
+                            let body = req.into_body().collect().await.unwrap().to_bytes();
+                            let status = if up.load(Ordering::Relaxed) {
+                                let events: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+                                received.lock().extend(events);
+                                StatusCode::OK
+                            } else {
+                                StatusCode::SERVICE_UNAVAILABLE
+                            };
+                            let mut resp = Response::new(Full::new(Bytes::new()));
+                            *resp.status_mut() = status;
+                            Ok::<_, Infallible>(resp)
+                        }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                });
+            }
+        });
+
+        forwarder.flush(vec![to_line(&block(1)), to_line(&block(2))]).await;
+        forwarder.flush(vec![to_line(&block(3))]).await;
+        assert!(bus.stats().spooled_bytes > 0);
+        assert!(received.lock().is_empty());
+
+        // Back up: the spool goes first, in order, then the new batch
+        up.store(true, Ordering::Relaxed);
+        forwarder.flush(vec![to_line(&block(4))]).await;
+        let ips: Vec<String> = received.lock().iter().map(|e| e["ip"].as_str().unwrap().to_string()).collect();
+        assert_eq!(ips, ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"]);
+        assert_eq!(received.lock()[0]["event_type"], "block");
+        assert_eq!(bus.stats().spooled_bytes, 0);
+        assert!(!spool.exists());
+
+        // A full spool drops and counts instead of growing
+        forwarder.config.spool_max_mb = 0;
+        up.store(false, Ordering::Relaxed);
+        forwarder.flush(vec![to_line(&block(5))]).await;
+        assert_eq!(bus.stats().dropped_events, 1);
+    }
+
+    #[test]
+    fn test_slow_sinks_lose_the_oldest_events_and_count_them() {
+        let bus = EventBus::new(2);
+        // Nobody listens yet, so nothing is published
+        bus.publish(block(1));
+        assert!(!bus.is_active());
+        let mut rx = bus.subscribe();
+        for n in 1..=5 {
+            bus.publish(block(n));
+        }
+        match rx.try_recv() {
+            Err(TryRecvError::Lagged(n)) => bus.record_dropped(n),
+            other => panic!("expected a lag, got {:?}", other.map(|e| e.ip)),
+        }
+        assert_eq!(rx.try_recv().unwrap().ip, Some(IpAddr::from([10, 0, 0, 4])));
+        let stats = bus.stats();
+        assert_eq!((stats.published, stats.dropped_events), (5, 3));
+    }
+}
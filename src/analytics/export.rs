@@ -0,0 +1,471 @@
+//! Push emitters for `[metrics.export]`: StatsD over UDP and InfluxDB line
+//! protocol over HTTP, for environments that cannot scrape `/metrics`.
+//!
+//! Both send the snapshot the metrics reporter publishes on every
+//! escalation check, each on its own `interval_secs`. An endpoint that is
+//! down is warned about at most once per [`WARN_INTERVAL`].
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::analytics::collector::ServiceCounters;
+use crate::config::settings::{InfluxdbExportConfig, SharedSettings, StatsdExportConfig};
+use crate::models::metrics::MetricsSnapshot;
+
+type ExportClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimum time between two warnings about the same failing emitter.
+const WARN_INTERVAL: Duration = Duration::from_secs(300);
+
+/// StatsD lines are batched into datagrams of at most this many bytes, so
+/// they fit a 1500-byte MTU.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Metrics as of one escalation check.
+#[derive(Debug, Clone)]
+pub struct ExportSnapshot {
+    /// Unix seconds.
+    pub timestamp: u64,
+    /// Global protection level.
+    pub level: u8,
+    pub metrics: MetricsSnapshot,
+    /// Lifetime `(passed, challenged, blocked)` totals.
+    pub actions: (u64, u64, u64),
+    pub services: Vec<ServiceExport>,
+}
+
+/// Lifetime counters of one service and the level that applies to it.
+#[derive(Debug, Clone)]
+pub struct ServiceExport {
+    pub id: String,
+    pub level: u8,
+    pub counts: ServiceCounters,
+}
+
+pub type SnapshotReceiver = watch::Receiver<Option<Arc<ExportSnapshot>>>;
+
+/// Runs the emitters enabled under `[metrics.export]`. Settings are read on
+/// every interval, so a config reload turns emitters on and off.
+pub struct MetricsExporter {
+    settings: SharedSettings,
+    snapshots: SnapshotReceiver,
+    client: ExportClient,
+}
+
+impl MetricsExporter {
+    pub fn new(settings: SharedSettings, snapshots: SnapshotReceiver) -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            settings,
+            snapshots,
+            client: Client::builder(TokioExecutor::new())
+                .pool_idle_timeout(Duration::from_secs(30))
+                .build(https),
+        }
+    }
+
+    /// The snapshot last published by the reporter.
+    fn latest(&self) -> Option<Arc<ExportSnapshot>> {
+        self.snapshots.borrow().clone()
+    }
+
+    /// Send counters and gauges to `metrics.export.statsd` forever.
+    pub async fn run_statsd(self: Arc<Self>) {
+        let mut emitter = StatsdEmitter::default();
+        let mut failures = FailureLog::default();
+        loop {
+            let config = self.settings.load().metrics.export.statsd.clone();
+            tokio::time::sleep(Duration::from_secs(config.interval_secs.max(1))).await;
+            if !config.enabled {
+                continue;
+            }
+            if let Some(snapshot) = self.latest() {
+                failures.record("statsd", emitter.emit(&config, &snapshot).await);
+            }
+        }
+    }
+
+    /// Write line protocol to `metrics.export.influxdb` forever.
+    pub async fn run_influxdb(self: Arc<Self>) {
+        let mut failures = FailureLog::default();
+        loop {
+            let config = self.settings.load().metrics.export.influxdb.clone();
+            tokio::time::sleep(Duration::from_secs(config.interval_secs.max(1))).await;
+            if !config.enabled {
+                continue;
+            }
+            if let Some(snapshot) = self.latest() {
+                failures.record("influxdb", self.push_influxdb(&config, &snapshot).await);
+            }
+        }
+    }
+
+    async fn push_influxdb(&self, config: &InfluxdbExportConfig, snapshot: &ExportSnapshot) -> Result<(), String> {
+        let url = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=s",
+            config.url.trim_end_matches('/'),
+            query_escape(&config.org),
+            query_escape(&config.bucket),
+        );
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(&url)
+            .header("Authorization", format!("Token {}", config.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Full::new(Bytes::from(influx_lines(snapshot))))
+            .map_err(|e| format!("invalid request: {}", e))?;
+
+        let resp = tokio::time::timeout(PUSH_TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| "request timed out".to_string())?
+            .map_err(|e| format!("request failed: {}", e))?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+/// StatsD socket plus the totals last sent, so counters go out as
+/// increments.
+#[derive(Default)]
+struct StatsdEmitter {
+    socket: Option<(SocketAddr, UdpSocket)>,
+    sent: HashMap<(String, &'static str), u64>,
+}
+
+impl StatsdEmitter {
+    async fn emit(&mut self, config: &StatsdExportConfig, snapshot: &ExportSnapshot) -> Result<(), String> {
+        let target = tokio::net::lookup_host(&config.address)
+            .await
+            .map_err(|e| format!("cannot resolve {}: {}", config.address, e))?
+            .next()
+            .ok_or_else(|| format!("cannot resolve {}", config.address))?;
+        if self.socket.as_ref().is_none_or(|(addr, _)| *addr != target) {
+            let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(local).await.map_err(|e| format!("cannot bind UDP socket: {}", e))?;
+            self.socket = Some((target, socket));
+        }
+
+        let lines = statsd_lines(&config.prefix, snapshot, &mut self.sent);
+        let Some((_, socket)) = self.socket.as_ref() else {
+            return Ok(());
+        };
+        let mut datagram = String::with_capacity(MAX_DATAGRAM);
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                socket.send_to(datagram.as_bytes(), target).await.map_err(|e| e.to_string())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            socket.send_to(datagram.as_bytes(), target).await.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// StatsD lines with DogStatsD tags. Request counters are sent as the
+/// increase since the totals in `sent`, which are updated.
+fn statsd_lines(prefix: &str, snapshot: &ExportSnapshot, sent: &mut HashMap<(String, &'static str), u64>) -> Vec<String> {
+    let name = |metric: &str| {
+        if prefix.is_empty() {
+            metric.to_string()
+        } else {
+            format!("{}.{}", prefix, metric)
+        }
+    };
+    let mut delta = |service: &str, action: &'static str, total: u64| {
+        let last = sent.insert((service.to_string(), action), total).unwrap_or(0);
+        total.saturating_sub(last)
+    };
+
+    let m = &snapshot.metrics;
+    let level = format!("L{}", snapshot.level);
+    let mut lines = vec![
+        format!("{}:{}|g", name("requests_per_second"), m.rps),
+        format!("{}:{}|g", name("unique_ips"), m.unique_ips),
+        format!("{}:{}|g", name("avg_latency_ms"), m.avg_latency_ms),
+        format!("{}:{}|g", name("protection_level"), snapshot.level),
+    ];
+    let (passed, challenged, blocked) = snapshot.actions;
+    for (action, total) in [("passed", passed), ("challenged", challenged), ("blocked", blocked)] {
+        let count = delta("", action, total);
+        lines.push(format!("{}:{}|c|#action:{},level:{}", name("requests"), count, action, level));
+    }
+    for svc in &snapshot.services {
+        let service = statsd_tag(&svc.id);
+        let level = format!("L{}", svc.level);
+        lines.push(format!("{}:{}|g|#service:{}", name("service.protection_level"), svc.level, service));
+        for (action, total) in [
+            ("passed", svc.counts.passed),
+            ("challenged", svc.counts.challenged),
+            ("blocked", svc.counts.blocked),
+        ] {
+            let count = delta(&svc.id, action, total);
+            lines.push(format!(
+                "{}:{}|c|#service:{},action:{},level:{}",
+                name("service.requests"),
+                count,
+                service,
+                action,
+                level
+            ));
+        }
+    }
+    lines
+}
+
+/// InfluxDB line protocol for `snapshot`, counters as lifetime totals.
+fn influx_lines(snapshot: &ExportSnapshot) -> String {
+    let mut out = String::with_capacity(1024);
+    let ts = snapshot.timestamp;
+    let m = &snapshot.metrics;
+    let level = snapshot.level;
+    let _ = writeln!(
+        out,
+        "fortress,level=L{} requests_per_second={},unique_ips={}i,avg_latency_ms={},protection_level={}i {}",
+        level, m.rps, m.unique_ips, m.avg_latency_ms, level, ts
+    );
+    let (passed, challenged, blocked) = snapshot.actions;
+    for (action, total) in [("passed", passed), ("challenged", challenged), ("blocked", blocked)] {
+        let _ = writeln!(out, "fortress_requests,action={},level=L{} total={}i {}", action, level, total, ts);
+    }
+    for svc in &snapshot.services {
+        let service = influx_tag(&svc.id);
+        let _ = writeln!(
+            out,
+            "fortress_service,service={},level=L{} protection_level={}i {}",
+            service, svc.level, svc.level, ts
+        );
+        for (action, total) in [
+            ("passed", svc.counts.passed),
+            ("challenged", svc.counts.challenged),
+            ("blocked", svc.counts.blocked),
+        ] {
+            let _ = writeln!(
+                out,
+                "fortress_service_requests,service={},action={},level=L{} total={}i {}",
+                service, action, svc.level, total, ts
+            );
+        }
+    }
+    out
+}
+
+/// A tag value without the characters that separate DogStatsD tags.
+fn statsd_tag(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+/// A tag value with line protocol's special characters escaped.
+fn influx_tag(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ',' | '=' | ' ' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push('_'),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encode everything but unreserved characters.
+fn query_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+/// Keeps a down endpoint from filling the log: one warning per
+/// [`WARN_INTERVAL`], carrying the number of failures since the last one.
+#[derive(Default)]
+struct FailureLog {
+    last_warned: Option<Instant>,
+    suppressed: u64,
+    failing: bool,
+}
+
+impl FailureLog {
+    fn record(&mut self, emitter: &str, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                if std::mem::take(&mut self.failing) {
+                    info!(emitter, "Metrics export recovered");
+                }
+            }
+            Err(error) => {
+                self.failing = true;
+                let now = Instant::now();
+                if self.last_warned.is_none_or(|at| now.duration_since(at) >= WARN_INTERVAL) {
+                    warn!(emitter, error = %error, suppressed = self.suppressed, "Metrics export failed");
+                    self.last_warned = Some(now);
+                    self.suppressed = 0;
+                } else {
+                    debug!(emitter, error = %error, "Metrics export failed");
+                    self.suppressed += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use arc_swap::ArcSwap;
+    use http_body_util::BodyExt;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::settings::Settings;
+
+    fn snapshot(blocked: u64) -> ExportSnapshot {
+        ExportSnapshot {
+            timestamp: 1_700_000_000,
+            level: 1,
+            metrics: MetricsSnapshot {
+                rps: 12.5,
+                blocked_per_sec: 0.0,
+                challenged_per_sec: 0.0,
+                passed_per_sec: 0.0,
+                unique_ips: 40,
+                avg_latency_ms: 3.0,
+                total_requests: 0,
+                total_blocked: 0,
+                uptime_secs: 0,
+            },
+            actions: (100, 10, blocked),
+            services: vec![ServiceExport {
+                id: "shop api".to_string(),
+                level: 3,
+                counts: ServiceCounters { passed: 50, challenged: 5, blocked, ..Default::default() },
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_statsd_sends_increments_with_tags() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = StatsdExportConfig {
+            enabled: true,
+            address: agent.local_addr().unwrap().to_string(),
+            prefix: "edge".to_string(),
+            interval_secs: 1,
+        };
+        let mut emitter = StatsdEmitter::default();
+        let mut buf = [0u8; 2048];
+
+        emitter.emit(&config, &snapshot(7)).await.unwrap();
+        let len = agent.recv(&mut buf).await.unwrap();
+        let first = String::from_utf8_lossy(&buf[..len]).to_string();
+        for line in [
+            "edge.requests_per_second:12.5|g",
+            "edge.protection_level:1|g",
+            "edge.requests:7|c|#action:blocked,level:L1",
+            "edge.service.protection_level:3|g|#service:shop api",
+            "edge.service.requests:7|c|#service:shop api,action:blocked,level:L3",
+        ] {
+            assert!(first.lines().any(|l| l == line), "{:?} missing from\n{}", line, first);
+        }
+
+        // Counters carry only what happened since the previous interval
+        emitter.emit(&config, &snapshot(9)).await.unwrap();
+        let len = agent.recv(&mut buf).await.unwrap();
+        let second = String::from_utf8_lossy(&buf[..len]).to_string();
+        assert!(second.lines().any(|l| l == "edge.requests:2|c|#action:blocked,level:L1"), "{}", second);
+        assert!(second.lines().any(|l| l == "edge.requests:0|c|#action:passed,level:L1"), "{}", second);
+    }
+
+    #[tokio::test]
+    async fn test_influxdb_writes_line_protocol() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                    let tx = tx.clone();
+                    async move {
+                        let uri = req.uri().to_string();
+                        let auth = req.headers()["authorization"].to_str().unwrap().to_string();
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        let _ = tx.send((uri, auth, String::from_utf8_lossy(&body).to_string()));
+                        let mut resp = Response::new(Full::new(Bytes::new()));
+                        *resp.status_mut() = StatusCode::NO_CONTENT;
+                        Ok::<_, Infallible>(resp)
+                    }
+                });
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let settings = Arc::new(ArcSwap::from_pointee(Settings::default()));
+        let (_tx, snapshots) = watch::channel(None);
+        let exporter = MetricsExporter::new(settings, snapshots);
+        let config = InfluxdbExportConfig {
+            enabled: true,
+            url: format!("http://{}/", addr),
+            org: "ops team".to_string(),
+            bucket: "edge".to_string(),
+            token: "secret".to_string(),
+            interval_secs: 1,
+        };
+        exporter.push_influxdb(&config, &snapshot(7)).await.unwrap();
+
+        let (uri, auth, body) = rx.recv().await.unwrap();
+        assert_eq!(uri, "/api/v2/write?org=ops%20team&bucket=edge&precision=s");
+        assert_eq!(auth, "Token secret");
+        for line in [
+            "fortress,level=L1 requests_per_second=12.5,unique_ips=40i,avg_latency_ms=3,protection_level=1i 1700000000",
+            "fortress_requests,action=blocked,level=L1 total=7i 1700000000",
+            "fortress_service,service=shop\\ api,level=L3 protection_level=3i 1700000000",
+            "fortress_service_requests,service=shop\\ api,action=passed,level=L3 total=50i 1700000000",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{:?} missing from\n{}", line, body);
+        }
+
+        // An endpoint that is down is an error, not a panic
+        let down = InfluxdbExportConfig { url: "http://127.0.0.1:1".to_string(), ..config };
+        assert!(exporter.push_influxdb(&down, &snapshot(7)).await.is_err());
+    }
+}
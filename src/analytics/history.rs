@@ -0,0 +1,184 @@
+//! Request-count history over arbitrary ranges, stitched together from the
+//! in-memory per-second ring and the SQLite minute and hour rollups.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::Result;
+use serde::Serialize;
+
+use crate::analytics::collector::{BypassCounts, MetricsCollector, SecondSnapshot};
+use crate::storage::sqlite::{MetricsRow, MinuteMetricsRow, SqliteStore};
+
+/// Request counts for one bucket; `timestamp` is the bucket start.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HistoryBucket {
+    pub timestamp: u64,
+    pub requests: u64,
+    pub blocked: u64,
+    pub challenged: u64,
+    pub passed: u64,
+    /// The share of `passed` let through without inspection.
+    #[serde(flatten)]
+    pub bypassed: BypassCounts,
+}
+
+/// Counts for `[from, to]` (unix seconds) in buckets of `bucket_secs`.
+///
+/// Seconds still held by the collector come from memory. Older data is only
+/// used at a bucket size it can fill: minute rollups for minute and hour
+/// buckets, hourly rows for hour buckets older than the oldest minute
+/// rollup. Per-second data exists only for the in-memory window.
+pub async fn load_history(
+    collector: &MetricsCollector,
+    sqlite: &SqliteStore,
+    from: u64,
+    to: u64,
+    bucket_secs: u64,
+) -> Result<Vec<HistoryBucket>> {
+    let memory_start = collector.oldest_second().unwrap_or_else(unix_now);
+    let seconds = collector.get_second_range(from.max(memory_start), to.saturating_add(1));
+
+    let mut minutes = Vec::new();
+    let mut hours = Vec::new();
+    // Rows are only taken when they end before the in-memory window starts,
+    // so no request is counted twice.
+    if bucket_secs >= 60 && from + 60 <= memory_start {
+        let last_minute = (memory_start - 60).min(to);
+        minutes = sqlite.get_metrics_minutely(to_datetime(from), to_datetime(last_minute)).await?;
+
+        if bucket_secs >= 3600 {
+            let minutes_start = sqlite
+                .oldest_metrics_minute()
+                .await?
+                .and_then(|ts| parse_sql_timestamp(&ts))
+                .map_or(memory_start, |oldest| oldest.min(memory_start));
+            if from + 3600 <= minutes_start {
+                let last_hour = (minutes_start - 3600).min(to);
+                hours = sqlite.get_metrics_history(to_datetime(from), to_datetime(last_hour)).await?;
+            }
+        }
+    }
+
+    Ok(stitch(&seconds, &minutes, &hours, bucket_secs))
+}
+
+/// Sum every source into buckets of `bucket_secs`, oldest first.
+fn stitch(
+    seconds: &[SecondSnapshot],
+    minutes: &[MinuteMetricsRow],
+    hours: &[MetricsRow],
+    bucket_secs: u64,
+) -> Vec<HistoryBucket> {
+    let bucket_secs = bucket_secs.max(1);
+    let mut buckets: BTreeMap<u64, HistoryBucket> = BTreeMap::new();
+    let mut add = |timestamp: u64, requests: u64, blocked: u64, challenged: u64, passed: u64, bypassed: BypassCounts| {
+        let start = timestamp / bucket_secs * bucket_secs;
+        let bucket = buckets.entry(start).or_insert_with(|| HistoryBucket {
+            timestamp: start,
+            ..Default::default()
+        });
+        bucket.requests += requests;
+        bucket.blocked += blocked;
+        bucket.challenged += challenged;
+        bucket.passed += passed;
+        bucket.bypassed.add(&bypassed);
+    };
+
+    for row in hours {
+        if let Some(ts) = parse_sql_timestamp(&row.timestamp) {
+            let bypassed = BypassCounts {
+                bypassed_static: row.bypassed_static,
+                bypassed_preflight: row.bypassed_preflight,
+                whitelisted: row.whitelisted,
+            };
+            add(ts, row.total_requests, row.blocked_requests, row.challenged_requests, row.passed_requests, bypassed);
+        }
+    }
+    for row in minutes {
+        if let Some(ts) = parse_sql_timestamp(&row.timestamp) {
+            let bypassed = BypassCounts {
+                bypassed_static: row.bypassed_static,
+                bypassed_preflight: row.bypassed_preflight,
+                whitelisted: row.whitelisted,
+            };
+            add(ts, row.total_requests, row.blocked_requests, row.challenged_requests, row.passed_requests, bypassed);
+        }
+    }
+    for snap in seconds {
+        add(snap.timestamp, snap.requests, snap.blocked, snap.challenged, snap.passed, snap.bypassed);
+    }
+
+    buckets.into_values().collect()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn to_datetime(secs: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs as i64, 0).unwrap_or_default()
+}
+
+/// Unix timestamp in the `YYYY-MM-DD HH:MM:SS` form used by the metrics tables.
+pub(crate) fn sql_timestamp(secs: u64) -> String {
+    to_datetime(secs).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn parse_sql_timestamp(value: &str) -> Option<u64> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .and_then(|t| u64::try_from(t.and_utc().timestamp()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn second(timestamp: u64, requests: u64) -> SecondSnapshot {
+        let bypassed = BypassCounts { bypassed_static: 1, ..Default::default() };
+        SecondSnapshot { timestamp, requests, blocked: 1, challenged: 0, passed: requests - 1, bypassed }
+    }
+
+    fn minute(timestamp: u64, requests: u64) -> MinuteMetricsRow {
+        MinuteMetricsRow {
+            timestamp: sql_timestamp(timestamp),
+            total_requests: requests,
+            passed_requests: requests,
+            blocked_requests: 0,
+            challenged_requests: 0,
+            bypassed_static: 0,
+            bypassed_preflight: 0,
+            whitelisted: 0,
+        }
+    }
+
+    #[test]
+    fn test_sources_are_summed_into_aligned_buckets() {
+        // 2026-03-06T12:00:00Z
+        let hour = 1_772_798_400;
+        let seconds = [second(hour + 120, 5), second(hour + 121, 3), second(hour + 185, 2)];
+        let minutes = [minute(hour, 10), minute(hour + 60, 20)];
+
+        let by_minute = stitch(&seconds, &minutes, &[], 60);
+        let counts: Vec<(u64, u64)> = by_minute.iter().map(|b| (b.timestamp - hour, b.requests)).collect();
+        assert_eq!(counts, vec![(0, 10), (60, 20), (120, 8), (180, 2)]);
+        assert_eq!(by_minute[2].blocked, 2);
+        assert_eq!(by_minute[2].passed, 6);
+        assert_eq!(by_minute[2].bypassed.bypassed_static, 2);
+
+        let by_hour = stitch(&seconds, &minutes, &[], 3600);
+        assert_eq!(by_hour.len(), 1);
+        assert_eq!((by_hour[0].timestamp, by_hour[0].requests), (hour, 40));
+    }
+
+    #[test]
+    fn test_sql_timestamps_round_trip() {
+        assert_eq!(sql_timestamp(1_772_798_400), "2026-03-06 12:00:00");
+        assert_eq!(parse_sql_timestamp("2026-03-06 12:00:00"), Some(1_772_798_400));
+        assert_eq!(parse_sql_timestamp("yesterday"), None);
+    }
+}
@@ -0,0 +1,232 @@
+//! Live tail of the access log for `GET /api/fortress/logs/stream`.
+//!
+//! Every request is offered to a bounded broadcast channel as it is
+//! logged, whether or not an access log file is configured. Each streaming
+//! client reads from its own position in the channel; a client that falls
+//! more than `logging.live_tail_buffer` entries behind skips the oldest
+//! ones instead of holding them, and is told how many it missed.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::settings::SharedSettings;
+use crate::proxy::access_log::AccessLogEntry;
+
+/// One streamed access-log entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct TailEntry {
+    pub ts: String,
+    pub ip: IpAddr,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub status: u16,
+    /// `passed`, `challenged`, `blocked` or `probe`, as in the access log.
+    pub action: String,
+    pub reason: Option<String>,
+    /// Pipeline score; 0 for requests the pipeline did not score.
+    pub score: f64,
+    pub latency_us: u64,
+    pub bytes: u64,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub user_agent: String,
+    pub service_id: Option<String>,
+    pub route: Option<String>,
+    pub ray_id: String,
+}
+
+/// Server-side filters of one stream. Unset filters match everything.
+#[derive(Debug, Clone, Default)]
+pub struct TailFilter {
+    pub action: Option<String>,
+    /// Matched against the textual IP, so `203.0.113.` or `2001:db8:`.
+    pub ip_prefix: Option<String>,
+    pub host: Option<String>,
+    pub min_score: Option<f64>,
+}
+
+impl TailFilter {
+    pub fn matches(&self, entry: &TailEntry) -> bool {
+        self.action.as_deref().is_none_or(|action| action == entry.action)
+            && self.ip_prefix.as_deref().is_none_or(|prefix| entry.ip.to_string().starts_with(prefix))
+            && self.host.as_deref().is_none_or(|host| host.eq_ignore_ascii_case(&entry.host))
+            && self.min_score.is_none_or(|min| entry.score >= min)
+    }
+}
+
+/// Broadcast of access-log entries to the streaming clients.
+pub struct LiveTail {
+    tx: broadcast::Sender<Arc<TailEntry>>,
+    settings: SharedSettings,
+    clients: AtomicUsize,
+    /// Entries skipped by clients that fell behind, since startup.
+    lagged: AtomicU64,
+}
+
+/// What a client missed, or the next entry for it.
+pub enum TailEvent {
+    Entry(Arc<TailEntry>),
+    Lagged(u64),
+}
+
+/// A streaming client's place in the tail. Dropping it frees the slot.
+pub struct TailSubscription {
+    rx: broadcast::Receiver<Arc<TailEntry>>,
+    tail: Arc<LiveTail>,
+}
+
+impl TailSubscription {
+    /// The next entry matching `filter`; `None` once the tail is gone.
+    pub async fn next(&mut self, filter: &TailFilter) -> Option<TailEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(entry) if filter.matches(&entry) => return Some(TailEvent::Entry(entry)),
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => {
+                    self.tail.lagged.fetch_add(n, Ordering::Relaxed);
+                    return Some(TailEvent::Lagged(n));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for TailSubscription {
+    fn drop(&mut self) {
+        self.tail.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LiveTail {
+    /// `logging.live_tail_buffer` is read here only; the client limit
+    /// follows reloads.
+    pub fn new(settings: SharedSettings) -> Self {
+        let capacity = settings.load().logging.live_tail_buffer.max(1);
+        Self {
+            tx: broadcast::channel(capacity).0,
+            settings,
+            clients: AtomicUsize::new(0),
+            lagged: AtomicU64::new(0),
+        }
+    }
+
+    /// Offer a logged request to the streaming clients, if there are any.
+    pub fn record(&self, entry: &AccessLogEntry<'_>, score: f64) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.tx.send(Arc::new(TailEntry {
+            ts: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            ip: entry.client_ip,
+            method: entry.method.to_string(),
+            host: entry.host.to_string(),
+            path: entry.path.to_string(),
+            status: entry.status,
+            action: entry.action.to_string(),
+            reason: entry.reason.map(|r| r.to_string()),
+            score,
+            latency_us: entry.latency_us,
+            bytes: entry.bytes,
+            country: entry.country.map(str::to_string),
+            asn: entry.asn,
+            user_agent: entry.user_agent.to_string(),
+            service_id: entry.service_id.map(str::to_string),
+            route: entry.route.map(str::to_string),
+            ray_id: entry.ray_id.to_string(),
+        }));
+    }
+
+    /// Join the tail, unless `logging.live_tail_max_clients` are streaming
+    /// already.
+    pub fn subscribe(self: &Arc<Self>) -> Option<TailSubscription> {
+        let max = self.settings.load().logging.live_tail_max_clients;
+        self.clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+            .ok()?;
+        Some(TailSubscription { rx: self.tx.subscribe(), tail: self.clone() })
+    }
+
+    /// `(clients, lagged)`: streams open now, and entries skipped by slow
+    /// clients since startup.
+    pub fn stats(&self) -> (usize, u64) {
+        (self.clients.load(Ordering::Relaxed), self.lagged.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::settings::Settings;
+    use arc_swap::ArcSwap;
+
+    fn entry<'a>(ip: IpAddr, action: &'a str) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            client_ip: ip,
+            method: "GET",
+            path: "/",
+            host: "shop.example.com",
+            protocol: "HTTP/1.1",
+            status: 200,
+            action,
+            reason: None,
+            latency_us: 10,
+            bytes: 0,
+            country: None,
+            asn: None,
+            ja3: None,
+            user_agent: "curl",
+            referer: None,
+            ray_id: "ray",
+            request_id: None,
+            service_id: None,
+            route: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_filter_skip_when_behind_and_are_capped() {
+        let mut settings = Settings::default();
+        settings.logging.live_tail_buffer = 4;
+        settings.logging.live_tail_max_clients = 1;
+        let tail = Arc::new(LiveTail::new(Arc::new(ArcSwap::from_pointee(settings))));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // Nothing is kept while nobody streams
+        tail.record(&entry(ip, "blocked"), 90.0);
+        let mut sub = tail.subscribe().unwrap();
+        assert!(tail.subscribe().is_none());
+
+        let filter = TailFilter {
+            action: Some("blocked".to_string()),
+            ip_prefix: Some("203.0.113.".to_string()),
+            host: Some("SHOP.example.com".to_string()),
+            min_score: Some(50.0),
+        };
+        tail.record(&entry(other, "blocked"), 90.0);
+        tail.record(&entry(ip, "passed"), 90.0);
+        tail.record(&entry(ip, "blocked"), 10.0);
+        tail.record(&entry(ip, "blocked"), 70.0);
+        match sub.next(&filter).await {
+            Some(TailEvent::Entry(e)) => assert_eq!((e.ip, e.score), (ip, 70.0)),
+            _ => panic!("expected the matching entry"),
+        }
+
+        // A client more than the buffer behind skips ahead
+        for _ in 0..6 {
+            tail.record(&entry(ip, "blocked"), 70.0);
+        }
+        assert!(matches!(sub.next(&filter).await, Some(TailEvent::Lagged(2))));
+        assert!(matches!(sub.next(&filter).await, Some(TailEvent::Entry(_))));
+        assert_eq!(tail.stats(), (1, 2));
+
+        drop(sub);
+        assert!(tail.subscribe().is_some());
+    }
+}
@@ -1,3 +1,11 @@
+pub mod capture;
+pub mod challenge_stats;
 pub mod collector;
+pub mod events;
+pub mod export;
+pub mod history;
+pub mod live_tail;
+pub mod request_samples;
 pub mod reporter;
+pub mod sketch;
 pub mod alerting;
@@ -1,16 +1,22 @@
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::Utc;
 use parking_lot::Mutex;
+use tokio::sync::watch;
 use tokio::time::{interval, MissedTickBehavior};
 use tracing::{info, warn};
 
-use crate::analytics::alerting::AlertManager;
-use crate::analytics::collector::MetricsCollector;
-use crate::config::settings::Settings;
-use crate::protection::escalation::EscalationEngine;
-use crate::storage::sqlite::{AttackRow, MetricsRow, SqliteStore};
+use crate::analytics::alerting::{AlertManager, Severity};
+use crate::analytics::collector::{BypassCounts, MetricsCollector, ServiceCounters, UpstreamTotals};
+use crate::analytics::events::{EventBus, EventType, SecurityEvent};
+use crate::analytics::export::{ExportSnapshot, ServiceExport, SnapshotReceiver};
+use crate::analytics::history::{sql_timestamp, unix_now};
+use crate::config::settings::SharedSettings;
+use crate::models::metrics::MetricsSnapshot;
+use crate::protection::escalation::{EscalationEngine, OriginHealth, ServiceTraffic};
+use crate::storage::sqlite::{AttackRow, MetricsRow, MinuteMetricsRow, SqliteStore};
 
 /// Periodic reporter that drives the collector tick and flushes aggregated
 /// metrics to the SQLite backing store.
@@ -18,14 +24,72 @@ pub struct MetricsReporter {
     collector: Arc<MetricsCollector>,
     sqlite: Arc<SqliteStore>,
     escalation: Arc<EscalationEngine>,
-    settings: Arc<Settings>,
-    alerting: Option<Arc<AlertManager>>,
+    settings: SharedSettings,
+    alerting: Arc<AlertManager>,
+    events: Arc<EventBus>,
 
     // Attack tracking state
     previous_level: Mutex<u8>,
-    current_attack_id: Mutex<Option<i64>>,
-    attack_peak_rps: Mutex<u64>,
-    attack_started_at: Mutex<Option<String>>,
+    /// Held across the SQLite writes that record the attack.
+    attack: tokio::sync::Mutex<Option<ActiveAttack>>,
+
+    rollup: Mutex<Rollup>,
+
+    /// Collector upstream totals at the previous check, for origin health.
+    upstream_sample: Mutex<UpstreamTotals>,
+
+    /// Latest snapshot for the `[metrics.export]` emitters.
+    export: watch::Sender<Option<Arc<ExportSnapshot>>>,
+}
+
+/// Minute and hour currently being accumulated, as unix timestamps.
+struct Rollup {
+    minute: u64,
+    hour: u64,
+    /// Collector `(passed, challenged, blocked)` totals at the last hourly flush.
+    flushed_totals: (u64, u64, u64),
+    /// Collector bypass totals at the last hourly flush.
+    flushed_bypassed: BypassCounts,
+    /// Per-service collector totals at the last hourly flush.
+    flushed_services: HashMap<String, ServiceCounters>,
+}
+
+/// Running totals for the attack currently being recorded.
+struct ActiveAttack {
+    id: i64,
+    started_at: String,
+    peak_rps: u64,
+    max_level: u8,
+    /// Requests seen since the attack started.
+    total_requests: u64,
+    /// Collector `total_requests` at the previous check, to accumulate deltas.
+    last_total: u64,
+    unique_ips: u64,
+    /// Consecutive checks with traffic back to normal.
+    calm_checks: u8,
+}
+
+impl ActiveAttack {
+    fn observe(&mut self, rps: u64, level: u8, snapshot: &MetricsSnapshot) {
+        self.peak_rps = self.peak_rps.max(rps);
+        self.max_level = self.max_level.max(level);
+        self.total_requests += snapshot.total_requests.saturating_sub(self.last_total);
+        self.last_total = snapshot.total_requests;
+        self.unique_ips = self.unique_ips.max(snapshot.unique_ips);
+    }
+}
+
+/// Global escalation inputs `(rps, blocked, total)` without the traffic of
+/// services that have their own level.
+fn untracked_traffic(
+    (rps, blocked, total): (f64, u64, u64),
+    (tracked_rps, tracked): (f64, ServiceTraffic),
+) -> (f64, u64, u64) {
+    (
+        (rps - tracked_rps).max(0.0),
+        blocked.saturating_sub(tracked.blocked),
+        total.saturating_sub(tracked.total),
+    )
 }
 
 impl MetricsReporter {
@@ -33,23 +97,39 @@ impl MetricsReporter {
         collector: Arc<MetricsCollector>,
         sqlite: Arc<SqliteStore>,
         escalation: Arc<EscalationEngine>,
-        settings: Arc<Settings>,
-        alerting: Option<Arc<AlertManager>>,
+        settings: SharedSettings,
+        alerting: Arc<AlertManager>,
+        events: Arc<EventBus>,
     ) -> Self {
         let initial_level = escalation.level_as_u8();
+        let now = unix_now();
         Self {
             collector,
             sqlite,
             escalation,
             settings,
             alerting,
+            events,
             previous_level: Mutex::new(initial_level),
-            current_attack_id: Mutex::new(None),
-            attack_peak_rps: Mutex::new(0),
-            attack_started_at: Mutex::new(None),
+            attack: tokio::sync::Mutex::new(None),
+            rollup: Mutex::new(Rollup {
+                minute: now / 60 * 60,
+                hour: now / 3600 * 3600,
+                flushed_totals: (0, 0, 0),
+                flushed_bypassed: BypassCounts::default(),
+                flushed_services: HashMap::new(),
+            }),
+            upstream_sample: Mutex::new(UpstreamTotals::default()),
+            export: watch::channel(None).0,
         }
     }
 
+    /// Snapshots published on every escalation check, for the push
+    /// emitters.
+    pub fn export_snapshots(&self) -> SnapshotReceiver {
+        self.export.subscribe()
+    }
+
     /// Run the reporter loop forever.
     pub async fn run(&self) {
         let mut tick_interval = interval(Duration::from_secs(1));
@@ -58,195 +138,307 @@ impl MetricsReporter {
         let mut escalation_interval = interval(Duration::from_secs(5));
         escalation_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        let mut flush_interval = interval(Duration::from_secs(3600));
-        flush_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
-
         loop {
             tokio::select! {
                 _ = tick_interval.tick() => {
                     self.collector.tick();
+                    self.roll_up().await;
                 }
 
                 _ = escalation_interval.tick() => {
-                    self.evaluate_escalation();
-                }
-
-                _ = flush_interval.tick() => {
-                    self.flush_to_sqlite();
+                    self.evaluate_escalation().await;
                 }
             }
         }
     }
 
+    /// Persist minute rollups once their minute has passed, and the hourly
+    /// row once its hour has.
+    async fn roll_up(&self) {
+        let now = unix_now();
+        let (minutes, finished_hour) = {
+            let mut rollup = self.rollup.lock();
+            let minute = now / 60 * 60;
+            let hour = now / 3600 * 3600;
+            let minutes = (minute > rollup.minute).then(|| std::mem::replace(&mut rollup.minute, minute)..minute);
+            let finished_hour = (hour > rollup.hour).then(|| std::mem::replace(&mut rollup.hour, hour));
+            (minutes, finished_hour)
+        };
+
+        if let Some(minutes) = minutes {
+            self.store_minutes(minutes.start, minutes.end).await;
+        }
+        if let Some(hour) = finished_hour {
+            self.flush_to_sqlite(hour).await;
+        }
+    }
+
+    /// Store per-minute totals of the per-second ring for `[from, to)`.
+    async fn store_minutes(&self, from: u64, to: u64) {
+        let mut rows: BTreeMap<u64, MinuteMetricsRow> = BTreeMap::new();
+        for snap in self.collector.get_second_range(from, to) {
+            let minute = snap.timestamp / 60 * 60;
+            let row = rows.entry(minute).or_insert_with(|| MinuteMetricsRow {
+                timestamp: sql_timestamp(minute),
+                total_requests: 0,
+                passed_requests: 0,
+                blocked_requests: 0,
+                challenged_requests: 0,
+                bypassed_static: 0,
+                bypassed_preflight: 0,
+                whitelisted: 0,
+            });
+            row.total_requests += snap.requests;
+            row.passed_requests += snap.passed;
+            row.blocked_requests += snap.blocked;
+            row.challenged_requests += snap.challenged;
+            row.bypassed_static += snap.bypassed.bypassed_static;
+            row.bypassed_preflight += snap.bypassed.bypassed_preflight;
+            row.whitelisted += snap.bypassed.whitelisted;
+        }
+
+        let retention_hours = self.settings.load().storage.metrics_minutely_retention_hours;
+        let keep_from = Utc::now() - chrono::Duration::hours(retention_hours.min(i32::MAX as u64) as i64);
+        if let Err(e) = self.sqlite.insert_metrics_minutely(rows.into_values().collect(), keep_from).await {
+            warn!("Failed to store minute metrics: {}", e);
+        }
+    }
+
     /// Feed live traffic stats into the escalation engine and track attacks.
-    fn evaluate_escalation(&self) {
+    ///
+    /// An attack starts when the level leaves L0 or RPS reaches
+    /// `escalation.l0_to_l1_rps`, is updated on every check while it lasts,
+    /// and ends once the level is back at L0 and RPS has stayed under the
+    /// threshold for `escalation.sustained_checks_required` checks.
+    async fn evaluate_escalation(&self) {
+        let settings = self.settings.load();
         let current_rps = self.collector.get_current_rps();
         let snapshot = self.collector.get_snapshot();
+        // Without bypassed requests, the engine only sees inspected traffic
+        let count_bypassed = settings.escalation.count_bypassed_requests;
+        let (escalation_rps, escalation_total) = if count_bypassed {
+            (current_rps, snapshot.total_requests)
+        } else {
+            (
+                (current_rps - self.collector.get_current_bypassed().total() as f64).max(0.0),
+                snapshot.total_requests.saturating_sub(self.collector.bypass_totals().total()),
+            )
+        };
+
+        // Per-service levels first; their traffic is left out of the global one
+        let service_counts = self.collector.get_service_counts();
+        let traffic: Vec<(String, ServiceTraffic)> = service_counts
+            .iter()
+            .map(|(id, c)| {
+                let mut total = c.passed + c.challenged + c.blocked;
+                if !count_bypassed {
+                    total = total.saturating_sub(c.bypassed.total());
+                }
+                (id.clone(), ServiceTraffic { total, blocked: c.blocked })
+            })
+            .collect();
+        let (escalation_rps, escalation_blocked, escalation_total) = untracked_traffic(
+            (escalation_rps, snapshot.total_blocked, escalation_total),
+            self.escalation.evaluate_services(&traffic, &settings),
+        );
+
+        let upstream = self.collector.upstream_totals();
+        let previous = std::mem::replace(&mut *self.upstream_sample.lock(), upstream);
+        let origin = OriginHealth {
+            responses: upstream.responses.saturating_sub(previous.responses),
+            errors: upstream.errors.saturating_sub(previous.errors),
+            latency_us: upstream.latency_us.saturating_sub(previous.latency_us),
+        };
 
         // Run the escalation engine
-        self.escalation.evaluate(current_rps, snapshot.total_blocked, snapshot.total_requests, &self.settings);
+        self.escalation.evaluate(escalation_rps, escalation_blocked, escalation_total, origin, &settings);
+        self.escalation.save(&self.sqlite).await;
 
         let new_level = self.escalation.level_as_u8();
-        let mut prev_level = self.previous_level.lock();
-        let old_level = *prev_level;
-
-        // Track peak RPS during ongoing attack
-        if self.current_attack_id.lock().is_some() {
-            let mut peak = self.attack_peak_rps.lock();
-            let rps = current_rps as u64;
-            if rps > *peak {
-                *peak = rps;
+        let old_level = std::mem::replace(&mut *self.previous_level.lock(), new_level);
+        self.publish_export_snapshot(new_level, &snapshot, service_counts);
+
+        let rps = current_rps as u64;
+        let threshold = settings.escalation.l0_to_l1_rps;
+        let under_attack = new_level > 0 || rps >= threshold;
+
+        let mut attack = self.attack.lock().await;
+        match attack.as_mut() {
+            None if under_attack => {
+                *attack = self.record_attack_start(new_level, rps, &snapshot).await;
+                return;
             }
-        }
-
-        if new_level != old_level {
-            *prev_level = new_level;
+            None => {}
+            Some(active) => {
+                active.observe(rps, new_level, &snapshot);
+                if under_attack {
+                    active.calm_checks = 0;
+                } else {
+                    active.calm_checks = active.calm_checks.saturating_add(1);
+                }
 
-            // L0 -> L1+: attack started
-            if old_level == 0 && new_level >= 1 {
-                self.record_attack_start(new_level, current_rps as u64);
-            }
-            // L1+ -> L0: attack ended
-            else if old_level >= 1 && new_level == 0 {
-                self.record_attack_end();
-            }
-            // L1+ -> higher: update attack severity
-            else if new_level > old_level {
-                self.update_attack_severity(new_level);
-
-                // Send alert on escalation
-                if let Some(ref alerting) = self.alerting {
-                    let msg = format!(
-                        "Protection level escalated: L{} -> L{} (RPS: {:.0})",
-                        old_level, new_level, current_rps
-                    );
-                    let alerting = alerting.clone();
-                    tokio::spawn(async move {
-                        alerting.send_alert("escalation", &msg).await;
-                    });
+                if active.calm_checks >= settings.escalation.sustained_checks_required.max(1) {
+                    if let Some(ended) = attack.take() {
+                        self.record_attack_end(ended, threshold).await;
+                    }
+                } else {
+                    self.update_attack(active, threshold).await;
                 }
             }
         }
+        drop(attack);
+
+        // Alert on level changes
+        if new_level > old_level {
+            let signal = self.escalation.escalation_signal().map_or("manual", |s| s.as_str());
+            let msg = format!(
+                "Protection level escalated: L{} -> L{} (RPS: {:.0}, signal: {}, upstream latency: {:.0}ms, upstream errors: {:.0}%)",
+                old_level,
+                new_level,
+                current_rps,
+                signal,
+                origin.avg_latency_ms(),
+                origin.error_ratio() * 100.0,
+            );
+            let severity = if new_level >= 3 { Severity::Critical } else { Severity::Warning };
+            self.alerting.notify("escalation", &format!("escalation:L{}", new_level), severity, msg);
+            self.publish_level_change("escalate", signal, new_level);
+        } else if new_level < old_level {
+            let msg = format!(
+                "Protection level lowered: L{} -> L{} (RPS: {:.0})",
+                old_level, new_level, current_rps
+            );
+            self.alerting.notify("deescalation", &format!("deescalation:L{}", new_level), Severity::Info, msg);
+            self.publish_level_change("deescalate", "calm", new_level);
+        }
+    }
+
+    fn publish_level_change(&self, action: &str, reason: &str, level: u8) {
+        if self.events.is_active() {
+            self.events.publish(SecurityEvent {
+                reason: Some(reason.to_string()),
+                level: Some(level),
+                ..SecurityEvent::new(EventType::Escalation, action)
+            });
+        }
+    }
+
+    fn publish_export_snapshot(
+        &self,
+        level: u8,
+        snapshot: &MetricsSnapshot,
+        service_counts: Vec<(String, crate::analytics::collector::ServiceCounters)>,
+    ) {
+        let services = service_counts
+            .into_iter()
+            .map(|(id, counts)| ServiceExport {
+                level: self.escalation.effective_level(Some(&id)).as_u8(),
+                id,
+                counts,
+            })
+            .collect();
+        self.export.send_replace(Some(Arc::new(ExportSnapshot {
+            timestamp: unix_now(),
+            level,
+            metrics: snapshot.clone(),
+            actions: self.collector.action_totals(),
+            services,
+        })));
     }
 
     /// Record the start of a new attack.
-    fn record_attack_start(&self, level: u8, rps: u64) {
+    async fn record_attack_start(&self, level: u8, rps: u64, snapshot: &MetricsSnapshot) -> Option<ActiveAttack> {
         let now = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        let severity = Self::level_to_severity(level);
-        let snapshot = self.collector.get_snapshot();
-
-        let top_countries = self.collector.get_top_countries(10);
-        let top_ips = self.collector.get_top_ips(10);
-        let top_countries_json = serde_json::to_string(
-            &top_countries.iter().map(|(c, n)| (c.as_str(), *n)).collect::<Vec<_>>()
-        ).ok();
-        let top_ips_json = serde_json::to_string(
-            &top_ips.iter().map(|(ip, n)| (ip.to_string(), *n)).collect::<Vec<_>>()
-        ).ok();
+        let (top_countries_json, top_ips_json) = self.top_talkers_json();
 
         let attack = AttackRow {
             id: 0,
             started_at: now.clone(),
             ended_at: None,
             peak_rps: rps,
-            total_requests: snapshot.total_requests,
+            total_requests: 0,
             unique_ips: snapshot.unique_ips,
             max_level: level,
             top_countries_json,
             top_ips_json,
-            severity,
+            severity: Self::level_to_severity(level),
         };
 
-        match self.sqlite.insert_attack(&attack) {
+        let msg = format!("Attack detected! Level: L{}, RPS: {}", level, rps);
+        let severity = if level >= 3 { Severity::Critical } else { Severity::Warning };
+        self.alerting.notify("attack_start", "attack_start", severity, msg);
+
+        match self.sqlite.insert_attack(&attack).await {
             Ok(id) => {
                 info!(attack_id = id, level = level, rps = rps, "Attack recorded: started");
-                *self.current_attack_id.lock() = Some(id);
-                *self.attack_peak_rps.lock() = rps;
-                *self.attack_started_at.lock() = Some(now);
+                Some(ActiveAttack {
+                    id,
+                    started_at: now,
+                    peak_rps: rps,
+                    max_level: level,
+                    total_requests: 0,
+                    last_total: snapshot.total_requests,
+                    unique_ips: snapshot.unique_ips,
+                    calm_checks: 0,
+                })
             }
             Err(e) => {
                 warn!("Failed to record attack start: {}", e);
+                None
             }
         }
+    }
 
-        // Send alert
-        if let Some(ref alerting) = self.alerting {
-            let msg = format!("Attack detected! Level: L{}, RPS: {}", level, rps);
-            let alerting = alerting.clone();
-            tokio::spawn(async move {
-                alerting.send_alert("attack_start", &msg).await;
-            });
+    /// Persist the running totals of an ongoing attack.
+    async fn update_attack(&self, active: &ActiveAttack, rps_threshold: u64) {
+        let attack = self.attack_row(active, None, rps_threshold);
+        if let Err(e) = self.sqlite.update_attack(active.id, &attack).await {
+            warn!(attack_id = active.id, "Failed to update attack: {}", e);
         }
     }
 
     /// Record the end of an ongoing attack.
-    fn record_attack_end(&self) {
-        let mut attack_id = self.current_attack_id.lock();
-        let id = match *attack_id {
-            Some(id) => id,
-            None => return,
-        };
-
+    async fn record_attack_end(&self, active: ActiveAttack, rps_threshold: u64) {
         let now = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        let peak = *self.attack_peak_rps.lock();
-        let started = self.attack_started_at.lock().clone().unwrap_or_default();
-        let snapshot = self.collector.get_snapshot();
-
-        let top_countries = self.collector.get_top_countries(10);
-        let top_ips = self.collector.get_top_ips(10);
-        let top_countries_json = serde_json::to_string(
-            &top_countries.iter().map(|(c, n)| (c.as_str(), *n)).collect::<Vec<_>>()
-        ).ok();
-        let top_ips_json = serde_json::to_string(
-            &top_ips.iter().map(|(ip, n)| (ip.to_string(), *n)).collect::<Vec<_>>()
-        ).ok();
+        let attack = self.attack_row(&active, Some(now), rps_threshold);
 
-        let attack = AttackRow {
-            id,
-            started_at: started,
-            ended_at: Some(now),
-            peak_rps: peak,
-            total_requests: snapshot.total_requests,
-            unique_ips: snapshot.unique_ips,
-            max_level: 0,
-            top_countries_json,
-            top_ips_json,
-            severity: "low".to_string(),
-        };
-
-        if let Err(e) = self.sqlite.update_attack(id, &attack) {
-            warn!(attack_id = id, "Failed to record attack end: {}", e);
+        if let Err(e) = self.sqlite.update_attack(active.id, &attack).await {
+            warn!(attack_id = active.id, "Failed to record attack end: {}", e);
         } else {
-            info!(attack_id = id, peak_rps = peak, "Attack ended");
+            info!(
+                attack_id = active.id,
+                peak_rps = active.peak_rps,
+                total_requests = active.total_requests,
+                severity = %attack.severity,
+                "Attack ended"
+            );
         }
 
-        *attack_id = None;
-        *self.attack_peak_rps.lock() = 0;
-        *self.attack_started_at.lock() = None;
+        let msg = format!(
+            "Attack ended. Peak RPS: {}, requests: {}, severity: {}",
+            active.peak_rps, active.total_requests, attack.severity
+        );
+        self.alerting.notify("attack_end", "attack_end", Severity::Info, msg);
+    }
 
-        // Send alert
-        if let Some(ref alerting) = self.alerting {
-            let msg = format!("Attack ended. Peak RPS: {}", peak);
-            let alerting = alerting.clone();
-            tokio::spawn(async move {
-                alerting.send_alert("attack_end", &msg).await;
-            });
+    fn attack_row(&self, active: &ActiveAttack, ended_at: Option<String>, rps_threshold: u64) -> AttackRow {
+        let (top_countries_json, top_ips_json) = self.top_talkers_json();
+        AttackRow {
+            id: active.id,
+            started_at: active.started_at.clone(),
+            ended_at,
+            peak_rps: active.peak_rps,
+            total_requests: active.total_requests,
+            unique_ips: active.unique_ips,
+            max_level: active.max_level,
+            top_countries_json,
+            top_ips_json,
+            severity: Self::attack_severity(active.max_level, active.peak_rps, rps_threshold),
         }
     }
 
-    /// Update the severity of an ongoing attack when level increases.
-    fn update_attack_severity(&self, new_level: u8) {
-        let attack_id = self.current_attack_id.lock();
-        let id = match *attack_id {
-            Some(id) => id,
-            None => return,
-        };
-
-        let peak = *self.attack_peak_rps.lock();
-        let started = self.attack_started_at.lock().clone().unwrap_or_default();
-        let severity = Self::level_to_severity(new_level);
-        let snapshot = self.collector.get_snapshot();
-
+    /// Top 10 countries and IPs as JSON arrays of `[key, count]` pairs.
+    fn top_talkers_json(&self) -> (Option<String>, Option<String>) {
         let top_countries = self.collector.get_top_countries(10);
         let top_ips = self.collector.get_top_ips(10);
         let top_countries_json = serde_json::to_string(
@@ -255,25 +447,23 @@ impl MetricsReporter {
         let top_ips_json = serde_json::to_string(
             &top_ips.iter().map(|(ip, n)| (ip.to_string(), *n)).collect::<Vec<_>>()
         ).ok();
+        (top_countries_json, top_ips_json)
+    }
 
-        let attack = AttackRow {
-            id,
-            started_at: started,
-            ended_at: None,
-            peak_rps: peak,
-            total_requests: snapshot.total_requests,
-            unique_ips: snapshot.unique_ips,
-            max_level: new_level,
-            top_countries_json,
-            top_ips_json,
-            severity,
-        };
-
-        if let Err(e) = self.sqlite.update_attack(id, &attack) {
-            warn!(attack_id = id, "Failed to update attack severity: {}", e);
+    /// Severity from the highest level reached, raised if peak RPS was a
+    /// large multiple of the attack threshold.
+    fn attack_severity(max_level: u8, peak_rps: u64, rps_threshold: u64) -> String {
+        let threshold = rps_threshold.max(1);
+        let rps_level = if peak_rps >= threshold * 10 {
+            4
+        } else if peak_rps >= threshold * 5 {
+            3
+        } else if peak_rps >= threshold * 2 {
+            2
         } else {
-            info!(attack_id = id, new_level = new_level, "Attack severity updated");
-        }
+            1
+        };
+        Self::level_to_severity(max_level.max(rps_level))
     }
 
     fn level_to_severity(level: u8) -> String {
@@ -286,11 +476,39 @@ impl MetricsReporter {
         }
     }
 
-    /// Persist accumulated metrics to SQLite and reset hourly aggregates.
-    fn flush_to_sqlite(&self) {
+    /// Persist the counts for the hour starting at `hour` to SQLite and reset
+    /// hourly aggregates.
+    async fn flush_to_sqlite(&self, hour: u64) {
         info!("Flushing hourly metrics to SQLite");
 
         let snapshot = self.collector.get_snapshot();
+        let totals = self.collector.action_totals();
+        let bypass_totals = self.collector.bypass_totals();
+        let services = self.collector.get_all_service_metrics();
+        let ((passed, challenged, blocked), bypassed, service_counts) = {
+            let mut rollup = self.rollup.lock();
+            let last = std::mem::replace(&mut rollup.flushed_totals, totals);
+            let bypassed = bypass_totals.saturating_sub(&std::mem::replace(&mut rollup.flushed_bypassed, bypass_totals));
+            let service_counts: Vec<ServiceCounters> = services
+                .iter()
+                .map(|svc| {
+                    let last = rollup.flushed_services.insert(svc.service_id.clone(), svc.totals.clone());
+                    let last = last.unwrap_or_default();
+                    ServiceCounters {
+                        passed: svc.totals.passed.saturating_sub(last.passed),
+                        challenged: svc.totals.challenged.saturating_sub(last.challenged),
+                        blocked: svc.totals.blocked.saturating_sub(last.blocked),
+                        bypassed: svc.totals.bypassed.saturating_sub(&last.bypassed),
+                    }
+                })
+                .collect();
+            let counts = (
+                totals.0.saturating_sub(last.0),
+                totals.1.saturating_sub(last.1),
+                totals.2.saturating_sub(last.2),
+            );
+            (counts, bypassed, service_counts)
+        };
         let top_countries = self.collector.get_top_countries(50);
         let top_asns = self.collector.get_top_asns(50);
 
@@ -298,21 +516,56 @@ impl MetricsReporter {
         let top_asns_json = serde_json::to_string(&top_asns).ok();
 
         let level = self.escalation.level_as_u8();
+        let challenges = self.collector.challenges();
+        let (challenges_issued, challenges_solved) = challenges.hour_totals(hour, None);
 
         let metrics_row = MetricsRow {
-            timestamp: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-            total_requests: snapshot.total_requests,
-            passed_requests: snapshot.total_requests.saturating_sub(snapshot.total_blocked),
-            blocked_requests: snapshot.total_blocked,
-            challenged_requests: 0,
+            timestamp: sql_timestamp(hour),
+            total_requests: passed + challenged + blocked,
+            passed_requests: passed,
+            blocked_requests: blocked,
+            challenged_requests: challenged,
             unique_ips: snapshot.unique_ips,
             avg_latency_ms: snapshot.avg_latency_ms,
             protection_level: level,
             top_countries_json,
             top_asns_json,
+            service_id: None,
+            challenges_issued,
+            challenges_solved,
+            bypassed_static: bypassed.bypassed_static,
+            bypassed_preflight: bypassed.bypassed_preflight,
+            whitelisted: bypassed.whitelisted,
         };
+        let mut rows = vec![metrics_row];
+        for (svc, counts) in services.iter().zip(service_counts) {
+            let total = counts.passed + counts.challenged + counts.blocked;
+            if total == 0 {
+                continue;
+            }
+            let (challenges_issued, challenges_solved) = challenges.hour_totals(hour, Some(&svc.service_id));
+            rows.push(MetricsRow {
+                timestamp: sql_timestamp(hour),
+                total_requests: total,
+                passed_requests: counts.passed,
+                blocked_requests: counts.blocked,
+                challenged_requests: counts.challenged,
+                // Unique IPs are only counted globally
+                unique_ips: 0,
+                avg_latency_ms: svc.avg_latency_ms,
+                protection_level: self.escalation.effective_level(Some(&svc.service_id)).as_u8(),
+                top_countries_json: None,
+                top_asns_json: None,
+                service_id: Some(svc.service_id.clone()),
+                challenges_issued,
+                challenges_solved,
+                bypassed_static: counts.bypassed.bypassed_static,
+                bypassed_preflight: counts.bypassed.bypassed_preflight,
+                whitelisted: counts.bypassed.whitelisted,
+            });
+        }
 
-        if let Err(e) = self.sqlite.insert_metrics_hourly(&metrics_row) {
+        if let Err(e) = self.sqlite.insert_metrics_hourly(rows).await {
             warn!("Failed to store hourly metrics: {}", e);
         }
 
@@ -320,3 +573,19 @@ impl MetricsReporter {
         info!("Hourly metrics flushed and counters reset");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_traffic_is_left_out_of_the_global_evaluation() {
+        // 400 RPS in all, 350 of it on a service with its own level
+        let tracked = (350.0, ServiceTraffic { total: 700, blocked: 180 });
+        assert_eq!(untracked_traffic((400.0, 200, 800), tracked), (50.0, 20, 100));
+
+        // Counters read a moment apart never go negative
+        let tracked = (20.0, ServiceTraffic { total: 9, blocked: 2 });
+        assert_eq!(untracked_traffic((10.0, 1, 5), tracked), (0.0, 0, 0));
+    }
+}
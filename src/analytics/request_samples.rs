@@ -0,0 +1,222 @@
+//! Sampled per-request log in SQLite, for "what did this IP request" after
+//! the fact and top lists that survive a restart.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::analytics::history::{sql_timestamp, unix_now};
+use crate::config::settings::SharedSettings;
+use crate::proxy::access_log::AccessLogEntry;
+use crate::storage::sqlite::{RequestSampleRow, SqliteStore};
+
+/// Samples held in memory between flushes before new ones are dropped.
+const MAX_PENDING: usize = 50_000;
+
+/// Columns the historical top lists can be grouped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleDimension {
+    Ip,
+    Country,
+    Asn,
+    Path,
+}
+
+impl SampleDimension {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ip" => Some(Self::Ip),
+            "country" => Some(Self::Country),
+            "asn" => Some(Self::Asn),
+            "path" => Some(Self::Path),
+            _ => None,
+        }
+    }
+
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Ip => "client_ip",
+            Self::Country => "country",
+            Self::Asn => "asn",
+            Self::Path => "path",
+        }
+    }
+}
+
+/// Records `storage.request_sample_rate` of all requests in the
+/// `request_samples` table.
+///
+/// Samples are buffered and written by [`run`](Self::run) in one
+/// transaction per second, so the request path never waits on SQLite.
+pub struct RequestSampler {
+    sqlite: Arc<SqliteStore>,
+    settings: SharedSettings,
+    pending: Mutex<Vec<RequestSampleRow>>,
+    dropped: AtomicU64,
+}
+
+impl RequestSampler {
+    pub fn new(sqlite: Arc<SqliteStore>, settings: SharedSettings) -> Self {
+        Self {
+            sqlite,
+            settings,
+            pending: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `entry` if it falls in the sample.
+    pub fn record(&self, entry: &AccessLogEntry<'_>) {
+        let rate = self.settings.load().storage.request_sample_rate;
+        if rate <= 0.0 || (rate < 1.0 && rand::random::<f64>() >= rate) {
+            return;
+        }
+        let row = RequestSampleRow {
+            timestamp: sql_timestamp(unix_now()),
+            client_ip: entry.client_ip.to_string(),
+            method: entry.method.to_string(),
+            host: entry.host.to_string(),
+            path: entry.path.to_string(),
+            action: entry.action.to_string(),
+            status: entry.status,
+            service_id: entry.service_id.map(str::to_string),
+            country: entry.country.map(str::to_string),
+            asn: entry.asn,
+        };
+        let mut pending = self.pending.lock();
+        if pending.len() < MAX_PENDING {
+            pending.push(row);
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Flush queued samples every second.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Write all queued samples.
+    pub async fn flush(&self) {
+        let rows = std::mem::take(&mut *self.pending.lock());
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(dropped, "Request sampler fell behind; samples dropped");
+        }
+        if rows.is_empty() {
+            return;
+        }
+        if let Err(e) = self.sqlite.insert_request_samples(rows).await {
+            warn!("Failed to store request samples: {}", e);
+        }
+    }
+
+    /// Delete samples older than `storage.request_sample_retention_hours`,
+    /// returning how many were deleted.
+    pub async fn prune(&self) -> usize {
+        let hours = self.settings.load().storage.request_sample_retention_hours;
+        let keep_from = Utc::now() - chrono::Duration::hours(hours.min(i32::MAX as u64) as i64);
+        match self.sqlite.prune_request_samples(keep_from).await {
+            Ok(0) => 0,
+            Ok(n) => {
+                debug!(removed = n, "Pruned old request samples");
+                n
+            }
+            Err(e) => {
+                warn!("Failed to prune request samples: {}", e);
+                0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::config::settings::Settings;
+    use crate::storage::sqlite::tests::TempDb;
+
+    fn entry<'a>(ip: IpAddr, path: &'a str, country: Option<&'a str>) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            client_ip: ip,
+            method: "GET",
+            path,
+            host: "example.com",
+            protocol: "HTTP/1.1",
+            status: 200,
+            action: "passed",
+            reason: None,
+            latency_us: 100,
+            bytes: 10,
+            country,
+            asn: None,
+            ja3: None,
+            user_agent: "curl/8.5.0",
+            referer: None,
+            ray_id: "ray",
+            request_id: None,
+            service_id: None,
+            route: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_samples_are_flushed_queried_and_pruned() {
+        let db = TempDb::new("samples");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let mut settings = Settings::default();
+        let shared: SharedSettings = Arc::new(ArcSwap::from_pointee(settings.clone()));
+        let sampler = RequestSampler::new(Arc::clone(&sqlite), Arc::clone(&shared));
+
+        let a: IpAddr = "198.51.100.7".parse().unwrap();
+        let b: IpAddr = "203.0.113.9".parse().unwrap();
+        // Sampling is off by default
+        sampler.record(&entry(a, "/", None));
+        assert!(sampler.pending.lock().is_empty());
+
+        settings.storage.request_sample_rate = 1.0;
+        shared.store(Arc::new(settings.clone()));
+        for p in ["/login", "/login", "/api"] {
+            sampler.record(&entry(a, p, Some("DE")));
+        }
+        sampler.record(&entry(b, "/login", None));
+        sampler.flush().await;
+        assert!(sampler.pending.lock().is_empty());
+
+        let (from, to) = (Utc::now() - chrono::Duration::minutes(1), Utc::now() + chrono::Duration::minutes(1));
+        let requests = sqlite.get_request_samples("198.51.100.7", from, to, 2).await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/api");
+
+        let top = |dim: &str| {
+            let column = SampleDimension::parse(dim).unwrap().column();
+            let sqlite = Arc::clone(&sqlite);
+            async move {
+                let rows = sqlite.get_top_request_samples(column, from, to, 10).await.unwrap();
+                rows.into_iter().map(|r| (r.value, r.count)).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(top("path").await, vec![("/login".to_string(), 3), ("/api".to_string(), 1)]);
+        assert_eq!(top("ip").await[0], ("198.51.100.7".to_string(), 3));
+        assert_eq!(top("country").await, vec![("DE".to_string(), 3)]);
+        assert!(SampleDimension::parse("ja3").is_none());
+
+        settings.storage.request_sample_retention_hours = 0;
+        shared.store(Arc::new(settings));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        sampler.prune().await;
+        assert!(sqlite.get_request_samples("198.51.100.7", from, to, 10).await.unwrap().is_empty());
+    }
+}
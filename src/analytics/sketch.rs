@@ -0,0 +1,235 @@
+//! Fixed-size sketches for per-IP metrics, so memory stays constant no
+//! matter how many (possibly spoofed) addresses a flood uses.
+//!
+//! - [`HyperLogLog`] estimates distinct IPs in 16 KiB with a standard
+//!   error of about 0.8% (1.04 / √16384); 99% of estimates fall within
+//!   ±2.5%. Below ~40k distinct IPs linear counting is used, which is
+//!   closer still.
+//! - [`TopIps`] keeps the heaviest IPs with Space-Saving: at most
+//!   [`TOP_IPS_CAPACITY`] addresses are tracked, and each reported count
+//!   overestimates the true one by at most 1/512 of the requests in its
+//!   shard (1/16 of all IPs). Any IP sending more than that is guaranteed
+//!   to be listed.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use parking_lot::Mutex;
+
+/// Register index bits: 2^14 one-byte registers.
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// IPs tracked by [`TopIps`] at most.
+pub const TOP_IPS_CAPACITY: usize = 8192;
+const TOP_SHARDS: usize = 16;
+const SHARD_CAPACITY: usize = TOP_IPS_CAPACITY / TOP_SHARDS;
+
+/// 64-bit hash of an address (splitmix64 finalizer). IPv4 is hashed as
+/// its IPv4-mapped IPv6 form.
+fn hash_ip(ip: IpAddr) -> u64 {
+    fn mix(mut z: u64) -> u64 {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    let bits = match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    };
+    mix((bits as u64) ^ mix((bits >> 64) as u64).wrapping_add(0x9e37_79b9_7f4a_7c15))
+}
+
+/// Distinct-count estimate of IPs, updated lock-free.
+pub struct HyperLogLog {
+    registers: Box<[AtomicU8]>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self {
+            registers: (0..REGISTERS).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    pub fn insert(&self, ip: IpAddr) {
+        let hash = hash_ip(ip);
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The guard bit caps the rank at 64 - PRECISION + 1
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[index].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let mut sum = 0.0;
+        let mut zeros = 0usize;
+        for register in self.registers.iter() {
+            let rank = register.load(Ordering::Relaxed);
+            if rank == 0 {
+                zeros += 1;
+            }
+            sum += 1.0 / (1u64 << rank) as f64;
+        }
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let raw = alpha * m * m / sum;
+        if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate at small cardinalities
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+
+    pub fn clear(&self) {
+        for register in self.registers.iter() {
+            register.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One Space-Saving summary: when full, a new IP replaces the one with the
+/// lowest count and inherits that count as its error.
+struct SpaceSaving {
+    counts: HashMap<IpAddr, u64>,
+    by_count: BTreeSet<(u64, IpAddr)>,
+}
+
+impl SpaceSaving {
+    fn insert(&mut self, ip: IpAddr) {
+        if let Some(count) = self.counts.get_mut(&ip) {
+            self.by_count.remove(&(*count, ip));
+            *count += 1;
+            self.by_count.insert((*count, ip));
+            return;
+        }
+        let count = if self.counts.len() < SHARD_CAPACITY {
+            1
+        } else {
+            let Some((min, victim)) = self.by_count.pop_first() else {
+                return;
+            };
+            self.counts.remove(&victim);
+            min + 1
+        };
+        self.counts.insert(ip, count);
+        self.by_count.insert((count, ip));
+    }
+}
+
+/// Bounded heavy-hitter counts of IPs, sharded by address so concurrent
+/// requests rarely contend.
+pub struct TopIps {
+    shards: Box<[Mutex<SpaceSaving>]>,
+}
+
+impl TopIps {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..TOP_SHARDS)
+                .map(|_| {
+                    Mutex::new(SpaceSaving {
+                        counts: HashMap::with_capacity(SHARD_CAPACITY),
+                        by_count: BTreeSet::new(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn insert(&self, ip: IpAddr) {
+        let shard = hash_ip(ip) as usize % TOP_SHARDS;
+        self.shards[shard].lock().insert(ip);
+    }
+
+    /// The `limit` IPs with the highest counts, sorted descending.
+    pub fn top(&self, limit: usize) -> Vec<(IpAddr, u64)> {
+        let mut entries: Vec<(IpAddr, u64)> = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock();
+            entries.extend(shard.by_count.iter().rev().take(limit).map(|&(count, ip)| (ip, count)));
+        }
+        entries.sort_by_key(|&(_, count)| Reverse(count));
+        entries.truncate(limit);
+        entries
+    }
+
+    /// Number of IPs currently tracked.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().counts.len()).sum()
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            shard.counts.clear();
+            shard.by_count.clear();
+        }
+    }
+}
+
+impl Default for TopIps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn synthetic_ip(i: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000u32.wrapping_add(i)))
+    }
+
+    #[test]
+    fn test_unique_estimate_stays_within_bounds() {
+        let hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0);
+        for n in [1_000u32, 50_000, 300_000] {
+            hll.clear();
+            for i in 0..n {
+                // Repeats must not count twice
+                hll.insert(synthetic_ip(i));
+                hll.insert(synthetic_ip(i));
+            }
+            let error = (hll.estimate() as f64 - n as f64).abs() / n as f64;
+            assert!(error < 0.025, "{} distinct IPs: error {:.4}", n, error);
+        }
+    }
+
+    #[test]
+    fn test_heavy_hitters_survive_a_spoofed_flood() {
+        let top = TopIps::new();
+        let heavy: Vec<IpAddr> = (0..5).map(|i| synthetic_ip(0xff_0000 + i)).collect();
+        for i in 0..200_000u32 {
+            top.insert(synthetic_ip(i));
+            if i % 20 == 0 {
+                for ip in &heavy {
+                    top.insert(*ip);
+                }
+            }
+        }
+        assert!(top.len() <= TOP_IPS_CAPACITY);
+        let listed = top.top(5);
+        assert_eq!(listed.len(), 5);
+        for ip in &heavy {
+            let (_, count) = listed.iter().find(|(listed, _)| listed == ip).expect("heavy hitter listed");
+            // True count is 10,000; the overestimate is bounded by the
+            // shard's traffic / 512
+            assert!((10_000..10_000 + 210_000 / SHARD_CAPACITY as u64 * 2).contains(count));
+        }
+    }
+}
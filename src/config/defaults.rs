@@ -1,10 +1,15 @@
+use std::collections::HashMap;
+
 use super::settings::{
-    AdminApiConfig, AsnScoringConfig, AutoBanConfig, BehavioralConfig, BlocklistConfig,
-    BotWhitelistConfig, ChallengeConfig, CloudflareConfig, AlertingConfig, EscalationConfig,
-    GeoipConfig, IpReputationConfig, L4ProtectionConfig, LoggingConfig, MobileProxyConfig,
-    ProtectionConfig, RateLimitConfig, RateLimitLevels, ServerConfig, StorageConfig, TlsConfig,
-    UpstreamConfig,
+    AdminApiConfig, AsnScoringConfig, CacheConfig, AutoBanConfig, BehavioralConfig, BlocklistConfig, BodyInspectionConfig,
+    BotWhitelistConfig, ChallengeConfig, CrawlerConfig, CircuitBreakerConfig, CloudflareConfig, ClusterConfig, AlertingConfig,
+    CredentialStuffingConfig, DistributedMitigationConfig, EscalationConfig, FirewallConfig, GeoipConfig, HealthCheckConfig,
+    InfluxdbExportConfig, IpReputationConfig, L4ProtectionConfig, LoggingConfig, MetricsConfig, MetricsExportConfig,
+    MobileProxyConfig, ProbeConfig, ProtectionConfig, RateLimitConfig, RateLimitLevels, SecurityEventsConfig,
+    SecurityEventsFileConfig, SecurityEventsHttpConfig, ServerConfig, ServerMode, StatsdExportConfig, StorageConfig,
+    TarpitConfig, TlsConfig, UpstreamConfig, WhenUnhealthy,
 };
+use crate::storage::ip_ranges::IpRangeMap;
 
 // ---------------------------------------------------------------------------
 // Top-level struct defaults
@@ -12,6 +17,7 @@ use super::settings::{
 
 pub fn default_server_config() -> ServerConfig {
     ServerConfig {
+        mode: default_server_mode(),
         bind_http: default_bind_http(),
         bind_https: default_bind_https(),
         workers: default_workers(),
@@ -19,9 +25,26 @@ pub fn default_server_config() -> ServerConfig {
         connection_timeout_secs: default_connection_timeout_secs(),
         request_timeout_secs: default_request_timeout_secs(),
         keepalive_timeout_secs: default_keepalive_timeout_secs(),
+        max_body_size_mb: default_max_body_size_mb(),
+        header_timeout_secs: default_header_timeout_secs(),
+        max_redirect_connections: default_max_redirect_connections(),
+        min_body_rate_bytes_per_sec: default_min_body_rate_bytes_per_sec(),
+        body_rate_grace_secs: default_body_rate_grace_secs(),
+        probes: default_probe_config(),
     }
 }
 
+pub fn default_probe_config() -> ProbeConfig {
+    ProbeConfig {
+        enabled: default_probes_enabled(),
+        require_healthy_upstream: false,
+        access_log: false,
+        metrics: false,
+    }
+}
+
+pub fn default_probes_enabled() -> bool { true }
+
 pub fn default_tls_config() -> TlsConfig {
     TlsConfig {
         cert_dir: default_cert_dir(),
@@ -35,6 +58,28 @@ pub fn default_upstream_config() -> UpstreamConfig {
         max_connections: default_upstream_max_connections(),
         connect_timeout_ms: default_connect_timeout_ms(),
         response_timeout_ms: default_response_timeout_ms(),
+        retries: default_upstream_retries(),
+        retry_backoff_ms: default_upstream_retry_backoff_ms(),
+        retry_methods: default_upstream_retry_methods(),
+        health_check: default_health_check_config(),
+        circuit_breaker: default_circuit_breaker_config(),
+    }
+}
+
+pub fn default_circuit_breaker_config() -> CircuitBreakerConfig {
+    CircuitBreakerConfig {
+        failure_threshold: default_circuit_failure_threshold(),
+        open_secs: default_circuit_open_secs(),
+    }
+}
+
+pub fn default_health_check_config() -> HealthCheckConfig {
+    HealthCheckConfig {
+        interval_secs: default_health_check_interval_secs(),
+        timeout_ms: default_health_check_timeout_ms(),
+        healthy_threshold: default_health_check_healthy_threshold(),
+        unhealthy_threshold: default_health_check_unhealthy_threshold(),
+        when_unhealthy: default_health_check_when_unhealthy(),
     }
 }
 
@@ -42,6 +87,12 @@ pub fn default_admin_api_config() -> AdminApiConfig {
     AdminApiConfig {
         bind: default_admin_bind(),
         api_key: default_api_key(),
+        read_only_api_key: None,
+        metrics_token: None,
+        public_status_enabled: false,
+        tls_cert: None,
+        tls_key: None,
+        client_ca: None,
     }
 }
 
@@ -49,6 +100,13 @@ pub fn default_geoip_config() -> GeoipConfig {
     GeoipConfig {
         city_db: default_city_db(),
         asn_db: default_asn_db(),
+        reload_interval_secs: default_geoip_reload_interval_secs(),
+        cache_capacity: default_geoip_cache_capacity(),
+        cache_ttl_secs: default_geoip_cache_ttl_secs(),
+        cache_key: default_geoip_cache_key(),
+        country_header: String::new(),
+        trusted_proxies: Vec::new(),
+        trusted_proxy_ranges: Default::default(),
     }
 }
 
@@ -58,13 +116,26 @@ pub fn default_protection_config() -> ProtectionConfig {
         auto_escalation: default_auto_escalation(),
         rate_limits: default_rate_limits(),
         ipv4_subnet_mask: default_ipv4_subnet_mask(),
+        ipv6_subnet_mask: default_ipv6_subnet_mask(),
+        max_tracked_ips: default_max_tracked_ips(),
+        max_tracked_subnets: default_max_tracked_subnets(),
+        max_tracked_asns: default_max_tracked_asns(),
+        exempt_cors_preflight: default_exempt_cors_preflight(),
+        tarpit: default_tarpit_config(),
+        distributed_mitigation: default_distributed_mitigation_config(),
+        body_inspection: default_body_inspection_config(),
+        credential_stuffing: default_credential_stuffing_config(),
+        max_bytes_per_ip_per_sec: 0,
+        bandwidth_cap_action: Default::default(),
         whitelisted_ips: Vec::new(),
         whitelisted_subnets: Vec::new(),
+        whitelist: IpRangeMap::new(),
     }
 }
 
 pub fn default_challenge_config() -> ChallengeConfig {
     ChallengeConfig {
+        mode: Default::default(),
         pow_difficulty_l1: default_pow_difficulty_l1(),
         pow_difficulty_l2: default_pow_difficulty_l2(),
         pow_difficulty_l3: default_pow_difficulty_l3(),
@@ -74,6 +145,12 @@ pub fn default_challenge_config() -> ChallengeConfig {
         exempt_paths: Vec::new(),
         cookie_subnet_binding: false,
         nojs_fallback_enabled: false,
+        nojs_max_attempts_per_minute: default_nojs_max_attempts_per_minute(),
+        max_unanswered_challenges: default_max_unanswered_challenges(),
+        unanswered_window_secs: default_unanswered_window_secs(),
+        challenge_flood_action: default_challenge_flood_action(),
+        clearance_relaxes_rate_limits: default_clearance_relaxes_rate_limits(),
+        cleared_rate_limit_multiplier: default_cleared_rate_limit_multiplier(),
     }
 }
 
@@ -83,6 +160,11 @@ pub fn default_blocklist_config() -> BlocklistConfig {
         challenged_countries: Vec::new(),
         blocked_asns: Vec::new(),
         country_challenge_score: default_country_challenge_score(),
+        allowed_countries: Vec::new(),
+        allowed_asns: Vec::new(),
+        allowlist_action: default_allowlist_action(),
+        allowlist_unknown_action: default_allowlist_unknown_action(),
+        feeds: Vec::new(),
     }
 }
 
@@ -90,8 +172,18 @@ pub fn default_behavioral_config() -> BehavioralConfig {
     BehavioralConfig {
         scoring_window_secs: default_scoring_window_secs(),
         max_profiles: default_max_profiles(),
+        interval_history: default_interval_history(),
         regularity_weight: default_regularity_weight(),
+        regular_cv: default_regular_cv(),
+        near_regular_cv: default_near_regular_cv(),
+        rate_weight: default_rate_weight(),
+        rate_min_requests: default_rate_min_requests(),
+        high_rps: default_high_rps(),
+        elevated_rps: default_elevated_rps(),
+        path_diversity_weight: default_path_diversity_weight(),
         path_diversity_min_requests: default_path_diversity_min_requests(),
+        path_diversity_max_paths: default_path_diversity_max_paths(),
+        consistency_weight: default_consistency_weight(),
     }
 }
 
@@ -105,6 +197,12 @@ pub fn default_escalation_config() -> EscalationConfig {
         l3_to_l4_rps: default_l3_to_l4_rps(),
         sustained_checks_required: default_sustained_checks_required(),
         block_ratio_threshold: default_block_ratio_threshold(),
+        per_service: false,
+        per_service_min_rps: default_per_service_min_rps(),
+        restore_max_age_secs: default_restore_max_age_secs(),
+        latency_escalation_ms: 0,
+        error_ratio_escalation: 0.0,
+        count_bypassed_requests: default_count_bypassed_requests(),
     }
 }
 
@@ -113,12 +211,22 @@ pub fn default_logging_config() -> LoggingConfig {
         level: default_log_level(),
         file: default_log_file(),
         access_log: default_access_log(),
+        access_log_format: default_access_log_format(),
+        access_log_max_size_mb: 0,
+        access_log_max_files: default_access_log_max_files(),
+        live_tail_max_clients: default_live_tail_max_clients(),
+        live_tail_buffer: default_live_tail_buffer(),
     }
 }
 
 pub fn default_storage_config() -> StorageConfig {
     StorageConfig {
         sqlite_path: default_sqlite_path(),
+        metrics_minutely_retention_hours: default_metrics_minutely_retention_hours(),
+        request_sample_rate: 0.0,
+        request_sample_retention_hours: default_request_sample_retention_hours(),
+        capture_memory_bytes: default_capture_memory_bytes(),
+        capture_retention_secs: default_capture_retention_secs(),
     }
 }
 
@@ -126,6 +234,10 @@ pub fn default_storage_config() -> StorageConfig {
 // ServerConfig field defaults
 // ---------------------------------------------------------------------------
 
+pub fn default_server_mode() -> ServerMode {
+    ServerMode::Https
+}
+
 pub fn default_bind_http() -> String {
     "0.0.0.0:80".to_string()
 }
@@ -154,6 +266,26 @@ pub fn default_keepalive_timeout_secs() -> u64 {
     5
 }
 
+pub fn default_max_body_size_mb() -> u64 {
+    100
+}
+
+pub fn default_header_timeout_secs() -> u64 {
+    10
+}
+
+pub fn default_max_redirect_connections() -> usize {
+    1024
+}
+
+pub fn default_min_body_rate_bytes_per_sec() -> u64 {
+    100
+}
+
+pub fn default_body_rate_grace_secs() -> u64 {
+    5
+}
+
 // ---------------------------------------------------------------------------
 // TlsConfig field defaults
 // ---------------------------------------------------------------------------
@@ -186,6 +318,23 @@ pub fn default_response_timeout_ms() -> u64 {
     60_000
 }
 
+pub fn default_upstream_retries() -> u32 { 2 }
+pub fn default_upstream_retry_backoff_ms() -> u64 { 50 }
+
+pub fn default_upstream_retry_methods() -> Vec<String> {
+    ["GET", "HEAD", "OPTIONS"].iter().map(|m| m.to_string()).collect()
+}
+
+pub fn default_health_check_interval_secs() -> u64 { 10 }
+pub fn default_health_check_timeout_ms() -> u64 { 5000 }
+pub fn default_health_check_healthy_threshold() -> u32 { 2 }
+pub fn default_health_check_unhealthy_threshold() -> u32 { 1 }
+pub fn default_health_check_when_unhealthy() -> WhenUnhealthy { WhenUnhealthy::FailFast }
+
+pub fn default_circuit_failure_threshold() -> u32 { 5 }
+
+pub fn default_circuit_open_secs() -> u64 { 30 }
+
 // ---------------------------------------------------------------------------
 // AdminApiConfig field defaults
 // ---------------------------------------------------------------------------
@@ -212,6 +361,22 @@ pub fn default_asn_db() -> String {
     "/opt/fortress/data/GeoLite2-ASN.mmdb".to_string()
 }
 
+pub fn default_geoip_reload_interval_secs() -> u64 {
+    300
+}
+
+pub fn default_geoip_cache_capacity() -> usize {
+    100_000
+}
+
+pub fn default_geoip_cache_ttl_secs() -> u64 {
+    3600
+}
+
+pub fn default_geoip_cache_key() -> String {
+    "subnet".to_string()
+}
+
 // ---------------------------------------------------------------------------
 // ProtectionConfig field defaults
 // ---------------------------------------------------------------------------
@@ -311,6 +476,30 @@ pub fn default_hmac_secret() -> String {
     String::new()
 }
 
+pub fn default_nojs_max_attempts_per_minute() -> u64 {
+    10
+}
+
+pub fn default_max_unanswered_challenges() -> u64 {
+    5
+}
+
+pub fn default_unanswered_window_secs() -> u64 {
+    60
+}
+
+pub fn default_challenge_flood_action() -> String {
+    "block".to_string()
+}
+
+pub fn default_clearance_relaxes_rate_limits() -> bool {
+    true
+}
+
+pub fn default_cleared_rate_limit_multiplier() -> f64 {
+    5.0
+}
+
 // ---------------------------------------------------------------------------
 // BehavioralConfig field defaults
 // ---------------------------------------------------------------------------
@@ -323,6 +512,17 @@ pub fn default_max_profiles() -> usize {
     1_000_000
 }
 
+pub fn default_interval_history() -> usize { 100 }
+pub fn default_regular_cv() -> f64 { 0.05 }
+pub fn default_near_regular_cv() -> f64 { 0.15 }
+pub fn default_rate_weight() -> f64 { 1.0 }
+pub fn default_rate_min_requests() -> u64 { 100 }
+pub fn default_high_rps() -> f64 { 50.0 }
+pub fn default_elevated_rps() -> f64 { 20.0 }
+pub fn default_path_diversity_weight() -> f64 { 1.0 }
+pub fn default_path_diversity_max_paths() -> usize { 2 }
+pub fn default_consistency_weight() -> f64 { 1.0 }
+
 // ---------------------------------------------------------------------------
 // EscalationConfig field defaults
 // ---------------------------------------------------------------------------
@@ -351,6 +551,10 @@ pub fn default_l3_to_l4_rps() -> u64 {
     100_000
 }
 
+pub fn default_restore_max_age_secs() -> u64 {
+    600
+}
+
 // ---------------------------------------------------------------------------
 // LoggingConfig field defaults
 // ---------------------------------------------------------------------------
@@ -367,6 +571,22 @@ pub fn default_access_log() -> String {
     "/var/log/fortress/access.log".to_string()
 }
 
+pub fn default_access_log_format() -> String {
+    "json".to_string()
+}
+
+pub fn default_live_tail_max_clients() -> usize {
+    4
+}
+
+pub fn default_live_tail_buffer() -> usize {
+    1024
+}
+
+pub fn default_access_log_max_files() -> usize {
+    5
+}
+
 // ---------------------------------------------------------------------------
 // StorageConfig field defaults
 // ---------------------------------------------------------------------------
@@ -375,6 +595,22 @@ pub fn default_sqlite_path() -> String {
     "/opt/fortress/data/fortress.db".to_string()
 }
 
+pub fn default_metrics_minutely_retention_hours() -> u64 {
+    24
+}
+
+pub fn default_request_sample_retention_hours() -> u64 {
+    72
+}
+
+pub fn default_capture_memory_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+pub fn default_capture_retention_secs() -> u64 {
+    3_600
+}
+
 // ---------------------------------------------------------------------------
 // L4ProtectionConfig field defaults
 // ---------------------------------------------------------------------------
@@ -385,8 +621,13 @@ pub fn default_l4_protection_config() -> L4ProtectionConfig {
         syn_rate_per_ip_per_sec: default_syn_rate(),
         connection_rate_per_ip_per_sec: default_conn_rate(),
         max_concurrent_per_ip: default_max_concurrent(),
+        max_concurrent_per_subnet: default_max_concurrent_per_subnet(),
+        connection_rate_per_subnet_per_sec: default_subnet_conn_rate(),
         tarpit_enabled: default_tarpit_enabled(),
         tarpit_delay_ms: default_tarpit_delay(),
+        handshake_failure_threshold: default_handshake_failure_threshold(),
+        handshake_failure_window_secs: default_handshake_failure_window_secs(),
+        handshake_failure_cooldown_secs: default_handshake_failure_cooldown_secs(),
     }
 }
 
@@ -394,8 +635,13 @@ pub fn default_l4_enabled() -> bool { true }
 pub fn default_syn_rate() -> u64 { 50 }
 pub fn default_conn_rate() -> u64 { 30 }
 pub fn default_max_concurrent() -> u64 { 100 }
+pub fn default_max_concurrent_per_subnet() -> u64 { 1000 }
+pub fn default_subnet_conn_rate() -> u64 { 300 }
 pub fn default_tarpit_enabled() -> bool { true }
 pub fn default_tarpit_delay() -> u64 { 5000 }
+pub fn default_handshake_failure_threshold() -> u64 { 20 }
+pub fn default_handshake_failure_window_secs() -> u64 { 60 }
+pub fn default_handshake_failure_cooldown_secs() -> u64 { 300 }
 
 // ---------------------------------------------------------------------------
 // AlertingConfig defaults
@@ -405,9 +651,18 @@ pub fn default_alerting_config() -> AlertingConfig {
     AlertingConfig {
         enabled: false,
         webhook_url: None,
+        channels: Vec::new(),
+        cooldown_secs: default_alert_cooldown_secs(),
+        cert_expiry_days: default_alert_cert_expiry_days(),
+        subnet_ban_threshold: default_alert_subnet_ban_threshold(),
     }
 }
 
+pub fn default_alert_cooldown_secs() -> u64 { 600 }
+pub fn default_alert_cert_expiry_days() -> u64 { 14 }
+pub fn default_alert_subnet_ban_threshold() -> u32 { 10 }
+pub fn default_alert_min_severity() -> String { "info".to_string() }
+
 // ---------------------------------------------------------------------------
 // BotWhitelistConfig defaults
 // ---------------------------------------------------------------------------
@@ -416,11 +671,24 @@ pub fn default_bot_whitelist_config() -> BotWhitelistConfig {
     BotWhitelistConfig {
         enabled: default_bot_whitelist_enabled(),
         verify_ip: default_bot_verify_ip(),
+        crawlers: default_crawlers(),
+        cache_ttl_secs: default_bot_cache_ttl_secs(),
+        dns_timeout_ms: default_bot_dns_timeout_ms(),
+        max_pending_lookups: default_bot_max_pending_lookups(),
+        pending_score: default_bot_pending_score(),
     }
 }
 
 pub fn default_bot_whitelist_enabled() -> bool { true }
 pub fn default_bot_verify_ip() -> bool { true }
+pub fn default_bot_cache_ttl_secs() -> u64 { 3600 }
+pub fn default_bot_dns_timeout_ms() -> u64 { 3000 }
+pub fn default_bot_max_pending_lookups() -> usize { 64 }
+pub fn default_bot_pending_score() -> f64 { 10.0 }
+
+pub fn default_crawlers() -> Vec<CrawlerConfig> {
+    serde_json::from_str(include_str!("crawlers.json")).expect("built-in crawler list is valid JSON")
+}
 
 // ---------------------------------------------------------------------------
 // MobileProxyConfig defaults
@@ -445,23 +713,107 @@ pub fn default_asn_scoring_config() -> AsnScoringConfig {
         datacenter_score: default_datacenter_score(),
         vpn_score: default_vpn_score(),
         residential_proxy_score: default_residential_proxy_score(),
+        dataset: String::new(),
+        dataset_refresh_secs: default_asn_dataset_refresh_secs(),
     }
 }
 
 pub fn default_datacenter_score() -> f64 { 5.0 }
 pub fn default_vpn_score() -> f64 { 5.0 }
 pub fn default_residential_proxy_score() -> f64 { 25.0 }
+pub fn default_asn_dataset_refresh_secs() -> u64 { 86_400 }
 
 // ---------------------------------------------------------------------------
 // New field defaults (added to existing structs)
 // ---------------------------------------------------------------------------
 
 pub fn default_country_challenge_score() -> f64 { 20.0 }
+pub fn default_allowlist_action() -> String { "block".to_string() }
+pub fn default_allowlist_unknown_action() -> String { "challenge".to_string() }
+pub fn default_feed_refresh_interval_secs() -> u64 { 3600 }
+pub fn default_feed_enabled() -> bool { true }
 pub fn default_regularity_weight() -> f64 { 0.5 }
 pub fn default_path_diversity_min_requests() -> u64 { 50 }
 pub fn default_sustained_checks_required() -> u8 { 3 }
 pub fn default_block_ratio_threshold() -> f64 { 0.3 }
+pub fn default_per_service_min_rps() -> u64 { 10 }
+pub fn default_count_bypassed_requests() -> bool { true }
 pub fn default_ipv4_subnet_mask() -> u8 { 24 }
+pub fn default_ipv6_subnet_mask() -> u8 { 64 }
+pub fn default_max_tracked_ips() -> usize { 500_000 }
+pub fn default_max_tracked_subnets() -> usize { 200_000 }
+pub fn default_max_tracked_asns() -> usize { 100_000 }
+pub fn default_exempt_cors_preflight() -> bool { true }
+
+pub fn default_tarpit_config() -> TarpitConfig {
+    TarpitConfig {
+        delay_secs: default_tarpit_delay_secs(),
+        drip_interval_ms: default_tarpit_drip_interval_ms(),
+        max_concurrent: default_tarpit_max_concurrent(),
+    }
+}
+
+pub fn default_distributed_mitigation_config() -> DistributedMitigationConfig {
+    DistributedMitigationConfig {
+        enabled: default_distributed_mitigation_enabled(),
+        action: default_escalation_action(),
+        min_path_share: default_distributed_mitigation_min_path_share(),
+        hold_secs: default_distributed_mitigation_hold_secs(),
+    }
+}
+
+pub fn default_distributed_mitigation_enabled() -> bool { true }
+pub fn default_distributed_mitigation_min_path_share() -> f64 { 0.5 }
+pub fn default_distributed_mitigation_hold_secs() -> u64 { 300 }
+
+pub fn default_tarpit_delay_secs() -> u64 { 30 }
+pub fn default_tarpit_drip_interval_ms() -> u64 { 1000 }
+pub fn default_tarpit_max_concurrent() -> usize { 1000 }
+
+pub fn default_body_inspection_config() -> BodyInspectionConfig {
+    BodyInspectionConfig {
+        max_bytes: default_body_inspection_max_bytes(),
+        read_timeout_secs: default_body_inspection_read_timeout_secs(),
+        content_types: default_body_inspection_content_types(),
+        exempt_content_types: Vec::new(),
+        exempt_paths: Vec::new(),
+        max_decompressed_bytes: default_body_inspection_max_decompressed_bytes(),
+        max_expansion_ratio: default_body_inspection_max_expansion_ratio(),
+    }
+}
+
+pub fn default_body_inspection_max_bytes() -> usize { 64 * 1024 }
+pub fn default_body_inspection_read_timeout_secs() -> u64 { 10 }
+pub fn default_body_inspection_max_decompressed_bytes() -> usize { 10 * 1024 * 1024 }
+pub fn default_body_inspection_max_expansion_ratio() -> f64 { 100.0 }
+pub fn default_body_inspection_content_types() -> Vec<String> {
+    vec![
+        "application/x-www-form-urlencoded".to_string(),
+        "application/json".to_string(),
+    ]
+}
+
+pub fn default_credential_stuffing_config() -> CredentialStuffingConfig {
+    CredentialStuffingConfig {
+        enabled: false,
+        login_paths: default_credential_stuffing_login_paths(),
+        username_field: default_credential_stuffing_username_field(),
+        window_secs: default_credential_stuffing_window_secs(),
+        max_ips_per_username: default_credential_stuffing_max_ips(),
+        max_attempts_per_username: default_credential_stuffing_max_attempts(),
+        action: default_escalation_action(),
+        max_tracked_usernames: default_credential_stuffing_max_tracked(),
+    }
+}
+
+pub fn default_credential_stuffing_login_paths() -> Vec<String> {
+    vec!["/login".to_string(), "/signin".to_string(), "/auth/login".to_string()]
+}
+pub fn default_credential_stuffing_username_field() -> String { "username".to_string() }
+pub fn default_credential_stuffing_window_secs() -> u64 { 600 }
+pub fn default_credential_stuffing_max_ips() -> usize { 10 }
+pub fn default_credential_stuffing_max_attempts() -> usize { 30 }
+pub fn default_credential_stuffing_max_tracked() -> usize { 100_000 }
 
 // ---------------------------------------------------------------------------
 // IpReputationConfig defaults
@@ -476,6 +828,13 @@ pub fn default_ip_reputation_config() -> IpReputationConfig {
         decay_percent: default_decay_percent(),
         block_threshold: default_reputation_block_threshold(),
         high_reputation_score: default_high_reputation_score(),
+        proxy_score: default_proxy_score(),
+        category_decay_days: default_category_decay_days(),
+        tor_exit_list_url: default_tor_exit_list_url(),
+        tor_exit_list_path: None,
+        proxy_feeds: Vec::new(),
+        feed_refresh_secs: default_reputation_feed_refresh_secs(),
+        offline: false,
     }
 }
 
@@ -486,6 +845,10 @@ pub fn default_decay_interval_secs() -> u64 { 600 }
 pub fn default_decay_percent() -> f64 { 10.0 }
 pub fn default_reputation_block_threshold() -> f64 { 80.0 }
 pub fn default_high_reputation_score() -> f64 { 20.0 }
+pub fn default_proxy_score() -> f64 { 10.0 }
+pub fn default_category_decay_days() -> u64 { 7 }
+pub fn default_tor_exit_list_url() -> String { "https://check.torproject.org/torbulkexitlist".to_string() }
+pub fn default_reputation_feed_refresh_secs() -> u64 { 3600 }
 
 // ---------------------------------------------------------------------------
 // AutoBanConfig defaults
@@ -499,6 +862,15 @@ pub fn default_auto_ban_config() -> AutoBanConfig {
         ban_threshold_1h: default_ban_threshold_1h(),
         repeat_ban_threshold: default_repeat_ban_threshold(),
         subnet_ban_ratio: default_subnet_ban_ratio(),
+        asn_escalation_enabled: false,
+        asn_ban_ip_threshold: default_asn_ban_ip_threshold(),
+        asn_ban_ttl_secs: default_escalation_ttl_secs(),
+        asn_ban_action: default_escalation_action(),
+        country_escalation_enabled: false,
+        country_ban_ip_threshold: default_country_ban_ip_threshold(),
+        country_ban_ttl_secs: default_escalation_ttl_secs(),
+        country_ban_action: default_escalation_action(),
+        escalation_window_secs: default_escalation_window_secs(),
     }
 }
 
@@ -508,6 +880,11 @@ pub fn default_ban_threshold_15m() -> u32 { 25 }
 pub fn default_ban_threshold_1h() -> u32 { 50 }
 pub fn default_repeat_ban_threshold() -> u32 { 3 }
 pub fn default_subnet_ban_ratio() -> f64 { 0.3 }
+pub fn default_asn_ban_ip_threshold() -> u32 { 20 }
+pub fn default_country_ban_ip_threshold() -> u32 { 200 }
+pub fn default_escalation_ttl_secs() -> u64 { 3600 }
+pub fn default_escalation_action() -> String { "challenge".to_string() }
+pub fn default_escalation_window_secs() -> u64 { 600 }
 
 // ---------------------------------------------------------------------------
 // CloudflareConfig defaults
@@ -519,6 +896,169 @@ pub fn default_cloudflare_config() -> CloudflareConfig {
     }
 }
 
+// ---------------------------------------------------------------------------
+// ClusterConfig defaults
+// ---------------------------------------------------------------------------
+
+pub fn default_cluster_config() -> ClusterConfig {
+    ClusterConfig {
+        enabled: false,
+        node_id: String::new(),
+        peers: Vec::new(),
+        shared_secret: String::new(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CacheConfig defaults
+// ---------------------------------------------------------------------------
+
+pub fn default_cache_config() -> CacheConfig {
+    CacheConfig {
+        enabled: false,
+        max_size_mb: default_cache_max_size_mb(),
+        max_entry_size_kb: default_cache_max_entry_size_kb(),
+        max_ttl_secs: default_cache_max_ttl_secs(),
+        stale_ttl_secs: default_cache_stale_ttl_secs(),
+        stale_max_size_mb: default_cache_stale_max_size_mb(),
+        stale_banner_html: default_cache_stale_banner_html(),
+    }
+}
+
+pub fn default_cache_max_size_mb() -> u64 { 64 }
+pub fn default_cache_max_entry_size_kb() -> u64 { 1024 }
+pub fn default_cache_max_ttl_secs() -> u64 { 3600 }
+pub fn default_cache_stale_ttl_secs() -> u64 { 86400 }
+pub fn default_cache_stale_max_size_mb() -> u64 { 64 }
+pub fn default_cache_stale_banner_html() -> String {
+    "<div style=\"background:#fef3c7;color:#78350f;padding:8px;text-align:center;font:14px sans-serif\">\
+     This site is temporarily unavailable. You are viewing a saved copy of this page.</div>"
+        .to_string()
+}
+
+// ---------------------------------------------------------------------------
+// MetricsConfig defaults
+// ---------------------------------------------------------------------------
+
+pub fn default_metrics_config() -> MetricsConfig {
+    MetricsConfig {
+        export: default_metrics_export_config(),
+    }
+}
+
+pub fn default_metrics_export_config() -> MetricsExportConfig {
+    MetricsExportConfig {
+        statsd: default_statsd_export_config(),
+        influxdb: default_influxdb_export_config(),
+    }
+}
+
+pub fn default_statsd_export_config() -> StatsdExportConfig {
+    StatsdExportConfig {
+        enabled: false,
+        address: default_statsd_address(),
+        prefix: default_statsd_prefix(),
+        interval_secs: default_metrics_export_interval_secs(),
+    }
+}
+
+pub fn default_influxdb_export_config() -> InfluxdbExportConfig {
+    InfluxdbExportConfig {
+        enabled: false,
+        url: default_influxdb_url(),
+        org: String::new(),
+        bucket: String::new(),
+        token: String::new(),
+        interval_secs: default_metrics_export_interval_secs(),
+    }
+}
+
+pub fn default_statsd_address() -> String { "127.0.0.1:8125".to_string() }
+pub fn default_statsd_prefix() -> String { "fortress".to_string() }
+pub fn default_influxdb_url() -> String { "http://127.0.0.1:8086".to_string() }
+pub fn default_metrics_export_interval_secs() -> u64 { 10 }
+
+// ---------------------------------------------------------------------------
+// SecurityEventsConfig defaults
+// ---------------------------------------------------------------------------
+
+pub fn default_security_events_config() -> SecurityEventsConfig {
+    SecurityEventsConfig {
+        buffer_size: default_security_events_buffer_size(),
+        file: default_security_events_file_config(),
+        http: default_security_events_http_config(),
+    }
+}
+
+pub fn default_security_events_file_config() -> SecurityEventsFileConfig {
+    SecurityEventsFileConfig {
+        path: String::new(),
+        max_size_mb: default_security_events_max_size_mb(),
+        max_files: default_access_log_max_files(),
+    }
+}
+
+pub fn default_security_events_http_config() -> SecurityEventsHttpConfig {
+    SecurityEventsHttpConfig {
+        url: String::new(),
+        headers: HashMap::new(),
+        batch_size: default_security_events_batch_size(),
+        flush_interval_ms: default_security_events_flush_interval_ms(),
+        max_retries: default_security_events_max_retries(),
+        timeout_secs: default_security_events_timeout_secs(),
+        spool_path: default_security_events_spool_path(),
+        spool_max_mb: default_security_events_spool_max_mb(),
+    }
+}
+
+pub fn default_security_events_buffer_size() -> usize { 10_000 }
+pub fn default_security_events_max_size_mb() -> u64 { 100 }
+pub fn default_security_events_batch_size() -> usize { 500 }
+pub fn default_security_events_flush_interval_ms() -> u64 { 1000 }
+pub fn default_security_events_max_retries() -> u32 { 3 }
+pub fn default_security_events_timeout_secs() -> u64 { 10 }
+pub fn default_security_events_spool_path() -> String { "data/security-events.spool".to_string() }
+pub fn default_security_events_spool_max_mb() -> u64 { 100 }
+
+// ---------------------------------------------------------------------------
+// FirewallConfig defaults
+// ---------------------------------------------------------------------------
+
+pub fn default_firewall_config() -> FirewallConfig {
+    FirewallConfig {
+        enabled: false,
+        add_command: default_firewall_add_command(),
+        remove_command: default_firewall_remove_command(),
+        flush_command: default_firewall_flush_command(),
+        command_timeout_ms: default_firewall_command_timeout_ms(),
+        max_commands_per_sec: default_firewall_max_commands_per_sec(),
+        queue_size: default_firewall_queue_size(),
+        l4_drop_threshold: default_firewall_l4_drop_threshold(),
+        l4_drop_window_secs: default_firewall_l4_drop_window_secs(),
+        l4_ban_secs: default_firewall_l4_ban_secs(),
+    }
+}
+
+fn argv(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+pub fn default_firewall_add_command() -> Vec<String> {
+    argv(&["nft", "add", "element", "inet", "fortress", "banned_{family}", "{ {ip} timeout {timeout}s }"])
+}
+pub fn default_firewall_remove_command() -> Vec<String> {
+    argv(&["nft", "delete", "element", "inet", "fortress", "banned_{family}", "{ {ip} }"])
+}
+pub fn default_firewall_flush_command() -> Vec<String> {
+    argv(&["nft", "flush", "set", "inet", "fortress", "banned_{family}"])
+}
+pub fn default_firewall_command_timeout_ms() -> u64 { 5000 }
+pub fn default_firewall_max_commands_per_sec() -> u32 { 20 }
+pub fn default_firewall_queue_size() -> usize { 1000 }
+pub fn default_firewall_l4_drop_threshold() -> u32 { 200 }
+pub fn default_firewall_l4_drop_window_secs() -> u64 { 60 }
+pub fn default_firewall_l4_ban_secs() -> u64 { 600 }
+
 // ---------------------------------------------------------------------------
 // Helpers
 // ---------------------------------------------------------------------------
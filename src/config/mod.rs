@@ -1,3 +1,4 @@
 pub mod settings;
 pub mod defaults;
 pub mod service;
+pub mod reload;
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tracing::{info, warn};
+
+use crate::config::settings::{Settings, SharedSettings};
+use crate::protection::challenge::ChallengeSystem;
+use crate::protection::escalation::EscalationEngine;
+use crate::storage::blocklist::BlocklistManager;
+use crate::storage::memory::MemoryStore;
+
+/// Re-reads the config file and applies it to the running proxy.
+///
+/// Values the pipeline reads per request (rate limits, whitelists, scores)
+/// take effect as soon as the new [`Settings`] is swapped in. Components that
+/// copy their config at construction (challenge, escalation, blocklist,
+/// memory store caps) are updated explicitly. Listener addresses, TLS and
+/// storage paths still need a restart.
+pub struct ConfigReloader {
+    path: String,
+    settings: SharedSettings,
+    challenge: Arc<ChallengeSystem>,
+    escalation: Arc<EscalationEngine>,
+    blocklist: Arc<BlocklistManager>,
+    memory: Arc<MemoryStore>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: String,
+        settings: SharedSettings,
+        challenge: Arc<ChallengeSystem>,
+        escalation: Arc<EscalationEngine>,
+        blocklist: Arc<BlocklistManager>,
+        memory: Arc<MemoryStore>,
+    ) -> Self {
+        Self {
+            path,
+            settings,
+            challenge,
+            escalation,
+            blocklist,
+            memory,
+        }
+    }
+
+    /// Load the config file and apply it. On error the running configuration
+    /// is left untouched.
+    pub async fn reload(&self) -> Result<()> {
+        let new = Settings::load(&self.path)?;
+        if new.challenge.hmac_secret.is_empty() {
+            bail!("challenge.hmac_secret is empty");
+        }
+
+        let current = self.settings.load();
+        if new.server.mode != current.server.mode
+            || new.server.bind_http != current.server.bind_http
+            || new.server.bind_https != current.server.bind_https
+            || new.tls.cert_dir != current.tls.cert_dir
+            || new.admin_api.bind != current.admin_api.bind
+            || new.admin_api.tls_cert != current.admin_api.tls_cert
+            || new.admin_api.tls_key != current.admin_api.tls_key
+            || new.admin_api.client_ca != current.admin_api.client_ca
+        {
+            warn!("Listener and TLS changes in {} require a restart and were not applied", self.path);
+        }
+        if new.admin_api.api_key != current.admin_api.api_key
+            || new.admin_api.read_only_api_key != current.admin_api.read_only_api_key
+        {
+            warn!("Admin API key changes in {} require a restart and were not applied", self.path);
+        }
+
+        self.challenge.reload(&new.challenge, &new.protection);
+        self.escalation.reload(&new);
+        self.memory.set_tracking_limits(&new.protection);
+        self.memory.set_behavior_config(&new.behavioral);
+        if let Err(e) = self.blocklist.apply_config(&new.blocklist).await {
+            warn!("Failed to apply config blocklists on reload: {}", e);
+        }
+
+        self.settings.store(Arc::new(new));
+        info!("Configuration reloaded from {}", self.path);
+        Ok(())
+    }
+}
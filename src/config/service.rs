@@ -1,4 +1,9 @@
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::protection::challenge::{glob_match, ExemptPath};
 
 /// Configuration for a single protected service/backend.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -6,7 +11,17 @@ pub struct ServiceConfig {
     pub id: String,
     pub name: String,
     pub domains: Vec<String>,
-    pub upstream_address: String,
+    /// One or more upstreams, each `host:port` (plain HTTP) or a URL such as
+    /// `https://backend.internal:8443`. Accepts a single string for
+    /// backwards compatibility with older configs.
+    #[serde(deserialize_with = "string_or_list")]
+    pub upstream_address: Vec<String>,
+    #[serde(default)]
+    pub lb_strategy: LoadBalanceStrategy,
+    /// Requests matching one of these, tried in order, go to the route's
+    /// upstreams instead of `upstream_address`.
+    #[serde(default)]
+    pub routes: Vec<ServiceRoute>,
     #[serde(default = "default_enabled")]
     pub enabled: bool,
     pub protection_level_override: Option<u8>,
@@ -14,22 +29,541 @@ pub struct ServiceConfig {
     pub always_challenge: bool,
     #[serde(default = "default_rate_limit_multiplier")]
     pub rate_limit_multiplier: f64,
+    /// Absolute per-IP limit for this service, replacing the level's
+    /// `ip_per_10s` (and the multiplier) when set.
+    #[serde(default)]
+    pub max_requests_per_ip_10s: Option<u64>,
     #[serde(default = "default_service_max_connections")]
     pub max_connections: usize,
     #[serde(default = "default_service_connect_timeout")]
     pub connect_timeout_ms: u64,
     #[serde(default = "default_service_response_timeout")]
     pub response_timeout_ms: u64,
+    /// Paths (`*` wildcards) that are never challenged and bypass
+    /// maintenance mode, e.g. health checks and webhooks. Added to
+    /// `challenge.exempt_paths`; entries may be limited to some methods.
+    #[serde(default)]
+    pub exempt_paths: Vec<ExemptPath>,
+    /// Verify the certificate of `https://` upstreams. Turn off for
+    /// self-signed internal certificates.
+    #[serde(default = "default_upstream_tls_verify")]
+    pub upstream_tls_verify: bool,
+    /// Server name sent (and verified) in the TLS handshake with `https://`
+    /// upstreams, instead of the upstream's host.
+    #[serde(default)]
+    pub upstream_sni_host: Option<String>,
+    /// Speak HTTP/2 to the upstreams: prior knowledge (h2c) for plain
+    /// `http://` upstreams, ALPN for `https://` ones. Upstreams that turn
+    /// out not to support it are sent HTTP/1.1 for a while.
+    #[serde(default)]
+    pub upstream_http2: bool,
+    /// Headers set on requests sent upstream, replacing any the client sent.
+    /// Values may use `{client_ip}`, `{ray_id}`, `{country}` and `{host}`.
+    #[serde(default)]
+    pub add_request_headers: HashMap<String, String>,
+    /// Client headers that are not forwarded upstream.
+    #[serde(default)]
+    pub remove_request_headers: Vec<String>,
+    /// Headers set on upstream responses, with the same templates as
+    /// `add_request_headers`.
+    #[serde(default)]
+    pub add_response_headers: HashMap<String, String>,
+    /// Upstream headers that are not returned to the client.
+    #[serde(default)]
+    pub remove_response_headers: Vec<String>,
+    /// Country allowlist for this service. When this or `allowed_asns` is
+    /// non-empty it replaces the global `blocklist.allowed_*` lists.
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+    #[serde(default)]
+    pub allowed_asns: Vec<u32>,
+    /// Countries blocked or challenged on this service in addition to the
+    /// global `blocklist` lists.
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+    #[serde(default)]
+    pub challenged_countries: Vec<String>,
+    /// Countries this service never blocks or challenges by country:
+    /// they beat the service's own lists and the global blocklist and
+    /// allowlist alike.
+    #[serde(default)]
+    pub country_exceptions: Vec<String>,
+    /// `Domain` attribute of the clearance cookie, e.g. `example.com` to
+    /// share clearance between `www.` and `api.`. Unset keeps the cookie
+    /// on the requesting host only.
+    #[serde(default)]
+    pub clearance_cookie_domain: Option<String>,
+    /// Clearance lifetime for this service, replacing
+    /// `challenge.cookie_max_age_secs`.
+    #[serde(default)]
+    pub clearance_ttl_secs: Option<u64>,
+    /// Origins (`https://app.example.com`, or `*`) whose CORS preflights
+    /// Fortress answers itself instead of forwarding them upstream.
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// Answer every request except `exempt_paths` with a 503 maintenance
+    /// page, without contacting the upstream.
+    #[serde(default)]
+    pub maintenance_mode: bool,
+    /// HTML file served in maintenance mode instead of the built-in page.
+    #[serde(default)]
+    pub maintenance_html_path: Option<String>,
+    /// Run the body inspection rules (SQLi, XSS, null bytes, serialized
+    /// PHP objects) on form and JSON bodies. Adds latency: the start of the
+    /// body is read before the request is forwarded.
+    #[serde(default)]
+    pub body_inspection: bool,
+    /// Form field or JSON key holding the username on login paths, for
+    /// credential stuffing detection; unset uses
+    /// `protection.credential_stuffing.username_field`.
+    #[serde(default)]
+    pub login_username_field: Option<String>,
+    /// Request body limit for this service, replacing
+    /// `server.max_body_size_mb`. 0 means unlimited.
+    #[serde(default)]
+    pub max_body_size_mb: Option<u64>,
+    /// What to do with request bodies sent with a `Content-Encoding`.
+    #[serde(default)]
+    pub request_encoding: RequestEncodingPolicy,
+    /// Keep the last good copy of HTML pages and serve it while the
+    /// upstream is down or answering 5xx, marked
+    /// `X-Fortress-Served-Stale: true`.
     #[serde(default)]
-    pub exempt_paths: Vec<String>,
+    pub always_online: bool,
+    /// Inject `cache.stale_banner_html` into stale pages served by
+    /// `always_online`.
+    #[serde(default)]
+    pub always_online_banner: bool,
+    /// Queue new visitors instead of forwarding them while the upstreams
+    /// are saturated (see [`WaitingRoomConfig`]).
+    #[serde(default)]
+    pub waiting_room: Option<WaitingRoomConfig>,
+    /// How the service's upstreams are health checked; unset uses a TCP
+    /// connect with the global `upstream.health_check` settings.
+    #[serde(default)]
+    pub health_check: Option<ServiceHealthCheck>,
     #[serde(default)]
     pub created_at: Option<String>,
     #[serde(default)]
     pub updated_at: Option<String>,
 }
 
+impl ServiceConfig {
+    /// Whether a `method` request to `path` matches one of `exempt_paths`.
+    pub fn is_exempt_path(&self, method: &str, path: &str) -> bool {
+        self.exempt_paths.iter().any(|e| e.matches(method, path))
+    }
+
+    /// Whether `country` is one of `country_exceptions`.
+    pub fn is_country_excepted(&self, country: &str) -> bool {
+        self.country_exceptions.iter().any(|c| c.eq_ignore_ascii_case(country))
+    }
+
+    /// Index into `routes` of the first route matching a request for
+    /// `path` on `host`; `None` sends it to `upstream_address`.
+    pub fn route_for(&self, host: &str, path: &str) -> Option<usize> {
+        self.routes.iter().position(|r| r.matches(host, path))
+    }
+
+    /// Every distinct upstream of the service, its own and its routes'.
+    pub fn all_upstreams(&self) -> Vec<&str> {
+        let mut upstreams: Vec<&str> = Vec::new();
+        for addr in self.upstream_address.iter().chain(self.routes.iter().flat_map(|r| &r.upstream_address)) {
+            if !upstreams.contains(&addr.as_str()) {
+                upstreams.push(addr);
+            }
+        }
+        upstreams
+    }
+}
+
+/// Sends a service's requests for some paths, or for one of its domains,
+/// to other upstreams, e.g. `/api/` to the API servers and the rest to the
+/// web frontend. Every matcher that is set must match.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceRoute {
+    /// Logged as `route` in the access log; `route-<n>` (1-based) if unset.
+    #[serde(default)]
+    pub id: String,
+    /// Domain of the service this route is limited to; `*` wildcards.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Matches paths starting with this, e.g. `/api/`.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Matches the whole path, `*` matching any run of characters.
+    #[serde(default)]
+    pub path_glob: Option<String>,
+    /// Load balanced with the service's `lb_strategy`, and health checked
+    /// like the service's own upstreams.
+    #[serde(deserialize_with = "string_or_list")]
+    pub upstream_address: Vec<String>,
+    /// Replace the service's timeouts for requests on this route.
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub response_timeout_ms: Option<u64>,
+}
+
+impl ServiceRoute {
+    /// Whether a request for `path` (normalized) on `host` takes this route.
+    pub fn matches(&self, host: &str, path: &str) -> bool {
+        let host = host.split(':').next().unwrap_or(host);
+        self.host
+            .as_deref()
+            .is_none_or(|pattern| glob_match(&pattern.to_ascii_lowercase(), &host.to_ascii_lowercase()))
+            && self.path_prefix.as_deref().is_none_or(|prefix| path.starts_with(prefix))
+            && self.path_glob.as_deref().is_none_or(|pattern| glob_match(pattern, path))
+    }
+}
+
+/// Check the routes of a service and give unnamed ones their default id.
+pub fn normalize_routes(routes: &mut [ServiceRoute]) -> Result<(), String> {
+    for (i, route) in routes.iter_mut().enumerate() {
+        if route.id.trim().is_empty() {
+            route.id = format!("route-{}", i + 1);
+        }
+        if route.host.is_none() && route.path_prefix.is_none() && route.path_glob.is_none() {
+            return Err(format!("route {} needs host, path_prefix or path_glob", route.id));
+        }
+        if route.path_prefix.is_some() && route.path_glob.is_some() {
+            return Err(format!("route {} has both path_prefix and path_glob", route.id));
+        }
+        if let Some(path) = route.path_prefix.as_deref().or(route.path_glob.as_deref()) {
+            if !path.starts_with('/') && !path.starts_with('*') {
+                return Err(format!("path of route {} must start with /", route.id));
+            }
+        }
+        if route.upstream_address.iter().all(|a| a.trim().is_empty()) {
+            return Err(format!("route {} has no upstream_address", route.id));
+        }
+    }
+    for (i, route) in routes.iter().enumerate() {
+        if routes[..i].iter().any(|r| r.id == route.id) {
+            return Err(format!("duplicate route id {}", route.id));
+        }
+    }
+    Ok(())
+}
+
+/// Waiting room of a service. While it is active and more than
+/// `max_in_flight` requests are in flight to the upstreams, visitors
+/// without clearance get a queue position and an auto-refreshing page;
+/// positions are let in at `admit_per_sec` with the clearance cookie.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WaitingRoomConfig {
+    /// Protection level (0-4) from which the room opens by itself. Unset,
+    /// it only opens when turned on through the admin API.
+    #[serde(default = "default_waiting_room_level")]
+    pub activate_at_level: Option<u8>,
+    #[serde(default = "default_waiting_room_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Queue positions admitted per second.
+    #[serde(default = "default_waiting_room_admit_per_sec")]
+    pub admit_per_sec: f64,
+    /// How often the queue page reloads itself.
+    #[serde(default = "default_waiting_room_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+impl Default for WaitingRoomConfig {
+    fn default() -> Self {
+        Self {
+            activate_at_level: default_waiting_room_level(),
+            max_in_flight: default_waiting_room_max_in_flight(),
+            admit_per_sec: default_waiting_room_admit_per_sec(),
+            refresh_secs: default_waiting_room_refresh_secs(),
+        }
+    }
+}
+
+impl WaitingRoomConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.activate_at_level.is_some_and(|l| l > 4) {
+            return Err("waiting_room.activate_at_level must be between 0 and 4".to_string());
+        }
+        if !self.admit_per_sec.is_finite() || self.admit_per_sec <= 0.0 {
+            return Err("waiting_room.admit_per_sec must be greater than zero".to_string());
+        }
+        if !(1..=300).contains(&self.refresh_secs) {
+            return Err("waiting_room.refresh_secs must be between 1 and 300".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Active health check of a service's upstreams, its routes' included.
+/// Unset intervals, timeouts and thresholds are taken from the global
+/// `upstream.health_check`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceHealthCheck {
+    #[serde(default)]
+    pub probe: HealthProbe,
+    /// Path requested by `http` probes.
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    /// `Host` header of `http` probes; the upstream's address if unset.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Statuses an `http` probe may get back, codes (`401`) or inclusive
+    /// ranges (`"200-399"`).
+    #[serde(default = "default_health_check_expected_status")]
+    pub expected_status: Vec<StatusMatch>,
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Consecutive successes before an unhealthy upstream is back in rotation.
+    #[serde(default)]
+    pub healthy_threshold: Option<u32>,
+    /// Consecutive failures before an upstream is taken out of rotation.
+    #[serde(default)]
+    pub unhealthy_threshold: Option<u32>,
+}
+
+impl ServiceHealthCheck {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.probe == HealthProbe::Http {
+            if !self.path.starts_with('/') {
+                return Err("health_check.path must start with /".to_string());
+            }
+            if self.expected_status.is_empty() {
+                return Err("health_check.expected_status must not be empty".to_string());
+            }
+            if let Some(status) = self.expected_status.iter().find(|s| s.bounds().is_none()) {
+                return Err(format!("health_check.expected_status {} is not a status or range of statuses", status));
+            }
+        }
+        if self.interval_secs.is_some_and(|s| !(1..=3600).contains(&s)) {
+            return Err("health_check.interval_secs must be between 1 and 3600".to_string());
+        }
+        if self.timeout_ms == Some(0) {
+            return Err("health_check.timeout_ms must be greater than zero".to_string());
+        }
+        if self.healthy_threshold == Some(0) || self.unhealthy_threshold == Some(0) {
+            return Err("health_check thresholds must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a health check does with each upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthProbe {
+    /// Open a TCP connection, for backends that are not HTTP servers.
+    #[default]
+    Tcp,
+    /// `GET` the probe path and check the response status.
+    Http,
+}
+
+/// A status code or an inclusive range of them, such as `"200-399"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StatusMatch {
+    Code(u16),
+    Range(String),
+}
+
+impl StatusMatch {
+    /// Lowest and highest status matched; `None` when malformed.
+    fn bounds(&self) -> Option<(u16, u16)> {
+        let (low, high) = match self {
+            StatusMatch::Code(code) => (*code, *code),
+            StatusMatch::Range(range) => {
+                let (low, high) = range.split_once('-').unwrap_or((range, range));
+                (low.trim().parse().ok()?, high.trim().parse().ok()?)
+            }
+        };
+        ((100..=599).contains(&low) && (low..=599).contains(&high)).then_some((low, high))
+    }
+
+    pub fn matches(&self, status: u16) -> bool {
+        self.bounds().is_some_and(|(low, high)| (low..=high).contains(&status))
+    }
+}
+
+impl std::fmt::Display for StatusMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatusMatch::Code(code) => write!(f, "{}", code),
+            StatusMatch::Range(range) => write!(f, "{:?}", range),
+        }
+    }
+}
+
+/// How requests are spread across a service's upstreams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalanceStrategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+impl LoadBalanceStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoadBalanceStrategy::RoundRobin => "round_robin",
+            LoadBalanceStrategy::LeastConnections => "least_connections",
+        }
+    }
+
+    pub fn from_str_name(s: &str) -> Self {
+        match s {
+            "least_connections" => LoadBalanceStrategy::LeastConnections,
+            _ => LoadBalanceStrategy::RoundRobin,
+        }
+    }
+}
+
+/// How a service handles request bodies sent with a `Content-Encoding`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestEncodingPolicy {
+    /// Forward encoded bodies without looking inside them.
+    #[default]
+    Forward,
+    /// Answer encoded bodies with 415.
+    Reject,
+    /// Decode the body, within `protection.body_inspection`'s size cap and
+    /// expansion ratio, so the body rules see the decoded bytes. The
+    /// original bytes are forwarded.
+    Inspect,
+}
+
+impl RequestEncodingPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestEncodingPolicy::Forward => "forward",
+            RequestEncodingPolicy::Reject => "reject",
+            RequestEncodingPolicy::Inspect => "inspect",
+        }
+    }
+
+    pub fn from_str_name(s: &str) -> Self {
+        match s {
+            "reject" => RequestEncodingPolicy::Reject,
+            "inspect" => RequestEncodingPolicy::Inspect,
+            _ => RequestEncodingPolicy::Forward,
+        }
+    }
+}
+
+/// Deserialize either `"host:port"` or `["host:port", ...]` into a list.
+pub fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// Decode the `services.upstream_address` column, which holds either a bare
+/// address (legacy rows) or a JSON array of addresses.
+pub fn decode_upstreams(raw: &str) -> Vec<String> {
+    if raw.trim_start().starts_with('[') {
+        serde_json::from_str(raw).unwrap_or_default()
+    } else {
+        vec![raw.to_string()]
+    }
+}
+
+/// Split an upstream into its scheme and `host[:port]`. Bare addresses are
+/// plain HTTP.
+pub fn split_upstream(upstream: &str) -> (&'static str, &str) {
+    let upstream = upstream.trim().trim_end_matches('/');
+    if let Some(rest) = upstream.strip_prefix("https://") {
+        ("https", rest)
+    } else if let Some(rest) = upstream.strip_prefix("http://") {
+        ("http", rest)
+    } else {
+        ("http", upstream)
+    }
+}
+
+/// Base URL (`scheme://host[:port]`) requests to an upstream are sent to.
+pub fn upstream_base_url(upstream: &str) -> String {
+    let (scheme, authority) = split_upstream(upstream);
+    format!("{}://{}", scheme, authority)
+}
+
+/// `host:port` to open a TCP connection to, filling in the scheme's
+/// default port.
+pub fn upstream_socket_addr(upstream: &str) -> String {
+    let (scheme, authority) = split_upstream(upstream);
+    let has_port = match authority.rfind(']') {
+        Some(end) => authority[end..].contains(':'),
+        None => authority.contains(':'),
+    };
+    if has_port {
+        authority.to_string()
+    } else if scheme == "https" {
+        format!("{}:443", authority)
+    } else {
+        format!("{}:80", authority)
+    }
+}
+
+/// Encode upstreams for the `services.upstream_address` column. A single
+/// upstream is stored bare so older binaries can still read the row.
+pub fn encode_upstreams(upstreams: &[String]) -> String {
+    match upstreams {
+        [single] => single.clone(),
+        many => serde_json::to_string(many).unwrap_or_default(),
+    }
+}
+
+/// Encode a list or map (header rules, allowlists) for its `services` column.
+pub fn encode_json_column<T: Serialize>(rules: &T) -> Option<String> {
+    serde_json::to_string(rules).ok()
+}
+
+/// Decode a JSON `services` column; NULL or malformed values mean empty.
+pub fn decode_json_column<T: DeserializeOwned + Default>(raw: Option<&str>) -> T {
+    raw.and_then(|s| serde_json::from_str(s).ok()).unwrap_or_default()
+}
+
 fn default_enabled() -> bool { true }
+fn default_upstream_tls_verify() -> bool { true }
 fn default_rate_limit_multiplier() -> f64 { 1.0 }
 fn default_service_max_connections() -> usize { 10_000 }
 fn default_service_connect_timeout() -> u64 { 5_000 }
 fn default_service_response_timeout() -> u64 { 60_000 }
+fn default_waiting_room_level() -> Option<u8> { Some(4) }
+fn default_waiting_room_max_in_flight() -> usize { 500 }
+fn default_waiting_room_admit_per_sec() -> f64 { 10.0 }
+fn default_waiting_room_refresh_secs() -> u64 { 5 }
+fn default_health_check_path() -> String { "/".to_string() }
+fn default_health_check_expected_status() -> Vec<StatusMatch> { vec![StatusMatch::Range("200-399".to_string())] }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_addresses_get_scheme_and_default_port() {
+        let cases = [
+            ("web:8080", ("http", "web:8080"), "http://web:8080", "web:8080"),
+            ("https://web", ("https", "web"), "https://web", "web:443"),
+            ("https://[::1]", ("https", "[::1]"), "https://[::1]", "[::1]:443"),
+            ("https://[::1]:8443/", ("https", "[::1]:8443"), "https://[::1]:8443", "[::1]:8443"),
+            ("http://web/", ("http", "web"), "http://web", "web:80"),
+        ];
+        for (upstream, split, base, socket) in cases {
+            assert_eq!(split_upstream(upstream), split, "{}", upstream);
+            assert_eq!(upstream_base_url(upstream), base, "{}", upstream);
+            assert_eq!(upstream_socket_addr(upstream), socket, "{}", upstream);
+        }
+    }
+}
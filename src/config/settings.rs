@@ -1,8 +1,14 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
+use tracing::warn;
 
 use super::defaults;
+use crate::protection::challenge::ExemptPath;
+use crate::storage::ip_ranges::{parse_ip_or_cidr, IpRangeMap};
 
 /// Top-level configuration for the Fortress anti-DDoS proxy.
 /// Deserializes from a TOML configuration file.
@@ -68,17 +74,37 @@ pub struct Settings {
     #[serde(default = "defaults::default_cloudflare_config")]
     pub cloudflare: CloudflareConfig,
 
+    #[serde(default = "defaults::default_cluster_config")]
+    pub cluster: ClusterConfig,
+
+    #[serde(default = "defaults::default_cache_config")]
+    pub cache: CacheConfig,
+
+    #[serde(default = "defaults::default_metrics_config")]
+    pub metrics: MetricsConfig,
+
+    #[serde(default = "defaults::default_security_events_config")]
+    pub security_events: SecurityEventsConfig,
+
+    #[serde(default = "defaults::default_firewall_config")]
+    pub firewall: FirewallConfig,
+
     #[serde(default)]
     pub services: Vec<crate::config::service::ServiceConfig>,
 }
 
+/// Settings shared with components that must observe a config reload.
+pub type SharedSettings = Arc<ArcSwap<Settings>>;
+
 impl Settings {
     /// Load configuration from a TOML file at the given path.
     pub fn load(path: &str) -> Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path))?;
-        let settings: Settings = toml::from_str(&content)
+        let mut settings: Settings = toml::from_str(&content)
             .with_context(|| format!("Failed to parse config file: {}", path))?;
+        settings.protection.build_whitelist();
+        settings.geoip.build_trusted_proxies();
         Ok(settings)
     }
 }
@@ -106,6 +132,11 @@ impl Default for Settings {
             ip_reputation: defaults::default_ip_reputation_config(),
             auto_ban: defaults::default_auto_ban_config(),
             cloudflare: defaults::default_cloudflare_config(),
+            cluster: defaults::default_cluster_config(),
+            cache: defaults::default_cache_config(),
+            metrics: defaults::default_metrics_config(),
+            security_events: defaults::default_security_events_config(),
+            firewall: defaults::default_firewall_config(),
             services: Vec::new(),
         }
     }
@@ -114,6 +145,10 @@ impl Default for Settings {
 /// HTTP/HTTPS server configuration.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig {
+    /// Which listeners serve traffic; see [`ServerMode`]. Applied at startup.
+    #[serde(default = "defaults::default_server_mode")]
+    pub mode: ServerMode,
+
     #[serde(default = "defaults::default_bind_http")]
     pub bind_http: String,
 
@@ -134,6 +169,77 @@ pub struct ServerConfig {
 
     #[serde(default = "defaults::default_keepalive_timeout_secs")]
     pub keepalive_timeout_secs: u64,
+
+    /// Largest request body (MiB) streamed to an upstream; larger ones get
+    /// a 413. Services can override it. 0 means unlimited.
+    #[serde(default = "defaults::default_max_body_size_mb")]
+    pub max_body_size_mb: u64,
+
+    /// Seconds a client gets to send the complete request head. Connections
+    /// that trickle headers past this are dropped as slowloris.
+    #[serde(default = "defaults::default_header_timeout_secs")]
+    pub header_timeout_secs: u64,
+
+    /// Connections the HTTP -> HTTPS redirect listener serves at once;
+    /// more are closed on accept. Only used with `mode = "https"`.
+    #[serde(default = "defaults::default_max_redirect_connections")]
+    pub max_redirect_connections: usize,
+
+    /// Minimum average upload rate for request bodies once the grace
+    /// period has passed. 0 disables the check.
+    #[serde(default = "defaults::default_min_body_rate_bytes_per_sec")]
+    pub min_body_rate_bytes_per_sec: u64,
+
+    #[serde(default = "defaults::default_body_rate_grace_secs")]
+    pub body_rate_grace_secs: u64,
+
+    #[serde(default = "defaults::default_probe_config")]
+    pub probes: ProbeConfig,
+}
+
+/// `/__fortress/healthz` and `/__fortress/readyz` for load balancers,
+/// answered on every listener before service resolution and the pipeline.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProbeConfig {
+    #[serde(default = "defaults::default_probes_enabled")]
+    pub enabled: bool,
+
+    /// `readyz` also requires at least one service upstream to pass its
+    /// health checks.
+    #[serde(default)]
+    pub require_healthy_upstream: bool,
+
+    /// Write probe requests to the access log.
+    #[serde(default)]
+    pub access_log: bool,
+
+    /// Count probe requests in the request metrics.
+    #[serde(default)]
+    pub metrics: bool,
+}
+
+/// Listeners the proxy serves traffic on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerMode {
+    /// HTTPS on `bind_https`; `bind_http` only redirects to HTTPS.
+    #[default]
+    Https,
+    /// Plain HTTP on `bind_http`, for deployments behind another TLS
+    /// terminator. No HTTPS listener and no JA3 fingerprints.
+    Http,
+    /// Both listeners serve traffic.
+    Both,
+}
+
+impl ServerMode {
+    pub fn serves_https(self) -> bool {
+        self != ServerMode::Http
+    }
+
+    pub fn serves_plain_http(self) -> bool {
+        self != ServerMode::Https
+    }
 }
 
 /// TLS configuration.
@@ -160,6 +266,88 @@ pub struct UpstreamConfig {
 
     #[serde(default = "defaults::default_response_timeout_ms")]
     pub response_timeout_ms: u64,
+
+    /// Times a request that failed on a connection-level error (refused,
+    /// reset or closed before the response) is retried, within what is left
+    /// of the response timeout. Only `retry_methods` without a streamed
+    /// body are retried.
+    #[serde(default = "defaults::default_upstream_retries")]
+    pub retries: u32,
+
+    /// Wait before the first retry, doubled for each further one.
+    #[serde(default = "defaults::default_upstream_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+
+    #[serde(default = "defaults::default_upstream_retry_methods")]
+    pub retry_methods: Vec<String>,
+
+    #[serde(default = "defaults::default_health_check_config")]
+    pub health_check: HealthCheckConfig,
+
+    #[serde(default = "defaults::default_circuit_breaker_config")]
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+/// Per-service circuit breaker for upstream requests.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed upstream requests (errors or response timeouts)
+    /// that open a service's circuit. 0 disables the breaker.
+    #[serde(default = "defaults::default_circuit_failure_threshold")]
+    pub failure_threshold: u32,
+
+    /// Seconds an open circuit fails requests fast before letting a single
+    /// probe request through.
+    #[serde(default = "defaults::default_circuit_open_secs")]
+    pub open_secs: u64,
+}
+
+/// Active health checks of service upstreams: a TCP connect unless the
+/// service sets its own `health_check`, whose unset fields default to these.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "defaults::default_health_check_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "defaults::default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// Consecutive successful checks before an unhealthy upstream is put
+    /// back into rotation.
+    #[serde(default = "defaults::default_health_check_healthy_threshold")]
+    pub healthy_threshold: u32,
+
+    /// Consecutive failed checks before an upstream is taken out of
+    /// rotation.
+    #[serde(default = "defaults::default_health_check_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+
+    /// `fail_fast` (503, the maintenance page) or `try_anyway`.
+    #[serde(default = "defaults::default_health_check_when_unhealthy")]
+    pub when_unhealthy: WhenUnhealthy,
+}
+
+/// What to do with a request for a service whose upstreams are all down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhenUnhealthy {
+    /// Answer 503 with the maintenance page.
+    #[default]
+    FailFast,
+    /// Forward it to one of the failing upstreams anyway.
+    TryAnyway,
+}
+
+impl UpstreamConfig {
+    pub fn may_retry(&self, method: &str) -> bool {
+        self.retry_methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn fail_fast(&self) -> bool {
+        self.when_unhealthy == WhenUnhealthy::FailFast
+    }
 }
 
 /// Admin API configuration.
@@ -170,6 +358,34 @@ pub struct AdminApiConfig {
 
     #[serde(default = "defaults::default_api_key")]
     pub api_key: String,
+
+    /// Optional key that may only make `GET`/`HEAD` requests, for dashboards
+    /// that should not be able to change state.
+    #[serde(default)]
+    pub read_only_api_key: Option<String>,
+
+    /// Optional bearer token for `GET /metrics`. When unset the Prometheus
+    /// endpoint is served without authentication.
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+
+    /// Serve `GET /api/fortress/public/status` without authentication, for
+    /// status pages. Only the level, attack state, RPS and totals are exposed.
+    #[serde(default)]
+    pub public_status_enabled: bool,
+
+    /// PEM certificate chain and private key; when both are set the admin
+    /// API is served over HTTPS.
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    #[serde(default)]
+    pub tls_key: Option<String>,
+
+    /// PEM CA certificates. When set only clients presenting a certificate
+    /// signed by one of them can connect, and `api_key` may be left empty
+    /// to let the certificate alone authenticate as admin.
+    #[serde(default)]
+    pub client_ca: Option<String>,
 }
 
 /// GeoIP database configuration.
@@ -180,6 +396,64 @@ pub struct GeoipConfig {
 
     #[serde(default = "defaults::default_asn_db")]
     pub asn_db: String,
+
+    /// How often to check the database files for updates and reload them.
+    /// 0 disables polling; `POST /api/fortress/geoip/reload` still works.
+    #[serde(default = "defaults::default_geoip_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+
+    /// Lookup results kept in memory; 0 disables the cache.
+    #[serde(default = "defaults::default_geoip_cache_capacity")]
+    pub cache_capacity: usize,
+
+    #[serde(default = "defaults::default_geoip_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+
+    /// `subnet` (IPv4 /24, IPv6 /48) or `ip`. Per-IP keys churn under IPv6
+    /// privacy addresses.
+    #[serde(default = "defaults::default_geoip_cache_key")]
+    pub cache_key: String,
+
+    /// Header carrying the client's country (e.g. `CF-IPCountry` or
+    /// `X-Geo-Country`), used when GeoIP has no answer for the client.
+    /// Only trusted from `trusted_proxies`, whether or not Cloudflare mode
+    /// is on. Empty disables the fallback.
+    #[serde(default)]
+    pub country_header: String,
+
+    /// IPs and CIDR ranges of the proxies whose `country_header` is trusted.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// `trusted_proxies` compiled for lookup.
+    #[serde(skip)]
+    pub trusted_proxy_ranges: IpRangeMap<()>,
+}
+
+impl GeoipConfig {
+    /// Compile `trusted_proxies` into [`GeoipConfig::trusted_proxy_ranges`].
+    /// Entries that fail to parse are skipped with a warning.
+    pub fn build_trusted_proxies(&mut self) {
+        let mut ranges = IpRangeMap::new();
+        for entry in &self.trusted_proxies {
+            match parse_ip_or_cidr(entry) {
+                Some(net) => ranges.insert(net, ()),
+                None => warn!("Ignoring invalid geoip.trusted_proxies entry: {}", entry),
+            }
+        }
+        self.trusted_proxy_ranges = ranges;
+    }
+
+    /// The country named by `country_header`, if the request came from a
+    /// trusted proxy and the header holds a country code.
+    pub fn fallback_country(&self, peer: std::net::IpAddr, headers: &std::collections::HashMap<String, String>) -> Option<String> {
+        if self.country_header.is_empty() || !self.trusted_proxy_ranges.contains(&peer) {
+            return None;
+        }
+        let value = headers.get(&self.country_header.to_ascii_lowercase())?.trim();
+        let valid = value.len() == 2 && value.bytes().all(|b| b.is_ascii_alphabetic()) && !value.eq_ignore_ascii_case("XX");
+        valid.then(|| value.to_ascii_uppercase())
+    }
 }
 
 /// Protection configuration with nested rate-limit levels.
@@ -197,11 +471,244 @@ pub struct ProtectionConfig {
     #[serde(default = "defaults::default_ipv4_subnet_mask")]
     pub ipv4_subnet_mask: u8,
 
+    /// Prefix length IPv6 clients are grouped by for subnet rate limits,
+    /// subnet ban alerts and `challenge.cookie_subnet_binding`.
+    #[serde(default = "defaults::default_ipv6_subnet_mask")]
+    pub ipv6_subnet_mask: u8,
+
+    /// Most client IPs with their own rate-limit window. IPs seen once the
+    /// cap is reached are only counted against their subnet, ASN and
+    /// country until idle entries are evicted.
+    #[serde(default = "defaults::default_max_tracked_ips")]
+    pub max_tracked_ips: usize,
+
+    /// Most subnets (`ipv4_subnet_mask` / `ipv6_subnet_mask`) with a window.
+    #[serde(default = "defaults::default_max_tracked_subnets")]
+    pub max_tracked_subnets: usize,
+
+    /// Most ASNs with a window.
+    #[serde(default = "defaults::default_max_tracked_asns")]
+    pub max_tracked_asns: usize,
+
+    /// Let CORS preflights (`OPTIONS`) skip challenges and scoring. They
+    /// are still subject to the blocklist, auto-ban and rate limits.
+    #[serde(default = "defaults::default_exempt_cors_preflight")]
+    pub exempt_cors_preflight: bool,
+
+    #[serde(default = "defaults::default_tarpit_config")]
+    pub tarpit: TarpitConfig,
+
+    #[serde(default = "defaults::default_distributed_mitigation_config")]
+    pub distributed_mitigation: DistributedMitigationConfig,
+
+    #[serde(default = "defaults::default_body_inspection_config")]
+    pub body_inspection: BodyInspectionConfig,
+
+    #[serde(default = "defaults::default_credential_stuffing_config")]
+    pub credential_stuffing: CredentialStuffingConfig,
+
+    /// Per-IP cap on the bytes/s written to a client across all of its
+    /// connections, WebSocket relays included; `0` disables it.
+    /// Whitelisted IPs are exempt.
+    #[serde(default)]
+    pub max_bytes_per_ip_per_sec: u64,
+
+    /// What happens to an IP over `max_bytes_per_ip_per_sec`.
+    #[serde(default)]
+    pub bandwidth_cap_action: BandwidthCapAction,
+
     #[serde(default)]
     pub whitelisted_ips: Vec<String>,
 
+    /// IPs and CIDR ranges (IPv4 or IPv6) that bypass the pipeline.
     #[serde(default)]
     pub whitelisted_subnets: Vec<String>,
+
+    /// `whitelisted_ips` and `whitelisted_subnets` compiled for lookup.
+    #[serde(skip)]
+    pub whitelist: IpRangeMap<()>,
+}
+
+impl ProtectionConfig {
+    /// Compile the whitelist entries into [`ProtectionConfig::whitelist`].
+    /// Entries that fail to parse are skipped with a warning.
+    pub fn build_whitelist(&mut self) {
+        let mut whitelist = IpRangeMap::new();
+        for entry in self.whitelisted_ips.iter().chain(&self.whitelisted_subnets) {
+            match parse_ip_or_cidr(entry) {
+                Some(net) => whitelist.insert(net, ()),
+                None => warn!("Ignoring invalid whitelist entry: {}", entry),
+            }
+        }
+        self.whitelist = whitelist;
+    }
+}
+
+/// Enforcement of `protection.max_bytes_per_ip_per_sec`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandwidthCapAction {
+    /// Response bodies are written no faster than the cap allows; the
+    /// budget is shared by all of the IP's connections.
+    #[default]
+    Throttle,
+    /// New requests get a 429 while the IP's rolling rate is over the cap.
+    Reject,
+}
+
+/// How tarpitted requests are answered: a 403 whose body trickles out one
+/// byte per `drip_interval_ms` for `delay_secs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TarpitConfig {
+    #[serde(default = "defaults::default_tarpit_delay_secs")]
+    pub delay_secs: u64,
+
+    /// Applied at startup; the drip ticker is shared by all responses.
+    #[serde(default = "defaults::default_tarpit_drip_interval_ms")]
+    pub drip_interval_ms: u64,
+
+    /// Tarpitted responses held open at once. Beyond this, tarpitted
+    /// requests get an empty 403 and the connection is closed.
+    #[serde(default = "defaults::default_tarpit_max_concurrent")]
+    pub max_concurrent: usize,
+}
+
+impl TarpitConfig {
+    /// Bytes dripped per response: one per interval over the delay.
+    pub fn drip_bytes(&self) -> u64 {
+        (self.delay_secs * 1000 / self.drip_interval_ms.max(1)).max(1)
+    }
+}
+
+/// Targeted mitigation of the hottest path while a distributed attack is
+/// detected: requests for it (and its method, when one dominates) are
+/// challenged or blocked before scoring, the rest of the site is not.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DistributedMitigationConfig {
+    #[serde(default = "defaults::default_distributed_mitigation_enabled")]
+    pub enabled: bool,
+
+    /// `challenge` or `block`.
+    #[serde(default = "defaults::default_escalation_action")]
+    pub action: String,
+
+    /// Share of the window's requests the hottest path must draw for a
+    /// mitigation to be created.
+    #[serde(default = "defaults::default_distributed_mitigation_min_path_share")]
+    pub min_path_share: f64,
+
+    /// How long a mitigation outlives the last request that still showed
+    /// the attack.
+    #[serde(default = "defaults::default_distributed_mitigation_hold_secs")]
+    pub hold_secs: u64,
+}
+
+/// Detection of credential stuffing: many IPs, or many attempts, against
+/// the same username on a login path, regardless of the per-IP rate.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CredentialStuffingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Path globs of the login endpoints. Only their `POST` form and JSON
+    /// bodies are read, up to `body_inspection.max_bytes`.
+    #[serde(default = "defaults::default_credential_stuffing_login_paths")]
+    pub login_paths: Vec<String>,
+
+    /// Form field or top-level JSON key holding the username; services
+    /// can override it with `login_username_field`.
+    #[serde(default = "defaults::default_credential_stuffing_username_field")]
+    pub username_field: String,
+
+    #[serde(default = "defaults::default_credential_stuffing_window_secs")]
+    pub window_secs: u64,
+
+    /// A username attempted from more distinct IPs than this within the
+    /// window is under attack.
+    #[serde(default = "defaults::default_credential_stuffing_max_ips")]
+    pub max_ips_per_username: usize,
+
+    /// ... as is one attempted more often than this, from any IPs.
+    #[serde(default = "defaults::default_credential_stuffing_max_attempts")]
+    pub max_attempts_per_username: usize,
+
+    /// `challenge` or `block` the attempts on a username under attack.
+    #[serde(default = "defaults::default_escalation_action")]
+    pub action: String,
+
+    /// Most usernames tracked at once; attempts on further usernames are
+    /// not counted until idle ones expire.
+    #[serde(default = "defaults::default_credential_stuffing_max_tracked")]
+    pub max_tracked_usernames: usize,
+}
+
+impl CredentialStuffingConfig {
+    /// Whether a `method` request to `path` is a login attempt to track.
+    pub fn applies(&self, method: &str, path: &str) -> bool {
+        self.enabled
+            && method == "POST"
+            && self
+                .login_paths
+                .iter()
+                .any(|p| crate::protection::challenge::glob_match(p, path))
+    }
+}
+
+/// Limits and exemptions for request body inspection, which services opt
+/// into with `body_inspection = true`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BodyInspectionConfig {
+    /// Only the first `max_bytes` of a body are inspected.
+    #[serde(default = "defaults::default_body_inspection_max_bytes")]
+    pub max_bytes: usize,
+
+    /// Give up on a body that has not delivered `max_bytes` (or ended) in
+    /// this time; the request is answered with 408.
+    #[serde(default = "defaults::default_body_inspection_read_timeout_secs")]
+    pub read_timeout_secs: u64,
+
+    /// Media types (without parameters) whose bodies are inspected.
+    #[serde(default = "defaults::default_body_inspection_content_types")]
+    pub content_types: Vec<String>,
+
+    /// Media types never inspected, even if listed in `content_types`.
+    #[serde(default)]
+    pub exempt_content_types: Vec<String>,
+
+    /// Paths (`*` wildcards) whose bodies are never inspected, e.g. a CMS
+    /// editor that legitimately posts HTML.
+    #[serde(default)]
+    pub exempt_paths: Vec<String>,
+
+    /// Services with `request_encoding = "inspect"` abort decoding a body
+    /// past this many decoded bytes, answering 413.
+    #[serde(default = "defaults::default_body_inspection_max_decompressed_bytes")]
+    pub max_decompressed_bytes: usize,
+
+    /// ... or past this multiple of the encoded size.
+    #[serde(default = "defaults::default_body_inspection_max_expansion_ratio")]
+    pub max_expansion_ratio: f64,
+}
+
+impl BodyInspectionConfig {
+    /// Whether a `method` request to `path` with `content_type` should have
+    /// its body inspected.
+    pub fn applies(&self, method: &str, path: &str, content_type: Option<&str>) -> bool {
+        if !matches!(method, "POST" | "PUT" | "PATCH") {
+            return false;
+        }
+        let Some(content_type) = content_type else {
+            return false;
+        };
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        let listed = |types: &[String]| types.iter().any(|t| t.eq_ignore_ascii_case(media_type));
+        listed(&self.content_types)
+            && !listed(&self.exempt_content_types)
+            && !self
+                .exempt_paths
+                .iter()
+                .any(|p| crate::protection::challenge::glob_match(p, path))
+    }
 }
 
 /// Rate-limit thresholds for each protection level.
@@ -239,6 +746,10 @@ pub struct RateLimitConfig {
 /// Challenge (proof-of-work) configuration.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ChallengeConfig {
+    /// Which challenge page is served, see [`ChallengeMode`].
+    #[serde(default)]
+    pub mode: ChallengeMode,
+
     #[serde(default = "defaults::default_pow_difficulty_l1")]
     pub pow_difficulty_l1: u8,
 
@@ -257,14 +768,58 @@ pub struct ChallengeConfig {
     #[serde(default = "defaults::default_hmac_secret")]
     pub hmac_secret: String,
 
+    /// Paths never challenged (`*` wildcards), as strings or as
+    /// `{ path, methods }` to exempt only some methods. Services add their
+    /// own `exempt_paths`.
     #[serde(default)]
-    pub exempt_paths: Vec<String>,
+    pub exempt_paths: Vec<ExemptPath>,
 
     #[serde(default)]
     pub cookie_subnet_binding: bool,
 
     #[serde(default)]
     pub nojs_fallback_enabled: bool,
+
+    /// Nojs verification attempts allowed per IP per minute; further
+    /// attempts fail without being checked. 0 disables the limit.
+    #[serde(default = "defaults::default_nojs_max_attempts_per_minute")]
+    pub nojs_max_attempts_per_minute: u64,
+
+    /// Challenges an IP may be served without solving one inside
+    /// `unanswered_window_secs` before it gets `challenge_flood_action`
+    /// instead of another challenge page. 0 disables the limit.
+    #[serde(default = "defaults::default_max_unanswered_challenges")]
+    pub max_unanswered_challenges: u64,
+
+    #[serde(default = "defaults::default_unanswered_window_secs")]
+    pub unanswered_window_secs: u64,
+
+    /// `"block"` (plain 403) or `"tarpit"`.
+    #[serde(default = "defaults::default_challenge_flood_action")]
+    pub challenge_flood_action: String,
+
+    /// Clients with a valid clearance cookie skip the scoring layers. When
+    /// set, their rate limits are also raised by
+    /// `cleared_rate_limit_multiplier`; otherwise the normal limits apply.
+    #[serde(default = "defaults::default_clearance_relaxes_rate_limits")]
+    pub clearance_relaxes_rate_limits: bool,
+
+    #[serde(default = "defaults::default_cleared_rate_limit_multiplier")]
+    pub cleared_rate_limit_multiplier: f64,
+}
+
+/// Kind of challenge page served to suspicious clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMode {
+    /// The browser solves a SHA-256 proof of work.
+    #[default]
+    Pow,
+    /// A "verify you are human" button that posts a signed, single-use
+    /// token. No hashing, so cheap for low-end phones.
+    Interactive,
+    /// Interactive at L0-L1, proof of work from L2 up.
+    Auto,
 }
 
 /// Blocklist configuration for countries, ASNs, and IPs.
@@ -281,22 +836,106 @@ pub struct BlocklistConfig {
 
     #[serde(default = "defaults::default_country_challenge_score")]
     pub country_challenge_score: f64,
+
+    /// Allowlist mode: when either list is non-empty, only requests from a
+    /// listed country or ASN get through; everything else receives
+    /// `allowlist_action`. Services can replace these with their own lists.
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+
+    #[serde(default)]
+    pub allowed_asns: Vec<u32>,
+
+    /// `"block"` or `"challenge"` for requests outside the allowlist.
+    #[serde(default = "defaults::default_allowlist_action")]
+    pub allowlist_action: String,
+
+    /// `"allow"`, `"block"` or `"challenge"` when the allowlist is active
+    /// but the client's country/ASN is unknown (GeoIP miss).
+    #[serde(default = "defaults::default_allowlist_unknown_action")]
+    pub allowlist_unknown_action: String,
+
+    /// External IP/CIDR feeds pulled in the background.
+    #[serde(default)]
+    pub feeds: Vec<BlocklistFeedConfig>,
+}
+
+/// An external blocklist feed (plain text, one IP or CIDR per line).
+/// Entries are stored with source `feed:<name>` and kept in sync with the
+/// feed on every refresh.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlocklistFeedConfig {
+    pub name: String,
+    pub url: String,
+
+    #[serde(default = "defaults::default_feed_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+
+    #[serde(default = "defaults::default_feed_enabled")]
+    pub enabled: bool,
+
+    /// Reason stored on entries from this feed. Defaults to `feed:<name>`.
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 /// Behavioral analysis configuration.
+///
+/// A client's score (0-1) sums four signals, each scaled by its weight:
+/// regular request timing (0.3, or 0.1 when only near-regular), a high
+/// request rate (0.3, or 0.15 when elevated), hammering few paths (0.1)
+/// and JA3/User-Agent changes (0.1 each, at most 0.25).
 #[derive(Debug, Clone, Deserialize)]
 pub struct BehavioralConfig {
+    /// A profile idle for this long is forgotten and starts over.
     #[serde(default = "defaults::default_scoring_window_secs")]
     pub scoring_window_secs: u64,
 
+    /// Profiles kept at most; past it the least recently seen are evicted.
     #[serde(default = "defaults::default_max_profiles")]
     pub max_profiles: usize,
 
+    /// Request intervals kept per profile for the regularity signal.
+    #[serde(default = "defaults::default_interval_history")]
+    pub interval_history: usize,
+
     #[serde(default = "defaults::default_regularity_weight")]
     pub regularity_weight: f64,
 
+    /// Coefficient of variation of the intervals below which timing counts
+    /// as regular, and as near-regular.
+    #[serde(default = "defaults::default_regular_cv")]
+    pub regular_cv: f64,
+
+    #[serde(default = "defaults::default_near_regular_cv")]
+    pub near_regular_cv: f64,
+
+    #[serde(default = "defaults::default_rate_weight")]
+    pub rate_weight: f64,
+
+    /// Requests a profile needs before its average rate is scored.
+    #[serde(default = "defaults::default_rate_min_requests")]
+    pub rate_min_requests: u64,
+
+    #[serde(default = "defaults::default_high_rps")]
+    pub high_rps: f64,
+
+    #[serde(default = "defaults::default_elevated_rps")]
+    pub elevated_rps: f64,
+
+    #[serde(default = "defaults::default_path_diversity_weight")]
+    pub path_diversity_weight: f64,
+
+    /// Requests after which a client that has visited at most
+    /// `path_diversity_max_paths` distinct paths is suspicious.
     #[serde(default = "defaults::default_path_diversity_min_requests")]
     pub path_diversity_min_requests: u64,
+
+    #[serde(default = "defaults::default_path_diversity_max_paths")]
+    pub path_diversity_max_paths: usize,
+
+    #[serde(default = "defaults::default_consistency_weight")]
+    pub consistency_weight: f64,
 }
 
 /// Automatic escalation/de-escalation configuration.
@@ -325,6 +964,37 @@ pub struct EscalationConfig {
 
     #[serde(default = "defaults::default_block_ratio_threshold")]
     pub block_ratio_threshold: f64,
+
+    /// Give busy services a level of their own instead of one global level.
+    #[serde(default)]
+    pub per_service: bool,
+
+    /// RPS a service needs before it is escalated independently; quieter
+    /// services follow the global level.
+    #[serde(default = "defaults::default_per_service_min_rps")]
+    pub per_service_min_rps: u64,
+
+    /// The global level is saved whenever it changes and restored at
+    /// startup if it changed less than this long ago. Pinned levels are
+    /// always restored.
+    #[serde(default = "defaults::default_restore_max_age_secs")]
+    pub restore_max_age_secs: u64,
+
+    /// Escalate while the average upstream response time stays above this
+    /// many milliseconds, up to L3. 0 disables.
+    #[serde(default)]
+    pub latency_escalation_ms: u64,
+
+    /// Escalate while this fraction of upstream requests fail (timeouts,
+    /// connection errors and 5xx), up to L3. 0 disables.
+    #[serde(default)]
+    pub error_ratio_escalation: f64,
+
+    /// Count static assets, exempt CORS preflights and whitelisted clients
+    /// towards the RPS thresholds. Turn off to keep thresholds tuned on
+    /// the traffic the pipeline inspects.
+    #[serde(default = "defaults::default_count_bypassed_requests")]
+    pub count_bypassed_requests: bool,
 }
 
 /// Logging configuration.
@@ -338,6 +1008,28 @@ pub struct LoggingConfig {
 
     #[serde(default = "defaults::default_access_log")]
     pub access_log: String,
+
+    /// `"json"` (one object per line) or `"combined"` (Apache/nginx).
+    #[serde(default = "defaults::default_access_log_format")]
+    pub access_log_format: String,
+
+    /// Rotate the access log once it reaches this size. 0 disables
+    /// rotation (e.g. when logrotate handles it; send SIGUSR1 afterwards).
+    #[serde(default)]
+    pub access_log_max_size_mb: u64,
+
+    /// Rotated files kept as `access.log.1` .. `access.log.N`.
+    #[serde(default = "defaults::default_access_log_max_files")]
+    pub access_log_max_files: usize,
+
+    /// Clients that may stream `GET /api/fortress/logs/stream` at once.
+    #[serde(default = "defaults::default_live_tail_max_clients")]
+    pub live_tail_max_clients: usize,
+
+    /// Entries a streaming client may fall behind before it skips ahead.
+    /// Read at startup.
+    #[serde(default = "defaults::default_live_tail_buffer")]
+    pub live_tail_buffer: usize,
 }
 
 /// Storage configuration.
@@ -345,6 +1037,28 @@ pub struct LoggingConfig {
 pub struct StorageConfig {
     #[serde(default = "defaults::default_sqlite_path")]
     pub sqlite_path: String,
+
+    /// Hours of minute-level request counts kept in `metrics_minutely`.
+    #[serde(default = "defaults::default_metrics_minutely_retention_hours")]
+    pub metrics_minutely_retention_hours: u64,
+
+    /// Fraction of requests (0.0 - 1.0) kept in `request_samples` for
+    /// per-IP drilldown and historical top lists. 0 disables sampling.
+    #[serde(default)]
+    pub request_sample_rate: f64,
+
+    /// Hours sampled requests are kept.
+    #[serde(default = "defaults::default_request_sample_retention_hours")]
+    pub request_sample_retention_hours: u64,
+
+    /// Memory all request captures (`POST /api/fortress/capture`) may hold
+    /// together; matching requests beyond it are counted but not kept.
+    #[serde(default = "defaults::default_capture_memory_bytes")]
+    pub capture_memory_bytes: usize,
+
+    /// Seconds a finished capture is kept for download before it is dropped.
+    #[serde(default = "defaults::default_capture_retention_secs")]
+    pub capture_retention_secs: u64,
 }
 
 /// L4 (TCP-level) protection configuration.
@@ -362,11 +1076,32 @@ pub struct L4ProtectionConfig {
     #[serde(default = "defaults::default_max_concurrent")]
     pub max_concurrent_per_ip: u64,
 
+    /// Limits for all addresses in a subnet together, grouped by
+    /// `protection.ipv4_subnet_mask` / `ipv6_subnet_mask` (0 disables).
+    #[serde(default = "defaults::default_max_concurrent_per_subnet")]
+    pub max_concurrent_per_subnet: u64,
+
+    #[serde(default = "defaults::default_subnet_conn_rate")]
+    pub connection_rate_per_subnet_per_sec: u64,
+
     #[serde(default = "defaults::default_tarpit_enabled")]
     pub tarpit_enabled: bool,
 
     #[serde(default = "defaults::default_tarpit_delay")]
     pub tarpit_delay_ms: u64,
+
+    /// Failed or timed-out TLS handshakes from one IP within
+    /// `handshake_failure_window_secs` after which its connections are
+    /// refused for `handshake_failure_cooldown_secs` (0 disables).
+    /// Connections closed before sending a ClientHello are not counted.
+    #[serde(default = "defaults::default_handshake_failure_threshold")]
+    pub handshake_failure_threshold: u64,
+
+    #[serde(default = "defaults::default_handshake_failure_window_secs")]
+    pub handshake_failure_window_secs: u64,
+
+    #[serde(default = "defaults::default_handshake_failure_cooldown_secs")]
+    pub handshake_failure_cooldown_secs: u64,
 }
 
 /// Alerting configuration (webhook notifications).
@@ -375,8 +1110,50 @@ pub struct AlertingConfig {
     #[serde(default)]
     pub enabled: bool,
 
+    /// Shorthand for a single `webhook` channel.
     #[serde(default)]
     pub webhook_url: Option<String>,
+
+    #[serde(default)]
+    pub channels: Vec<AlertChannelConfig>,
+
+    /// Minimum time between two alerts with the same dedup key (e.g. the
+    /// same upstream going down).
+    #[serde(default = "defaults::default_alert_cooldown_secs")]
+    pub cooldown_secs: u64,
+
+    /// Alert when a TLS certificate expires within this many days. 0
+    /// disables the check.
+    #[serde(default = "defaults::default_alert_cert_expiry_days")]
+    pub cert_expiry_days: u64,
+
+    /// Alert once this many IPs of one /24 (IPv4) or /48 (IPv6) are
+    /// auto-banned at the same time.
+    #[serde(default = "defaults::default_alert_subnet_ban_threshold")]
+    pub subnet_ban_threshold: u32,
+}
+
+/// One alert destination in `[[alerting.channels]]`.
+///
+/// `type` is `webhook` (generic JSON POST to `url`), `slack` (incoming
+/// webhook `url`) or `telegram` (`bot_token` and `chat_id`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertChannelConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    #[serde(default)]
+    pub url: Option<String>,
+
+    #[serde(default)]
+    pub bot_token: Option<String>,
+
+    #[serde(default)]
+    pub chat_id: Option<String>,
+
+    /// `info`, `warning` or `critical`; lower-severity alerts are skipped.
+    #[serde(default = "defaults::default_alert_min_severity")]
+    pub min_severity: String,
 }
 
 /// Bot whitelist configuration for known search engine crawlers.
@@ -385,8 +1162,49 @@ pub struct BotWhitelistConfig {
     #[serde(default = "defaults::default_bot_whitelist_enabled")]
     pub enabled: bool,
 
+    /// Require a published range or forward-confirmed reverse DNS before
+    /// trusting a crawler user agent.
     #[serde(default = "defaults::default_bot_verify_ip")]
     pub verify_ip: bool,
+
+    /// Replaces the built-in list (`src/config/crawlers.json`) when set.
+    #[serde(default = "defaults::default_crawlers")]
+    pub crawlers: Vec<CrawlerConfig>,
+
+    /// How long a reverse-DNS verdict is kept per IP.
+    #[serde(default = "defaults::default_bot_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+
+    /// Limit for one verification (reverse lookup plus forward confirm).
+    #[serde(default = "defaults::default_bot_dns_timeout_ms")]
+    pub dns_timeout_ms: u64,
+
+    /// Verifications allowed in flight at once; further crawler requests
+    /// stay pending until a slot frees up.
+    #[serde(default = "defaults::default_bot_max_pending_lookups")]
+    pub max_pending_lookups: usize,
+
+    /// Score added to a crawler request while its IP is being verified
+    /// (managed rule 12).
+    #[serde(default = "defaults::default_bot_pending_score")]
+    pub pending_score: f64,
+}
+
+/// One crawler the bot whitelist can verify.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CrawlerConfig {
+    pub name: String,
+
+    /// Case-insensitive User-Agent substrings that claim this crawler.
+    pub user_agents: Vec<String>,
+
+    /// Domains the crawler's reverse DNS names end in, e.g. `googlebot.com`.
+    #[serde(default)]
+    pub rdns_suffixes: Vec<String>,
+
+    /// Published CIDR ranges, trusted without a DNS lookup.
+    #[serde(default)]
+    pub ip_ranges: Vec<String>,
 }
 
 /// Mobile proxy detection tuning.
@@ -410,6 +1228,15 @@ pub struct AsnScoringConfig {
 
     #[serde(default = "defaults::default_residential_proxy_score")]
     pub residential_proxy_score: f64,
+
+    /// CSV of `asn,category` lines replacing the built-in ASN lists: an
+    /// `http(s)://` URL or a local file path. Empty keeps the built-in lists,
+    /// which also stay in use until the first successful load.
+    #[serde(default)]
+    pub dataset: String,
+
+    #[serde(default = "defaults::default_asn_dataset_refresh_secs")]
+    pub dataset_refresh_secs: u64,
 }
 
 /// IP reputation system configuration.
@@ -435,6 +1262,51 @@ pub struct IpReputationConfig {
 
     #[serde(default = "defaults::default_high_reputation_score")]
     pub high_reputation_score: f64,
+
+    /// Added for an IP listed by one of the `proxy_feeds`.
+    #[serde(default = "defaults::default_proxy_score")]
+    pub proxy_score: f64,
+
+    /// Days after its last occurrence that a category (Scanner,
+    /// BruteForce, ...) is dropped from an IP. 0 keeps categories for as
+    /// long as the IP is tracked.
+    #[serde(default = "defaults::default_category_decay_days")]
+    pub category_decay_days: u64,
+
+    /// Tor exit list, one IP per line, re-downloaded every
+    /// `feed_refresh_secs`. Until the first download succeeds a built-in
+    /// sample is used.
+    #[serde(default = "defaults::default_tor_exit_list_url")]
+    pub tor_exit_list_url: String,
+
+    /// Read instead of `tor_exit_list_url` when `offline` is set.
+    #[serde(default)]
+    pub tor_exit_list_path: Option<String>,
+
+    /// Open proxy / VPN lists (IPs or CIDRs, one per line).
+    #[serde(default)]
+    pub proxy_feeds: Vec<ReputationFeedConfig>,
+
+    #[serde(default = "defaults::default_reputation_feed_refresh_secs")]
+    pub feed_refresh_secs: u64,
+
+    /// For air-gapped deployments: load every list from its local `path`
+    /// and never download.
+    #[serde(default)]
+    pub offline: bool,
+}
+
+/// One `[[ip_reputation.proxy_feeds]]` entry.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReputationFeedConfig {
+    pub name: String,
+
+    #[serde(default)]
+    pub url: String,
+
+    /// Local copy of the list, read instead of `url` in offline mode.
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
 /// Auto-ban configuration for repeated offenders.
@@ -457,6 +1329,37 @@ pub struct AutoBanConfig {
 
     #[serde(default = "defaults::default_subnet_ban_ratio")]
     pub subnet_ban_ratio: f64,
+
+    /// Temporarily list an ASN once `asn_ban_ip_threshold` distinct IPs
+    /// from it are auto-banned within `escalation_window_secs`.
+    #[serde(default)]
+    pub asn_escalation_enabled: bool,
+
+    #[serde(default = "defaults::default_asn_ban_ip_threshold")]
+    pub asn_ban_ip_threshold: u32,
+
+    #[serde(default = "defaults::default_escalation_ttl_secs")]
+    pub asn_ban_ttl_secs: u64,
+
+    /// `challenge`, `block` or `tarpit`.
+    #[serde(default = "defaults::default_escalation_action")]
+    pub asn_ban_action: String,
+
+    /// As the ASN settings, per country.
+    #[serde(default)]
+    pub country_escalation_enabled: bool,
+
+    #[serde(default = "defaults::default_country_ban_ip_threshold")]
+    pub country_ban_ip_threshold: u32,
+
+    #[serde(default = "defaults::default_escalation_ttl_secs")]
+    pub country_ban_ttl_secs: u64,
+
+    #[serde(default = "defaults::default_escalation_action")]
+    pub country_ban_action: String,
+
+    #[serde(default = "defaults::default_escalation_window_secs")]
+    pub escalation_window_secs: u64,
 }
 
 /// Cloudflare compatibility configuration.
@@ -466,3 +1369,262 @@ pub struct CloudflareConfig {
     #[serde(default)]
     pub enabled: bool,
 }
+
+/// Multi-node state sync. Auto-bans, manual IP blocks and protection level
+/// changes are pushed to every peer's `POST /api/fortress/cluster/sync`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Identifies this node in sync batches. Generated at startup if empty.
+    #[serde(default)]
+    pub node_id: String,
+
+    /// Base URLs of the peers' admin APIs, e.g. `http://10.0.0.2:9090`.
+    #[serde(default)]
+    pub peers: Vec<String>,
+
+    /// HMAC key shared by all nodes; sync batches are signed with it.
+    #[serde(default)]
+    pub shared_secret: String,
+}
+
+/// In-memory cache of upstream GET responses the origin marks cacheable
+/// (`Cache-Control: max-age` / `s-maxage` or `Expires`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Total size of cached bodies; least recently used entries are evicted
+    /// beyond it.
+    #[serde(default = "defaults::default_cache_max_size_mb")]
+    pub max_size_mb: u64,
+
+    /// Larger responses are never cached.
+    #[serde(default = "defaults::default_cache_max_entry_size_kb")]
+    pub max_entry_size_kb: u64,
+
+    /// Upper bound on the freshness lifetime the origin asks for.
+    #[serde(default = "defaults::default_cache_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+
+    /// How long the last good copy of a page is kept for services with
+    /// `always_online`, served while their upstream is down. Kept apart
+    /// from the cache above and used even when it is disabled.
+    #[serde(default = "defaults::default_cache_stale_ttl_secs")]
+    pub stale_ttl_secs: u64,
+
+    /// Total size of the stale copies kept for `always_online`.
+    #[serde(default = "defaults::default_cache_stale_max_size_mb")]
+    pub stale_max_size_mb: u64,
+
+    /// Inserted after `<body>` of stale pages for services with
+    /// `always_online_banner`.
+    #[serde(default = "defaults::default_cache_stale_banner_html")]
+    pub stale_banner_html: String,
+}
+
+impl CacheConfig {
+    pub fn max_bytes(&self) -> usize {
+        (self.max_size_mb as usize).saturating_mul(1024 * 1024)
+    }
+
+    pub fn max_entry_bytes(&self) -> usize {
+        (self.max_entry_size_kb as usize).saturating_mul(1024)
+    }
+
+    pub fn stale_max_bytes(&self) -> usize {
+        (self.stale_max_size_mb as usize).saturating_mul(1024 * 1024)
+    }
+}
+
+/// Metrics pushed to external systems, next to the Prometheus endpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "defaults::default_metrics_export_config")]
+    pub export: MetricsExportConfig,
+}
+
+/// Push emitters, each enabled on its own. They send the snapshot the
+/// metrics reporter takes every escalation check.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsExportConfig {
+    #[serde(default = "defaults::default_statsd_export_config")]
+    pub statsd: StatsdExportConfig,
+
+    #[serde(default = "defaults::default_influxdb_export_config")]
+    pub influxdb: InfluxdbExportConfig,
+}
+
+/// StatsD over UDP, with DogStatsD tags (`|#service:api,action:blocked`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdExportConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// `host:port` of the StatsD agent.
+    #[serde(default = "defaults::default_statsd_address")]
+    pub address: String,
+
+    /// Prepended to every metric name, e.g. `fortress.requests`.
+    #[serde(default = "defaults::default_statsd_prefix")]
+    pub prefix: String,
+
+    #[serde(default = "defaults::default_metrics_export_interval_secs")]
+    pub interval_secs: u64,
+}
+
+/// InfluxDB v2 line protocol, written to `<url>/api/v2/write`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InfluxdbExportConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Base URL of the InfluxDB server.
+    #[serde(default = "defaults::default_influxdb_url")]
+    pub url: String,
+
+    #[serde(default)]
+    pub org: String,
+
+    #[serde(default)]
+    pub bucket: String,
+
+    /// API token, sent as `Authorization: Token <token>`.
+    #[serde(default)]
+    pub token: String,
+
+    #[serde(default = "defaults::default_metrics_export_interval_secs")]
+    pub interval_secs: u64,
+}
+
+/// Structured security events (blocks, challenges, auto-bans, escalations)
+/// for SIEM ingestion. Each sink is enabled by giving it a destination.
+/// Applied at startup.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityEventsConfig {
+    /// Events queued for each sink. A sink that falls further behind
+    /// loses the oldest ones, counted as dropped.
+    #[serde(default = "defaults::default_security_events_buffer_size")]
+    pub buffer_size: usize,
+
+    #[serde(default = "defaults::default_security_events_file_config")]
+    pub file: SecurityEventsFileConfig,
+
+    #[serde(default = "defaults::default_security_events_http_config")]
+    pub http: SecurityEventsHttpConfig,
+}
+
+/// NDJSON file, rotated by size like the access log.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityEventsFileConfig {
+    /// Empty disables the file.
+    #[serde(default)]
+    pub path: String,
+
+    /// 0 disables rotation.
+    #[serde(default = "defaults::default_security_events_max_size_mb")]
+    pub max_size_mb: u64,
+
+    #[serde(default = "defaults::default_access_log_max_files")]
+    pub max_files: usize,
+}
+
+/// Batches of events POSTed as a JSON array.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityEventsHttpConfig {
+    /// Empty disables the forwarder.
+    #[serde(default)]
+    pub url: String,
+
+    /// Extra request headers, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    #[serde(default = "defaults::default_security_events_batch_size")]
+    pub batch_size: usize,
+
+    /// A partial batch is sent after this long.
+    #[serde(default = "defaults::default_security_events_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+
+    /// Retries of a failed POST, with exponential backoff, before the
+    /// batch is spooled to disk.
+    #[serde(default = "defaults::default_security_events_max_retries")]
+    pub max_retries: u32,
+
+    #[serde(default = "defaults::default_security_events_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Batches that could not be delivered, resent once the endpoint
+    /// accepts events again.
+    #[serde(default = "defaults::default_security_events_spool_path")]
+    pub spool_path: String,
+
+    /// Events that would grow the spool past this are dropped.
+    #[serde(default = "defaults::default_security_events_spool_max_mb")]
+    pub spool_max_mb: u64,
+}
+
+/// Offload of bans to a kernel set (nftables or ipset), so volumetric
+/// offenders are dropped before their packets reach Fortress. Commands are
+/// given as program and arguments and run without a shell; `{ip}`,
+/// `{family}` (`ipv4` or `ipv6`) and `{timeout}` (seconds) are replaced.
+/// A failed command leaves the IP blocked at L7 only. Applied at startup.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FirewallConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    #[serde(default = "defaults::default_firewall_add_command")]
+    pub add_command: Vec<String>,
+
+    #[serde(default = "defaults::default_firewall_remove_command")]
+    pub remove_command: Vec<String>,
+
+    /// Run once per family at startup to clear entries left by an earlier
+    /// run. Empty skips it.
+    #[serde(default = "defaults::default_firewall_flush_command")]
+    pub flush_command: Vec<String>,
+
+    /// Commands taking longer are killed and count as failed.
+    #[serde(default = "defaults::default_firewall_command_timeout_ms")]
+    pub command_timeout_ms: u64,
+
+    /// Commands run per second at most; the rest wait in the queue.
+    #[serde(default = "defaults::default_firewall_max_commands_per_sec")]
+    pub max_commands_per_sec: u32,
+
+    /// Commands waiting to run. IPs banned while it is full are not
+    /// offloaded.
+    #[serde(default = "defaults::default_firewall_queue_size")]
+    pub queue_size: usize,
+
+    /// L4 drops of one IP within `l4_drop_window_secs` after which it is
+    /// offloaded for `l4_ban_secs`, without an auto-ban (0 disables).
+    #[serde(default = "defaults::default_firewall_l4_drop_threshold")]
+    pub l4_drop_threshold: u32,
+
+    #[serde(default = "defaults::default_firewall_l4_drop_window_secs")]
+    pub l4_drop_window_secs: u64,
+
+    #[serde(default = "defaults::default_firewall_l4_ban_secs")]
+    pub l4_ban_secs: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_when_unhealthy_rejects_unknown_values() {
+        let parse =
+            |value: &str| toml::from_str::<Settings>(&format!("[upstream.health_check]\nwhen_unhealthy = \"{}\"", value));
+        assert!(!parse("try_anyway").unwrap().upstream.health_check.fail_fast());
+        assert!(parse("fail_fast").unwrap().upstream.health_check.fail_fast());
+        assert!(parse("try-anyway").is_err());
+        assert!(Settings::default().upstream.health_check.fail_fast());
+    }
+}
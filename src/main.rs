@@ -9,6 +9,7 @@ mod storage;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
@@ -16,10 +17,19 @@ use crate::admin_api::routes::AppState;
 use crate::admin_api::server::AdminApiServer;
 use crate::analytics::alerting::AlertManager;
 use crate::analytics::collector::MetricsCollector;
+use crate::analytics::events::{self, EventBus};
+use crate::analytics::export::MetricsExporter;
 use crate::analytics::reporter::MetricsReporter;
-use crate::config::settings::Settings;
+use crate::analytics::capture::RequestCapture;
+use crate::analytics::live_tail::LiveTail;
+use crate::analytics::request_samples::RequestSampler;
+use crate::config::reload::ConfigReloader;
+use crate::config::settings::{Settings, SharedSettings};
+use crate::protection::api_tokens::ApiTokenStore;
+use crate::protection::ip_policies::IpPolicyStore;
 use crate::protection::asn::AsnClassifier;
 use crate::protection::auto_ban::AutoBanManager;
+use crate::protection::credential_stuffing::CredentialStuffingDetector;
 use crate::protection::distributed::DistributedDetector;
 use crate::protection::custom_rules::CustomRulesEngine;
 use crate::protection::managed_rules::ManagedRulesEngine;
@@ -28,6 +38,7 @@ use crate::protection::bot_whitelist::BotWhitelist;
 use crate::protection::challenge::ChallengeSystem;
 use crate::protection::escalation::EscalationEngine;
 use crate::protection::fingerprint::FingerprintAnalyzer;
+use crate::protection::firewall::FirewallOffload;
 use crate::protection::geoip::GeoIpLookup;
 use crate::protection::header_analysis::HeaderAnalyzer;
 use crate::protection::ip_reputation::IpReputationManager;
@@ -36,15 +47,22 @@ use crate::protection::mobile_proxy::MobileProxyDetector;
 use crate::protection::pipeline::ProtectionPipeline;
 use crate::protection::rate_limiter::RateLimiter;
 use crate::protection::slowloris::SlowlorisDetector;
+use crate::proxy::access_log::AccessLogger;
 use crate::proxy::connection::ConnectionTracker;
 use crate::proxy::health_check::HealthChecker;
 use crate::proxy::http_handler::HttpHandler;
+use crate::proxy::response_cache::ResponseCache;
+use crate::proxy::upstream::UpstreamClients;
 use crate::proxy::server::ProxyServer;
 use crate::proxy::service_router::ServiceRouter;
-use crate::proxy::tls::build_tls_config;
+use crate::proxy::tarpit::Tarpit;
+use crate::proxy::tls::{build_tls_config, FortressCertResolver};
+use crate::proxy::waiting_room::WaitingRoom;
 use crate::storage::blocklist::BlocklistManager;
+use crate::storage::cluster::ClusterSync;
 use crate::storage::memory::MemoryStore;
 use crate::storage::sqlite::SqliteStore;
+use crate::storage::sweep::SweepStats;
 
 /// Parse the `--config` CLI flag. Defaults to `/opt/fortress/config/fortress.toml`.
 fn parse_config_path() -> String {
@@ -96,28 +114,192 @@ fn init_tracing(log_dir: &str) {
 }
 
 /// Background task that periodically evicts expired entries from the
-/// in-memory store, L4 tracker, slowloris detector, auto-ban, and IP reputation.
+/// in-memory store, L4 tracker, slowloris detector, auto-ban, firewall
+/// offload, IP reputation, credential stuffing and rule rate counters, and
+/// prunes old request samples, expired cache entries and finished request
+/// captures.
+///
+/// Each run visits at most [`CLEANUP_BUDGET`] entries of every map, resuming
+/// where the last run stopped, and the runs come more often the larger the
+/// maps so each is still covered about every [`CLEANUP_PERIOD`]. The time
+/// taken and entries evicted are recorded per component.
+#[allow(clippy::too_many_arguments)]
 async fn cleanup_loop(
+    metrics: Arc<MetricsCollector>,
     memory: Arc<MemoryStore>,
     l4_tracker: Option<Arc<L4Tracker>>,
     slowloris: Arc<SlowlorisDetector>,
     auto_ban: Arc<AutoBanManager>,
+    firewall: Arc<FirewallOffload>,
     ip_reputation: Arc<IpReputationManager>,
     distributed: Arc<DistributedDetector>,
+    credential_stuffing: Arc<CredentialStuffingDetector>,
     managed_rules: Arc<ManagedRulesEngine>,
+    custom_rules: Arc<CustomRulesEngine>,
+    bot_whitelist: Arc<BotWhitelist>,
+    request_sampler: Arc<RequestSampler>,
+    response_cache: Arc<ResponseCache>,
+    request_capture: Arc<RequestCapture>,
 ) {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    let mut delay = CLEANUP_PERIOD;
     loop {
-        interval.tick().await;
-        memory.cleanup();
-        if let Some(ref l4) = l4_tracker {
-            l4.cleanup();
+        tokio::time::sleep(delay).await;
+        let sizes = [
+            timed_cleanup(&metrics, "memory", || memory.cleanup(CLEANUP_BUDGET)),
+            l4_tracker
+                .as_ref()
+                .map_or(0, |l4| timed_cleanup(&metrics, "l4_tracker", || l4.cleanup(CLEANUP_BUDGET))),
+            timed_cleanup(&metrics, "slowloris", || slowloris.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "auto_ban", || auto_ban.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "firewall", || firewall.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "ip_reputation", || ip_reputation.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "distributed", || distributed.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "credential_stuffing", || credential_stuffing.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "managed_rules", || managed_rules.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "custom_rules", || custom_rules.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "bot_whitelist", || bot_whitelist.cleanup(CLEANUP_BUDGET)),
+            timed_cleanup(&metrics, "response_cache", || response_cache.cleanup()),
+        ];
+        timed_cleanup(&metrics, "request_capture", || {
+            request_capture.cleanup();
+            SweepStats::default()
+        });
+        let started = Instant::now();
+        let pruned = request_sampler.prune().await;
+        let stats = SweepStats { removed: pruned, ..SweepStats::default() };
+        metrics.record_cleanup("request_samples", started.elapsed(), stats);
+
+        delay = cleanup_interval(sizes.into_iter().max().unwrap_or(0));
+        metrics.set_cleanup_interval(delay);
+    }
+}
+
+/// Run one component's cleanup, record its cost and return the size of its
+/// largest map.
+fn timed_cleanup(metrics: &MetricsCollector, component: &'static str, cleanup: impl FnOnce() -> SweepStats) -> usize {
+    let started = Instant::now();
+    let stats = cleanup();
+    metrics.record_cleanup(component, started.elapsed(), stats);
+    stats.largest
+}
+
+/// A full cleanup pass over each map should take about this long.
+const CLEANUP_PERIOD: Duration = Duration::from_secs(30);
+
+/// Entries of each map visited per cleanup run.
+const CLEANUP_BUDGET: usize = 20_000;
+
+/// Cleanup runs are never closer together than this.
+const MIN_CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay until the next cleanup run when the largest map holds `largest`
+/// entries: one run per [`CLEANUP_PERIOD`] while a run covers it, more
+/// often as it outgrows [`CLEANUP_BUDGET`].
+fn cleanup_interval(largest: usize) -> Duration {
+    let runs = largest.div_ceil(CLEANUP_BUDGET).clamp(1, u32::MAX as usize) as u32;
+    (CLEANUP_PERIOD / runs).max(MIN_CLEANUP_INTERVAL)
+}
+
+/// Reload the config file whenever the process receives SIGHUP.
+#[cfg(unix)]
+async fn reload_on_sighup(reloader: Arc<ConfigReloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        if let Err(e) = reloader.reload().await {
+            error!("Config reload failed: {:#}", e);
+        }
+    }
+}
+
+/// Reopen the access log whenever the process receives SIGUSR1, for
+/// logrotate's `postrotate` hook.
+#[cfg(unix)]
+async fn reopen_on_sigusr1(access_log: Arc<AccessLogger>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to install SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+    while usr1.recv().await.is_some() {
+        info!("SIGUSR1 received, reopening access log");
+        access_log.reopen();
+    }
+}
+
+/// Warn when country or ASN lists are configured but the GeoIP database
+/// they depend on is not loaded: such entries never match.
+fn warn_unusable_geo_lists(
+    geoip: &GeoIpLookup,
+    blocklist: &BlocklistManager,
+    settings: &Settings,
+    services: &[Arc<crate::config::service::ServiceConfig>],
+) {
+    if !geoip.has_city_db() {
+        let entries = blocklist.country_entry_count()
+            + settings.blocklist.allowed_countries.len()
+            + services
+                .iter()
+                .map(|s| s.allowed_countries.len() + s.blocked_countries.len() + s.challenged_countries.len())
+                .sum::<usize>();
+        if entries > 0 {
+            if settings.geoip.country_header.is_empty() {
+                warn!(
+                    entries,
+                    "GeoIP city database missing: country blocklist/allowlist entries will not match"
+                );
+            } else {
+                warn!(
+                    entries,
+                    header = %settings.geoip.country_header,
+                    "GeoIP city database missing: country entries only match requests from trusted proxies"
+                );
+            }
+        }
+    }
+    if !geoip.has_asn_db() {
+        let entries = blocklist.asn_entry_count()
+            + settings.blocklist.allowed_asns.len()
+            + services.iter().map(|s| s.allowed_asns.len()).sum::<usize>();
+        if entries > 0 {
+            warn!(entries, "GeoIP ASN database missing: ASN blocklist/allowlist entries will not match");
+        }
+    }
+}
+
+/// Poll the GeoIP database files and reload them when they change, so the
+/// weekly GeoLite2 update is picked up without a restart. Paths and interval
+/// are re-read from the live settings on every tick.
+async fn geoip_reload_loop(geoip: Arc<GeoIpLookup>, settings: SharedSettings) {
+    loop {
+        let interval = settings.load().geoip.reload_interval_secs;
+        if interval == 0 {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+
+        let config = settings.load().geoip.clone();
+        let geoip = geoip.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            geoip.reload_if_changed(&config.city_db, &config.asn_db)
+        })
+        .await;
+        if let Err(e) = result {
+            error!("GeoIP reload task failed: {}", e);
         }
-        slowloris.cleanup();
-        auto_ban.cleanup();
-        ip_reputation.cleanup();
-        distributed.cleanup();
-        managed_rules.cleanup();
     }
 }
 
@@ -134,6 +316,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config_path = parse_config_path();
     let settings = Settings::load(&config_path)?;
     let settings = Arc::new(settings);
+    // Live view for components that pick up `POST /api/fortress/config/reload`
+    // and SIGHUP; `settings` stays the startup snapshot.
+    let shared_settings: SharedSettings = Arc::new(ArcSwap::new(settings.clone()));
 
     // ---------------------------------------------------------------
     // 2. Logging
@@ -154,7 +339,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if settings.challenge.hmac_secret.is_empty() {
         panic!("CRITICAL: challenge.hmac_secret is empty. Set a random secret in the config file to prevent token forgery.");
     }
-    if settings.admin_api.api_key.is_empty() {
+    if settings.admin_api.api_key.is_empty() && settings.admin_api.client_ca.is_none() {
         panic!("CRITICAL: admin_api.api_key is empty. Set a strong API key in the config file to protect the admin interface.");
     }
 
@@ -167,33 +352,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     let memory = Arc::new(MemoryStore::new());
+    memory.set_tracking_limits(&settings.protection);
+    memory.set_behavior_config(&settings.behavioral);
 
-    let blocklist = Arc::new(BlocklistManager::new(memory.clone(), sqlite.clone()));
+    let geoip = Arc::new(
+        GeoIpLookup::new(&settings.geoip),
+    );
+    let blocklist = Arc::new(BlocklistManager::new(memory.clone(), sqlite.clone(), geoip.clone()));
     blocklist
         .load_from_db()
+        .await
         .expect("Failed to load blocklist from database");
 
     // ---------------------------------------------------------------
     // 3.5 Load config blocklists into database
     // ---------------------------------------------------------------
-    for country in &settings.blocklist.blocked_countries {
-        if let Err(e) = sqlite.add_blocked_country(country, None, "block", Some("config")) {
-            warn!("Failed to load blocked country {}: {}", country, e);
-        }
-    }
-    for country in &settings.blocklist.challenged_countries {
-        if let Err(e) = sqlite.add_blocked_country(country, None, "challenge", Some("config")) {
-            warn!("Failed to load challenged country {}: {}", country, e);
-        }
-    }
-    for asn in &settings.blocklist.blocked_asns {
-        if let Err(e) = sqlite.add_blocked_asn(*asn, None, "block", Some("config")) {
-            warn!("Failed to load blocked ASN {}: {}", asn, e);
-        }
-    }
-    // Reload blocklist after config additions
-    if let Err(e) = blocklist.load_from_db() {
-        warn!("Failed to reload blocklist: {}", e);
+    if let Err(e) = blocklist.apply_config(&settings.blocklist).await {
+        warn!("Failed to apply config blocklists: {}", e);
     }
 
     info!("Storage layer initialised");
@@ -202,34 +377,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Service Router
     // ---------------------------------------------------------------
     let service_router = Arc::new(ServiceRouter::new(&settings.upstream.address));
-    service_router.load_from_db(&sqlite)?;
+    service_router.load_from_db(&sqlite).await?;
     service_router.load_from_config(&settings.services);
     info!("Loaded {} services", service_router.service_count());
 
     // ---------------------------------------------------------------
     // 4. Protection components
     // ---------------------------------------------------------------
-    let geoip = Arc::new(
-        GeoIpLookup::new(&settings.geoip.city_db, &settings.geoip.asn_db),
-    );
+    warn_unusable_geo_lists(&geoip, &blocklist, &settings, &service_router.list_services());
 
     let asn_classifier = Arc::new(AsnClassifier::new());
+    asn_classifier.load_overrides(&sqlite).await;
 
     let rate_limiter = Arc::new(RateLimiter::new(memory.clone()));
     let fingerprint_analyzer = Arc::new(FingerprintAnalyzer::new());
-    let challenge_system = Arc::new(ChallengeSystem::new(&settings.challenge, memory.clone()));
+    let challenge_system = Arc::new(ChallengeSystem::new(&settings.challenge, &settings.protection, memory.clone()));
     let behavioral_analyzer = Arc::new(BehavioralAnalyzer::new(memory.clone()));
     let mobile_proxy_detector = Arc::new(MobileProxyDetector::new(asn_classifier.clone(), &settings.mobile_proxy));
     let header_analyzer = Arc::new(HeaderAnalyzer::new());
-    let slowloris_detector = Arc::new(SlowlorisDetector::new());
 
     let escalation = Arc::new(EscalationEngine::with_config(&settings));
     let bot_whitelist = Arc::new(BotWhitelist::new(&settings.bot_whitelist));
     let ip_reputation = Arc::new(IpReputationManager::new(&settings.ip_reputation));
-    let auto_ban = Arc::new(AutoBanManager::new(&settings.auto_ban));
-    let distributed = Arc::new(DistributedDetector::new());
+    let cluster = Arc::new(ClusterSync::new(shared_settings.clone()));
+    if settings.cluster.enabled {
+        info!(node_id = %cluster.node_id(), peers = settings.cluster.peers.len(), "Cluster sync enabled");
+    }
+    // Reads channels and cooldown from the live settings; see section 7.
+    let alerting = Arc::new(AlertManager::new(shared_settings.clone()));
+    // Sinks subscribe before anything can publish
+    let events = Arc::new(EventBus::new(settings.security_events.buffer_size));
+    let security_events = &settings.security_events;
+    if !security_events.file.path.is_empty() {
+        match events::start_file_sink(&events, &security_events.file) {
+            Ok(()) => info!(path = %security_events.file.path, "Security events written to file"),
+            Err(e) => error!("Failed to open security event log {}: {}", security_events.file.path, e),
+        }
+    }
+    if !security_events.http.url.is_empty() {
+        events::start_http_forwarder(&events, &security_events.http);
+        info!(url = %security_events.http.url, "Security events forwarded over HTTP");
+    }
+    // Bans mirrored into the kernel firewall, if `firewall.enabled`
+    let firewall = Arc::new(FirewallOffload::new(shared_settings.clone()));
+    let auto_ban = Arc::new(AutoBanManager::new(
+        &settings.auto_ban,
+        &settings.protection,
+        Arc::clone(&sqlite),
+        cluster.clone(),
+        alerting.clone(),
+        geoip.clone(),
+        blocklist.clone(),
+        events.clone(),
+        firewall.clone(),
+    ));
+    let slowloris_detector = Arc::new(SlowlorisDetector::new(auto_ban.clone(), Arc::clone(&sqlite)));
+    let distributed = Arc::new(DistributedDetector::new(alerting.clone()));
+    let credential_stuffing = Arc::new(CredentialStuffingDetector::new());
     let managed_rules = Arc::new(ManagedRulesEngine::new());
-    let custom_rules = Arc::new(CustomRulesEngine::new(Arc::clone(&sqlite)));
+    if let Err(e) = managed_rules.load_from_db(&sqlite).await {
+        warn!("Failed to load managed rule settings: {}", e);
+    }
+    let custom_rules = Arc::new(CustomRulesEngine::new(Arc::clone(&sqlite), managed_rules.clone()));
+    custom_rules.reload_rules().await;
+    let api_tokens = Arc::new(ApiTokenStore::new(Arc::clone(&sqlite)));
+    api_tokens.load().await;
+    let ip_policies = Arc::new(IpPolicyStore::new(Arc::clone(&sqlite), memory.clone()));
+    ip_policies.load().await;
 
     // Apply default protection level from config
     if settings.protection.default_level > 0 {
@@ -238,6 +452,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             info!("Default protection level set to L{}", settings.protection.default_level);
         }
     }
+    // A recent (or pinned) level from the last run takes precedence
+    let restore_max_age = Duration::from_secs(settings.escalation.restore_max_age_secs);
+    if let Some(level) = escalation.restore(&sqlite, restore_max_age).await {
+        info!(
+            "Restored protection level L{} ({})",
+            level.as_u8(),
+            escalation.level_mode().as_str()
+        );
+    }
 
     let pipeline = Arc::new(ProtectionPipeline {
         rate_limiter: rate_limiter.clone(),
@@ -257,6 +480,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         distributed: distributed.clone(),
         managed_rules: managed_rules.clone(),
         custom_rules: custom_rules.clone(),
+        slowloris: slowloris_detector.clone(),
+        api_tokens: api_tokens.clone(),
+        ip_policies: ip_policies.clone(),
+        credential_stuffing: credential_stuffing.clone(),
+        events: events.clone(),
     });
 
     info!("Protection pipeline initialised");
@@ -265,7 +493,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // L4 Protection
     // ---------------------------------------------------------------
     let l4_tracker = if settings.l4_protection.enabled {
-        let tracker = Arc::new(L4Tracker::new(settings.l4_protection.clone()));
+        let tracker = Arc::new(L4Tracker::new(
+            settings.l4_protection.clone(),
+            settings.protection.ipv4_subnet_mask,
+            settings.protection.ipv6_subnet_mask,
+        ));
         info!("L4 TCP protection enabled");
         Some(tracker)
     } else {
@@ -279,36 +511,67 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let connections = Arc::new(ConnectionTracker::new());
     let metrics = Arc::new(MetricsCollector::new());
 
+    // Per-request access logger (best-effort)
+    let access_log = if !settings.logging.access_log.is_empty() {
+        match AccessLogger::new(&settings.logging) {
+            Ok(logger) => {
+                info!(
+                    "Access log enabled: {} ({})",
+                    settings.logging.access_log, settings.logging.access_log_format
+                );
+                Some(Arc::new(logger))
+            }
+            Err(e) => {
+                error!("Failed to open access log {}: {}", settings.logging.access_log, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let tarpit = Arc::new(Tarpit::new());
+    tarpit.start(Duration::from_millis(settings.protection.tarpit.drip_interval_ms));
+
+    let request_sampler = Arc::new(RequestSampler::new(sqlite.clone(), shared_settings.clone()));
+    let request_capture = Arc::new(RequestCapture::new(shared_settings.clone()));
+    let live_tail = Arc::new(LiveTail::new(shared_settings.clone()));
+    let upstream_clients = Arc::new(UpstreamClients::new());
+    let response_cache = Arc::new(ResponseCache::new(shared_settings.clone()));
+    let waiting_room = Arc::new(WaitingRoom::new(challenge_system.clone()));
+
     let http_handler = Arc::new(HttpHandler::new(
         pipeline.clone(),
         service_router.clone(),
         memory.clone(),
         connections.clone(),
         metrics.clone(),
-        settings.clone(),
+        shared_settings.clone(),
         challenge_system.clone(),
+        upstream_clients.clone(),
+        access_log.clone(),
+        request_sampler.clone(),
+        request_capture.clone(),
+        live_tail.clone(),
+        tarpit.clone(),
+        response_cache.clone(),
+        waiting_room.clone(),
     ));
 
-    let tls_config = build_tls_config(&settings.tls.cert_dir).ok();
-    if tls_config.is_some() {
-        info!("TLS configuration loaded");
-    } else {
-        info!("Running without TLS (plain HTTP mode)");
-    }
-
-    let tls_server_config = match tls_config {
-        Some(config) => Arc::new(config),
-        None => {
+    let cert_resolver = if settings.server.mode.serves_https() {
+        let resolver = Arc::new(FortressCertResolver::load_certs(&settings.tls.cert_dir));
+        if resolver.certificate_count() > 0 {
+            info!("TLS configuration loaded");
+        } else {
             info!("No valid TLS certificates found; HTTPS will not work until certs are configured.");
-            Arc::new(
-                rustls::ServerConfig::builder()
-                    .with_no_client_auth()
-                    .with_cert_resolver(Arc::new(
-                        crate::proxy::tls::FortressCertResolver::load_certs(&settings.tls.cert_dir),
-                    )),
-            )
         }
+        http_handler.set_cert_resolver(resolver.clone());
+        Some(resolver)
+    } else {
+        info!("Running without TLS (server.mode = \"http\")");
+        None
     };
+    let tls_server_config = cert_resolver.clone().map(|resolver| Arc::new(build_tls_config(resolver)));
 
     let proxy_server = ProxyServer::new(
         settings.clone(),
@@ -318,6 +581,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         l4_tracker.clone(),
         sqlite.clone(),
         slowloris_detector.clone(),
+        auto_ban.clone(),
+        firewall.clone(),
     );
 
     info!("Proxy server configured");
@@ -325,6 +590,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // ---------------------------------------------------------------
     // 6. Admin API
     // ---------------------------------------------------------------
+    let reloader = Arc::new(ConfigReloader::new(
+        config_path.clone(),
+        shared_settings.clone(),
+        challenge_system.clone(),
+        escalation.clone(),
+        blocklist.clone(),
+        memory.clone(),
+    ));
+
     let admin_state = AppState {
         memory: memory.clone(),
         sqlite: sqlite.clone(),
@@ -336,34 +610,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         api_key: settings.admin_api.api_key.clone(),
         service_router: service_router.clone(),
         l4_tracker: l4_tracker.clone(),
-        settings: settings.clone(),
+        settings: shared_settings.clone(),
+        reloader: reloader.clone(),
         ip_reputation: ip_reputation.clone(),
         auto_ban: auto_ban.clone(),
         distributed: distributed.clone(),
         managed_rules: managed_rules.clone(),
+        custom_rules: custom_rules.clone(),
         geoip: geoip.clone(),
+        cluster: cluster.clone(),
+        access_log: access_log.clone(),
+        tarpit: tarpit.clone(),
+        alerting: alerting.clone(),
+        pipeline: pipeline.clone(),
+        upstream_clients: upstream_clients.clone(),
+        response_cache: response_cache.clone(),
+        cert_resolver: cert_resolver.clone(),
+        request_capture: request_capture.clone(),
+        live_tail: live_tail.clone(),
+        waiting_room: waiting_room.clone(),
+        firewall: firewall.clone(),
+        public_status: Default::default(),
     };
 
     let admin_bind = settings.admin_api.bind.clone();
 
-    let admin_server = AdminApiServer::new(admin_state, admin_bind.clone());
+    let admin_tls = admin_api::tls::build_admin_tls_config(&settings.admin_api)
+        .unwrap_or_else(|e| panic!("CRITICAL: admin API TLS: {}", e));
+    let admin_server = AdminApiServer::new(admin_state, admin_bind.clone(), admin_tls);
 
     info!("Admin API will listen on {}", admin_bind);
 
     // ---------------------------------------------------------------
     // 7. Alerting
     // ---------------------------------------------------------------
-    let alerting = if settings.alerting.enabled {
-        let manager = Arc::new(AlertManager::new(
-            settings.alerting.webhook_url.clone(),
-            true,
-        ));
-        info!("Alerting enabled");
-        Some(manager)
+    if settings.alerting.enabled {
+        info!(channels = settings.alerting.channels.len(), "Alerting enabled");
     } else {
         info!("Alerting disabled");
-        None
-    };
+    }
 
     // ---------------------------------------------------------------
     // 8. Metrics reporter
@@ -372,17 +657,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         metrics.clone(),
         sqlite.clone(),
         escalation.clone(),
-        settings.clone(),
+        shared_settings.clone(),
         alerting.clone(),
+        events.clone(),
     );
+    let exporter = Arc::new(MetricsExporter::new(shared_settings.clone(), reporter.export_snapshots()));
 
     // ---------------------------------------------------------------
     // 9. Health checker
     // ---------------------------------------------------------------
     let health_checker = Arc::new(HealthChecker::new(
         service_router.clone(),
-        10, // check every 10 seconds
-        5000, // 5 second timeout
+        alerting.clone(),
+        shared_settings.clone(),
+        upstream_clients.clone(),
     ));
 
     // ---------------------------------------------------------------
@@ -411,21 +699,57 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let reporter_handle = tokio::spawn(async move {
         reporter.run().await;
     });
+    let statsd_handle = tokio::spawn(exporter.clone().run_statsd());
+    let influxdb_handle = tokio::spawn(exporter.clone().run_influxdb());
 
     let cleanup_handle = tokio::spawn(cleanup_loop(
+        metrics.clone(),
         memory_clone,
         l4_tracker_cleanup,
         slowloris_cleanup,
         auto_ban_cleanup,
+        firewall.clone(),
         ip_reputation_cleanup,
         distributed_cleanup,
+        credential_stuffing.clone(),
         managed_rules_cleanup,
+        custom_rules.clone(),
+        bot_whitelist.clone(),
+        request_sampler.clone(),
+        response_cache.clone(),
+        request_capture.clone(),
     ));
 
     let health_handle = tokio::spawn(async move {
         health_checker.run().await;
     });
 
+    let geoip_handle = tokio::spawn(geoip_reload_loop(geoip.clone(), shared_settings.clone()));
+    let feeds_handle = tokio::spawn(crate::storage::feeds::run_feed_fetcher(
+        blocklist.clone(),
+        shared_settings.clone(),
+    ));
+    let reputation_feeds_handle = tokio::spawn(crate::protection::reputation_feeds::run_reputation_feeds(
+        ip_reputation.clone(),
+        shared_settings.clone(),
+    ));
+    let asn_dataset_handle = tokio::spawn(crate::protection::asn::run_asn_dataset(
+        asn_classifier.clone(),
+        shared_settings.clone(),
+    ));
+
+    let cluster_handle = tokio::spawn(cluster.clone().run());
+    let cert_expiry_handle = tokio::spawn(alerting.clone().run_cert_expiry_checks());
+    let custom_rules_handle = tokio::spawn(custom_rules.clone().run());
+    let request_sampler_handle = tokio::spawn(request_sampler.clone().run());
+    let api_tokens_handle = tokio::spawn(api_tokens.clone().run());
+    let ip_policies_handle = tokio::spawn(ip_policies.clone().run());
+
+    #[cfg(unix)]
+    let reload_handle = tokio::spawn(reload_on_sighup(reloader.clone()));
+    #[cfg(unix)]
+    let reopen_handle = access_log.clone().map(|logger| tokio::spawn(reopen_on_sigusr1(logger)));
+
     info!("Fortress is running. Press Ctrl+C to shut down.");
 
     // ---------------------------------------------------------------
@@ -442,8 +766,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     proxy_handle.abort();
     admin_handle.abort();
     reporter_handle.abort();
+    statsd_handle.abort();
+    influxdb_handle.abort();
     cleanup_handle.abort();
     health_handle.abort();
+    geoip_handle.abort();
+    feeds_handle.abort();
+    reputation_feeds_handle.abort();
+    asn_dataset_handle.abort();
+    cluster_handle.abort();
+    cert_expiry_handle.abort();
+    custom_rules_handle.abort();
+    request_sampler_handle.abort();
+    api_tokens_handle.abort();
+    ip_policies_handle.abort();
+    #[cfg(unix)]
+    reload_handle.abort();
+    #[cfg(unix)]
+    if let Some(handle) = reopen_handle {
+        handle.abort();
+    }
+
+    // Let queued samples, audit and L4 event writes reach the database.
+    request_sampler.flush().await;
+    sqlite.flush().await;
 
     info!("Fortress shut down gracefully");
     Ok(())
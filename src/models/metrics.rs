@@ -20,7 +20,8 @@ pub struct MetricsSnapshot {
     /// Passed requests per second.
     pub passed_per_sec: f64,
 
-    /// Number of unique client IPs seen in the snapshot window.
+    /// Number of unique client IPs seen in the snapshot window, estimated
+    /// to within ~0.8% (see `analytics::sketch`).
     pub unique_ips: u64,
 
     /// Average upstream response latency in milliseconds.
@@ -43,6 +44,26 @@ pub struct L4MetricsSnapshot {
     pub total_dropped: u64,
     pub total_tarpitted: u64,
     pub tracked_ips: u64,
+    pub tracked_subnets: u64,
+    /// Failed TLS handshakes, timeouts included.
+    pub handshake_failures: u64,
+    pub handshake_timeouts: u64,
+    /// Handshake failures per second over `handshake_failure_window_secs`.
+    pub handshake_failure_rate: f64,
+    /// IPs refused for handshake failures right now.
+    pub handshake_blocked_ips: u64,
+}
+
+/// Current L4 state of one client IP, for `GET /api/fortress/l4/top`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct L4IpStats {
+    pub ip: String,
+    pub subnet: String,
+    pub concurrent: u64,
+    /// Connections opened in the last second.
+    pub rate: u64,
+    pub dropped: u64,
+    pub tarpitted: u64,
 }
 
 /// A single traffic event for real-time WebSocket streaming to the admin UI.
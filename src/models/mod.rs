@@ -2,3 +2,4 @@ pub mod request;
 pub mod connection;
 pub mod threat;
 pub mod metrics;
+pub mod schedule;
@@ -2,6 +2,12 @@ use std::collections::HashMap;
 use std::net::IpAddr;
 use std::time::Instant;
 
+use bytes::Bytes;
+use hyper::header::HeaderMap;
+
+use crate::protection::credential_stuffing::UsernameHash;
+use crate::protection::ip_policies::IpPolicy;
+
 /// Full context for an incoming request, enriched with GeoIP data,
 /// fingerprint information, and behavioral scoring.
 #[derive(Debug, Clone)]
@@ -15,6 +21,10 @@ pub struct RequestContext {
     /// ISO 3166-1 alpha-2 country code from GeoIP lookup.
     pub country_code: Option<String>,
 
+    /// Country sent by a trusted proxy (`geoip.country_header`), used when
+    /// the GeoIP lookup finds nothing.
+    pub fallback_country: Option<String>,
+
     /// Autonomous System Number from GeoIP lookup.
     pub asn: Option<u32>,
 
@@ -27,15 +37,40 @@ pub struct RequestContext {
     /// HTTP method (GET, POST, etc.).
     pub method: String,
 
-    /// Request path (e.g. "/api/v1/users").
+    /// Canonical request path (e.g. "/api/v1/users"): percent-decoded,
+    /// with duplicate slashes and dot segments removed. Rules match on
+    /// this one; see [`normalize_path`].
     pub path: String,
 
+    /// The path as received, which is what gets forwarded upstream.
+    pub raw_path: String,
+
+    /// Whether the raw path climbed out of a directory with a `..`
+    /// segment, in any encoding.
+    pub path_traversal: bool,
+
+    /// Raw query string, without the leading `?`.
+    pub query: Option<String>,
+
     /// Host header value.
     pub host: String,
 
-    /// All request headers as key-value pairs.
+    /// All request headers as key-value pairs; of a repeated header only
+    /// one value is kept. For lookups, never for forwarding.
     pub headers: HashMap<String, String>,
 
+    /// The request headers as received, with every field in order. The
+    /// upstream request is built from these.
+    pub header_map: HeaderMap,
+
+    /// Start of the request body, up to `protection.body_inspection.max_bytes`,
+    /// when the service has body inspection enabled.
+    pub body: Option<Bytes>,
+
+    /// Hashed username of a login attempt, when credential stuffing
+    /// detection covers the path.
+    pub login_user: Option<UsernameHash>,
+
     /// Whether the IP belongs to a known datacenter/hosting provider.
     pub is_datacenter: bool,
 
@@ -48,6 +83,15 @@ pub struct RequestContext {
     /// Whether this request came through Cloudflare (detected via CF headers).
     pub is_behind_cloudflare: bool,
 
+    /// ID of the API token that exempts this request from challenges.
+    pub api_token: Option<String>,
+
+    /// Policy set for the client's IP or range, looked up by the pipeline.
+    pub ip_policy: Option<IpPolicy>,
+
+    /// Ray ID of the request, as sent back in `X-Fortress-Ray`.
+    pub ray_id: Option<String>,
+
     /// Timestamp when the request was received.
     pub timestamp: Instant,
 }
@@ -60,21 +104,41 @@ impl RequestContext {
             client_ip,
             ja3_hash: None,
             country_code: None,
+            fallback_country: None,
             asn: None,
             asn_name: None,
             user_agent: None,
             method,
+            raw_path: path.clone(),
             path,
+            path_traversal: false,
+            query: None,
             host,
             headers: HashMap::new(),
+            header_map: HeaderMap::new(),
+            body: None,
+            login_user: None,
             is_datacenter: false,
             is_residential_proxy: false,
             behavioral_score: 0.0,
             is_behind_cloudflare: false,
+            api_token: None,
+            ip_policy: None,
+            ray_id: None,
             timestamp: Instant::now(),
         }
     }
 
+    /// Create a RequestContext for a path as received from the client. The
+    /// path is normalized for rule matching and the host lowercased.
+    pub fn from_raw(client_ip: IpAddr, method: String, raw_path: &str, host: &str) -> Result<Self, InvalidPath> {
+        let normalized = normalize_path(raw_path)?;
+        let mut ctx = Self::new(client_ip, method, normalized.path, host.to_ascii_lowercase());
+        ctx.raw_path = raw_path.to_string();
+        ctx.path_traversal = normalized.traversal;
+        Ok(ctx)
+    }
+
     /// Returns the /24 (IPv4) or /48 (IPv6) subnet string for this client.
     pub fn subnet_key(&self) -> String {
         match self.client_ip {
@@ -89,3 +153,176 @@ impl RequestContext {
         }
     }
 }
+
+/// Why a request path was refused by [`normalize_path`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidPath {
+    /// A percent-encoded control character, such as `%00` or `%0a`.
+    ControlCharacter,
+    /// A control character encoded twice, such as `%2500`.
+    DoubleEncodedControl,
+}
+
+impl InvalidPath {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ControlCharacter => "encoded_control_character",
+            Self::DoubleEncodedControl => "double_encoded_control_character",
+        }
+    }
+}
+
+/// A path in the form rules match against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedPath {
+    pub path: String,
+    /// A `..` segment was resolved.
+    pub traversal: bool,
+}
+
+/// Canonicalize a request path so that encodings of the same path match
+/// the same rules:
+///
+/// - percent-escapes are decoded once; a control character that is encoded
+///   (once or twice) is refused, anything else double-encoded stays encoded
+/// - backslashes count as slashes
+/// - duplicate slashes and `.` segments are dropped and `..` segments
+///   resolved, never above the root
+///
+/// A trailing slash is kept. Paths not starting with a slash (`*`) are
+/// returned as they are.
+pub fn normalize_path(raw: &str) -> Result<NormalizedPath, InvalidPath> {
+    if !raw.starts_with('/') && !raw.starts_with('\\') {
+        return Ok(NormalizedPath { path: raw.to_string(), traversal: false });
+    }
+
+    let decoded = percent_decode(raw.as_bytes())?;
+    let decoded = String::from_utf8_lossy(&decoded).replace('\\', "/");
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut traversal = false;
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                traversal = true;
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let last = decoded.rsplit('/').next().unwrap_or("");
+    let trailing_slash = matches!(last, "" | "." | "..") && !segments.is_empty();
+    let mut path = String::with_capacity(decoded.len());
+    for segment in &segments {
+        path.push('/');
+        path.push_str(segment);
+    }
+    if trailing_slash || path.is_empty() {
+        path.push('/');
+    }
+    Ok(NormalizedPath { path, traversal })
+}
+
+fn percent_decode(raw: &[u8]) -> Result<Vec<u8>, InvalidPath> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        match escaped_byte(&raw[i..]) {
+            Some(byte) => {
+                if byte.is_ascii_control() {
+                    return Err(InvalidPath::ControlCharacter);
+                }
+                // `%25` followed by the hex digits of a control character
+                if byte == b'%' && hex_byte(&raw[i + 3..]).is_some_and(|b| b.is_ascii_control()) {
+                    return Err(InvalidPath::DoubleEncodedControl);
+                }
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(raw[i]);
+                i += 1;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The byte encoded by a `%XX` escape at the start of `s`.
+fn escaped_byte(s: &[u8]) -> Option<u8> {
+    s.strip_prefix(b"%").and_then(hex_byte)
+}
+
+/// The byte given by two hex digits at the start of `s`.
+fn hex_byte(s: &[u8]) -> Option<u8> {
+    let hex = |c: &u8| (*c as char).to_digit(16);
+    Some((hex(s.first()?)? * 16 + hex(s.get(1)?)?) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evasion_variants_normalize_to_the_same_path() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("/wp-admin", "/wp-admin", false),
+            ("/%77p-admin", "/wp-admin", false),
+            ("/%77%70%2d%61%64%6d%69%6e", "/wp-admin", false),
+            ("/%57p-admin", "/Wp-admin", false),
+            ("//wp-admin", "/wp-admin", false),
+            ("///wp-admin//", "/wp-admin/", false),
+            ("/wp-admin/.", "/wp-admin/", false),
+            ("/./wp-admin", "/wp-admin", false),
+            ("/wp-admin/./index.php", "/wp-admin/index.php", false),
+            ("/%2e/wp-admin", "/wp-admin", false),
+            ("/\\wp-admin", "/wp-admin", false),
+            ("\\wp-admin", "/wp-admin", false),
+            ("/%5cwp-admin", "/wp-admin", false),
+            ("/%2fwp-admin", "/wp-admin", false),
+            ("/%2Fwp-admin%2F", "/wp-admin/", false),
+            ("/foo/../wp-admin", "/wp-admin", true),
+            ("/foo/%2e%2e/wp-admin", "/wp-admin", true),
+            ("/foo/%2E%2E/wp-admin", "/wp-admin", true),
+            ("/foo/.%2e/wp-admin", "/wp-admin", true),
+            ("/foo%2f..%2fwp-admin", "/wp-admin", true),
+            ("/foo\\..\\wp-admin", "/wp-admin", true),
+            ("/foo%5c..%5cwp-admin", "/wp-admin", true),
+            ("/foo/bar/../../wp-admin", "/wp-admin", true),
+            ("/../../../etc/passwd", "/etc/passwd", true),
+            ("/%2e%2e/%2e%2e/etc/passwd", "/etc/passwd", true),
+            ("/static/..%2f.env", "/.env", true),
+            ("/images/..", "/", true),
+            ("/a/b/..", "/a/", true),
+            ("/.git/config", "/.git/config", false),
+            ("/%2egit/config", "/.git/config", false),
+            ("/.%65nv", "/.env", false),
+            // Double-encoded: decoded once, the escape stays in the path
+            ("/%252e%252e/etc", "/%2e%2e/etc", false),
+            ("/%2577p-admin", "/%77p-admin", false),
+            // Not escapes
+            ("/100%", "/100%", false),
+            ("/%zz/a", "/%zz/a", false),
+            ("/caf%C3%A9", "/caf\u{e9}", false),
+            ("/", "/", false),
+            ("*", "*", false),
+        ];
+        for (raw, path, traversal) in cases {
+            let normalized = normalize_path(raw).unwrap_or_else(|e| panic!("{raw}: {e:?}"));
+            assert_eq!(normalized.path, *path, "{raw}");
+            assert_eq!(normalized.traversal, *traversal, "{raw}");
+        }
+    }
+
+    #[test]
+    fn test_encoded_control_characters_are_refused() {
+        for raw in ["/a%00b", "/a%0d%0aSet-Cookie:x", "/%7f", "/a%09"] {
+            assert_eq!(normalize_path(raw), Err(InvalidPath::ControlCharacter), "{raw}");
+        }
+        for raw in ["/a%2500b", "/a%250d%250a", "/%257F"] {
+            assert_eq!(normalize_path(raw), Err(InvalidPath::DoubleEncodedControl), "{raw}");
+        }
+    }
+}
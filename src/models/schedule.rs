@@ -0,0 +1,208 @@
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+// ---------------------------------------------------------------------------
+// Schedule – recurring UTC window for rules and blocklist entries
+// ---------------------------------------------------------------------------
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Bitmask with every day set (bit 0 = Monday).
+const ALL_DAYS: u8 = 0x7f;
+
+/// When a scheduled custom rule or blocklist entry is in force, in UTC.
+///
+/// Outside its window the entry is dormant rather than expired: it stays
+/// stored and applies again when the next window opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// Start and end as minutes since midnight; `None` means all day. An end
+    /// before the start runs past midnight, and the part after midnight
+    /// belongs to the day the window opened on.
+    window: Option<(u16, u16)>,
+    /// Days the window opens on, bit 0 = Monday.
+    days: u8,
+}
+
+impl Schedule {
+    /// Whether `now` falls inside the schedule.
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        let day_bit = |t: DateTime<Utc>| 1u8 << t.weekday().num_days_from_monday();
+        let Some((from, to)) = self.window else {
+            return self.days & day_bit(now) != 0;
+        };
+        let minute = (now.hour() * 60 + now.minute()) as u16;
+        if from < to {
+            (from..to).contains(&minute) && self.days & day_bit(now) != 0
+        } else if minute >= from {
+            self.days & day_bit(now) != 0
+        } else {
+            minute < to && self.days & day_bit(now - Duration::days(1)) != 0
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(Utc::now())
+    }
+
+    /// Canonical `active_from` / `active_to` / `active_days` values.
+    pub fn fields(&self) -> ScheduleFields {
+        let time = |m: u16| format!("{:02}:{:02}", m / 60, m % 60);
+        let days = (self.days != ALL_DAYS).then(|| {
+            DAY_NAMES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| self.days & (1 << i) != 0)
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        ScheduleFields {
+            active_from: self.window.map(|(from, _)| time(from)),
+            active_to: self.window.map(|(_, to)| time(to)),
+            active_days: days,
+        }
+    }
+}
+
+/// Schedule columns as stored in SQLite and sent over the admin API.
+///
+/// `active_from` and `active_to` are `HH:MM` (UTC) and must be set
+/// together; `active_days` is a list such as `mon,wed` or `mon-fri`. With
+/// only days set the entry is active all day on those days.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleFields {
+    #[serde(default)]
+    pub active_from: Option<String>,
+    #[serde(default)]
+    pub active_to: Option<String>,
+    #[serde(default)]
+    pub active_days: Option<String>,
+}
+
+impl ScheduleFields {
+    pub fn is_empty(&self) -> bool {
+        self.active_from.is_none() && self.active_to.is_none() && self.active_days.is_none()
+    }
+
+    /// Validate into a [`Schedule`]; `None` when no field is set.
+    pub fn parse(&self) -> Result<Option<Schedule>, String> {
+        let window = match (self.active_from.as_deref(), self.active_to.as_deref()) {
+            (None, None) => None,
+            (Some(from), Some(to)) => {
+                let (from, to) = (parse_time(from)?, parse_time(to)?);
+                if from == to {
+                    return Err("active_from and active_to must differ".to_string());
+                }
+                Some((from, to))
+            }
+            _ => return Err("active_from and active_to must be set together".to_string()),
+        };
+        let days = match self.active_days.as_deref() {
+            Some(days) => parse_days(days)?,
+            None if window.is_none() => return Ok(None),
+            None => ALL_DAYS,
+        };
+        Ok(Some(Schedule { window, days }))
+    }
+}
+
+/// Parse `HH:MM` into minutes since midnight.
+fn parse_time(value: &str) -> Result<u16, String> {
+    let invalid = || format!("Invalid time of day (expected HH:MM): {}", value);
+    let (h, m) = value.trim().split_once(':').ok_or_else(invalid)?;
+    let (h, m): (u16, u16) = (h.parse().map_err(|_| invalid())?, m.parse().map_err(|_| invalid())?);
+    if h > 23 || m > 59 {
+        return Err(invalid());
+    }
+    Ok(h * 60 + m)
+}
+
+/// Parse a comma-separated list of day names (`mon` or `monday`) and
+/// `from-to` ranges (which may wrap, e.g. `fri-mon`) into a day bitmask.
+fn parse_days(value: &str) -> Result<u8, String> {
+    const FULL_NAMES: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+    let day = |name: &str| {
+        let name = name.trim().to_ascii_lowercase();
+        DAY_NAMES
+            .iter()
+            .zip(FULL_NAMES)
+            .position(|(short, full)| name == *short || name == full)
+            .ok_or_else(|| format!("Invalid day: {}", name))
+    };
+    let mut days = 0u8;
+    for part in value.split(',').filter(|p| !p.trim().is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (day(start)?, day(end)?);
+                let mut d = start;
+                loop {
+                    days |= 1 << d;
+                    if d == end {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days |= 1 << day(part)?,
+        }
+    }
+    if days == 0 {
+        return Err("active_days is empty".to_string());
+    }
+    Ok(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(from: Option<&str>, to: Option<&str>, days: Option<&str>) -> ScheduleFields {
+        ScheduleFields {
+            active_from: from.map(str::to_string),
+            active_to: to.map(str::to_string),
+            active_days: days.map(str::to_string),
+        }
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_windows_wrap_midnight_and_respect_days() {
+        // 2026-03-06 is a Friday.
+        let nightly = fields(Some("01:00"), Some("05:00"), None).parse().unwrap().unwrap();
+        assert!(nightly.is_active_at(at("2026-03-06T01:00:00Z")));
+        assert!(nightly.is_active_at(at("2026-03-06T04:59:59Z")));
+        assert!(!nightly.is_active_at(at("2026-03-06T05:00:00Z")));
+        assert!(!nightly.is_active_at(at("2026-03-06T00:59:00Z")));
+
+        // Friday night into Saturday morning only.
+        let weekend = fields(Some("22:00"), Some("02:00"), Some("fri")).parse().unwrap().unwrap();
+        assert!(weekend.is_active_at(at("2026-03-06T23:30:00Z")));
+        assert!(weekend.is_active_at(at("2026-03-07T01:30:00Z")));
+        assert!(!weekend.is_active_at(at("2026-03-07T23:30:00Z")));
+        assert!(!weekend.is_active_at(at("2026-03-06T01:30:00Z")));
+
+        let weekdays = fields(None, None, Some("mon-fri")).parse().unwrap().unwrap();
+        assert!(weekdays.is_active_at(at("2026-03-06T12:00:00Z")));
+        assert!(!weekdays.is_active_at(at("2026-03-07T12:00:00Z")));
+    }
+
+    #[test]
+    fn test_parse_validates_and_canonicalises() {
+        assert_eq!(fields(None, None, None).parse(), Ok(None));
+        assert!(fields(Some("01:00"), None, None).parse().is_err());
+        assert!(fields(Some("24:00"), Some("05:00"), None).parse().is_err());
+        assert!(fields(Some("1am"), Some("05:00"), None).parse().is_err());
+        assert!(fields(Some("03:00"), Some("03:00"), None).parse().is_err());
+        assert!(fields(None, None, Some("funday")).parse().is_err());
+        assert!(fields(None, None, Some(",")).parse().is_err());
+
+        let schedule = fields(Some("1:5"), Some("05:00"), Some("Sat-Mon, wed")).parse().unwrap().unwrap();
+        assert_eq!(schedule.fields(), fields(Some("01:05"), Some("05:00"), Some("mon,wed,sat,sun")));
+        let every_day = fields(Some("01:00"), Some("05:00"), Some("mon-sun")).parse().unwrap().unwrap();
+        assert_eq!(every_day.fields().active_days, None);
+    }
+}
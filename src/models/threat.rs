@@ -87,6 +87,15 @@ pub enum ThreatReason {
     DistributedAttack,
     /// Request matched a user-defined custom rule.
     CustomRule,
+    /// Client kept requesting challenge pages without solving any.
+    ChallengeFlood,
+    /// Country/ASN is not on an active allowlist.
+    NotAllowlisted,
+    /// Request body's `Content-Encoding` was refused, or decoding it was
+    /// aborted.
+    EncodedBody,
+    /// Login attempt on a username tried from too many IPs or too often.
+    CredentialStuffing,
 }
 
 impl fmt::Display for ThreatReason {
@@ -108,6 +117,10 @@ impl fmt::Display for ThreatReason {
             ThreatReason::ManagedRule => write!(f, "managed_rule"),
             ThreatReason::DistributedAttack => write!(f, "distributed_attack"),
             ThreatReason::CustomRule => write!(f, "custom_rule"),
+            ThreatReason::ChallengeFlood => write!(f, "challenge_flood"),
+            ThreatReason::NotAllowlisted => write!(f, "not_allowlisted"),
+            ThreatReason::EncodedBody => write!(f, "encoded_body"),
+            ThreatReason::CredentialStuffing => write!(f, "credential_stuffing"),
         }
     }
 }
@@ -132,6 +145,10 @@ impl ThreatReason {
             "managed_rule" => Some(Self::ManagedRule),
             "distributed_attack" => Some(Self::DistributedAttack),
             "custom_rule" => Some(Self::CustomRule),
+            "challenge_flood" => Some(Self::ChallengeFlood),
+            "not_allowlisted" => Some(Self::NotAllowlisted),
+            "encoded_body" => Some(Self::EncodedBody),
+            "credential_stuffing" => Some(Self::CredentialStuffing),
             _ => None,
         }
     }
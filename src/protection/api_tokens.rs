@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, warn};
+
+use crate::models::request::RequestContext;
+use crate::storage::sqlite::{ApiTokenRow, SqliteStore};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Request header carrying a client API token.
+pub const TOKEN_HEADER: &str = "x-fortress-token";
+
+/// How often usage counters are written back to the database.
+const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// What a valid token lets a request skip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenAction {
+    /// Never challenged; blocklists, rules and rate limits still apply.
+    BypassChallenge,
+    /// Skips the protection pipeline (rate limits aside, unless exempt).
+    BypassPipeline,
+}
+
+impl TokenAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenAction::BypassChallenge => "bypass_challenge",
+            TokenAction::BypassPipeline => "bypass_pipeline",
+        }
+    }
+
+    pub fn from_str_name(s: &str) -> Option<Self> {
+        match s {
+            "bypass_challenge" => Some(TokenAction::BypassChallenge),
+            "bypass_pipeline" => Some(TokenAction::BypassPipeline),
+            _ => None,
+        }
+    }
+}
+
+/// Settings of a token, as minted or edited through the admin API.
+#[derive(Debug, Clone)]
+pub struct TokenSpec {
+    pub name: String,
+    /// Exact hosts or `*.suffix` wildcards; empty means any host.
+    pub allowed_hosts: Vec<String>,
+    /// Path prefixes; empty means any path.
+    pub allowed_paths: Vec<String>,
+    pub action: TokenAction,
+    pub exempt_rate_limit: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A loaded token with its live usage counters.
+struct ApiToken {
+    id: String,
+    secret: Vec<u8>,
+    spec: TokenSpec,
+    created_at: String,
+    requests: AtomicU64,
+    /// Unix seconds of the last accepted request, 0 if never used.
+    last_used: AtomicI64,
+}
+
+impl ApiToken {
+    fn from_row(row: ApiTokenRow) -> Option<Self> {
+        let action = TokenAction::from_str_name(&row.action)?;
+        let expires_at = match row.expires_at.as_deref() {
+            Some(value) => Some(parse_time(value)?),
+            None => None,
+        };
+        Some(Self {
+            spec: TokenSpec {
+                name: row.name,
+                allowed_hosts: serde_json::from_str(&row.allowed_hosts).unwrap_or_default(),
+                allowed_paths: serde_json::from_str(&row.allowed_paths).unwrap_or_default(),
+                action,
+                exempt_rate_limit: row.exempt_rate_limit,
+                expires_at,
+            },
+            id: row.id,
+            secret: row.secret,
+            created_at: row.created_at,
+            requests: AtomicU64::new(row.usage_count),
+            last_used: AtomicI64::new(row.last_used_at.as_deref().and_then(parse_time).map_or(0, |t| t.timestamp())),
+        })
+    }
+
+    fn row(&self) -> ApiTokenRow {
+        ApiTokenRow {
+            id: self.id.clone(),
+            name: self.spec.name.clone(),
+            secret: self.secret.clone(),
+            allowed_hosts: serde_json::to_string(&self.spec.allowed_hosts).unwrap_or_else(|_| "[]".to_string()),
+            allowed_paths: serde_json::to_string(&self.spec.allowed_paths).unwrap_or_else(|_| "[]".to_string()),
+            action: self.spec.action.as_str().to_string(),
+            exempt_rate_limit: self.spec.exempt_rate_limit,
+            expires_at: self.spec.expires_at.map(|t| t.format(TIME_FORMAT).to_string()),
+            created_at: self.created_at.clone(),
+            usage_count: self.requests.load(Ordering::Relaxed),
+            last_used_at: self.last_used_at().map(|t| t.format(TIME_FORMAT).to_string()),
+        }
+    }
+
+    fn last_used_at(&self) -> Option<DateTime<Utc>> {
+        match self.last_used.load(Ordering::Relaxed) {
+            0 => None,
+            secs => DateTime::from_timestamp(secs, 0),
+        }
+    }
+
+    fn in_scope(&self, host: &str, path: &str) -> bool {
+        let host = strip_port(host).to_ascii_lowercase();
+        let host_ok = self.spec.allowed_hosts.is_empty()
+            || self.spec.allowed_hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+                Some(suffix) => host
+                    .strip_suffix(suffix)
+                    .is_some_and(|rest| rest.ends_with('.') && rest.len() > 1),
+                None => *pattern == host,
+            });
+        let path_ok = self.spec.allowed_paths.is_empty() || self.spec.allowed_paths.iter().any(|p| path.starts_with(p.as_str()));
+        host_ok && path_ok
+    }
+
+    fn summary(&self) -> TokenSummary {
+        TokenSummary {
+            id: self.id.clone(),
+            name: self.spec.name.clone(),
+            allowed_hosts: self.spec.allowed_hosts.clone(),
+            allowed_paths: self.spec.allowed_paths.clone(),
+            action: self.spec.action,
+            exempt_rate_limit: self.spec.exempt_rate_limit,
+            expires_at: self.spec.expires_at,
+            created_at: self.created_at.clone(),
+            requests: self.requests.load(Ordering::Relaxed),
+            last_used_at: self.last_used_at(),
+        }
+    }
+}
+
+/// A token as shown by the admin API. The secret is never included.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenSummary {
+    pub id: String,
+    pub name: String,
+    pub allowed_hosts: Vec<String>,
+    pub allowed_paths: Vec<String>,
+    pub action: TokenAction,
+    pub exempt_rate_limit: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: String,
+    pub requests: u64,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A token accepted for a request.
+#[derive(Debug, Clone)]
+pub struct TokenGrant {
+    pub id: String,
+    pub action: TokenAction,
+    pub exempt_rate_limit: bool,
+}
+
+/// Signed client tokens that let known automated clients (monitoring,
+/// partner integrations) through the challenge layers.
+///
+/// A token is `<id>.<signature>`, the signature being the base64url
+/// HMAC-SHA256 of the id under a per-token secret kept in SQLite. Tokens
+/// are checked against the in-memory copy only, so revoking one takes
+/// effect on the next request.
+pub struct ApiTokenStore {
+    sqlite: Arc<SqliteStore>,
+    tokens: RwLock<HashMap<String, Arc<ApiToken>>>,
+}
+
+impl ApiTokenStore {
+    pub fn new(sqlite: Arc<SqliteStore>) -> Self {
+        Self {
+            sqlite,
+            tokens: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Load the tokens stored in the database.
+    pub async fn load(&self) {
+        match self.sqlite.get_api_tokens().await {
+            Ok(rows) => {
+                let mut tokens = HashMap::new();
+                for row in rows {
+                    let id = row.id.clone();
+                    match ApiToken::from_row(row) {
+                        Some(token) => {
+                            tokens.insert(id, Arc::new(token));
+                        }
+                        None => warn!(token_id = %id, "Skipping invalid API token"),
+                    }
+                }
+                *self.tokens.write() = tokens;
+            }
+            Err(e) => warn!(error = %e, "Failed to load API tokens from database"),
+        }
+    }
+
+    /// Periodically write usage counters back to the database.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(USAGE_FLUSH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut flushed: HashMap<String, u64> = HashMap::new();
+        loop {
+            interval.tick().await;
+            let usage: Vec<_> = self
+                .tokens
+                .read()
+                .values()
+                .map(|t| t.row())
+                .filter(|row| flushed.get(&row.id) != Some(&row.usage_count))
+                .map(|row| (row.id, row.usage_count, row.last_used_at))
+                .collect();
+            if usage.is_empty() {
+                continue;
+            }
+            let counts: Vec<_> = usage.iter().map(|(id, count, _)| (id.clone(), *count)).collect();
+            match self.sqlite.update_api_token_usage(usage).await {
+                Ok(()) => {
+                    flushed.extend(counts);
+                    let tokens = self.tokens.read();
+                    flushed.retain(|id, _| tokens.contains_key(id));
+                }
+                Err(e) => warn!(error = %e, "Failed to store API token usage"),
+            }
+        }
+    }
+
+    /// Mint a new token. Returns its summary and the token string, which is
+    /// not stored and cannot be shown again.
+    pub async fn mint(&self, spec: TokenSpec) -> rusqlite::Result<(TokenSummary, String)> {
+        let (id, secret) = {
+            let mut rng = rand::rng();
+            let secret: Vec<u8> = (0..32).map(|_| rng.random::<u8>()).collect();
+            (format!("{:016x}", rng.random::<u64>()), secret)
+        };
+        let token = ApiToken {
+            id: id.clone(),
+            secret,
+            spec,
+            created_at: Utc::now().format(TIME_FORMAT).to_string(),
+            requests: AtomicU64::new(0),
+            last_used: AtomicI64::new(0),
+        };
+        self.sqlite.insert_api_token(token.row()).await?;
+        let value = format!("{}.{}", id, sign(&token.secret, &id));
+        let summary = token.summary();
+        self.tokens.write().insert(id, Arc::new(token));
+        Ok((summary, value))
+    }
+
+    /// Replace the settings of a token, keeping its secret and usage.
+    /// Returns `None` if there is no such token.
+    pub async fn update(&self, id: &str, spec: TokenSpec) -> rusqlite::Result<Option<TokenSummary>> {
+        let Some(current) = self.tokens.read().get(id).cloned() else {
+            return Ok(None);
+        };
+        let token = ApiToken {
+            id: current.id.clone(),
+            secret: current.secret.clone(),
+            spec,
+            created_at: current.created_at.clone(),
+            requests: AtomicU64::new(current.requests.load(Ordering::Relaxed)),
+            last_used: AtomicI64::new(current.last_used.load(Ordering::Relaxed)),
+        };
+        if !self.sqlite.update_api_token(token.row()).await? {
+            return Ok(None);
+        }
+        let summary = token.summary();
+        self.tokens.write().insert(token.id.clone(), Arc::new(token));
+        Ok(Some(summary))
+    }
+
+    /// Revoke a token. It stops working before the database is updated.
+    pub async fn revoke(&self, id: &str) -> rusqlite::Result<bool> {
+        let removed = self.tokens.write().remove(id).is_some();
+        let deleted = self.sqlite.delete_api_token(id).await?;
+        Ok(removed || deleted)
+    }
+
+    pub fn list(&self) -> Vec<TokenSummary> {
+        let mut tokens: Vec<_> = self.tokens.read().values().map(|t| t.summary()).collect();
+        tokens.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        tokens
+    }
+
+    pub fn get(&self, id: &str) -> Option<TokenSummary> {
+        self.tokens.read().get(id).map(|t| t.summary())
+    }
+
+    /// Check the request's `X-Fortress-Token` header. A token is accepted
+    /// when its signature is valid, it has not expired and the request's
+    /// host and path are in its scope. `count` records the use.
+    pub fn verify(&self, ctx: &RequestContext, count: bool) -> Option<TokenGrant> {
+        let value = ctx.headers.get(TOKEN_HEADER)?;
+        let (id, signature) = value.trim().split_once('.')?;
+        let token = self.tokens.read().get(id).cloned()?;
+
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let mut mac = HmacSha256::new_from_slice(&token.secret).expect("HMAC accepts any key length");
+        mac.update(id.as_bytes());
+        if mac.verify_slice(&signature).is_err() {
+            debug!(ip = %ctx.client_ip, token_id = %id, "API token signature mismatch");
+            return None;
+        }
+        let now = Utc::now();
+        if token.spec.expires_at.is_some_and(|exp| exp <= now) {
+            debug!(ip = %ctx.client_ip, token_id = %id, "API token expired");
+            return None;
+        }
+        if !token.in_scope(&ctx.host, &ctx.path) {
+            debug!(ip = %ctx.client_ip, token_id = %id, host = %ctx.host, path = %ctx.path, "API token out of scope");
+            return None;
+        }
+
+        if count {
+            token.requests.fetch_add(1, Ordering::Relaxed);
+            token.last_used.store(now.timestamp(), Ordering::Relaxed);
+        }
+        Some(TokenGrant {
+            id: token.id.clone(),
+            action: token.spec.action,
+            exempt_rate_limit: token.spec.exempt_rate_limit,
+        })
+    }
+}
+
+fn sign(secret: &[u8], id: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(id.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(&format!("{} +0000", value), "%Y-%m-%d %H:%M:%S %z")
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// The host without a trailing `:port` (IPv6 literals keep their brackets).
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && !name.ends_with(':') && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
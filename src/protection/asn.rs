@@ -1,6 +1,20 @@
 use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-use crate::config::settings::AsnScoringConfig;
+use arc_swap::ArcSwapOption;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::settings::{AsnScoringConfig, SharedSettings};
+use crate::storage::feeds::{feed_client, load_feed, FeedClient, FeedLocation, FetchOutcome, Validators};
+use crate::storage::sqlite::{AsnOverrideRow, SqliteStore};
+
+/// How often the dataset refresher wakes up to check whether a reload is due.
+const DATASET_TICK: Duration = Duration::from_secs(30);
 
 /// ASN classification for datacenter, residential proxy, VPN, and mobile
 /// carrier identification.
@@ -8,11 +22,17 @@ use crate::config::settings::AsnScoringConfig;
 /// This module maintains curated sets of known ASNs for different network
 /// types. During request processing, the ASN classification helps determine
 /// the likelihood that traffic is automated or proxied.
+///
+/// The curated sets can be replaced by a dataset loaded from
+/// `asn_scoring.dataset`, and single ASNs pinned with manual overrides, which
+/// take precedence over both.
 pub struct AsnClassifier {
-    datacenter_asns: HashSet<u32>,
-    residential_proxy_asns: HashSet<u32>,
-    vpn_asns: HashSet<u32>,
-    mobile_carrier_asns: HashSet<u32>,
+    builtin: AsnDataset,
+    dataset: ArcSwapOption<AsnDataset>,
+    overrides: DashMap<u32, (AsnType, AsnOverrideRow)>,
+    status: Mutex<AsnDatasetStatus>,
+    validators: Mutex<Validators>,
+    client: OnceLock<FeedClient>,
 }
 
 /// Classification of an ASN's network type.
@@ -32,37 +52,181 @@ pub enum AsnType {
     Unknown,
 }
 
-impl AsnClassifier {
-    /// Create a new AsnClassifier with comprehensive known ASN databases.
-    pub fn new() -> Self {
-        let mut classifier = Self {
-            datacenter_asns: HashSet::new(),
-            residential_proxy_asns: HashSet::new(),
-            vpn_asns: HashSet::new(),
-            mobile_carrier_asns: HashSet::new(),
+impl AsnType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Residential => "residential",
+            Self::Datacenter => "datacenter",
+            Self::ResidentialProxy => "residential_proxy",
+            Self::VPN => "vpn",
+            Self::MobileCarrier => "mobile",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Parse a dataset or override category, accepting a few common aliases.
+    pub fn from_str_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "residential" | "isp" => Some(Self::Residential),
+            "datacenter" | "hosting" | "cloud" => Some(Self::Datacenter),
+            "residential_proxy" | "proxy" => Some(Self::ResidentialProxy),
+            "vpn" => Some(Self::VPN),
+            "mobile" | "mobile_carrier" => Some(Self::MobileCarrier),
+            "unknown" => Some(Self::Unknown),
+            _ => None,
+        }
+    }
+}
+
+/// State of the ASN dataset, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct AsnDatasetStatus {
+    /// Configured `asn_scoring.dataset`; empty when only the built-in lists
+    /// are used.
+    pub source: String,
+    /// `"dataset"` once a load succeeded, `"builtin"` until then.
+    pub active: &'static str,
+    pub entries: usize,
+    pub invalid_lines: usize,
+    pub last_refresh: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl AsnDatasetStatus {
+    fn builtin(source: &str, entries: usize) -> Self {
+        Self {
+            source: source.to_string(),
+            active: "builtin",
+            entries,
+            invalid_lines: 0,
+            last_refresh: None,
+            last_error: None,
+        }
+    }
+}
+
+/// One set of ASN classifications: the built-in lists or a loaded dataset.
+#[derive(Default)]
+struct AsnDataset {
+    datacenter_asns: HashSet<u32>,
+    residential_proxy_asns: HashSet<u32>,
+    vpn_asns: HashSet<u32>,
+    mobile_carrier_asns: HashSet<u32>,
+    residential_asns: HashSet<u32>,
+}
+
+impl AsnDataset {
+    fn builtin() -> Self {
+        let mut dataset = Self::default();
+        dataset.populate_known_asns();
+        dataset
+    }
+
+    /// Parse `asn,category` lines. Blank lines, `#` comments and a header
+    /// line are skipped; the ASN may carry an `AS` prefix. Returns the
+    /// dataset and the number of lines that could not be parsed.
+    fn parse_csv(text: &str) -> (Self, usize) {
+        let mut dataset = Self::default();
+        let mut invalid = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(',').map(str::trim);
+            let asn = fields.next().unwrap_or_default();
+            let category = fields.next().unwrap_or_default();
+            let asn = asn
+                .strip_prefix("AS")
+                .or_else(|| asn.strip_prefix("as"))
+                .unwrap_or(asn)
+                .parse::<u32>();
+            match (asn, AsnType::from_str_name(category)) {
+                (Ok(asn), Some(asn_type)) if asn_type != AsnType::Unknown => dataset.insert(asn, asn_type),
+                _ if i == 0 => {} // header
+                _ => invalid += 1,
+            }
+        }
+        (dataset, invalid)
+    }
+
+    fn insert(&mut self, asn: u32, asn_type: AsnType) {
+        let set = match asn_type {
+            AsnType::Datacenter => &mut self.datacenter_asns,
+            AsnType::ResidentialProxy => &mut self.residential_proxy_asns,
+            AsnType::VPN => &mut self.vpn_asns,
+            AsnType::MobileCarrier => &mut self.mobile_carrier_asns,
+            AsnType::Residential => &mut self.residential_asns,
+            AsnType::Unknown => return,
         };
-        classifier.populate_known_asns();
-        classifier
+        set.insert(asn);
     }
 
-    /// Classify an ASN into a network type.
-    pub fn classify(&self, asn: u32) -> AsnType {
+    fn len(&self) -> usize {
+        self.datacenter_asns.len()
+            + self.residential_proxy_asns.len()
+            + self.vpn_asns.len()
+            + self.mobile_carrier_asns.len()
+            + self.residential_asns.len()
+    }
+
+    fn classify(&self, asn: u32) -> Option<AsnType> {
         if self.residential_proxy_asns.contains(&asn) {
-            AsnType::ResidentialProxy
+            Some(AsnType::ResidentialProxy)
         } else if self.vpn_asns.contains(&asn) {
-            AsnType::VPN
+            Some(AsnType::VPN)
         } else if self.datacenter_asns.contains(&asn) {
-            AsnType::Datacenter
+            Some(AsnType::Datacenter)
         } else if self.mobile_carrier_asns.contains(&asn) {
-            AsnType::MobileCarrier
+            Some(AsnType::MobileCarrier)
+        } else if self.residential_asns.contains(&asn) {
+            Some(AsnType::Residential)
         } else {
-            AsnType::Unknown
+            None
+        }
+    }
+}
+
+impl AsnClassifier {
+    /// Create a new AsnClassifier with comprehensive known ASN databases.
+    pub fn new() -> Self {
+        let builtin = AsnDataset::builtin();
+        let status = AsnDatasetStatus::builtin("", builtin.len());
+        Self {
+            builtin,
+            dataset: ArcSwapOption::empty(),
+            overrides: DashMap::new(),
+            status: Mutex::new(status),
+            validators: Mutex::new(Validators::default()),
+            client: OnceLock::new(),
+        }
+    }
+
+    /// Classify an ASN into a network type.
+    pub fn classify(&self, asn: u32) -> AsnType {
+        self.lookup(asn).0
+    }
+
+    /// Classify an ASN and name where the classification came from:
+    /// `"override"`, `"dataset"`, `"builtin"`, or `"none"` when unclassified.
+    pub fn lookup(&self, asn: u32) -> (AsnType, &'static str) {
+        if let Some(entry) = self.overrides.get(&asn) {
+            return (entry.0.clone(), "override");
+        }
+        let dataset = self.dataset.load();
+        let (found, source) = match dataset.as_deref() {
+            Some(dataset) => (dataset.classify(asn), "dataset"),
+            None => (self.builtin.classify(asn), "builtin"),
+        };
+        match found {
+            Some(asn_type) => (asn_type, source),
+            None => (AsnType::Unknown, "none"),
         }
     }
 
     /// Check if an ASN is suspicious (datacenter or proxy).
     pub fn is_suspicious(&self, asn: u32) -> bool {
-        self.residential_proxy_asns.contains(&asn)
+        self.classify(asn) == AsnType::ResidentialProxy
     }
 
     /// Get a graduated suspicion score based on ASN type.
@@ -80,6 +244,122 @@ impl AsnClassifier {
         }
     }
 
+    pub fn dataset_status(&self) -> AsnDatasetStatus {
+        self.status.lock().clone()
+    }
+
+    /// Load `source` (see [`AsnScoringConfig::dataset`]) and swap it in.
+    /// A failed or empty load keeps the current classifications; an empty
+    /// `source` reverts to the built-in lists.
+    pub async fn refresh_dataset(&self, source: &str) -> AsnDatasetStatus {
+        let validators = {
+            let mut status = self.status.lock();
+            if status.source != source || source.is_empty() {
+                self.dataset.store(None);
+                *self.validators.lock() = Validators::default();
+                *status = AsnDatasetStatus::builtin(source, self.builtin.len());
+            }
+            if source.is_empty() {
+                return status.clone();
+            }
+            self.validators.lock().clone()
+        };
+
+        let client = self.client.get_or_init(feed_client);
+        let result = load_feed(client, &FeedLocation::parse(source), &validators).await;
+
+        let mut status = self.status.lock();
+        match result {
+            Ok(FetchOutcome::NotModified) => {
+                status.last_refresh = Some(Utc::now());
+                status.last_error = None;
+            }
+            Ok(FetchOutcome::Fetched { text, validators }) => {
+                let (dataset, invalid) = AsnDataset::parse_csv(&text);
+                // An empty dataset is far more likely a broken upstream than
+                // a real one; keep classifying with what we have.
+                if dataset.len() == 0 {
+                    warn!(source, invalid, "ASN dataset has no valid entries, keeping current classifications");
+                    status.last_error = Some(format!("no valid entries ({} invalid lines)", invalid));
+                } else {
+                    info!(source, entries = dataset.len(), invalid, "ASN dataset loaded");
+                    *status = AsnDatasetStatus {
+                        source: source.to_string(),
+                        active: "dataset",
+                        entries: dataset.len(),
+                        invalid_lines: invalid,
+                        last_refresh: Some(Utc::now()),
+                        last_error: None,
+                    };
+                    self.dataset.store(Some(Arc::new(dataset)));
+                    *self.validators.lock() = validators;
+                }
+            }
+            Err(e) => {
+                warn!(source, "ASN dataset load failed: {}", e);
+                status.last_error = Some(e);
+            }
+        }
+        status.clone()
+    }
+
+    pub async fn load_overrides(&self, sqlite: &SqliteStore) {
+        match sqlite.get_asn_overrides().await {
+            Ok(rows) => {
+                self.overrides.clear();
+                for row in rows {
+                    if let Err(e) = self.set_override(row) {
+                        warn!("Skipping ASN override: {}", e);
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to load ASN overrides: {}", e),
+        }
+    }
+
+    /// Pin `row.asn` to `row.category` until the override is removed.
+    pub fn set_override(&self, row: AsnOverrideRow) -> Result<(), String> {
+        let asn_type = AsnType::from_str_name(&row.category)
+            .ok_or_else(|| format!("AS{}: unknown category '{}'", row.asn, row.category))?;
+        self.overrides.insert(row.asn, (asn_type, row));
+        Ok(())
+    }
+
+    pub fn remove_override(&self, asn: u32) -> bool {
+        self.overrides.remove(&asn).is_some()
+    }
+
+    pub fn get_override(&self, asn: u32) -> Option<AsnOverrideRow> {
+        self.overrides.get(&asn).map(|entry| entry.1.clone())
+    }
+
+    pub fn overrides(&self) -> Vec<AsnOverrideRow> {
+        let mut rows: Vec<_> = self.overrides.iter().map(|entry| entry.1.clone()).collect();
+        rows.sort_by_key(|row| row.asn);
+        rows
+    }
+}
+
+/// Reload `asn_scoring.dataset` every `dataset_refresh_secs`, and right
+/// away when a config reload points it somewhere else.
+pub async fn run_asn_dataset(classifier: Arc<AsnClassifier>, settings: SharedSettings) {
+    let mut last: Option<(String, Instant)> = None;
+
+    loop {
+        let config = settings.load().asn_scoring.clone();
+        let interval = Duration::from_secs(config.dataset_refresh_secs.max(60));
+        let due = last
+            .as_ref()
+            .is_none_or(|(source, at)| *source != config.dataset || at.elapsed() >= interval);
+        if due {
+            classifier.refresh_dataset(&config.dataset).await;
+            last = Some((config.dataset, Instant::now()));
+        }
+        tokio::time::sleep(DATASET_TICK).await;
+    }
+}
+
+impl AsnDataset {
     /// Populate all known ASN databases.
     fn populate_known_asns(&mut self) {
         self.populate_datacenter_asns();
@@ -333,4 +613,33 @@ mod tests {
         assert!(classifier.is_suspicious(9009));   // Bright Data - proxy
         assert!(!classifier.is_suspicious(99999)); // Unknown - not suspicious
     }
+
+    #[tokio::test]
+    async fn test_dataset_replaces_builtin_lists_and_overrides_win() {
+        let path = std::env::temp_dir().join(format!("fortress-asn-{}.csv", std::process::id()));
+        std::fs::write(&path, "asn,category\nAS64500,vpn\n64501,hosting\nnot-an-asn,vpn\n").unwrap();
+        let classifier = AsnClassifier::new();
+
+        let status = classifier.refresh_dataset(path.to_str().unwrap()).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((status.active, status.entries, status.invalid_lines), ("dataset", 2, 1));
+        assert_eq!(classifier.lookup(64500), (AsnType::VPN, "dataset"));
+        assert_eq!(classifier.lookup(64501), (AsnType::Datacenter, "dataset"));
+        // The dataset replaces the built-in lists rather than extending them.
+        assert_eq!(classifier.lookup(14618), (AsnType::Unknown, "none"));
+
+        classifier
+            .set_override(AsnOverrideRow {
+                asn: 64500,
+                category: "residential".to_string(),
+                reason: None,
+                created_at: String::new(),
+            })
+            .unwrap();
+        assert_eq!(classifier.lookup(64500), (AsnType::Residential, "override"));
+        assert_eq!(classifier.suspicion_score(64500, &crate::config::defaults::default_asn_scoring_config()), 0.0);
+
+        classifier.refresh_dataset("").await;
+        assert_eq!(classifier.lookup(14618), (AsnType::Datacenter, "builtin"));
+    }
 }
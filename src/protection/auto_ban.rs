@@ -1,11 +1,24 @@
 use std::collections::VecDeque;
+use std::hash::Hash;
 use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
+use ipnet::IpNet;
 use tracing::{debug, info, warn};
 
-use crate::config::settings::AutoBanConfig;
+use crate::analytics::alerting::{AlertManager, Severity};
+use crate::analytics::events::{EventBus, EventType, SecurityEvent};
+use crate::config::settings::{AutoBanConfig, ProtectionConfig};
+use crate::models::threat::ThreatAction;
+use crate::protection::firewall::FirewallOffload;
+use crate::protection::geoip::GeoIpLookup;
+use crate::storage::blocklist::BlocklistManager;
+use crate::storage::cluster::{ClusterOp, ClusterSync};
+use crate::storage::memory::subnet_network;
+use crate::storage::sqlite::SqliteStore;
+use crate::storage::sweep::{Sweep, SweepStats};
 
 // ---------------------------------------------------------------------------
 // Types
@@ -57,6 +70,32 @@ impl IpBlockHistory {
     }
 }
 
+/// Recently banned IPs per ASN or country, oldest first.
+type BanGroups<K> = DashMap<K, VecDeque<(Instant, IpAddr)>>;
+
+/// Record a new ban of `ip` under `key` and return how many distinct IPs
+/// were banned under it within `window`.
+fn record_group_ban<K: Eq + Hash>(groups: &BanGroups<K>, key: K, ip: IpAddr, window: Duration) -> usize {
+    let now = Instant::now();
+    let mut bans = groups.entry(key).or_default();
+    while bans.front().is_some_and(|(at, _)| now.duration_since(*at) >= window) {
+        bans.pop_front();
+    }
+    bans.retain(|(_, banned)| *banned != ip);
+    bans.push_back((now, ip));
+    bans.len()
+}
+
+/// Escalation action from config: anything but `block` or `tarpit`
+/// challenges.
+fn escalation_action(configured: &str) -> ThreatAction {
+    match configured {
+        "block" => ThreatAction::Block,
+        "tarpit" => ThreatAction::Tarpit,
+        _ => ThreatAction::Challenge,
+    }
+}
+
 // ---------------------------------------------------------------------------
 // AutoBanManager
 // ---------------------------------------------------------------------------
@@ -67,12 +106,44 @@ pub struct AutoBanManager {
     /// Block history per IP (for determining when to ban)
     history: DashMap<IpAddr, IpBlockHistory>,
     /// Track which subnets have bans (for NAT-aware subnet banning)
-    subnet_bans: DashMap<String, u32>,
+    subnet_bans: DashMap<IpNet, u32>,
     config: AutoBanConfig,
+    /// `protection.ipv4_subnet_mask` / `ipv6_subnet_mask` at startup.
+    ipv4_subnet_mask: u8,
+    ipv6_subnet_mask: u8,
+    /// Ban, unban and expiry events are written to the audit log.
+    sqlite: Arc<SqliteStore>,
+    /// New bans and manual unbans are replicated to cluster peers.
+    cluster: Arc<ClusterSync>,
+    /// Alerts when a subnet accumulates `alerting.subnet_ban_threshold` bans.
+    alerting: Arc<AlertManager>,
+    /// Bans per ASN and country, for escalating to a temporary ASN or
+    /// country entry on `blocklist`.
+    asn_bans: BanGroups<u32>,
+    country_bans: BanGroups<String>,
+    geoip: Arc<GeoIpLookup>,
+    blocklist: Arc<BlocklistManager>,
+    events: Arc<EventBus>,
+    /// Bans are mirrored into the kernel firewall set, if enabled.
+    firewall: Arc<FirewallOffload>,
+    bans_sweep: Sweep,
+    history_sweep: Sweep,
+    subnet_sweep: Sweep,
 }
 
 impl AutoBanManager {
-    pub fn new(config: &AutoBanConfig) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &AutoBanConfig,
+        protection: &ProtectionConfig,
+        sqlite: Arc<SqliteStore>,
+        cluster: Arc<ClusterSync>,
+        alerting: Arc<AlertManager>,
+        geoip: Arc<GeoIpLookup>,
+        blocklist: Arc<BlocklistManager>,
+        events: Arc<EventBus>,
+        firewall: Arc<FirewallOffload>,
+    ) -> Self {
         info!(
             "Auto-ban system initialized (enabled={}, 5m_threshold={}, 15m_threshold={}, 1h_threshold={})",
             config.enabled, config.ban_threshold_5m, config.ban_threshold_15m, config.ban_threshold_1h
@@ -82,6 +153,20 @@ impl AutoBanManager {
             history: DashMap::with_capacity(10_000),
             subnet_bans: DashMap::new(),
             config: config.clone(),
+            ipv4_subnet_mask: protection.ipv4_subnet_mask,
+            ipv6_subnet_mask: protection.ipv6_subnet_mask,
+            sqlite,
+            cluster,
+            alerting,
+            asn_bans: DashMap::new(),
+            country_bans: DashMap::new(),
+            geoip,
+            blocklist,
+            events,
+            firewall,
+            bans_sweep: Sweep::new(),
+            history_sweep: Sweep::new(),
+            subnet_sweep: Sweep::new(),
         }
     }
 
@@ -150,40 +235,206 @@ impl AutoBanManager {
             history.ban_count += 1;
             drop(history); // Release the lock before inserting ban
 
-            // Create ban
-            self.bans.insert(*ip, BanEntry {
-                banned_at: Instant::now(),
-                duration,
-                reason: reason.clone(),
-                block_count: blocks_5m.max(blocks_15m).max(blocks_1h),
+            self.insert_ban(ip, duration, reason.clone(), blocks_5m.max(blocks_15m).max(blocks_1h), "auto_ban");
+            self.cluster.publish(ClusterOp::Ban {
+                ip: *ip,
+                reason,
+                duration_secs: duration.as_secs(),
             });
+            return true;
+        }
 
-            // Track subnet for NAT-aware banning
-            let subnet = ip_to_subnet_str(ip);
-            let mut count = self.subnet_bans.entry(subnet).or_insert(0);
-            *count += 1;
+        false
+    }
 
-            info!(
-                ip = %ip,
-                duration_secs = duration.as_secs(),
-                reason = %reason,
-                "Auto-banned IP"
-            );
+    /// Ban an IP immediately, bypassing the block-count thresholds. Used by
+    /// detectors that have already established the IP is a repeat offender
+    /// (e.g. slowloris). Repeat offenders get the 24-hour ban, everyone else
+    /// 30 minutes. Returns true if a new ban was created.
+    pub fn ban(&self, ip: &IpAddr, reason: &str, violations: u32) -> bool {
+        if !self.config.enabled || self.is_banned(ip).is_some() {
+            return false;
+        }
 
-            return true;
+        let mut history = self.history.entry(*ip).or_insert_with(IpBlockHistory::new);
+        let duration = if history.ban_count >= self.config.repeat_ban_threshold {
+            Duration::from_secs(86400)
+        } else {
+            Duration::from_secs(1800)
+        };
+        history.ban_count += 1;
+        // Keep the history entry alive through cleanup() so the ban count
+        // still counts towards the repeat-offender threshold.
+        history.blocks.push_back(BlockRecord {
+            timestamp: Instant::now(),
+        });
+        drop(history);
+
+        self.insert_ban(ip, duration, reason.to_string(), violations, "auto_ban");
+        self.cluster.publish(ClusterOp::Ban {
+            ip: *ip,
+            reason: reason.to_string(),
+            duration_secs: duration.as_secs(),
+        });
+        true
+    }
+
+    /// Install a ban replicated from a cluster peer. Not re-published.
+    pub fn apply_remote_ban(&self, ip: &IpAddr, reason: &str, duration: Duration, actor: &str) {
+        if !self.config.enabled {
+            return;
         }
+        self.insert_ban(ip, duration, reason.to_string(), 0, actor);
+    }
 
-        false
+    fn insert_ban(&self, ip: &IpAddr, duration: Duration, reason: String, block_count: u32, actor: &str) {
+        let previous = self.bans.insert(*ip, BanEntry {
+            banned_at: Instant::now(),
+            duration,
+            reason: reason.clone(),
+            block_count,
+        });
+        self.firewall.add(*ip, duration);
+
+        // Track subnet for NAT-aware banning
+        if previous.is_none() {
+            let subnet = self.subnet_of(ip);
+            let count = {
+                let mut entry = self.subnet_bans.entry(subnet).or_insert(0);
+                *entry += 1;
+                *entry
+            };
+            let threshold = self.alerting.subnet_ban_threshold();
+            if threshold > 0 && count == threshold {
+                let msg = format!("{} IPs in subnet {} have been auto-banned", count, subnet);
+                let key = format!("subnet_ban:{}", subnet);
+                self.alerting.notify("subnet_ban", &key, Severity::Warning, msg);
+            }
+            self.escalate(ip);
+            if self.events.is_active() {
+                self.events.publish(SecurityEvent {
+                    ip: Some(*ip),
+                    country: self.geoip.lookup_country(*ip),
+                    asn: self.geoip.lookup_asn(*ip).map(|(asn, _)| asn),
+                    reason: Some(reason.clone()),
+                    ..SecurityEvent::new(EventType::AutoBan, "ban")
+                });
+            }
+        }
+
+        info!(
+            ip = %ip,
+            duration_secs = duration.as_secs(),
+            reason = %reason,
+            "Auto-banned IP"
+        );
+        self.sqlite.audit(
+            actor,
+            "ban",
+            "ip",
+            &ip.to_string(),
+            Some(&format!("{} ({}s)", reason, duration.as_secs())),
+        );
     }
 
-    /// Remove a ban manually (for admin API).
-    pub fn unban(&self, ip: &IpAddr) -> bool {
+    /// Count a new ban against the IP's ASN and country and, once either
+    /// reaches its threshold within `escalation_window_secs`, list it on
+    /// the blocklist for its TTL. The group's count then starts over.
+    fn escalate(&self, ip: &IpAddr) {
+        let config = &self.config;
+        let window = Duration::from_secs(config.escalation_window_secs);
+
+        if config.asn_escalation_enabled && config.asn_ban_ip_threshold > 0 {
+            if let Some((asn, name)) = self.geoip.lookup_asn(*ip) {
+                let count = record_group_ban(&self.asn_bans, asn, *ip, window);
+                if count >= config.asn_ban_ip_threshold as usize {
+                    self.asn_bans.remove(&asn);
+                    let blocklist = self.blocklist.clone();
+                    let alerting = self.alerting.clone();
+                    let action = escalation_action(&config.asn_ban_action);
+                    let ttl = Duration::from_secs(config.asn_ban_ttl_secs);
+                    let reason = format!("{} IPs auto-banned within {}s", count, window.as_secs());
+                    tokio::spawn(async move {
+                        match blocklist.escalate_asn(asn, action, &reason, ttl).await {
+                            Ok(Some(_)) => {
+                                let msg = format!(
+                                    "ASN {} ({}) set to {} for {}s: {}",
+                                    asn, name, action, ttl.as_secs(), reason
+                                );
+                                warn!("{}", msg);
+                                alerting.notify("asn_escalation", &format!("asn_escalation:{}", asn), Severity::Warning, msg);
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("Failed to escalate ASN {}: {}", asn, e),
+                        }
+                    });
+                }
+            }
+        }
+
+        if config.country_escalation_enabled && config.country_ban_ip_threshold > 0 {
+            if let Some(country) = self.geoip.lookup_country(*ip) {
+                let count = record_group_ban(&self.country_bans, country.clone(), *ip, window);
+                if count >= config.country_ban_ip_threshold as usize {
+                    self.country_bans.remove(&country);
+                    let blocklist = self.blocklist.clone();
+                    let alerting = self.alerting.clone();
+                    let action = escalation_action(&config.country_ban_action);
+                    let ttl = Duration::from_secs(config.country_ban_ttl_secs);
+                    let reason = format!("{} IPs auto-banned within {}s", count, window.as_secs());
+                    tokio::spawn(async move {
+                        match blocklist.escalate_country(&country, action, &reason, ttl).await {
+                            Ok(Some(_)) => {
+                                let msg = format!(
+                                    "Country {} set to {} for {}s: {}",
+                                    country, action, ttl.as_secs(), reason
+                                );
+                                warn!("{}", msg);
+                                let key = format!("country_escalation:{}", country);
+                                alerting.notify("country_escalation", &key, Severity::Warning, msg);
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("Failed to escalate country {}: {}", country, e),
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Remove a ban manually (for admin API). `actor` is recorded in the
+    /// audit log and the unban is replicated to cluster peers.
+    pub fn unban(&self, ip: &IpAddr, actor: &str) -> bool {
+        if self.remove_ban(ip, actor) {
+            self.cluster.publish(ClusterOp::Unban { ip: *ip });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove every ban within `net` (for admin API), e.g. a subnet caught
+    /// as collateral damage. Each unban is audited and replicated as with
+    /// [`unban`](Self::unban). Returns the unbanned IPs.
+    pub fn unban_subnet(&self, net: &IpNet, actor: &str) -> Vec<IpAddr> {
+        let ips: Vec<IpAddr> = self
+            .bans
+            .iter()
+            .map(|e| *e.key())
+            .filter(|ip| net.contains(ip))
+            .collect();
+        ips.into_iter().filter(|ip| self.unban(ip, actor)).collect()
+    }
+
+    /// Remove a ban without replicating it.
+    pub fn remove_ban(&self, ip: &IpAddr, actor: &str) -> bool {
         if self.bans.remove(ip).is_some() {
-            let subnet = ip_to_subnet_str(ip);
-            if let Some(mut count) = self.subnet_bans.get_mut(&subnet) {
+            if let Some(mut count) = self.subnet_bans.get_mut(&self.subnet_of(ip)) {
                 *count = count.saturating_sub(1);
             }
-            info!(ip = %ip, "Manually unbanned IP");
+            self.firewall.remove(ip);
+            info!(ip = %ip, actor = %actor, "Unbanned IP");
+            self.sqlite.audit(actor, "unban", "ip", &ip.to_string(), None);
             true
         } else {
             false
@@ -220,26 +471,33 @@ impl AutoBanManager {
         }).count()
     }
 
-    /// Cleanup expired bans and old history.
-    pub fn cleanup(&self) {
+    /// Cleanup expired bans and old history, visiting about `budget`
+    /// entries of each per-IP map.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
         let now = Instant::now();
 
         // Remove expired bans
-        self.bans.retain(|ip, entry| {
+        let mut expired_bans = Vec::new();
+        let mut stats = self.bans_sweep.retain(&self.bans, budget, |ip, entry| {
             let expired = now.duration_since(entry.banned_at) >= entry.duration;
             if expired {
                 debug!(ip = %ip, "Auto-ban expired");
-                let subnet = ip_to_subnet_str(ip);
-                if let Some(mut count) = self.subnet_bans.get_mut(&subnet) {
+                expired_bans.push((*ip, entry.reason.clone()));
+                if let Some(mut count) = self.subnet_bans.get_mut(&self.subnet_of(ip)) {
                     *count = count.saturating_sub(1);
                 }
             }
             !expired
         });
+        // Audit outside `retain` so the map shards aren't held during the write.
+        for (ip, reason) in expired_bans {
+            self.sqlite
+                .audit("auto_ban", "ban_expired", "ip", &ip.to_string(), Some(&reason));
+        }
 
         // Remove old history entries (no blocks in 2 hours)
         let stale = Duration::from_secs(7200);
-        self.history.retain(|_, h| {
+        stats += self.history_sweep.retain(&self.history, budget, |_, h| {
             if let Some(last) = h.blocks.back() {
                 now.duration_since(last.timestamp) < stale
             } else {
@@ -248,20 +506,38 @@ impl AutoBanManager {
         });
 
         // Cleanup subnet counters
-        self.subnet_bans.retain(|_, count| *count > 0);
+        stats += self.subnet_sweep.retain(&self.subnet_bans, budget, |_, count| *count > 0);
+
+        // Forget ASN/country bans that fell out of the escalation window;
+        // there are few enough of those to sweep in one go
+        let window = Duration::from_secs(self.config.escalation_window_secs);
+        self.asn_bans.retain(|_, bans| bans.back().is_some_and(|(at, _)| now.duration_since(*at) < window));
+        self.country_bans.retain(|_, bans| bans.back().is_some_and(|(at, _)| now.duration_since(*at) < window));
+        stats
+    }
+    /// The subnet an IP's ban is counted against for subnet alerts.
+    fn subnet_of(&self, ip: &IpAddr) -> IpNet {
+        subnet_network(*ip, self.ipv4_subnet_mask, self.ipv6_subnet_mask)
     }
 }
 
-/// Convert an IP to its /24 (IPv4) or /48 (IPv6) subnet string.
-fn ip_to_subnet_str(ip: &IpAddr) -> String {
-    match ip {
-        IpAddr::V4(v4) => {
-            let o = v4.octets();
-            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
-        }
-        IpAddr::V6(v6) => {
-            let s = v6.segments();
-            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_bans_count_distinct_ips_within_the_window() {
+        let groups: BanGroups<u32> = DashMap::new();
+        let window = Duration::from_secs(600);
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+
+        assert_eq!(record_group_ban(&groups, 64500, a, window), 1);
+        assert_eq!(record_group_ban(&groups, 64500, a, window), 1);
+        assert_eq!(record_group_ban(&groups, 64500, b, window), 2);
+        assert_eq!(record_group_ban(&groups, 64501, b, window), 1);
+
+        // Bans older than the window no longer count.
+        groups.get_mut(&64500).unwrap().front_mut().unwrap().0 -= window;
+        assert_eq!(record_group_ban(&groups, 64500, "192.0.2.3".parse().unwrap(), window), 2);
     }
 }
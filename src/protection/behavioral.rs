@@ -40,4 +40,17 @@ impl BehavioralAnalyzer {
 
         composite
     }
+
+    /// The score [`analyze`](Self::analyze) would return, without updating
+    /// the client's profile.
+    pub fn peek(&self, ctx: &RequestContext) -> f64 {
+        let raw_score = self.memory.peek_behavior(
+            ctx.client_ip,
+            &ctx.path,
+            &ctx.method,
+            ctx.ja3_hash.as_deref(),
+            ctx.user_agent.as_deref(),
+        );
+        (raw_score * 100.0).clamp(0.0, 100.0)
+    }
 }
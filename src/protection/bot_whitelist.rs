@@ -1,198 +1,307 @@
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
 use std::time::{Duration, Instant};
 
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::config::settings::BotWhitelistConfig;
+use crate::storage::ip_ranges::{parse_ip_or_cidr, IpRangeMap};
+use crate::storage::sweep::{Sweep, SweepStats};
 
-/// Known-good search engine bot whitelist.
+/// What the bot whitelist makes of a request's user agent and IP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotVerdict {
+    /// The user agent claims no known crawler.
+    NotCrawler,
+    /// A known crawler, confirmed by a published range or reverse DNS (or
+    /// trusted on its user agent when `verify_ip` is off).
+    Verified(String),
+    /// Claims a crawler whose IP has not been verified yet.
+    Pending(String),
+    /// Claims a crawler but the IP failed verification.
+    Spoofed(String),
+}
+
+/// Reverse-DNS verification state for one (IP, crawler) pair.
+#[derive(Debug, Clone, Copy)]
+enum Lookup {
+    Pending,
+    Verified(Instant),
+    Spoofed(Instant),
+    /// DNS did not answer; retried after [`RETRY_FAILED_AFTER`].
+    Failed(Instant),
+}
+
+struct Crawler {
+    name: String,
+    /// Lowercase User-Agent substrings.
+    user_agents: Vec<String>,
+    /// Lowercase domains without surrounding dots.
+    rdns_suffixes: Arc<[String]>,
+}
+
+/// How soon a verification that DNS could not answer is tried again.
+const RETRY_FAILED_AFTER: Duration = Duration::from_secs(60);
+
+/// Known-good crawler whitelist.
 ///
 /// Prevents false positives by allowing verified search engine crawlers
 /// to bypass the protection pipeline entirely (after blocklist checks).
+/// A crawler is verified by its published IP ranges or by forward-confirmed
+/// reverse DNS: the IP's PTR name must end in one of the crawler's domains
+/// and resolve back to the IP. DNS runs in the background, so requests are
+/// never held up by it; until a verdict is cached the crawler is `Pending`.
 pub struct BotWhitelist {
     enabled: bool,
     verify_ip: bool,
-    /// Cache of verified bot IPs: IP -> (bot_name, verified_at)
-    verified_cache: DashMap<IpAddr, (String, Instant)>,
+    crawlers: Vec<Crawler>,
+    /// Published ranges -> index into `crawlers`
+    ranges: IpRangeMap<usize>,
+    /// (IP, index into `crawlers`) -> verification state
+    lookups: Arc<DashMap<(IpAddr, usize), Lookup>>,
+    lookups_sweep: Sweep,
+    in_flight: Arc<AtomicUsize>,
+    cache_ttl: Duration,
+    dns_timeout: Duration,
+    max_lookups: usize,
 }
 
-struct KnownBot {
-    name: &'static str,
-    ua_contains: &'static str,
-    dns_suffixes: &'static [&'static str],
-}
-
-const KNOWN_BOTS: &[KnownBot] = &[
-    KnownBot {
-        name: "Googlebot",
-        ua_contains: "googlebot",
-        dns_suffixes: &[".googlebot.com.", ".google.com."],
-    },
-    KnownBot {
-        name: "Bingbot",
-        ua_contains: "bingbot",
-        dns_suffixes: &[".search.msn.com."],
-    },
-    KnownBot {
-        name: "YandexBot",
-        ua_contains: "yandexbot",
-        dns_suffixes: &[".yandex.ru.", ".yandex.net.", ".yandex.com."],
-    },
-    KnownBot {
-        name: "Baiduspider",
-        ua_contains: "baiduspider",
-        dns_suffixes: &[".baidu.com.", ".baidu.jp."],
-    },
-    KnownBot {
-        name: "DuckDuckBot",
-        ua_contains: "duckduckbot",
-        dns_suffixes: &[".duckduckgo.com."],
-    },
-    KnownBot {
-        name: "Slurp",
-        ua_contains: "slurp",
-        dns_suffixes: &[".crawl.yahoo.net."],
-    },
-    KnownBot {
-        name: "Applebot",
-        ua_contains: "applebot",
-        dns_suffixes: &[".apple.com."],
-    },
-    KnownBot {
-        name: "AhrefsBot",
-        ua_contains: "ahrefsbot",
-        dns_suffixes: &[".ahrefs.com."],
-    },
-];
-
-/// Cache entry TTL: 1 hour
-const CACHE_TTL: Duration = Duration::from_secs(3600);
-
 impl BotWhitelist {
     pub fn new(config: &BotWhitelistConfig) -> Self {
+        let normalize = |s: &String| s.trim().trim_matches('.').to_ascii_lowercase();
+        let mut ranges = IpRangeMap::new();
+        let mut crawlers = Vec::with_capacity(config.crawlers.len());
+        for (idx, crawler) in config.crawlers.iter().enumerate() {
+            for range in &crawler.ip_ranges {
+                match parse_ip_or_cidr(range) {
+                    Some(net) => ranges.insert(net, idx),
+                    None => warn!(crawler = %crawler.name, range = %range, "Ignoring invalid crawler IP range"),
+                }
+            }
+            crawlers.push(Crawler {
+                name: crawler.name.clone(),
+                user_agents: crawler.user_agents.iter().map(normalize).filter(|s| !s.is_empty()).collect(),
+                rdns_suffixes: crawler.rdns_suffixes.iter().map(normalize).filter(|s| !s.is_empty()).collect(),
+            });
+        }
+
         Self {
             enabled: config.enabled,
             verify_ip: config.verify_ip,
-            verified_cache: DashMap::new(),
+            crawlers,
+            ranges,
+            lookups: Arc::new(DashMap::new()),
+            lookups_sweep: Sweep::new(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            cache_ttl: Duration::from_secs(config.cache_ttl_secs),
+            dns_timeout: Duration::from_millis(config.dns_timeout_ms.max(1)),
+            max_lookups: config.max_pending_lookups,
         }
     }
 
-    /// Check if the request is from a known search engine bot.
-    /// Returns Some(bot_name) if whitelisted, None otherwise.
-    pub fn check(&self, ua: Option<&str>, ip: &IpAddr) -> Option<String> {
+    /// Match the request against the known crawlers, starting a background
+    /// verification of `ip` if none is cached.
+    pub fn check(&self, ua: Option<&str>, ip: &IpAddr) -> BotVerdict {
+        self.identify(ua, ip, true)
+    }
+
+    /// What [`check`](Self::check) would return, without starting a
+    /// verification.
+    pub fn peek(&self, ua: Option<&str>, ip: &IpAddr) -> BotVerdict {
+        self.identify(ua, ip, false)
+    }
+
+    fn identify(&self, ua: Option<&str>, ip: &IpAddr, verify: bool) -> BotVerdict {
         if !self.enabled {
-            return None;
+            return BotVerdict::NotCrawler;
         }
+        let Some(ua) = ua else {
+            return BotVerdict::NotCrawler;
+        };
+        let ua = ua.to_lowercase();
+        let Some(idx) = self
+            .crawlers
+            .iter()
+            .position(|c| c.user_agents.iter().any(|pattern| ua.contains(pattern.as_str())))
+        else {
+            return BotVerdict::NotCrawler;
+        };
+        let crawler = &self.crawlers[idx];
+        let name = crawler.name.clone();
 
-        let ua_str = ua?;
-        let ua_lower = ua_str.to_lowercase();
+        if !self.verify_ip || self.ranges.lookup(ip).is_some_and(|(_, owner)| *owner == idx) {
+            return BotVerdict::Verified(name);
+        }
+        if crawler.rdns_suffixes.is_empty() {
+            // Only published ranges count, and the IP is not in them.
+            return BotVerdict::Spoofed(name);
+        }
 
-        // Check cache first
-        if let Some(entry) = self.verified_cache.get(ip) {
-            let (ref name, verified_at) = *entry.value();
-            if verified_at.elapsed() < CACHE_TTL {
-                return Some(name.clone());
-            }
-            // Cache expired, remove and re-check
-            drop(entry);
-            self.verified_cache.remove(ip);
+        let key = (*ip, idx);
+        match self.lookups.get(&key).map(|entry| *entry) {
+            Some(Lookup::Verified(at)) if at.elapsed() < self.cache_ttl => return BotVerdict::Verified(name),
+            Some(Lookup::Spoofed(at)) if at.elapsed() < self.cache_ttl => return BotVerdict::Spoofed(name),
+            Some(Lookup::Pending) => return BotVerdict::Pending(name),
+            Some(Lookup::Failed(at)) if at.elapsed() < RETRY_FAILED_AFTER => return BotVerdict::Pending(name),
+            _ => {}
+        }
+        if verify {
+            self.start_lookup(key);
         }
+        BotVerdict::Pending(name)
+    }
 
-        // Find matching bot by UA
-        for bot in KNOWN_BOTS {
-            if ua_lower.contains(bot.ua_contains) {
-                if self.verify_ip {
-                    // Attempt reverse DNS verification
-                    if self.verify_bot_ip(ip, bot.dns_suffixes) {
-                        let name = bot.name.to_string();
-                        self.verified_cache
-                            .insert(*ip, (name.clone(), Instant::now()));
-                        debug!(ip = %ip, bot = bot.name, "Bot verified via reverse DNS");
-                        return Some(name);
-                    }
-                    // IP verification failed - UA claims bot but IP doesn't match
-                    debug!(ip = %ip, bot = bot.name, "Bot UA claimed but IP verification failed");
-                    return None;
-                } else {
-                    // Trust UA without IP verification
-                    let name = bot.name.to_string();
-                    self.verified_cache
-                        .insert(*ip, (name.clone(), Instant::now()));
-                    return Some(name);
+    /// Verify `key` on the runtime unless a verification is already in
+    /// flight or the concurrency limit is reached.
+    fn start_lookup(&self, key: (IpAddr, usize)) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if self.in_flight.load(Ordering::Relaxed) >= self.max_lookups {
+            debug!(ip = %key.0, "Crawler verification limit reached, staying pending");
+            return;
+        }
+        match self.lookups.entry(key) {
+            Entry::Occupied(mut entry) => {
+                if matches!(entry.get(), Lookup::Pending) {
+                    return;
                 }
+                entry.insert(Lookup::Pending);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Lookup::Pending);
             }
         }
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
 
-        None
+        let lookups = self.lookups.clone();
+        let in_flight = self.in_flight.clone();
+        let suffixes = self.crawlers[key.1].rdns_suffixes.clone();
+        let name = self.crawlers[key.1].name.clone();
+        let timeout = self.dns_timeout;
+        runtime.spawn(async move {
+            let result = tokio::time::timeout(timeout, forward_confirmed_rdns(key.0, &suffixes)).await;
+            let now = Instant::now();
+            let (state, outcome) = match result {
+                Ok(Some(true)) => (Lookup::Verified(now), "verified"),
+                Ok(Some(false)) => (Lookup::Spoofed(now), "spoofed"),
+                Ok(None) => (Lookup::Failed(now), "no answer"),
+                Err(_) => (Lookup::Failed(now), "timed out"),
+            };
+            debug!(ip = %key.0, bot = %name, outcome, "Crawler reverse DNS verification finished");
+            lookups.insert(key, state);
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
     }
 
-    /// Verify a bot IP via reverse DNS lookup + forward verification.
-    fn verify_bot_ip(&self, ip: &IpAddr, valid_suffixes: &[&str]) -> bool {
-        use std::net::ToSocketAddrs;
-
-        // Reverse DNS lookup: convert IP to hostname
-        let hostname = match dns_lookup_reverse(ip) {
-            Some(h) => h,
-            None => return false, // DNS lookup failed, fail-open = don't whitelist
-        };
-
-        // Check if hostname ends with one of the valid suffixes
-        let hostname_lower = hostname.to_lowercase();
-        let suffix_match = valid_suffixes
-            .iter()
-            .any(|suffix| hostname_lower.ends_with(suffix) || hostname_lower.ends_with(&suffix[..suffix.len()-1]));
-
-        if !suffix_match {
-            return false;
-        }
+    /// Cleanup expired cache entries, visiting about `budget` of them.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
+        let ttl = self.cache_ttl;
+        self.lookups_sweep.retain(&self.lookups, budget, |_, state| match *state {
+            Lookup::Pending => true,
+            Lookup::Verified(at) | Lookup::Spoofed(at) => at.elapsed() < ttl,
+            Lookup::Failed(at) => at.elapsed() < RETRY_FAILED_AFTER,
+        })
+    }
+}
 
-        // Forward verification: resolve hostname back to IP
-        match (hostname.as_str(), 0u16).to_socket_addrs() {
-            Ok(addrs) => addrs.into_iter().any(|addr| &addr.ip() == ip),
-            Err(_) => false,
+/// Forward-confirmed reverse DNS: whether a PTR name of `ip` ends in one of
+/// `suffixes` and resolves back to `ip`. `None` if DNS did not answer.
+async fn forward_confirmed_rdns(ip: IpAddr, suffixes: &[String]) -> Option<bool> {
+    let hostnames = reverse_lookup(ip).await?;
+    let mut unanswered = false;
+    for hostname in hostnames.iter().filter(|h| has_suffix(h, suffixes)) {
+        match tokio::net::lookup_host((hostname.as_str(), 0)).await {
+            Ok(mut addrs) => {
+                if addrs.any(|addr| addr.ip() == ip) {
+                    return Some(true);
+                }
+            }
+            Err(_) => unanswered = true,
         }
     }
+    (!unanswered).then_some(false)
+}
 
-    /// Cleanup expired cache entries.
-    pub fn cleanup(&self) {
-        self.verified_cache
-            .retain(|_, (_, verified_at)| verified_at.elapsed() < CACHE_TTL);
-    }
+/// Whether `hostname` is one of `suffixes` or a subdomain of one.
+fn has_suffix(hostname: &str, suffixes: &[String]) -> bool {
+    suffixes.iter().any(|suffix| {
+        hostname == suffix
+            || hostname
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|rest| rest.ends_with('.'))
+    })
 }
 
-/// Perform reverse DNS lookup using system dig command.
-/// This function uses blocking I/O and should be called from
-/// within a `tokio::task::spawn_blocking` context.
-fn dns_lookup_reverse(ip: &IpAddr) -> Option<String> {
-    use std::net::SocketAddr;
-
-    let _socket_addr = SocketAddr::new(*ip, 0);
-
-    // Use tokio::process::Command for non-blocking DNS lookup to avoid
-    // blocking the async runtime. Since this is called from a sync context,
-    // we wrap the caller in spawn_blocking instead.
-    let output = std::process::Command::new("dig")
-        .args(&[
-            "+short",
-            "+time=3",
-            "+tries=1",
-            "-x",
-            &ip.to_string(),
-        ])
-        .output()
-        .ok()?;
+/// PTR names of `ip`, lowercase without the trailing dot, looked up with
+/// `dig`. `None` if dig is missing or got no answer; an empty list means
+/// the IP has no PTR record.
+async fn reverse_lookup(ip: IpAddr) -> Option<Vec<String>> {
+    static DIG_MISSING: Once = Once::new();
 
+    let output = match tokio::process::Command::new("dig")
+        .args(["+short", "+time=2", "+tries=1", "-x", &ip.to_string()])
+        .kill_on_drop(true)
+        .output()
+        .await
+    {
+        Ok(output) => output,
+        Err(e) => {
+            DIG_MISSING.call_once(|| {
+                warn!(error = %e, "Could not run dig; crawler claims outside published ranges stay unverified");
+            });
+            return None;
+        }
+    };
     if !output.status.success() {
         return None;
     }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(
+        stdout
+            .lines()
+            .map(|line| line.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|line| !line.is_empty() && !line.starts_with(';'))
+            .collect(),
+    )
+}
 
-    let hostname = String::from_utf8(output.stdout).ok()?;
-    let hostname = hostname.trim().to_string();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::defaults::default_bot_whitelist_config;
 
-    if hostname.is_empty() || hostname == "." {
-        return None;
+    const GOOGLEBOT_UA: &str = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    #[test]
+    fn test_crawlers_are_verified_by_range_and_cached_reverse_dns() {
+        let whitelist = BotWhitelist::new(&default_bot_whitelist_config());
+        let ua = Some(GOOGLEBOT_UA);
+        let in_range: IpAddr = "66.249.66.1".parse().unwrap();
+        let elsewhere: IpAddr = "203.0.113.5".parse().unwrap();
+
+        assert_eq!(whitelist.check(Some("curl/8.0"), &elsewhere), BotVerdict::NotCrawler);
+        assert_eq!(whitelist.check(ua, &in_range), BotVerdict::Verified("Googlebot".to_string()));
+        // Outside a runtime no lookup can start; the claim stays pending.
+        assert_eq!(whitelist.check(ua, &elsewhere), BotVerdict::Pending("Googlebot".to_string()));
+
+        let idx = whitelist.crawlers.iter().position(|c| c.name == "Googlebot").unwrap();
+        whitelist.lookups.insert((elsewhere, idx), Lookup::Spoofed(Instant::now()));
+        assert_eq!(whitelist.check(ua, &elsewhere), BotVerdict::Spoofed("Googlebot".to_string()));
+        // Another crawler's verdict for the same IP is separate.
+        let bing = Some("Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)");
+        assert_eq!(whitelist.peek(bing, &elsewhere), BotVerdict::Pending("Bingbot".to_string()));
     }
 
-    Some(hostname)
+    #[test]
+    fn test_reverse_dns_suffixes_match_whole_labels() {
+        let suffixes = ["googlebot.com".to_string(), "google.com".to_string()];
+        assert!(has_suffix("crawl-66-249-66-1.googlebot.com", &suffixes));
+        assert!(has_suffix("google.com", &suffixes));
+        assert!(!has_suffix("crawl.evilgooglebot.com", &suffixes));
+        assert!(!has_suffix("googlebot.com.attacker.net", &suffixes));
+    }
 }
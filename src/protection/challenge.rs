@@ -2,20 +2,104 @@ use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use base64::Engine;
 use chrono::Utc;
 use hmac::{Hmac, Mac};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tracing::debug;
 
-use crate::config::settings::ChallengeConfig;
+use crate::config::service::ServiceConfig;
+use crate::config::settings::{ChallengeConfig, ChallengeMode, ProtectionConfig};
 use crate::models::request::RequestContext;
-use crate::models::threat::ProtectionLevel;
-use crate::storage::memory::MemoryStore;
+use crate::models::threat::{ProtectionLevel, ThreatAction};
+use crate::storage::memory::{subnet_network, MemoryStore};
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// How long an issued PoW challenge can be redeemed for.
+const CHALLENGE_TTL_SECS: i64 = 300;
+
+/// Interactive tokens posted sooner than this after the page was served
+/// are rejected: nobody reads the page and clicks within a second.
+const INTERACTIVE_MIN_AGE_SECS: i64 = 1;
+
+/// Nojs links followed sooner than this after the page was served are
+/// rejected, as the meta refresh waits longer.
+const NOJS_MIN_AGE_SECS: i64 = 3;
+
+/// Where a clearance cookie may be used, and for how long.
+///
+/// The scope is signed into the cookie: either the exact host it was issued
+/// on, or (when the service sets `clearance_cookie_domain`) that domain and
+/// its subdomains, so a cookie can't be replayed to unrelated sites sharing
+/// the same HMAC secret.
+#[derive(Debug, Clone)]
+pub struct ClearanceScope {
+    host: String,
+    domain: Option<String>,
+    max_age_secs: Option<u64>,
+    /// Whether the cookie is issued over HTTPS and so marked `Secure`.
+    secure: bool,
+}
+
+impl ClearanceScope {
+    pub fn new(host: &str, service: Option<&ServiceConfig>) -> Self {
+        Self {
+            host: host_without_port(host).to_ascii_lowercase(),
+            domain: service
+                .and_then(|s| s.clearance_cookie_domain.as_deref())
+                .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+                .filter(|d| !d.is_empty()),
+            max_age_secs: service.and_then(|s| s.clearance_ttl_secs),
+            secure: true,
+        }
+    }
+
+    /// Issue the cookie without `Secure`, for clients served over plain
+    /// HTTP: browsers drop `Secure` cookies set on an insecure origin.
+    pub fn over_plain_http(mut self) -> Self {
+        self.secure = false;
+        self
+    }
+
+    /// Value signed into the cookie: `.domain` for a domain cookie, the
+    /// bare host otherwise.
+    fn signed_value(&self) -> String {
+        match &self.domain {
+            Some(domain) => format!(".{}", domain),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Whether a cookie signed for `signed` may be used on this host.
+    fn admits(&self, signed: &str) -> bool {
+        match signed.strip_prefix('.') {
+            Some(domain) => host_in_domain(&self.host, domain),
+            None => signed == self.host,
+        }
+    }
+}
+
+/// Strip the port from a Host header value, keeping IPv6 literals intact.
+fn host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+pub fn host_in_domain(host: &str, domain: &str) -> bool {
+    let host = host_without_port(host);
+    host.eq_ignore_ascii_case(domain)
+        || (host.len() > domain.len()
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain))
+}
+
 /// JavaScript proof-of-work challenge system.
 ///
 /// Issues challenges to suspicious clients that require computing a SHA-256
@@ -25,26 +109,45 @@ type HmacSha256 = Hmac<Sha256>;
 /// Challenge flow:
 /// 1. Server returns challenge HTML page with embedded PoW JavaScript
 /// 2. Browser computes SHA-256 hashes until leading zeros match difficulty
-/// 3. Browser sets a signed cookie with the solution
-/// 4. Browser reloads the page, and the clearance cookie bypasses the challenge
+/// 3. Browser POSTs the solution to `/__fortress/verify`, which checks it and
+///    sets a signed clearance cookie
+/// 4. Browser follows the redirect, and the clearance cookie bypasses the challenge
+///
+/// In interactive mode (`challenge.mode`) steps 1-3 are replaced by a page
+/// with a button that POSTs the token embedded in the page to
+/// `/__fortress/verify-interactive`; the token is bound to the client's IP.
+///
+/// Issued challenges are HMAC-signed with their timestamp and difficulty, are
+/// only accepted for `CHALLENGE_TTL_SECS`, and can be redeemed exactly once.
 pub struct ChallengeSystem {
     memory: Arc<MemoryStore>,
+    params: ArcSwap<ChallengeParams>,
+}
+
+/// Config-derived challenge parameters, swapped as a whole on reload.
+struct ChallengeParams {
+    mode: ChallengeMode,
     hmac_secret: Vec<u8>,
     cookie_name: String,
     cookie_max_age: Duration,
-    exempt_paths: Vec<String>,
+    exempt_paths: Vec<ExemptPath>,
     pow_difficulty_l1: u8,
     pow_difficulty_l2: u8,
     pow_difficulty_l3: u8,
     cookie_subnet_binding: bool,
+    ipv4_subnet_mask: u8,
+    ipv6_subnet_mask: u8,
     nojs_fallback_enabled: bool,
+    nojs_max_attempts_per_minute: u64,
+    max_unanswered: u64,
+    unanswered_window_secs: u64,
+    flood_action: ThreatAction,
 }
 
-impl ChallengeSystem {
-    /// Create a new ChallengeSystem from configuration.
-    pub fn new(config: &ChallengeConfig, memory: Arc<MemoryStore>) -> Self {
+impl ChallengeParams {
+    fn from_config(config: &ChallengeConfig, protection: &ProtectionConfig) -> Self {
         Self {
-            memory,
+            mode: config.mode,
             hmac_secret: config.hmac_secret.as_bytes().to_vec(),
             cookie_name: config.cookie_name.clone(),
             cookie_max_age: Duration::from_secs(config.cookie_max_age_secs),
@@ -53,10 +156,37 @@ impl ChallengeSystem {
             pow_difficulty_l2: config.pow_difficulty_l2,
             pow_difficulty_l3: config.pow_difficulty_l3,
             cookie_subnet_binding: config.cookie_subnet_binding,
+            ipv4_subnet_mask: protection.ipv4_subnet_mask,
+            ipv6_subnet_mask: protection.ipv6_subnet_mask,
             nojs_fallback_enabled: config.nojs_fallback_enabled,
+            nojs_max_attempts_per_minute: config.nojs_max_attempts_per_minute,
+            max_unanswered: config.max_unanswered_challenges,
+            unanswered_window_secs: config.unanswered_window_secs,
+            flood_action: match ThreatAction::from_str_name(&config.challenge_flood_action) {
+                Some(ThreatAction::Tarpit) => ThreatAction::Tarpit,
+                _ => ThreatAction::Block,
+            },
+        }
+    }
+}
+
+impl ChallengeSystem {
+    /// Create a new ChallengeSystem from configuration.
+    /// `protection` supplies the subnet masks used by `cookie_subnet_binding`.
+    pub fn new(config: &ChallengeConfig, protection: &ProtectionConfig, memory: Arc<MemoryStore>) -> Self {
+        Self {
+            memory,
+            params: ArcSwap::from_pointee(ChallengeParams::from_config(config, protection)),
         }
     }
 
+    /// Apply a reloaded `[challenge]` section. Changing `hmac_secret`, or the
+    /// subnet masks while `cookie_subnet_binding` is on, invalidates every
+    /// clearance cookie issued so far.
+    pub fn reload(&self, config: &ChallengeConfig, protection: &ProtectionConfig) {
+        self.params.store(Arc::new(ChallengeParams::from_config(config, protection)));
+    }
+
     /// Determine if a challenge should be issued for this request.
     ///
     /// Challenge criteria depend on the current protection level:
@@ -82,15 +212,47 @@ impl ChallengeSystem {
         score > threshold
     }
 
+    /// If `ip` has been served `max_unanswered_challenges` pages without
+    /// solving one, return the action to take instead of serving another.
+    ///
+    /// A full challenge page is several KB; during a flood, answering every
+    /// request with one amplifies the attacker's bandwidth.
+    pub fn flood_action(&self, ip: &IpAddr) -> Option<ThreatAction> {
+        let action = self.peek_flood_action(ip)?;
+        self.memory.record_challenge_suppressed();
+        Some(action)
+    }
+
+    /// What [`flood_action`](Self::flood_action) would return, without
+    /// counting a suppressed challenge.
+    pub fn peek_flood_action(&self, ip: &IpAddr) -> Option<ThreatAction> {
+        let params = self.params.load();
+        (params.max_unanswered > 0 && self.memory.unanswered_challenges(ip) >= params.max_unanswered)
+            .then_some(params.flood_action)
+    }
+
+    /// Record that a challenge page was served to `ip`.
+    pub fn record_issued(&self, ip: IpAddr) {
+        let window = self.params.load().unanswered_window_secs;
+        self.memory.record_challenge_issued(ip, window);
+    }
+
+    /// Record a solved challenge, resetting the unanswered count for `ip`.
+    pub fn record_solved(&self, ip: &IpAddr) {
+        self.memory.record_challenge_solved(ip);
+    }
+
     /// Check if the request has a valid clearance cookie.
     ///
     /// Validates:
     /// 1. Cookie exists with the correct name
-    /// 2. Cookie format is `challenge:nonce:signature`
+    /// 2. Cookie format is `challenge:nonce:scope:signature`
     /// 3. HMAC signature is valid
-    /// 4. Challenge timestamp is not expired
+    /// 4. Challenge timestamp is within the scope's lifetime
     /// 5. IP hash in challenge matches requesting IP
-    pub fn has_valid_clearance(&self, ip: &IpAddr, cookies: Option<&str>) -> bool {
+    /// 6. The signed host scope covers the requesting host
+    pub fn has_valid_clearance(&self, ip: &IpAddr, cookies: Option<&str>, scope: &ClearanceScope) -> bool {
+        let params = self.params.load();
         let cookies_str = match cookies {
             Some(c) => c,
             None => return false,
@@ -102,26 +264,28 @@ impl ChallengeSystem {
             None => return false,
         };
 
-        // Cookie format: challenge:nonce:signature
+        // Cookie format: challenge:nonce:scope:signature
         // Challenge format: timestamp:random_hex:ip_hash
-        // So full cookie: timestamp:random_hex:ip_hash:nonce:signature
+        // So full cookie: timestamp:random_hex:ip_hash:nonce:scope:signature
+        // The scope may itself contain ':' (IPv6 literal hosts), so the
+        // signature is split off from the right.
         let parts: Vec<&str> = cookie_value.splitn(5, ':').collect();
-        if parts.len() != 5 {
+        let Some((cookie_scope, signature)) = parts.get(4).and_then(|rest| rest.rsplit_once(':')) else {
             debug!("Invalid clearance cookie format: wrong number of parts");
             return false;
-        }
+        };
 
         let timestamp_str = parts[0];
         let random_hex = parts[1];
         let ip_hash = parts[2];
         let nonce = parts[3];
-        let signature = parts[4];
 
         // Reconstruct the challenge string
         let challenge = format!("{}:{}:{}", timestamp_str, random_hex, ip_hash);
 
         // Verify HMAC signature (constant-time comparison)
-        let expected_signature = self.compute_signature(&challenge, nonce, "clearance");
+        let expected_signature =
+            self.compute_signature(&challenge, &format!("{}:{}", nonce, cookie_scope), "clearance");
         if !constant_time_eq(signature.as_bytes(), expected_signature.as_bytes()) {
             debug!("Invalid clearance cookie: signature mismatch");
             return false;
@@ -138,12 +302,14 @@ impl ChallengeSystem {
 
         let now = Utc::now().timestamp();
         let age = now - timestamp;
-        if age < 0 || age > self.cookie_max_age.as_secs() as i64 {
-            debug!(
-                age = age,
-                max_age = self.cookie_max_age.as_secs(),
-                "Clearance cookie expired"
-            );
+        let max_age = scope.max_age_secs.unwrap_or(params.cookie_max_age.as_secs());
+        if age < 0 || age > max_age as i64 {
+            debug!(age = age, max_age = max_age, "Clearance cookie expired");
+            return false;
+        }
+
+        if !scope.admits(cookie_scope) {
+            debug!(scope = cookie_scope, host = %scope.host, "Clearance cookie issued for another host");
             return false;
         }
 
@@ -158,51 +324,171 @@ impl ChallengeSystem {
         true
     }
 
+    /// Generate the challenge page for `ip`: the interactive page or, per
+    /// `challenge.mode` and the protection level, the PoW page.
+    pub fn generate_challenge_page(&self, level: &ProtectionLevel, ip: &IpAddr) -> String {
+        let interactive = match self.params.load().mode {
+            ChallengeMode::Pow => false,
+            ChallengeMode::Interactive => true,
+            ChallengeMode::Auto => matches!(level, ProtectionLevel::L0 | ProtectionLevel::L1),
+        };
+        if interactive {
+            self.generate_interactive_page(ip)
+        } else {
+            self.generate_pow_page(level, ip)
+        }
+    }
+
     /// Generate a full HTML challenge page with embedded PoW JavaScript.
     ///
     /// The difficulty scales with the protection level (and reads from config):
     /// - L0-L1: pow_difficulty_l1 leading zero bits
     /// - L2: pow_difficulty_l2 leading zero bits
     /// - L3-L4: pow_difficulty_l3 leading zero bits
-    pub fn generate_challenge_page(&self, level: &ProtectionLevel) -> String {
+    ///
+    /// The nojs fallback link, if enabled, is bound to `ip`.
+    fn generate_pow_page(&self, level: &ProtectionLevel, ip: &IpAddr) -> String {
+        let params = self.params.load();
         let difficulty = match level {
-            ProtectionLevel::L0 | ProtectionLevel::L1 => self.pow_difficulty_l1 as u32,
-            ProtectionLevel::L2 => self.pow_difficulty_l2 as u32,
-            ProtectionLevel::L3 | ProtectionLevel::L4 => self.pow_difficulty_l3 as u32,
+            ProtectionLevel::L0 | ProtectionLevel::L1 => params.pow_difficulty_l1 as u32,
+            ProtectionLevel::L2 => params.pow_difficulty_l2 as u32,
+            ProtectionLevel::L3 | ProtectionLevel::L4 => params.pow_difficulty_l3 as u32,
         };
 
         let timestamp = Utc::now().timestamp();
         let random_hex = self.generate_random_hex(16);
-        let challenge_template = format!("{}:{}", timestamp, random_hex);
 
-        // Generate nojs fallback redirect URL
-        let nojs_redirect = if self.nojs_fallback_enabled {
-            let nojs_token = format!("{}:{}", timestamp, random_hex);
-            let nojs_sig = self.compute_signature(&nojs_token, "0", "nojs");
+        // Signed challenge token: timestamp:random_hex:difficulty:signature
+        let unsigned = format!("{}:{}:{}", timestamp, random_hex, difficulty);
+        let signature = self.compute_signature(&unsigned, "0", "pow");
+        let challenge_token = format!("{}:{}", unsigned, signature);
+
+        // Nojs fallback redirect URL: timestamp:random_hex:ip_hash. It
+        // shares random_hex with the PoW token, so the page can be redeemed
+        // once, by either flow.
+        let nojs_redirect = if params.nojs_fallback_enabled {
+            let (nojs_token, nojs_sig) = self.issue_nojs_token(ip, timestamp, &random_hex);
             format!("/__fortress/nojs-verify?token={}&sig={}", nojs_token, nojs_sig)
         } else {
             String::from("javascript:void(0)")
         };
 
         let html = CHALLENGE_HTML_TEMPLATE
+            .replace("__HEADLESS_DETECTION__", HEADLESS_DETECTION_JS)
             .replace("__NOJS_REDIRECT__", &nojs_redirect)
-            .replace("__CHALLENGE__", &challenge_template)
+            .replace("__CHALLENGE__", &challenge_token)
             .replace("__DIFFICULTY__", &difficulty.to_string());
 
         html
     }
 
+    /// Signed nojs token `timestamp:random_hex:ip_hash` and its signature.
+    fn issue_nojs_token(&self, ip: &IpAddr, timestamp: i64, random_hex: &str) -> (String, String) {
+        let token = format!("{}:{}:{}", timestamp, random_hex, self.hash_ip(ip));
+        let sig = self.compute_signature(&token, "0", "nojs");
+        (token, sig)
+    }
+
+    /// Generate the interactive "verify you are human" page for `ip`.
+    fn generate_interactive_page(&self, ip: &IpAddr) -> String {
+        let token = self.issue_interactive_token(ip, Utc::now().timestamp());
+        INTERACTIVE_HTML_TEMPLATE
+            .replace("__HEADLESS_DETECTION__", HEADLESS_DETECTION_JS)
+            .replace("__TOKEN__", &token)
+    }
+
+    /// Signed interactive token: `timestamp:random_hex:ip_hash:signature`.
+    fn issue_interactive_token(&self, ip: &IpAddr, timestamp: i64) -> String {
+        let unsigned = format!("{}:{}:{}", timestamp, self.generate_random_hex(16), self.hash_ip(ip));
+        let signature = self.compute_signature(&unsigned, "0", "interactive");
+        format!("{}:{}", unsigned, signature)
+    }
+
+    /// Verify a token posted from the interactive page by `ip`.
+    ///
+    /// The signature must match, the token must have been issued to the same
+    /// IP (or subnet, with `cookie_subnet_binding`), be between
+    /// `INTERACTIVE_MIN_AGE_SECS` and `CHALLENGE_TTL_SECS` old, and not have
+    /// been redeemed before.
+    pub fn verify_interactive(&self, token: &str, ip: &IpAddr) -> bool {
+        let parts: Vec<&str> = token.splitn(4, ':').collect();
+        if parts.len() != 4 {
+            debug!("Invalid interactive token: wrong number of parts");
+            return false;
+        }
+
+        let unsigned = format!("{}:{}:{}", parts[0], parts[1], parts[2]);
+        let expected_signature = self.compute_signature(&unsigned, "0", "interactive");
+        if !constant_time_eq(parts[3].as_bytes(), expected_signature.as_bytes()) {
+            debug!("Invalid interactive token: signature mismatch");
+            return false;
+        }
+
+        let Ok(timestamp) = parts[0].parse::<i64>() else {
+            debug!("Invalid interactive token: bad timestamp");
+            return false;
+        };
+        let age = Utc::now().timestamp() - timestamp;
+        if !(INTERACTIVE_MIN_AGE_SECS..=CHALLENGE_TTL_SECS).contains(&age) {
+            debug!(age = age, "Interactive token too fresh or expired");
+            return false;
+        }
+
+        if parts[2] != self.hash_ip(ip) {
+            debug!("Invalid interactive token: IP hash mismatch");
+            return false;
+        }
+
+        let ttl = Duration::from_secs(CHALLENGE_TTL_SECS as u64);
+        if !self.memory.redeem_challenge(parts[1], ttl) {
+            debug!("Interactive token already redeemed");
+            return false;
+        }
+
+        true
+    }
+
     /// Verify a proof-of-work solution.
     ///
-    /// Checks that SHA-256(challenge + ":" + nonce) has the required number
-    /// of leading zero bits based on the current protection level.
-    pub fn verify_solution(&self, challenge: &str, nonce: &str, level: &ProtectionLevel) -> bool {
-        let difficulty = match level {
-            ProtectionLevel::L0 | ProtectionLevel::L1 => self.pow_difficulty_l1 as u32,
-            ProtectionLevel::L2 => self.pow_difficulty_l2 as u32,
-            ProtectionLevel::L3 | ProtectionLevel::L4 => self.pow_difficulty_l3 as u32,
+    /// The challenge must be a token issued by `generate_challenge_page`:
+    /// its signature must match, it must be younger than `CHALLENGE_TTL_SECS`,
+    /// SHA-256(challenge + ":" + nonce) must have at least the difficulty
+    /// embedded in the token in leading zero bits, and it must not have been
+    /// redeemed before.
+    pub fn verify_solution(&self, challenge: &str, nonce: &str) -> bool {
+        // Token format: timestamp:random_hex:difficulty:signature
+        let parts: Vec<&str> = challenge.splitn(4, ':').collect();
+        if parts.len() != 4 {
+            debug!("Invalid PoW challenge: wrong number of parts");
+            return false;
+        }
+
+        let unsigned = format!("{}:{}:{}", parts[0], parts[1], parts[2]);
+        let expected_signature = self.compute_signature(&unsigned, "0", "pow");
+        if !constant_time_eq(parts[3].as_bytes(), expected_signature.as_bytes()) {
+            debug!("Invalid PoW challenge: signature mismatch");
+            return false;
+        }
+
+        let (timestamp, difficulty) = match (parts[0].parse::<i64>(), parts[2].parse::<u32>()) {
+            (Ok(t), Ok(d)) => (t, d),
+            _ => {
+                debug!("Invalid PoW challenge: bad timestamp or difficulty");
+                return false;
+            }
         };
 
+        let age = Utc::now().timestamp() - timestamp;
+        if !(0..=CHALLENGE_TTL_SECS).contains(&age) {
+            debug!(age = age, "PoW challenge expired");
+            return false;
+        }
+
+        if nonce.is_empty() || !nonce.bytes().all(|b| b.is_ascii_digit()) {
+            debug!("Invalid PoW nonce");
+            return false;
+        }
+
         let data = format!("{}:{}", challenge, nonce);
         let hash = Sha256::digest(data.as_bytes());
 
@@ -218,70 +504,130 @@ impl ChallengeSystem {
             }
         }
 
-        // Verify against the configured difficulty for this protection level
-        zeros >= difficulty
+        // Verify against the difficulty the challenge was issued with
+        if zeros < difficulty {
+            return false;
+        }
+
+        // Single use: reject a solution that has already been redeemed
+        let ttl = Duration::from_secs(CHALLENGE_TTL_SECS as u64);
+        if !self.memory.redeem_challenge(parts[1], ttl) {
+            debug!("PoW challenge already redeemed");
+            return false;
+        }
+
+        true
+    }
+
+    /// Unix time a PoW, interactive or nojs token was issued at: its
+    /// leading field. Only trustworthy once the token has been verified.
+    pub fn issued_at(token: &str) -> Option<i64> {
+        token.split(':').next()?.parse().ok()
     }
 
-    /// Generate a signed clearance cookie value for the given IP.
+    /// Generate a signed `Set-Cookie` clearance value for the given IP and
+    /// scope.
     ///
-    /// Cookie format: `timestamp:random_hex:ip_hash:nonce:signature`
-    /// Where signature = base64url(HMAC-SHA256(challenge + ":" + nonce, hmac_secret))
-    pub fn generate_clearance_cookie(&self, ip: &IpAddr) -> String {
+    /// Cookie format: `timestamp:random_hex:ip_hash:nonce:scope:signature`
+    /// Where signature = base64url(HMAC-SHA256(challenge + ":" + nonce + ":" + scope, hmac_secret))
+    pub fn generate_clearance_cookie(&self, ip: &IpAddr, scope: &ClearanceScope) -> String {
+        let params = self.params.load();
         let timestamp = Utc::now().timestamp();
         let random_hex = self.generate_random_hex(16);
         let ip_hash = self.hash_ip(ip);
         let challenge = format!("{}:{}:{}", timestamp, random_hex, ip_hash);
         let nonce = "0"; // Pre-verified clearance, no PoW needed
-        let signature = self.compute_signature(&challenge, nonce, "clearance");
+        let signed_scope = scope.signed_value();
+        let signature = self.compute_signature(&challenge, &format!("{}:{}", nonce, signed_scope), "clearance");
 
-        let cookie_value = format!("{}:{}:{}", challenge, nonce, signature);
+        let cookie_value = format!("{}:{}:{}:{}", challenge, nonce, signed_scope, signature);
+        let domain = scope
+            .domain
+            .as_deref()
+            .map(|d| format!("; Domain={}", d))
+            .unwrap_or_default();
         format!(
-            "{}={}; Path=/; Max-Age={}; SameSite=Lax; HttpOnly; Secure",
-            self.cookie_name,
+            "{}={}; Path=/{}; Max-Age={}; SameSite=Lax; HttpOnly{}",
+            params.cookie_name,
             cookie_value,
-            self.cookie_max_age.as_secs()
+            domain,
+            scope.max_age_secs.unwrap_or(params.cookie_max_age.as_secs()),
+            if scope.secure { "; Secure" } else { "" }
         )
     }
 
 
-    /// Verify a nojs verification token and signature.
+    /// Verify a nojs verification token and signature followed by `ip`.
     ///
     /// Used by the non-JavaScript fallback flow: the `<meta http-equiv="refresh">`
     /// tag redirects browsers to `/__fortress/nojs-verify?token=...&sig=...`.
-    /// This method validates the HMAC signature and checks that the token
-    /// timestamp is no older than 5 minutes.
-    pub fn verify_nojs_token(&self, token: &str, sig: &str) -> bool {
+    /// Past `nojs_max_attempts_per_minute` attempts from `ip`, every token
+    /// fails. Otherwise the signature must match, the token must have been
+    /// issued to the same IP (or subnet, with `cookie_subnet_binding`), be
+    /// between `NOJS_MIN_AGE_SECS` and `CHALLENGE_TTL_SECS` old, and not
+    /// have been redeemed before, by this flow or the PoW one.
+    pub fn verify_nojs_token(&self, token: &str, sig: &str, ip: &IpAddr) -> bool {
+        let max_attempts = self.params.load().nojs_max_attempts_per_minute;
+        if max_attempts > 0 && self.memory.record_nojs_attempt(*ip, 60) > max_attempts {
+            debug!(ip = %ip, "Too many nojs verification attempts");
+            return false;
+        }
+
         let expected_sig = self.compute_signature(token, "0", "nojs");
         if !constant_time_eq(sig.as_bytes(), expected_sig.as_bytes()) {
             return false;
         }
-        // Check timestamp freshness (5 minutes) and minimum age (3 seconds)
-        if let Some(ts_str) = token.split(':').next() {
-            if let Ok(ts) = ts_str.parse::<i64>() {
-                let now = chrono::Utc::now().timestamp();
-                let age = now - ts;
-                // Token must be at least 3 seconds old to prevent instant bypass
-                if age < 3 {
-                    debug!("NoJS token too fresh: age {}s < 3s minimum", age);
-                    return false;
-                }
-                if age.abs() > 300 {
-                    return false;
-                }
-            }
+
+        // Token format: timestamp:random_hex:ip_hash
+        let parts: Vec<&str> = token.split(':').collect();
+        if parts.len() != 3 {
+            debug!("Invalid nojs token: wrong number of parts");
+            return false;
+        }
+        let Ok(timestamp) = parts[0].parse::<i64>() else {
+            debug!("Invalid nojs token: bad timestamp");
+            return false;
+        };
+        let age = Utc::now().timestamp() - timestamp;
+        if !(NOJS_MIN_AGE_SECS..=CHALLENGE_TTL_SECS).contains(&age) {
+            debug!(age = age, "Nojs token too fresh or expired");
+            return false;
         }
+
+        if parts[2] != self.hash_ip(ip) {
+            debug!("Invalid nojs token: IP hash mismatch");
+            return false;
+        }
+
+        let ttl = Duration::from_secs(CHALLENGE_TTL_SECS as u64);
+        if !self.memory.redeem_challenge(parts[1], ttl) {
+            debug!("Nojs token already redeemed");
+            return false;
+        }
+
         true
     }
 
-    /// Check if a path is exempt from challenges.
+    /// Sign `value` for `ip` (its subnet, with `cookie_subnet_binding`),
+    /// for Fortress cookies other than clearance, e.g. the waiting room
+    /// position. `purpose` keeps the signatures of each cookie apart.
+    pub fn sign_for_ip(&self, value: &str, ip: &IpAddr, purpose: &str) -> String {
+        self.compute_signature(value, &self.hash_ip(ip), purpose)
+    }
+
+    /// Whether `signature` is [`sign_for_ip`](Self::sign_for_ip)'s for
+    /// the same value, IP and purpose.
+    pub fn verify_for_ip(&self, value: &str, ip: &IpAddr, purpose: &str, signature: &str) -> bool {
+        constant_time_eq(signature.as_bytes(), self.sign_for_ip(value, ip, purpose).as_bytes())
+    }
+
+    /// Check if a request is exempt from challenges, by the global
+    /// `challenge.exempt_paths` or the service's own `exempt_paths`.
     /// Supports `*` wildcard anywhere in the pattern (e.g. `/google*.html`, `/api/*/webhook`).
-    pub fn is_exempt_path(&self, path: &str) -> bool {
-        for exempt in &self.exempt_paths {
-            if glob_match(exempt, path) {
-                return true;
-            }
-        }
-        false
+    pub fn is_exempt(&self, method: &str, path: &str, service: Option<&ServiceConfig>) -> bool {
+        let params = self.params.load();
+        params.exempt_paths.iter().any(|e| e.matches(method, path))
+            || service.is_some_and(|s| s.is_exempt_path(method, path))
     }
 
     // ====================================================================
@@ -290,9 +636,10 @@ impl ChallengeSystem {
 
     /// Extract the clearance cookie value from a Cookie header string.
     fn extract_cookie<'a>(&self, cookies: &'a str) -> Option<&'a str> {
+        let params = self.params.load();
         for cookie in cookies.split(';') {
             let cookie = cookie.trim();
-            if let Some(value) = cookie.strip_prefix(&format!("{}=", self.cookie_name)) {
+            if let Some(value) = cookie.strip_prefix(&format!("{}=", params.cookie_name)) {
                 return Some(value);
             }
         }
@@ -304,8 +651,9 @@ impl ChallengeSystem {
     /// The `purpose` parameter is mixed into the HMAC to produce
     /// domain-separated signatures (e.g. "clearance" vs "nojs").
     fn compute_signature(&self, challenge: &str, nonce: &str, purpose: &str) -> String {
+        let params = self.params.load();
         let data = format!("{}:{}", challenge, nonce);
-        let mut mac = HmacSha256::new_from_slice(&self.hmac_secret)
+        let mut mac = HmacSha256::new_from_slice(&params.hmac_secret)
             .expect("HMAC can take key of any size");
         mac.update(data.as_bytes());
         mac.update(b":");
@@ -316,25 +664,18 @@ impl ChallengeSystem {
 
     /// Hash an IP address with the HMAC secret, returning first 8 hex chars.
     ///
-    /// When `cookie_subnet_binding` is enabled, hashes the /24 (IPv4) or
-    /// /48 (IPv6) subnet instead of the exact IP. This reduces false positives
-    /// when a user switches between nearby networks (e.g. WiFi -> mobile).
+    /// When `cookie_subnet_binding` is enabled, hashes the client's subnet
+    /// (`protection.ipv4_subnet_mask` / `ipv6_subnet_mask`) instead of the
+    /// exact IP. This reduces false positives when a user switches between
+    /// nearby networks (e.g. WiFi -> mobile) or rotates IPv6 privacy addresses.
     fn hash_ip(&self, ip: &IpAddr) -> String {
-        let ip_str = if self.cookie_subnet_binding {
-            match ip {
-                IpAddr::V4(v4) => {
-                    let o = v4.octets();
-                    format!("{}.{}.{}.0", o[0], o[1], o[2])
-                }
-                IpAddr::V6(v6) => {
-                    let s = v6.segments();
-                    format!("{:x}:{:x}:{:x}::", s[0], s[1], s[2])
-                }
-            }
+        let params = self.params.load();
+        let ip_str = if params.cookie_subnet_binding {
+            subnet_network(*ip, params.ipv4_subnet_mask, params.ipv6_subnet_mask).to_string()
         } else {
             ip.to_string()
         };
-        let data = format!("{}{}", ip_str, String::from_utf8_lossy(&self.hmac_secret));
+        let data = format!("{}{}", ip_str, String::from_utf8_lossy(&params.hmac_secret));
         let hash = Sha256::digest(data.as_bytes());
         hex::encode(&hash[..4]) // First 4 bytes = 8 hex chars
     }
@@ -366,10 +707,45 @@ mod hex {
     }
 }
 
+/// Headless browser detection shared by the challenge pages. Sets `hlScore`,
+/// which is posted as `hl`; 40 or more fails verification.
+const HEADLESS_DETECTION_JS: &str = r#"  // Headless browser detection
+  var hlScore = 0;
+  try {
+    // navigator.webdriver (Puppeteer/Playwright/Selenium)
+    if (navigator.webdriver) hlScore += 40;
+    // Chrome DevTools Protocol traces
+    if (window.chrome && window.chrome.csi) hlScore += 10;
+    if (window.__nightmare) hlScore += 40;
+    if (document.__selenium_unwrapped || document.__webdriver_evaluate || document.__driver_evaluate) hlScore += 40;
+    // Zero plugins on desktop (mobile normally has 0)
+    var isMobile = /Mobi|Android|iPhone|iPad/i.test(navigator.userAgent);
+    if (!isMobile && navigator.plugins && navigator.plugins.length === 0) hlScore += 10;
+    // WebGL renderer check for headless signatures
+    try {
+      var canvas = document.createElement("canvas");
+      var gl = canvas.getContext("webgl") || canvas.getContext("experimental-webgl");
+      if (gl) {
+        var dbg = gl.getExtension("WEBGL_debug_renderer_info");
+        if (dbg) {
+          var renderer = gl.getParameter(dbg.UNMASKED_RENDERER_WEBGL) || "";
+          if (/SwiftShader|LLVMpipe|Mesa/i.test(renderer)) hlScore += 30;
+        }
+      }
+    } catch(e) {}
+    // Screen dimensions of 0 (headless default)
+    if (screen.width === 0 || screen.height === 0) hlScore += 20;
+    // Missing language
+    if (!navigator.language && !navigator.languages) hlScore += 10;
+    // Phantom.js
+    if (window.callPhantom || window._phantom) hlScore += 40;
+  } catch(e) {}"#;
+
 /// The full HTML challenge page template.
 ///
 /// Placeholders:
-/// - `__CHALLENGE__`: The challenge string (timestamp:random_hex)
+/// - `__HEADLESS_DETECTION__`: [`HEADLESS_DETECTION_JS`]
+/// - `__CHALLENGE__`: The signed challenge token (timestamp:random_hex:difficulty:signature)
 /// - `__DIFFICULTY__`: Number of leading zero bits required
 const CHALLENGE_HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html lang="en">
@@ -404,37 +780,7 @@ noscript { color: #ef4444; }
 </div>
 <script>
 (async function() {
-  // Headless browser detection
-  var hlScore = 0;
-  try {
-    // navigator.webdriver (Puppeteer/Playwright/Selenium)
-    if (navigator.webdriver) hlScore += 40;
-    // Chrome DevTools Protocol traces
-    if (window.chrome && window.chrome.csi) hlScore += 10;
-    if (window.__nightmare) hlScore += 40;
-    if (document.__selenium_unwrapped || document.__webdriver_evaluate || document.__driver_evaluate) hlScore += 40;
-    // Zero plugins on desktop (mobile normally has 0)
-    var isMobile = /Mobi|Android|iPhone|iPad/i.test(navigator.userAgent);
-    if (!isMobile && navigator.plugins && navigator.plugins.length === 0) hlScore += 10;
-    // WebGL renderer check for headless signatures
-    try {
-      var canvas = document.createElement("canvas");
-      var gl = canvas.getContext("webgl") || canvas.getContext("experimental-webgl");
-      if (gl) {
-        var dbg = gl.getExtension("WEBGL_debug_renderer_info");
-        if (dbg) {
-          var renderer = gl.getParameter(dbg.UNMASKED_RENDERER_WEBGL) || "";
-          if (/SwiftShader|LLVMpipe|Mesa/i.test(renderer)) hlScore += 30;
-        }
-      }
-    } catch(e) {}
-    // Screen dimensions of 0 (headless default)
-    if (screen.width === 0 || screen.height === 0) hlScore += 20;
-    // Missing language
-    if (!navigator.language && !navigator.languages) hlScore += 10;
-    // Phantom.js
-    if (window.callPhantom || window._phantom) hlScore += 40;
-  } catch(e) {}
+__HEADLESS_DETECTION__
   var challenge = "__CHALLENGE__";
   var difficulty = __DIFFICULTY__;
   var statusEl = document.getElementById("status");
@@ -459,7 +805,19 @@ noscript { color: #ef4444; }
       statusEl.textContent = "Verified! Redirecting...";
       progressEl.style.width = "100%";
       var redirect = window.location.pathname + window.location.search;
-      window.location.href = "/__fortress/verify?challenge=" + encodeURIComponent(challenge) + "&nonce=" + n + "&redirect=" + encodeURIComponent(redirect) + "&hl=" + hlScore;
+      var form = document.createElement("form");
+      form.method = "POST";
+      form.action = "/__fortress/verify";
+      var fields = { challenge: challenge, nonce: String(n), redirect: redirect, hl: String(hlScore) };
+      for (var k in fields) {
+        var input = document.createElement("input");
+        input.type = "hidden";
+        input.name = k;
+        input.value = fields[k];
+        form.appendChild(input);
+      }
+      document.body.appendChild(form);
+      form.submit();
       return;
     }
   }
@@ -469,9 +827,90 @@ noscript { color: #ef4444; }
 </body>
 </html>"#;
 
+/// The interactive ("verify you are human") challenge page template. The
+/// form also works without JavaScript, minus the headless check and the
+/// return to the challenged URL.
+///
+/// Placeholders:
+/// - `__HEADLESS_DETECTION__`: [`HEADLESS_DETECTION_JS`]
+/// - `__TOKEN__`: The signed interactive token (timestamp:random_hex:ip_hash:signature)
+const INTERACTIVE_HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width,initial-scale=1">
+<title>Security Check</title>
+<style>
+* { margin: 0; padding: 0; box-sizing: border-box; }
+body { background: #0a0a0a; color: #fff; font-family: -apple-system, system-ui, sans-serif; display: flex; justify-content: center; align-items: center; min-height: 100vh; }
+.container { text-align: center; max-width: 400px; padding: 2rem; }
+.shield { font-size: 48px; margin-bottom: 1rem; }
+h2 { font-size: 1.25rem; margin-bottom: 0.5rem; }
+p { color: #888; font-size: 0.9rem; margin-bottom: 1.5rem; }
+button { background: #3b82f6; color: #fff; border: 0; border-radius: 6px; padding: 0.75rem 1.5rem; font-size: 1rem; cursor: pointer; }
+button:disabled { background: #333; cursor: default; }
+</style>
+</head>
+<body>
+<div class="container">
+<div class="shield">&#x1f6e1;</div>
+<h2>Verify you are human</h2>
+<p>Please confirm you are not a robot to continue to the site.</p>
+<form method="POST" action="/__fortress/verify-interactive" id="verify">
+<input type="hidden" name="token" value="__TOKEN__">
+<input type="hidden" name="redirect" id="redirect" value="/">
+<input type="hidden" name="hl" id="hl" value="0">
+<button type="submit" id="button">I am human</button>
+</form>
+</div>
+<script>
+(function() {
+__HEADLESS_DETECTION__
+  document.getElementById("hl").value = String(hlScore);
+  document.getElementById("redirect").value = window.location.pathname + window.location.search;
+  document.getElementById("verify").addEventListener("submit", function() {
+    document.getElementById("button").disabled = true;
+  });
+})();
+</script>
+</body>
+</html>"#;
+
+/// A challenge exemption: a path pattern (`*` wildcards), optionally for
+/// some methods only. Written as a plain string (every method) or as
+/// `{ path = "/webhooks/*", methods = ["POST"] }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ExemptPath {
+    Path(String),
+    Scoped {
+        path: String,
+        #[serde(default)]
+        methods: Vec<String>,
+    },
+}
+
+impl ExemptPath {
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        match self {
+            ExemptPath::Path(pattern) => glob_match(pattern, path),
+            ExemptPath::Scoped { path: pattern, methods } => {
+                (methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+                    && glob_match(pattern, path)
+            }
+        }
+    }
+}
+
+impl From<&str> for ExemptPath {
+    fn from(path: &str) -> Self {
+        ExemptPath::Path(path.to_string())
+    }
+}
+
 /// Simple glob matching: supports `*` wildcard anywhere in the pattern.
 /// Each `*` matches zero or more characters (non-greedy segments).
-fn glob_match(pattern: &str, text: &str) -> bool {
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
     let parts: Vec<&str> = pattern.split('*').collect();
 
     // No wildcard → exact match
@@ -504,3 +943,201 @@ fn glob_match(pattern: &str, text: &str) -> bool {
     }
     text[pos..].ends_with(last) && (text.len() - last.len()) >= pos
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::defaults::default_protection_config;
+
+    fn system() -> ChallengeSystem {
+        let config: ChallengeConfig = toml::from_str("hmac_secret = \"test\"").unwrap();
+        ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()))
+    }
+
+    fn scope(host: &str, domain: Option<&str>) -> ClearanceScope {
+        ClearanceScope {
+            host: host.to_string(),
+            domain: domain.map(str::to_string),
+            max_age_secs: None,
+            secure: true,
+        }
+    }
+
+    /// Cookie header value from a `Set-Cookie` line.
+    fn cookie_pair(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap()
+    }
+
+    #[test]
+    fn test_interactive_tokens_are_ip_bound_aged_and_single_use() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let config: ChallengeConfig = toml::from_str("hmac_secret = \"test\"\nmode = \"auto\"").unwrap();
+        let challenge = ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()));
+
+        let page = challenge.generate_challenge_page(&ProtectionLevel::L1, &ip);
+        assert!(page.contains("/__fortress/verify-interactive"));
+        assert!(page.contains("hlScore"));
+        let page = challenge.generate_challenge_page(&ProtectionLevel::L2, &ip);
+        assert!(page.contains("/__fortress/verify\""));
+
+        let now = Utc::now().timestamp();
+        // Posted the moment the page was served
+        assert!(!challenge.verify_interactive(&challenge.issue_interactive_token(&ip, now), &ip));
+
+        let token = challenge.issue_interactive_token(&ip, now - 5);
+        assert!(!challenge.verify_interactive(&token, &"198.51.100.1".parse().unwrap()));
+        let backdated = token.replacen(&(now - 5).to_string(), &(now - 6).to_string(), 1);
+        assert!(!challenge.verify_interactive(&backdated, &ip));
+        assert!(challenge.verify_interactive(&token, &ip));
+        assert!(!challenge.verify_interactive(&token, &ip));
+
+        let expired = challenge.issue_interactive_token(&ip, now - CHALLENGE_TTL_SECS - 1);
+        assert!(!challenge.verify_interactive(&expired, &ip));
+        // PoW tokens are signed for another purpose
+        let pow = challenge.generate_pow_page(&ProtectionLevel::L1, &ip);
+        let pow_token = pow.split("var challenge = \"").nth(1).unwrap().split('"').next().unwrap();
+        assert!(!challenge.verify_interactive(pow_token, &ip));
+    }
+
+    #[test]
+    fn test_nojs_tokens_are_ip_bound_single_use_and_rate_limited() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        let config: ChallengeConfig = toml::from_str(
+            "hmac_secret = \"test\"\nnojs_fallback_enabled = true\npow_difficulty_l1 = 0\nnojs_max_attempts_per_minute = 4",
+        )
+        .unwrap();
+        let challenge = ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()));
+        let now = Utc::now().timestamp();
+
+        // Followed the moment the page was served
+        let (token, sig) = challenge.issue_nojs_token(&ip, now, "aa11");
+        assert!(!challenge.verify_nojs_token(&token, &sig, &ip));
+
+        // A shared link fails for everyone but the client it was issued to,
+        // and for that client only once
+        let (token, sig) = challenge.issue_nojs_token(&ip, now - 5, "bb22");
+        assert!(!challenge.verify_nojs_token(&token, &sig, &other));
+        assert!(challenge.verify_nojs_token(&token, &sig, &ip));
+        assert!(!challenge.verify_nojs_token(&token, &sig, &ip));
+
+        // Redeeming the nojs link of a page uses up its PoW challenge too
+        let page = challenge.generate_challenge_page(&ProtectionLevel::L1, &ip);
+        let pow_token = page.split("var challenge = \"").nth(1).unwrap().split('"').next().unwrap();
+        let random_hex = pow_token.split(':').nth(1).unwrap();
+        assert!(page.contains(&format!(":{}:{}&sig=", random_hex, challenge.hash_ip(&ip))));
+        let (token, sig) = challenge.issue_nojs_token(&ip, now - 5, random_hex);
+        assert!(challenge.verify_nojs_token(&token, &sig, &ip));
+        assert!(!challenge.verify_solution(pow_token, "0"));
+
+        // Four attempts a minute per IP, valid or not
+        let (token, sig) = challenge.issue_nojs_token(&ip, now - 5, "cc33");
+        assert!(!challenge.verify_nojs_token(&token, &sig, &ip));
+        let (token, sig) = challenge.issue_nojs_token(&other, now - 5, "cc33");
+        assert!(challenge.verify_nojs_token(&token, &sig, &other));
+    }
+
+    #[test]
+    fn test_pow_solutions_are_single_use() {
+        let config: ChallengeConfig = toml::from_str("hmac_secret = \"test\"\npow_difficulty_l1 = 0").unwrap();
+        let challenge = ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let page = challenge.generate_challenge_page(&ProtectionLevel::L1, &ip);
+        let pow_token = page.split("var challenge = \"").nth(1).unwrap().split('"').next().unwrap();
+        assert!(challenge.verify_solution(pow_token, "0"));
+        assert!(!challenge.verify_solution(pow_token, "0"));
+        assert!(!challenge.verify_solution(pow_token, "1"));
+    }
+
+    #[test]
+    fn test_clearance_is_bound_to_its_signed_scope() {
+        let challenge = system();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let shared = challenge.generate_clearance_cookie(&ip, &scope("www.example.com", Some("example.com")));
+        assert!(shared.contains("; Domain=example.com;"));
+        let cookie = Some(cookie_pair(&shared));
+        assert!(challenge.has_valid_clearance(&ip, cookie, &scope("api.example.com", Some("example.com"))));
+        assert!(challenge.has_valid_clearance(&ip, cookie, &scope("example.com", None)));
+        assert!(!challenge.has_valid_clearance(&ip, cookie, &scope("example.org", None)));
+        assert!(!challenge.has_valid_clearance(&ip, cookie, &scope("badexample.com", None)));
+
+        let host_only = challenge.generate_clearance_cookie(&ip, &scope("[::1]", None));
+        let cookie = Some(cookie_pair(&host_only));
+        assert!(challenge.has_valid_clearance(&ip, cookie, &scope("[::1]", None)));
+        assert!(!challenge.has_valid_clearance(&ip, cookie, &scope("www.example.com", None)));
+
+        // A tampered scope fails the signature check
+        let forged = cookie_pair(&shared).replace(":.example.com:", ":.example.org:");
+        assert!(!challenge.has_valid_clearance(&ip, Some(&forged), &scope("www.example.org", Some("example.org"))));
+    }
+
+    #[test]
+    fn test_exempt_paths_merge_service_entries_and_filter_methods() {
+        let config: ChallengeConfig = toml::from_str(
+            r#"
+            hmac_secret = "test"
+            exempt_paths = ["/health", { path = "/hooks/*", methods = ["POST"] }]
+            "#,
+        )
+        .unwrap();
+        let challenge = ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()));
+        let service: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "shop",
+            "name": "shop",
+            "domains": ["shop.example.com"],
+            "upstream_address": "127.0.0.1:8080",
+            "exempt_paths": [{ "path": "/webhooks/stripe", "methods": ["post"] }, "/status"],
+        }))
+        .unwrap();
+
+        assert!(challenge.is_exempt("GET", "/health", None));
+        assert!(challenge.is_exempt("POST", "/hooks/github", None));
+        assert!(!challenge.is_exempt("GET", "/hooks/github", None));
+        assert!(challenge.is_exempt("POST", "/webhooks/stripe", Some(&service)));
+        assert!(!challenge.is_exempt("GET", "/webhooks/stripe", Some(&service)));
+        assert!(!challenge.is_exempt("POST", "/webhooks/stripe", None));
+        assert!(challenge.is_exempt("GET", "/status", Some(&service)));
+
+        // Plain strings stay plain strings when stored.
+        let stored = serde_json::to_value(&service.exempt_paths).unwrap();
+        assert_eq!(stored, serde_json::json!([{ "path": "/webhooks/stripe", "methods": ["post"] }, "/status"]));
+    }
+
+    #[test]
+    fn test_secure_attribute_follows_the_connection() {
+        let challenge = system();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let https = challenge.generate_clearance_cookie(&ip, &scope("www.example.com", None));
+        assert!(https.ends_with("; HttpOnly; Secure"));
+
+        let plain = scope("www.example.com", None).over_plain_http();
+        let http = challenge.generate_clearance_cookie(&ip, &plain);
+        assert!(!http.contains("Secure"));
+        assert!(challenge.has_valid_clearance(&ip, Some(cookie_pair(&http)), &plain));
+    }
+
+    #[test]
+    fn test_subnet_binding_uses_configured_ipv6_prefix() {
+        let config: ChallengeConfig = toml::from_str("hmac_secret = \"test\"\ncookie_subnet_binding = true").unwrap();
+        let challenge = ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()));
+        let scope = scope("www.example.com", None);
+        let ip: IpAddr = "2001:db8:1:2::10".parse().unwrap();
+        let set_cookie = challenge.generate_clearance_cookie(&ip, &scope);
+        let cookie = Some(cookie_pair(&set_cookie));
+
+        let same_64: IpAddr = "2001:db8:1:2:abcd::1".parse().unwrap();
+        let other_64: IpAddr = "2001:db8:1:3::10".parse().unwrap();
+        let v4: IpAddr = "203.0.113.7".parse().unwrap();
+        assert!(challenge.has_valid_clearance(&same_64, cookie, &scope));
+        assert!(!challenge.has_valid_clearance(&other_64, cookie, &scope));
+        assert!(!challenge.has_valid_clearance(&v4, cookie, &scope));
+
+        let mut protection = default_protection_config();
+        protection.ipv6_subnet_mask = 48;
+        challenge.reload(&config, &protection);
+        let set_cookie = challenge.generate_clearance_cookie(&ip, &scope);
+        assert!(challenge.has_valid_clearance(&other_64, Some(cookie_pair(&set_cookie)), &scope));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::config::settings::CredentialStuffingConfig;
+use crate::protection::managed_rules::percent_decode;
+use crate::storage::sweep::{Sweep, SweepStats};
+
+/// A username as tracked: the start of its HMAC under a key drawn at
+/// startup, so usernames never reach memory in the clear and the hashes
+/// mean nothing outside this process.
+pub type UsernameHash = [u8; 16];
+
+/// Credential stuffing detection.
+///
+/// Per-IP login rate limits (managed rule 5) miss attackers that spread a
+/// credential list over many IPs, each trying a few passwords. This
+/// detector counts login attempts per username instead: a username tried
+/// from more than `max_ips_per_username` distinct IPs, or more than
+/// `max_attempts_per_username` times, within `window_secs` is under
+/// attack, and further attempts on it are challenged or blocked whatever
+/// their source.
+pub struct CredentialStuffingDetector {
+    key: [u8; 32],
+    usernames: DashMap<UsernameHash, Attempts>,
+    sweep: Sweep,
+    /// Login attempts counted against a username.
+    attempts: AtomicU64,
+    /// Attempts on a username under attack.
+    flagged: AtomicU64,
+    /// Attempts not counted because `max_tracked_usernames` was reached.
+    untracked: AtomicU64,
+}
+
+/// Recent attempts on one username.
+struct Attempts {
+    /// `(when, from)`, oldest first. Only the last
+    /// `max_attempts_per_username + 1` are kept, which is enough to tell
+    /// whether either limit is exceeded.
+    recent: VecDeque<(Instant, IpAddr)>,
+    /// When the newest attempt leaves the window.
+    expires: Instant,
+}
+
+impl Attempts {
+    fn expire(&mut self, window: Duration, now: Instant) {
+        while self.recent.front().is_some_and(|(at, _)| now.duration_since(*at) > window) {
+            self.recent.pop_front();
+        }
+    }
+
+    fn verdict(&self, config: &CredentialStuffingConfig) -> Option<StuffingMatch> {
+        let attempts = self.recent.len();
+        let mut ips: Vec<IpAddr> = self.recent.iter().map(|(_, ip)| *ip).collect();
+        ips.sort_unstable();
+        ips.dedup();
+        (attempts > config.max_attempts_per_username || ips.len() > config.max_ips_per_username)
+            .then_some(StuffingMatch { attempts, ips: ips.len() })
+    }
+}
+
+/// A username under attack, as seen within the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StuffingMatch {
+    pub attempts: usize,
+    pub ips: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CredentialStuffingStats {
+    pub tracked_usernames: usize,
+    pub attempts: u64,
+    pub flagged: u64,
+    pub untracked: u64,
+}
+
+impl Default for CredentialStuffingDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CredentialStuffingDetector {
+    pub fn new() -> Self {
+        Self {
+            key: rand::random(),
+            usernames: DashMap::new(),
+            sweep: Sweep::new(),
+            attempts: AtomicU64::new(0),
+            flagged: AtomicU64::new(0),
+            untracked: AtomicU64::new(0),
+        }
+    }
+
+    /// Hash of the username in `field` of a login body: a form field, or a
+    /// top-level string in a JSON object. Usernames are compared trimmed
+    /// and case-insensitively.
+    pub fn username_hash(&self, body: &[u8], content_type: &str, field: &str) -> Option<UsernameHash> {
+        let username = username_from_body(body, content_type, field)?;
+        let username = username.trim().to_lowercase();
+        if username.is_empty() {
+            return None;
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(username.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let mut hash = UsernameHash::default();
+        hash.copy_from_slice(&digest[..size_of::<UsernameHash>()]);
+        Some(hash)
+    }
+
+    /// Count a login attempt on `username` from `ip`. Returns the match if
+    /// the username is under attack, this attempt included.
+    pub fn check(&self, username: &UsernameHash, ip: IpAddr, config: &CredentialStuffingConfig) -> Option<StuffingMatch> {
+        let now = Instant::now();
+        let window = Duration::from_secs(config.window_secs);
+        let mut entry = match self.usernames.get_mut(username) {
+            Some(entry) => entry,
+            None if self.usernames.len() >= config.max_tracked_usernames => {
+                self.untracked.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            None => self.usernames.entry(*username).or_insert_with(|| Attempts {
+                recent: VecDeque::new(),
+                expires: now,
+            }),
+        };
+        entry.expire(window, now);
+        entry.recent.push_back((now, ip));
+        if entry.recent.len() > config.max_attempts_per_username + 1 {
+            entry.recent.pop_front();
+        }
+        entry.expires = now + window;
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+
+        let verdict = entry.verdict(config);
+        if verdict.is_some() {
+            self.flagged.fetch_add(1, Ordering::Relaxed);
+        }
+        verdict
+    }
+
+    /// [`check`](Self::check) without counting the attempt.
+    pub fn peek(&self, username: &UsernameHash, config: &CredentialStuffingConfig) -> Option<StuffingMatch> {
+        let window = Duration::from_secs(config.window_secs);
+        let now = Instant::now();
+        let entry = self.usernames.get(username)?;
+        let attempts = Attempts {
+            recent: entry.recent.iter().filter(|(at, _)| now.duration_since(*at) <= window).copied().collect(),
+            expires: entry.expires,
+        };
+        attempts.verdict(config)
+    }
+
+    /// Forget usernames with no attempt left in their window.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
+        let now = Instant::now();
+        self.sweep.retain(&self.usernames, budget, |_, attempts| attempts.expires > now)
+    }
+
+    pub fn stats(&self) -> CredentialStuffingStats {
+        CredentialStuffingStats {
+            tracked_usernames: self.usernames.len(),
+            attempts: self.attempts.load(Ordering::Relaxed),
+            flagged: self.flagged.load(Ordering::Relaxed),
+            untracked: self.untracked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The value of `field` in a form or JSON body, if it has one.
+fn username_from_body(body: &[u8], content_type: &str, field: &str) -> Option<String> {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if media_type == "application/x-www-form-urlencoded" {
+        return body.split(|&b| b == b'&').find_map(|pair| {
+            let mut parts = pair.splitn(2, |&b| b == b'=');
+            if percent_decode(parts.next()?) != field.as_bytes() {
+                return None;
+            }
+            Some(String::from_utf8_lossy(&percent_decode(parts.next().unwrap_or_default())).into_owned())
+        });
+    }
+    if media_type.ends_with("json") {
+        let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+        return value.get(field)?.as_str().map(str::to_string);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::defaults::default_credential_stuffing_config;
+
+    #[test]
+    fn test_flags_usernames_tried_from_many_ips_or_too_often() {
+        let detector = CredentialStuffingDetector::new();
+        let mut config = default_credential_stuffing_config();
+        config.max_ips_per_username = 3;
+        config.max_attempts_per_username = 5;
+        let form = "application/x-www-form-urlencoded";
+        let alice = detector.username_hash(b"user=Alice%40example.com&password=x", form, "user").unwrap();
+        let json = br#"{"password":"y","user":" alice@example.com "}"#;
+        assert_eq!(detector.username_hash(json, "application/json", "user"), Some(alice));
+        assert_eq!(detector.username_hash(b"password=x", form, "user"), None);
+
+        // Spread over IPs: the fourth distinct IP crosses the limit
+        for i in 1..=3 {
+            assert_eq!(detector.check(&alice, IpAddr::from([10, 0, 0, i]), &config), None);
+        }
+        let hit = detector.check(&alice, IpAddr::from([10, 0, 0, 4]), &config);
+        assert_eq!(hit, Some(StuffingMatch { attempts: 4, ips: 4 }));
+        assert_eq!(detector.peek(&alice, &config), hit);
+
+        // From one IP: only the attempt count matters
+        let bob = detector.username_hash(b"user=bob", form, "user").unwrap();
+        let ip = IpAddr::from([10, 0, 1, 1]);
+        for _ in 0..5 {
+            assert_eq!(detector.check(&bob, ip, &config), None);
+        }
+        assert_eq!(detector.check(&bob, ip, &config), Some(StuffingMatch { attempts: 6, ips: 1 }));
+
+        // Past the cap, new usernames are not tracked
+        config.max_tracked_usernames = 2;
+        let carol = detector.username_hash(b"user=carol", form, "user").unwrap();
+        assert_eq!(detector.check(&carol, ip, &config), None);
+        let stats = detector.stats();
+        assert_eq!((stats.tracked_usernames, stats.attempts, stats.flagged, stats.untracked), (2, 10, 2, 1));
+    }
+}
@@ -1,12 +1,26 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use parking_lot::RwLock;
-use serde::Deserialize;
-use tracing::{debug, warn};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
 use crate::models::request::RequestContext;
-use crate::models::threat::ThreatAction;
+use crate::models::schedule::Schedule;
+use crate::protection::ip_reputation::ReputationCategory;
+use crate::protection::managed_rules::{EndpointRateTracker, ManagedRulesEngine, RATE_LIMIT_RULES};
 use crate::storage::sqlite::SqliteStore;
+use crate::storage::sweep::SweepStats;
+
+/// How many recent matches are kept per rule for `/rules/{id}/matches`.
+const RECENT_MATCHES_PER_RULE: usize = 50;
+
+/// Score added by a `score` rule that does not set its own.
+const DEFAULT_RULE_SCORE: f64 = 25.0;
 
 /// A cached custom rule loaded from the database.
 #[derive(Debug, Clone)]
@@ -14,82 +28,335 @@ pub struct CachedRule {
     pub id: i64,
     pub name: String,
     pub priority: i32,
-    pub condition: RuleCondition,
-    pub action: ThreatAction,
+    pub conditions_json: String,
+    pub compiled: Arc<CompiledRule>,
+    pub action: CustomAction,
     pub enabled: bool,
+    /// Dry-run: matches are recorded but the action is not enforced.
+    pub log_only: bool,
+    /// Only evaluated inside this UTC window.
+    pub schedule: Option<Schedule>,
 }
 
-/// Parsed rule condition supporting path, method, country, IP, user-agent matching.
+/// Rule condition as stored in `conditions_json`.
+///
+/// Every field that is set must match (AND). `all`, `any` and `not` nest
+/// further conditions, e.g.
+/// `{"any": [{"path_regex": "^/wp-"}, {"not": {"method": "GET"}}], "country": "CN"}`.
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct RuleCondition {
+    /// Wildcard path pattern (`*` at start and/or end).
     #[serde(default)]
     pub path: Option<String>,
     #[serde(default)]
-    pub method: Option<String>,
+    pub path_regex: Option<String>,
+    /// Case-insensitive substring of the raw query string.
     #[serde(default)]
-    pub country: Option<String>,
+    pub query: Option<String>,
+    #[serde(default)]
+    pub query_regex: Option<String>,
+    /// A single method or a list of methods.
+    #[serde(default)]
+    pub method: Option<OneOrMany<String>>,
+    #[serde(default)]
+    pub country: Option<OneOrMany<String>>,
+    #[serde(default)]
+    pub asn: Option<OneOrMany<u32>>,
+    #[serde(default)]
+    pub ja3: Option<OneOrMany<String>>,
     #[serde(default)]
     pub ip: Option<String>,
     #[serde(default)]
     pub user_agent: Option<String>,
     #[serde(default)]
     pub host: Option<String>,
+    /// Header name -> case-insensitive substring of its value.
     #[serde(default)]
-    pub header: Option<std::collections::HashMap<String, String>>,
+    pub header: Option<HashMap<String, String>>,
+    /// Header names that must be present.
+    #[serde(default)]
+    pub header_present: Option<Vec<String>>,
+    /// Header name -> regex its value must match.
+    #[serde(default)]
+    pub header_regex: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub all: Option<Vec<RuleCondition>>,
+    #[serde(default)]
+    pub any: Option<Vec<RuleCondition>>,
+    #[serde(default)]
+    pub not: Option<Box<RuleCondition>>,
 }
 
-impl RuleCondition {
-    /// Check if the request matches ALL specified conditions (AND logic).
-    pub fn matches(&self, ctx: &RequestContext) -> bool {
-        if let Some(ref path_pattern) = self.path {
-            if !pattern_matches(path_pattern, &ctx.path) {
-                return false;
-            }
-        }
+/// Full `conditions_json` document: a [`RuleCondition`] plus the fields
+/// that select the rule kind.
+///
+/// `{"kind": "rate_limit", "path": "/login*", "method": "POST", "limit": 5,
+/// "window_secs": 60, "key": "ip"}` only matches once an IP sends more than
+/// five matching requests in a minute. `overrides` names a managed
+/// rate-limit rule (5, 6, 7 or 19) that this rule replaces.
+#[derive(Debug, Deserialize)]
+struct RuleSpec {
+    /// `match` (default) or `rate_limit`.
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    limit: Option<u32>,
+    #[serde(default)]
+    window_secs: Option<u64>,
+    /// `ip` (default) or `ip_path`.
+    #[serde(default)]
+    key: Option<String>,
+    /// Score added when the rule action is `score`.
+    #[serde(default)]
+    score: Option<f64>,
+    #[serde(default)]
+    overrides: Option<u32>,
+    /// Reputation category a match tags the client IP with, e.g.
+    /// `scanner`, `brute_force` or `ddos`.
+    #[serde(default)]
+    category: Option<ReputationCategory>,
+    #[serde(flatten)]
+    condition: RuleCondition,
+}
 
-        if let Some(ref method) = self.method {
-            if !method.eq_ignore_ascii_case(&ctx.method) {
-                return false;
-            }
+/// How requests are grouped into rate-limit counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateKey {
+    /// One counter per client IP.
+    Ip,
+    /// One counter per client IP and exact request path.
+    IpPath,
+}
+
+/// Rate limit attached to a `rate_limit` rule.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub window_secs: u64,
+    pub key: RateKey,
+}
+
+/// A compiled `conditions_json`.
+#[derive(Debug)]
+pub struct CompiledRule {
+    pub matcher: Matcher,
+    pub rate_limit: Option<RateLimit>,
+    pub score: Option<f64>,
+    /// Managed rule replaced while this rule is enabled and enforced.
+    pub overrides: Option<u32>,
+    pub category: Option<ReputationCategory>,
+}
+
+/// What an enforced custom rule does to the request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CustomAction {
+    Pass,
+    Challenge,
+    Tarpit,
+    Block,
+    /// Add to the request's threat score and keep evaluating.
+    Score(f64),
+}
+
+/// A value that may be given as a single item or a list.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(v) => vec![v],
+            OneOrMany::Many(v) => v,
         }
+    }
+}
 
-        if let Some(ref country) = self.country {
-            let ctx_country = ctx.country_code.as_deref().unwrap_or("");
-            if !country.eq_ignore_ascii_case(ctx_country) {
-                return false;
-            }
+/// A compiled condition tree. Regexes are compiled once when the rule is
+/// loaded, so evaluation only does matching.
+#[derive(Debug)]
+pub enum Matcher {
+    All(Vec<Matcher>),
+    Any(Vec<Matcher>),
+    Not(Box<Matcher>),
+    Path(String),
+    PathRegex(Regex),
+    Query(String),
+    QueryRegex(Regex),
+    Method(Vec<String>),
+    Country(Vec<String>),
+    Asn(Vec<u32>),
+    Ja3(Vec<String>),
+    Ip(String),
+    UserAgent(String),
+    Host(String),
+    HeaderContains(String, String),
+    HeaderPresent(String),
+    HeaderRegex(String, Regex),
+}
+
+impl RuleCondition {
+    /// Compile into a [`Matcher`]. Fails on an invalid regex.
+    pub fn compile(self) -> Result<Matcher, regex::Error> {
+        let mut parts = Vec::new();
+
+        if let Some(p) = self.path {
+            parts.push(Matcher::Path(p));
+        }
+        if let Some(re) = self.path_regex {
+            parts.push(Matcher::PathRegex(Regex::new(&re)?));
+        }
+        if let Some(q) = self.query {
+            parts.push(Matcher::Query(q.to_lowercase()));
+        }
+        if let Some(re) = self.query_regex {
+            parts.push(Matcher::QueryRegex(Regex::new(&re)?));
+        }
+        if let Some(m) = self.method {
+            parts.push(Matcher::Method(m.into_vec()));
+        }
+        if let Some(c) = self.country {
+            parts.push(Matcher::Country(c.into_vec()));
+        }
+        if let Some(a) = self.asn {
+            parts.push(Matcher::Asn(a.into_vec()));
+        }
+        if let Some(j) = self.ja3 {
+            parts.push(Matcher::Ja3(j.into_vec().into_iter().map(|h| h.to_lowercase()).collect()));
+        }
+        if let Some(ip) = self.ip {
+            parts.push(Matcher::Ip(ip));
+        }
+        if let Some(ua) = self.user_agent {
+            parts.push(Matcher::UserAgent(ua.to_lowercase()));
+        }
+        if let Some(h) = self.host {
+            parts.push(Matcher::Host(h));
+        }
+        for (name, value) in self.header.unwrap_or_default() {
+            parts.push(Matcher::HeaderContains(name.to_lowercase(), value.to_lowercase()));
+        }
+        for name in self.header_present.unwrap_or_default() {
+            parts.push(Matcher::HeaderPresent(name.to_lowercase()));
+        }
+        for (name, re) in self.header_regex.unwrap_or_default() {
+            parts.push(Matcher::HeaderRegex(name.to_lowercase(), Regex::new(&re)?));
+        }
+        if let Some(all) = self.all {
+            let nested = all.into_iter().map(RuleCondition::compile).collect::<Result<_, _>>()?;
+            parts.push(Matcher::All(nested));
+        }
+        if let Some(any) = self.any {
+            let nested = any.into_iter().map(RuleCondition::compile).collect::<Result<_, _>>()?;
+            parts.push(Matcher::Any(nested));
+        }
+        if let Some(not) = self.not {
+            parts.push(Matcher::Not(Box::new(not.compile()?)));
         }
 
-        if let Some(ref ip_pattern) = self.ip {
-            let ip_str = ctx.client_ip.to_string();
-            if !pattern_matches(ip_pattern, &ip_str) {
-                return false;
+        Ok(match parts.len() {
+            1 => parts.pop().expect("one part"),
+            _ => Matcher::All(parts),
+        })
+    }
+}
+
+/// Parse and compile a `conditions_json` string.
+pub fn compile_conditions(json: &str) -> Result<CompiledRule, String> {
+    let spec: RuleSpec = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+    let rate_limit = match spec.kind.as_deref().unwrap_or("match") {
+        "match" => {
+            if spec.limit.is_some() || spec.window_secs.is_some() || spec.key.is_some() {
+                return Err("limit, window_secs and key require \"kind\": \"rate_limit\"".into());
             }
+            if spec.overrides.is_some() {
+                return Err("overrides requires \"kind\": \"rate_limit\"".into());
+            }
+            None
         }
-
-        if let Some(ref ua_pattern) = self.user_agent {
-            let ua = ctx.user_agent.as_deref().unwrap_or("");
-            if !ua.to_lowercase().contains(&ua_pattern.to_lowercase()) {
-                return false;
+        "rate_limit" => {
+            let limit = spec.limit.ok_or("rate_limit rules need a limit")?;
+            let window_secs = spec.window_secs.ok_or("rate_limit rules need window_secs")?;
+            if limit == 0 || window_secs == 0 {
+                return Err("limit and window_secs must be greater than zero".into());
             }
+            let key = match spec.key.as_deref().unwrap_or("ip") {
+                "ip" => RateKey::Ip,
+                "ip_path" => RateKey::IpPath,
+                other => return Err(format!("unknown rate limit key '{}' (use ip or ip_path)", other)),
+            };
+            Some(RateLimit { limit, window_secs, key })
         }
+        other => return Err(format!("unknown rule kind '{}' (use match or rate_limit)", other)),
+    };
 
-        if let Some(ref host_pattern) = self.host {
-            if !pattern_matches(host_pattern, &ctx.host) {
-                return false;
-            }
+    if let Some(id) = spec.overrides {
+        if !RATE_LIMIT_RULES.contains(&id) {
+            return Err(format!(
+                "overrides must name a managed rate-limit rule ({:?})",
+                RATE_LIMIT_RULES
+            ));
         }
+    }
+    if spec.score.is_some_and(|s| !s.is_finite() || s < 0.0) {
+        return Err("score must be a non-negative number".into());
+    }
 
-        if let Some(ref header_map) = self.header {
-            for (key, expected_val) in header_map {
-                let actual = ctx.headers.get(&key.to_lowercase()).map(|s| s.as_str()).unwrap_or("");
-                if !actual.to_lowercase().contains(&expected_val.to_lowercase()) {
-                    return false;
-                }
+    Ok(CompiledRule {
+        matcher: spec.condition.compile().map_err(|e| e.to_string())?,
+        rate_limit,
+        score: spec.score,
+        overrides: spec.overrides,
+        category: spec.category,
+    })
+}
+
+impl Matcher {
+    /// Evaluate against a request. An empty `All` matches everything.
+    pub fn matches(&self, ctx: &RequestContext) -> bool {
+        match self {
+            Matcher::All(parts) => parts.iter().all(|m| m.matches(ctx)),
+            Matcher::Any(parts) => parts.iter().any(|m| m.matches(ctx)),
+            Matcher::Not(inner) => !inner.matches(ctx),
+            Matcher::Path(pattern) => pattern_matches(pattern, &ctx.path),
+            Matcher::PathRegex(re) => re.is_match(&ctx.path),
+            Matcher::Query(needle) => ctx
+                .query
+                .as_deref()
+                .is_some_and(|q| q.to_lowercase().contains(needle.as_str())),
+            Matcher::QueryRegex(re) => re.is_match(ctx.query.as_deref().unwrap_or("")),
+            Matcher::Method(methods) => methods.iter().any(|m| m.eq_ignore_ascii_case(&ctx.method)),
+            Matcher::Country(countries) => {
+                let ctx_country = ctx.country_code.as_deref().unwrap_or("");
+                countries.iter().any(|c| c.eq_ignore_ascii_case(ctx_country))
             }
+            Matcher::Asn(asns) => ctx.asn.is_some_and(|asn| asns.contains(&asn)),
+            Matcher::Ja3(hashes) => ctx
+                .ja3_hash
+                .as_deref()
+                .is_some_and(|h| hashes.iter().any(|x| x.eq_ignore_ascii_case(h))),
+            Matcher::Ip(pattern) => pattern_matches(pattern, &ctx.client_ip.to_string()),
+            Matcher::UserAgent(needle) => ctx
+                .user_agent
+                .as_deref()
+                .unwrap_or("")
+                .to_lowercase()
+                .contains(needle.as_str()),
+            Matcher::Host(pattern) => pattern_matches(pattern, &ctx.host),
+            Matcher::HeaderContains(name, needle) => ctx
+                .headers
+                .get(name)
+                .map(|s| s.as_str())
+                .unwrap_or("")
+                .to_lowercase()
+                .contains(needle.as_str()),
+            Matcher::HeaderPresent(name) => ctx.headers.contains_key(name),
+            Matcher::HeaderRegex(name, re) => ctx.headers.get(name).is_some_and(|v| re.is_match(v)),
         }
-
-        true
     }
 }
 
@@ -113,45 +380,101 @@ fn pattern_matches(pattern: &str, value: &str) -> bool {
     value == pattern
 }
 
-fn parse_action(action: &str) -> ThreatAction {
+fn parse_action(action: &str, score: Option<f64>) -> CustomAction {
     match action.to_lowercase().as_str() {
-        "pass" | "allow" => ThreatAction::Pass,
-        "challenge" => ThreatAction::Challenge,
-        "tarpit" => ThreatAction::Tarpit,
-        _ => ThreatAction::Block,
+        "pass" | "allow" => CustomAction::Pass,
+        "challenge" => CustomAction::Challenge,
+        "tarpit" => CustomAction::Tarpit,
+        "score" => CustomAction::Score(score.unwrap_or(DEFAULT_RULE_SCORE)),
+        _ => CustomAction::Block,
     }
 }
 
+/// A single recorded rule match.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    pub timestamp: String,
+    pub ip: String,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub country: Option<String>,
+    /// Whether the rule's action was applied (false for log-only rules).
+    pub enforced: bool,
+}
+
+/// Match counter and recent samples for one rule.
+#[derive(Default)]
+struct RuleMatchLog {
+    count: AtomicU64,
+    recent: Mutex<VecDeque<RuleMatch>>,
+}
+
 /// Engine that caches custom rules from the database and evaluates them.
 pub struct CustomRulesEngine {
     sqlite: Arc<SqliteStore>,
     rules: RwLock<Vec<CachedRule>>,
-    last_reload: RwLock<Instant>,
     reload_interval: Duration,
+    matches: DashMap<i64, RuleMatchLog>,
+    /// Counters for `rate_limit` rules, keyed by (IP, `rule:{id}[:path]`).
+    rate_counters: EndpointRateTracker,
+    /// Told which of its rate-limit rules are overridden after each reload.
+    managed_rules: Arc<ManagedRulesEngine>,
 }
 
 impl CustomRulesEngine {
-    pub fn new(sqlite: Arc<SqliteStore>) -> Self {
-        let engine = Self {
+    pub fn new(sqlite: Arc<SqliteStore>, managed_rules: Arc<ManagedRulesEngine>) -> Self {
+        Self {
             sqlite,
             rules: RwLock::new(Vec::new()),
-            last_reload: RwLock::new(Instant::now() - Duration::from_secs(999)),
             reload_interval: Duration::from_secs(5),
-        };
-        engine.reload_rules();
-        engine
+            matches: DashMap::new(),
+            rate_counters: EndpointRateTracker::new(),
+            managed_rules,
+        }
     }
 
-    /// Reload rules from the database (called periodically).
-    fn reload_rules(&self) {
-        match self.sqlite.get_rules() {
+    /// Keep the cached rules in sync with the database. Reloading happens
+    /// here rather than on the request path so `check` never touches SQLite.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.reload_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            self.reload_rules().await;
+        }
+    }
+
+    /// Reload rules from the database.
+    ///
+    /// Rules whose `conditions_json` is unchanged keep their compiled
+    /// matcher, so regexes are only recompiled when a rule is edited.
+    pub async fn reload_rules(&self) {
+        match self.sqlite.get_rules().await {
             Ok(rows) => {
+                let previous: HashMap<i64, (String, Arc<CompiledRule>)> = self
+                    .rules
+                    .read()
+                    .iter()
+                    .map(|r| (r.id, (r.conditions_json.clone(), r.compiled.clone())))
+                    .collect();
+
                 let mut rules = Vec::new();
                 for row in rows {
-                    let condition: RuleCondition = match serde_json::from_str(&row.conditions_json) {
-                        Ok(c) => c,
+                    let compiled = match previous.get(&row.id) {
+                        Some((json, compiled)) if *json == row.conditions_json => compiled.clone(),
+                        _ => match compile_conditions(&row.conditions_json) {
+                            Ok(m) => Arc::new(m),
+                            Err(e) => {
+                                warn!(rule_id = row.id, error = %e, "Failed to compile rule condition");
+                                continue;
+                            }
+                        },
+                    };
+                    let schedule = match row.schedule.parse() {
+                        Ok(schedule) => schedule,
                         Err(e) => {
-                            warn!(rule_id = row.id, error = %e, "Failed to parse rule condition JSON");
+                            warn!(rule_id = row.id, error = %e, "Invalid rule schedule");
                             continue;
                         }
                     };
@@ -159,15 +482,30 @@ impl CustomRulesEngine {
                         id: row.id,
                         name: row.name,
                         priority: row.priority,
-                        condition,
-                        action: parse_action(&row.action),
+                        action: parse_action(&row.action, compiled.score),
+                        conditions_json: row.conditions_json,
+                        compiled,
                         enabled: row.enabled,
+                        log_only: row.log_only,
+                        schedule,
                     });
                 }
                 // Sort by priority (lower = higher priority)
                 rules.sort_by_key(|r| r.priority);
+
+                // Drop match logs of deleted rules
+                self.matches.retain(|id, _| rules.iter().any(|r| r.id == *id));
+
+                // Reloads run every few seconds, so a scheduled override
+                // follows its window closely enough.
+                let overridden: HashSet<u32> = rules
+                    .iter()
+                    .filter(|r| r.enabled && !r.log_only && r.schedule.is_none_or(|s| s.is_active()))
+                    .filter_map(|r| r.compiled.overrides)
+                    .collect();
+                self.managed_rules.set_overridden(overridden);
+
                 *self.rules.write() = rules;
-                *self.last_reload.write() = Instant::now();
             }
             Err(e) => {
                 warn!(error = %e, "Failed to reload custom rules from database");
@@ -175,25 +513,47 @@ impl CustomRulesEngine {
         }
     }
 
-    /// Ensure rules are up-to-date (reload if stale).
-    fn ensure_fresh(&self) {
-        let elapsed = self.last_reload.read().elapsed();
-        if elapsed >= self.reload_interval {
-            self.reload_rules();
-        }
+    /// Evaluate all enabled custom rules against a request.
+    /// Returns the first matching enforced rule's action and category, or
+    /// None.
+    /// Log-only rules are recorded and evaluation continues past them.
+    /// A `rate_limit` rule counts every request its condition matches and
+    /// only itself matches once the count goes over the limit. Scheduled
+    /// rules are skipped outside their window.
+    pub fn check(&self, ctx: &RequestContext) -> Option<(CustomAction, String, Option<ReputationCategory>)> {
+        self.check_at(ctx, Utc::now(), false)
     }
 
-    /// Evaluate all enabled custom rules against a request.
-    /// Returns the first matching rule's action, or None.
-    pub fn check(&self, ctx: &RequestContext) -> Option<(ThreatAction, String)> {
-        self.ensure_fresh();
+    /// What [`check`](Self::check) would return, without counting the
+    /// request towards `rate_limit` rules or recording matches.
+    pub fn peek(&self, ctx: &RequestContext) -> Option<(CustomAction, String, Option<ReputationCategory>)> {
+        self.check_at(ctx, Utc::now(), true)
+    }
 
+    fn check_at(&self, ctx: &RequestContext, now: DateTime<Utc>, dry_run: bool) -> Option<(CustomAction, String, Option<ReputationCategory>)> {
         let rules = self.rules.read();
         for rule in rules.iter() {
-            if !rule.enabled {
+            if !rule.enabled || rule.schedule.is_some_and(|s| !s.is_active_at(now)) {
                 continue;
             }
-            if rule.condition.matches(ctx) {
+            if rule.compiled.matcher.matches(ctx) && self.over_rate_limit(rule, ctx, dry_run) {
+                if dry_run {
+                    if rule.log_only {
+                        continue;
+                    }
+                    return Some((rule.action, format!("Custom rule: {}", rule.name), rule.compiled.category));
+                }
+                self.record_match(rule.id, ctx, !rule.log_only);
+                if rule.log_only {
+                    info!(
+                        ip = %ctx.client_ip,
+                        rule_name = %rule.name,
+                        rule_id = rule.id,
+                        action = ?rule.action,
+                        "Custom rule matched (log only)"
+                    );
+                    continue;
+                }
                 debug!(
                     ip = %ctx.client_ip,
                     rule_name = %rule.name,
@@ -202,12 +562,131 @@ impl CustomRulesEngine {
                     "Custom rule matched"
                 );
                 return Some((
-                    rule.action.clone(),
+                    rule.action,
                     format!("Custom rule: {}", rule.name),
+                    rule.compiled.category,
                 ));
             }
         }
 
         None
     }
+
+    /// True when the rule has no rate limit or the request takes its
+    /// counter over the limit.
+    fn over_rate_limit(&self, rule: &CachedRule, ctx: &RequestContext, dry_run: bool) -> bool {
+        let Some(rl) = rule.compiled.rate_limit else {
+            return true;
+        };
+        let bucket = match rl.key {
+            RateKey::Ip => format!("rule:{}", rule.id),
+            RateKey::IpPath => format!("rule:{}:{}", rule.id, ctx.path),
+        };
+        let ip = ctx.client_ip.to_string();
+        if dry_run {
+            self.rate_counters.peek(&ip, &bucket, rl.limit, rl.window_secs)
+        } else {
+            self.rate_counters.check(&ip, &bucket, rl.limit, rl.window_secs)
+        }
+    }
+
+    /// Drop expired rate-limit counters, visiting about `budget` of them.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
+        self.rate_counters.cleanup(budget)
+    }
+
+    /// Total matches and most recent matches (newest first) for a rule.
+    pub fn get_matches(&self, rule_id: i64) -> (u64, Vec<RuleMatch>) {
+        match self.matches.get(&rule_id) {
+            Some(log) => (
+                log.count.load(Ordering::Relaxed),
+                log.recent.lock().iter().rev().cloned().collect(),
+            ),
+            None => (0, Vec::new()),
+        }
+    }
+
+    fn record_match(&self, rule_id: i64, ctx: &RequestContext, enforced: bool) {
+        let log = self.matches.entry(rule_id).or_default();
+        log.count.fetch_add(1, Ordering::Relaxed);
+        let mut recent = log.recent.lock();
+        if recent.len() >= RECENT_MATCHES_PER_RULE {
+            recent.pop_front();
+        }
+        recent.push_back(RuleMatch {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            ip: ctx.client_ip.to_string(),
+            method: ctx.method.clone(),
+            host: ctx.host.clone(),
+            path: ctx.path.clone(),
+            country: ctx.country_code.clone(),
+            enforced,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::schedule::ScheduleFields;
+    use crate::storage::sqlite::tests::TempDb;
+
+    #[test]
+    fn test_compile_rate_limit_rule() {
+        let rule = compile_conditions(
+            r#"{"kind": "rate_limit", "path": "/login*", "method": "POST",
+                "limit": 5, "window_secs": 60, "key": "ip_path", "overrides": 5}"#,
+        )
+        .unwrap();
+        let rl = rule.rate_limit.unwrap();
+        assert_eq!((rl.limit, rl.window_secs, rl.key), (5, 60, RateKey::IpPath));
+        assert_eq!(rule.overrides, Some(5));
+        assert!(matches!(rule.matcher, Matcher::All(ref parts) if parts.len() == 2));
+
+        assert!(compile_conditions(r#"{"path": "/x", "limit": 5}"#).is_err());
+        assert!(compile_conditions(r#"{"kind": "rate_limit", "limit": 5}"#).is_err());
+        assert!(compile_conditions(
+            r#"{"kind": "rate_limit", "limit": 5, "window_secs": 60, "overrides": 8}"#
+        )
+        .is_err());
+        assert!(compile_conditions(r#"{"kind": "quota"}"#).is_err());
+    }
+
+    #[test]
+    fn test_compile_rule_category() {
+        let rule = compile_conditions(r#"{"path_regex": "^/cgi-bin/", "category": "scanner"}"#).unwrap();
+        assert_eq!(rule.category, Some(ReputationCategory::Scanner));
+        let rule = compile_conditions(r#"{"path": "/api/*", "category": "ddos"}"#).unwrap();
+        assert_eq!(rule.category, Some(ReputationCategory::DDoS));
+        assert_eq!(compile_conditions(r#"{"path": "/"}"#).unwrap().category, None);
+        assert!(compile_conditions(r#"{"path": "/", "category": "spammer"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_rule_only_matches_inside_its_window() {
+        let db = TempDb::new("rules");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let nightly = ScheduleFields {
+            active_from: Some("01:00".to_string()),
+            active_to: Some("05:00".to_string()),
+            active_days: None,
+        };
+        sqlite
+            .add_rule("night scrapers", 1, r#"{"asn": 64500}"#, "block", false, &nightly)
+            .await
+            .unwrap();
+        let engine = CustomRulesEngine::new(sqlite.clone(), Arc::new(ManagedRulesEngine::new()));
+        engine.reload_rules().await;
+
+        let mut ctx = RequestContext::new(
+            "198.51.100.1".parse().unwrap(),
+            "GET".to_string(),
+            "/".to_string(),
+            "example.com".to_string(),
+        );
+        ctx.asn = Some(64500);
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        assert!(engine.check_at(&ctx, at("2026-03-06T02:30:00Z"), false).is_some());
+        assert!(engine.check_at(&ctx, at("2026-03-06T12:00:00Z"), false).is_none());
+    }
 }
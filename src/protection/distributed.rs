@@ -1,20 +1,38 @@
 use std::net::IpAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use parking_lot::RwLock;
+use serde::Serialize;
 use tracing::{debug, info};
 
+use crate::analytics::alerting::{AlertManager, Severity};
+use crate::config::settings::DistributedMitigationConfig;
+use crate::protection::challenge::glob_match;
+use crate::storage::sweep::{Sweep, SweepStats};
+
+/// Share of the hot path's requests one method must have for a mitigation
+/// to be limited to that method.
+const DOMINANT_METHOD_SHARE: f64 = 0.9;
+
 /// Tracks traffic patterns to detect distributed/coordinated attacks.
 ///
 /// Detection signals (need >= 2 to trigger):
 /// 1. Path concentration: >70% of requests hit the same path
 /// 2. UA entropy: Low user-agent diversity (< 5 unique UAs for 50+ requests)
 /// 3. New IP ratio: >80% of IPs are first-time visitors
+///
+/// While an attack is detected and one path draws most of the traffic, an
+/// active mitigation for that path is created; see
+/// [`DistributedMitigationConfig`].
 pub struct DistributedDetector {
     /// Per-path request counts in current window
     path_counts: DashMap<String, u32>,
+    /// Per-(path, method) request counts in current window
+    path_method_counts: DashMap<(String, String), u32>,
     /// Per-UA counts in current window
     ua_counts: DashMap<String, u32>,
     /// Total requests in current window
@@ -23,6 +41,7 @@ pub struct DistributedDetector {
     window_ips: DashMap<IpAddr, ()>,
     /// IPs seen before this window (known IPs)
     known_ips: DashMap<IpAddr, Instant>,
+    known_ips_sweep: Sweep,
     /// New IPs in current window (not in known_ips)
     new_ip_count: std::sync::atomic::AtomicU32,
     /// Window start time
@@ -33,6 +52,42 @@ pub struct DistributedDetector {
     attack_active: AtomicBool,
     /// Attack details for the current/last detection
     last_attack: RwLock<Option<AttackInfo>>,
+    mitigations: RwLock<Vec<ActiveMitigation>>,
+    next_mitigation_id: AtomicU64,
+    /// (path, method) of automatic mitigations an admin removed; not
+    /// re-created until the attack subsides.
+    dismissed: DashMap<(String, Option<String>), ()>,
+    alerting: Arc<AlertManager>,
+}
+
+/// A path (`*` wildcards) whose requests are challenged or blocked before
+/// scoring until `expires_at`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Mitigation {
+    pub id: u64,
+    pub path: String,
+    /// Only requests with this method match; `None` matches all.
+    pub method: Option<String>,
+    /// `challenge` or `block`.
+    pub action: String,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub hits: u64,
+}
+
+struct ActiveMitigation {
+    mitigation: Mitigation,
+    hits: AtomicU64,
+}
+
+impl ActiveMitigation {
+    fn matches(&self, method: &str, path: &str, now: DateTime<Utc>) -> bool {
+        let m = &self.mitigation;
+        m.expires_at > now
+            && m.method.as_deref().is_none_or(|wanted| wanted.eq_ignore_ascii_case(method))
+            && glob_match(&m.path, path)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -53,29 +108,46 @@ pub struct DistributedCheckResult {
 }
 
 impl DistributedDetector {
-    pub fn new() -> Self {
+    pub fn new(alerting: Arc<AlertManager>) -> Self {
         Self {
             path_counts: DashMap::new(),
+            path_method_counts: DashMap::new(),
             ua_counts: DashMap::new(),
             total_requests: std::sync::atomic::AtomicU32::new(0),
             window_ips: DashMap::new(),
             known_ips: DashMap::with_capacity(100_000),
+            known_ips_sweep: Sweep::new(),
             new_ip_count: std::sync::atomic::AtomicU32::new(0),
             window_start: RwLock::new(Instant::now()),
             window_duration: Duration::from_secs(30),
             attack_active: AtomicBool::new(false),
             last_attack: RwLock::new(None),
+            mitigations: RwLock::new(Vec::new()),
+            next_mitigation_id: AtomicU64::new(1),
+            dismissed: DashMap::new(),
+            alerting,
         }
     }
 
     /// Record a request and check for distributed attack patterns.
     /// Returns a score modifier and whether this is a new IP during an attack.
-    pub fn check(&self, ip: IpAddr, path: &str, user_agent: Option<&str>) -> DistributedCheckResult {
+    pub fn check(
+        &self,
+        ip: IpAddr,
+        method: &str,
+        path: &str,
+        user_agent: Option<&str>,
+        mitigation: &DistributedMitigationConfig,
+    ) -> DistributedCheckResult {
         self.maybe_rotate_window();
 
         // Record request
         self.total_requests.fetch_add(1, Ordering::Relaxed);
         *self.path_counts.entry(path.to_string()).or_insert(0) += 1;
+        *self
+            .path_method_counts
+            .entry((path.to_string(), method.to_string()))
+            .or_insert(0) += 1;
         let ua = user_agent.unwrap_or("").to_string();
         *self.ua_counts.entry(ua).or_insert(0) += 1;
 
@@ -104,8 +176,9 @@ impl DistributedDetector {
 
         // Signal 1: Path concentration (>70% same path)
         let top_path = self.get_top_path();
+        let mut concentration = 0.0;
         if let Some((ref path_name, count)) = top_path {
-            let concentration = count as f64 / total as f64;
+            concentration = count as f64 / total as f64;
             if concentration > 0.70 {
                 signals.push(format!("path_concentration:{:.0}%:{}", concentration * 100.0, path_name));
             }
@@ -137,7 +210,7 @@ impl DistributedDetector {
             let attack_info = AttackInfo {
                 detected_at: Instant::now(),
                 signals: signals.clone(),
-                top_path: top_path.map(|(p, _)| p).unwrap_or_default(),
+                top_path: top_path.as_ref().map(|(p, _)| p.clone()).unwrap_or_default(),
                 request_count: total,
                 unique_ips: total_ips,
                 new_ip_ratio: new_ratio,
@@ -153,19 +226,31 @@ impl DistributedDetector {
         } else if !is_attack && self.attack_active.load(Ordering::Relaxed) {
             // Attack subsided
             self.attack_active.store(false, Ordering::Relaxed);
+            self.dismissed.clear();
             info!("Distributed attack subsided");
         }
 
-        // Score modifier: attack + new IP = +30, attack + existing IP = +10
-        let score_modifier = if is_attack {
-            if is_new { 30.0 } else { 10.0 }
-        } else {
-            0.0
-        };
+        if is_attack && mitigation.enabled && concentration >= mitigation.min_path_share {
+            if let Some((hot_path, count)) = &top_path {
+                self.mitigate_hot_path(hot_path, *count, concentration, &signals, mitigation);
+            }
+        }
 
         DistributedCheckResult {
             is_attack,
-            score_modifier,
+            score_modifier: score_modifier(is_attack, is_new),
+            is_new_ip: is_new,
+        }
+    }
+
+    /// What [`check`](Self::check) would return for `ip`, judged on the
+    /// current attack state without recording a request.
+    pub fn peek(&self, ip: IpAddr) -> DistributedCheckResult {
+        let is_attack = self.is_attack_active();
+        let is_new = !self.known_ips.contains_key(&ip);
+        DistributedCheckResult {
+            is_attack,
+            score_modifier: score_modifier(is_attack, is_new),
             is_new_ip: is_new,
         }
     }
@@ -180,11 +265,79 @@ impl DistributedDetector {
         self.last_attack.read().clone()
     }
 
-    /// Cleanup old known IPs (keep for 1 hour).
-    pub fn cleanup(&self) {
+    /// Cleanup old known IPs (keep for 1 hour), visiting about `budget` of
+    /// them, and expired mitigations.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
         let now = Instant::now();
         let stale = Duration::from_secs(3600);
-        self.known_ips.retain(|_, seen| now.duration_since(*seen) < stale);
+        let stats = self
+            .known_ips_sweep
+            .retain(&self.known_ips, budget, |_, seen| now.duration_since(*seen) < stale);
+
+        let now = Utc::now();
+        self.mitigations.write().retain(|m| {
+            let live = m.mitigation.expires_at > now;
+            if !live {
+                info!(
+                    id = m.mitigation.id,
+                    path = %m.mitigation.path,
+                    hits = m.hits.load(Ordering::Relaxed),
+                    "Distributed attack mitigation expired"
+                );
+            }
+            live
+        });
+        stats
+    }
+
+    /// Action (`challenge` or `block`) of the first active mitigation
+    /// matching the request, counting the hit unless `peek`.
+    pub fn mitigation_for(&self, method: &str, path: &str, peek: bool) -> Option<(u64, String)> {
+        let mitigations = self.mitigations.read();
+        if mitigations.is_empty() {
+            return None;
+        }
+        let now = Utc::now();
+        let m = mitigations.iter().find(|m| m.matches(method, path, now))?;
+        if !peek {
+            m.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Some((m.mitigation.id, m.mitigation.action.clone()))
+    }
+
+    /// Active mitigations, oldest first.
+    pub fn list_mitigations(&self) -> Vec<Mitigation> {
+        let now = Utc::now();
+        self.mitigations
+            .read()
+            .iter()
+            .filter(|m| m.mitigation.expires_at > now)
+            .map(|m| Mitigation {
+                hits: m.hits.load(Ordering::Relaxed),
+                ..m.mitigation.clone()
+            })
+            .collect()
+    }
+
+    /// Remove a mitigation. It is not re-created for the current attack.
+    pub fn remove_mitigation(&self, id: u64) -> bool {
+        let mut mitigations = self.mitigations.write();
+        let Some(pos) = mitigations.iter().position(|m| m.mitigation.id == id) else {
+            return false;
+        };
+        let removed = mitigations.remove(pos).mitigation;
+        self.dismissed.insert((removed.path, removed.method), ());
+        true
+    }
+
+    /// Remove all mitigations; none are re-created for the current attack.
+    pub fn clear_mitigations(&self) -> usize {
+        let removed = std::mem::take(&mut *self.mitigations.write());
+        for m in &removed {
+            let m = &m.mitigation;
+            self.dismissed.insert((m.path.clone(), m.method.clone()), ());
+        }
+        removed.len()
     }
 
     /// Get current window stats for admin API.
@@ -211,6 +364,7 @@ impl DistributedDetector {
             if now.duration_since(*start) >= self.window_duration {
                 *start = now;
                 self.path_counts.clear();
+                self.path_method_counts.clear();
                 self.ua_counts.clear();
                 self.total_requests.store(0, Ordering::Relaxed);
                 self.window_ips.clear();
@@ -220,6 +374,91 @@ impl DistributedDetector {
         }
     }
 
+    /// Create a mitigation for `path`, or extend the existing one, while the
+    /// attack keeps concentrating on it.
+    fn mitigate_hot_path(
+        &self,
+        path: &str,
+        count: u32,
+        share: f64,
+        signals: &[String],
+        config: &DistributedMitigationConfig,
+    ) {
+        let method = self
+            .path_method_counts
+            .iter()
+            .filter(|e| e.key().0 == path)
+            .max_by_key(|e| *e.value())
+            .filter(|e| *e.value() as f64 >= count as f64 * DOMINANT_METHOD_SHARE)
+            .map(|e| e.key().1.clone());
+        let key = (path.to_string(), method);
+        if self.dismissed.contains_key(&key) {
+            return;
+        }
+        let (path, method) = key;
+
+        let now = Utc::now();
+        let hold = chrono::Duration::seconds(config.hold_secs.min(i32::MAX as u64) as i64);
+        let expires_at = now + hold;
+        let is_current = |m: &ActiveMitigation| m.mitigation.path == path && m.mitigation.method == method;
+        // Refreshing takes the write lock, so only do it once a second.
+        let fresh = self
+            .mitigations
+            .read()
+            .iter()
+            .find(|m| is_current(m))
+            .map(|m| m.mitigation.expires_at);
+        if fresh.is_some_and(|at| at + chrono::Duration::seconds(1) > expires_at) {
+            return;
+        }
+
+        let mut mitigations = self.mitigations.write();
+        if let Some(m) = mitigations.iter_mut().find(|m| is_current(m)) {
+            m.mitigation.expires_at = m.mitigation.expires_at.max(expires_at);
+            return;
+        }
+        let action = if config.action == "block" { "block" } else { "challenge" };
+        let mitigation = Mitigation {
+            id: self.next_mitigation_id.fetch_add(1, Ordering::Relaxed),
+            path,
+            method,
+            action: action.to_string(),
+            reason: signals.join(", "),
+            created_at: now,
+            expires_at,
+            hits: 0,
+        };
+        let target = match &mitigation.method {
+            Some(method) => format!("{} {}", method, mitigation.path),
+            None => mitigation.path.clone(),
+        };
+        info!(
+            id = mitigation.id,
+            target = %target,
+            action = action,
+            share = format!("{:.0}%", share * 100.0),
+            "Distributed attack mitigation created"
+        );
+        let msg = format!(
+            "Distributed attack on {} ({:.0}% of requests): {} for {}s after it subsides. Signals: {}",
+            target,
+            share * 100.0,
+            if action == "block" { "blocking" } else { "challenging" },
+            config.hold_secs,
+            mitigation.reason,
+        );
+        self.alerting.notify(
+            "distributed_mitigation",
+            &format!("distributed_mitigation:{}", target),
+            Severity::Warning,
+            msg,
+        );
+        mitigations.push(ActiveMitigation {
+            mitigation,
+            hits: AtomicU64::new(0),
+        });
+    }
+
     fn get_top_path(&self) -> Option<(String, u32)> {
         let mut top: Option<(String, u32)> = None;
         for entry in self.path_counts.iter() {
@@ -237,3 +476,57 @@ impl DistributedDetector {
         top
     }
 }
+
+/// Score modifier: attack + new IP = +30, attack + existing IP = +10.
+fn score_modifier(is_attack: bool, is_new: bool) -> f64 {
+    match (is_attack, is_new) {
+        (true, true) => 30.0,
+        (true, false) => 10.0,
+        (false, _) => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::config::settings::Settings;
+
+    #[test]
+    fn test_hot_path_of_an_attack_is_mitigated_until_dismissed() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let settings = Settings::default();
+        let config = settings.protection.distributed_mitigation.clone();
+        let alerting = Arc::new(AlertManager::new(Arc::new(ArcSwap::from_pointee(settings))));
+        let detector = DistributedDetector::new(alerting);
+
+        // A POST flood on /search from fresh IPs with one user agent
+        let flood = |detector: &DistributedDetector, from: u32| {
+            for i in from..from + 60 {
+                let ip = IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + i));
+                detector.check(ip, "POST", "/search", Some("flood/1.0"), &config);
+            }
+        };
+        flood(&detector, 0);
+        assert!(detector.is_attack_active());
+
+        let mitigations = detector.list_mitigations();
+        assert_eq!(mitigations.len(), 1);
+        assert_eq!(mitigations[0].path, "/search");
+        assert_eq!(mitigations[0].method.as_deref(), Some("POST"));
+        assert_eq!(mitigations[0].action, "challenge");
+
+        let id = mitigations[0].id;
+        assert_eq!(detector.mitigation_for("POST", "/search", false), Some((id, "challenge".to_string())));
+        assert_eq!(detector.mitigation_for("GET", "/search", false), None);
+        assert_eq!(detector.mitigation_for("POST", "/login", false), None);
+        assert_eq!(detector.list_mitigations()[0].hits, 1);
+
+        // Removed by an admin: not re-created while the attack lasts
+        assert!(detector.remove_mitigation(id));
+        flood(&detector, 60);
+        assert!(detector.list_mitigations().is_empty());
+        assert!(!detector.remove_mitigation(id));
+    }
+}
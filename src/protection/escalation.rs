@@ -1,11 +1,19 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
 use crate::config::settings::Settings;
 use crate::models::threat::ProtectionLevel;
+use crate::storage::sqlite::SqliteStore;
+
+/// Key of the saved global level in the SQLite `config` table.
+const SAVED_LEVEL_KEY: &str = "escalation_state";
 
 /// Auto-escalation engine that adjusts protection level based on traffic patterns.
 ///
@@ -14,66 +22,361 @@ use crate::models::threat::ProtectionLevel;
 /// - Considers block ratio (high RPS with low block ratio = legitimate traffic)
 /// - Uses config values for de-escalation cooldown
 /// - Faster de-escalation (3 consecutive checks instead of 5)
+///
+/// With `latency_escalation_ms` or `error_ratio_escalation` set, a
+/// struggling origin escalates the global level as well (up to L3), even
+/// at low RPS, and the level only comes down once both traffic and the
+/// origin are back to normal.
+///
+/// With `escalation.per_service` enabled, services that receive at least
+/// `per_service_min_rps` get a level of their own, computed from their own
+/// traffic with the same rules. Their traffic is then left out of the
+/// global evaluation, so an attack on one service doesn't raise the level
+/// of every other. Services below the threshold follow the global level.
 pub struct EscalationEngine {
-    current_level: AtomicU8,
-    last_escalation: Mutex<Instant>,
-    last_deescalation: Mutex<Instant>,
-    deescalation_counter: AtomicU8,
-    escalation_counter: AtomicU8,
+    global: LevelState,
+    global_meta: Mutex<GlobalMeta>,
+    /// Services with their own level, keyed by service id.
+    services: DashMap<String, LevelState>,
+    /// Last seen cumulative request count per service, for RPS deltas.
+    samples: Mutex<HashMap<String, (u64, Instant)>>,
+    tuning: Mutex<EscalationTuning>,
+    /// Origin health seen at the last global evaluation.
+    origin: Mutex<OriginHealth>,
+}
+
+/// Upstream requests completed since the previous check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OriginHealth {
+    pub responses: u64,
+    pub errors: u64,
+    /// Summed time to the response headers.
+    pub latency_us: u64,
+}
+
+impl OriginHealth {
+    pub fn avg_latency_ms(&self) -> f64 {
+        if self.responses == 0 {
+            return 0.0;
+        }
+        self.latency_us as f64 / self.responses as f64 / 1000.0
+    }
+
+    pub fn error_ratio(&self) -> f64 {
+        if self.responses == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.responses as f64
+    }
+}
+
+/// What made the level go up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscalationSignal {
+    Rps,
+    BlockedRequests,
+    UpstreamLatency,
+    UpstreamErrors,
+}
+
+impl EscalationSignal {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Rps => "rps",
+            Self::BlockedRequests => "blocked_requests",
+            Self::UpstreamLatency => "upstream_latency",
+            Self::UpstreamErrors => "upstream_errors",
+        }
+    }
+}
+
+/// How the global level came about, shown in the status output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelMode {
+    /// Set by auto-escalation or the config default.
+    Auto,
+    /// Set through the admin API; never auto-de-escalated below the pinned
+    /// level until released.
+    Pinned,
+    /// Restored from the last run and not changed since.
+    Restored,
+}
+
+impl LevelMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Pinned => "pinned",
+            Self::Restored => "restored",
+        }
+    }
+}
+
+struct GlobalMeta {
+    pinned: Option<u8>,
+    restored: bool,
+    changed_at: DateTime<Utc>,
+    /// Changed since last saved to SQLite.
+    dirty: bool,
+}
+
+/// The global level as saved across restarts.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedLevel {
+    level: u8,
+    #[serde(default)]
+    pinned: bool,
+    changed_at: DateTime<Utc>,
+}
+
+/// Config-derived knobs that can be replaced on reload.
+#[derive(Clone, Copy)]
+struct EscalationTuning {
     sustained_checks_required: u8,
     block_ratio_threshold: f64,
     deescalation_cooldown: Duration,
+    per_service: bool,
+    per_service_min_rps: f64,
+}
+
+impl EscalationTuning {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            sustained_checks_required: settings.escalation.sustained_checks_required,
+            block_ratio_threshold: settings.escalation.block_ratio_threshold,
+            deescalation_cooldown: Duration::from_secs(settings.escalation.deescalation_cooldown_secs),
+            per_service: settings.escalation.per_service,
+            per_service_min_rps: settings.escalation.per_service_min_rps as f64,
+        }
+    }
 }
 
 /// Number of consecutive low-traffic checks required before de-escalation
 const DEESCALATION_CONSECUTIVE_CHECKS: u8 = 3;
 /// Minimum time between escalations (seconds)
 const ESCALATION_COOLDOWN_SECS: u64 = 10;
+/// Upstream requests needed in a check before origin health counts, so a
+/// couple of slow requests on an idle site don't escalate
+const MIN_ORIGIN_SAMPLES: u64 = 20;
+/// Highest level origin health alone escalates to; L4 stays reserved for
+/// traffic floods
+const MAX_ORIGIN_LEVEL: u8 = 3;
+
+/// Traffic attributed to one service since the engine started.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServiceTraffic {
+    pub total: u64,
+    pub blocked: u64,
+}
 
 impl EscalationEngine {
     pub fn new() -> Self {
         Self {
-            current_level: AtomicU8::new(0),
-            last_escalation: Mutex::new(Instant::now()),
-            last_deescalation: Mutex::new(Instant::now()),
-            deescalation_counter: AtomicU8::new(0),
-            escalation_counter: AtomicU8::new(0),
-            sustained_checks_required: 3,
-            block_ratio_threshold: 0.3,
-            deescalation_cooldown: Duration::from_secs(60),
+            global: LevelState::new(),
+            global_meta: Mutex::new(GlobalMeta {
+                pinned: None,
+                restored: false,
+                changed_at: Utc::now(),
+                dirty: false,
+            }),
+            services: DashMap::new(),
+            samples: Mutex::new(HashMap::new()),
+            tuning: Mutex::new(EscalationTuning {
+                sustained_checks_required: 3,
+                block_ratio_threshold: 0.3,
+                deescalation_cooldown: Duration::from_secs(60),
+                per_service: false,
+                per_service_min_rps: 10.0,
+            }),
+            origin: Mutex::new(OriginHealth::default()),
         }
     }
 
     /// Create with config values.
     pub fn with_config(settings: &Settings) -> Self {
-        Self {
-            current_level: AtomicU8::new(0),
-            last_escalation: Mutex::new(Instant::now()),
-            last_deescalation: Mutex::new(Instant::now()),
-            deescalation_counter: AtomicU8::new(0),
-            escalation_counter: AtomicU8::new(0),
-            sustained_checks_required: settings.escalation.sustained_checks_required,
-            block_ratio_threshold: settings.escalation.block_ratio_threshold,
-            deescalation_cooldown: Duration::from_secs(settings.escalation.deescalation_cooldown_secs),
+        let engine = Self::new();
+        *engine.tuning.lock() = EscalationTuning::from_settings(settings);
+        engine
+    }
+
+    /// Apply a reloaded `[escalation]` section. The current levels are kept,
+    /// unless per-service tracking was turned off.
+    pub fn reload(&self, settings: &Settings) {
+        let tuning = EscalationTuning::from_settings(settings);
+        if !tuning.per_service {
+            self.services.clear();
+            self.samples.lock().clear();
         }
+        *self.tuning.lock() = tuning;
     }
 
     pub fn current_level(&self) -> ProtectionLevel {
-        Self::u8_to_level(self.current_level.load(Ordering::Relaxed))
+        Self::u8_to_level(self.global.level())
     }
 
     pub fn level_as_u8(&self) -> u8 {
-        self.current_level.load(Ordering::Relaxed)
+        self.global.level()
     }
 
     pub fn set_level(&self, level: ProtectionLevel) {
-        let level_u8 = Self::level_to_u8(&level);
-        let prev = self.current_level.swap(level_u8, Ordering::Relaxed);
-        if prev != level_u8 {
-            info!(from = prev, to = level_u8, "Protection level manually set");
-            self.deescalation_counter.store(0, Ordering::Relaxed);
-            self.escalation_counter.store(0, Ordering::Relaxed);
+        if self.global.set(Self::level_to_u8(&level)) {
+            info!(scope = "global", to = level.as_u8(), "Protection level manually set");
+            self.global_changed();
+        }
+    }
+
+    /// Set the global level and pin it: auto-escalation may still raise
+    /// it, but never lowers it below `level` until [`release_pin`] is
+    /// called.
+    ///
+    /// [`release_pin`]: Self::release_pin
+    pub fn pin_level(&self, level: ProtectionLevel) {
+        self.set_level(level);
+        let mut meta = self.global_meta.lock();
+        meta.pinned = Some(Self::level_to_u8(&level));
+        meta.dirty = true;
+        info!(level = level.as_u8(), "Protection level pinned");
+    }
+
+    /// Hand the global level back to auto-escalation. Returns `false` if
+    /// it was not pinned.
+    pub fn release_pin(&self) -> bool {
+        let mut meta = self.global_meta.lock();
+        if meta.pinned.take().is_none() {
+            return false;
+        }
+        meta.restored = false;
+        meta.dirty = true;
+        info!(level = self.global.level(), "Protection level pin released");
+        true
+    }
+
+    pub fn level_mode(&self) -> LevelMode {
+        let meta = self.global_meta.lock();
+        match (meta.pinned, meta.restored) {
+            (Some(_), _) => LevelMode::Pinned,
+            (None, true) => LevelMode::Restored,
+            (None, false) => LevelMode::Auto,
+        }
+    }
+
+    /// The pinned floor of the global level, if any.
+    pub fn pinned_level(&self) -> Option<u8> {
+        self.global_meta.lock().pinned
+    }
+
+    /// The signal behind the last automatic escalation of the global
+    /// level; `None` once the level has been set by hand.
+    pub fn escalation_signal(&self) -> Option<EscalationSignal> {
+        *self.global.last_signal.lock()
+    }
+
+    /// Origin health seen at the last global evaluation.
+    pub fn origin_health(&self) -> OriginHealth {
+        *self.origin.lock()
+    }
+
+    /// When the global level last changed.
+    pub fn level_changed_at(&self) -> DateTime<Utc> {
+        self.global_meta.lock().changed_at
+    }
+
+    fn global_changed(&self) {
+        let mut meta = self.global_meta.lock();
+        meta.restored = false;
+        meta.changed_at = Utc::now();
+        meta.dirty = true;
+    }
+
+    /// Save the global level to SQLite if it changed since the last save.
+    pub async fn save(&self, sqlite: &SqliteStore) {
+        let saved = {
+            let mut meta = self.global_meta.lock();
+            if !meta.dirty {
+                return;
+            }
+            meta.dirty = false;
+            SavedLevel {
+                level: self.global.level(),
+                pinned: meta.pinned.is_some(),
+                changed_at: meta.changed_at,
+            }
+        };
+        let json = serde_json::to_string(&saved).unwrap_or_default();
+        if let Err(e) = sqlite.set_config(SAVED_LEVEL_KEY, &json).await {
+            warn!("Failed to save protection level: {}", e);
+            self.global_meta.lock().dirty = true;
+        }
+    }
+
+    /// Restore the global level saved by the last run: a pinned level
+    /// always, an automatic one only if it changed within `max_age`.
+    /// Returns the restored level.
+    pub async fn restore(&self, sqlite: &SqliteStore, max_age: Duration) -> Option<ProtectionLevel> {
+        let json = match sqlite.get_config(SAVED_LEVEL_KEY).await {
+            Ok(json) => json?,
+            Err(e) => {
+                warn!("Failed to load saved protection level: {}", e);
+                return None;
+            }
+        };
+        let saved: SavedLevel = serde_json::from_str(&json)
+            .map_err(|e| warn!("Ignoring invalid saved protection level: {}", e))
+            .ok()?;
+        let level = ProtectionLevel::from_u8(saved.level)?;
+        let age = (Utc::now() - saved.changed_at).to_std().unwrap_or_default();
+        if !saved.pinned && age > max_age {
+            debug!(level = saved.level, age_secs = age.as_secs(), "Saved protection level is stale, not restoring");
+            return None;
+        }
+
+        self.global.set(saved.level);
+        *self.global_meta.lock() = GlobalMeta {
+            pinned: saved.pinned.then_some(saved.level),
+            restored: true,
+            changed_at: saved.changed_at,
+            dirty: false,
+        };
+        Some(level)
+    }
+
+    /// Whether per-service levels are enabled.
+    pub fn per_service_enabled(&self) -> bool {
+        self.tuning.lock().per_service
+    }
+
+    /// The service's own level, if it currently has one.
+    pub fn service_level(&self, service_id: &str) -> Option<ProtectionLevel> {
+        self.services.get(service_id).map(|s| Self::u8_to_level(s.level()))
+    }
+
+    /// Level that applies to requests for `service_id`: its own level when
+    /// it has one, the global level otherwise.
+    pub fn effective_level(&self, service_id: Option<&str>) -> ProtectionLevel {
+        service_id
+            .and_then(|id| self.service_level(id))
+            .unwrap_or_else(|| self.current_level())
+    }
+
+    /// Manually set a service's level. Returns `false` when per-service
+    /// levels are disabled.
+    pub fn set_service_level(&self, service_id: &str, level: ProtectionLevel) -> bool {
+        if !self.per_service_enabled() {
+            return false;
+        }
+        let to = Self::level_to_u8(&level);
+        if self.services.entry(service_id.to_string()).or_insert_with(LevelState::new).set(to) {
+            info!(scope = service_id, to = to, "Protection level manually set");
         }
+        true
+    }
+
+    /// Services with their own level, sorted by id.
+    pub fn service_levels(&self) -> Vec<(String, u8)> {
+        let mut levels: Vec<(String, u8)> =
+            self.services.iter().map(|s| (s.key().clone(), s.level())).collect();
+        levels.sort();
+        levels
     }
 
     /// Evaluate current traffic metrics and adjust protection level.
@@ -82,10 +385,192 @@ impl EscalationEngine {
     /// - `rps`: Current requests per second
     /// - `blocked_per_min`: Number of requests blocked in the last minute
     /// - `total_per_min`: Total requests in the last minute (for block ratio)
+    /// - `origin`: Upstream requests completed since the previous check
     /// - `settings`: Application settings containing escalation thresholds
-    pub fn evaluate(&self, rps: f64, blocked_per_min: u64, total_per_min: u64, settings: &Settings) {
-        let current = self.current_level.load(Ordering::Relaxed);
+    ///
+    /// Traffic of services with their own level should already have been
+    /// subtracted (see `evaluate_services`).
+    pub fn evaluate(
+        &self,
+        rps: f64,
+        blocked_per_min: u64,
+        total_per_min: u64,
+        origin: OriginHealth,
+        settings: &Settings,
+    ) {
+        let thresholds = self.get_thresholds(settings);
+        let tuning = *self.tuning.lock();
+        let floor = self.pinned_level().unwrap_or(0);
+        let before = self.global.level();
+        *self.origin.lock() = origin;
+        self.global.evaluate(
+            "global",
+            rps,
+            blocked_per_min,
+            total_per_min,
+            Some(&origin),
+            floor,
+            &thresholds,
+            &tuning,
+        );
+        if self.global.level() != before {
+            self.global_changed();
+        }
+    }
+
+    /// Evaluate every service's traffic and adjust the per-service levels.
+    ///
+    /// `traffic` holds cumulative counts per service id; RPS is derived
+    /// from the change since the previous call. A service gets its own level
+    /// once its RPS reaches `per_service_min_rps`, and falls back to the
+    /// global level once it is back at L0 below that rate.
+    ///
+    /// Returns the summed `(rps, traffic)` of services that have their own
+    /// level, to be left out of the global evaluation.
+    pub fn evaluate_services(
+        &self,
+        traffic: &[(String, ServiceTraffic)],
+        settings: &Settings,
+    ) -> (f64, ServiceTraffic) {
+        let tuning = *self.tuning.lock();
+        let mut tracked = (0.0, ServiceTraffic::default());
+        if !tuning.per_service {
+            return tracked;
+        }
         let thresholds = self.get_thresholds(settings);
+        let now = Instant::now();
+
+        let mut samples = self.samples.lock();
+        for (id, counts) in traffic {
+            let rps = match samples.insert(id.clone(), (counts.total, now)) {
+                Some((prev, at)) => {
+                    let secs = now.duration_since(at).as_secs_f64();
+                    if secs > 0.0 { counts.total.saturating_sub(prev) as f64 / secs } else { 0.0 }
+                }
+                None => 0.0,
+            };
+
+            if !self.services.contains_key(id) && rps < tuning.per_service_min_rps {
+                continue;
+            }
+            let state = self.services.entry(id.clone()).or_insert_with(LevelState::new);
+            state.evaluate(id, rps, counts.blocked, counts.total, None, 0, &thresholds, &tuning);
+            let level = state.level();
+            drop(state);
+
+            if level == 0 && rps < tuning.per_service_min_rps {
+                debug!(service = %id, "Service traffic back under per-service threshold, following global level");
+                self.services.remove(id);
+                continue;
+            }
+            tracked.0 += rps;
+            tracked.1.total += counts.total;
+            tracked.1.blocked += counts.blocked;
+        }
+        drop(samples);
+
+        // Services set manually but without any traffic still de-escalate
+        let idle: Vec<String> = self
+            .services
+            .iter()
+            .map(|s| s.key().clone())
+            .filter(|id| !traffic.iter().any(|(t, _)| t == id))
+            .collect();
+        for id in idle {
+            if let Some(state) = self.services.get(&id) {
+                state.evaluate(&id, 0.0, 0, 0, None, 0, &thresholds, &tuning);
+            }
+            self.services.remove_if(&id, |_, s| s.level() == 0);
+        }
+        tracked
+    }
+
+    fn get_thresholds(&self, settings: &Settings) -> EscalationThresholds {
+        let esc = &settings.escalation;
+        EscalationThresholds {
+            l0_to_l1_rps: esc.l0_to_l1_rps as f64,
+            l1_to_l2_rps: esc.l1_to_l2_rps as f64,
+            l2_to_l3_rps: esc.l2_to_l3_rps as f64,
+            l3_to_l4_rps: esc.l3_to_l4_rps as f64,
+            latency_ms: esc.latency_escalation_ms as f64,
+            error_ratio: esc.error_ratio_escalation,
+        }
+    }
+
+    fn u8_to_level(level: u8) -> ProtectionLevel {
+        match level {
+            0 => ProtectionLevel::L0,
+            1 => ProtectionLevel::L1,
+            2 => ProtectionLevel::L2,
+            3 => ProtectionLevel::L3,
+            _ => ProtectionLevel::L4,
+        }
+    }
+
+    fn level_to_u8(level: &ProtectionLevel) -> u8 {
+        match level {
+            ProtectionLevel::L0 => 0,
+            ProtectionLevel::L1 => 1,
+            ProtectionLevel::L2 => 2,
+            ProtectionLevel::L3 => 3,
+            ProtectionLevel::L4 => 4,
+        }
+    }
+}
+
+/// Level and escalation bookkeeping for one scope (global or a service).
+struct LevelState {
+    current_level: AtomicU8,
+    last_escalation: Mutex<Instant>,
+    last_deescalation: Mutex<Instant>,
+    deescalation_counter: AtomicU8,
+    escalation_counter: AtomicU8,
+    last_signal: Mutex<Option<EscalationSignal>>,
+}
+
+impl LevelState {
+    fn new() -> Self {
+        Self {
+            current_level: AtomicU8::new(0),
+            last_escalation: Mutex::new(Instant::now()),
+            last_deescalation: Mutex::new(Instant::now()),
+            deescalation_counter: AtomicU8::new(0),
+            escalation_counter: AtomicU8::new(0),
+            last_signal: Mutex::new(None),
+        }
+    }
+
+    fn level(&self) -> u8 {
+        self.current_level.load(Ordering::Relaxed)
+    }
+
+    /// Set the level, returning whether it changed.
+    fn set(&self, level: u8) -> bool {
+        let prev = self.current_level.swap(level, Ordering::Relaxed);
+        if prev != level {
+            self.deescalation_counter.store(0, Ordering::Relaxed);
+            self.escalation_counter.store(0, Ordering::Relaxed);
+            *self.last_signal.lock() = None;
+        }
+        prev != level
+    }
+
+    /// Escalate or de-escalate by one step; never de-escalates below
+    /// `floor`. `origin` is only given for the global scope, since
+    /// upstream health isn't tracked per service.
+    #[allow(clippy::too_many_arguments)]
+    fn evaluate(
+        &self,
+        scope: &str,
+        rps: f64,
+        blocked_per_min: u64,
+        total_per_min: u64,
+        origin: Option<&OriginHealth>,
+        floor: u8,
+        thresholds: &EscalationThresholds,
+        tuning: &EscalationTuning,
+    ) {
+        let current = self.current_level.load(Ordering::Relaxed);
 
         // Calculate block ratio
         let block_ratio = if total_per_min > 0 {
@@ -94,14 +579,20 @@ impl EscalationEngine {
             0.0
         };
 
+        let origin_signal = Self::origin_signal(origin, thresholds);
+        let signal = Self::should_escalate(current, rps, blocked_per_min, thresholds)
+            .or(origin_signal.filter(|_| current < MAX_ORIGIN_LEVEL));
+
         // Try escalation with sustained-traffic requirement
-        if self.should_escalate(current, rps, blocked_per_min, &thresholds) {
-            // Block ratio check: high RPS with low block ratio = likely legitimate
-            if block_ratio < self.block_ratio_threshold && current == 0 {
+        if let Some(signal) = signal {
+            // Block ratio check: high RPS with low block ratio = likely
+            // legitimate, unless the origin is struggling with it
+            if block_ratio < tuning.block_ratio_threshold && current == 0 && origin_signal.is_none() {
                 debug!(
+                    scope = scope,
                     rps = rps,
                     block_ratio = block_ratio,
-                    threshold = self.block_ratio_threshold,
+                    threshold = tuning.block_ratio_threshold,
                     "High RPS but low block ratio — skipping escalation (likely legitimate traffic)"
                 );
                 self.escalation_counter.store(0, Ordering::Relaxed);
@@ -109,17 +600,19 @@ impl EscalationEngine {
             }
 
             let counter = self.escalation_counter.fetch_add(1, Ordering::Relaxed) + 1;
-            if counter >= self.sustained_checks_required {
-                self.try_escalate(current);
+            if counter >= tuning.sustained_checks_required {
+                self.try_escalate(scope, current, signal);
                 self.escalation_counter.store(0, Ordering::Relaxed);
             } else {
                 debug!(
+                    scope = scope,
                     rps = rps,
+                    signal = signal.as_str(),
                     counter = counter,
-                    required = self.sustained_checks_required,
+                    required = tuning.sustained_checks_required,
                     "Escalation condition met {}/{} consecutive checks",
                     counter,
-                    self.sustained_checks_required,
+                    tuning.sustained_checks_required,
                 );
             }
             return;
@@ -128,11 +621,14 @@ impl EscalationEngine {
         // Conditions not met for escalation, reset counter
         self.escalation_counter.store(0, Ordering::Relaxed);
 
-        // Try de-escalation
-        if self.should_deescalate(current, rps, blocked_per_min, &thresholds) {
+        // Try de-escalation, once both traffic and the origin are back to normal
+        if current > floor
+            && origin_signal.is_none()
+            && Self::should_deescalate(current, rps, blocked_per_min, thresholds)
+        {
             let counter = self.deescalation_counter.fetch_add(1, Ordering::Relaxed) + 1;
             if counter >= DEESCALATION_CONSECUTIVE_CHECKS {
-                self.try_deescalate(current);
+                self.try_deescalate(scope, current, tuning.deescalation_cooldown);
             }
         } else {
             self.deescalation_counter.store(0, Ordering::Relaxed);
@@ -140,23 +636,41 @@ impl EscalationEngine {
     }
 
     fn should_escalate(
-        &self,
         current: u8,
         rps: f64,
         blocked_per_min: u64,
         thresholds: &EscalationThresholds,
-    ) -> bool {
-        match current {
-            0 => rps > thresholds.l0_to_l1_rps || blocked_per_min > 50,
-            1 => rps > thresholds.l1_to_l2_rps || blocked_per_min > 200,
-            2 => rps > thresholds.l2_to_l3_rps || blocked_per_min > 500,
-            3 => rps > thresholds.l3_to_l4_rps,
-            _ => false,
+    ) -> Option<EscalationSignal> {
+        let (rps_threshold, block_threshold) = match current {
+            0 => (thresholds.l0_to_l1_rps, Some(50)),
+            1 => (thresholds.l1_to_l2_rps, Some(200)),
+            2 => (thresholds.l2_to_l3_rps, Some(500)),
+            3 => (thresholds.l3_to_l4_rps, None),
+            _ => return None,
+        };
+        if rps > rps_threshold {
+            Some(EscalationSignal::Rps)
+        } else if block_threshold.is_some_and(|t| blocked_per_min > t) {
+            Some(EscalationSignal::BlockedRequests)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the origin is unhealthy enough to escalate, whatever the
+    /// current level.
+    fn origin_signal(origin: Option<&OriginHealth>, thresholds: &EscalationThresholds) -> Option<EscalationSignal> {
+        let origin = origin.filter(|o| o.responses >= MIN_ORIGIN_SAMPLES)?;
+        if thresholds.error_ratio > 0.0 && origin.error_ratio() >= thresholds.error_ratio {
+            Some(EscalationSignal::UpstreamErrors)
+        } else if thresholds.latency_ms > 0.0 && origin.avg_latency_ms() >= thresholds.latency_ms {
+            Some(EscalationSignal::UpstreamLatency)
+        } else {
+            None
         }
     }
 
     fn should_deescalate(
-        &self,
         current: u8,
         rps: f64,
         blocked_per_min: u64,
@@ -185,7 +699,7 @@ impl EscalationEngine {
         rps < half_threshold && blocked_per_min < block_threshold
     }
 
-    fn try_escalate(&self, current: u8) {
+    fn try_escalate(&self, scope: &str, current: u8, signal: EscalationSignal) {
         if current >= 4 {
             return;
         }
@@ -205,10 +719,18 @@ impl EscalationEngine {
             Ok(_) => {
                 *last = Instant::now();
                 self.deescalation_counter.store(0, Ordering::Relaxed);
-                warn!(from = current, to = new_level, "Protection level ESCALATED");
+                *self.last_signal.lock() = Some(signal);
+                warn!(
+                    scope = scope,
+                    from = current,
+                    to = new_level,
+                    signal = signal.as_str(),
+                    "Protection level ESCALATED"
+                );
             }
             Err(actual) => {
                 info!(
+                    scope = scope,
                     expected = current,
                     actual = actual,
                     "Escalation skipped: level changed concurrently"
@@ -217,13 +739,13 @@ impl EscalationEngine {
         }
     }
 
-    fn try_deescalate(&self, current: u8) {
+    fn try_deescalate(&self, scope: &str, current: u8, cooldown: Duration) {
         if current == 0 {
             return;
         }
 
         let mut last = self.last_deescalation.lock();
-        if last.elapsed() < self.deescalation_cooldown {
+        if last.elapsed() < cooldown {
             return;
         }
 
@@ -237,10 +759,11 @@ impl EscalationEngine {
             Ok(_) => {
                 *last = Instant::now();
                 self.deescalation_counter.store(0, Ordering::Relaxed);
-                info!(from = current, to = new_level, "Protection level de-escalated");
+                info!(scope = scope, from = current, to = new_level, "Protection level de-escalated");
             }
             Err(actual) => {
                 info!(
+                    scope = scope,
                     expected = current,
                     actual = actual,
                     "De-escalation skipped: level changed concurrently"
@@ -248,36 +771,6 @@ impl EscalationEngine {
             }
         }
     }
-
-    fn get_thresholds(&self, settings: &Settings) -> EscalationThresholds {
-        let esc = &settings.escalation;
-        EscalationThresholds {
-            l0_to_l1_rps: esc.l0_to_l1_rps as f64,
-            l1_to_l2_rps: esc.l1_to_l2_rps as f64,
-            l2_to_l3_rps: esc.l2_to_l3_rps as f64,
-            l3_to_l4_rps: esc.l3_to_l4_rps as f64,
-        }
-    }
-
-    fn u8_to_level(level: u8) -> ProtectionLevel {
-        match level {
-            0 => ProtectionLevel::L0,
-            1 => ProtectionLevel::L1,
-            2 => ProtectionLevel::L2,
-            3 => ProtectionLevel::L3,
-            _ => ProtectionLevel::L4,
-        }
-    }
-
-    fn level_to_u8(level: &ProtectionLevel) -> u8 {
-        match level {
-            ProtectionLevel::L0 => 0,
-            ProtectionLevel::L1 => 1,
-            ProtectionLevel::L2 => 2,
-            ProtectionLevel::L3 => 3,
-            ProtectionLevel::L4 => 4,
-        }
-    }
 }
 
 impl Default for EscalationEngine {
@@ -291,4 +784,165 @@ struct EscalationThresholds {
     l1_to_l2_rps: f64,
     l2_to_l3_rps: f64,
     l3_to_l4_rps: f64,
+    /// 0 disables.
+    latency_ms: f64,
+    /// 0 disables.
+    error_ratio: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::tests::TempDb;
+
+    #[tokio::test]
+    async fn test_saved_level_is_restored_unless_stale() {
+        let db = TempDb::new("escalation");
+        let sqlite = SqliteStore::new(db.path()).unwrap();
+        let max_age = Duration::from_secs(600);
+
+        let engine = EscalationEngine::new();
+        engine.pin_level(ProtectionLevel::L3);
+        engine.save(&sqlite).await;
+
+        // Pinned levels come back pinned, whatever their age
+        let restarted = EscalationEngine::new();
+        assert_eq!(restarted.restore(&sqlite, Duration::ZERO).await, Some(ProtectionLevel::L3));
+        assert_eq!(restarted.level_mode(), LevelMode::Pinned);
+        assert_eq!(restarted.pinned_level(), Some(3));
+
+        assert!(restarted.release_pin());
+        assert!(!restarted.release_pin());
+        assert_eq!(restarted.level_mode(), LevelMode::Auto);
+        restarted.save(&sqlite).await;
+
+        let restarted = EscalationEngine::new();
+        assert_eq!(restarted.restore(&sqlite, max_age).await, Some(ProtectionLevel::L3));
+        assert_eq!(restarted.level_mode(), LevelMode::Restored);
+        assert_eq!(restarted.level_as_u8(), 3);
+
+        let stale = EscalationEngine::new();
+        assert_eq!(stale.restore(&sqlite, Duration::ZERO).await, None);
+        assert_eq!(stale.level_as_u8(), 0);
+    }
+
+    #[test]
+    fn test_slow_origin_escalates_and_holds_the_level() {
+        let mut settings = Settings::default();
+        settings.escalation.latency_escalation_ms = 500;
+        settings.escalation.sustained_checks_required = 2;
+        let engine = EscalationEngine::with_config(&settings);
+        let allow_escalation = || *engine.global.last_escalation.lock() = Instant::now() - Duration::from_secs(60);
+        let slow = OriginHealth { responses: 100, errors: 0, latency_us: 100 * 800_000 };
+        let healthy = OriginHealth { responses: 100, errors: 0, latency_us: 100 * 50_000 };
+
+        // Too few upstream requests to judge the origin
+        allow_escalation();
+        for _ in 0..3 {
+            engine.evaluate(1.0, 0, 100, OriginHealth { responses: 5, ..slow }, &settings);
+        }
+        assert_eq!(engine.level_as_u8(), 0);
+
+        // Low RPS, no blocks, but a slow origin for two checks in a row
+        engine.evaluate(1.0, 0, 100, slow, &settings);
+        assert_eq!(engine.level_as_u8(), 0);
+        engine.evaluate(1.0, 0, 100, slow, &settings);
+        assert_eq!(engine.level_as_u8(), 1);
+        assert_eq!(engine.escalation_signal(), Some(EscalationSignal::UpstreamLatency));
+
+        // At L3 a slow origin neither escalates further nor lets quiet
+        // traffic bring the level down
+        engine.set_level(ProtectionLevel::L3);
+        assert_eq!(engine.escalation_signal(), None);
+        allow_escalation();
+        *engine.global.last_deescalation.lock() = Instant::now() - Duration::from_secs(3600);
+        for _ in 0..DEESCALATION_CONSECUTIVE_CHECKS {
+            engine.evaluate(0.0, 0, 0, slow, &settings);
+        }
+        assert_eq!(engine.level_as_u8(), 3);
+        for _ in 0..DEESCALATION_CONSECUTIVE_CHECKS {
+            engine.evaluate(0.0, 0, 0, healthy, &settings);
+        }
+        assert_eq!(engine.level_as_u8(), 2);
+    }
+
+    fn per_service_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.escalation.per_service = true;
+        settings.escalation.per_service_min_rps = 50;
+        settings.escalation.l0_to_l1_rps = 100;
+        settings.escalation.sustained_checks_required = 1;
+        settings.escalation.deescalation_cooldown_secs = 0;
+        settings
+    }
+
+    /// Evaluate cumulative `(service, total, blocked)` counts as if the
+    /// previous check ran a second ago, with the escalation cooldown over.
+    fn check_services(engine: &EscalationEngine, traffic: &[(&str, u64, u64)], settings: &Settings) -> (f64, ServiceTraffic) {
+        for sample in engine.samples.lock().values_mut() {
+            sample.1 -= Duration::from_secs(1);
+        }
+        for state in engine.services.iter() {
+            *state.last_escalation.lock() -= Duration::from_secs(60);
+        }
+        let traffic: Vec<(String, ServiceTraffic)> = traffic
+            .iter()
+            .map(|(id, total, blocked)| (id.to_string(), ServiceTraffic { total: *total, blocked: *blocked }))
+            .collect();
+        engine.evaluate_services(&traffic, settings)
+    }
+
+    #[test]
+    fn test_busy_service_gets_its_own_level_and_escalates_alone() {
+        let settings = per_service_settings();
+        let engine = EscalationEngine::with_config(&settings);
+
+        // The first check only records where the counters start
+        let (rps, _) = check_services(&engine, &[("shop", 0, 0), ("blog", 0, 0)], &settings);
+        assert_eq!(rps, 0.0);
+        assert!(engine.service_levels().is_empty());
+
+        // ~300 RPS on shop, ~20 on blog: only shop is adopted and tracked
+        let (rps, tracked) = check_services(&engine, &[("shop", 300, 150), ("blog", 20, 0)], &settings);
+        assert!(rps > 250.0 && rps <= 300.0, "{}", rps);
+        assert_eq!((tracked.total, tracked.blocked), (300, 150));
+        assert_eq!(engine.service_levels(), vec![("shop".to_string(), 0)]);
+
+        // Still flooded: shop escalates, blog and the global level don't
+        check_services(&engine, &[("shop", 600, 300), ("blog", 40, 0)], &settings);
+        assert_eq!(engine.service_level("shop"), Some(ProtectionLevel::L1));
+        assert_eq!(engine.service_level("blog"), None);
+        assert_eq!(engine.effective_level(Some("shop")), ProtectionLevel::L1);
+        assert_eq!(engine.effective_level(Some("blog")), ProtectionLevel::L0);
+        assert_eq!(engine.level_as_u8(), 0);
+    }
+
+    #[test]
+    fn test_quiet_services_fall_back_to_the_global_level() {
+        let settings = per_service_settings();
+        let engine = EscalationEngine::with_config(&settings);
+
+        // Adopted at ~200 RPS, but nothing blocked keeps it at L0
+        check_services(&engine, &[("shop", 0, 0)], &settings);
+        check_services(&engine, &[("shop", 200, 0)], &settings);
+        assert_eq!(engine.service_level("shop"), Some(ProtectionLevel::L0));
+
+        // Back under per_service_min_rps at L0: no longer tracked
+        let (rps, tracked) = check_services(&engine, &[("shop", 210, 0)], &settings);
+        assert_eq!((rps, tracked.total), (0.0, 0));
+        assert_eq!(engine.service_level("shop"), None);
+
+        // A level set by hand on a service without traffic steps down and
+        // is dropped at L0
+        assert!(engine.set_service_level("admin", ProtectionLevel::L2));
+        for _ in 0..DEESCALATION_CONSECUTIVE_CHECKS {
+            check_services(&engine, &[("shop", 210, 0)], &settings);
+        }
+        assert_eq!(engine.service_level("admin"), Some(ProtectionLevel::L1));
+        for _ in 0..DEESCALATION_CONSECUTIVE_CHECKS {
+            check_services(&engine, &[("shop", 210, 0)], &settings);
+        }
+        assert_eq!(engine.service_level("admin"), None);
+        assert!(engine.service_levels().is_empty());
+    }
 }
@@ -0,0 +1,536 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::config::settings::{FirewallConfig, SharedSettings};
+use crate::storage::sweep::{Sweep, SweepStats};
+
+/// Runs the firewall commands of [`FirewallOffload`]. Only called from its
+/// worker thread, so implementations may block.
+pub trait FirewallExecutor: Send {
+    fn run(&mut self, argv: &[String]) -> Result<(), String>;
+}
+
+/// Runs each command as a child process, killed after `timeout`.
+pub struct CommandExecutor {
+    timeout: Duration,
+}
+
+impl CommandExecutor {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl FirewallExecutor for CommandExecutor {
+    fn run(&mut self, argv: &[String]) -> Result<(), String> {
+        let (program, args) = argv.split_first().ok_or("empty command")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("{}: {}", program, e))?;
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait().map_err(|e| format!("{}: {}", program, e))? {
+                Some(status) => break status,
+                None if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!("{} timed out after {}ms", program, self.timeout.as_millis()));
+                }
+                None => std::thread::sleep(Duration::from_millis(5)),
+            }
+        };
+        if status.success() {
+            return Ok(());
+        }
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+        Err(format!("{} exited with {}: {}", program, status, stderr.trim()))
+    }
+}
+
+/// `ipv4` or `ipv6`, for `{family}`.
+fn family(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    }
+}
+
+/// Replace the `{name}` placeholders of a command template.
+fn expand(template: &[String], vars: &[(&str, &str)]) -> Vec<String> {
+    template
+        .iter()
+        .map(|arg| {
+            vars.iter()
+                .fold(arg.clone(), |arg, (name, value)| arg.replace(&format!("{{{}}}", name), value))
+        })
+        .collect()
+}
+
+enum FirewallOp {
+    Add { ip: IpAddr, timeout_secs: u64 },
+    Remove(IpAddr),
+}
+
+#[derive(Default)]
+struct Shared {
+    /// IPs whose add command succeeded and that were not removed since.
+    managed: AtomicUsize,
+    queued: AtomicUsize,
+    added: AtomicU64,
+    removed: AtomicU64,
+    failures: AtomicU64,
+    skipped: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Current state of the kernel offload, for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirewallStatus {
+    pub enabled: bool,
+    /// IPs Fortress added to the kernel set and will remove again.
+    pub managed: usize,
+    /// IPs offloaded and not yet due for removal, including those whose
+    /// add command is still queued or failed.
+    pub tracked: usize,
+    /// Commands waiting to run.
+    pub queued: usize,
+    pub added: u64,
+    pub removed: u64,
+    pub failures: u64,
+    /// Offloads and removals not queued because the queue was full.
+    pub skipped: u64,
+    pub last_error: Option<String>,
+}
+
+/// Offload of banned IPs to a kernel firewall set.
+///
+/// Auto-banned IPs, and IPs whose connections the L4 tracker keeps
+/// refusing, are added to the set by the configured commands and removed
+/// once their time is up or their ban is lifted. The commands run one at a
+/// time on a dedicated thread, at most `max_commands_per_sec`; an IP
+/// already offloaded is only added again, with its new timeout, when it is
+/// banned for longer. A command that fails leaves the IP blocked at L7
+/// only.
+pub struct FirewallOffload {
+    config: FirewallConfig,
+    settings: SharedSettings,
+    /// `None` when the offload is disabled.
+    tx: Option<SyncSender<FirewallOp>>,
+    /// Offloaded IPs and when they are removed again.
+    entries: DashMap<IpAddr, Instant>,
+    /// Start of the current window and L4 drops within it, per IP.
+    l4_drops: DashMap<IpAddr, (Instant, u32)>,
+    shared: Arc<Shared>,
+    entries_sweep: Sweep,
+    drops_sweep: Sweep,
+}
+
+impl FirewallOffload {
+    pub fn new(settings: SharedSettings) -> Self {
+        let timeout = Duration::from_millis(settings.load().firewall.command_timeout_ms);
+        Self::with_executor(settings, Box::new(CommandExecutor::new(timeout)))
+    }
+
+    pub fn with_executor(settings: SharedSettings, executor: Box<dyn FirewallExecutor>) -> Self {
+        let config = settings.load().firewall.clone();
+        let shared = Arc::new(Shared::default());
+        let mut tx = None;
+        if !config.enabled {
+            debug!("Firewall offload disabled");
+        } else if config.add_command.is_empty() || config.remove_command.is_empty() {
+            warn!("firewall.add_command and firewall.remove_command must be set; firewall offload disabled");
+        } else {
+            let (sender, rx) = mpsc::sync_channel(config.queue_size.max(1));
+            let worker = Worker::new(config.clone(), executor, shared.clone());
+            match std::thread::Builder::new()
+                .name("firewall".to_string())
+                .spawn(move || worker.run(rx))
+            {
+                Ok(_) => {
+                    info!(max_commands_per_sec = config.max_commands_per_sec, "Firewall offload enabled");
+                    tx = Some(sender);
+                }
+                Err(e) => warn!("Failed to start the firewall worker, offload disabled: {}", e),
+            }
+        }
+
+        Self {
+            config,
+            settings,
+            tx,
+            entries: DashMap::new(),
+            l4_drops: DashMap::new(),
+            shared,
+            entries_sweep: Sweep::new(),
+            drops_sweep: Sweep::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    /// Add `ip` to the kernel set for `duration`. An IP already offloaded
+    /// is added again with the longer timeout if `duration` ends later.
+    /// Whitelisted IPs are never offloaded.
+    pub fn add(&self, ip: IpAddr, duration: Duration) {
+        let Some(tx) = &self.tx else { return };
+        if self.settings.load().protection.whitelist.contains(&ip) {
+            return;
+        }
+        let expires = Instant::now() + duration;
+        // The entry stays locked while the command is queued, so the adds
+        // and removals of one IP are queued in the order they happened.
+        let op = FirewallOp::Add { ip, timeout_secs: duration.as_secs().max(1) };
+        match self.entries.entry(ip) {
+            Entry::Occupied(mut entry) => {
+                if *entry.get() < expires && self.send(tx, op) {
+                    entry.insert(expires);
+                }
+            }
+            Entry::Vacant(entry) => {
+                if self.send(tx, op) {
+                    entry.insert(expires);
+                }
+            }
+        }
+    }
+
+    /// Take `ip` out of the kernel set, e.g. when its ban is lifted.
+    /// Returns whether it was offloaded.
+    pub fn remove(&self, ip: &IpAddr) -> bool {
+        let Some(tx) = &self.tx else { return false };
+        let Entry::Occupied(mut entry) = self.entries.entry(*ip) else { return false };
+        if self.send(tx, FirewallOp::Remove(*ip)) {
+            entry.remove();
+        } else {
+            // Retried by the next cleanup
+            entry.insert(Instant::now());
+        }
+        true
+    }
+
+    /// Count a connection of `ip` refused by the L4 tracker. Once
+    /// `l4_drop_threshold` were refused within `l4_drop_window_secs`, the
+    /// IP is offloaded for `l4_ban_secs`.
+    pub fn record_l4_drop(&self, ip: IpAddr) {
+        if self.tx.is_none() || self.config.l4_drop_threshold == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.l4_drop_window_secs);
+        let reached = {
+            let mut drops = self.l4_drops.entry(ip).or_insert((now, 0));
+            if now.duration_since(drops.0) >= window {
+                *drops = (now, 0);
+            }
+            drops.1 += 1;
+            drops.1 == self.config.l4_drop_threshold
+        };
+        if reached {
+            info!(
+                ip = %ip,
+                drops = self.config.l4_drop_threshold,
+                window_secs = window.as_secs(),
+                "Offloading IP to the firewall after repeated L4 drops"
+            );
+            self.add(ip, Duration::from_secs(self.config.l4_ban_secs));
+        }
+    }
+
+    pub fn status(&self) -> FirewallStatus {
+        FirewallStatus {
+            enabled: self.is_enabled(),
+            managed: self.shared.managed.load(Ordering::Relaxed),
+            tracked: self.entries.len(),
+            queued: self.shared.queued.load(Ordering::Relaxed),
+            added: self.shared.added.load(Ordering::Relaxed),
+            removed: self.shared.removed.load(Ordering::Relaxed),
+            failures: self.shared.failures.load(Ordering::Relaxed),
+            skipped: self.shared.skipped.load(Ordering::Relaxed),
+            last_error: self.shared.last_error.lock().clone(),
+        }
+    }
+
+    /// Remove expired IPs from the kernel set and forget old L4 drop
+    /// counts, visiting about `budget` entries of each map.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut stats = self.entries_sweep.retain(&self.entries, budget, |ip, expires| {
+            if *expires <= now {
+                expired.push(*ip);
+            }
+            true
+        });
+        // Removed outside `retain`, through the same path as an unban
+        for ip in expired {
+            let still_expired = self.entries.get(&ip).is_some_and(|expires| *expires <= now);
+            if still_expired {
+                debug!(ip = %ip, "Firewall offload expired");
+                if self.remove(&ip) {
+                    stats.removed += 1;
+                }
+            }
+        }
+        let window = Duration::from_secs(self.config.l4_drop_window_secs);
+        stats += self
+            .drops_sweep
+            .retain(&self.l4_drops, budget, |_, (start, _)| now.duration_since(*start) < window);
+        stats
+    }
+
+    /// Queue a command without blocking; false if the queue is full.
+    fn send(&self, tx: &SyncSender<FirewallOp>, op: FirewallOp) -> bool {
+        self.shared.queued.fetch_add(1, Ordering::Relaxed);
+        if tx.try_send(op).is_ok() {
+            return true;
+        }
+        self.shared.queued.fetch_sub(1, Ordering::Relaxed);
+        let skipped = self.shared.skipped.fetch_add(1, Ordering::Relaxed);
+        if skipped.is_multiple_of(100) {
+            warn!(skipped = skipped + 1, "Firewall command queue full; IPs stay blocked at L7 only");
+        }
+        false
+    }
+}
+
+/// Owns the executor and the set of IPs it added, on the firewall thread.
+struct Worker {
+    config: FirewallConfig,
+    executor: Box<dyn FirewallExecutor>,
+    shared: Arc<Shared>,
+    installed: HashSet<IpAddr>,
+    /// Minimum time between two commands.
+    gap: Duration,
+    last_run: Option<Instant>,
+    consecutive_failures: u32,
+}
+
+impl Worker {
+    fn new(config: FirewallConfig, executor: Box<dyn FirewallExecutor>, shared: Arc<Shared>) -> Self {
+        let gap = match config.max_commands_per_sec {
+            0 => Duration::ZERO,
+            n => Duration::from_secs(1) / n,
+        };
+        Self {
+            config,
+            executor,
+            shared,
+            installed: HashSet::new(),
+            gap,
+            last_run: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    fn run(mut self, rx: Receiver<FirewallOp>) {
+        self.flush();
+        while let Ok(op) = rx.recv() {
+            self.shared.queued.fetch_sub(1, Ordering::Relaxed);
+            self.handle(op);
+            self.shared.managed.store(self.installed.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Clear what an earlier run may have left in the set, once per family.
+    fn flush(&mut self) {
+        if self.config.flush_command.is_empty() {
+            return;
+        }
+        let mut commands = vec![
+            expand(&self.config.flush_command, &[("family", "ipv4")]),
+            expand(&self.config.flush_command, &[("family", "ipv6")]),
+        ];
+        commands.dedup();
+        for argv in commands {
+            self.execute(&argv);
+        }
+    }
+
+    fn handle(&mut self, op: FirewallOp) {
+        match op {
+            FirewallOp::Add { ip, timeout_secs } => {
+                let (ip_str, timeout) = (ip.to_string(), timeout_secs.to_string());
+                let vars = [("ip", ip_str.as_str()), ("family", family(&ip)), ("timeout", timeout.as_str())];
+                // Adding an element again keeps its first timeout, so a
+                // longer ban replaces it.
+                if self.installed.remove(&ip) {
+                    let argv = expand(&self.config.remove_command, &vars[..2]);
+                    if self.execute(&argv) {
+                        self.shared.removed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                let argv = expand(&self.config.add_command, &vars);
+                if self.execute(&argv) {
+                    self.installed.insert(ip);
+                    self.shared.added.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            FirewallOp::Remove(ip) => {
+                // Nothing to remove if the add failed
+                if !self.installed.remove(&ip) {
+                    return;
+                }
+                let ip_str = ip.to_string();
+                let vars = [("ip", ip_str.as_str()), ("family", family(&ip))];
+                let argv = expand(&self.config.remove_command, &vars);
+                if self.execute(&argv) {
+                    self.shared.removed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Run one command, waiting for the rate limit first.
+    fn execute(&mut self, argv: &[String]) -> bool {
+        if let Some(last) = self.last_run {
+            let next = last + self.gap;
+            let now = Instant::now();
+            if next > now {
+                std::thread::sleep(next - now);
+            }
+        }
+        self.last_run = Some(Instant::now());
+
+        match self.executor.run(argv) {
+            Ok(()) => {
+                if self.consecutive_failures > 0 {
+                    info!(failed = self.consecutive_failures, "Firewall commands succeed again");
+                    self.consecutive_failures = 0;
+                }
+                true
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                self.shared.failures.fetch_add(1, Ordering::Relaxed);
+                // Once per run of failures, so a missing `nft` doesn't flood the log
+                if self.consecutive_failures == 1 {
+                    warn!(command = %argv.join(" "), error = %e, "Firewall command failed; IPs stay blocked at L7 only");
+                } else {
+                    debug!(command = %argv.join(" "), error = %e, "Firewall command failed");
+                }
+                *self.shared.last_error.lock() = Some(e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::settings::Settings;
+    use arc_swap::ArcSwap;
+
+    /// Records every command; those naming `203.0.113.9` fail.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl FirewallExecutor for Recorder {
+        fn run(&mut self, argv: &[String]) -> Result<(), String> {
+            let command = argv.join(" ");
+            let failed = command.contains("203.0.113.9");
+            self.0.lock().push(command);
+            if failed {
+                Err("element does not exist".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn wait_for(commands: &Mutex<Vec<String>>, n: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while commands.lock().len() < n && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        commands.lock().clone()
+    }
+
+    #[test]
+    fn test_offloads_extends_and_removes_on_unban_expiry_and_l4_drops() {
+        let mut settings = Settings::default();
+        settings.firewall.enabled = true;
+        settings.firewall.max_commands_per_sec = 0;
+        settings.firewall.l4_drop_threshold = 3;
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let firewall = FirewallOffload::with_executor(
+            Arc::new(ArcSwap::from_pointee(settings)),
+            Box::new(Recorder(commands.clone())),
+        );
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+
+        // Banned again for longer, re-added with the new timeout; a shorter
+        // ban changes nothing and a failed add is not managed
+        firewall.add(ip("192.0.2.1"), Duration::from_secs(30));
+        firewall.add(ip("192.0.2.1"), Duration::from_secs(60));
+        firewall.add(ip("192.0.2.1"), Duration::from_secs(10));
+        firewall.add(ip("2001:db8::1"), Duration::from_secs(30));
+        firewall.add(ip("203.0.113.9"), Duration::from_secs(30));
+        let seen = wait_for(&commands, 7);
+        assert_eq!(
+            seen,
+            [
+                "nft flush set inet fortress banned_ipv4",
+                "nft flush set inet fortress banned_ipv6",
+                "nft add element inet fortress banned_ipv4 { 192.0.2.1 timeout 30s }",
+                "nft delete element inet fortress banned_ipv4 { 192.0.2.1 }",
+                "nft add element inet fortress banned_ipv4 { 192.0.2.1 timeout 60s }",
+                "nft add element inet fortress banned_ipv6 { 2001:db8::1 timeout 30s }",
+                "nft add element inet fortress banned_ipv4 { 203.0.113.9 timeout 30s }",
+            ]
+        );
+
+        // Unbanned; the failed one has nothing to remove
+        firewall.remove(&ip("192.0.2.1"));
+        firewall.remove(&ip("203.0.113.9"));
+        firewall.remove(&ip("198.51.100.1"));
+        let seen = wait_for(&commands, 8);
+        assert_eq!(seen[7], "nft delete element inet fortress banned_ipv4 { 192.0.2.1 }");
+
+        // Repeated L4 drops offload for `l4_ban_secs`
+        for _ in 0..4 {
+            firewall.record_l4_drop(ip("198.51.100.7"));
+        }
+        let seen = wait_for(&commands, 9);
+        assert_eq!(seen[8], "nft add element inet fortress banned_ipv4 { 198.51.100.7 timeout 600s }");
+
+        // Expired offloads are removed by the cleanup
+        firewall.add(ip("192.0.2.2"), Duration::ZERO);
+        firewall.cleanup(1000);
+        let seen = wait_for(&commands, 11);
+        assert_eq!(seen[10], "nft delete element inet fortress banned_ipv4 { 192.0.2.2 }");
+        assert_eq!(seen.len(), 11);
+
+        // The counters are updated once the last command returned
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while firewall.status().removed < 3 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        let status = firewall.status();
+        assert_eq!((status.managed, status.tracked), (2, 2));
+        assert_eq!((status.added, status.removed, status.failures), (5, 3, 1));
+        assert!(status.last_error.is_some());
+    }
+}
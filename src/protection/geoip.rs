@@ -1,15 +1,189 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use serde::Serialize;
 use tracing::{info, warn};
 
+use crate::config::settings::GeoipConfig;
+
 /// GeoIP lookup using MaxMind databases.
 ///
 /// Provides country, ASN, and city lookups for IP addresses. Gracefully
 /// degrades if database files are not available - lookups simply return None.
+///
+/// Both readers live in one [`GeoIpDatabases`] snapshot behind an
+/// `ArcSwap`, so a reload replaces them together and a lookup in flight
+/// keeps using the snapshot it started with.
+///
+/// Results are cached per IP or per subnet (`geoip.cache_key`), since
+/// floods repeat the same ranges. A reload empties the cache.
+///
+/// Lookups that come back empty are counted, so a missing database or a
+/// poorly covered range shows up in the metrics rather than only as
+/// country rules that never match.
 pub struct GeoIpLookup {
-    city_reader: Option<maxminddb::Reader<Vec<u8>>>,
-    asn_reader: Option<maxminddb::Reader<Vec<u8>>>,
+    databases: ArcSwap<GeoIpDatabases>,
+    /// None when `geoip.cache_capacity` is 0.
+    cache: Option<GeoCache>,
+    country_lookups: AtomicU64,
+    country_misses: AtomicU64,
+    asn_lookups: AtomicU64,
+    asn_misses: AtomicU64,
+}
+
+/// Everything looked up for one cache key.
+struct CachedGeo {
+    country: Option<String>,
+    city: Option<String>,
+    asn: Option<(u32, String)>,
+    inserted: Instant,
+}
+
+struct GeoCache {
+    entries: DashMap<IpAddr, CachedGeo>,
+    capacity: usize,
+    ttl: Duration,
+    /// Key IPv4 by /24 and IPv6 by /48 instead of the full address.
+    by_subnet: bool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Cache counters reported by `/api/fortress/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoIpCacheStats {
+    pub enabled: bool,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl GeoCache {
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        if !self.by_subnet {
+            return ip;
+        }
+        match ip {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & 0xFFFF_FF00)),
+            IpAddr::V6(v6) => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1)))
+            }
+        }
+    }
+
+    /// Insert, making room first when full: expired entries go, and if
+    /// that is not enough the whole cache is dropped. A flood of distinct
+    /// ranges then costs a refill rather than unbounded memory.
+    fn insert(&self, key: IpAddr, entry: CachedGeo) {
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, e| e.inserted.elapsed() < self.ttl);
+            if self.entries.len() >= self.capacity {
+                self.entries.clear();
+            }
+        }
+        self.entries.insert(key, entry);
+    }
+}
+
+/// Country and ASN lookups made and how many returned nothing, reported by
+/// `/api/fortress/metrics` and Prometheus.
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoIpCoverage {
+    pub country_lookups: u64,
+    pub country_misses: u64,
+    pub asn_lookups: u64,
+    pub asn_misses: u64,
+}
+
+#[derive(Default)]
+struct GeoIpDatabases {
+    city: Option<Arc<LoadedDb>>,
+    asn: Option<Arc<LoadedDb>>,
+}
+
+/// An opened database file and the mtime it had when it was read.
+struct LoadedDb {
+    path: String,
+    modified: Option<SystemTime>,
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+/// Load state of one database, reported by `/api/fortress/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoIpDbStatus {
+    pub path: Option<String>,
+    pub loaded: bool,
+    pub database_type: Option<String>,
+    /// Unix timestamp at which MaxMind built the database.
+    pub build_epoch: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoIpStatus {
+    /// True when at least one database is loaded and lookups return data.
+    pub active: bool,
+    /// `ok`, `missing_city`, `missing_asn` or `missing` (both).
+    pub mode: &'static str,
+    pub city: GeoIpDbStatus,
+    pub asn: GeoIpDbStatus,
+}
+
+fn file_mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Open the database at `path`. When it cannot be opened and `previous` was
+/// loaded from the same path, the previous reader is kept so a half-written
+/// update never disables lookups.
+fn open_db(kind: &str, path: &str, previous: Option<&Arc<LoadedDb>>) -> Option<Arc<LoadedDb>> {
+    let keep_previous = || previous.filter(|db| db.path == path).cloned();
+
+    if !Path::new(path).exists() {
+        warn!(path, "GeoIP {} database file not found", kind);
+        return keep_previous();
+    }
+
+    let modified = file_mtime(path);
+    match maxminddb::Reader::open_readfile(path) {
+        Ok(reader) => {
+            info!(
+                path,
+                build_epoch = reader.metadata.build_epoch,
+                "GeoIP {} database loaded successfully",
+                kind
+            );
+            Some(Arc::new(LoadedDb {
+                path: path.to_string(),
+                modified,
+                reader,
+            }))
+        }
+        Err(e) => {
+            warn!(path, error = %e, "Failed to load GeoIP {} database", kind);
+            keep_previous()
+        }
+    }
+}
+
+fn db_changed(db: Option<&Arc<LoadedDb>>, path: &str) -> bool {
+    match db {
+        Some(db) => db.path != path || file_mtime(path).is_some_and(|m| Some(m) != db.modified),
+        None => Path::new(path).exists(),
+    }
+}
+
+fn db_status(db: Option<&Arc<LoadedDb>>) -> GeoIpDbStatus {
+    GeoIpDbStatus {
+        path: db.map(|db| db.path.clone()),
+        loaded: db.is_some(),
+        database_type: db.map(|db| db.reader.metadata.database_type.clone()),
+        build_epoch: db.map(|db| db.reader.metadata.build_epoch),
+    }
 }
 
 /// Minimal struct for deserializing country data from MaxMind GeoIP2/GeoLite2.
@@ -48,50 +222,137 @@ impl GeoIpLookup {
     /// If a database file is not found or fails to load, the corresponding
     /// lookups will return None (graceful degradation). This allows Fortress
     /// to run without GeoIP databases, just with reduced functionality.
-    pub fn new(city_db: &str, asn_db: &str) -> Self {
-        let city_reader = if Path::new(city_db).exists() {
-            match maxminddb::Reader::open_readfile(city_db) {
-                Ok(reader) => {
-                    info!(path = city_db, "GeoIP city database loaded successfully");
-                    Some(reader)
-                }
-                Err(e) => {
-                    warn!(path = city_db, error = %e, "Failed to load GeoIP city database");
-                    None
-                }
-            }
-        } else {
-            warn!(path = city_db, "GeoIP city database file not found");
-            None
+    pub fn new(config: &GeoipConfig) -> Self {
+        let databases = GeoIpDatabases {
+            city: open_db("city", &config.city_db, None),
+            asn: open_db("ASN", &config.asn_db, None),
         };
+        let cache = (config.cache_capacity > 0).then(|| GeoCache {
+            entries: DashMap::new(),
+            capacity: config.cache_capacity,
+            ttl: Duration::from_secs(config.cache_ttl_secs),
+            by_subnet: config.cache_key != "ip",
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        Self {
+            databases: ArcSwap::from_pointee(databases),
+            cache,
+            country_lookups: AtomicU64::new(0),
+            country_misses: AtomicU64::new(0),
+            asn_lookups: AtomicU64::new(0),
+            asn_misses: AtomicU64::new(0),
+        }
+    }
 
-        let asn_reader = if Path::new(asn_db).exists() {
-            match maxminddb::Reader::open_readfile(asn_db) {
-                Ok(reader) => {
-                    info!(path = asn_db, "GeoIP ASN database loaded successfully");
-                    Some(reader)
-                }
-                Err(e) => {
-                    warn!(path = asn_db, error = %e, "Failed to load GeoIP ASN database");
-                    None
-                }
-            }
-        } else {
-            warn!(path = asn_db, "GeoIP ASN database file not found");
-            None
+    /// Reopen both database files and swap them in atomically. A file that
+    /// fails to open keeps its previously loaded reader.
+    pub fn reload(&self, city_db: &str, asn_db: &str) -> GeoIpStatus {
+        let current = self.databases.load();
+        let databases = GeoIpDatabases {
+            city: open_db("city", city_db, current.city.as_ref()),
+            asn: open_db("ASN", asn_db, current.asn.as_ref()),
         };
+        self.databases.store(Arc::new(databases));
+        if let Some(cache) = &self.cache {
+            cache.entries.clear();
+        }
+        self.status()
+    }
 
-        Self {
-            city_reader,
-            asn_reader,
+    /// Reload if either file was replaced, modified, or appeared since it
+    /// was last read. Returns true when a reload happened.
+    pub fn reload_if_changed(&self, city_db: &str, asn_db: &str) -> bool {
+        let current = self.databases.load();
+        if !db_changed(current.city.as_ref(), city_db) && !db_changed(current.asn.as_ref(), asn_db) {
+            return false;
+        }
+        info!("GeoIP database files changed, reloading");
+        self.reload(city_db, asn_db);
+        true
+    }
+
+    /// Load state of both databases.
+    pub fn status(&self) -> GeoIpStatus {
+        let current = self.databases.load();
+        GeoIpStatus {
+            active: current.city.is_some() || current.asn.is_some(),
+            mode: match (current.city.is_some(), current.asn.is_some()) {
+                (true, true) => "ok",
+                (false, true) => "missing_city",
+                (true, false) => "missing_asn",
+                (false, false) => "missing",
+            },
+            city: db_status(current.city.as_ref()),
+            asn: db_status(current.asn.as_ref()),
+        }
+    }
+
+    /// Hit and miss counters of the lookup cache.
+    pub fn cache_stats(&self) -> GeoIpCacheStats {
+        match &self.cache {
+            Some(cache) => GeoIpCacheStats {
+                enabled: true,
+                entries: cache.entries.len(),
+                hits: cache.hits.load(Ordering::Relaxed),
+                misses: cache.misses.load(Ordering::Relaxed),
+            },
+            None => GeoIpCacheStats { enabled: false, entries: 0, hits: 0, misses: 0 },
         }
     }
 
+    /// Lookup counters since startup.
+    pub fn coverage(&self) -> GeoIpCoverage {
+        GeoIpCoverage {
+            country_lookups: self.country_lookups.load(Ordering::Relaxed),
+            country_misses: self.country_misses.load(Ordering::Relaxed),
+            asn_lookups: self.asn_lookups.load(Ordering::Relaxed),
+            asn_misses: self.asn_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Read one field from the cache entry for `ip`, filling the entry with
+    /// all lookups on a miss so the next field is a hit too.
+    fn cached<T>(&self, cache: &GeoCache, ip: IpAddr, field: impl Fn(&CachedGeo) -> T) -> T {
+        let key = cache.key(ip);
+        if let Some(entry) = cache.entries.get(&key) {
+            if entry.inserted.elapsed() < cache.ttl {
+                cache.hits.fetch_add(1, Ordering::Relaxed);
+                return field(&entry);
+            }
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+
+        let (country, city) = self.city_record(ip);
+        let entry = CachedGeo {
+            country,
+            city,
+            asn: self.asn_record(ip),
+            inserted: Instant::now(),
+        };
+        let value = field(&entry);
+        cache.insert(key, entry);
+        value
+    }
+
     /// Look up the 2-letter ISO country code for an IP address.
     ///
     /// Returns None if the database is not loaded or the IP is not found.
     pub fn lookup_country(&self, ip: IpAddr) -> Option<String> {
-        let reader = self.city_reader.as_ref()?;
+        let country = self.country_record(ip);
+        self.country_lookups.fetch_add(1, Ordering::Relaxed);
+        if country.is_none() {
+            self.country_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        country
+    }
+
+    fn country_record(&self, ip: IpAddr) -> Option<String> {
+        if let Some(cache) = &self.cache {
+            return self.cached(cache, ip, |e| e.country.clone());
+        }
+        let databases = self.databases.load();
+        let reader = &databases.city.as_ref()?.reader;
 
         match reader.lookup::<GeoIpCountry>(ip) {
             Ok(result) => result
@@ -112,7 +373,20 @@ impl GeoIpLookup {
     ///
     /// Returns None if the ASN database is not loaded or the IP is not found.
     pub fn lookup_asn(&self, ip: IpAddr) -> Option<(u32, String)> {
-        let reader = self.asn_reader.as_ref()?;
+        let asn = match &self.cache {
+            Some(cache) => self.cached(cache, ip, |e| e.asn.clone()),
+            None => self.asn_record(ip),
+        };
+        self.asn_lookups.fetch_add(1, Ordering::Relaxed);
+        if asn.is_none() {
+            self.asn_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        asn
+    }
+
+    fn asn_record(&self, ip: IpAddr) -> Option<(u32, String)> {
+        let databases = self.databases.load();
+        let reader = &databases.asn.as_ref()?.reader;
 
         match reader.lookup::<GeoIpAsn>(ip) {
             Ok(result) => {
@@ -135,29 +409,111 @@ impl GeoIpLookup {
     ///
     /// Returns the English city name if available, None otherwise.
     pub fn lookup_city(&self, ip: IpAddr) -> Option<String> {
-        let reader = self.city_reader.as_ref()?;
+        if let Some(cache) = &self.cache {
+            return self.cached(cache, ip, |e| e.city.clone());
+        }
+        self.city_record(ip).1
+    }
 
-        match reader.lookup::<GeoIpCity>(ip) {
-            Ok(result) => result
-                .city
-                .and_then(|c| c.names)
-                .and_then(|names| names.get("en").cloned()),
+    /// Country code and English city name from one city database read.
+    fn city_record(&self, ip: IpAddr) -> (Option<String>, Option<String>) {
+        let databases = self.databases.load();
+        let Some(db) = databases.city.as_ref() else {
+            return (None, None);
+        };
+
+        match db.reader.lookup::<GeoIpCity>(ip) {
+            Ok(result) => (
+                result
+                    .country
+                    .and_then(|c| c.iso_code)
+                    .map(|code| code.to_uppercase()),
+                result
+                    .city
+                    .and_then(|c| c.names)
+                    .and_then(|names| names.get("en").cloned()),
+            ),
             Err(e) => {
                 if !matches!(e, maxminddb::MaxMindDBError::AddressNotFoundError(_)) {
                     warn!(ip = %ip, error = %e, "GeoIP city lookup error");
                 }
-                None
+                (None, None)
             }
         }
     }
 
     /// Check if the GeoIP city database is loaded.
     pub fn has_city_db(&self) -> bool {
-        self.city_reader.is_some()
+        self.databases.load().city.is_some()
     }
 
     /// Check if the GeoIP ASN database is loaded.
     pub fn has_asn_db(&self) -> bool {
-        self.asn_reader.is_some()
+        self.databases.load().asn.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(by_subnet: bool, capacity: usize) -> GeoCache {
+        GeoCache {
+            entries: DashMap::new(),
+            capacity,
+            ttl: Duration::from_secs(60),
+            by_subnet,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn test_subnet_keys_group_v4_by_24_and_v6_by_48() {
+        let c = cache(true, 10);
+        let key = |s: &str| c.key(s.parse().unwrap());
+        assert_eq!(key("203.0.113.7"), key("203.0.113.200"));
+        assert_ne!(key("203.0.113.7"), key("203.0.114.7"));
+        assert_eq!(key("2001:db8:1:aaaa::1"), key("2001:db8:1:bbbb:1:2:3:4"));
+        assert_ne!(key("2001:db8:1::1"), key("2001:db8:2::1"));
+
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(cache(false, 10).key(ip), ip);
+    }
+
+    #[test]
+    fn test_missing_databases_are_reported_and_fall_back_to_trusted_header() {
+        let mut config = crate::config::defaults::default_geoip_config();
+        config.city_db = "/nonexistent/city.mmdb".to_string();
+        config.asn_db = "/nonexistent/asn.mmdb".to_string();
+        config.country_header = "X-Geo-Country".to_string();
+        config.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+        config.build_trusted_proxies();
+
+        let geoip = GeoIpLookup::new(&config);
+        assert_eq!(geoip.status().mode, "missing");
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(geoip.lookup_country(ip), None);
+        assert_eq!(geoip.lookup_asn(ip), None);
+        let coverage = geoip.coverage();
+        assert_eq!((coverage.country_lookups, coverage.country_misses), (1, 1));
+        assert_eq!((coverage.asn_lookups, coverage.asn_misses), (1, 1));
+
+        let headers = std::collections::HashMap::from([("x-geo-country".to_string(), "de".to_string())]);
+        let proxy: IpAddr = "10.1.2.3".parse().unwrap();
+        assert_eq!(config.fallback_country(proxy, &headers).as_deref(), Some("DE"));
+        assert_eq!(config.fallback_country(ip, &headers), None);
+        let unknown = std::collections::HashMap::from([("x-geo-country".to_string(), "XX".to_string())]);
+        assert_eq!(config.fallback_country(proxy, &unknown), None);
+    }
+
+    #[test]
+    fn test_full_cache_is_emptied_before_insert() {
+        let c = cache(false, 2);
+        for i in 1..=3u8 {
+            let entry = CachedGeo { country: None, city: None, asn: None, inserted: Instant::now() };
+            c.insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), entry);
+        }
+        assert_eq!(c.entries.len(), 1);
     }
 }
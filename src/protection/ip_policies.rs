@@ -0,0 +1,166 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::storage::ip_ranges::{parse_ip_or_cidr, IpRangeMap};
+use crate::storage::memory::{IpPolicyEntry, MemoryStore};
+use crate::storage::sqlite::{IpPolicyRow, SqliteStore};
+
+/// How often policies past their TTL are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// How the pipeline treats an IP or range, short of whitelisting or
+/// blocking it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPolicy {
+    /// Never shown a challenge page. Blocklists, rules and rate limits
+    /// still apply; over a rate limit is a block at any level.
+    NeverChallenge,
+    /// Challenged whatever its score, until it holds a clearance.
+    AlwaysChallenge,
+    /// Exempt from the rate limits; every other layer applies.
+    BypassRateLimit,
+}
+
+impl IpPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpPolicy::NeverChallenge => "never_challenge",
+            IpPolicy::AlwaysChallenge => "always_challenge",
+            IpPolicy::BypassRateLimit => "bypass_rate_limit",
+        }
+    }
+
+    pub fn from_str_name(s: &str) -> Option<Self> {
+        match s {
+            "never_challenge" => Some(IpPolicy::NeverChallenge),
+            "always_challenge" => Some(IpPolicy::AlwaysChallenge),
+            "bypass_rate_limit" => Some(IpPolicy::BypassRateLimit),
+            _ => None,
+        }
+    }
+}
+
+/// Per-IP and per-CIDR policies, kept in SQLite and cached in the
+/// [`MemoryStore`] for the pipeline. A network has one policy; when ranges
+/// overlap the most specific one applies.
+pub struct IpPolicyStore {
+    sqlite: Arc<SqliteStore>,
+    memory: Arc<MemoryStore>,
+}
+
+impl IpPolicyStore {
+    pub fn new(sqlite: Arc<SqliteStore>, memory: Arc<MemoryStore>) -> Self {
+        Self { sqlite, memory }
+    }
+
+    /// Load the policies stored in the database into the cache.
+    pub async fn load(&self) {
+        match self.sqlite.get_ip_policies().await {
+            Ok(rows) => {
+                let mut policies = IpRangeMap::new();
+                for row in &rows {
+                    match cache_entry(row) {
+                        Some((net, entry)) => policies.insert(net, entry),
+                        None => warn!(network = %row.network, policy = %row.policy, "Skipping invalid IP policy"),
+                    }
+                }
+                self.memory.replace_ip_policies(policies);
+            }
+            Err(e) => warn!(error = %e, "Failed to load IP policies from database"),
+        }
+    }
+
+    /// Periodically drop the policies whose TTL has run out. The pipeline
+    /// ignores them from the moment they expire.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            let evicted = self.memory.purge_expired_ip_policies();
+            match self.sqlite.delete_expired_ip_policies().await {
+                Ok(deleted) if evicted + deleted > 0 => debug!(evicted, deleted, "Expired IP policies purged"),
+                Ok(_) => {}
+                Err(e) => warn!(error = %e, "Failed to purge expired IP policies"),
+            }
+        }
+    }
+
+    /// Set the policy of an IP or CIDR, replacing the one it had. `actor`
+    /// is recorded in the audit log.
+    pub async fn set(
+        &self,
+        value: &str,
+        policy: IpPolicy,
+        reason: Option<&str>,
+        ttl: Option<Duration>,
+        actor: &str,
+    ) -> Result<IpPolicyRow, Box<dyn std::error::Error>> {
+        let net = parse_ip_or_cidr(value).ok_or_else(|| format!("Invalid IP address or CIDR range: {}", value))?.trunc();
+        let network = net.to_string();
+        let expires_at = ttl.map(|d| Utc::now() + chrono::Duration::seconds(d.as_secs().min(i64::MAX as u64) as i64));
+
+        let id = self.sqlite.upsert_ip_policy(&network, policy.as_str(), reason, expires_at).await?;
+        let row = IpPolicyRow {
+            id,
+            network,
+            policy: policy.as_str().to_string(),
+            reason: reason.map(str::to_string),
+            expires_at: expires_at.map(|t| t.format(TIME_FORMAT).to_string()),
+            created_at: Utc::now().format(TIME_FORMAT).to_string(),
+        };
+        self.memory.set_ip_policy(
+            net,
+            IpPolicyEntry { id, policy, reason: row.reason.clone(), expires_at },
+        );
+        self.sqlite.audit(actor, "set", "ip_policy", &row.network, Some(policy.as_str()));
+        Ok(row)
+    }
+
+    /// Remove a policy by its row ID. Returns the network it covered, if
+    /// the row existed.
+    pub async fn remove(&self, id: i64, actor: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let rows = self.sqlite.get_ip_policies().await?;
+        let Some(row) = rows.into_iter().find(|r| r.id == id) else {
+            return Ok(None);
+        };
+        if let Some(net) = parse_ip_or_cidr(&row.network) {
+            self.memory.remove_ip_policy(&net);
+        }
+        if !self.sqlite.delete_ip_policy(id).await? {
+            return Ok(None);
+        }
+        self.sqlite.audit(actor, "delete", "ip_policy", &row.network, Some(&row.policy));
+        Ok(Some(row.network))
+    }
+
+    /// Every stored policy, expired ones included until they are purged.
+    pub async fn list(&self) -> rusqlite::Result<Vec<IpPolicyRow>> {
+        self.sqlite.get_ip_policies().await
+    }
+}
+
+/// The cache entry for a row, or `None` if the row is malformed.
+fn cache_entry(row: &IpPolicyRow) -> Option<(IpNet, IpPolicyEntry)> {
+    let net = parse_ip_or_cidr(&row.network)?;
+    let policy = IpPolicy::from_str_name(&row.policy)?;
+    let expires_at = match row.expires_at.as_deref() {
+        Some(value) => Some(parse_time(value)?),
+        None => None,
+    };
+    Some((net, IpPolicyEntry { id: row.id, policy, reason: row.reason.clone(), expires_at }))
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(&format!("{} +0000", value), "%Y-%m-%d %H:%M:%S %z")
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc))
+}
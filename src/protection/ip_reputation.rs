@@ -1,23 +1,35 @@
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
 use crate::config::settings::IpReputationConfig;
+use crate::storage::ip_ranges::IpRangeMap;
+use crate::storage::sweep::{Sweep, SweepStats};
 
 // ---------------------------------------------------------------------------
 // Types
 // ---------------------------------------------------------------------------
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What an IP has been seen doing. Managed rules declare one and custom
+/// rules may set one (`"category": "scanner"`); a matching rule tags the
+/// client IP with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ReputationCategory {
     TorExit,
     KnownProxy,
     Scanner,
     BruteForce,
+    #[serde(rename = "ddos")]
     DDoS,
 }
 
@@ -31,7 +43,8 @@ struct IpEntry {
     first_seen: Instant,
     last_seen: Instant,
     last_decay: Instant,
-    categories: HashSet<ReputationCategory>,
+    /// Each category with its last occurrence.
+    categories: HashMap<ReputationCategory, Instant>,
     ban_count: u32,
 }
 
@@ -47,10 +60,32 @@ impl IpEntry {
             first_seen: now,
             last_seen: now,
             last_decay: now,
-            categories: HashSet::new(),
+            categories: HashMap::new(),
             ban_count: 0,
         }
     }
+
+    /// Categories that recurred within `ttl` (all of them for a zero `ttl`).
+    fn live_categories(&self, ttl: Duration, now: Instant) -> impl Iterator<Item = ReputationCategory> + '_ {
+        self.categories
+            .iter()
+            .filter(move |(_, seen)| ttl.is_zero() || now.duration_since(**seen) < ttl)
+            .map(|(category, _)| *category)
+    }
+}
+
+/// Refresh state of one reputation list, for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedStatus {
+    pub name: String,
+    /// `tor` or `proxy`.
+    pub kind: &'static str,
+    /// URL or local path the list was last loaded from.
+    pub source: String,
+    pub entries: usize,
+    /// Last successful refresh, including a 304 Not Modified.
+    pub last_refresh: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
 }
 
 // ---------------------------------------------------------------------------
@@ -59,7 +94,12 @@ impl IpEntry {
 
 pub struct IpReputationManager {
     entries: DashMap<IpAddr, IpEntry>,
-    tor_exits: DashMap<IpAddr, ()>,
+    entries_sweep: Sweep,
+    /// Swapped whole by the feed refresher, so lookups never see a
+    /// half-loaded list.
+    tor_exits: ArcSwap<IpRangeMap<()>>,
+    proxies: ArcSwap<IpRangeMap<()>>,
+    feeds: Mutex<Vec<FeedStatus>>,
     config: IpReputationConfig,
 }
 
@@ -67,7 +107,10 @@ impl IpReputationManager {
     pub fn new(config: &IpReputationConfig) -> Self {
         let manager = Self {
             entries: DashMap::with_capacity(100_000),
-            tor_exits: DashMap::new(),
+            entries_sweep: Sweep::new(),
+            tor_exits: ArcSwap::from_pointee(IpRangeMap::new()),
+            proxies: ArcSwap::from_pointee(IpRangeMap::new()),
+            feeds: Mutex::new(Vec::new()),
             config: config.clone(),
         };
 
@@ -93,9 +136,12 @@ impl IpReputationManager {
         let mut score = 0.0;
 
         // Check if Tor exit node
-        if self.config.tor_detection && self.tor_exits.contains_key(ip) {
+        if self.config.tor_detection && self.tor_exits.load().contains(ip) {
             score += self.config.tor_score;
         }
+        if self.proxies.load().contains(ip) {
+            score += self.config.proxy_score;
+        }
 
         // Check reputation score
         if let Some(entry) = self.entries.get(ip) {
@@ -124,11 +170,12 @@ impl IpReputationManager {
             }
 
             // Category-based scoring
-            if entry.categories.contains(&ReputationCategory::KnownProxy) {
-                score += 10.0;
-            }
-            if entry.categories.contains(&ReputationCategory::Scanner) {
-                score += 15.0;
+            for category in entry.live_categories(self.category_ttl(), now) {
+                match category {
+                    ReputationCategory::KnownProxy => score += 10.0,
+                    ReputationCategory::Scanner => score += 15.0,
+                    _ => {}
+                }
             }
         }
 
@@ -176,10 +223,27 @@ impl IpReputationManager {
         }
     }
 
-    /// Add a category to an IP's reputation.
+    /// Add a category to an IP's reputation, or refresh its last
+    /// occurrence.
     pub fn add_category(&self, ip: &IpAddr, category: ReputationCategory) {
+        if !self.config.enabled {
+            return;
+        }
         let mut entry = self.entries.entry(*ip).or_insert_with(IpEntry::new);
-        entry.categories.insert(category);
+        entry.categories.insert(category, Instant::now());
+    }
+
+    /// Current categories of an IP, sorted by name (for admin API).
+    pub fn get_categories(&self, ip: &IpAddr) -> Vec<String> {
+        let Some(entry) = self.entries.get(ip) else {
+            return Vec::new();
+        };
+        let mut cats: Vec<String> = entry
+            .live_categories(self.category_ttl(), Instant::now())
+            .map(|c| format!("{:?}", c))
+            .collect();
+        cats.sort();
+        cats
     }
 
     /// Get the reputation score for an IP (for admin API).
@@ -214,13 +278,34 @@ impl IpReputationManager {
 
     /// Check if an IP is a known Tor exit node.
     pub fn is_tor_exit(&self, ip: &IpAddr) -> bool {
-        self.tor_exits.contains_key(ip)
+        self.tor_exits.load().contains(ip)
+    }
+
+    /// Replace the Tor exit list.
+    pub fn replace_tor_exits<'a>(&self, nets: impl IntoIterator<Item = &'a IpNet>) {
+        self.tor_exits.store(Arc::new(range_map(nets)));
+    }
+
+    /// Replace the combined list of all proxy feeds.
+    pub fn replace_proxies<'a>(&self, nets: impl IntoIterator<Item = &'a IpNet>) {
+        self.proxies.store(Arc::new(range_map(nets)));
+    }
+
+    pub fn set_feed_status(&self, feeds: Vec<FeedStatus>) {
+        *self.feeds.lock() = feeds;
+    }
+
+    /// Last refresh and size of the Tor exit list and every proxy feed.
+    pub fn feed_status(&self) -> Vec<FeedStatus> {
+        self.feeds.lock().clone()
     }
 
     /// Get top IPs by reputation score (for admin API).
     pub fn get_top_ips(&self, limit: usize) -> Vec<(IpAddr, f64, u64, u64, Vec<String>)> {
+        let (ttl, now) = (self.category_ttl(), Instant::now());
         let mut entries: Vec<_> = self.entries.iter().map(|e| {
-            let cats: Vec<String> = e.categories.iter().map(|c| format!("{:?}", c)).collect();
+            let mut cats: Vec<String> = e.live_categories(ttl, now).map(|c| format!("{:?}", c)).collect();
+            cats.sort();
             (*e.key(), e.score, e.total_requests, e.blocked_count, cats)
         }).collect();
         entries.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
@@ -228,16 +313,22 @@ impl IpReputationManager {
         entries
     }
 
-    /// Cleanup old entries with zero score and no recent activity.
-    pub fn cleanup(&self) {
+    /// Cleanup old entries with zero score and no recent activity, and
+    /// categories that have not recurred for `category_decay_days`,
+    /// visiting about `budget` of them.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
         let now = Instant::now();
         let stale_threshold = Duration::from_secs(3600); // 1 hour
+        let category_ttl = self.category_ttl();
 
-        self.entries.retain(|_, entry| {
+        self.entries_sweep.retain(&self.entries, budget, |_, entry| {
+            if !category_ttl.is_zero() {
+                entry.categories.retain(|_, seen| now.duration_since(*seen) < category_ttl);
+            }
             let age = now.duration_since(entry.last_seen);
             // Keep entries with score > 1 or seen in the last hour
             entry.score > 1.0 || age < stale_threshold
-        });
+        })
     }
 
     /// Total tracked IPs count.
@@ -249,6 +340,10 @@ impl IpReputationManager {
     // Private helpers
     // -----------------------------------------------------------------------
 
+    fn category_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.category_decay_days * 86_400)
+    }
+
     fn apply_decay(&self, entry: &mut IpEntry) {
         let now = Instant::now();
         let decay_interval = Duration::from_secs(self.config.decay_interval_secs);
@@ -262,8 +357,8 @@ impl IpReputationManager {
     }
 
     /// Load well-known Tor exit node IPs.
-    /// These are a representative sample of commonly used exit nodes.
-    /// In production, this should be updated from a live feed periodically.
+    /// These are a representative sample of commonly used exit nodes, used
+    /// until the feed refresher has loaded `tor_exit_list_url`.
     fn load_tor_exit_nodes(&self) {
         // Well-known Tor exit node IPs (representative sample)
         let tor_exits = [
@@ -316,13 +411,28 @@ impl IpReputationManager {
             "209.127.17.234", "209.127.17.242",
         ];
 
-        let mut count = 0;
-        for ip_str in &tor_exits {
-            if let Ok(ip) = ip_str.parse::<IpAddr>() {
-                self.tor_exits.insert(ip, ());
-                count += 1;
-            }
-        }
-        info!("Loaded {} Tor exit node IPs", count);
+        let nets: Vec<IpNet> = tor_exits
+            .iter()
+            .filter_map(|ip_str| ip_str.parse::<IpAddr>().ok())
+            .map(IpNet::from)
+            .collect();
+        self.replace_tor_exits(&nets);
+        self.set_feed_status(vec![FeedStatus {
+            name: "tor".to_string(),
+            kind: "tor",
+            source: "built-in".to_string(),
+            entries: nets.len(),
+            last_refresh: None,
+            last_error: None,
+        }]);
+        info!("Loaded {} Tor exit node IPs", nets.len());
+    }
+}
+
+fn range_map<'a>(nets: impl IntoIterator<Item = &'a IpNet>) -> IpRangeMap<()> {
+    let mut map = IpRangeMap::new();
+    for net in nets {
+        map.insert(*net, ());
     }
+    map
 }
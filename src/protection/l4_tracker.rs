@@ -1,27 +1,127 @@
+use std::fmt;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
+use ipnet::IpNet;
 use tracing::{debug, info, warn};
 
 use crate::config::settings::L4ProtectionConfig;
-use crate::models::metrics::L4MetricsSnapshot;
+use crate::models::metrics::{L4IpStats, L4MetricsSnapshot};
+use crate::storage::memory::{ip_to_subnet, subnet_network, SubnetKey};
+use crate::storage::sweep::{Sweep, SweepStats};
 
 /// Action the proxy should take for a new TCP connection.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum L4Action {
     Allow,
-    Drop,
-    Tarpit,
+    Drop(L4Limit),
+    Tarpit(L4Limit),
 }
 
-/// Per-IP tracking state.
+/// Which limit a refused connection hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L4Limit {
+    Concurrent,
+    Rate,
+    SubnetConcurrent(IpNet),
+    SubnetRate(IpNet),
+    /// Cooling down after too many failed TLS handshakes.
+    HandshakeFailures,
+}
+
+/// The reason recorded on L4 events; subnet limits carry the subnet, e.g.
+/// `subnet_connection_limit_exceeded:203.0.113.0/24`.
+impl fmt::Display for L4Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Concurrent => f.write_str("connection_limit_exceeded"),
+            Self::Rate => f.write_str("rate_limit_exceeded"),
+            Self::SubnetConcurrent(net) => write!(f, "subnet_connection_limit_exceeded:{}", net),
+            Self::SubnetRate(net) => write!(f, "subnet_rate_limit_exceeded:{}", net),
+            Self::HandshakeFailures => f.write_str("handshake_failures"),
+        }
+    }
+}
+
+/// Per-IP (or per-subnet) tracking state.
+#[derive(Default)]
 struct IpState {
     concurrent: AtomicU64,
     /// Ring of recent connection timestamps (second-granularity).
     recent_connects: std::sync::Mutex<Vec<Instant>>,
+    dropped: AtomicU64,
+    tarpitted: AtomicU64,
+    /// Recent failed TLS handshakes, pruned to the failure window.
+    handshake_failures: std::sync::Mutex<Vec<Instant>>,
+    /// Connections are refused until then after too many failures.
+    handshake_cooldown_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl IpState {
+    /// Connections opened in the last second, pruning older ones.
+    fn rate(&self, now: Instant) -> u64 {
+        let one_sec_ago = now - Duration::from_secs(1);
+        match self.recent_connects.lock() {
+            Ok(mut recent) => {
+                recent.retain(|t| *t > one_sec_ago);
+                recent.len() as u64
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn record_connect(&self, now: Instant) {
+        if let Ok(mut recent) = self.recent_connects.lock() {
+            recent.push(now);
+        }
+    }
+
+    fn decrement(&self) {
+        // Use compare_exchange loop to prevent atomic underflow
+        loop {
+            let current = self.concurrent.load(Ordering::Relaxed);
+            if current == 0 {
+                break;
+            }
+            if self.concurrent.compare_exchange(current, current - 1, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                break;
+            }
+        }
+    }
+
+    /// Failed handshakes within `window`, pruning older ones.
+    fn handshake_failures(&self, now: Instant, window: Duration) -> u64 {
+        match self.handshake_failures.lock() {
+            Ok(mut failures) => {
+                failures.retain(|t| now.duration_since(*t) < window);
+                failures.len() as u64
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn in_handshake_cooldown(&self, now: Instant) -> bool {
+        self.handshake_cooldown_until
+            .lock()
+            .map(|until| until.is_some_and(|until| until > now))
+            .unwrap_or(false)
+    }
+
+    /// Still holds connections, connected within the last minute, or is
+    /// cooling down after handshake failures.
+    fn is_active(&self) -> bool {
+        if self.concurrent.load(Ordering::Relaxed) > 0 || self.in_handshake_cooldown(Instant::now()) {
+            return true;
+        }
+        if let Ok(recent) = self.recent_connects.lock() {
+            let cutoff = Instant::now() - Duration::from_secs(60);
+            recent.iter().any(|t| *t > cutoff)
+        } else {
+            false
+        }
+    }
 }
 
 /// TCP-level (Layer 4) connection tracker and rate limiter.
@@ -30,123 +130,306 @@ struct IpState {
 /// volumetric attacks without wasting CPU on crypto.
 pub struct L4Tracker {
     config: L4ProtectionConfig,
+    ipv4_subnet_mask: u8,
+    ipv6_subnet_mask: u8,
     ip_states: DashMap<IpAddr, IpState>,
+    subnet_states: DashMap<SubnetKey, IpState>,
+    ip_sweep: Sweep,
+    subnet_sweep: Sweep,
     total_allowed: AtomicU64,
     total_dropped: AtomicU64,
     total_tarpitted: AtomicU64,
+    total_handshake_failures: AtomicU64,
+    total_handshake_timeouts: AtomicU64,
 }
 
 impl L4Tracker {
-    /// Create a new tracker with the given configuration.
-    pub fn new(config: L4ProtectionConfig) -> Self {
+    /// Create a new tracker with the given configuration. Subnet limits
+    /// group addresses by the given prefix lengths.
+    pub fn new(config: L4ProtectionConfig, ipv4_subnet_mask: u8, ipv6_subnet_mask: u8) -> Self {
         Self {
             config,
+            ipv4_subnet_mask,
+            ipv6_subnet_mask,
             ip_states: DashMap::new(),
+            subnet_states: DashMap::new(),
+            ip_sweep: Sweep::new(),
+            subnet_sweep: Sweep::new(),
             total_allowed: AtomicU64::new(0),
             total_dropped: AtomicU64::new(0),
             total_tarpitted: AtomicU64::new(0),
+            total_handshake_failures: AtomicU64::new(0),
+            total_handshake_timeouts: AtomicU64::new(0),
         }
     }
 
+    fn subnet_key(&self, ip: IpAddr) -> SubnetKey {
+        ip_to_subnet(ip, self.ipv4_subnet_mask, self.ipv6_subnet_mask)
+    }
+
+    fn subnet_of(&self, ip: IpAddr) -> IpNet {
+        subnet_network(ip, self.ipv4_subnet_mask, self.ipv6_subnet_mask)
+    }
+
     /// Decide whether to allow, drop, or tarpit a new connection from `ip`.
     pub fn check_connection(&self, ip: IpAddr) -> L4Action {
-        let state = self.ip_states.entry(ip).or_insert_with(|| IpState {
-            concurrent: AtomicU64::new(0),
-            recent_connects: std::sync::Mutex::new(Vec::new()),
-        });
+        let state = self.ip_states.entry(ip).or_default();
+        let subnet = self.subnet_states.entry(self.subnet_key(ip)).or_default();
+        let now = Instant::now();
 
-        let concurrent = state.concurrent.load(Ordering::Relaxed);
+        if state.in_handshake_cooldown(now) {
+            debug!(client_ip = %ip, "L4: cooling down after TLS handshake failures");
+            return self.refuse(&state, L4Limit::HandshakeFailures, true);
+        }
 
-        // Check concurrent connection limit.
+        // Check concurrent connection limits.
+        let concurrent = state.concurrent.load(Ordering::Relaxed);
         if concurrent >= self.config.max_concurrent_per_ip {
             warn!(client_ip = %ip, concurrent = concurrent, "L4: max concurrent connections exceeded");
-            self.total_dropped.fetch_add(1, Ordering::Relaxed);
-            return L4Action::Drop;
-        }
-
-        // Check connection rate.
-        if let Ok(mut recent) = state.recent_connects.lock() {
-            let now = Instant::now();
-            let one_sec_ago = now - Duration::from_secs(1);
-            recent.retain(|t| *t > one_sec_ago);
-            let rate = recent.len() as u64;
-
-            if rate >= self.config.connection_rate_per_ip_per_sec {
-                if self.config.tarpit_enabled {
-                    debug!(client_ip = %ip, rate = rate, "L4: connection rate exceeded, tarpitting");
-                    self.total_tarpitted.fetch_add(1, Ordering::Relaxed);
-                    return L4Action::Tarpit;
-                } else {
-                    debug!(client_ip = %ip, rate = rate, "L4: connection rate exceeded, dropping");
-                    self.total_dropped.fetch_add(1, Ordering::Relaxed);
-                    return L4Action::Drop;
-                }
-            }
+            return self.refuse(&state, L4Limit::Concurrent, false);
+        }
+        let subnet_concurrent = subnet.concurrent.load(Ordering::Relaxed);
+        let max_subnet = self.config.max_concurrent_per_subnet;
+        if max_subnet > 0 && subnet_concurrent >= max_subnet {
+            let net = self.subnet_of(ip);
+            warn!(client_ip = %ip, subnet = %net, concurrent = subnet_concurrent, "L4: max concurrent connections per subnet exceeded");
+            return self.refuse(&state, L4Limit::SubnetConcurrent(net), false);
+        }
 
-            recent.push(now);
+        // Check connection rates.
+        let rate = state.rate(now);
+        if rate >= self.config.connection_rate_per_ip_per_sec {
+            debug!(client_ip = %ip, rate = rate, "L4: connection rate exceeded");
+            return self.refuse(&state, L4Limit::Rate, true);
+        }
+        let subnet_rate = subnet.rate(now);
+        let max_subnet_rate = self.config.connection_rate_per_subnet_per_sec;
+        if max_subnet_rate > 0 && subnet_rate >= max_subnet_rate {
+            let net = self.subnet_of(ip);
+            debug!(client_ip = %ip, subnet = %net, rate = subnet_rate, "L4: connection rate per subnet exceeded");
+            return self.refuse(&state, L4Limit::SubnetRate(net), true);
         }
 
+        state.record_connect(now);
+        subnet.record_connect(now);
         self.total_allowed.fetch_add(1, Ordering::Relaxed);
         L4Action::Allow
     }
 
+    /// Count a refused connection; rate-limited ones are tarpitted when
+    /// `tarpit_enabled`.
+    fn refuse(&self, state: &IpState, reason: L4Limit, tarpit: bool) -> L4Action {
+        if tarpit && self.config.tarpit_enabled {
+            state.tarpitted.fetch_add(1, Ordering::Relaxed);
+            self.total_tarpitted.fetch_add(1, Ordering::Relaxed);
+            L4Action::Tarpit(reason)
+        } else {
+            state.dropped.fetch_add(1, Ordering::Relaxed);
+            self.total_dropped.fetch_add(1, Ordering::Relaxed);
+            L4Action::Drop(reason)
+        }
+    }
+
     /// Register that a connection from `ip` is now active.
     pub fn register_connection(&self, ip: IpAddr) {
         if let Some(state) = self.ip_states.get(&ip) {
             state.concurrent.fetch_add(1, Ordering::Relaxed);
         }
+        if let Some(subnet) = self.subnet_states.get(&self.subnet_key(ip)) {
+            subnet.concurrent.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     /// Unregister that a connection from `ip` has closed.
     pub fn unregister_connection(&self, ip: IpAddr) {
         if let Some(state) = self.ip_states.get(&ip) {
-            // Use compare_exchange loop to prevent atomic underflow
-            loop {
-                let current = state.concurrent.load(Ordering::Relaxed);
-                if current == 0 {
-                    break;
-                }
-                if state.concurrent.compare_exchange(current, current - 1, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
-                    break;
-                }
-            }
+            state.decrement();
+        }
+        if let Some(subnet) = self.subnet_states.get(&self.subnet_key(ip)) {
+            subnet.decrement();
         }
     }
 
+    fn handshake_failure_window(&self) -> Duration {
+        Duration::from_secs(self.config.handshake_failure_window_secs.max(1))
+    }
+
+    /// Count a failed or timed-out TLS handshake from `ip`. Returns true
+    /// when it reaches `handshake_failure_threshold` and starts the cooldown
+    /// during which [`Self::check_connection`] refuses the IP.
+    pub fn record_handshake_failure(&self, ip: IpAddr, timed_out: bool) -> bool {
+        self.total_handshake_failures.fetch_add(1, Ordering::Relaxed);
+        if timed_out {
+            self.total_handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        let threshold = self.config.handshake_failure_threshold;
+        if threshold == 0 {
+            return false;
+        }
+
+        let state = self.ip_states.entry(ip).or_default();
+        let now = Instant::now();
+        if let Ok(mut failures) = state.handshake_failures.lock() {
+            failures.push(now);
+        }
+        // A few resets now and then are normal; only a burst within the
+        // window counts.
+        if state.handshake_failures(now, self.handshake_failure_window()) < threshold {
+            return false;
+        }
+        if let Ok(mut until) = state.handshake_cooldown_until.lock() {
+            *until = Some(now + Duration::from_secs(self.config.handshake_failure_cooldown_secs));
+        }
+        true
+    }
+
     /// Return the tarpit delay duration from the config.
     pub fn tarpit_delay(&self) -> Duration {
         Duration::from_millis(self.config.tarpit_delay_ms)
     }
 
+    /// Connections allowed so far; cheaper than [`Self::get_metrics`].
+    pub fn total_allowed(&self) -> u64 {
+        self.total_allowed.load(Ordering::Relaxed)
+    }
+
     /// Get a snapshot of L4 metrics.
     pub fn get_metrics(&self) -> L4MetricsSnapshot {
+        let now = Instant::now();
+        let window = self.handshake_failure_window();
+        let (recent_failures, blocked) = self.ip_states.iter().fold((0, 0), |(failures, blocked), entry| {
+            let state = entry.value();
+            (
+                failures + state.handshake_failures(now, window),
+                blocked + state.in_handshake_cooldown(now) as u64,
+            )
+        });
         L4MetricsSnapshot {
             total_allowed: self.total_allowed.load(Ordering::Relaxed),
             total_dropped: self.total_dropped.load(Ordering::Relaxed),
             total_tarpitted: self.total_tarpitted.load(Ordering::Relaxed),
             tracked_ips: self.ip_states.len() as u64,
+            tracked_subnets: self.subnet_states.len() as u64,
+            handshake_failures: self.total_handshake_failures.load(Ordering::Relaxed),
+            handshake_timeouts: self.total_handshake_timeouts.load(Ordering::Relaxed),
+            handshake_failure_rate: recent_failures as f64 / window.as_secs_f64(),
+            handshake_blocked_ips: blocked,
         }
     }
 
-    /// Remove IP entries that have zero concurrent connections and no recent
-    /// activity. Called periodically from the cleanup loop.
-    pub fn cleanup(&self) {
-        let before = self.ip_states.len();
-        self.ip_states.retain(|_ip, state| {
-            let concurrent = state.concurrent.load(Ordering::Relaxed);
-            if concurrent > 0 {
-                return true;
-            }
-            if let Ok(recent) = state.recent_connects.lock() {
-                let cutoff = Instant::now() - Duration::from_secs(60);
-                recent.iter().any(|t| *t > cutoff)
-            } else {
-                false
-            }
-        });
-        let removed = before - self.ip_states.len();
-        if removed > 0 {
-            info!(removed = removed, remaining = self.ip_states.len(), "L4 tracker cleanup");
+    /// The `limit` tracked IPs with the most concurrent connections, or
+    /// with `by_rate` the highest connection rate.
+    pub fn top_ips(&self, by_rate: bool, limit: usize) -> Vec<L4IpStats> {
+        let now = Instant::now();
+        let mut stats: Vec<L4IpStats> = self
+            .ip_states
+            .iter()
+            .map(|entry| {
+                let (ip, state) = entry.pair();
+                L4IpStats {
+                    ip: ip.to_string(),
+                    subnet: self.subnet_of(*ip).to_string(),
+                    concurrent: state.concurrent.load(Ordering::Relaxed),
+                    rate: state.rate(now),
+                    dropped: state.dropped.load(Ordering::Relaxed),
+                    tarpitted: state.tarpitted.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        if by_rate {
+            stats.sort_by(|a, b| b.rate.cmp(&a.rate).then(b.concurrent.cmp(&a.concurrent)));
+        } else {
+            stats.sort_by(|a, b| b.concurrent.cmp(&a.concurrent).then(b.rate.cmp(&a.rate)));
+        }
+        stats.truncate(limit);
+        stats
+    }
+
+    /// Remove IP and subnet entries that have zero concurrent connections
+    /// and no recent activity, visiting about `budget` entries of each map.
+    /// Called periodically from the cleanup loop.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
+        let ips = self.ip_sweep.retain(&self.ip_states, budget, |_ip, state| state.is_active());
+        if ips.removed > 0 {
+            info!(removed = ips.removed, remaining = ips.largest, "L4 tracker cleanup");
+        }
+        let mut stats = ips;
+        stats += self.subnet_sweep.retain(&self.subnet_states, budget, |_subnet, state| state.is_active());
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::defaults;
+
+    #[test]
+    fn test_subnet_limits_apply_across_addresses() {
+        let mut config = defaults::default_l4_protection_config();
+        config.max_concurrent_per_ip = 3;
+        config.max_concurrent_per_subnet = 4;
+        config.connection_rate_per_subnet_per_sec = 6;
+        config.tarpit_enabled = false;
+        let tracker = L4Tracker::new(config, 24, 64);
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+
+        for addr in ["198.51.100.1", "198.51.100.1", "198.51.100.2", "198.51.100.2"] {
+            assert_eq!(tracker.check_connection(ip(addr)), L4Action::Allow);
+            tracker.register_connection(ip(addr));
         }
+        let net: IpNet = "198.51.100.0/24".parse().unwrap();
+        let refused = tracker.check_connection(ip("198.51.100.3"));
+        assert_eq!(refused, L4Action::Drop(L4Limit::SubnetConcurrent(net)));
+        assert_eq!(
+            L4Limit::SubnetConcurrent(net).to_string(),
+            "subnet_connection_limit_exceeded:198.51.100.0/24"
+        );
+        // Other subnets are unaffected
+        assert_eq!(tracker.check_connection(ip("198.51.101.1")), L4Action::Allow);
+
+        // Freed connections make room again, until the subnet's rate is used up
+        tracker.unregister_connection(ip("198.51.100.1"));
+        tracker.unregister_connection(ip("198.51.100.2"));
+        assert_eq!(tracker.check_connection(ip("198.51.100.3")), L4Action::Allow);
+        assert_eq!(tracker.check_connection(ip("198.51.100.4")), L4Action::Allow);
+        assert_eq!(
+            tracker.check_connection(ip("198.51.100.5")),
+            L4Action::Drop(L4Limit::SubnetRate(net))
+        );
+
+        let top = tracker.top_ips(false, 2);
+        assert_eq!(top.len(), 2);
+        assert!(top.iter().all(|s| s.concurrent == 1 && s.subnet == "198.51.100.0/24"));
+        let top = tracker.top_ips(true, 10);
+        assert_eq!(top[0].rate, 2);
+        assert_eq!(top.iter().map(|s| s.dropped).sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_repeated_handshake_failures_start_a_cooldown() {
+        let mut config = defaults::default_l4_protection_config();
+        config.handshake_failure_threshold = 3;
+        config.tarpit_enabled = false;
+        let tracker = L4Tracker::new(config, 24, 64);
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+
+        // Isolated failures stay below the threshold.
+        assert!(!tracker.record_handshake_failure(ip, false));
+        assert!(!tracker.record_handshake_failure(ip, true));
+        assert_eq!(tracker.check_connection(ip), L4Action::Allow);
+
+        assert!(tracker.record_handshake_failure(ip, false));
+        assert_eq!(tracker.check_connection(ip), L4Action::Drop(L4Limit::HandshakeFailures));
+        assert_eq!(L4Limit::HandshakeFailures.to_string(), "handshake_failures");
+        // Other clients are unaffected, and the cooldown survives cleanup.
+        assert_eq!(tracker.check_connection("198.51.100.8".parse().unwrap()), L4Action::Allow);
+        tracker.cleanup(usize::MAX);
+        assert_eq!(tracker.check_connection(ip), L4Action::Drop(L4Limit::HandshakeFailures));
+
+        let metrics = tracker.get_metrics();
+        assert_eq!((metrics.handshake_failures, metrics.handshake_timeouts), (3, 1));
+        assert_eq!(metrics.handshake_blocked_ips, 1);
+        assert!(metrics.handshake_failure_rate > 0.0);
     }
 }
@@ -1,9 +1,16 @@
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
-use tracing::info;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
 use crate::models::request::RequestContext;
+use crate::protection::framing::validate_framing;
+use crate::protection::ip_reputation::ReputationCategory::{self, BruteForce, DDoS, Scanner};
+use crate::storage::sqlite::{ManagedRuleSettingRow, SqliteStore};
+use crate::storage::sweep::{Sweep, SweepStats};
 
 /// A managed rule action.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -21,27 +28,210 @@ pub struct ManagedRuleResult {
     pub rule_id: u32,
 }
 
+/// Managed rules that only rate-limit an endpoint. A custom rate-limit
+/// rule may replace one of these (see `CustomRulesEngine`).
+pub const RATE_LIMIT_RULES: [u32; 4] = [5, 6, 7, 19];
+
+/// Number of managed rules; ids run from 1 to `RULE_COUNT`.
+const RULE_COUNT: u32 = 24;
+
+/// Tunable parameters of a managed rule. Each rule accepts only the
+/// fields set in its [`default_params`]; unset fields use those defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleParams {
+    /// Requests allowed per `window_secs` (per IP, or per UA for rule 17).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_secs: Option<u64>,
+    /// Largest allowed `Content-Length` (rule 8).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Path prefixes the rule applies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+    /// Added to the threat score by scoring rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+impl RuleParams {
+    /// `self` with unset fields taken from `defaults`.
+    fn or(&self, defaults: &RuleParams) -> RuleParams {
+        RuleParams {
+            limit: self.limit.or(defaults.limit),
+            window_secs: self.window_secs.or(defaults.window_secs),
+            max_bytes: self.max_bytes.or(defaults.max_bytes),
+            paths: self.paths.clone().or_else(|| defaults.paths.clone()),
+            score: self.score.or(defaults.score),
+        }
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit.unwrap_or(0)
+    }
+
+    fn window_secs(&self) -> u64 {
+        self.window_secs.unwrap_or(60)
+    }
+
+    fn score(&self) -> f64 {
+        self.score.unwrap_or(0.0)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        self.paths.iter().flatten().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+fn paths(prefixes: &[&str]) -> Option<Vec<String>> {
+    Some(prefixes.iter().map(|p| p.to_string()).collect())
+}
+
+/// Built-in parameters of `rule_id`; empty for rules without any.
+pub fn default_params(rule_id: u32) -> RuleParams {
+    let rate = |limit, prefixes: &[&str]| RuleParams {
+        limit: Some(limit),
+        window_secs: Some(60),
+        paths: paths(prefixes),
+        ..Default::default()
+    };
+    let score = |score| RuleParams { score: Some(score), ..Default::default() };
+    match rule_id {
+        5 => rate(5, &["/login", "/signin", "/auth/login"]),
+        6 => rate(3, &["/register", "/signup"]),
+        7 => rate(2, &["/forgot-password", "/reset-password", "/password/reset"]),
+        8 => RuleParams { max_bytes: Some(10_485_760), ..Default::default() },
+        9 => score(15.0),
+        17 => RuleParams { limit: Some(1000), window_secs: Some(60), score: Some(25.0), ..Default::default() },
+        19 => rate(100, &["/api/"]),
+        23 => score(30.0),
+        _ => RuleParams::default(),
+    }
+}
+
+/// Check `params` against what `rule_id` accepts.
+pub fn validate_params(rule_id: u32, params: &RuleParams) -> Result<(), String> {
+    let accepted = default_params(rule_id);
+    let unsupported = [
+        ("limit", params.limit.is_some() && accepted.limit.is_none()),
+        ("window_secs", params.window_secs.is_some() && accepted.window_secs.is_none()),
+        ("max_bytes", params.max_bytes.is_some() && accepted.max_bytes.is_none()),
+        ("paths", params.paths.is_some() && accepted.paths.is_none()),
+        ("score", params.score.is_some() && accepted.score.is_none()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, bad)| *bad) {
+        return Err(format!("Rule {} has no parameter '{}'", rule_id, name));
+    }
+    if params.limit == Some(0) {
+        return Err("limit must be at least 1".to_string());
+    }
+    if params.window_secs.is_some_and(|w| !(1..=86_400).contains(&w)) {
+        return Err("window_secs must be between 1 and 86400".to_string());
+    }
+    if params.max_bytes == Some(0) {
+        return Err("max_bytes must be at least 1".to_string());
+    }
+    if let Some(paths) = &params.paths {
+        if paths.is_empty() || paths.iter().any(|p| !p.starts_with('/')) {
+            return Err("paths must be a non-empty list of paths starting with '/'".to_string());
+        }
+    }
+    if params.score.is_some_and(|s| !(0.0..=100.0).contains(&s)) {
+        return Err("score must be between 0 and 100".to_string());
+    }
+    Ok(())
+}
+
+/// Reputation category declared by a managed rule.
+pub fn rule_category(rule_id: u32) -> Option<ReputationCategory> {
+    RULE_INFO.iter().find(|r| r.0 == rule_id).and_then(|r| r.3)
+}
+
+/// A managed rule as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManagedRuleInfo {
+    pub id: u32,
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Category a match adds to the client IP's reputation.
+    pub category: Option<ReputationCategory>,
+    pub enabled: bool,
+    /// Effective parameters, defaults included.
+    pub params: RuleParams,
+}
+
+/// Id, name, description and the reputation category a match tags the
+/// client IP with.
+const RULE_INFO: [(u32, &str, &str, Option<ReputationCategory>); RULE_COUNT as usize] = [
+    (1, "path_traversal", "Block path traversal attempts (../)", Some(Scanner)),
+    (2, "sensitive_files", "Block access to sensitive files (.env, .git, wp-admin)", Some(Scanner)),
+    (3, "backup_files", "Block access to backup files (.bak, .sql, .old)", Some(Scanner)),
+    (4, "hidden_files", "Block access to hidden files (except .well-known)", Some(Scanner)),
+    (5, "login_rate_limit", "Rate limit login attempts per IP", Some(BruteForce)),
+    (6, "registration_limit", "Rate limit registrations per IP", Some(BruteForce)),
+    (7, "password_reset_limit", "Rate limit password resets per IP", Some(BruteForce)),
+    (8, "large_payload", "Block payloads over max_bytes", None),
+    (9, "missing_content_type", "Score POST/PUT without Content-Type", None),
+    (10, "empty_ua_post", "Block POST with empty User-Agent", None),
+    (11, "fake_crawler", "Block crawler UAs that fail reverse-DNS verification", None),
+    (12, "unverified_crawler", "Score crawler UAs while their IP is being verified", None),
+    (13, "http_method_restrict", "Block TRACE/TRACK/CONNECT/DEBUG methods", None),
+    (14, "request_smuggling", "Block ambiguous TE / CL framing (smuggling)", None),
+    (15, "host_header_injection", "Block Host header injection", None),
+    (16, "referer_spam", "Block known referer spam domains", None),
+    (17, "connection_flood_ua", "Score same-UA floods", Some(DDoS)),
+    (18, "slow_post", "Slow POST detection (handled by slowloris detector)", None),
+    (19, "api_rate_limit", "API rate limit per IP (disabled by default)", Some(DDoS)),
+    (20, "invalid_method", "Block unknown HTTP methods", None),
+    (21, "body_sqli", "Block SQL injection in request bodies (body inspection)", None),
+    (22, "body_xss", "Block script injection in request bodies (body inspection)", None),
+    (23, "body_null_byte", "Score null bytes in request bodies (body inspection)", None),
+    (24, "body_php_object", "Block serialized PHP objects in request bodies (body inspection)", None),
+];
+
+/// Matched against the lowercased, whitespace-collapsed body (rule 21).
+const SQLI_PATTERNS: &[&str] = &[
+    "union select", "union all select", "' or '1'='1", "' or 1=1", "\" or 1=1",
+    "or 1=1--", "'; drop table", "; drop table", "and sleep(", "or sleep(",
+    "pg_sleep(", "benchmark(", "waitfor delay", "information_schema.", "load_file(",
+    "into outfile", "into dumpfile", "xp_cmdshell", "extractvalue(", "updatexml(",
+];
+
+/// Matched against the lowercased body with all whitespace removed (rule 22).
+const XSS_PATTERNS: &[&str] = &[
+    "<script", "</script", "javascript:", "vbscript:", "<iframe", "<svg/onload",
+    "onerror=", "onload=", "onmouseover=", "onfocus=", "document.cookie",
+];
+
 /// Per-IP rate tracking for endpoint-specific rules.
-struct EndpointRateTracker {
-    /// Map of (IP, path_prefix) -> (count, window_start)
-    counters: DashMap<(String, String), (u32, Instant)>,
+pub(crate) struct EndpointRateTracker {
+    /// Map of (IP, bucket) -> (count, window_start, window)
+    counters: DashMap<(String, String), (u32, Instant, Duration)>,
+    sweep: Sweep,
 }
 
 impl EndpointRateTracker {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             counters: DashMap::new(),
+            sweep: Sweep::new(),
         }
     }
 
-    fn check(&self, ip: &str, path_prefix: &str, limit: u32, window_secs: u64) -> bool {
-        let key = (ip.to_string(), path_prefix.to_string());
+    /// Count a request in the `(ip, bucket)` window; true once it goes
+    /// over `limit`.
+    pub(crate) fn check(&self, ip: &str, bucket: &str, limit: u32, window_secs: u64) -> bool {
+        let key = (ip.to_string(), bucket.to_string());
         let now = Instant::now();
-        let mut entry = self.counters.entry(key).or_insert((0, now));
+        let window = Duration::from_secs(window_secs);
+        let mut entry = self.counters.entry(key).or_insert((0, now, window));
 
-        if now.duration_since(entry.1) > Duration::from_secs(window_secs) {
+        if now.duration_since(entry.1) > window {
             entry.0 = 1;
             entry.1 = now;
+            entry.2 = window;
             false
         } else {
             entry.0 += 1;
@@ -49,53 +239,136 @@ impl EndpointRateTracker {
         }
     }
 
-    fn cleanup(&self) {
+    /// What [`check`](Self::check) would return, without counting the
+    /// request.
+    pub(crate) fn peek(&self, ip: &str, bucket: &str, limit: u32, window_secs: u64) -> bool {
+        let key = (ip.to_string(), bucket.to_string());
+        let window = Duration::from_secs(window_secs);
+        match self.counters.get(&key) {
+            Some(entry) if Instant::now().duration_since(entry.1) <= window => entry.0 + 1 > limit,
+            Some(_) => false,
+            None => 1 > limit,
+        }
+    }
+
+    /// Drop counters whose window has ended, visiting about `budget` of
+    /// them.
+    pub(crate) fn cleanup(&self, budget: usize) -> SweepStats {
         let now = Instant::now();
-        let stale = Duration::from_secs(300);
-        self.counters.retain(|_, (_, start)| now.duration_since(*start) < stale);
+        self.sweep
+            .retain(&self.counters, budget, |_, (_, start, window)| now.duration_since(*start) <= *window)
     }
 }
 
-/// Managed rules engine with 20 pre-built security rules.
+/// Managed rules engine with 24 pre-built security rules. Rules 21-24
+/// look at the request body and only run for services with body
+/// inspection enabled (see [`ManagedRulesEngine::check_body`]).
 pub struct ManagedRulesEngine {
     /// Which rules are enabled (rule_id -> enabled)
     enabled_rules: DashMap<u32, bool>,
+    /// Parameters set through the admin API, without defaults.
+    param_overrides: RwLock<HashMap<u32, RuleParams>>,
+    /// Effective parameters of every rule that has any.
+    params: RwLock<HashMap<u32, RuleParams>>,
     /// Per-endpoint rate tracker
     endpoint_rates: EndpointRateTracker,
-    /// Per-UA flood tracker: UA -> (count, window_start)
-    ua_flood: DashMap<String, (u32, Instant)>,
+    /// Per-UA flood tracker, keyed by (UA, "ua_flood")
+    ua_flood: EndpointRateTracker,
+    /// Rate-limit rules currently replaced by a custom rule
+    overridden: RwLock<HashSet<u32>>,
 }
 
 impl ManagedRulesEngine {
     pub fn new() -> Self {
         let engine = Self {
             enabled_rules: DashMap::new(),
+            param_overrides: RwLock::new(HashMap::new()),
+            params: RwLock::new(HashMap::new()),
             endpoint_rates: EndpointRateTracker::new(),
-            ua_flood: DashMap::new(),
+            ua_flood: EndpointRateTracker::new(),
+            overridden: RwLock::new(HashSet::new()),
         };
+        engine.reset();
+
+        info!("Managed rules engine initialized with {} rules ({} enabled by default)", RULE_COUNT, RULE_COUNT - 1);
+        engine
+    }
 
+    /// Put every rule back to its default enabled flag and parameters.
+    fn reset(&self) {
+        // Same lock order as `update_rule`
+        let mut overrides = self.param_overrides.write();
+        let mut params = self.params.write();
+        overrides.clear();
+        params.clear();
         // Enable all rules by default except api_rate_limit (rule 19)
-        for id in 1..=20 {
-            engine.enabled_rules.insert(id, id != 19);
+        for id in 1..=RULE_COUNT {
+            self.enabled_rules.insert(id, id != 19);
+            let defaults = default_params(id);
+            if defaults != RuleParams::default() {
+                params.insert(id, defaults);
+            }
         }
+    }
 
-        info!("Managed rules engine initialized with 20 rules (19 enabled by default)");
-        engine
+    /// Replace the current settings with the ones in the database, after it
+    /// was changed underneath the engine (as by a state import). Rules
+    /// without a row go back to their defaults.
+    pub async fn reload_from_db(&self, sqlite: &SqliteStore) -> rusqlite::Result<()> {
+        let rows = sqlite.get_managed_rule_settings().await?;
+        self.reset();
+        self.apply_settings(rows);
+        Ok(())
+    }
+
+    /// Apply the enabled flags and parameters saved in the
+    /// `managed_rule_settings` table. Invalid rows are logged and skipped.
+    pub async fn load_from_db(&self, sqlite: &SqliteStore) -> rusqlite::Result<()> {
+        self.apply_settings(sqlite.get_managed_rule_settings().await?);
+        Ok(())
+    }
+
+    fn apply_settings(&self, rows: Vec<ManagedRuleSettingRow>) {
+        for row in rows {
+            let params = match serde_json::from_str::<RuleParams>(&row.params_json) {
+                Ok(params) => params,
+                Err(e) => {
+                    warn!(rule_id = row.rule_id, "Ignoring invalid managed rule parameters: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.update_rule(row.rule_id, Some(row.enabled), Some(params)) {
+                warn!(rule_id = row.rule_id, "Ignoring saved managed rule settings: {}", e);
+            }
+        }
     }
 
     /// Check a request against all enabled managed rules.
     /// Returns None if no rule matched, or Some with the matching rule result.
     pub fn check(&self, ctx: &RequestContext) -> Option<ManagedRuleResult> {
+        self.evaluate(ctx, false)
+    }
+
+    /// What [`check`](Self::check) would return, without counting the
+    /// request towards the per-endpoint and per-UA rate rules.
+    pub fn peek(&self, ctx: &RequestContext) -> Option<ManagedRuleResult> {
+        self.evaluate(ctx, true)
+    }
+
+    fn evaluate(&self, ctx: &RequestContext, dry_run: bool) -> Option<ManagedRuleResult> {
         let path = ctx.path.as_str();
         let method = ctx.method.as_str();
         let ua = ctx.user_agent.as_deref().unwrap_or("");
         let ip_str = ctx.client_ip.to_string();
         let headers = &ctx.headers;
 
-        // Rule 1: Path traversal
+        // Rule 1: Path traversal. `path` has its dot segments resolved
+        // already, in any single encoding; the raw path is still checked
+        // for double-encoded dots, which normalization leaves encoded.
         if self.is_enabled(1) {
-            if path.contains("../") || path.contains("..%2f") || path.contains("..%2F")
-                || path.contains("%2e%2e/") || path.contains("%2e%2e%2f") {
+            let raw = &ctx.raw_path;
+            if ctx.path_traversal || raw.contains("../")
+                || raw.contains("%25") && raw.to_ascii_lowercase().contains("%252e%252e") {
                 return Some(ManagedRuleResult {
                     matched_rule: Some("path_traversal".to_string()),
                     action: RuleAction::Block,
@@ -149,11 +422,11 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 5: Login rate limit (5 per minute per IP)
+        // Rule 5: Login rate limit (default 5 per minute per IP)
         if self.is_enabled(5) {
-            if (path.starts_with("/login") || path.starts_with("/signin") || path == "/auth/login")
-                && (method == "POST" || method == "GET") {
-                if self.endpoint_rates.check(&ip_str, "/login", 5, 60) {
+            let p = self.param(5);
+            if p.matches_path(path) && (method == "POST" || method == "GET") {
+                if self.over_endpoint_limit(dry_run, &ip_str, "/login", p.limit(), p.window_secs()) {
                     return Some(ManagedRuleResult {
                         matched_rule: Some("login_rate_limit".to_string()),
                         action: RuleAction::Challenge,
@@ -163,10 +436,11 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 6: Registration rate limit (3 per minute per IP)
+        // Rule 6: Registration rate limit (default 3 per minute per IP)
         if self.is_enabled(6) {
-            if (path.starts_with("/register") || path.starts_with("/signup")) && method == "POST" {
-                if self.endpoint_rates.check(&ip_str, "/register", 3, 60) {
+            let p = self.param(6);
+            if p.matches_path(path) && method == "POST" {
+                if self.over_endpoint_limit(dry_run, &ip_str, "/register", p.limit(), p.window_secs()) {
                     return Some(ManagedRuleResult {
                         matched_rule: Some("registration_limit".to_string()),
                         action: RuleAction::Challenge,
@@ -176,11 +450,11 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 7: Password reset rate limit (2 per minute per IP)
+        // Rule 7: Password reset rate limit (default 2 per minute per IP)
         if self.is_enabled(7) {
-            if (path.starts_with("/forgot-password") || path.starts_with("/reset-password")
-                || path.starts_with("/password/reset")) && method == "POST" {
-                if self.endpoint_rates.check(&ip_str, "/password-reset", 2, 60) {
+            let p = self.param(7);
+            if p.matches_path(path) && method == "POST" {
+                if self.over_endpoint_limit(dry_run, &ip_str, "/password-reset", p.limit(), p.window_secs()) {
                     return Some(ManagedRuleResult {
                         matched_rule: Some("password_reset_limit".to_string()),
                         action: RuleAction::Challenge,
@@ -190,11 +464,11 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 8: Large payload (Content-Length > 10MB)
+        // Rule 8: Large payload (Content-Length > max_bytes, default 10MB)
         if self.is_enabled(8) {
             if let Some(cl) = headers.get("content-length") {
                 if let Ok(size) = cl.parse::<u64>() {
-                    if size > 10_485_760 {
+                    if self.param(8).max_bytes.is_some_and(|max| size > max) {
                         return Some(ManagedRuleResult {
                             matched_rule: Some("large_payload".to_string()),
                             action: RuleAction::Block,
@@ -210,7 +484,7 @@ impl ManagedRulesEngine {
             if (method == "POST" || method == "PUT") && !headers.contains_key("content-type") {
                 return Some(ManagedRuleResult {
                     matched_rule: Some("missing_content_type".to_string()),
-                    action: RuleAction::Score(15.0),
+                    action: RuleAction::Score(self.param(9).score()),
                     rule_id: 9,
                 });
             }
@@ -227,59 +501,8 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 11: Fake Google bot
-        if self.is_enabled(11) {
-            let ua_lower = ua.to_lowercase();
-            if ua_lower.contains("googlebot") || ua_lower.contains("google-inspectiontool") {
-                // Real Googlebot IPs are in 66.249.x.x or 64.233.x.x ranges
-                let ip = ctx.client_ip;
-                let is_google = match ip {
-                    std::net::IpAddr::V4(v4) => {
-                        let octets = v4.octets();
-                        (octets[0] == 66 && octets[1] == 249)
-                            || (octets[0] == 64 && octets[1] == 233)
-                            || (octets[0] == 72 && octets[1] == 14)
-                            || (octets[0] == 209 && octets[1] == 85)
-                            || (octets[0] == 216 && octets[1] == 239)
-                    }
-                    _ => false,
-                };
-                if !is_google {
-                    return Some(ManagedRuleResult {
-                        matched_rule: Some("fake_google_bot".to_string()),
-                        action: RuleAction::Block,
-                        rule_id: 11,
-                    });
-                }
-            }
-        }
-
-        // Rule 12: Fake Bing bot
-        if self.is_enabled(12) {
-            let ua_lower = ua.to_lowercase();
-            if ua_lower.contains("bingbot") || ua_lower.contains("msnbot") {
-                let ip = ctx.client_ip;
-                let is_bing = match ip {
-                    std::net::IpAddr::V4(v4) => {
-                        let octets = v4.octets();
-                        // Microsoft IP ranges: 40.x.x.x, 13.x.x.x, 157.55.x.x, 207.46.x.x
-                        octets[0] == 40 || octets[0] == 13
-                            || (octets[0] == 157 && octets[1] == 55)
-                            || (octets[0] == 207 && octets[1] == 46)
-                            || (octets[0] == 65 && octets[1] == 55)
-                            || (octets[0] == 199 && octets[1] == 30)
-                    }
-                    _ => false,
-                };
-                if !is_bing {
-                    return Some(ManagedRuleResult {
-                        matched_rule: Some("fake_bing_bot".to_string()),
-                        action: RuleAction::Block,
-                        rule_id: 12,
-                    });
-                }
-            }
-        }
+        // Rules 11-12 (fake and unverified crawlers) need the bot
+        // whitelist's verdict and are applied by the pipeline at 2.1.
 
         // Rule 13: HTTP method restrict (TRACE/TRACK/CONNECT/DEBUG)
         if self.is_enabled(13) {
@@ -292,9 +515,11 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 14: Request smuggling (TE + CL together)
+        // Rule 14: Request smuggling (ambiguous framing headers)
         if self.is_enabled(14) {
-            if headers.contains_key("transfer-encoding") && headers.contains_key("content-length") {
+            if (headers.contains_key("transfer-encoding") && headers.contains_key("content-length"))
+                || validate_framing(&ctx.header_map).is_err()
+            {
                 return Some(ManagedRuleResult {
                     matched_rule: Some("request_smuggling".to_string()),
                     action: RuleAction::Block,
@@ -337,31 +562,25 @@ impl ManagedRulesEngine {
             }
         }
 
-        // Rule 17: Connection flood by UA (same UA 1000+ req/min)
+        // Rule 17: Connection flood by UA (default same UA 1000+ req/min)
         if self.is_enabled(17) && !ua.is_empty() {
-            let now = Instant::now();
-            let mut entry = self.ua_flood.entry(ua.to_string()).or_insert((0, now));
-            if now.duration_since(entry.1) > Duration::from_secs(60) {
-                entry.0 = 1;
-                entry.1 = now;
-            } else {
-                entry.0 += 1;
-                if entry.0 > 1000 {
-                    return Some(ManagedRuleResult {
-                        matched_rule: Some("connection_flood_ua".to_string()),
-                        action: RuleAction::Score(25.0),
-                        rule_id: 17,
-                    });
-                }
+            let p = self.param(17);
+            if self.ua_flooding(ua, dry_run, p.limit(), p.window_secs()) {
+                return Some(ManagedRuleResult {
+                    matched_rule: Some("connection_flood_ua".to_string()),
+                    action: RuleAction::Score(p.score()),
+                    rule_id: 17,
+                });
             }
         }
 
         // Rule 18: Slow POST detection is handled by slowloris detector, skip here
 
-        // Rule 19: API rate limit (off by default, configurable)
+        // Rule 19: API rate limit (off by default, 100 per minute per IP)
         if self.is_enabled(19) {
-            if path.starts_with("/api/") {
-                if self.endpoint_rates.check(&ip_str, "/api/", 100, 60) {
+            let p = self.param(19);
+            if p.matches_path(path) {
+                if self.over_endpoint_limit(dry_run, &ip_str, "/api/", p.limit(), p.window_secs()) {
                     return Some(ManagedRuleResult {
                         matched_rule: Some("api_rate_limit".to_string()),
                         action: RuleAction::Block,
@@ -387,58 +606,351 @@ impl ManagedRulesEngine {
         None
     }
 
-    /// Enable or disable a rule.
-    pub fn set_rule_enabled(&self, rule_id: u32, enabled: bool) -> bool {
-        if rule_id >= 1 && rule_id <= 20 {
+    /// Check the inspected request body (`ctx.body`) against the enabled
+    /// body rules. Form bodies are percent-decoded and JSON string escapes
+    /// are resolved before matching.
+    pub fn check_body(&self, ctx: &RequestContext) -> Option<ManagedRuleResult> {
+        let raw = ctx.body.as_deref().filter(|b| !b.is_empty())?;
+        let content_type = ctx.headers.get("content-type").map(|s| s.as_str()).unwrap_or("");
+        let decoded = decode_body(raw, content_type);
+
+        // Rule 24: Serialized PHP objects (case-sensitive, so checked first)
+        if self.is_enabled(24) && has_php_object(&decoded) {
+            return Some(ManagedRuleResult {
+                matched_rule: Some("body_php_object".to_string()),
+                action: RuleAction::Block,
+                rule_id: 24,
+            });
+        }
+
+        let text = normalize_body(&decoded);
+
+        // Rule 21: SQL injection
+        if self.is_enabled(21) && SQLI_PATTERNS.iter().any(|p| text.contains(p)) {
+            return Some(ManagedRuleResult {
+                matched_rule: Some("body_sqli".to_string()),
+                action: RuleAction::Block,
+                rule_id: 21,
+            });
+        }
+
+        // Rule 22: Cross-site scripting (whitespace removed to catch `onerror =`)
+        if self.is_enabled(22) {
+            let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+            if XSS_PATTERNS.iter().any(|p| compact.contains(p)) {
+                return Some(ManagedRuleResult {
+                    matched_rule: Some("body_xss".to_string()),
+                    action: RuleAction::Block,
+                    rule_id: 22,
+                });
+            }
+        }
+
+        // Rule 23: Null bytes
+        if self.is_enabled(23) && (raw.contains(&0) || decoded.contains('\0')) {
+            return Some(ManagedRuleResult {
+                matched_rule: Some("body_null_byte".to_string()),
+                action: RuleAction::Score(self.param(23).score()),
+                rule_id: 23,
+            });
+        }
+
+        None
+    }
+
+    /// Enable or disable a rule and, if `params` is given, replace its
+    /// parameter overrides (unset fields go back to the defaults). Returns
+    /// the settings to persist: the enabled flag and the overrides.
+    pub fn update_rule(
+        &self,
+        rule_id: u32,
+        enabled: Option<bool>,
+        params: Option<RuleParams>,
+    ) -> Result<(bool, RuleParams), String> {
+        if !(1..=RULE_COUNT).contains(&rule_id) {
+            return Err("Invalid rule ID".to_string());
+        }
+        if let Some(params) = &params {
+            validate_params(rule_id, params)?;
+        }
+
+        if let Some(enabled) = enabled {
             self.enabled_rules.insert(rule_id, enabled);
-            info!(rule_id = rule_id, enabled = enabled, "Managed rule toggled");
-            true
-        } else {
-            false
         }
+        let mut overrides = self.param_overrides.write();
+        if let Some(params) = params {
+            let effective = params.or(&default_params(rule_id));
+            if effective != RuleParams::default() {
+                self.params.write().insert(rule_id, effective);
+            }
+            overrides.insert(rule_id, params);
+        }
+        let enabled = self.enabled_rules.get(&rule_id).map(|v| *v).unwrap_or(false);
+        let params = overrides.get(&rule_id).cloned().unwrap_or_default();
+        info!(rule_id = rule_id, enabled = enabled, params = ?params, "Managed rule updated");
+        Ok((enabled, params))
     }
 
-    /// Get all rules with their enabled status.
-    pub fn get_rules(&self) -> Vec<(u32, String, String, bool)> {
-        let rule_info = [
-            (1, "path_traversal", "Block path traversal attempts (../)"),
-            (2, "sensitive_files", "Block access to sensitive files (.env, .git, wp-admin)"),
-            (3, "backup_files", "Block access to backup files (.bak, .sql, .old)"),
-            (4, "hidden_files", "Block access to hidden files (except .well-known)"),
-            (5, "login_rate_limit", "Rate limit login attempts (5/min/IP)"),
-            (6, "registration_limit", "Rate limit registrations (3/min/IP)"),
-            (7, "password_reset_limit", "Rate limit password resets (2/min/IP)"),
-            (8, "large_payload", "Block payloads > 10MB"),
-            (9, "missing_content_type", "Score POST/PUT without Content-Type (+15)"),
-            (10, "empty_ua_post", "Block POST with empty User-Agent"),
-            (11, "fake_google_bot", "Block fake Googlebot (UA spoofing)"),
-            (12, "fake_bing_bot", "Block fake Bingbot (UA spoofing)"),
-            (13, "http_method_restrict", "Block TRACE/TRACK/CONNECT/DEBUG methods"),
-            (14, "request_smuggling", "Block TE + CL header combo (smuggling)"),
-            (15, "host_header_injection", "Block Host header injection"),
-            (16, "referer_spam", "Block known referer spam domains"),
-            (17, "connection_flood_ua", "Score same-UA flood (1000+/min, +25)"),
-            (18, "slow_post", "Slow POST detection (handled by slowloris detector)"),
-            (19, "api_rate_limit", "API rate limit (100/min/IP, disabled by default)"),
-            (20, "invalid_method", "Block unknown HTTP methods"),
-        ];
-
-        rule_info.iter().map(|(id, name, desc)| {
-            let enabled = self.enabled_rules.get(id).map(|v| *v).unwrap_or(false);
-            (*id, name.to_string(), desc.to_string(), enabled)
-        }).collect()
+    /// All rules with their enabled status and effective parameters.
+    pub fn get_rules(&self) -> Vec<ManagedRuleInfo> {
+        let params = self.params.read();
+        RULE_INFO
+            .iter()
+            .map(|&(id, name, description, category)| ManagedRuleInfo {
+                id,
+                name,
+                description,
+                category,
+                enabled: self.enabled_rules.get(&id).map(|v| *v).unwrap_or(false),
+                params: params.get(&id).cloned().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Replace the set of rate-limit rules that custom rules override.
+    /// Overridden rules are skipped without changing their enabled flag.
+    pub fn set_overridden(&self, rule_ids: HashSet<u32>) {
+        let mut overridden = self.overridden.write();
+        if *overridden != rule_ids {
+            info!(rules = ?rule_ids, "Managed rate-limit rules overridden by custom rules");
+            *overridden = rule_ids;
+        }
+    }
+
+    /// Whether a custom rule currently replaces this managed rule.
+    pub fn is_overridden(&self, rule_id: u32) -> bool {
+        self.overridden.read().contains(&rule_id)
     }
 
     /// Check if a rule is enabled.
-    fn is_enabled(&self, rule_id: u32) -> bool {
+    fn over_endpoint_limit(&self, dry_run: bool, ip: &str, bucket: &str, limit: u32, window_secs: u64) -> bool {
+        if dry_run {
+            self.endpoint_rates.peek(ip, bucket, limit, window_secs)
+        } else {
+            self.endpoint_rates.check(ip, bucket, limit, window_secs)
+        }
+    }
+
+    /// Count a request from `ua`; true once it is over `limit` in the
+    /// window.
+    fn ua_flooding(&self, ua: &str, dry_run: bool, limit: u32, window_secs: u64) -> bool {
+        if dry_run {
+            self.ua_flood.peek(ua, "ua_flood", limit, window_secs)
+        } else {
+            self.ua_flood.check(ua, "ua_flood", limit, window_secs)
+        }
+    }
+
+    /// Effective parameters of `rule_id`.
+    fn param(&self, rule_id: u32) -> RuleParams {
+        self.params.read().get(&rule_id).cloned().unwrap_or_default()
+    }
+
+    /// Whether `rule_id` is on and not replaced by a custom rule.
+    pub fn is_enabled(&self, rule_id: u32) -> bool {
         self.enabled_rules.get(&rule_id).map(|v| *v).unwrap_or(false)
+            && !self.is_overridden(rule_id)
     }
 
-    /// Cleanup stale rate tracking data.
-    pub fn cleanup(&self) {
-        self.endpoint_rates.cleanup();
-        let now = Instant::now();
-        let stale = Duration::from_secs(120);
-        self.ua_flood.retain(|_, (_, start)| now.duration_since(*start) < stale);
+    /// Cleanup stale rate tracking data, visiting about `budget` entries
+    /// of each tracker.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
+        let mut stats = self.endpoint_rates.cleanup(budget);
+        stats += self.ua_flood.cleanup(budget);
+        stats
+    }
+}
+
+/// Body text as the application will see it: percent-decoded for forms,
+/// with `\uXXXX` and `\/` escapes resolved for JSON.
+fn decode_body(raw: &[u8], content_type: &str) -> String {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if media_type == "application/x-www-form-urlencoded" {
+        return String::from_utf8_lossy(&percent_decode(raw)).into_owned();
+    }
+    let text = String::from_utf8_lossy(raw);
+    if media_type.ends_with("json") {
+        return unescape_json(&text);
+    }
+    text.into_owned()
+}
+
+pub(crate) fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < input.len() => {
+                let hex = std::str::from_utf8(&input[i + 1..i + 3]).ok();
+                if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+                out.push(b'%');
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+fn unescape_json(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('u') => {
+                chars.next();
+                let hex: String = chars.by_ref().take(4).collect();
+                match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    Some(ch) => out.push(ch),
+                    None => {
+                        out.push_str("\\u");
+                        out.push_str(&hex);
+                    }
+                }
+            }
+            Some('/') => {
+                chars.next();
+                out.push('/');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Lowercase and turn SQL comments and whitespace runs into single spaces.
+fn normalize_body(text: &str) -> String {
+    let lower = text.to_lowercase().replace("/**/", " ");
+    let mut out = String::with_capacity(lower.len());
+    let mut last_space = false;
+    for c in lower.chars() {
+        if c.is_whitespace() {
+            if !last_space {
+                out.push(' ');
+            }
+            last_space = true;
+        } else {
+            out.push(c);
+            last_space = false;
+        }
+    }
+    out
+}
+
+/// Whether `text` contains a PHP `serialize()` object, `O:<len>:"` or
+/// `C:<len>:"`.
+fn has_php_object(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    bytes.windows(2).enumerate().any(|(i, w)| {
+        if !matches!(w, b"O:" | b"C:") || (i > 0 && bytes[i - 1].is_ascii_alphanumeric()) {
+            return false;
+        }
+        let rest = &bytes[i + 2..];
+        let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+        digits > 0 && rest[digits..].starts_with(b":\"")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::tests::TempDb;
+
+    fn post(content_type: &str, body: &str) -> RequestContext {
+        let mut ctx = RequestContext::new(
+            "203.0.113.7".parse().unwrap(),
+            "POST".to_string(),
+            "/comments".to_string(),
+            "example.com".to_string(),
+        );
+        ctx.headers.insert("content-type".to_string(), content_type.to_string());
+        ctx.body = Some(bytes::Bytes::from(body.to_string()));
+        ctx
+    }
+
+    fn rule(ctx: &RequestContext) -> Option<u32> {
+        ManagedRulesEngine::new().check_body(ctx).map(|r| r.rule_id)
+    }
+
+    #[test]
+    fn test_encoded_paths_match_path_rules() {
+        let engine = ManagedRulesEngine::new();
+        let rule = |raw: &str| {
+            let ctx = RequestContext::from_raw("203.0.113.7".parse().unwrap(), "GET".to_string(), raw, "example.com")
+                .unwrap();
+            engine.check(&ctx).map(|r| r.rule_id)
+        };
+        for raw in ["/%77p-admin", "//wp-admin", "/wp-admin/.", "/\\wp-admin", "/%2e/wp-login.php"] {
+            assert_eq!(rule(raw), Some(2), "{raw}");
+        }
+        for raw in ["/static/..%2f..%2fetc/passwd", "/a/%2e%2e/b", "/a\\..\\b", "/a/%252e%252e/b"] {
+            assert_eq!(rule(raw), Some(1), "{raw}");
+        }
+        assert_eq!(rule("/%2egit/config"), Some(2));
+        assert_eq!(rule("/blog/2024/post"), None);
+    }
+
+    #[test]
+    fn test_body_rules() {
+        let form = "application/x-www-form-urlencoded";
+        assert_eq!(rule(&post(form, "id=1%27+UNION/**/SELECT+password+FROM+users")), Some(21));
+        assert_eq!(rule(&post(form, "comment=%3Cimg+src%3Dx+onerror+%3D+alert(1)%3E")), Some(22));
+        assert_eq!(rule(&post(form, "file=report.pdf%00.php")), Some(23));
+        assert_eq!(rule(&post(form, r#"data=O:8:"stdClass":0:{}"#)), Some(24));
+        assert_eq!(
+            rule(&post("application/json", r#"{"bio":"\u003cscript\u003ealert(1)"}"#)),
+            Some(22)
+        );
+
+        // Ordinary prose that mentions the keywords
+        assert_eq!(rule(&post(form, "q=how+to+select+a+union+rep&note=TODO:+fix")), None);
+        assert_eq!(rule(&post("application/json", r#"{"name":"O'Brien","ratio":"1:2"}"#)), None);
+    }
+
+    #[tokio::test]
+    async fn test_rule_parameters_are_validated_applied_and_restored() {
+        let db = TempDb::new("managed");
+        let sqlite = SqliteStore::new(db.path()).unwrap();
+        let engine = ManagedRulesEngine::new();
+
+        let mut upload = post("application/octet-stream", "");
+        upload.headers.insert("content-length".to_string(), "2048".to_string());
+        upload.user_agent = Some("curl/8.5.0".to_string());
+        assert!(engine.check(&upload).is_none());
+
+        let small = RuleParams { max_bytes: Some(1024), ..Default::default() };
+        let (enabled, saved) = engine.update_rule(8, None, Some(small.clone())).unwrap();
+        assert!(enabled);
+        assert_eq!(saved, small);
+        assert_eq!(engine.check(&upload).map(|r| r.rule_id), Some(8));
+
+        // Parameters a rule doesn't have, and out-of-range values, are rejected.
+        assert!(engine.update_rule(8, None, Some(RuleParams { limit: Some(5), ..Default::default() })).is_err());
+        assert!(engine.update_rule(5, None, Some(RuleParams { limit: Some(0), ..Default::default() })).is_err());
+        assert!(engine.update_rule(5, None, Some(RuleParams { paths: Some(vec![]), ..Default::default() })).is_err());
+
+        // Unset fields keep their defaults.
+        let login = RuleParams { paths: Some(vec!["/account/login".to_string()]), ..Default::default() };
+        let (_, login) = engine.update_rule(5, None, Some(login)).unwrap();
+        let rule5 = engine.get_rules().into_iter().find(|r| r.id == 5).unwrap();
+        assert_eq!(rule5.params.limit, Some(5));
+        assert_eq!(rule5.params.paths, login.paths);
+
+        // Saved settings survive a restart.
+        sqlite.set_managed_rule_setting(8, true, &serde_json::to_string(&saved).unwrap()).await.unwrap();
+        sqlite.set_managed_rule_setting(19, true, "{}").await.unwrap();
+        let restarted = ManagedRulesEngine::new();
+        restarted.load_from_db(&sqlite).await.unwrap();
+        assert!(restarted.is_enabled(19));
+        assert_eq!(restarted.check(&upload).map(|r| r.rule_id), Some(8));
     }
 }
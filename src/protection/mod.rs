@@ -13,7 +13,14 @@ pub mod escalation;
 pub mod l4_tracker;
 pub mod bot_whitelist;
 pub mod ip_reputation;
+pub mod reputation_feeds;
 pub mod auto_ban;
+pub mod firewall;
 pub mod distributed;
 pub mod managed_rules;
 pub mod custom_rules;
+pub mod trace;
+pub mod api_tokens;
+pub mod framing;
+pub mod credential_stuffing;
+pub mod ip_policies;
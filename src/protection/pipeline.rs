@@ -1,29 +1,36 @@
 use std::net::IpAddr;
 use std::sync::Arc;
-use tracing::{debug, info, warn};
+use tracing::{debug, info, warn, Level};
 
+use crate::analytics::collector::Bypass;
+use crate::analytics::events::{EventBus, EventType, SecurityEvent};
 use crate::config::service::ServiceConfig;
 use crate::config::settings::Settings;
 use crate::models::request::RequestContext;
 use crate::models::threat::{ThreatAction, ProtectionLevel, ThreatReason};
 use crate::storage::blocklist::BlocklistManager;
-use crate::storage::memory::MemoryStore;
+use crate::storage::memory::{ip_to_subnet, MemoryStore, SubnetKey};
 
+use super::api_tokens::{ApiTokenStore, TokenAction, TokenGrant};
+use super::ip_policies::{IpPolicy, IpPolicyStore};
 use super::auto_ban::AutoBanManager;
 use super::behavioral::BehavioralAnalyzer;
-use super::challenge::ChallengeSystem;
+use super::challenge::{ChallengeSystem, ClearanceScope};
+use super::credential_stuffing::CredentialStuffingDetector;
 use super::distributed::DistributedDetector;
 use super::escalation::EscalationEngine;
-use super::custom_rules::CustomRulesEngine;
-use super::managed_rules::{ManagedRulesEngine, RuleAction};
+use super::custom_rules::{CustomAction, CustomRulesEngine};
+use super::managed_rules::{rule_category, ManagedRulesEngine, RuleAction};
 use super::fingerprint::FingerprintAnalyzer;
 use super::geoip::GeoIpLookup;
 use super::header_analysis::HeaderAnalyzer;
-use super::ip_reputation::IpReputationManager;
+use super::ip_reputation::{IpReputationManager, ReputationCategory};
 use super::mobile_proxy::MobileProxyDetector;
 use super::asn::AsnClassifier;
-use super::bot_whitelist::BotWhitelist;
+use super::bot_whitelist::{BotVerdict, BotWhitelist};
 use super::rate_limiter::RateLimiter;
+use super::slowloris::SlowlorisDetector;
+use super::trace::PipelineTrace;
 
 /// The main protection pipeline that chains all detection layers together.
 /// Each layer can short-circuit the pipeline with a Block or Challenge action.
@@ -45,6 +52,11 @@ pub struct ProtectionPipeline {
     pub distributed: Arc<DistributedDetector>,
     pub managed_rules: Arc<ManagedRulesEngine>,
     pub custom_rules: Arc<CustomRulesEngine>,
+    pub slowloris: Arc<SlowlorisDetector>,
+    pub api_tokens: Arc<ApiTokenStore>,
+    pub ip_policies: Arc<IpPolicyStore>,
+    pub credential_stuffing: Arc<CredentialStuffingDetector>,
+    pub events: Arc<EventBus>,
 }
 
 /// Result of running a request through the full protection pipeline.
@@ -53,6 +65,8 @@ pub struct PipelineResult {
     pub reason: Option<ThreatReason>,
     pub score: f64,
     pub challenge_html: Option<String>,
+    /// Set when the request passed without going through every layer.
+    pub bypass: Option<Bypass>,
 }
 
 impl PipelineResult {
@@ -62,15 +76,33 @@ impl PipelineResult {
             reason: None,
             score: 0.0,
             challenge_html: None,
+            bypass: None,
         }
     }
 
-    fn block(reason: ThreatReason, score: f64) -> Self {
+    fn bypassed(bypass: Bypass) -> Self {
+        Self { bypass: Some(bypass), ..Self::allow() }
+    }
+
+    pub fn block(reason: ThreatReason, score: f64) -> Self {
         Self {
             action: ThreatAction::Block,
             reason: Some(reason),
             score,
             challenge_html: None,
+            bypass: None,
+        }
+    }
+
+    fn tarpit(reason: ThreatReason, score: f64) -> Self {
+        Self { action: ThreatAction::Tarpit, ..Self::block(reason, score) }
+    }
+
+    /// Block or tarpit a request matching a blocklist entry with `action`.
+    fn listed(action: ThreatAction, reason: ThreatReason) -> Self {
+        match action {
+            ThreatAction::Tarpit => Self::tarpit(reason, 100.0),
+            _ => Self::block(reason, 100.0),
         }
     }
 
@@ -80,7 +112,51 @@ impl PipelineResult {
             reason: Some(reason),
             score,
             challenge_html: Some(html),
+            bypass: None,
+        }
+    }
+}
+
+/// Per-run state threaded through the layers.
+struct Run<'t> {
+    /// Leave every counter and profile untouched (see
+    /// [`ProtectionPipeline::evaluate`]).
+    dry_run: bool,
+    trace: Option<&'t mut PipelineTrace>,
+    /// Name of the managed rule that matched, for the security event.
+    managed_rule: Option<String>,
+    /// Category declared by the custom or managed rule that matched.
+    category: Option<ReputationCategory>,
+}
+
+impl Run<'_> {
+    /// Record a stage that let the request carry on. `detail` is only
+    /// built when tracing.
+    fn note(&mut self, layer: &'static str, stage: &'static str, score: f64, detail: impl FnOnce() -> String) {
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.push(layer, stage, score, None, detail());
+        }
+    }
+
+    /// Record the stage that ended the pipeline and hand back its result.
+    fn decide(
+        &mut self,
+        layer: &'static str,
+        stage: &'static str,
+        score: f64,
+        result: PipelineResult,
+        detail: impl FnOnce() -> String,
+    ) -> PipelineResult {
+        if let Some(trace) = self.trace.as_deref_mut() {
+            let decision = match result.action {
+                ThreatAction::Pass => "allow",
+                ThreatAction::Challenge => "challenge",
+                ThreatAction::Block => "block",
+                ThreatAction::Tarpit => "tarpit",
+            };
+            trace.push(layer, stage, score, Some(decision), detail());
         }
+        result
     }
 }
 
@@ -89,16 +165,27 @@ impl ProtectionPipeline {
     ///
     /// Layer order:
     /// 0.0  IP/Subnet whitelist (bypass all checks)
+    /// 0.05 IP policy (never/always challenge, rate-limit bypass)
+    /// 0.1  API token (bypass the pipeline, or challenges only)
     /// 1.0  Blocklist check (IP, ASN, country)
+    /// 1.1  JA3 blocklist (block/challenge; allowlisted JA3s are never challenged)
     /// 1.5  Auto-Ban check
+    /// 1.52 Clearance cookie check (cleared clients take the fast path
+    ///      after 2.0)
+    /// 1.55 GeoIP enrichment (country, ASN)
+    /// 1.57 Country/ASN allowlist
     /// 1.6  Custom rules
     /// 1.8  Managed rules (pre-built security rules)
-    /// 2.0  GeoIP lookup + country score
+    /// 1.9  Credential stuffing (login attempts per username)
+    /// 2.0  Country/ASN blocklist + country score
+    /// 2.005 Distributed attack mitigation of the hot path
+    /// 2.01 Cleared fast path: rate limits and behavioral profile only
     /// 2.05 Static asset bypass
-    /// 2.1  Bot whitelist
+    /// 2.1  Bot whitelist + fake/unverified crawler rules (11-12)
     /// 2.2  IP Reputation scoring
     /// 2.5  Sliding windows feed
-    /// 3.0  Rate limiting (challenge at L0-L2, block at L3-L4)
+    /// 3.0  Rate limiting (challenge at L0-L2, block at L3-L4 or for
+    ///      never-challenge IPs)
     /// 3.2  Distributed attack detection
     /// 3.5  ASN reputation
     /// 4.0  Fingerprint (JA3)
@@ -106,8 +193,49 @@ impl ProtectionPipeline {
     /// 6.0  Mobile proxy detection
     /// 7.0  Behavioral scoring
     /// 8.0  Challenge gate (escalation-aware)
-    /// 9.0  Clearance cookie check
+    /// 9.0  Clearance cookie check (for challenges raised before 2.01)
+    ///
+    /// The decision is fed back into IP reputation and auto-ban (see
+    /// [`record_outcome`](Self::record_outcome)). With debug logging
+    /// enabled, each decision is logged with the trace of the stages that
+    /// led to it.
     pub fn process(&self, ctx: &mut RequestContext, settings: &Settings, service: Option<&ServiceConfig>) -> PipelineResult {
+        let traced = tracing::enabled!(Level::DEBUG);
+        let mut trace = PipelineTrace::default();
+        let mut run = Run { dry_run: false, trace: traced.then_some(&mut trace), managed_rule: None, category: None };
+        let result = self.run(ctx, settings, service, &mut run);
+        self.record_outcome(&ctx.client_ip, &result, run.category);
+        self.publish_decision(ctx, service, &result, run.managed_rule.as_deref());
+        if !traced {
+            return result;
+        }
+        debug!(
+            ip = %ctx.client_ip,
+            action = %result.action,
+            score = result.score,
+            trace = %trace,
+            "Pipeline decision"
+        );
+        result
+    }
+
+    /// Dry run of [`process`](Self::process): nothing is recorded, so
+    /// rate-limit windows, behavioral profiles, rule counters, reputation
+    /// and challenge counts are left as they were, and no challenge page is
+    /// rendered. Rate limits are therefore judged on the traffic seen so
+    /// far, not counting this request.
+    pub fn evaluate(
+        &self,
+        ctx: &mut RequestContext,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+    ) -> (PipelineResult, PipelineTrace) {
+        let mut trace = PipelineTrace::default();
+        let result = self.run(ctx, settings, service, &mut Run { dry_run: true, trace: Some(&mut trace), managed_rule: None, category: None });
+        (result, trace)
+    }
+
+    fn run(&self, ctx: &mut RequestContext, settings: &Settings, service: Option<&ServiceConfig>, run: &mut Run<'_>) -> PipelineResult {
         let mut cumulative_score: f64 = 0.0;
 
         // ----------------------------------------------------------------
@@ -115,15 +243,68 @@ impl ProtectionPipeline {
         // ----------------------------------------------------------------
         if Self::is_whitelisted(&ctx.client_ip, settings) {
             debug!(ip = %ctx.client_ip, "Whitelisted IP/subnet - bypassing pipeline");
-            return PipelineResult::allow();
+            return run.decide("0.0", "whitelist", 0.0, PipelineResult::bypassed(Bypass::Whitelisted), String::new);
+        }
+
+        // ----------------------------------------------------------------
+        // Layer 0.05: IP policy, acted on by the rate limits and the
+        // challenge layers
+        // ----------------------------------------------------------------
+        ctx.ip_policy = self.memory.ip_policy(&ctx.client_ip);
+        if let Some(policy) = ctx.ip_policy {
+            run.note("0.05", "ip_policy", 0.0, || policy.as_str().to_string());
+        }
+
+        // ----------------------------------------------------------------
+        // Layer 0.1: API token. A challenge-bypass token makes the request
+        // count as cleared; a pipeline-bypass token skips straight to the
+        // rate limits.
+        // ----------------------------------------------------------------
+        let token = self.api_tokens.verify(ctx, !run.dry_run);
+        if let Some(grant) = &token {
+            match grant.action {
+                TokenAction::BypassPipeline => return self.process_token_bypass(ctx, settings, service, grant, run),
+                TokenAction::BypassChallenge => {
+                    ctx.api_token = Some(grant.id.clone());
+                    run.note("0.1", "api_token", 0.0, || grant.id.clone());
+                }
+            }
         }
 
         // ----------------------------------------------------------------
         // Layer 1.0: Blocklist check (IP, ASN, country)
         // ----------------------------------------------------------------
-        if self.blocklist.check_ip(&ctx.client_ip).is_some() {
-            info!(ip = %ctx.client_ip, "Blocked by IP blocklist");
-            return PipelineResult::block(ThreatReason::BlockedIp, 100.0);
+        if let Some((action, reason)) = self.blocklist.check_ip(&ctx.client_ip) {
+            if action == ThreatAction::Challenge {
+                let level = Self::protection_level(&self.escalation, service);
+                if let Some(result) =
+                    self.challenge_unless_cleared(ctx, service, &level, 100.0, ThreatReason::BlockedIp, run)
+                {
+                    return run.decide("1.0", "ip_blocklist", 0.0, result, || reason);
+                }
+            } else {
+                info!(ip = %ctx.client_ip, action = %action, "Blocked by IP blocklist");
+                let result = PipelineResult::listed(action, ThreatReason::BlockedIp);
+                return run.decide("1.0", "ip_blocklist", 0.0, result, || reason);
+            }
+        }
+
+        // ----------------------------------------------------------------
+        // Layer 1.1: JA3 blocklist
+        // ----------------------------------------------------------------
+        if let Some((action, reason)) = Self::client_ja3(ctx).and_then(|ja3| self.blocklist.check_ja3(ja3)) {
+            if action == ThreatAction::Challenge {
+                let level = Self::protection_level(&self.escalation, service);
+                if let Some(result) =
+                    self.challenge_unless_cleared(ctx, service, &level, 100.0, ThreatReason::BadFingerprint, run)
+                {
+                    return run.decide("1.1", "ja3_blocklist", 0.0, result, || reason);
+                }
+            } else {
+                info!(ip = %ctx.client_ip, reason = %reason, "Blocked by JA3 blocklist");
+                let result = PipelineResult::listed(action, ThreatReason::BadFingerprint);
+                return run.decide("1.1", "ja3_blocklist", 0.0, result, || reason);
+            }
         }
 
         // ----------------------------------------------------------------
@@ -131,42 +312,101 @@ impl ProtectionPipeline {
         // ----------------------------------------------------------------
         if let Some(reason) = self.auto_ban.is_banned(&ctx.client_ip) {
             debug!(ip = %ctx.client_ip, reason = %reason, "Blocked by auto-ban");
-            return PipelineResult::block(ThreatReason::AutoBanned, 100.0);
+            return run.decide("1.5", "auto_ban", 0.0, PipelineResult::block(ThreatReason::AutoBanned, 100.0), || reason);
+        }
+
+        // ----------------------------------------------------------------
+        // Layer 1.52: Clearance cookie. Rules and the country/ASN lists
+        // below still apply; the scoring layers after 2.0 are skipped.
+        // ----------------------------------------------------------------
+        let cleared = token.is_some() || self.has_clearance(ctx, service);
+        if cleared {
+            run.note("1.52", "clearance", 0.0, String::new);
+        }
+
+        // ----------------------------------------------------------------
+        // Layer 1.55: GeoIP enrichment (custom rules match on country/ASN)
+        // ----------------------------------------------------------------
+        self.enrich_geo(ctx);
+
+        // ----------------------------------------------------------------
+        // Layer 1.57: Country / ASN allowlist ("block everything except")
+        // ----------------------------------------------------------------
+        if let Some(action) = self.allowlist_action(ctx, settings, service) {
+            match action {
+                "allow" => run.note("1.57", "allowlist", 0.0, || "allowed".to_string()),
+                "challenge" => {
+                    let level = Self::protection_level(&self.escalation, service);
+                    if let Some(result) =
+                        self.challenge_unless_cleared(ctx, service, &level, 100.0, ThreatReason::NotAllowlisted, run)
+                    {
+                        return run.decide("1.57", "allowlist", 0.0, result, || "not allowlisted".to_string());
+                    }
+                }
+                _ => {
+                    info!(ip = %ctx.client_ip, country = ?ctx.country_code, asn = ?ctx.asn,
+                          "Blocked by country/ASN allowlist");
+                    let result = PipelineResult::block(ThreatReason::NotAllowlisted, 100.0);
+                    return run.decide("1.57", "allowlist", 0.0, result, || "not allowlisted".to_string());
+                }
+            }
         }
 
         // ----------------------------------------------------------------
         // Layer 1.6: Custom rules (user-defined rules from admin panel)
         // ----------------------------------------------------------------
-        if let Some((action, reason_str)) = self.custom_rules.check(ctx) {
+        let custom = if run.dry_run {
+            self.custom_rules.peek(ctx)
+        } else {
+            self.custom_rules.check(ctx)
+        };
+        if let Some((action, reason_str, category)) = custom {
+            run.category = category;
             match action {
-                ThreatAction::Pass => {
+                CustomAction::Pass => {
                     debug!(ip = %ctx.client_ip, reason = %reason_str, "Custom rule: allowing");
-                    return PipelineResult::allow();
+                    return run.decide("1.6", "custom_rule", 0.0, PipelineResult::allow(), || reason_str);
                 }
-                ThreatAction::Block => {
+                CustomAction::Block => {
                     info!(ip = %ctx.client_ip, reason = %reason_str, "Custom rule: blocking");
-                    return PipelineResult::block(ThreatReason::CustomRule, 100.0);
+                    let result = PipelineResult::block(ThreatReason::CustomRule, 100.0);
+                    return run.decide("1.6", "custom_rule", 0.0, result, || reason_str);
                 }
-                ThreatAction::Challenge => {
+                CustomAction::Challenge => {
                     cumulative_score += 80.0;
                     debug!(ip = %ctx.client_ip, reason = %reason_str, "Custom rule: challenge score added");
+                    run.note("1.6", "custom_rule", 80.0, || reason_str);
                 }
-                ThreatAction::Tarpit => {
+                CustomAction::Score(s) => {
+                    cumulative_score += s;
+                    debug!(ip = %ctx.client_ip, reason = %reason_str, score = s, "Custom rule: score added");
+                    run.note("1.6", "custom_rule", s, || reason_str);
+                }
+                CustomAction::Tarpit => {
                     info!(ip = %ctx.client_ip, reason = %reason_str, "Custom rule: tarpitting");
-                    return PipelineResult {
-                        action: ThreatAction::Tarpit,
-                        reason: Some(ThreatReason::CustomRule),
-                        score: 100.0,
-                        challenge_html: None,
-                    };
+                    let result = PipelineResult::tarpit(ThreatReason::CustomRule, 100.0);
+                    return run.decide("1.6", "custom_rule", 0.0, result, || reason_str);
                 }
             }
         }
 
         // ----------------------------------------------------------------
-        // Layer 1.8: Managed rules (pre-built security rules)
+        // Layer 1.8: Managed rules (pre-built security rules), then the
+        // body rules when the handler read the body for inspection
         // ----------------------------------------------------------------
-        if let Some(rule_result) = self.managed_rules.check(ctx) {
+        let rule_result = if run.dry_run {
+            self.managed_rules.peek(ctx)
+        } else {
+            self.managed_rules.check(ctx)
+        };
+        let rule_result = rule_result.or_else(|| self.managed_rules.check_body(ctx));
+        if let Some(rule_result) = rule_result {
+            run.managed_rule = rule_result.matched_rule.clone();
+            run.category = run.category.or(rule_category(rule_result.rule_id));
+            let detail = || {
+                let name = rule_result.matched_rule.as_deref().unwrap_or("unnamed");
+                format!("rule {} {}", rule_result.rule_id, name)
+            };
             match rule_result.action {
                 RuleAction::Block => {
                     info!(
@@ -175,7 +415,7 @@ impl ProtectionPipeline {
                         rule_id = rule_result.rule_id,
                         "Blocked by managed rule"
                     );
-                    return PipelineResult::block(ThreatReason::ManagedRule, 100.0);
+                    return run.decide("1.8", "managed_rule", 0.0, PipelineResult::block(ThreatReason::ManagedRule, 100.0), detail);
                 }
                 RuleAction::Challenge => {
                     // Add high score to trigger challenge later
@@ -185,6 +425,7 @@ impl ProtectionPipeline {
                         rule = ?rule_result.matched_rule,
                         "Managed rule: challenge score added"
                     );
+                    run.note("1.8", "managed_rule", 80.0, detail);
                 }
                 RuleAction::Score(s) => {
                     cumulative_score += s;
@@ -194,50 +435,106 @@ impl ProtectionPipeline {
                         score = s,
                         "Managed rule: score added"
                     );
+                    run.note("1.8", "managed_rule", s, detail);
                 }
             }
         }
 
         // ----------------------------------------------------------------
-        // Layer 2.0: GeoIP lookup - populate context fields
+        // Layer 1.9: Credential stuffing. Every attempt on a username under
+        // attack is stopped, whichever IP it comes from.
         // ----------------------------------------------------------------
-        // Only do GeoIP lookup if country_code isn't already set (e.g. from CF-IPCountry header)
-        if ctx.country_code.is_none() {
-            if let Some(country) = self.geoip.lookup_country(ctx.client_ip) {
-                ctx.country_code = Some(country.clone());
+        if let Some(username) = ctx.login_user {
+            let config = &settings.protection.credential_stuffing;
+            let hit = if run.dry_run {
+                self.credential_stuffing.peek(&username, config)
+            } else {
+                self.credential_stuffing.check(&username, ctx.client_ip, config)
+            };
+            if let Some(hit) = hit {
+                let detail = || format!("{} attempts from {} IPs", hit.attempts, hit.ips);
+                if config.action == "block" {
+                    info!(ip = %ctx.client_ip, attempts = hit.attempts, ips = hit.ips, "Blocked credential stuffing attempt");
+                    let result = PipelineResult::block(ThreatReason::CredentialStuffing, 100.0);
+                    return run.decide("1.9", "credential_stuffing", 0.0, result, detail);
+                }
+                let level = Self::protection_level(&self.escalation, service);
+                if let Some(result) =
+                    self.challenge_unless_cleared(ctx, service, &level, 100.0, ThreatReason::CredentialStuffing, run)
+                {
+                    info!(ip = %ctx.client_ip, attempts = hit.attempts, ips = hit.ips, "Challenged credential stuffing attempt");
+                    return run.decide("1.9", "credential_stuffing", 0.0, result, detail);
+                }
             }
         }
+
+        // ----------------------------------------------------------------
+        // Layer 2.0: Country / ASN blocklist (context populated at 1.55)
+        // ----------------------------------------------------------------
         // Use whatever country we have now (from CF-IPCountry or GeoIP)
         if let Some(ref country) = ctx.country_code {
             // Check country blocklist after we know the country
-            if let Some((action, _reason)) = self.blocklist.check_country(country) {
-                match action {
-                    crate::storage::blocklist::ThreatAction::Block => {
-                        info!(ip = %ctx.client_ip, country = %country, "Blocked by country blocklist");
-                        return PipelineResult::block(ThreatReason::BlockedCountry, 100.0);
-                    }
-                    crate::storage::blocklist::ThreatAction::Challenge => {
-                        // Score modifier instead of immediate challenge
-                        cumulative_score += settings.blocklist.country_challenge_score;
-                        debug!(ip = %ctx.client_ip, country = %country,
-                               score = settings.blocklist.country_challenge_score,
-                               "Challenged country: adding score modifier");
-                    }
+            if let Some(action) = self.country_action(country, service) {
+                if action == ThreatAction::Challenge {
+                    // Score modifier instead of immediate challenge
+                    let score = settings.blocklist.country_challenge_score;
+                    cumulative_score += score;
+                    debug!(ip = %ctx.client_ip, country = %country, score = score,
+                           "Challenged country: adding score modifier");
+                    run.note("2.0", "country_list", score, || country.clone());
+                } else {
+                    info!(ip = %ctx.client_ip, country = %country, action = %action, "Blocked by country blocklist");
+                    let result = PipelineResult::listed(action, ThreatReason::BlockedCountry);
+                    return run.decide("2.0", "country_list", 0.0, result, || country.clone());
                 }
             }
         }
 
-        if let Some((asn_number, asn_name)) = self.geoip.lookup_asn(ctx.client_ip) {
-            ctx.asn = Some(asn_number);
-            ctx.asn_name = Some(asn_name);
-
+        if let Some(asn_number) = ctx.asn {
             // Check ASN blocklist after we know the ASN
-            if self.blocklist.check_asn(asn_number).is_some() {
-                info!(ip = %ctx.client_ip, asn = asn_number, "Blocked by ASN blocklist");
-                return PipelineResult::block(ThreatReason::BlockedAsn, 100.0);
+            if let Some((action, _)) = self.blocklist.check_asn(asn_number) {
+                let detail = || format!("AS{}", asn_number);
+                if action == ThreatAction::Challenge {
+                    let level = Self::protection_level(&self.escalation, service);
+                    if let Some(result) =
+                        self.challenge_unless_cleared(ctx, service, &level, 100.0, ThreatReason::BlockedAsn, run)
+                    {
+                        return run.decide("2.0", "asn_list", 0.0, result, detail);
+                    }
+                } else {
+                    info!(ip = %ctx.client_ip, asn = asn_number, action = %action, "Blocked by ASN blocklist");
+                    let result = PipelineResult::listed(action, ThreatReason::BlockedAsn);
+                    return run.decide("2.0", "asn_list", 0.0, result, detail);
+                }
+            }
+        }
+
+        // ----------------------------------------------------------------
+        // Layer 2.005: Distributed attack mitigation for the hot path.
+        // Cleared clients pass a challenge mitigation, not a block.
+        // ----------------------------------------------------------------
+        if let Some((id, action)) = self.distributed.mitigation_for(&ctx.method, &ctx.path, run.dry_run) {
+            let detail = || format!("mitigation {}", id);
+            if action == "block" {
+                info!(ip = %ctx.client_ip, path = %ctx.path, mitigation = id, "Blocked by distributed attack mitigation");
+                let result = PipelineResult::block(ThreatReason::DistributedAttack, 100.0);
+                return run.decide("2.005", "distributed_mitigation", 0.0, result, detail);
+            }
+            let level = Self::protection_level(&self.escalation, service);
+            if let Some(result) =
+                self.challenge_unless_cleared(ctx, service, &level, 100.0, ThreatReason::DistributedAttack, run)
+            {
+                return run.decide("2.005", "distributed_mitigation", 0.0, result, detail);
             }
         }
 
+        // ----------------------------------------------------------------
+        // Layer 2.01: Cleared fast path
+        // ----------------------------------------------------------------
+        if cleared {
+            return self.process_cleared(ctx, settings, service, token.as_ref(), run);
+        }
+
         // ----------------------------------------------------------------
         // Layer 2.05: Static asset bypass
         // ----------------------------------------------------------------
@@ -269,19 +566,40 @@ impl ProtectionPipeline {
                 || p.ends_with(".map");
             if is_static && (ctx.method == "GET" || ctx.method == "HEAD") {
                 debug!(ip = %ctx.client_ip, path = %ctx.path, "Static asset - bypassing pipeline");
-                return PipelineResult::allow();
+                return run.decide("2.05", "static_asset", 0.0, PipelineResult::bypassed(Bypass::Static), String::new);
             }
         }
 
         // ----------------------------------------------------------------
-        // Layer 2.1: Bot whitelist check
+        // Layer 2.1: Bot whitelist check, with managed rules 11 (fake
+        // crawler) and 12 (crawler pending verification)
         // ----------------------------------------------------------------
-        if let Some(bot_name) = self.bot_whitelist.check(
-            ctx.user_agent.as_deref(),
-            &ctx.client_ip,
-        ) {
-            debug!(ip = %ctx.client_ip, bot = %bot_name, "Whitelisted search engine bot - allowing");
-            return PipelineResult::allow();
+        let bot = if run.dry_run {
+            self.bot_whitelist.peek(ctx.user_agent.as_deref(), &ctx.client_ip)
+        } else {
+            self.bot_whitelist.check(ctx.user_agent.as_deref(), &ctx.client_ip)
+        };
+        match bot {
+            BotVerdict::NotCrawler => {}
+            BotVerdict::Verified(bot_name) => {
+                debug!(ip = %ctx.client_ip, bot = %bot_name, "Whitelisted search engine bot - allowing");
+                return run.decide("2.1", "bot_whitelist", 0.0, PipelineResult::bypassed(Bypass::Whitelisted), || bot_name);
+            }
+            BotVerdict::Pending(bot_name) => {
+                if self.managed_rules.is_enabled(12) {
+                    let score = settings.bot_whitelist.pending_score;
+                    cumulative_score += score;
+                    debug!(ip = %ctx.client_ip, bot = %bot_name, score = score, "Crawler pending verification: score added");
+                    run.note("2.1", "bot_whitelist", score, || format!("{} pending verification", bot_name));
+                }
+            }
+            BotVerdict::Spoofed(bot_name) => {
+                if self.managed_rules.is_enabled(11) {
+                    info!(ip = %ctx.client_ip, bot = %bot_name, rule_id = 11, "Blocked by managed rule");
+                    let result = PipelineResult::block(ThreatReason::ManagedRule, 100.0);
+                    return run.decide("2.1", "bot_whitelist", 0.0, result, || format!("rule 11 fake {}", bot_name));
+                }
+            }
         }
 
         // ----------------------------------------------------------------
@@ -291,66 +609,81 @@ impl ProtectionPipeline {
             let (rep_score, should_block) = self.ip_reputation.check(&ctx.client_ip);
             if should_block {
                 info!(ip = %ctx.client_ip, score = rep_score, "Blocked by IP reputation");
-                return PipelineResult::block(ThreatReason::BadReputation, rep_score);
+                let result = PipelineResult::block(ThreatReason::BadReputation, rep_score);
+                return run.decide("2.2", "ip_reputation", 0.0, result, String::new);
             }
             if rep_score > 0.0 {
                 cumulative_score += rep_score;
                 debug!(ip = %ctx.client_ip, score = rep_score, "IP reputation score added");
+                run.note("2.2", "ip_reputation", rep_score, String::new);
             }
         }
 
         // ----------------------------------------------------------------
         // Layer 2.5: Feed sliding windows for rate limiting
         // ----------------------------------------------------------------
-        let protection_level = match service.and_then(|s| s.protection_level_override) {
-            Some(0) => ProtectionLevel::L0,
-            Some(1) => ProtectionLevel::L1,
-            Some(2) => ProtectionLevel::L2,
-            Some(3) => ProtectionLevel::L3,
-            Some(4) => ProtectionLevel::L4,
-            _ => self.escalation.current_level(),
-        };
-        let subnet = crate::storage::memory::ip_to_subnet(ctx.client_ip, settings.protection.ipv4_subnet_mask);
+        let protection_level = Self::protection_level(&self.escalation, service);
+        let subnet = Self::subnet_of(ctx, settings);
         let asn = ctx.asn.unwrap_or(0);
         let country = ctx.country_code.as_deref().unwrap_or("XX");
 
-        self.memory.record_request(ctx.client_ip, subnet, asn, country);
+        if !run.dry_run {
+            self.memory.record_request(ctx.client_ip, subnet, asn, country);
+        }
 
         // ----------------------------------------------------------------
         // Layer 3.0: Rate limiting
         // At L0-L2: add high score to trigger challenge (graceful)
         // At L3-L4: hard block (emergency mode)
+        // Never-challenge IPs cannot be challenged, so they are blocked
         // ----------------------------------------------------------------
-        if let Some(reason) = self.rate_limiter.check(
+        if ctx.ip_policy == Some(IpPolicy::BypassRateLimit) {
+            run.note("3.0", "rate_limit", 0.0, || "bypassed by ip policy".to_string());
+        } else if let Some(reason) = self.rate_limiter.check(
             ctx.client_ip,
             subnet,
             asn,
             country,
             &protection_level,
             settings,
+            service,
         ) {
             match protection_level {
                 ProtectionLevel::L3 | ProtectionLevel::L4 => {
                     info!(ip = %ctx.client_ip, reason = ?reason, "Rate limit exceeded (emergency block)");
-                    return PipelineResult::block(reason, 90.0);
+                    return run.decide("3.0", "rate_limit", 0.0, PipelineResult::block(reason, 90.0), || "exceeded".to_string());
+                }
+                _ if ctx.ip_policy == Some(IpPolicy::NeverChallenge) => {
+                    info!(ip = %ctx.client_ip, reason = ?reason, "Rate limit exceeded by never-challenge IP");
+                    let detail = || "exceeded, never_challenge".to_string();
+                    return run.decide("3.0", "rate_limit", 0.0, PipelineResult::block(reason, 90.0), detail);
                 }
                 _ => {
                     // At normal levels, add high score to trigger challenge instead of hard block
                     cumulative_score += 90.0;
                     info!(ip = %ctx.client_ip, reason = ?reason, "Rate limit exceeded (challenge mode)");
+                    run.note("3.0", "rate_limit", 90.0, || "exceeded".to_string());
                 }
             }
+        } else {
+            run.note("3.0", "rate_limit", 0.0, || "within limits".to_string());
         }
 
         // ----------------------------------------------------------------
         // Layer 3.2: Distributed attack detection
         // ----------------------------------------------------------------
         {
-            let dist_result = self.distributed.check(
-                ctx.client_ip,
-                &ctx.path,
-                ctx.user_agent.as_deref(),
-            );
+            let dist_result = if run.dry_run {
+                self.distributed.peek(ctx.client_ip)
+            } else {
+                self.distributed.check(
+                    ctx.client_ip,
+                    &ctx.method,
+                    &ctx.path,
+                    ctx.user_agent.as_deref(),
+                    &settings.protection.distributed_mitigation,
+                )
+            };
             if dist_result.score_modifier > 0.0 {
                 cumulative_score += dist_result.score_modifier;
                 debug!(
@@ -359,6 +692,7 @@ impl ProtectionPipeline {
                     is_new = dist_result.is_new_ip,
                     "Distributed attack score added"
                 );
+                run.note("3.2", "distributed", dist_result.score_modifier, String::new);
             }
         }
 
@@ -371,13 +705,15 @@ impl ProtectionPipeline {
                 cumulative_score += asn_score;
                 debug!(ip = %ctx.client_ip, asn = asn_num, score = asn_score, "ASN reputation score");
             }
+            run.note("3.5", "asn", asn_score, || format!("AS{}", asn_num));
         }
 
         // ----------------------------------------------------------------
         // Layer 4.0: Fingerprint analysis (JA3 vs UA consistency)
         // ----------------------------------------------------------------
         // Skip JA3 fingerprinting for Cloudflare-proxied requests (JA3 would be CF's, not the client's)
-        if !ctx.is_behind_cloudflare {
+        // and for allowlisted fingerprints
+        if !ctx.is_behind_cloudflare && !self.is_ja3_allowed(ctx) {
             let (fp_score, fp_reason) = self.fingerprint.analyze(
                 ctx.ja3_hash.as_deref(),
                 ctx.user_agent.as_deref(),
@@ -387,10 +723,12 @@ impl ProtectionPipeline {
             if let Some(reason) = fp_reason {
                 if fp_score >= 80.0 {
                     warn!(ip = %ctx.client_ip, score = fp_score, "Fingerprint analysis: high threat");
-                    return PipelineResult::block(reason, cumulative_score);
+                    let result = PipelineResult::block(reason, cumulative_score);
+                    return run.decide("4.0", "fingerprint", fp_score, result, || reason.to_string());
                 }
                 debug!(ip = %ctx.client_ip, score = fp_score, reason = ?reason, "Fingerprint anomaly detected");
             }
+            run.note("4.0", "fingerprint", fp_score, || fp_reason.map(|r| r.to_string()).unwrap_or_default());
         }
 
         // ----------------------------------------------------------------
@@ -402,10 +740,12 @@ impl ProtectionPipeline {
         if let Some(reason) = header_reason {
             if header_score >= 80.0 {
                 warn!(ip = %ctx.client_ip, score = header_score, "Header analysis: high threat");
-                return PipelineResult::block(reason, cumulative_score);
+                let result = PipelineResult::block(reason, cumulative_score);
+                return run.decide("5.0", "headers", header_score, result, || reason.to_string());
             }
             debug!(ip = %ctx.client_ip, score = header_score, reason = ?reason, "Header anomaly detected");
         }
+        run.note("5.0", "headers", header_score, || header_reason.map(|r| r.to_string()).unwrap_or_default());
 
         // ----------------------------------------------------------------
         // Layer 6.0: Mobile proxy detection
@@ -416,15 +756,22 @@ impl ProtectionPipeline {
         if is_mobile_proxy {
             debug!(ip = %ctx.client_ip, score = mobile_score, "Mobile proxy detected");
             if mobile_score >= 70.0 {
-                return PipelineResult::block(ThreatReason::MobileProxy, cumulative_score);
+                let result = PipelineResult::block(ThreatReason::MobileProxy, cumulative_score);
+                return run.decide("6.0", "mobile_proxy", mobile_score, result, String::new);
             }
         }
+        run.note("6.0", "mobile_proxy", mobile_score, String::new);
 
         // ----------------------------------------------------------------
         // Layer 7.0: Behavioral scoring
         // ----------------------------------------------------------------
-        let behavioral_score = self.behavioral.analyze(ctx);
+        let behavioral_score = if run.dry_run {
+            self.behavioral.peek(ctx)
+        } else {
+            self.behavioral.analyze(ctx)
+        };
         cumulative_score += behavioral_score * 0.5; // Scale behavioral contribution
+        run.note("7.0", "behavioral", behavioral_score * 0.5, || format!("raw {}", behavioral_score));
 
         debug!(
             ip = %ctx.client_ip,
@@ -437,37 +784,32 @@ impl ProtectionPipeline {
         // Layer 8.0: Escalation-aware challenge gate
         // ----------------------------------------------------------------
         let force_challenge = service.map(|s| s.always_challenge).unwrap_or(false);
-        if force_challenge || self.challenge.should_challenge(ctx, &protection_level, cumulative_score) {
+        let policy_challenge = ctx.ip_policy == Some(IpPolicy::AlwaysChallenge);
+        if force_challenge
+            || policy_challenge
+            || self.challenge.should_challenge(ctx, &protection_level, cumulative_score)
+        {
             // Before issuing a challenge, check if the path is exempt
-            if self.challenge.is_exempt_path(&ctx.path) {
-                debug!(ip = %ctx.client_ip, path = %ctx.path, "Path exempt from challenge");
-            } else {
-                // --------------------------------------------------------
-                // Layer 9.0: Check for valid clearance cookie
-                // --------------------------------------------------------
-                let cookies = ctx.headers.get("cookie").map(|s| s.as_str());
-                if self.challenge.has_valid_clearance(&ctx.client_ip, cookies) {
-                    debug!(ip = %ctx.client_ip, "Valid clearance cookie found, allowing");
-                    return PipelineResult {
-                        action: ThreatAction::Pass,
-                        reason: None,
-                        score: cumulative_score,
-                        challenge_html: None,
-                    };
-                }
-
-                info!(
-                    ip = %ctx.client_ip,
-                    score = cumulative_score,
-                    level = ?protection_level,
-                    "Issuing challenge"
-                );
-                let html = self.challenge.generate_challenge_page(&protection_level);
-                return PipelineResult::challenge(
-                    ThreatReason::ChallengeRequired,
-                    cumulative_score,
-                    html,
-                );
+            // Layer 9.0 (clearance cookie) is checked inside the helper
+            if let Some(result) = self.challenge_unless_cleared(
+                ctx,
+                service,
+                &protection_level,
+                cumulative_score,
+                ThreatReason::ChallengeRequired,
+                run,
+            ) {
+                let detail = || {
+                    if force_challenge {
+                        "always_challenge"
+                    } else if policy_challenge {
+                        "ip policy always_challenge"
+                    } else {
+                        "score over threshold"
+                    }
+                    .to_string()
+                };
+                return run.decide("8.0", "challenge_gate", 0.0, result, detail);
             }
         }
 
@@ -477,42 +819,856 @@ impl ProtectionPipeline {
             reason: None,
             score: cumulative_score,
             challenge_html: None,
+            bypass: None,
         }
     }
 
-    /// Check if the client IP matches any whitelisted IP or subnet.
-    fn is_whitelisted(ip: &IpAddr, settings: &Settings) -> bool {
-        let ip_str = ip.to_string();
+    /// Cheap checks for CORS preflights exempted by
+    /// `protection.exempt_cors_preflight`.
+    ///
+    /// Browsers cannot solve a challenge for a preflight, so only the
+    /// layers that block outright run here: whitelist, blocklist, auto-ban
+    /// and rate limiting. Preflights still feed the rate-limit windows, so
+    /// an `OPTIONS` flood is throttled like any other, unless an IP policy
+    /// exempts the client.
+    pub fn process_preflight(&self, ctx: &mut RequestContext, settings: &Settings, service: Option<&ServiceConfig>) -> PipelineResult {
+        let mut result = self.run_preflight(ctx, settings, service);
+        if result.action == ThreatAction::Pass {
+            result.bypass = Some(Bypass::Preflight);
+        }
+        self.record_outcome(&ctx.client_ip, &result, None);
+        self.publish_decision(ctx, service, &result, None);
+        result
+    }
+
+    fn run_preflight(&self, ctx: &mut RequestContext, settings: &Settings, service: Option<&ServiceConfig>) -> PipelineResult {
+        if Self::is_whitelisted(&ctx.client_ip, settings) {
+            return PipelineResult::allow();
+        }
+        if let Some((action, _)) = self.blocklist.check_ip(&ctx.client_ip).filter(|(a, _)| a.is_blocking()) {
+            debug!(ip = %ctx.client_ip, action = %action, "Preflight blocked by IP blocklist");
+            return PipelineResult::listed(action, ThreatReason::BlockedIp);
+        }
+        if let Some(reason) = self.auto_ban.is_banned(&ctx.client_ip) {
+            debug!(ip = %ctx.client_ip, reason = %reason, "Preflight blocked by auto-ban");
+            return PipelineResult::block(ThreatReason::AutoBanned, 100.0);
+        }
+
+        self.enrich_geo(ctx);
+        let protection_level = Self::protection_level(&self.escalation, service);
+        let subnet = Self::subnet_of(ctx, settings);
+        let asn = ctx.asn.unwrap_or(0);
+        let country = ctx.country_code.as_deref().unwrap_or("XX");
+        self.memory.record_request(ctx.client_ip, subnet, asn, country);
+
+        ctx.ip_policy = self.memory.ip_policy(&ctx.client_ip);
+        if ctx.ip_policy == Some(IpPolicy::BypassRateLimit) {
+            return PipelineResult::allow();
+        }
+
+        // No challenge fallback for preflights: over the limit is a block
+        if let Some(reason) = self.rate_limiter.check(
+            ctx.client_ip,
+            subnet,
+            asn,
+            country,
+            &protection_level,
+            settings,
+            service,
+        ) {
+            info!(ip = %ctx.client_ip, reason = ?reason, "Preflight rate limit exceeded");
+            return PipelineResult::block(reason, 90.0);
+        }
+
+        PipelineResult::allow()
+    }
+
+    /// Feed a final decision back into IP reputation and auto-ban, so that
+    /// repeat offenders reach `ip_reputation.block_threshold` and the
+    /// auto-ban thresholds. Blocks that only restate a list (blocklist,
+    /// allowlist, an existing ban, bad reputation) are not new offences.
+    /// `rule_category` is the category of a custom or managed rule that
+    /// matched, whatever the final decision.
+    fn record_outcome(&self, ip: &IpAddr, result: &PipelineResult, rule_category: Option<ReputationCategory>) {
+        for category in [rule_category, result.reason.and_then(reputation_category)].into_iter().flatten() {
+            self.ip_reputation.add_category(ip, category);
+        }
+        match result.action {
+            ThreatAction::Pass => self.ip_reputation.record_pass(ip),
+            ThreatAction::Challenge => self.ip_reputation.record_challenge(ip),
+            ThreatAction::Block | ThreatAction::Tarpit => {
+                if result.reason.is_some_and(is_list_decision) {
+                    return;
+                }
+                self.ip_reputation.record_block(ip);
+                if self.auto_ban.record_block(ip) {
+                    self.ip_reputation.increment_ban_count(ip);
+                }
+            }
+        }
+    }
 
-        // Exact IP match
-        for whitelisted in &settings.protection.whitelisted_ips {
-            if ip_str == *whitelisted {
-                return true;
+    /// Publish a block or challenge as a security event.
+    pub fn publish_decision(
+        &self,
+        ctx: &RequestContext,
+        service: Option<&ServiceConfig>,
+        result: &PipelineResult,
+        managed_rule: Option<&str>,
+    ) {
+        let event_type = match result.action {
+            ThreatAction::Pass => return,
+            ThreatAction::Challenge => EventType::Challenge,
+            ThreatAction::Block | ThreatAction::Tarpit => EventType::Block,
+        };
+        if !self.events.is_active() {
+            return;
+        }
+        self.events.publish(SecurityEvent {
+            ip: Some(ctx.client_ip),
+            country: ctx.country_code.clone(),
+            asn: ctx.asn,
+            service_id: service.map(|s| s.id.clone()),
+            rule: managed_rule.map(str::to_string),
+            reason: result.reason.map(|r| r.to_string()),
+            score: Some(result.score),
+            ray_id: ctx.ray_id.clone(),
+            ..SecurityEvent::new(event_type, result.action.to_string())
+        });
+    }
+
+    /// The rest of the pipeline for a client with a valid clearance cookie.
+    /// It has already proven itself, so only the rate limits (relaxed by
+    /// `challenge.cleared_rate_limit_multiplier` if enabled) can stop it;
+    /// as with uncleared clients they block only at L3-L4. The sliding
+    /// windows and behavioral profile are still fed.
+    ///
+    /// A client let in by a challenge-bypass API token cannot solve the
+    /// challenge the rate limits would otherwise raise, so it is held to
+    /// the normal limits and blocked over them at any level, unless the
+    /// token exempts it. A `bypass_rate_limit` IP policy exempts it too.
+    fn process_cleared(
+        &self,
+        ctx: &mut RequestContext,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+        token: Option<&TokenGrant>,
+        run: &mut Run<'_>,
+    ) -> PipelineResult {
+        if let Some(grant) = token {
+            if let Some(result) = self.token_rate_limit(ctx, settings, service, grant, run) {
+                return result;
             }
+            if !run.dry_run {
+                self.behavioral.analyze(ctx);
+            }
+            debug!(ip = %ctx.client_ip, token_id = %grant.id, "API token, skipping challenges and scoring layers");
+            return run.decide("2.01", "api_token", 0.0, PipelineResult::allow(), || grant.id.clone());
+        }
+
+        let protection_level = Self::protection_level(&self.escalation, service);
+        let subnet = Self::subnet_of(ctx, settings);
+        let asn = ctx.asn.unwrap_or(0);
+        let country = ctx.country_code.as_deref().unwrap_or("XX");
+        if !run.dry_run {
+            self.memory.record_request(ctx.client_ip, subnet, asn, country);
         }
 
-        // Subnet match with proper CIDR parsing for any prefix length
-        for subnet_str in &settings.protection.whitelisted_subnets {
-            if ip_in_cidr(ip, subnet_str) {
-                return true;
+        let factor = if settings.challenge.clearance_relaxes_rate_limits {
+            settings.challenge.cleared_rate_limit_multiplier
+        } else {
+            1.0
+        };
+        let limited = if ctx.ip_policy == Some(IpPolicy::BypassRateLimit) {
+            None
+        } else {
+            self.rate_limiter.check_scaled(
+                ctx.client_ip,
+                subnet,
+                asn,
+                country,
+                &protection_level,
+                settings,
+                service,
+                factor,
+            )
+        };
+        if let Some(reason) = limited {
+            if matches!(protection_level, ProtectionLevel::L3 | ProtectionLevel::L4) {
+                info!(ip = %ctx.client_ip, reason = ?reason, "Rate limit exceeded by cleared client (emergency block)");
+                return run.decide("2.01", "rate_limit", 0.0, PipelineResult::block(reason, 90.0), || "exceeded".to_string());
             }
         }
 
-        false
+        if !run.dry_run {
+            self.behavioral.analyze(ctx);
+        }
+        debug!(ip = %ctx.client_ip, "Valid clearance cookie, skipping scoring layers");
+        run.decide("2.01", "cleared", 0.0, PipelineResult::allow(), String::new)
+    }
+
+    /// The whole pipeline for a request carrying a pipeline-bypass API
+    /// token: only the rate limits apply, unless the token exempts it.
+    fn process_token_bypass(
+        &self,
+        ctx: &mut RequestContext,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+        grant: &TokenGrant,
+        run: &mut Run<'_>,
+    ) -> PipelineResult {
+        if !grant.exempt_rate_limit {
+            self.enrich_geo(ctx);
+        }
+        if let Some(result) = self.token_rate_limit(ctx, settings, service, grant, run) {
+            return result;
+        }
+        debug!(ip = %ctx.client_ip, token_id = %grant.id, "API token - bypassing pipeline");
+        run.decide("0.1", "api_token", 0.0, PipelineResult::allow(), || grant.id.clone())
+    }
+
+    /// Rate limits for a request let in by an API token. Over the limit is
+    /// a block at any level, as the client cannot answer a challenge.
+    fn token_rate_limit(
+        &self,
+        ctx: &RequestContext,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+        grant: &TokenGrant,
+        run: &mut Run<'_>,
+    ) -> Option<PipelineResult> {
+        if grant.exempt_rate_limit || ctx.ip_policy == Some(IpPolicy::BypassRateLimit) {
+            return None;
+        }
+        let protection_level = Self::protection_level(&self.escalation, service);
+        let subnet = Self::subnet_of(ctx, settings);
+        let asn = ctx.asn.unwrap_or(0);
+        let country = ctx.country_code.as_deref().unwrap_or("XX");
+        if !run.dry_run {
+            self.memory.record_request(ctx.client_ip, subnet, asn, country);
+        }
+        let reason = self
+            .rate_limiter
+            .check(ctx.client_ip, subnet, asn, country, &protection_level, settings, service)?;
+        info!(ip = %ctx.client_ip, token_id = %grant.id, reason = ?reason, "Rate limit exceeded by API token client");
+        Some(run.decide("0.1", "rate_limit", 0.0, PipelineResult::block(reason, 90.0), || grant.id.clone()))
+    }
+
+    /// Whether the request carries a valid clearance cookie for its scope.
+    /// The client's rate-limit subnet under the configured IPv4/IPv6 masks.
+    fn subnet_of(ctx: &RequestContext, settings: &Settings) -> SubnetKey {
+        let protection = &settings.protection;
+        ip_to_subnet(ctx.client_ip, protection.ipv4_subnet_mask, protection.ipv6_subnet_mask)
+    }
+
+    fn has_clearance(&self, ctx: &RequestContext, service: Option<&ServiceConfig>) -> bool {
+        let cookies = ctx.headers.get("cookie").map(|s| s.as_str());
+        let scope = ClearanceScope::new(&ctx.host, service);
+        self.challenge.has_valid_clearance(&ctx.client_ip, cookies, &scope)
+    }
+
+    /// Fill in country and ASN. A country already set (e.g. from the
+    /// CF-IPCountry header) is kept; a trusted proxy's country header is
+    /// the fallback when GeoIP has none.
+    fn enrich_geo(&self, ctx: &mut RequestContext) {
+        if ctx.country_code.is_none() {
+            ctx.country_code = self.geoip.lookup_country(ctx.client_ip).or_else(|| ctx.fallback_country.clone());
+        }
+        if let Some((asn_number, asn_name)) = self.geoip.lookup_asn(ctx.client_ip) {
+            ctx.asn = Some(asn_number);
+            ctx.asn_name = Some(asn_name);
+        }
+    }
+
+    /// The client's own JA3 hash. Behind Cloudflare the handshake is CF's,
+    /// so there is nothing to match against.
+    fn client_ja3(ctx: &RequestContext) -> Option<&str> {
+        ctx.ja3_hash.as_deref().filter(|_| !ctx.is_behind_cloudflare)
+    }
+
+    fn is_ja3_allowed(&self, ctx: &RequestContext) -> bool {
+        Self::client_ja3(ctx).is_some_and(|ja3| self.blocklist.is_ja3_allowed(ja3))
+    }
+
+    /// Protection level requests to `service` are handled at now.
+    pub fn level_for(&self, service: Option<&ServiceConfig>) -> ProtectionLevel {
+        Self::protection_level(&self.escalation, service)
+    }
+
+    /// Effective protection level: the service override if set, otherwise
+    /// the service's own escalated level, otherwise the global level.
+    fn protection_level(escalation: &EscalationEngine, service: Option<&ServiceConfig>) -> ProtectionLevel {
+        match service.and_then(|s| s.protection_level_override) {
+            Some(0) => ProtectionLevel::L0,
+            Some(1) => ProtectionLevel::L1,
+            Some(2) => ProtectionLevel::L2,
+            Some(3) => ProtectionLevel::L3,
+            Some(4) => ProtectionLevel::L4,
+            _ => escalation.effective_level(service.map(|s| s.id.as_str())),
+        }
+    }
+
+    /// Issue a challenge unless the path is exempt or the client already
+    /// holds a valid clearance cookie (Layer 9.0), in which case `None` is
+    /// returned and the caller carries on.
+    fn challenge_unless_cleared(
+        &self,
+        ctx: &RequestContext,
+        service: Option<&ServiceConfig>,
+        level: &ProtectionLevel,
+        score: f64,
+        reason: ThreatReason,
+        run: &mut Run<'_>,
+    ) -> Option<PipelineResult> {
+        if self.challenge.is_exempt(&ctx.method, &ctx.path, service) {
+            debug!(ip = %ctx.client_ip, path = %ctx.path, "Path exempt from challenge");
+            run.note("9.0", "challenge_skipped", 0.0, || "exempt path".to_string());
+            return None;
+        }
+
+        if self.is_ja3_allowed(ctx) {
+            debug!(ip = %ctx.client_ip, "JA3 allowlisted, not challenging");
+            run.note("9.0", "challenge_skipped", 0.0, || "JA3 allowlisted".to_string());
+            return None;
+        }
+
+        if let Some(id) = &ctx.api_token {
+            debug!(ip = %ctx.client_ip, token_id = %id, "API token exempts from challenge, allowing");
+            run.note("9.0", "challenge_skipped", 0.0, || "api token".to_string());
+            return None;
+        }
+
+        if ctx.ip_policy == Some(IpPolicy::NeverChallenge) {
+            debug!(ip = %ctx.client_ip, "IP policy exempts from challenge, allowing");
+            run.note("9.0", "challenge_skipped", 0.0, || "ip policy".to_string());
+            return None;
+        }
+
+        if self.has_clearance(ctx, service) {
+            debug!(ip = %ctx.client_ip, "Valid clearance cookie found, allowing");
+            run.note("9.0", "challenge_skipped", 0.0, || "clearance cookie".to_string());
+            return None;
+        }
+
+        let flood_action = if run.dry_run {
+            self.challenge.peek_flood_action(&ctx.client_ip)
+        } else {
+            self.challenge.flood_action(&ctx.client_ip)
+        };
+        if let Some(action) = flood_action {
+            info!(ip = %ctx.client_ip, action = %action, "Too many unanswered challenges");
+            return Some(PipelineResult {
+                action,
+                reason: Some(ThreatReason::ChallengeFlood),
+                score,
+                challenge_html: None,
+                bypass: None,
+            });
+        }
+
+        info!(
+            ip = %ctx.client_ip,
+            score = score,
+            level = ?level,
+            reason = %reason,
+            "Issuing challenge"
+        );
+        if run.dry_run {
+            return Some(PipelineResult::challenge(reason, score, String::new()));
+        }
+        self.challenge.record_issued(ctx.client_ip);
+        let html = self.challenge.generate_challenge_page(level, &ctx.client_ip);
+        Some(PipelineResult::challenge(reason, score, html))
+    }
+
+    /// Country block/tarpit/challenge verdict. A service's
+    /// `country_exceptions` beat every list; otherwise the service's
+    /// `blocked_countries` / `challenged_countries` add to the global
+    /// blocklist, and a block from either side beats a tarpit, which beats
+    /// a challenge.
+    fn country_action(&self, country: &str, service: Option<&ServiceConfig>) -> Option<ThreatAction> {
+        if service.is_some_and(|s| s.is_country_excepted(country)) {
+            return None;
+        }
+        let listed = |list: &[String]| list.iter().any(|c| c.eq_ignore_ascii_case(country));
+        let global = self.blocklist.check_country(country).map(|(action, _)| action);
+        if global == Some(ThreatAction::Block) || service.is_some_and(|s| listed(&s.blocked_countries)) {
+            Some(ThreatAction::Block)
+        } else if global == Some(ThreatAction::Tarpit) {
+            Some(ThreatAction::Tarpit)
+        } else if global.is_some() || service.is_some_and(|s| listed(&s.challenged_countries)) {
+            Some(ThreatAction::Challenge)
+        } else {
+            None
+        }
+    }
+
+    /// Resolve the allowlist policy for this request. Returns `None` when no
+    /// allowlist is active, otherwise the configured action name.
+    fn allowlist_action(
+        &self,
+        ctx: &RequestContext,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+    ) -> Option<&'static str> {
+        let cfg = &settings.blocklist;
+        let country = ctx.country_code.as_deref();
+        let asn = ctx.asn;
+
+        // A service with its own lists replaces the global ones entirely
+        let (mut countries, asns) = match service {
+            Some(s) if !s.allowed_countries.is_empty() || !s.allowed_asns.is_empty() => (
+                AllowList {
+                    active: !s.allowed_countries.is_empty(),
+                    matched: country.map(|c| s.allowed_countries.iter().any(|a| a.eq_ignore_ascii_case(c))),
+                },
+                AllowList {
+                    active: !s.allowed_asns.is_empty(),
+                    matched: asn.map(|a| s.allowed_asns.contains(&a)),
+                },
+            ),
+            _ => (
+                AllowList {
+                    active: !cfg.allowed_countries.is_empty() || self.blocklist.has_allowed_countries(),
+                    matched: country.map(|c| {
+                        cfg.allowed_countries.iter().any(|a| a.eq_ignore_ascii_case(c))
+                            || self.blocklist.is_country_allowed(&c.to_ascii_uppercase())
+                    }),
+                },
+                AllowList {
+                    active: !cfg.allowed_asns.is_empty() || self.blocklist.has_allowed_asns(),
+                    matched: asn.map(|a| cfg.allowed_asns.contains(&a) || self.blocklist.is_asn_allowed(a)),
+                },
+            ),
+        };
+
+        // A service's country exceptions count as allowlisted countries
+        if service.zip(country).is_some_and(|(s, c)| s.is_country_excepted(c)) {
+            countries.matched = Some(true);
+        }
+
+        allowlist_verdict(countries, asns, &cfg.allowlist_action, &cfg.allowlist_unknown_action)
+    }
+
+    /// Check if the client IP matches any whitelisted IP or subnet.
+    fn is_whitelisted(ip: &IpAddr, settings: &Settings) -> bool {
+        settings.protection.whitelist.contains(ip)
+    }
+}
+
+/// Reputation category implied by the reason a request was stopped.
+/// Rules declare their own (see [`rule_category`]).
+fn reputation_category(reason: ThreatReason) -> Option<ReputationCategory> {
+    match reason {
+        ThreatReason::RateLimit | ThreatReason::DistributedAttack => Some(ReputationCategory::DDoS),
+        ThreatReason::CredentialStuffing => Some(ReputationCategory::BruteForce),
+        _ => None,
+    }
+}
+
+/// Whether a block only enforces a list or an earlier sanction.
+fn is_list_decision(reason: ThreatReason) -> bool {
+    matches!(
+        reason,
+        ThreatReason::BlockedIp
+            | ThreatReason::BlockedCountry
+            | ThreatReason::BlockedAsn
+            | ThreatReason::NotAllowlisted
+            | ThreatReason::ManualBlock
+            | ThreatReason::AutoBanned
+            | ThreatReason::BadReputation
+    )
+}
+
+/// One allowlist (countries or ASNs) as seen by a single request.
+/// `matched` is `None` when the lookup for that dimension failed.
+#[derive(Debug, Clone, Copy)]
+struct AllowList {
+    active: bool,
+    matched: Option<bool>,
+}
+
+/// Combine the country and ASN allowlists into an action. A request passes
+/// if it matches any active list; if every active lookup failed the
+/// unknown-location policy applies instead of the normal action.
+fn allowlist_verdict(countries: AllowList, asns: AllowList, action: &str, unknown_action: &str) -> Option<&'static str> {
+    let active: Vec<AllowList> = [countries, asns].into_iter().filter(|l| l.active).collect();
+    if active.is_empty() || active.iter().any(|l| l.matched == Some(true)) {
+        return None;
     }
+    let chosen = if active.iter().all(|l| l.matched.is_none()) { unknown_action } else { action };
+    Some(match chosen {
+        "allow" => "allow",
+        "challenge" => "challenge",
+        _ => "block",
+    })
 }
 
-/// Check if an IP address falls within a CIDR range.
-/// Supports any valid IPv4 prefix length (0-32).
-fn ip_in_cidr(ip: &IpAddr, cidr: &str) -> bool {
-    if let Some((network_str, prefix_len_str)) = cidr.rsplit_once('/') {
-        if let (Ok(network), Ok(prefix_len)) = (network_str.parse::<std::net::Ipv4Addr>(), prefix_len_str.parse::<u32>()) {
-            if let IpAddr::V4(ipv4) = ip {
-                if prefix_len > 32 { return false; }
-                let mask = if prefix_len == 0 { 0u32 } else { !0u32 << (32 - prefix_len) };
-                return (u32::from(*ipv4) & mask) == (u32::from(network) & mask);
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::analytics::alerting::AlertManager;
+    use crate::protection::firewall::FirewallOffload;
+    use crate::storage::cluster::ClusterSync;
+    use crate::storage::sqlite::tests::TempDb;
+    use crate::storage::sqlite::SqliteStore;
+    use arc_swap::ArcSwap;
+
+    /// A pipeline for `settings` on its own SQLite file, removed when the
+    /// returned guard is dropped.
+    pub(crate) fn test_pipeline(settings: &Settings, db_name: &str) -> (ProtectionPipeline, TempDb) {
+        // The alert and cluster clients need a rustls provider, as in main.
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let db = TempDb::new(db_name);
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let shared = Arc::new(ArcSwap::from_pointee(settings.clone()));
+        let memory = Arc::new(MemoryStore::new());
+        let asn_classifier = Arc::new(AsnClassifier::new());
+        let geoip = Arc::new(GeoIpLookup::new(&settings.geoip));
+        let blocklist = Arc::new(BlocklistManager::new(memory.clone(), sqlite.clone(), geoip.clone()));
+        let alerting = Arc::new(AlertManager::new(shared.clone()));
+        let auto_ban = Arc::new(AutoBanManager::new(
+            &settings.auto_ban,
+            &settings.protection,
+            sqlite.clone(),
+            Arc::new(ClusterSync::new(shared.clone())),
+            alerting.clone(),
+            geoip.clone(),
+            blocklist.clone(),
+            Arc::new(EventBus::new(16)),
+            Arc::new(FirewallOffload::new(shared.clone())),
+        ));
+        let managed_rules = Arc::new(ManagedRulesEngine::new());
+        let pipeline = ProtectionPipeline {
+            rate_limiter: Arc::new(RateLimiter::new(memory.clone())),
+            geoip,
+            fingerprint: Arc::new(FingerprintAnalyzer::new()),
+            challenge: Arc::new(ChallengeSystem::new(&settings.challenge, &settings.protection, memory.clone())),
+            behavioral: Arc::new(BehavioralAnalyzer::new(memory.clone())),
+            mobile_proxy: Arc::new(MobileProxyDetector::new(asn_classifier.clone(), &settings.mobile_proxy)),
+            header_analysis: Arc::new(HeaderAnalyzer::new()),
+            escalation: Arc::new(EscalationEngine::with_config(settings)),
+            blocklist,
+            memory: memory.clone(),
+            bot_whitelist: Arc::new(BotWhitelist::new(&settings.bot_whitelist)),
+            asn_classifier,
+            ip_reputation: Arc::new(IpReputationManager::new(&settings.ip_reputation)),
+            auto_ban: auto_ban.clone(),
+            distributed: Arc::new(DistributedDetector::new(alerting)),
+            managed_rules: managed_rules.clone(),
+            custom_rules: Arc::new(CustomRulesEngine::new(sqlite.clone(), managed_rules)),
+            slowloris: Arc::new(SlowlorisDetector::new(auto_ban, sqlite.clone())),
+            api_tokens: Arc::new(ApiTokenStore::new(sqlite.clone())),
+            ip_policies: Arc::new(IpPolicyStore::new(sqlite, memory)),
+            credential_stuffing: Arc::new(CredentialStuffingDetector::new()),
+            events: Arc::new(EventBus::new(16)),
+        };
+        (pipeline, db)
+    }
+
+    fn test_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.challenge.hmac_secret = "test-secret".to_string();
+        settings
+    }
+
+    /// A browser-like request, with the clearance cookie if given.
+    fn browser_request(ip: IpAddr, cookie: Option<&str>) -> RequestContext {
+        let mut ctx = RequestContext::new(ip, "GET".to_string(), "/account".to_string(), "app.example.com".to_string());
+        let ua = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0 Safari/537.36";
+        ctx.user_agent = Some(ua.to_string());
+        for (name, value) in [
+            ("user-agent", ua),
+            ("accept", "text/html,application/xhtml+xml"),
+            ("accept-language", "en-US,en;q=0.9"),
+            ("accept-encoding", "gzip, br"),
+            ("sec-fetch-mode", "navigate"),
+        ] {
+            ctx.headers.insert(name.to_string(), value.to_string());
+        }
+        if let Some(cookie) = cookie {
+            ctx.headers.insert("cookie".to_string(), cookie.to_string());
+        }
+        ctx
+    }
+
+    fn clearance_cookie(pipeline: &ProtectionPipeline, ip: IpAddr) -> String {
+        let scope = ClearanceScope::new("app.example.com", None);
+        let set_cookie = pipeline.challenge.generate_clearance_cookie(&ip, &scope);
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_cleared_clients_get_relaxed_rate_limits() {
+        let mut settings = test_settings();
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-cleared");
+        pipeline.escalation.set_level(ProtectionLevel::L3);
+
+        // L3 allows 5 req/s per IP; the clearance multiplier makes it 25.
+        let cleared: IpAddr = "198.51.100.10".parse().unwrap();
+        let cookie = clearance_cookie(&pipeline, cleared);
+        for _ in 0..20 {
+            let result = pipeline.process(&mut browser_request(cleared, Some(&cookie)), &settings, None);
+            assert_eq!(result.action, ThreatAction::Pass);
+        }
+
+        let other: IpAddr = "198.51.100.20".parse().unwrap();
+        let mut last = ThreatAction::Pass;
+        for _ in 0..20 {
+            last = pipeline.process(&mut browser_request(other, None), &settings, None).action;
+        }
+        assert_eq!(last, ThreatAction::Block);
+
+        // Without the relaxation a cleared client is held to the normal limits.
+        settings.challenge.clearance_relaxes_rate_limits = false;
+        let strict: IpAddr = "198.51.100.30".parse().unwrap();
+        let cookie = clearance_cookie(&pipeline, strict);
+        let mut last = ThreatAction::Pass;
+        for _ in 0..20 {
+            last = pipeline.process(&mut browser_request(strict, Some(&cookie)), &settings, None).action;
+        }
+        assert_eq!(last, ThreatAction::Block);
+    }
+
+    #[test]
+    fn test_evaluate_is_a_dry_run_with_a_trace() {
+        let mut settings = test_settings();
+        // Real blocks feed auto-ban, which would take over from the rate limit
+        settings.auto_ban.enabled = false;
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-evaluate");
+        pipeline.escalation.set_level(ProtectionLevel::L3);
+        let ip: IpAddr = "198.51.100.40".parse().unwrap();
+
+        // Dry runs never feed the rate-limit windows.
+        for _ in 0..20 {
+            let (result, trace) = pipeline.evaluate(&mut browser_request(ip, None), &settings, None);
+            assert_eq!(result.action, ThreatAction::Pass);
+            let stages: Vec<&str> = trace.stages.iter().map(|s| s.stage).collect();
+            assert!(stages.contains(&"rate_limit"));
+            assert!(stages.contains(&"behavioral"));
+        }
+
+        for _ in 0..20 {
+            pipeline.process(&mut browser_request(ip, None), &settings, None);
+        }
+        let (result, trace) = pipeline.evaluate(&mut browser_request(ip, None), &settings, None);
+        assert_eq!(result.action, ThreatAction::Block);
+        let last = trace.stages.last().unwrap();
+        assert_eq!((last.stage, last.decision), ("rate_limit", Some("block")));
+    }
+
+    #[test]
+    fn test_repeated_blocks_cross_the_reputation_threshold() {
+        fn probe(ip: IpAddr) -> RequestContext {
+            let mut ctx = browser_request(ip, None);
+            ctx.path = "/.env".to_string();
+            ctx
+        }
+        let ip: IpAddr = "198.51.100.50".parse().unwrap();
+
+        // Each managed-rule block adds 5 to the reputation score; the
+        // default threshold of 80 is crossed on the 16th.
+        let mut settings = test_settings();
+        settings.auto_ban.enabled = false;
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-reputation");
+        for _ in 0..16 {
+            let result = pipeline.process(&mut probe(ip), &settings, None);
+            assert_eq!(result.reason, Some(ThreatReason::ManagedRule));
+        }
+        assert_eq!(pipeline.ip_reputation.get_blocked_count(&ip), 16);
+        let result = pipeline.process(&mut browser_request(ip, None), &settings, None);
+        assert_eq!((result.action, result.reason), (ThreatAction::Block, Some(ThreatReason::BadReputation)));
+        let categories = pipeline.ip_reputation.get_top_ips(1).remove(0).4;
+        assert_eq!(categories, vec!["Scanner".to_string()]);
+
+        // The same blocks count towards auto-ban (10 in 5 minutes).
+        let settings = test_settings();
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-reputation-ban");
+        for _ in 0..9 {
+            pipeline.process(&mut probe(ip), &settings, None);
+        }
+        assert!(pipeline.auto_ban.is_banned(&ip).is_none());
+        pipeline.process(&mut probe(ip), &settings, None);
+        assert!(pipeline.auto_ban.is_banned(&ip).is_some());
+        assert_eq!(pipeline.ip_reputation.get_ban_count(&ip), 1);
+    }
+
+    #[tokio::test]
+    async fn test_service_country_lists_extend_the_global_blocklist() {
+        let settings = test_settings();
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-countries");
+        pipeline.blocklist.add_country("RU", ThreatAction::Block, "test", "test", None).await.unwrap();
+        pipeline.blocklist.add_country("KP", ThreatAction::Tarpit, "test", "test", None).await.unwrap();
+        pipeline.blocklist.add_country("CN", ThreatAction::Tarpit, "test", "test", None).await.unwrap();
+        let service: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "app",
+            "name": "app",
+            "domains": ["app.example.com"],
+            "upstream_address": "127.0.0.1:8080",
+            "blocked_countries": ["CN"],
+            "challenged_countries": ["BR", "CN"],
+            "country_exceptions": ["RU"],
+        }))
+        .unwrap();
+        let status_page = ServiceConfig {
+            blocked_countries: Vec::new(),
+            challenged_countries: Vec::new(),
+            country_exceptions: Vec::new(),
+            ..service.clone()
+        };
+
+        assert_eq!(pipeline.country_action("RU", None), Some(ThreatAction::Block));
+        assert_eq!(pipeline.country_action("RU", Some(&status_page)), Some(ThreatAction::Block));
+        // The exception beats the global block.
+        assert_eq!(pipeline.country_action("RU", Some(&service)), None);
+        // Service lists only apply to their service; block beats challenge.
+        assert_eq!(pipeline.country_action("CN", Some(&service)), Some(ThreatAction::Block));
+        assert_eq!(pipeline.country_action("BR", Some(&service)), Some(ThreatAction::Challenge));
+        assert_eq!(pipeline.country_action("BR", Some(&status_page)), None);
+        // A global tarpit beats a service challenge, not a service block.
+        assert_eq!(pipeline.country_action("KP", Some(&service)), Some(ThreatAction::Tarpit));
+        assert_eq!(pipeline.country_action("CN", Some(&status_page)), Some(ThreatAction::Tarpit));
+    }
+
+    #[tokio::test]
+    async fn test_listed_ips_get_their_entry_action() {
+        let settings = test_settings();
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-ip-actions");
+        let blocklist = &pipeline.blocklist;
+        blocklist.add_ip("198.51.100.0/24", ThreatAction::Tarpit, "scan", "admin_api", "test", None, None).await.unwrap();
+        blocklist.add_ip("198.51.100.9", ThreatAction::Block, "abuse", "admin_api", "test", None, None).await.unwrap();
+        blocklist.add_ip("203.0.113.5", ThreatAction::Challenge, "noisy", "admin_api", "test", None, None).await.unwrap();
+        assert!(blocklist.add_ip("203.0.113.6", ThreatAction::Pass, "x", "admin_api", "test", None, None).await.is_err());
+
+        let action = |ip: &str| pipeline.process(&mut browser_request(ip.parse().unwrap(), None), &settings, None).action;
+        assert_eq!(action("198.51.100.7"), ThreatAction::Tarpit);
+        // The exact entry beats the range.
+        assert_eq!(action("198.51.100.9"), ThreatAction::Block);
+        assert_eq!(action("203.0.113.5"), ThreatAction::Challenge);
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens_skip_challenges_within_their_scope() {
+        use crate::protection::api_tokens::TokenSpec;
+
+        let settings = test_settings();
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-tokens");
+        let service: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "app",
+            "name": "app",
+            "domains": ["app.example.com"],
+            "upstream_address": "127.0.0.1:8080",
+            "always_challenge": true,
+        }))
+        .unwrap();
+        let spec = |action, exempt_rate_limit| TokenSpec {
+            name: "monitor".to_string(),
+            allowed_hosts: vec!["*.example.com".to_string()],
+            allowed_paths: vec!["/account".to_string()],
+            action,
+            exempt_rate_limit,
+            expires_at: None,
+        };
+        let (monitor, monitor_token) = pipeline.api_tokens.mint(spec(TokenAction::BypassChallenge, false)).await.unwrap();
+        let (_, partner_token) = pipeline.api_tokens.mint(spec(TokenAction::BypassPipeline, true)).await.unwrap();
+
+        let ip: IpAddr = "198.51.100.40".parse().unwrap();
+        let request = |token: Option<&str>, path: &str| {
+            let mut ctx = browser_request(ip, None);
+            ctx.path = path.to_string();
+            if let Some(token) = token {
+                ctx.headers.insert("x-fortress-token".to_string(), token.to_string());
             }
+            ctx
+        };
+        let action = |ctx: &mut RequestContext| pipeline.process(ctx, &settings, Some(&service)).action;
+
+        assert_eq!(action(&mut request(None, "/account")), ThreatAction::Challenge);
+        assert_eq!(action(&mut request(Some(&monitor_token), "/account")), ThreatAction::Pass);
+        // Out of scope, or with a forged signature, the token is ignored.
+        assert_eq!(action(&mut request(Some(&monitor_token), "/admin")), ThreatAction::Challenge);
+        let forged = format!("{}.{}", monitor.id, "A".repeat(43));
+        assert_eq!(action(&mut request(Some(&forged), "/account")), ThreatAction::Challenge);
+        assert_eq!(pipeline.api_tokens.get(&monitor.id).unwrap().requests, 1);
+
+        // Rate limits still apply (L3 allows 5 req/s per IP) and block, as
+        // the client cannot solve a challenge, unless the token exempts them.
+        pipeline.escalation.set_level(ProtectionLevel::L3);
+        let mut last = ThreatAction::Pass;
+        for _ in 0..10 {
+            last = action(&mut request(Some(&monitor_token), "/account"));
         }
+        assert_eq!(last, ThreatAction::Block);
+        for _ in 0..10 {
+            assert_eq!(action(&mut request(Some(&partner_token), "/account/api")), ThreatAction::Pass);
+        }
+
+        assert!(pipeline.api_tokens.revoke(&monitor.id).await.unwrap());
+        pipeline.escalation.set_level(ProtectionLevel::L0);
+        let other: IpAddr = "198.51.100.41".parse().unwrap();
+        let mut ctx = request(Some(&monitor_token), "/account");
+        ctx.client_ip = other;
+        assert_eq!(action(&mut ctx), ThreatAction::Challenge);
+    }
+
+    #[tokio::test]
+    async fn test_ip_policies_override_challenges_and_rate_limits() {
+        let mut settings = test_settings();
+        settings.protection.rate_limits.level_0.ip_per_10s = 5;
+        let (pipeline, _db) = test_pipeline(&settings, "pipeline-ip-policies");
+        let service: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "app",
+            "name": "app",
+            "domains": ["app.example.com"],
+            "upstream_address": "127.0.0.1:8080",
+            "always_challenge": true,
+        }))
+        .unwrap();
+        let policies = &pipeline.ip_policies;
+        policies.set("198.51.100.0/24", IpPolicy::NeverChallenge, Some("office"), None, "test").await.unwrap();
+        let always = policies.set("198.51.100.50", IpPolicy::AlwaysChallenge, None, None, "test").await.unwrap();
+        policies.set("203.0.113.0/24", IpPolicy::BypassRateLimit, None, None, "test").await.unwrap();
+        policies.set("192.0.2.1", IpPolicy::AlwaysChallenge, None, Some(std::time::Duration::from_secs(60)), "test").await.unwrap();
+
+        let action = |ip: &str, service: Option<&ServiceConfig>| {
+            let mut ctx = browser_request(ip.parse().unwrap(), None);
+            pipeline.process(&mut ctx, &settings, service).action
+        };
+
+        // The office range is never challenged, even by an always-challenge
+        // service; the more specific /32 inside it is always challenged.
+        assert_eq!(action("198.51.100.7", Some(&service)), ThreatAction::Pass);
+        assert_eq!(action("198.51.100.50", None), ThreatAction::Challenge);
+        assert_eq!(action("192.0.2.1", None), ThreatAction::Challenge);
+        assert_eq!(action("192.0.2.2", None), ThreatAction::Pass);
+
+        // Over the rate limit a never-challenge IP is blocked, not
+        // challenged, while a bypass_rate_limit IP is not limited at all.
+        let mut last = ThreatAction::Pass;
+        for _ in 0..10 {
+            last = action("198.51.100.8", None);
+        }
+        assert_eq!(last, ThreatAction::Block);
+        for _ in 0..10 {
+            assert_eq!(action("203.0.113.9", None), ThreatAction::Pass);
+        }
+
+        // Removing the /32 leaves the IP under its range's policy
+        assert!(policies.remove(always.id, "test").await.unwrap().is_some());
+        assert_eq!(pipeline.memory.ip_policy(&"198.51.100.50".parse().unwrap()), Some(IpPolicy::NeverChallenge));
+        assert_eq!(policies.list().await.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_allowlist_verdict_uses_unknown_policy_only_on_lookup_miss() {
+        let on = |m| AllowList { active: true, matched: m };
+        let off = AllowList { active: false, matched: None };
+
+        assert_eq!(allowlist_verdict(off, off, "block", "challenge"), None);
+        assert_eq!(allowlist_verdict(on(Some(true)), on(Some(false)), "block", "challenge"), None);
+        assert_eq!(allowlist_verdict(on(Some(false)), off, "block", "challenge"), Some("block"));
+        assert_eq!(allowlist_verdict(on(None), off, "block", "challenge"), Some("challenge"));
+        assert_eq!(allowlist_verdict(on(None), on(Some(false)), "challenge", "allow"), Some("challenge"));
+        assert_eq!(allowlist_verdict(on(None), on(None), "block", "allow"), Some("allow"));
     }
-    false
 }
@@ -1,10 +1,11 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 use std::sync::Arc;
 use tracing::debug;
 
+use crate::config::service::ServiceConfig;
 use crate::config::settings::Settings;
 use crate::models::threat::{ProtectionLevel, ThreatReason};
-use crate::storage::memory::{MemoryStore, RateLimitConfig};
+use crate::storage::memory::{MemoryStore, RateLimitConfig, SubnetKey};
 
 /// Multi-tier rate limiter using the MemoryStore's sliding window counters.
 ///
@@ -12,7 +13,9 @@ use crate::storage::memory::{MemoryStore, RateLimitConfig};
 /// per-subnet, per-ASN, and per-country sliding windows in a single call.
 ///
 /// The rate-limit thresholds scale with the current protection level.
-/// Higher protection levels have lower thresholds.
+/// Higher protection levels have lower thresholds. When the request matched
+/// a service, its `rate_limit_multiplier` and `max_requests_per_ip_10s`
+/// are applied on top.
 pub struct RateLimiter {
     memory: Arc<MemoryStore>,
 }
@@ -26,20 +29,42 @@ impl RateLimiter {
     ///
     /// Returns `Some(ThreatReason::RateLimit)` if any tier is exceeded,
     /// `None` if all pass.
+    #[allow(clippy::too_many_arguments)]
     pub fn check(
         &self,
         ip: IpAddr,
-        subnet: u32,
+        subnet: SubnetKey,
         asn: u32,
         country: &str,
         level: &ProtectionLevel,
         settings: &Settings,
+        service: Option<&ServiceConfig>,
     ) -> Option<ThreatReason> {
-        let limits = self.get_limits_for_level(level, settings);
+        self.check_scaled(ip, subnet, asn, country, level, settings, service, 1.0)
+    }
+
+    /// [`check`](Self::check) with every threshold multiplied by `factor`,
+    /// used for clients holding a clearance cookie.
+    #[allow(clippy::too_many_arguments)]
+    pub fn check_scaled(
+        &self,
+        ip: IpAddr,
+        subnet: SubnetKey,
+        asn: u32,
+        country: &str,
+        level: &ProtectionLevel,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+        factor: f64,
+    ) -> Option<ThreatReason> {
+        let mut limits = Self::effective_limits(level, settings, service);
+        if factor.is_finite() && factor > 0.0 && factor != 1.0 {
+            limits = limits.scaled(factor);
+        }
 
         debug!(
             ip = %ip,
-            subnet = subnet,
+            subnet = %Ipv6Addr::from(subnet),
             asn = asn,
             country = country,
             level = ?level,
@@ -56,12 +81,44 @@ impl RateLimiter {
         None
     }
 
+    /// Per-second thresholds for `level`, adjusted for `service`.
+    ///
+    /// Every tier is scaled by the service's `rate_limit_multiplier`
+    /// (non-positive values are treated as 1.0). `max_requests_per_ip_10s`
+    /// then replaces the per-IP threshold outright; at L4 the emergency
+    /// per-IP ceiling still applies if it is lower.
+    pub fn effective_limits(
+        level: &ProtectionLevel,
+        settings: &Settings,
+        service: Option<&ServiceConfig>,
+    ) -> RateLimitConfig {
+        let mut limits = Self::get_limits_for_level(level, settings);
+        let Some(service) = service else {
+            return limits;
+        };
+
+        let multiplier = service.rate_limit_multiplier;
+        if multiplier.is_finite() && multiplier > 0.0 && multiplier != 1.0 {
+            limits = limits.scaled(multiplier);
+        }
+
+        if let Some(per_10s) = service.max_requests_per_ip_10s {
+            let per_second = (per_10s / 10).max(1);
+            limits.ip_per_second = match level {
+                ProtectionLevel::L4 => per_second.min(limits.ip_per_second),
+                _ => per_second,
+            };
+        }
+
+        limits
+    }
+
     /// Build a `RateLimitConfig` (per-second thresholds) for the current
     /// protection level by dividing the settings' per-10s values by 10.
     ///
     /// For L4 (emergency lockdown) there is no settings entry, so we use
     /// very restrictive hard-coded defaults.
-    fn get_limits_for_level(&self, level: &ProtectionLevel, settings: &Settings) -> RateLimitConfig {
+    fn get_limits_for_level(level: &ProtectionLevel, settings: &Settings) -> RateLimitConfig {
         let rate_limits = &settings.protection.rate_limits;
 
         match level {
@@ -95,3 +152,89 @@ impl RateLimiter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(multiplier: f64, max_per_ip_10s: Option<u64>) -> ServiceConfig {
+        ServiceConfig {
+            id: "svc".to_string(),
+            name: "svc".to_string(),
+            domains: vec!["example.com".to_string()],
+            upstream_address: vec!["127.0.0.1:8080".to_string()],
+            lb_strategy: Default::default(),
+            enabled: true,
+            protection_level_override: None,
+            always_challenge: false,
+            rate_limit_multiplier: multiplier,
+            max_requests_per_ip_10s: max_per_ip_10s,
+            max_connections: 10_000,
+            connect_timeout_ms: 5_000,
+            response_timeout_ms: 60_000,
+            exempt_paths: Vec::new(),
+            upstream_tls_verify: true,
+            upstream_sni_host: None,
+            upstream_http2: false,
+            add_request_headers: Default::default(),
+            remove_request_headers: Vec::new(),
+            add_response_headers: Default::default(),
+            remove_response_headers: Vec::new(),
+            allowed_countries: Vec::new(),
+            allowed_asns: Vec::new(),
+            blocked_countries: Vec::new(),
+            challenged_countries: Vec::new(),
+            country_exceptions: Vec::new(),
+            clearance_cookie_domain: None,
+            clearance_ttl_secs: None,
+            cors_allowed_origins: Vec::new(),
+            maintenance_mode: false,
+            maintenance_html_path: None,
+            body_inspection: false,
+            login_username_field: None,
+            max_body_size_mb: None,
+            always_online: false,
+            always_online_banner: false,
+            request_encoding: Default::default(),
+            routes: Vec::new(),
+            waiting_room: None,
+            health_check: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn test_service_multiplier_changes_effective_limit() {
+        let settings = Settings::default();
+        let memory = Arc::new(MemoryStore::new());
+        let limiter = RateLimiter::new(memory.clone());
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+
+        // Default L0 allows 50 req/s per IP; send 80.
+        for _ in 0..80 {
+            memory.record_request(ip, 1, 64500, "US");
+        }
+
+        let web = service(1.0, None);
+        let api = service(2.0, None);
+        let level = ProtectionLevel::L0;
+        assert_eq!(RateLimiter::effective_limits(&level, &settings, Some(&web)).ip_per_second, 50);
+        assert_eq!(RateLimiter::effective_limits(&level, &settings, Some(&api)).ip_per_second, 100);
+        assert!(limiter.check(ip, 1, 64500, "US", &level, &settings, Some(&web)).is_some());
+        assert!(limiter.check(ip, 1, 64500, "US", &level, &settings, Some(&api)).is_none());
+    }
+
+    #[test]
+    fn test_absolute_ip_override() {
+        let settings = Settings::default();
+        let svc = service(2.0, Some(2_000));
+        let l0 = RateLimiter::effective_limits(&ProtectionLevel::L0, &settings, Some(&svc));
+        assert_eq!(l0.ip_per_second, 200);
+        // Other tiers still follow the multiplier.
+        assert_eq!(l0.subnet_per_second, 400);
+        // The L4 emergency ceiling is not raised by the override.
+        let l4 = RateLimiter::effective_limits(&ProtectionLevel::L4, &settings, Some(&svc));
+        assert_eq!(l4.ip_per_second, 10);
+    }
+}
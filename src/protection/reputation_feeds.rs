@@ -0,0 +1,235 @@
+//! Background refresh of the Tor exit list and the open proxy / VPN feeds
+//! used by [`IpReputationManager`].
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use tracing::{info, warn};
+
+use crate::config::settings::{IpReputationConfig, SharedSettings};
+use crate::storage::feeds::{feed_client, load_feed, parse_entries, FeedClient, FeedLocation, FetchOutcome, Validators};
+
+use super::ip_reputation::{FeedStatus, IpReputationManager};
+
+/// How often the refresher wakes up to check whether the lists are due.
+const FEED_TICK: Duration = Duration::from_secs(30);
+
+struct Source {
+    name: String,
+    kind: &'static str,
+    location: FeedLocation,
+}
+
+/// The configured lists: the Tor exit list (with `tor_detection` on) and
+/// every proxy feed, from their local paths in offline mode.
+fn sources(config: &IpReputationConfig) -> Vec<Source> {
+    let location = |url: &str, path: &Option<String>| match (config.offline, path) {
+        (true, Some(path)) => FeedLocation::File(path.clone()),
+        (true, None) => FeedLocation::Missing,
+        (false, _) => FeedLocation::Url(url.to_string()),
+    };
+    let mut sources = Vec::new();
+    if config.tor_detection {
+        sources.push(Source {
+            name: "tor".to_string(),
+            kind: "tor",
+            location: location(&config.tor_exit_list_url, &config.tor_exit_list_path),
+        });
+    }
+    sources.extend(config.proxy_feeds.iter().map(|feed| Source {
+        name: feed.name.clone(),
+        kind: "proxy",
+        location: location(&feed.url, &feed.path),
+    }));
+    sources
+}
+
+/// Last good copy of one list.
+struct ListState {
+    kind: &'static str,
+    nets: Vec<IpNet>,
+    validators: Validators,
+    /// `nets` came from this source; the Tor list starts on the built-in
+    /// sample until then.
+    loaded: bool,
+    last_refresh: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+/// Loads the lists and swaps them into the [`IpReputationManager`]. A list
+/// that fails to load, or comes back empty, keeps its previous contents.
+pub struct FeedRefresher {
+    client: FeedClient,
+    reputation: Arc<IpReputationManager>,
+    lists: HashMap<String, ListState>,
+}
+
+impl FeedRefresher {
+    pub fn new(reputation: Arc<IpReputationManager>) -> Self {
+        Self {
+            client: feed_client(),
+            reputation,
+            lists: HashMap::new(),
+        }
+    }
+
+    /// Reload every configured list once.
+    pub async fn refresh(&mut self, config: &IpReputationConfig) {
+        let sources = sources(config);
+        let before = self.lists.len();
+        self.lists
+            .retain(|name, _| sources.iter().any(|s| &s.name == name));
+        let mut proxies_changed = self.lists.len() != before;
+
+        for source in &sources {
+            let state = self.lists.entry(source.name.clone()).or_insert_with(|| ListState {
+                kind: source.kind,
+                nets: Vec::new(),
+                validators: Validators::default(),
+                loaded: false,
+                last_refresh: None,
+                last_error: None,
+            });
+            match load_feed(&self.client, &source.location, &state.validators).await {
+                Ok(FetchOutcome::NotModified) => {
+                    state.last_refresh = Some(Utc::now());
+                    state.last_error = None;
+                }
+                Ok(FetchOutcome::Fetched { text, validators }) => {
+                    let parsed = parse_entries(&text);
+                    // Far more likely a broken upstream than a list that
+                    // really emptied
+                    if parsed.entries.is_empty() {
+                        warn!(feed = %source.name, "Reputation feed returned no entries, keeping current list");
+                        state.last_error = Some("no entries".to_string());
+                        continue;
+                    }
+                    state.nets = parsed.entries.into_iter().map(|e| e.net).collect();
+                    state.validators = validators;
+                    state.loaded = true;
+                    state.last_refresh = Some(Utc::now());
+                    state.last_error = None;
+                    info!(feed = %source.name, entries = state.nets.len(), "Reputation feed refreshed");
+                    if source.kind == "tor" {
+                        self.reputation.replace_tor_exits(&state.nets);
+                    } else {
+                        proxies_changed = true;
+                    }
+                }
+                Err(e) => {
+                    warn!(feed = %source.name, source = %source.location.describe(), "Reputation feed refresh failed: {}", e);
+                    state.last_error = Some(e);
+                }
+            }
+        }
+
+        if proxies_changed {
+            self.reputation.replace_proxies(
+                self.lists
+                    .values()
+                    .filter(|s| s.kind == "proxy")
+                    .flat_map(|s| &s.nets),
+            );
+        }
+
+        let previous = self.reputation.feed_status();
+        let status = sources
+            .iter()
+            .map(|source| {
+                let state = &self.lists[&source.name];
+                let entries = if state.loaded {
+                    state.nets.len()
+                } else {
+                    previous
+                        .iter()
+                        .find(|p| p.name == source.name)
+                        .map_or(0, |p| p.entries)
+                };
+                FeedStatus {
+                    name: source.name.clone(),
+                    kind: source.kind,
+                    source: source.location.describe(),
+                    entries,
+                    last_refresh: state.last_refresh,
+                    last_error: state.last_error.clone(),
+                }
+            })
+            .collect();
+        self.reputation.set_feed_status(status);
+    }
+}
+
+/// Refresh the reputation lists every `ip_reputation.feed_refresh_secs`,
+/// re-reading the feed settings on every tick.
+pub async fn run_reputation_feeds(reputation: Arc<IpReputationManager>, settings: SharedSettings) {
+    let mut refresher = FeedRefresher::new(reputation);
+    let mut last_refresh: Option<Instant> = None;
+
+    loop {
+        let config = settings.load().ip_reputation.clone();
+        let interval = Duration::from_secs(config.feed_refresh_secs.max(60));
+        if config.enabled && last_refresh.is_none_or(|at| at.elapsed() >= interval) {
+            last_refresh = Some(Instant::now());
+            refresher.refresh(&config).await;
+        }
+        tokio::time::sleep(FEED_TICK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::config::settings::{ReputationFeedConfig, Settings};
+
+    #[tokio::test]
+    async fn test_offline_lists_replace_the_built_in_sample_and_survive_failures() {
+        let dir = std::env::temp_dir().join(format!("fortress-reputation-feeds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tor_path = dir.join("tor.txt");
+        let proxy_path = dir.join("proxies.txt");
+        std::fs::write(&tor_path, "198.51.100.1\n").unwrap();
+        std::fs::write(&proxy_path, "# open proxies\n203.0.113.0/24\n").unwrap();
+
+        let mut config = Settings::default().ip_reputation;
+        config.offline = true;
+        config.tor_exit_list_path = Some(tor_path.to_string_lossy().into_owned());
+        config.proxy_feeds = vec![ReputationFeedConfig {
+            name: "proxies".to_string(),
+            url: "https://example.invalid/proxies.txt".to_string(),
+            path: Some(proxy_path.to_string_lossy().into_owned()),
+        }];
+        let reputation = Arc::new(IpReputationManager::new(&config));
+        let built_in: IpAddr = "185.220.100.240".parse().unwrap();
+        assert!(reputation.is_tor_exit(&built_in));
+
+        let mut refresher = FeedRefresher::new(Arc::clone(&reputation));
+        refresher.refresh(&config).await;
+        let tor: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(reputation.is_tor_exit(&tor));
+        assert!(!reputation.is_tor_exit(&built_in));
+        let proxy: IpAddr = "203.0.113.77".parse().unwrap();
+        assert_eq!(reputation.check(&proxy), (config.proxy_score, false));
+
+        // An emptied or vanished list keeps the last good copy
+        std::fs::write(&proxy_path, "").unwrap();
+        std::fs::remove_file(&tor_path).unwrap();
+        refresher.refresh(&config).await;
+        assert!(reputation.is_tor_exit(&tor));
+        assert_eq!(reputation.check(&proxy), (config.proxy_score, false));
+        let status = reputation.feed_status();
+        assert_eq!(status.len(), 2);
+        assert!(status.iter().all(|s| s.entries == 1 && s.last_error.is_some()));
+
+        // Dropping the feed from the config drops its entries
+        config.proxy_feeds.clear();
+        refresher.refresh(&config).await;
+        assert_eq!(reputation.check(&proxy), (0.0, false));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
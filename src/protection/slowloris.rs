@@ -1,8 +1,14 @@
+use std::collections::VecDeque;
 use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
-use tracing::debug;
+use tracing::{debug, warn};
+
+use crate::protection::auto_ban::AutoBanManager;
+use crate::storage::sqlite::SqliteStore;
+use crate::storage::sweep::{Sweep, SweepStats};
 
 /// Slowloris attack detection.
 ///
@@ -15,10 +21,22 @@ use tracing::debug;
 /// - Connection open > 30 seconds
 /// - Less than 1024 bytes received
 /// - HTTP headers not yet complete
+///
+/// Connections the proxy actually drops (header read timeout, request body
+/// below the minimum transfer rate) are reported through
+/// [`record_violation`](Self::record_violation); IPs that keep doing it are
+/// handed to the [`AutoBanManager`].
 pub struct SlowlorisDetector {
     slow_connections: DashMap<IpAddr, SlowConnInfo>,
     /// Count of slow connections per IP for threshold-based detection
     slow_conn_count: DashMap<IpAddr, u32>,
+    /// Recent enforced violations per IP
+    violations: DashMap<IpAddr, VecDeque<Instant>>,
+    connections_sweep: Sweep,
+    counts_sweep: Sweep,
+    violations_sweep: Sweep,
+    auto_ban: Arc<AutoBanManager>,
+    sqlite: Arc<SqliteStore>,
 }
 
 /// Maximum number of concurrent slow connections allowed per IP before flagging
@@ -41,15 +59,55 @@ const SLOWLORIS_TIMEOUT_SECS: u64 = 30;
 const SLOWLORIS_MIN_BYTES: u64 = 1024;
 const STALE_CONNECTION_SECS: u64 = 300; // 5 minutes
 
+/// Violations within the window after which an IP is auto-banned
+const VIOLATION_BAN_THRESHOLD: u32 = 3;
+const VIOLATION_WINDOW_SECS: u64 = 600; // 10 minutes
+
 impl SlowlorisDetector {
     /// Create a new SlowlorisDetector.
-    pub fn new() -> Self {
+    pub fn new(auto_ban: Arc<AutoBanManager>, sqlite: Arc<SqliteStore>) -> Self {
         Self {
             slow_connections: DashMap::new(),
             slow_conn_count: DashMap::new(),
+            violations: DashMap::new(),
+            connections_sweep: Sweep::new(),
+            counts_sweep: Sweep::new(),
+            violations_sweep: Sweep::new(),
+            auto_ban,
+            sqlite,
         }
     }
 
+    /// Record a connection that was dropped for slowloris behaviour.
+    ///
+    /// `reason` is written to the `l4_events` table as a `drop` event. Once
+    /// an IP reaches 3 violations within 10 minutes it is auto-banned.
+    /// Returns the number of violations in the current window.
+    pub fn record_violation(&self, ip: IpAddr, reason: &'static str) -> u32 {
+        let now = Instant::now();
+        let window = Duration::from_secs(VIOLATION_WINDOW_SECS);
+        let count = {
+            let mut entry = self.violations.entry(ip).or_default();
+            while entry.front().is_some_and(|t| now.duration_since(*t) > window) {
+                entry.pop_front();
+            }
+            entry.push_back(now);
+            entry.len() as u32
+        };
+
+        warn!(client_ip = %ip, reason = reason, violations = count, "Slowloris connection dropped");
+
+        self.sqlite
+            .insert_l4_event(&ip.to_string(), "drop", Some(reason), None, None);
+
+        if count >= VIOLATION_BAN_THRESHOLD {
+            self.auto_ban
+                .ban(&ip, &format!("slowloris_{}_violations", count), count);
+        }
+
+        count
+    }
+
     /// Begin tracking a new connection from the given IP.
     ///
     /// Called when a new TCP connection is accepted.
@@ -81,6 +139,17 @@ impl SlowlorisDetector {
         }
     }
 
+    /// Stop tracking a connection that was upgraded (e.g. to WebSocket).
+    ///
+    /// Upgraded connections stay open and mostly idle by design, so they
+    /// must not count towards the per-IP slow connection threshold.
+    pub fn mark_upgraded(&self, ip: &IpAddr) {
+        self.slow_connections.remove(ip);
+        if let Some(mut count) = self.slow_conn_count.get_mut(ip) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
     /// Check if a connection from the given IP exhibits slowloris behavior.
     ///
     /// Returns true if:
@@ -155,11 +224,11 @@ impl SlowlorisDetector {
     /// Remove completed or stale connection tracking entries.
     ///
     /// Should be called periodically (e.g., every 60 seconds) to prevent
-    /// unbounded memory growth.
-    pub fn cleanup(&self) {
+    /// unbounded memory growth. Visits about `budget` entries of each map.
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
         let stale_threshold = Duration::from_secs(STALE_CONNECTION_SECS);
 
-        self.slow_connections.retain(|ip, info| {
+        let mut stats = self.connections_sweep.retain(&self.slow_connections, budget, |ip, info| {
             let age = info.started.elapsed();
             let idle = info.last_activity.elapsed();
 
@@ -189,7 +258,13 @@ impl SlowlorisDetector {
         });
 
         // Remove IPs with zero slow connection count
-        self.slow_conn_count.retain(|_, count| *count > 0);
+        stats += self.counts_sweep.retain(&self.slow_conn_count, budget, |_, count| *count > 0);
+
+        let window = Duration::from_secs(VIOLATION_WINDOW_SECS);
+        stats += self.violations_sweep.retain(&self.violations, budget, |_, times| {
+            times.back().is_some_and(|t| t.elapsed() <= window)
+        });
+        stats
     }
 
     /// Get the number of currently tracked connections.
@@ -211,9 +286,3 @@ impl SlowlorisDetector {
             .count()
     }
 }
-
-impl Default for SlowlorisDetector {
-    fn default() -> Self {
-        Self::new()
-    }
-}
@@ -0,0 +1,71 @@
+//! Per-stage record of a pipeline run: which layers looked at a request,
+//! what each added to the score and which one decided the outcome.
+
+use std::fmt;
+
+use serde::Serialize;
+
+/// One pipeline layer that looked at the request.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStage {
+    /// Layer number as listed on [`ProtectionPipeline::process`], e.g. `"3.0"`.
+    ///
+    /// [`ProtectionPipeline::process`]: super::pipeline::ProtectionPipeline::process
+    pub layer: &'static str,
+    pub stage: &'static str,
+    /// Added to the cumulative score.
+    pub score: f64,
+    /// `block`, `challenge`, `tarpit` or `allow` when this stage ended the
+    /// pipeline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// The stages of one pipeline run, in order.
+#[derive(Debug, Default, Serialize)]
+pub struct PipelineTrace {
+    pub stages: Vec<TraceStage>,
+}
+
+impl PipelineTrace {
+    pub fn push(
+        &mut self,
+        layer: &'static str,
+        stage: &'static str,
+        score: f64,
+        decision: Option<&'static str>,
+        detail: String,
+    ) {
+        self.stages.push(TraceStage {
+            layer,
+            stage,
+            score,
+            decision,
+            detail: (!detail.is_empty()).then_some(detail),
+        });
+    }
+}
+
+/// `3.0 rate_limit +90 (RateLimit); 8.0 challenge_gate =challenge`
+impl fmt::Display for PipelineTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{} {}", stage.layer, stage.stage)?;
+            if stage.score != 0.0 {
+                write!(f, " +{}", stage.score)?;
+            }
+            if let Some(decision) = stage.decision {
+                write!(f, " ={}", decision)?;
+            }
+            if let Some(detail) = &stage.detail {
+                write!(f, " ({})", detail)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,71 +1,272 @@
 use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::net::IpAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
 
-use parking_lot::Mutex;
+use tracing::{error, warn};
 
-/// Per-request access logger that writes one JSON line per request.
-/// Uses `File` directly (OS kernel handles buffering) so every write
-/// is immediately visible in the log file — critical for attack analysis.
+use crate::config::settings::LoggingConfig;
+use crate::models::threat::ThreatReason;
+
+/// Lines queued for the writer thread before new ones are dropped.
+const QUEUE_CAPACITY: usize = 65_536;
+
+/// Everything recorded about one request.
+pub struct AccessLogEntry<'a> {
+    pub client_ip: IpAddr,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub host: &'a str,
+    /// `HTTP/1.1`, `HTTP/2.0`, ...
+    pub protocol: &'a str,
+    pub status: u16,
+    pub action: &'a str,
+    /// Why the pipeline blocked or challenged the request.
+    pub reason: Option<ThreatReason>,
+    pub latency_us: u64,
+    pub bytes: u64,
+    pub country: Option<&'a str>,
+    pub asn: Option<u32>,
+    pub ja3: Option<&'a str>,
+    pub user_agent: &'a str,
+    pub referer: Option<&'a str>,
+    pub ray_id: &'a str,
+    /// `X-Request-Id` sent by the client, if any.
+    pub request_id: Option<&'a str>,
+    pub service_id: Option<&'a str>,
+    /// Id of the service route the request took, if any.
+    pub route: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// One JSON object per line.
+    Json,
+    /// Apache/nginx "combined" format.
+    Combined,
+}
+
+enum Command {
+    Line(String),
+    Reopen,
+}
+
+/// Per-request access logger.
+///
+/// Lines are formatted on the request path and handed to a dedicated
+/// writer thread, so a slow disk never stalls request handling. The writer
+/// flushes after every batch, keeping the file current for attack analysis,
+/// and rotates it by size (`path` -> `path.1` -> ... -> `path.N`). Renames
+/// are atomic; the writer then reopens `path`. `reopen()` does the same
+/// for external rotation such as logrotate.
 pub struct AccessLogger {
-    writer: Mutex<File>,
+    tx: SyncSender<Command>,
+    format: Format,
+    shared: Arc<Shared>,
+}
+
+/// Counters and flags shared with the writer thread.
+#[derive(Default)]
+struct Shared {
+    dropped: AtomicU64,
+    reopen: AtomicBool,
 }
 
 impl AccessLogger {
-    /// Open (or create) the access log file in append mode.
-    pub fn new(path: &str) -> std::io::Result<Self> {
-        if let Some(parent) = Path::new(path).parent() {
+    /// Open (or create) the access log file in append mode and start the
+    /// writer thread.
+    pub fn new(config: &LoggingConfig) -> std::io::Result<Self> {
+        let path = PathBuf::from(&config.access_log);
+        if let Some(parent) = path.parent() {
             let _ = std::fs::create_dir_all(parent);
         }
+        let format = match config.access_log_format.as_str() {
+            "combined" => Format::Combined,
+            "json" => Format::Json,
+            other => {
+                warn!(format = other, "Unknown access_log_format, using json");
+                Format::Json
+            }
+        };
+
+        let writer = LogWriter::open(
+            path,
+            config.access_log_max_size_mb * 1024 * 1024,
+            config.access_log_max_files.max(1),
+        )?;
+        let shared = Arc::new(Shared::default());
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let thread_shared = shared.clone();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(rx, thread_shared))?;
+
+        Ok(Self { tx, format, shared })
+    }
+
+    /// Queue a single access-log entry. Never blocks; if the writer has
+    /// fallen too far behind the entry is dropped and counted.
+    pub fn log(&self, entry: &AccessLogEntry<'_>) {
+        let line = match self.format {
+            Format::Json => format_json(entry),
+            Format::Combined => format_combined(entry),
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(Command::Line(line)) {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Ask the writer to close and reopen the log file, e.g. after
+    /// logrotate has moved it away.
+    pub fn reopen(&self) {
+        // The flag survives a full queue; the writer checks it after every
+        // batch, and the message only wakes it up if it is idle.
+        self.shared.reopen.store(true, Ordering::Relaxed);
+        let _ = self.tx.try_send(Command::Reopen);
+    }
+}
+
+/// A size-rotated line log, owned by a writer thread. The security event
+/// file uses it too.
+pub(crate) struct LogWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    /// Rotate once the file reaches this many bytes; 0 disables rotation.
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl LogWriter {
+    pub(crate) fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        let (file, size) = open_append(&path)?;
+        Ok(Self { path, file, size, max_bytes, max_files })
+    }
+
+    fn run(mut self, rx: Receiver<Command>, shared: Arc<Shared>) {
+        while let Ok(cmd) = rx.recv() {
+            self.handle(cmd);
+            while let Ok(cmd) = rx.try_recv() {
+                self.handle(cmd);
+            }
+            if shared.reopen.swap(false, Ordering::Relaxed) {
+                self.reopen();
+            }
+            let _ = self.file.flush();
+
+            let n = shared.dropped.swap(0, Ordering::Relaxed);
+            if n > 0 {
+                warn!(dropped = n, "Access log writer fell behind; entries dropped");
+            }
+        }
+        let _ = self.file.flush();
+    }
+
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::Line(line) => self.write_line(&line),
+            // Handled through the shared flag once the batch is written
+            Command::Reopen => {}
+        }
+    }
+
+    /// Append `line` and a newline, rotating if the file is full.
+    pub(crate) fn write_line(&mut self, line: &str) {
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.size += line.len() as u64 + 1;
+        }
+        if self.max_bytes > 0 && self.size >= self.max_bytes {
+            self.rotate();
+        }
+    }
 
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)?;
+    pub(crate) fn flush(&mut self) {
+        let _ = self.file.flush();
+    }
 
-        Ok(Self {
-            writer: Mutex::new(file),
-        })
+    /// Shift `path.N-1` .. `path.1` up by one, move `path` to `path.1` and
+    /// start a fresh file. The oldest file falls off the end.
+    fn rotate(&mut self) {
+        let _ = self.file.flush();
+        for i in (1..self.max_files).rev() {
+            let _ = std::fs::rename(numbered(&self.path, i), numbered(&self.path, i + 1));
+        }
+        if let Err(e) = std::fs::rename(&self.path, numbered(&self.path, 1)) {
+            error!(path = %self.path.display(), "Failed to rotate access log: {}", e);
+        }
+        self.reopen();
     }
 
-    /// Write a single access-log entry as a JSON line.
-    pub fn log(
-        &self,
-        client_ip: IpAddr,
-        method: &str,
-        path: &str,
-        host: &str,
-        status: u16,
-        action: &str,
-        elapsed_us: u64,
-        user_agent: &str,
-        country: Option<&str>,
-        ray_id: &str,
-    ) {
-        let ts = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ");
-        let line = format!(
-            r#"{{"ts":"{}","ip":"{}","method":"{}","host":"{}","path":"{}","status":{},"action":"{}","us":{},"ua":"{}","cc":"{}","ray":"{}"}}"#,
-            ts,
-            client_ip,
-            escape_json(method),
-            escape_json(host),
-            escape_json(path),
-            status,
-            action,
-            elapsed_us,
-            escape_json(user_agent),
-            country.unwrap_or("-"),
-            ray_id,
-        );
-
-        let mut f = self.writer.lock();
-        let _ = writeln!(f, "{}", line);
+    fn reopen(&mut self) {
+        let _ = self.file.flush();
+        match open_append(&self.path) {
+            Ok((file, size)) => {
+                self.file = file;
+                self.size = size;
+            }
+            Err(e) => error!(path = %self.path.display(), "Failed to reopen access log: {}", e),
+        }
     }
 }
 
-/// Minimal JSON string escaping (quotes and backslashes).
-fn escape_json(s: &str) -> String {
+fn open_append(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok((BufWriter::new(file), size))
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn format_json(e: &AccessLogEntry<'_>) -> String {
+    serde_json::json!({
+        "ts": chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        "ip": e.client_ip,
+        "method": e.method,
+        "path": e.path,
+        "host": e.host,
+        "status": e.status,
+        "action": e.action,
+        "reason": e.reason.map(|r| r.to_string()),
+        "latency_us": e.latency_us,
+        "bytes": e.bytes,
+        "country": e.country,
+        "asn": e.asn,
+        "ja3": e.ja3,
+        "user_agent": e.user_agent,
+        "referer": e.referer,
+        "ray_id": e.ray_id,
+        "request_id": e.request_id,
+        "service_id": e.service_id,
+        "route": e.route,
+    })
+    .to_string()
+}
+
+/// `ip - - [time] "METHOD path PROTO" status bytes "referer" "ua"`
+fn format_combined(e: &AccessLogEntry<'_>) -> String {
+    format!(
+        r#"{} - - [{}] "{} {} {}" {} {} "{}" "{}""#,
+        e.client_ip,
+        chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        escape_quoted(e.method),
+        escape_quoted(e.path),
+        e.protocol,
+        e.status,
+        if e.bytes == 0 { "-".to_string() } else { e.bytes.to_string() },
+        escape_quoted(e.referer.unwrap_or("-")),
+        escape_quoted(e.user_agent),
+    )
+}
+
+/// Escape a value for a double-quoted combined-log field.
+fn escape_quoted(s: &str) -> String {
     let mut out = String::with_capacity(s.len());
     for ch in s.chars() {
         match ch {
@@ -79,3 +280,30 @@ fn escape_json(s: &str) -> String {
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_shifts_files_and_keeps_max_files() {
+        let dir = std::env::temp_dir().join(format!("fortress-access-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut writer = LogWriter::open(path.clone(), 10, 2).unwrap();
+        for line in ["first-line", "second-line", "third-line"] {
+            writer.handle(Command::Line(line.to_string()));
+        }
+        writer.file.flush().unwrap();
+
+        let read = |p: PathBuf| std::fs::read_to_string(p).unwrap_or_default();
+        assert_eq!(read(path.clone()), "");
+        assert_eq!(read(numbered(&path, 1)), "third-line\n");
+        assert_eq!(read(numbered(&path, 2)), "second-line\n");
+        assert!(!numbered(&path, 3).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::config::settings::CircuitBreakerConfig;
+
+// ---------------------------------------------------------------------------
+// Circuit breaker – fail fast while a service's upstreams keep failing
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed { failures: u32 },
+    /// Requests fail fast until `until`.
+    Open { until: Instant },
+    /// One probe request is in flight; if it never reports back, another is
+    /// let through after `probe_deadline`.
+    HalfOpen { probe_deadline: Instant },
+}
+
+/// Circuit state as reported by the admin API.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CircuitStatus {
+    /// `closed`, `open` or `half_open`.
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    /// Seconds until an open circuit lets a probe request through.
+    pub retry_in_secs: Option<u64>,
+}
+
+/// Consecutive-failure circuit breaker.
+///
+/// After `failure_threshold` failed requests in a row the circuit opens and
+/// requests are refused for `open_secs`. Then a single probe request is let
+/// through: success closes the circuit, failure opens it again.
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a request may go upstream; `Err` carries how long the caller
+    /// should wait before retrying.
+    pub fn try_acquire(&self, config: &CircuitBreakerConfig) -> Result<(), Duration> {
+        if config.failure_threshold == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(until - now),
+            State::HalfOpen { probe_deadline } if now < probe_deadline => Err(probe_deadline - now),
+            State::Open { .. } | State::HalfOpen { .. } => {
+                *state = State::HalfOpen {
+                    probe_deadline: now + Duration::from_secs(config.open_secs.max(1)),
+                };
+                Ok(())
+            }
+        }
+    }
+
+    /// Record a request that got a response. Returns `true` if this closed
+    /// the circuit.
+    pub fn record_success(&self) -> bool {
+        let mut state = self.state.lock();
+        match *state {
+            // Late results of requests admitted before the circuit opened
+            // say nothing about the probe.
+            State::Open { .. } => false,
+            State::Closed { .. } => {
+                *state = State::Closed { failures: 0 };
+                false
+            }
+            State::HalfOpen { .. } => {
+                *state = State::Closed { failures: 0 };
+                true
+            }
+        }
+    }
+
+    /// Record a failed request. Returns `true` if this opened the circuit.
+    pub fn record_failure(&self, config: &CircuitBreakerConfig) -> bool {
+        let mut state = self.state.lock();
+        let open = State::Open {
+            until: Instant::now() + Duration::from_secs(config.open_secs.max(1)),
+        };
+        match *state {
+            State::Open { .. } => false,
+            State::HalfOpen { .. } => {
+                *state = open;
+                true
+            }
+            State::Closed { failures } => {
+                let failures = failures + 1;
+                if config.failure_threshold > 0 && failures >= config.failure_threshold {
+                    *state = open;
+                    true
+                } else {
+                    *state = State::Closed { failures };
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        let now = Instant::now();
+        match *self.state.lock() {
+            State::Closed { failures } => CircuitStatus {
+                state: "closed",
+                consecutive_failures: failures,
+                retry_in_secs: None,
+            },
+            State::Open { until } => CircuitStatus {
+                state: "open",
+                consecutive_failures: 0,
+                retry_in_secs: Some(until.saturating_duration_since(now).as_secs()),
+            },
+            State::HalfOpen { .. } => CircuitStatus {
+                state: "half_open",
+                consecutive_failures: 0,
+                retry_in_secs: None,
+            },
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold_and_probes_once_cooled_down() {
+        let mut config = CircuitBreakerConfig {
+            failure_threshold: 3,
+            open_secs: 30,
+        };
+        let breaker = CircuitBreaker::new();
+
+        assert!(!breaker.record_failure(&config));
+        assert!(!breaker.record_failure(&config));
+        breaker.record_success();
+        assert_eq!(breaker.status().consecutive_failures, 0);
+
+        for _ in 0..2 {
+            assert!(!breaker.record_failure(&config));
+        }
+        assert!(breaker.record_failure(&config));
+        assert_eq!(breaker.status().state, "open");
+        assert!(breaker.try_acquire(&config).is_err());
+
+        // Cooled down: exactly one probe is let through.
+        *breaker.state.lock() = State::Open { until: Instant::now() };
+        assert!(breaker.try_acquire(&config).is_ok());
+        assert_eq!(breaker.status().state, "half_open");
+        assert!(breaker.try_acquire(&config).is_err());
+
+        // A failed probe opens the circuit again, a successful one closes it.
+        assert!(breaker.record_failure(&config));
+        *breaker.state.lock() = State::Open { until: Instant::now() };
+        assert!(breaker.try_acquire(&config).is_ok());
+        assert!(breaker.record_success());
+        assert_eq!(breaker.status().state, "closed");
+
+        config.failure_threshold = 0;
+        for _ in 0..10 {
+            assert!(!breaker.record_failure(&config));
+        }
+        assert!(breaker.try_acquire(&config).is_ok());
+    }
+}
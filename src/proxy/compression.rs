@@ -0,0 +1,113 @@
+use std::io::Write;
+
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::http::response::Builder;
+use hyper::Response;
+
+use super::http_handler::{full_body, ProxyBody};
+
+/// Pages smaller than this are sent as-is; the encoding overhead would eat
+/// most of the saving.
+const MIN_COMPRESS_SIZE: usize = 512;
+
+/// Brotli quality for generated pages. Pages differ per request (challenge
+/// tokens, ray IDs), so they are compressed on the fly and speed matters
+/// more than ratio during a flood.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_LG_WINDOW: u32 = 22;
+
+/// Content codings Fortress can apply to its own pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+
+    /// Pick an encoding from an `Accept-Encoding` header value, preferring
+    /// brotli. Codings with `q=0` are treated as refused.
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut br = None;
+        let mut gzip = None;
+        let mut wildcard = None;
+
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q=").and_then(|v| v.trim().parse::<f32>().ok()))
+                .unwrap_or(1.0);
+            match coding.as_str() {
+                "br" => br = Some(q),
+                "gzip" | "x-gzip" => gzip = Some(q),
+                "*" => wildcard = Some(q),
+                _ => {}
+            }
+        }
+
+        let br = br.or(wildcard).unwrap_or(0.0);
+        let gzip = gzip.or(wildcard).unwrap_or(0.0);
+        if br > 0.0 && br >= gzip {
+            Some(ContentEncoding::Brotli)
+        } else if gzip > 0.0 {
+            Some(ContentEncoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Brotli => {
+                let mut out = Vec::with_capacity(data.len() / 3);
+                {
+                    let mut writer =
+                        brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, BROTLI_LG_WINDOW);
+                    writer.write_all(data)?;
+                }
+                Ok(out)
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 3), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Finish a Fortress-generated page, compressing `body` when the client
+/// accepts gzip or brotli. Always sets `Vary: Accept-Encoding` so caches in
+/// front of Fortress keep the variants apart.
+pub fn encoded_page(
+    builder: Builder,
+    body: impl Into<Bytes>,
+    accept_encoding: Option<&str>,
+) -> Response<ProxyBody> {
+    let body = body.into();
+    let builder = builder.header("Vary", "Accept-Encoding");
+
+    let encoding = accept_encoding
+        .filter(|_| body.len() >= MIN_COMPRESS_SIZE)
+        .and_then(ContentEncoding::negotiate);
+
+    if let Some(encoding) = encoding {
+        if let Ok(compressed) = encoding.encode(&body) {
+            return builder
+                .header("Content-Encoding", encoding.as_str())
+                .body(full_body(compressed))
+                .unwrap();
+        }
+    }
+
+    builder.body(full_body(body)).unwrap()
+}
@@ -1,8 +1,11 @@
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 
 /// Snapshot of a single connection suitable for serialisation / API responses.
@@ -10,12 +13,98 @@ use tracing::{debug, info};
 pub struct ConnectionSnapshot {
     pub id: u64,
     pub client_ip: IpAddr,
+    pub connected_at: DateTime<Utc>,
     pub connected_at_secs_ago: u64,
+    pub idle_secs: u64,
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub requests: u64,
     pub ja3_hash: Option<String>,
     pub host: Option<String>,
+    pub tls: bool,
+    pub upgraded: bool,
+}
+
+/// Rolling transfer rate of one client IP, summed over its connections.
+#[derive(Debug, Clone)]
+pub struct IpBandwidthSnapshot {
+    pub client_ip: IpAddr,
+    /// Average bytes/s written to the client over the last
+    /// [`BANDWIDTH_WINDOW_SECS`].
+    pub bytes_sent_per_sec: u64,
+    /// Average bytes/s read from the client over the same window.
+    pub bytes_received_per_sec: u64,
+    pub connections: u64,
+}
+
+/// Seconds the per-IP bandwidth rate is averaged over.
+pub const BANDWIDTH_WINDOW_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct BandwidthSlot {
+    second: u64,
+    sent: u64,
+    received: u64,
+}
+
+/// Per-second byte counts of one IP for the last [`BANDWIDTH_WINDOW_SECS`],
+/// plus the token bucket for `protection.max_bytes_per_ip_per_sec`.
+struct IpBandwidth {
+    slots: [BandwidthSlot; BANDWIDTH_WINDOW_SECS as usize],
+    last_second: u64,
+    /// Bytes that may still be written without waiting; negative when the
+    /// IP is in debt and its writes are being delayed. Starts out infinite
+    /// so a new IP's bucket is full whatever the cap.
+    allowance: f64,
+    refilled_at: Instant,
+}
+
+impl IpBandwidth {
+    fn new(now: Instant) -> Self {
+        Self {
+            slots: [BandwidthSlot::default(); BANDWIDTH_WINDOW_SECS as usize],
+            last_second: 0,
+            allowance: f64::INFINITY,
+            refilled_at: now,
+        }
+    }
+
+    fn record(&mut self, second: u64, sent: u64, received: u64) {
+        let slot = &mut self.slots[(second % BANDWIDTH_WINDOW_SECS) as usize];
+        if slot.second != second {
+            *slot = BandwidthSlot {
+                second,
+                ..Default::default()
+            };
+        }
+        slot.sent += sent;
+        slot.received += received;
+        self.last_second = self.last_second.max(second);
+    }
+
+    /// Average (sent, received) bytes/s over the window ending at `second`.
+    fn rate(&self, second: u64) -> (u64, u64) {
+        let (sent, received) = self
+            .slots
+            .iter()
+            .filter(|slot| slot.second <= second && second - slot.second < BANDWIDTH_WINDOW_SECS)
+            .fold((0, 0), |(sent, received), slot| (sent + slot.sent, received + slot.received));
+        (sent / BANDWIDTH_WINDOW_SECS, received / BANDWIDTH_WINDOW_SECS)
+    }
+
+    /// Take `bytes` from the bucket and return how long the write has to
+    /// wait. The bucket holds one second's worth of `cap`.
+    fn take(&mut self, now: Instant, bytes: u64, cap: u64) -> Duration {
+        let cap = cap as f64;
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * cap;
+        self.allowance = (self.allowance + refill).min(cap) - bytes as f64;
+        self.refilled_at = now;
+        if self.allowance >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.allowance / cap)
+        }
+    }
 }
 
 /// Per-connection live state.
@@ -23,11 +112,28 @@ pub struct ConnectionInfo {
     pub id: u64,
     pub client_ip: IpAddr,
     pub connected_at: Instant,
+    pub connected_at_wall: DateTime<Utc>,
+    /// Milliseconds after `connected_at` of the last request or transfer.
+    pub last_activity_ms: AtomicU64,
     pub bytes_sent: AtomicU64,
     pub bytes_received: AtomicU64,
     pub requests: AtomicU64,
     pub ja3_hash: Option<String>,
     pub host: Option<String>,
+    /// Whether the client connected over TLS rather than plain HTTP.
+    pub tls: bool,
+    /// Set once the connection is handed to a WebSocket relay; the relay is
+    /// then responsible for removing the entry.
+    pub upgraded: AtomicBool,
+    /// Cancelled to force the connection closed (see [`ConnectionTracker::close`]).
+    pub close: CancellationToken,
+}
+
+impl ConnectionInfo {
+    fn touch(&self) {
+        let elapsed = self.connected_at.elapsed().as_millis() as u64;
+        self.last_activity_ms.fetch_max(elapsed, Ordering::Relaxed);
+    }
 }
 
 /// Thread-safe tracker for all active proxy connections.
@@ -36,6 +142,10 @@ pub struct ConnectionInfo {
 pub struct ConnectionTracker {
     next_id: AtomicU64,
     active: DashMap<u64, ConnectionInfo>,
+    /// Rolling transfer rate per client IP, see [`ConnectionTracker::top_bandwidth`].
+    bandwidth: DashMap<IpAddr, IpBandwidth>,
+    /// Origin of the bandwidth slots' second numbers.
+    started: Instant,
 }
 
 impl ConnectionTracker {
@@ -44,22 +154,29 @@ impl ConnectionTracker {
         Self {
             next_id: AtomicU64::new(1),
             active: DashMap::new(),
+            bandwidth: DashMap::new(),
+            started: Instant::now(),
         }
     }
 
     /// Register a new connection and return its unique ID.
-    pub fn register(&self, ip: IpAddr, ja3: Option<String>) -> u64 {
+    pub fn register(&self, ip: IpAddr, ja3: Option<String>, tls: bool) -> u64 {
         let id = self.next_id.fetch_add(1, Ordering::Relaxed);
 
         let info = ConnectionInfo {
             id,
             client_ip: ip,
             connected_at: Instant::now(),
+            connected_at_wall: Utc::now(),
+            last_activity_ms: AtomicU64::new(0),
             bytes_sent: AtomicU64::new(0),
             bytes_received: AtomicU64::new(0),
             requests: AtomicU64::new(0),
             ja3_hash: ja3,
             host: None,
+            tls,
+            upgraded: AtomicBool::new(false),
+            close: CancellationToken::new(),
         };
 
         self.active.insert(id, info);
@@ -82,18 +199,83 @@ impl ConnectionTracker {
         }
     }
 
-    /// Increment the byte counters for a given connection.
-    pub fn update_bytes(&self, id: u64, sent: u64, received: u64) {
+    /// Increment the byte counters for a given connection and the rolling
+    /// rate of `ip`, the client the bytes were for (which differs from the
+    /// connection's peer behind Cloudflare).
+    pub fn update_bytes(&self, id: u64, ip: IpAddr, sent: u64, received: u64) {
         if let Some(entry) = self.active.get(&id) {
             entry.bytes_sent.fetch_add(sent, Ordering::Relaxed);
             entry.bytes_received.fetch_add(received, Ordering::Relaxed);
+            entry.touch();
         }
+        let second = self.started.elapsed().as_secs();
+        self.bandwidth
+            .entry(ip)
+            .or_insert_with(|| IpBandwidth::new(Instant::now()))
+            .record(second, sent, received);
+    }
+
+    /// Charge `bytes` about to be written to `ip` against its share of
+    /// `cap` bytes/s and return how long the write should be delayed.
+    /// All of the IP's connections draw from the same bucket.
+    pub fn throttle_delay(&self, ip: IpAddr, bytes: u64, cap: u64) -> Duration {
+        if cap == 0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        self.bandwidth
+            .entry(ip)
+            .or_insert_with(|| IpBandwidth::new(now))
+            .take(now, bytes, cap)
+    }
+
+    /// Rolling (sent, received) bytes/s of `ip`.
+    pub fn bandwidth(&self, ip: &IpAddr) -> (u64, u64) {
+        let second = self.started.elapsed().as_secs();
+        self.bandwidth.get(ip).map(|entry| entry.rate(second)).unwrap_or((0, 0))
+    }
+
+    /// The `limit` IPs with the highest rolling transfer rate (sent plus
+    /// received), busiest first. Idle IPs are left out.
+    pub fn top_bandwidth(&self, limit: usize) -> Vec<IpBandwidthSnapshot> {
+        let second = self.started.elapsed().as_secs();
+        let mut connections: HashMap<IpAddr, u64> = HashMap::new();
+        for entry in self.active.iter() {
+            *connections.entry(entry.client_ip).or_default() += 1;
+        }
+        let mut top: Vec<IpBandwidthSnapshot> = self
+            .bandwidth
+            .iter()
+            .filter_map(|entry| {
+                let (sent, received) = entry.rate(second);
+                (sent + received > 0).then(|| IpBandwidthSnapshot {
+                    client_ip: *entry.key(),
+                    bytes_sent_per_sec: sent,
+                    bytes_received_per_sec: received,
+                    connections: connections.get(entry.key()).copied().unwrap_or(0),
+                })
+            })
+            .collect();
+        top.sort_by(|a, b| {
+            (b.bytes_sent_per_sec + b.bytes_received_per_sec)
+                .cmp(&(a.bytes_sent_per_sec + a.bytes_received_per_sec))
+        });
+        top.truncate(limit);
+        top
+    }
+
+    /// Forget IPs that have transferred nothing for a whole window.
+    pub fn prune_bandwidth(&self) {
+        let second = self.started.elapsed().as_secs();
+        self.bandwidth
+            .retain(|_ip, entry| second.saturating_sub(entry.last_second) < BANDWIDTH_WINDOW_SECS);
     }
 
     /// Increment the request counter for a given connection.
     pub fn increment_requests(&self, id: u64) {
         if let Some(entry) = self.active.get(&id) {
             entry.requests.fetch_add(1, Ordering::Relaxed);
+            entry.touch();
         }
     }
 
@@ -104,6 +286,58 @@ impl ConnectionTracker {
         }
     }
 
+    /// Mark a connection as upgraded (e.g. to WebSocket).
+    pub fn mark_upgraded(&self, id: u64) {
+        if let Some(entry) = self.active.get(&id) {
+            entry.upgraded.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether a connection arrived over TLS.
+    pub fn is_tls(&self, id: u64) -> bool {
+        self.active.get(&id).map(|entry| entry.tls).unwrap_or(false)
+    }
+
+    /// Whether a connection has been upgraded.
+    pub fn is_upgraded(&self, id: u64) -> bool {
+        self.active
+            .get(&id)
+            .map(|entry| entry.upgraded.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// Token that is cancelled when the connection is closed through
+    /// [`ConnectionTracker::close`]. The connection task selects on it.
+    pub fn close_token(&self, id: u64) -> Option<CancellationToken> {
+        self.active.get(&id).map(|entry| entry.close.clone())
+    }
+
+    /// Force a connection closed. The serving task drops the socket and
+    /// removes the entry. Returns `false` for an unknown ID.
+    pub fn close(&self, id: u64) -> bool {
+        match self.active.get(&id) {
+            Some(entry) => {
+                entry.close.cancel();
+                info!(connection_id = id, client_ip = %entry.client_ip, "Connection closed by admin");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Force every connection from `ip` closed. Returns how many there were.
+    pub fn close_by_ip(&self, ip: &IpAddr) -> usize {
+        let mut closed = 0;
+        for entry in self.active.iter().filter(|entry| entry.client_ip == *ip) {
+            entry.close.cancel();
+            closed += 1;
+        }
+        if closed > 0 {
+            info!(client_ip = %ip, closed = closed, "Connections closed by admin");
+        }
+        closed
+    }
+
     /// Return the number of currently active connections.
     pub fn active_count(&self) -> u64 {
         self.active.len() as u64
@@ -122,29 +356,35 @@ impl ConnectionTracker {
             .iter()
             .map(|entry| {
                 let info = entry.value();
+                let age = now.duration_since(info.connected_at);
+                let last_activity = Duration::from_millis(info.last_activity_ms.load(Ordering::Relaxed));
                 ConnectionSnapshot {
                     id: info.id,
                     client_ip: info.client_ip,
-                    connected_at_secs_ago: now
-                        .duration_since(info.connected_at)
-                        .as_secs(),
+                    connected_at: info.connected_at_wall,
+                    connected_at_secs_ago: age.as_secs(),
+                    idle_secs: age.saturating_sub(last_activity).as_secs(),
                     bytes_sent: info.bytes_sent.load(Ordering::Relaxed),
                     bytes_received: info.bytes_received.load(Ordering::Relaxed),
                     requests: info.requests.load(Ordering::Relaxed),
                     ja3_hash: info.ja3_hash.clone(),
                     host: info.host.clone(),
+                    tls: info.tls,
+                    upgraded: info.upgraded.load(Ordering::Relaxed),
                 }
             })
             .collect()
     }
 
     /// Remove connections that have been open longer than `max_age`.
+    /// Upgraded connections are long-lived by design and are left alone.
     pub fn cleanup_stale(&self, max_age: Duration) {
         let now = Instant::now();
         let mut removed: u64 = 0;
 
         self.active.retain(|_id, info| {
-            let alive = now.duration_since(info.connected_at) < max_age;
+            let alive = info.upgraded.load(Ordering::Relaxed)
+                || now.duration_since(info.connected_at) < max_age;
             if !alive {
                 removed += 1;
             }
@@ -166,3 +406,52 @@ impl Default for ConnectionTracker {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_cancels_connections_of_an_ip() {
+        let tracker = ConnectionTracker::new();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+        let a = tracker.register(ip, None, true);
+        let b = tracker.register(ip, None, true);
+        let other = tracker.register("198.51.100.5".parse().unwrap(), None, true);
+
+        assert_eq!(tracker.close_by_ip(&ip), 2);
+        assert!(tracker.close_token(a).unwrap().is_cancelled());
+        assert!(tracker.close_token(b).unwrap().is_cancelled());
+        assert!(!tracker.close_token(other).unwrap().is_cancelled());
+        assert!(!tracker.close(999));
+    }
+
+    #[test]
+    fn test_bandwidth_is_summed_per_ip_and_throttled_past_the_cap() {
+        let tracker = ConnectionTracker::new();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+        let a = tracker.register(ip, None, true);
+        let b = tracker.register(ip, None, true);
+        let other: IpAddr = "198.51.100.5".parse().unwrap();
+        tracker.register(other, None, true);
+
+        tracker.update_bytes(a, ip, 60_000, 1_000);
+        tracker.update_bytes(b, ip, 40_000, 0);
+        tracker.update_bytes(99, other, 10_000, 0);
+        assert_eq!(tracker.bandwidth(&ip), (10_000, 100));
+
+        let top = tracker.top_bandwidth(10);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].client_ip, ip);
+        assert_eq!(top[0].connections, 2);
+        assert_eq!(tracker.top_bandwidth(1).len(), 1);
+
+        // The bucket holds one second of the cap, shared by every
+        // connection of the IP; writes past it wait for the refill.
+        assert_eq!(tracker.throttle_delay(ip, 60_000, 100_000), Duration::ZERO);
+        assert_eq!(tracker.throttle_delay(ip, 30_000, 100_000), Duration::ZERO);
+        let wait = tracker.throttle_delay(ip, 60_000, 100_000);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        assert_eq!(tracker.throttle_delay(other, 50_000, 0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::warn;
+
+/// Hop-by-hop headers (RFC 9110 §7.6.1). They describe the client
+/// connection, not the message, so per-service rules may neither add nor
+/// remove them; the proxy manages them itself.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Values available to header templates.
+pub struct HeaderVars<'a> {
+    pub client_ip: IpAddr,
+    pub ray_id: &'a str,
+    pub country: Option<&'a str>,
+    pub host: &'a str,
+}
+
+impl HeaderVars<'_> {
+    /// Expand `{client_ip}`, `{ray_id}`, `{country}` and `{host}` in a header
+    /// value. Unknown placeholders are left as written; an unknown country
+    /// expands to an empty string.
+    pub fn expand(&self, template: &str) -> String {
+        if !template.contains('{') {
+            return template.to_string();
+        }
+        template
+            .replace("{client_ip}", &self.client_ip.to_string())
+            .replace("{ray_id}", self.ray_id)
+            .replace("{country}", self.country.unwrap_or(""))
+            .replace("{host}", self.host)
+    }
+}
+
+/// Apply a service's header rules: drop `remove`, then set each of `add`
+/// (replacing any existing value). Hop-by-hop headers are never touched,
+/// and names or expanded values that aren't valid HTTP are skipped.
+pub fn apply(
+    headers: &mut HeaderMap,
+    remove: &[String],
+    add: &HashMap<String, String>,
+    vars: &HeaderVars<'_>,
+) {
+    for name in remove {
+        if is_hop_by_hop(name) {
+            continue;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.trim().as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    for (name, template) in add {
+        if is_hop_by_hop(name) {
+            continue;
+        }
+        let Ok(name) = HeaderName::from_bytes(name.trim().as_bytes()) else {
+            warn!(header = %name, "Skipping header rule with an invalid name");
+            continue;
+        };
+        match HeaderValue::from_str(&vars.expand(template)) {
+            Ok(value) => {
+                headers.insert(name, value);
+            }
+            Err(_) => warn!(header = %name, "Skipping header rule with an invalid value"),
+        }
+    }
+}
+
+/// Check a set of header rules before they are saved, returning a message
+/// naming the first offending header.
+pub fn validate(remove: &[String], add: &HashMap<String, String>) -> Result<(), String> {
+    for name in remove.iter().chain(add.keys()) {
+        if HeaderName::from_bytes(name.trim().as_bytes()).is_err() {
+            return Err(format!("invalid header name: {:?}", name));
+        }
+        if is_hop_by_hop(name) {
+            return Err(format!("hop-by-hop header {:?} cannot be rewritten", name));
+        }
+    }
+    for (name, value) in add {
+        if HeaderValue::from_str(value).is_err() {
+            return Err(format!("invalid value for header {:?}", name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_expand_templates_and_keep_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("keep-alive"));
+        headers.insert("x-powered-by", HeaderValue::from_static("php"));
+        headers.insert("x-request-start", HeaderValue::from_static("client"));
+
+        let remove = vec!["X-Powered-By".to_string(), "Connection".to_string()];
+        let add = HashMap::from([
+            ("X-Request-Start".to_string(), "ray={ray_id} ip={client_ip} cc={country}".to_string()),
+            ("Transfer-Encoding".to_string(), "chunked".to_string()),
+        ]);
+        let vars = HeaderVars {
+            client_ip: "203.0.113.7".parse().unwrap(),
+            ray_id: "abc123",
+            country: Some("DE"),
+            host: "example.com",
+        };
+        apply(&mut headers, &remove, &add, &vars);
+
+        assert!(headers.get("x-powered-by").is_none());
+        assert_eq!(headers["connection"], "keep-alive");
+        assert!(headers.get("transfer-encoding").is_none());
+        assert_eq!(headers["x-request-start"], "ray=abc123 ip=203.0.113.7 cc=DE");
+        assert!(validate(&remove, &HashMap::new()).is_err());
+    }
+}
@@ -1,82 +1,254 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use futures_util::future::join_all;
+use hyper::{Request, Uri};
+use serde::Serialize;
 use tokio::net::TcpStream;
-use tracing::{debug, info, warn};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
 
-use crate::proxy::service_router::ServiceRouter;
+use crate::analytics::alerting::{AlertManager, Severity};
+use crate::config::service::{upstream_base_url, upstream_socket_addr, HealthProbe, ServiceConfig, StatusMatch};
+use crate::config::settings::{HealthCheckConfig, SharedSettings};
+use crate::proxy::service_router::{CheckOutcome, ServiceRouter};
 
-/// Periodic TCP health checker for upstream backends.
+use super::http_handler::empty_body;
+use super::upstream::UpstreamClients;
+
+/// Health check settings in effect for one service: its own
+/// `health_check` with unset fields taken from `upstream.health_check`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthCheckPlan {
+    pub probe: HealthProbe,
+    /// Path and accepted statuses of `http` probes.
+    pub path: Option<String>,
+    pub host: Option<String>,
+    pub expected_status: Vec<StatusMatch>,
+    pub interval_secs: u64,
+    pub timeout_ms: u64,
+    pub healthy_threshold: u32,
+    pub unhealthy_threshold: u32,
+}
+
+impl HealthCheckPlan {
+    pub fn for_service(svc: &ServiceConfig, global: &HealthCheckConfig) -> Self {
+        let own = svc.health_check.as_ref();
+        let probe = own.map_or(HealthProbe::Tcp, |c| c.probe);
+        let http = own.filter(|_| probe == HealthProbe::Http);
+        Self {
+            probe,
+            path: http.map(|c| c.path.clone()),
+            host: http.and_then(|c| c.host.clone()),
+            expected_status: http.map(|c| c.expected_status.clone()).unwrap_or_default(),
+            interval_secs: own.and_then(|c| c.interval_secs).unwrap_or(global.interval_secs).max(1),
+            timeout_ms: own.and_then(|c| c.timeout_ms).unwrap_or(global.timeout_ms),
+            healthy_threshold: own.and_then(|c| c.healthy_threshold).unwrap_or(global.healthy_threshold),
+            unhealthy_threshold: own.and_then(|c| c.unhealthy_threshold).unwrap_or(global.unhealthy_threshold),
+        }
+    }
+
+    fn accepts(&self, status: u16) -> bool {
+        self.expected_status.iter().any(|s| s.matches(status))
+    }
+}
+
+/// Periodic health checker for upstream backends.
 ///
-/// Every `interval` seconds, attempts a TCP connection to each registered
-/// service's upstream address. If the connection fails, the service is
-/// marked unhealthy and requests will receive a 503.
+/// Each service is checked on its own schedule with the settings of
+/// [`HealthCheckPlan`]: a TCP connect, or an HTTP `GET` whose status must be
+/// one of the expected ones. A backend is taken out of rotation after
+/// `unhealthy_threshold` failed checks in a row and returns after
+/// `healthy_threshold` successes. Both transitions raise an alert.
+#[derive(Clone)]
 pub struct HealthChecker {
     service_router: Arc<ServiceRouter>,
-    interval: Duration,
-    timeout: Duration,
+    alerting: Arc<AlertManager>,
+    settings: SharedSettings,
+    upstream_clients: Arc<UpstreamClients>,
 }
 
 impl HealthChecker {
-    pub fn new(service_router: Arc<ServiceRouter>, interval_secs: u64, timeout_ms: u64) -> Self {
+    pub fn new(
+        service_router: Arc<ServiceRouter>,
+        alerting: Arc<AlertManager>,
+        settings: SharedSettings,
+        upstream_clients: Arc<UpstreamClients>,
+    ) -> Self {
         Self {
             service_router,
-            interval: Duration::from_secs(interval_secs),
-            timeout: Duration::from_millis(timeout_ms),
+            alerting,
+            settings,
+            upstream_clients,
         }
     }
 
-    /// Run the health check loop forever.
+    /// Run the health check loop forever: every second, services without
+    /// a check loop of their own (new ones included) get one.
     pub async fn run(&self) {
-        let mut interval = tokio::time::interval(self.interval);
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
         interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut loops: HashMap<String, JoinHandle<()>> = HashMap::new();
 
         loop {
             interval.tick().await;
-            self.check_all().await;
+            loops.retain(|_, handle| !handle.is_finished());
+            for svc in self.service_router.list_services() {
+                if !loops.contains_key(&svc.id) {
+                    let checker = self.clone();
+                    let id = svc.id.clone();
+                    loops.insert(svc.id.clone(), tokio::spawn(async move { checker.run_service(id).await }));
+                }
+            }
         }
     }
 
-    /// Check all registered services.
-    async fn check_all(&self) {
-        let services = self.service_router.list_services();
-
-        for svc in &services {
-            let addr = &svc.upstream_address;
-            let healthy = match tokio::time::timeout(
-                self.timeout,
-                TcpStream::connect(addr),
-            )
-            .await
-            {
-                Ok(Ok(_)) => true,
-                Ok(Err(e)) => {
-                    warn!(
-                        service = %svc.name,
-                        upstream = %addr,
-                        error = %e,
-                        "Health check failed"
-                    );
-                    false
-                }
-                Err(_) => {
-                    warn!(
-                        service = %svc.name,
-                        upstream = %addr,
-                        "Health check timed out"
-                    );
-                    false
-                }
-            };
+    /// Check one service every `interval_secs` until it is removed. The
+    /// plan is read again before each round, so edits apply from the next.
+    async fn run_service(&self, service_id: String) {
+        while let Some(svc) = self.service_router.get_service(&service_id) {
+            let plan = HealthCheckPlan::for_service(&svc, &self.settings.load().upstream.health_check);
+            self.check_service(&svc, &plan).await;
+            tokio::time::sleep(Duration::from_secs(plan.interval_secs)).await;
+        }
+    }
 
-            self.service_router.set_health(&svc.id, healthy);
+    /// Probe every upstream of a service at once and record the results.
+    async fn check_service(&self, svc: &ServiceConfig, plan: &HealthCheckPlan) {
+        let upstreams = svc.all_upstreams();
+        let outcomes = join_all(upstreams.iter().map(|addr| self.probe(svc, addr, plan))).await;
+
+        for (addr, outcome) in upstreams.into_iter().zip(outcomes) {
+            if let Err(ref err) = outcome.result {
+                warn!(service = %svc.name, upstream = %addr, error = %err, "Health check failed");
+            }
+            let healthy = outcome.result.is_ok();
+            if self.service_router.record_check(
+                &svc.id,
+                addr,
+                &outcome,
+                plan.healthy_threshold,
+                plan.unhealthy_threshold,
+            ) {
+                self.alert_health_change(&svc.id, &svc.name, addr, healthy);
+            }
 
             debug!(
                 service = %svc.name,
                 upstream = %addr,
-                healthy = healthy,
+                ok = healthy,
+                status = outcome.status,
+                latency_ms = outcome.latency.as_millis() as u64,
                 "Health check completed"
             );
         }
     }
+
+    async fn probe(&self, svc: &ServiceConfig, addr: &str, plan: &HealthCheckPlan) -> CheckOutcome {
+        let started = Instant::now();
+        let timeout = Duration::from_millis(plan.timeout_ms);
+        let attempt = async {
+            match plan.probe {
+                HealthProbe::Tcp => TcpStream::connect(upstream_socket_addr(addr))
+                    .await
+                    .map(|_| None)
+                    .map_err(|e| e.to_string()),
+                HealthProbe::Http => self.http_status(svc, addr, plan).await.map(Some),
+            }
+        };
+        let (result, status) = match tokio::time::timeout(timeout, attempt).await {
+            Ok(Ok(Some(status))) if plan.accepts(status) => (Ok(()), Some(status)),
+            Ok(Ok(Some(status))) => (Err(format!("unexpected status {}", status)), Some(status)),
+            Ok(Ok(None)) => (Ok(()), None),
+            Ok(Err(err)) => (Err(err), None),
+            Err(_) => (Err(format!("timed out after {}ms", plan.timeout_ms)), None),
+        };
+        CheckOutcome {
+            result,
+            status,
+            latency: started.elapsed(),
+        }
+    }
+
+    /// `GET` the probe path from `addr` with the client proxied requests
+    /// to the service use, and return the response status.
+    async fn http_status(&self, svc: &ServiceConfig, addr: &str, plan: &HealthCheckPlan) -> Result<u16, String> {
+        let settings = self.settings.load_full();
+        let (client, _) = self.upstream_clients.for_upstream(Some(svc), None, &settings.upstream, addr);
+        let path = plan.path.as_deref().unwrap_or("/");
+        let uri: Uri = format!("{}{}", upstream_base_url(addr), path)
+            .parse()
+            .map_err(|e| format!("invalid probe URL: {}", e))?;
+        let mut builder = Request::get(uri).header(hyper::header::USER_AGENT, "Fortress-HealthCheck");
+        if let Some(host) = plan.host.as_deref() {
+            builder = builder.header(hyper::header::HOST, host);
+        }
+        let req = builder.body(empty_body()).map_err(|e| e.to_string())?;
+        let resp = client.request(req).await.map_err(|e| e.to_string())?;
+        Ok(resp.status().as_u16())
+    }
+
+    fn alert_health_change(&self, service_id: &str, service_name: &str, addr: &str, healthy: bool) {
+        if healthy {
+            let msg = format!("Upstream {} of service {} is back up", addr, service_name);
+            let key = format!("upstream_up:{}:{}", service_id, addr);
+            self.alerting.notify("upstream_up", &key, Severity::Info, msg);
+            return;
+        }
+
+        // Critical once the service has no healthy backend left.
+        let any_healthy = self
+            .service_router
+            .backend_status(service_id)
+            .iter()
+            .any(|b| b.healthy);
+        let (severity, msg) = if any_healthy {
+            (Severity::Warning, format!("Upstream {} of service {} is down", addr, service_name))
+        } else {
+            (Severity::Critical, format!("Upstream {} of service {} is down; no healthy upstreams left", addr, service_name))
+        };
+        let key = format!("upstream_down:{}:{}", service_id, addr);
+        self.alerting.notify("upstream_down", &key, severity, msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::defaults::default_health_check_config;
+
+    #[test]
+    fn test_service_settings_override_the_global_ones() {
+        let global = default_health_check_config();
+        let mut svc: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "app", "name": "App", "domains": ["app.test"], "upstream_address": "web:80",
+        }))
+        .unwrap();
+
+        let plan = HealthCheckPlan::for_service(&svc, &global);
+        assert_eq!(plan.probe, HealthProbe::Tcp);
+        assert_eq!(plan.path, None);
+        assert_eq!(plan.interval_secs, global.interval_secs);
+        assert_eq!(plan.unhealthy_threshold, global.unhealthy_threshold);
+
+        svc.health_check = Some(
+            serde_json::from_value(serde_json::json!({
+                "probe": "http", "path": "/healthz", "expected_status": [200, "300-302", 401],
+                "interval_secs": 3, "unhealthy_threshold": 2,
+            }))
+            .unwrap(),
+        );
+        svc.health_check.as_ref().unwrap().validate().unwrap();
+        let plan = HealthCheckPlan::for_service(&svc, &global);
+        assert_eq!(plan.path.as_deref(), Some("/healthz"));
+        assert_eq!((plan.interval_secs, plan.timeout_ms), (3, global.timeout_ms));
+        assert_eq!(plan.unhealthy_threshold, 2);
+        assert!(plan.accepts(302) && plan.accepts(401));
+        assert!(!plan.accepts(303) && !plan.accepts(500));
+
+        let bad: crate::config::service::ServiceHealthCheck =
+            serde_json::from_value(serde_json::json!({"probe": "http", "expected_status": ["2xx"]})).unwrap();
+        assert!(bad.validate().is_err());
+    }
 }
@@ -1,27 +1,63 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::net::IpAddr;
-use std::sync::Arc;
-
-use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::body::Incoming;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::HeaderMap;
+use hyper::upgrade::OnUpgrade;
 use hyper::{Request, Response, StatusCode};
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::client::legacy::Client as HyperClient;
-use hyper_util::rt::TokioExecutor;
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+use tokio::time::{Instant, Sleep};
 use tracing::{debug, error, info, warn};
 
-use crate::analytics::collector::MetricsCollector;
-use crate::config::settings::Settings;
+use crate::analytics::challenge_stats::{ChallengeFlow, ChallengeSlot, Verification};
+use crate::analytics::collector::{Bypass, MetricsCollector};
+use crate::analytics::capture::RequestCapture;
+use crate::analytics::live_tail::LiveTail;
+use crate::analytics::request_samples::RequestSampler;
+use crate::config::service::{upstream_base_url, RequestEncodingPolicy, ServiceConfig, WaitingRoomConfig};
+use crate::config::settings::{BandwidthCapAction, BodyInspectionConfig, Settings, SharedSettings};
 use crate::models::request::RequestContext;
-use crate::models::threat::{ThreatAction, ProtectionLevel};
-use crate::protection::challenge::ChallengeSystem;
-use crate::protection::pipeline::ProtectionPipeline;
-use crate::proxy::service_router::ServiceRouter;
+use crate::models::threat::{ThreatAction, ThreatReason, ProtectionLevel};
+use crate::protection::challenge::{ChallengeSystem, ClearanceScope};
+use crate::protection::framing::validate_framing;
+use crate::protection::pipeline::{PipelineResult, ProtectionPipeline};
+use crate::protection::slowloris::SlowlorisDetector;
+use crate::proxy::service_router::{Admission, BackendLease, ServiceRouter};
 use crate::storage::memory::MemoryStore;
 
-use super::access_log::AccessLogger;
-use super::connection::ConnectionTracker;
+use super::access_log::{AccessLogEntry, AccessLogger};
+use super::compression::encoded_page;
+use super::connection::{ConnectionTracker, BANDWIDTH_WINDOW_SECS};
+use super::header_rules::{self, HeaderVars};
+use super::request_encoding::{self, Coding, DecodeLimits, EncodedBodyError};
+use super::response_cache::{CacheKey, CachingBody, ResponseCache};
+use super::tarpit::{Tarpit, TarpitBody};
+use super::tls::FortressCertResolver;
+use super::upstream::{UpstreamClient, UpstreamClients, UpstreamProtocol};
+use super::waiting_room::{self, Ticket, WaitingRoom};
+use super::websocket::WebSocketProxy;
+
+/// Upper bound on the `/__fortress/verify` and `/__fortress/verify-interactive`
+/// form bodies.
+const MAX_VERIFY_FORM_SIZE: usize = 8 * 1024;
+
+/// `Retry-After` sent with the maintenance page.
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 300;
+
+/// Served in maintenance mode when the service has no page of its own.
+const MAINTENANCE_PAGE: &str = "<!DOCTYPE html>\
+    <html><head><meta charset=\"utf-8\"><title>Down for maintenance</title></head>\
+    <body><h1>Down for maintenance</h1>\
+    <p>We are performing scheduled maintenance and will be back shortly.</p>\
+    <hr><p>Fortress Anti-DDoS Proxy</p></body></html>";
 
 /// Core HTTP request handler for the Fortress reverse proxy.
 ///
@@ -40,44 +76,40 @@ pub struct HttpHandler {
     memory: Arc<MemoryStore>,
     connections: Arc<ConnectionTracker>,
     metrics: Arc<MetricsCollector>,
-    settings: Arc<Settings>,
+    settings: SharedSettings,
     challenge: Arc<ChallengeSystem>,
-    upstream_client: HyperClient<HttpConnector, Full<Bytes>>,
+    upstream_clients: Arc<UpstreamClients>,
     access_log: Option<Arc<AccessLogger>>,
+    request_sampler: Arc<RequestSampler>,
+    capture: Arc<RequestCapture>,
+    live_tail: Arc<LiveTail>,
+    tarpit: Arc<Tarpit>,
+    cache: Arc<ResponseCache>,
+    waiting_room: Arc<WaitingRoom>,
+    /// Certificates served over HTTPS; `readyz` needs at least one.
+    cert_resolver: OnceLock<Arc<FortressCertResolver>>,
 }
 
 impl HttpHandler {
     /// Create a new handler.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         pipeline: Arc<ProtectionPipeline>,
         service_router: Arc<ServiceRouter>,
         memory: Arc<MemoryStore>,
         connections: Arc<ConnectionTracker>,
         metrics: Arc<MetricsCollector>,
-        settings: Arc<Settings>,
+        settings: SharedSettings,
         challenge: Arc<ChallengeSystem>,
+        upstream_clients: Arc<UpstreamClients>,
+        access_log: Option<Arc<AccessLogger>>,
+        request_sampler: Arc<RequestSampler>,
+        capture: Arc<RequestCapture>,
+        live_tail: Arc<LiveTail>,
+        tarpit: Arc<Tarpit>,
+        cache: Arc<ResponseCache>,
+        waiting_room: Arc<WaitingRoom>,
     ) -> Self {
-        let upstream_client = HyperClient::builder(TokioExecutor::new())
-            .pool_idle_timeout(std::time::Duration::from_secs(30))
-            .pool_max_idle_per_host(128)
-            .build_http();
-
-        // Initialise the per-request access logger (best-effort).
-        let access_log = if !settings.logging.access_log.is_empty() {
-            match AccessLogger::new(&settings.logging.access_log) {
-                Ok(logger) => {
-                    info!("Access log enabled: {}", settings.logging.access_log);
-                    Some(Arc::new(logger))
-                }
-                Err(e) => {
-                    error!("Failed to open access log {}: {}", settings.logging.access_log, e);
-                    None
-                }
-            }
-        } else {
-            None
-        };
-
         Self {
             pipeline,
             service_router,
@@ -86,20 +118,153 @@ impl HttpHandler {
             metrics,
             settings,
             challenge,
-            upstream_client,
+            upstream_clients,
             access_log,
+            request_sampler,
+            capture,
+            live_tail,
+            tarpit,
+            cache,
+            waiting_room,
+            cert_resolver: OnceLock::new(),
+        }
+    }
+
+    pub fn set_cert_resolver(&self, resolver: Arc<FortressCertResolver>) {
+        let _ = self.cert_resolver.set(resolver);
+    }
+
+    /// Answer the `/__fortress/healthz` and `/__fortress/readyz` probes with
+    /// a status and JSON body; `None` for any other path or with
+    /// `server.probes.enabled` off.
+    ///
+    /// `healthz` only says the listener is accepting requests. `readyz`
+    /// also needs a loaded certificate when HTTPS is served and, with
+    /// `require_healthy_upstream`, one service with a healthy upstream.
+    pub fn probe(&self, path: &str) -> Option<(StatusCode, String)> {
+        let settings = self.settings.load();
+        if !settings.server.probes.enabled {
+            return None;
+        }
+        match path {
+            "/__fortress/healthz" => Some((StatusCode::OK, r#"{"status":"ok"}"#.to_string())),
+            "/__fortress/readyz" => {
+                let tls = !settings.server.mode.serves_https() || self.cert_resolver.get().is_some_and(|r| r.certificate_count() > 0);
+                let upstreams = !settings.server.probes.require_healthy_upstream || {
+                    let services = self.service_router.list_services();
+                    let mut with_upstream = services.iter().filter(|s| !s.all_upstreams().is_empty()).peekable();
+                    with_upstream.peek().is_none() || with_upstream.any(|s| self.service_router.is_healthy(&s.id))
+                };
+                let (status, label) = if tls && upstreams {
+                    (StatusCode::OK, "ready")
+                } else {
+                    (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+                };
+                Some((
+                    status,
+                    format!(r#"{{"status":"{}","tls":{},"upstreams":{}}}"#, label, tls, upstreams),
+                ))
+            }
+            _ => None,
         }
     }
 
-    /// Process a single inbound HTTP request end-to-end.
+    /// Whether `ip` is blocked or tarpitted by the IP blocklist and not
+    /// whitelisted, for listeners that answer without running the pipeline.
+    pub fn is_ip_blocked(&self, ip: &IpAddr) -> bool {
+        !self.settings.load().protection.whitelist.contains(ip)
+            && self.pipeline.blocklist.check_ip(ip).is_some_and(|(action, _)| action.is_blocking())
+    }
+
+    pub fn metrics(&self) -> &MetricsCollector {
+        &self.metrics
+    }
+
+    /// Count a request answered before the protection pipeline ran:
+    /// `"blocked"` for malformed ones, `"passed"` for maintenance pages
+    /// and challenge answers.
+    fn record_early(
+        &self,
+        ip: IpAddr,
+        ja3: Option<&str>,
+        service: Option<&ServiceConfig>,
+        action: &str,
+        start: std::time::Instant,
+    ) {
+        let elapsed_us = start.elapsed().as_micros() as u64;
+        self.metrics.record_request(ip, None, None, ja3, action, elapsed_us);
+        if let Some(svc) = service {
+            self.metrics.record_service_request(&svc.id, action, elapsed_us);
+        }
+    }
+
+    /// Process a single inbound HTTP request end-to-end. Every response,
+    /// whether proxied or generated by Fortress, carries the request's ray
+    /// ID in `X-Fortress-Ray` and is counted against the client IP's
+    /// bandwidth as it is written (see [`MeteredBody`]).
     pub async fn handle(
         &self,
         req: Request<Incoming>,
         client_ip: IpAddr,
         ja3_hash: Option<String>,
         conn_id: u64,
-    ) -> Response<Full<Bytes>> {
+    ) -> Response<ProxyBody> {
+        let ray_id = new_ray_id(conn_id);
+        let real_ip = extract_client_ip(&req, client_ip, self.settings.load().cloudflare.enabled);
+        let mut resp = self.process(req, client_ip, ja3_hash, conn_id, &ray_id).await;
+        if let Ok(value) = hyper::header::HeaderValue::from_str(&ray_id) {
+            resp.headers_mut().insert("x-fortress-ray", value);
+        }
+        let cap = self.bandwidth_cap(&real_ip, &self.settings.load());
+        resp.map(|body| {
+            MeteredBody {
+                inner: body,
+                connections: Arc::clone(&self.connections),
+                conn_id,
+                client_ip: real_ip,
+                cap,
+                pending: None,
+                delay: None,
+            }
+            .boxed()
+        })
+    }
+
+    /// Bytes/s response writes to `ip` are throttled to; `0` when
+    /// `protection.max_bytes_per_ip_per_sec` is off, set to reject instead
+    /// or `ip` is whitelisted.
+    fn bandwidth_cap(&self, ip: &IpAddr, settings: &Settings) -> u64 {
+        let protection = &settings.protection;
+        if protection.bandwidth_cap_action != BandwidthCapAction::Throttle || protection.whitelist.contains(ip) {
+            return 0;
+        }
+        protection.max_bytes_per_ip_per_sec
+    }
+
+    /// Whether new requests from `ip` are refused because the rolling rate
+    /// of bytes sent to it is over `protection.max_bytes_per_ip_per_sec`.
+    fn over_bandwidth_cap(&self, ip: &IpAddr, settings: &Settings) -> bool {
+        let protection = &settings.protection;
+        if protection.max_bytes_per_ip_per_sec == 0
+            || protection.bandwidth_cap_action != BandwidthCapAction::Reject
+            || protection.whitelist.contains(ip)
+        {
+            return false;
+        }
+        let (sent, _) = self.connections.bandwidth(ip);
+        sent > protection.max_bytes_per_ip_per_sec
+    }
+
+    async fn process(
+        &self,
+        mut req: Request<Incoming>,
+        client_ip: IpAddr,
+        ja3_hash: Option<String>,
+        conn_id: u64,
+        ray_id: &str,
+    ) -> Response<ProxyBody> {
         let start = std::time::Instant::now();
+        let settings = self.settings.load_full();
 
         // Track the request.
         self.connections.increment_requests(conn_id);
@@ -108,6 +273,7 @@ impl HttpHandler {
         let method = req.method().to_string();
         let path = req.uri().path().to_string();
         let query_string = req.uri().query().map(|q| q.to_string());
+        let protocol = format!("{:?}", req.version());
         let host = req
             .headers()
             .get("host")
@@ -120,20 +286,73 @@ impl HttpHandler {
             self.connections.set_host(conn_id, host.clone());
         }
 
+        // --- Health probes ---
+        // Answered before service resolution: never challenged or forwarded.
+        if let Some((status, body)) = self.probe(&path) {
+            let response = Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", "no-store")
+                .body(full_body(body))
+                .unwrap();
+            let probes = &settings.server.probes;
+            if probes.metrics || probes.access_log {
+                let real_ip = extract_client_ip(&req, client_ip, settings.cloudflare.enabled);
+                let elapsed_us = start.elapsed().as_micros() as u64;
+                if probes.metrics {
+                    self.metrics.record_request(real_ip, None, None, None, "passed", elapsed_us);
+                }
+                if probes.access_log {
+                    let user_agent = req.headers().get("user-agent").and_then(|v| v.to_str().ok()).unwrap_or("");
+                    let entry = AccessLogEntry {
+                        client_ip: real_ip,
+                        method: &method,
+                        path: &path,
+                        host: &host,
+                        protocol: &protocol,
+                        status: status.as_u16(),
+                        action: "probe",
+                        reason: None,
+                        latency_us: elapsed_us,
+                        bytes: response.body().size_hint().lower(),
+                        country: None,
+                        asn: None,
+                        ja3: ja3_hash.as_deref(),
+                        user_agent,
+                        referer: None,
+                        ray_id,
+                        request_id: None,
+                        service_id: None,
+                        route: None,
+                    };
+                    if let Some(ref logger) = self.access_log {
+                        logger.log(&entry);
+                    }
+                    self.live_tail.record(&entry, 0.0);
+                }
+            }
+            return response;
+        }
+
         // Resolve service from Host header
+        let real_ip = extract_client_ip(&req, client_ip, settings.cloudflare.enabled);
         let resolved_service = self.service_router.resolve(&host);
-        let upstream_addr = match &resolved_service {
-            Some(svc) if svc.enabled => svc.upstream_address.clone(),
-            Some(_) => {
+        // Requests answered before the pipeline runs are still counted
+        let early = |action: &str, service: Option<&ServiceConfig>| {
+            self.record_early(real_ip, ja3_hash.as_deref(), service, action, start);
+        };
+        let service_id = match &resolved_service {
+            Some(svc) if svc.enabled => Some(svc.id.clone()),
+            Some(svc) => {
+                early("passed", Some(svc));
                 return Response::builder()
                     .status(503)
-                    .body(Full::new(Bytes::from("Service Unavailable")))
+                    .body(full_body("Service Unavailable"))
                     .unwrap();
             }
-            None => self.service_router.default_upstream(),
+            None => None,
         };
-
-        let real_ip = extract_client_ip(&req, client_ip, self.settings.cloudflare.enabled);
+        let service = resolved_service.as_deref();
         let user_agent = req
             .headers()
             .get("user-agent")
@@ -149,15 +368,82 @@ impl HttpHandler {
             "Incoming request"
         );
 
+        // --- Bandwidth cap ---
+        if self.over_bandwidth_cap(&real_ip, &settings) {
+            debug!(client_ip = %real_ip, "Request refused, IP over its bandwidth cap");
+            early("blocked", service);
+            return bandwidth_exceeded();
+        }
+
+        // --- Maintenance mode ---
+        // Answered before the pipeline so crawlers see a plain 503, never
+        // a challenge. Exempt paths (health checks, webhooks) still pass.
+        if let Some(svc) = resolved_service.as_deref().filter(|s| s.maintenance_mode) {
+            if !svc.is_exempt_path(&method, &path) {
+                debug!(client_ip = %real_ip, service = %svc.id, "Serving maintenance page");
+                early("passed", service);
+                return maintenance_page(svc.maintenance_html_path.as_deref()).await;
+            }
+        }
+
+        // --- Unhealthy service ---
+        // With every upstream failing its health checks, `fail_fast` answers
+        // with the maintenance page instead of waiting on a connect error.
+        if let Some(svc) = resolved_service.as_deref() {
+            if settings.upstream.health_check.fail_fast()
+                && !svc.all_upstreams().is_empty()
+                && !self.service_router.is_healthy(&svc.id)
+                && !svc.is_exempt_path(&method, &path)
+            {
+                debug!(client_ip = %real_ip, service = %svc.id, "No healthy upstream, serving maintenance page");
+                early("passed", service);
+                return maintenance_page(svc.maintenance_html_path.as_deref()).await;
+            }
+        }
+
         // --- Internal endpoints ---
+        let challenge_slot = ChallengeSlot {
+            level: self.pipeline.level_for(resolved_service.as_deref()),
+            service: resolved_service.as_deref().map(|svc| svc.id.as_str()),
+        };
         if path == "/__fortress/nojs-verify" {
             let query = req.uri().query().unwrap_or("").to_string();
-            return self.handle_nojs_verification(&query, real_ip);
+            let scope = self.clearance_scope(&host, resolved_service.as_deref(), conn_id);
+            early("passed", service);
+            return self.handle_nojs_verification(&query, real_ip, &scope, challenge_slot);
         }
 
-        if path == "/__fortress/verify" {
-            let query = req.uri().query().unwrap_or("").to_string();
-            return self.handle_challenge_verification(&query, real_ip);
+        if path == "/__fortress/verify" || path == "/__fortress/verify-interactive" {
+            if method != "POST" {
+                early("blocked", service);
+                return Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header("Allow", "POST")
+                    .body(full_body("Method Not Allowed"))
+                    .unwrap();
+            }
+            let form = match Limited::new(req.into_body(), MAX_VERIFY_FORM_SIZE).collect().await {
+                Ok(collected) => String::from_utf8_lossy(&collected.to_bytes()).into_owned(),
+                Err(_) => {
+                    early("blocked", service);
+                    return payload_too_large();
+                }
+            };
+            let scope = self.clearance_scope(&host, resolved_service.as_deref(), conn_id);
+            early("passed", service);
+            if path == "/__fortress/verify-interactive" {
+                return self.handle_interactive_verification(&form, real_ip, &scope, challenge_slot);
+            }
+            return self.handle_challenge_verification(&form, real_ip, &scope, challenge_slot);
+        }
+
+        // --- Validate framing ---
+        // A body length the upstream could read differently from us is a
+        // request smuggling attempt; such requests are never forwarded.
+        if let Err(err) = validate_framing(req.headers()) {
+            warn!(client_ip = %real_ip, reason = err.as_str(), "Rejected request with ambiguous framing");
+            early("blocked", service);
+            return bad_request();
         }
 
         // --- Collect headers as HashMap ---
@@ -171,46 +457,38 @@ impl HttpHandler {
                 )
             })
             .collect();
-
-        // CORS preflight requests: still run blocklist and rate limit checks,
-        // but skip the full challenge pipeline to avoid breaking preflight flow.
-        if method == "OPTIONS" {
-            // Check if IP is blocked
-            if let Some(_entry) = self.pipeline.memory.is_blocked(&real_ip) {
-                debug!(client_ip = %real_ip, "OPTIONS request blocked by IP blocklist");
-                return forbidden();
-            }
-            if self.pipeline.blocklist.check_ip(&real_ip).is_some() {
-                debug!(client_ip = %real_ip, "OPTIONS request blocked by blocklist");
-                return forbidden();
-            }
-            if let Some(reason) = self.pipeline.auto_ban.is_banned(&real_ip) {
-                debug!(client_ip = %real_ip, reason = %reason, "OPTIONS request blocked by auto-ban");
-                return forbidden();
-            }
-            debug!(client_ip = %real_ip, path = %path, "CORS preflight - passed security checks");
-            return self.forward_to_backend(
-                &method,
-                &path,
-                query_string.as_deref(),
-                &host,
-                &headers,
-                Bytes::new(),
-                real_ip,
-                &upstream_addr,
-            ).await;
-        }
+        // A client-supplied request ID is kept and logged next to the ray ID.
+        let request_id = headers.get("x-request-id").filter(|id| !id.is_empty()).cloned();
 
         // --- Build RequestContext ---
-        let mut ctx = RequestContext::new(real_ip, method.clone(), path.clone(), host.clone());
-        ctx.is_behind_cloudflare = self.settings.cloudflare.enabled && crate::protection::cloudflare::is_cloudflare_ip(client_ip);
+        // Rules see the normalized path; the upstream gets `path` as sent.
+        let mut ctx = match RequestContext::from_raw(real_ip, method.clone(), &path, &host) {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                warn!(client_ip = %real_ip, path = %path, reason = err.as_str(), "Rejected request with invalid path");
+                early("blocked", service);
+                return bad_request();
+            }
+        };
+        ctx.is_behind_cloudflare = settings.cloudflare.enabled && crate::protection::cloudflare::is_cloudflare_ip(client_ip);
         ctx.ja3_hash = ja3_hash.clone();
+        ctx.ray_id = Some(ray_id.to_string());
+        ctx.query = query_string.clone();
         ctx.user_agent = if user_agent.is_empty() {
             None
         } else {
             Some(user_agent.clone())
         };
         ctx.headers = headers.clone();
+        ctx.header_map = req.headers().clone();
+
+        // --- Route within the service ---
+        let route = resolved_service.as_deref().and_then(|svc| {
+            let index = svc.route_for(&host, &ctx.path)?;
+            let id = svc.routes[index].id.clone();
+            debug!(service_id = %svc.id, route = %id, path = %ctx.path, "Request matched service route");
+            Some((index, id))
+        });
 
         // Use Cloudflare's country header when available (more accurate than GeoIP for CF traffic)
         if ctx.is_behind_cloudflare {
@@ -220,49 +498,226 @@ impl HttpHandler {
                 }
             }
         }
+        ctx.fallback_country = settings.geoip.fallback_country(client_ip, &headers);
 
-        // --- Run protection pipeline (NOT async) ---
-        let pipeline_result = self.pipeline.process(&mut ctx, &self.settings, resolved_service.as_deref());
+        // --- Detach the request body ---
+        // Nothing is read until the pipeline has decided: passed requests are
+        // streamed to the upstream, rejected ones are never read. Services
+        // with body inspection are the exception, see below.
+        let request_size = req
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let client_upgrade = if WebSocketProxy::is_websocket_upgrade(&req) {
+            Some(hyper::upgrade::on(&mut req))
+        } else {
+            None
+        };
+        let mut body = self.rate_checked_body(req.into_body(), real_ip, &settings);
+        // Chunked bodies carry no Content-Length, so the limit is also
+        // enforced on the bytes as they are read.
+        let body_limit = max_body_size(&settings, resolved_service.as_deref());
+        if let Some(limit) = body_limit {
+            body = Limited::new(body, limit).boxed();
+        }
 
-        // --- Consume the request body ---
-        let body_bytes = match req.into_body().collect().await {
-            Ok(collected) => collected.to_bytes(),
-            Err(err) => {
-                warn!("Failed to read request body: {}", err);
-                Bytes::new()
+        // --- Request Content-Encoding ---
+        // Services that reject encoded bodies answer 415 without reading
+        // them; services that inspect them decode the whole body below.
+        let encoding_policy = resolved_service.as_deref().map(|svc| svc.request_encoding).unwrap_or_default();
+        let mut encoding_rejection = None;
+        let mut codings = Vec::new();
+        if encoding_policy != RequestEncodingPolicy::Forward && !body.is_end_stream() {
+            match headers.get("content-encoding").map(|v| request_encoding::parse_codings(v)) {
+                Some(Ok(c)) if c.is_empty() => {}
+                Some(Ok(c)) if encoding_policy == RequestEncodingPolicy::Inspect => codings = c,
+                Some(_) => encoding_rejection = Some(EncodedBodyError::Unsupported),
+                None => {}
+            }
+        }
+
+        // --- Body inspection and capture ---
+        // The first `max_bytes` are buffered for the body rules in the
+        // pipeline and replayed ahead of the rest of the body upstream. A
+        // running request capture that matches gets its excerpt the same
+        // way, without handing it to the body rules. Encoded bodies are
+        // buffered whole and the body rules get the start of the decoded
+        // bytes; the original bytes are forwarded. Login attempts covered by
+        // credential stuffing detection are read the same way for their
+        // username, whether or not the service inspects bodies.
+        let inspection = &settings.protection.body_inspection;
+        let content_type = headers.get("content-type").map(String::as_str);
+        let inspect = resolved_service.as_deref().is_some_and(|svc| svc.body_inspection)
+            && !body.is_end_stream()
+            && inspection.applies(&method, &ctx.path, content_type);
+        let stuffing = &settings.protection.credential_stuffing;
+        let login = !body.is_end_stream() && stuffing.applies(&method, &ctx.path);
+        let capture_bytes = self
+            .capture
+            .body_bytes_wanted(real_ip, &path)
+            .filter(|_| !body.is_end_stream());
+        let mut captured_body = None;
+        if inspect || login || !codings.is_empty() || capture_bytes.is_some() {
+            let inspect_bytes = if inspect { inspection.max_bytes } else { 0 };
+            let plain_bytes = if login { inspection.max_bytes } else { inspect_bytes };
+            let mut read_bytes = plain_bytes.max(capture_bytes.unwrap_or(0));
+            if !codings.is_empty() {
+                // One byte over the cap tells an oversized body apart
+                read_bytes = read_bytes.max(inspection.max_decompressed_bytes.saturating_add(1));
             }
+            let read_timeout = Duration::from_secs(inspection.read_timeout_secs);
+            match tokio::time::timeout(read_timeout, read_body_sample(body, read_bytes)).await {
+                Ok(Ok((sample, replay))) => {
+                    let mut plain = None;
+                    if !codings.is_empty() {
+                        match decode_request_body(sample.clone(), codings, inspection, plain_bytes).await {
+                            Ok(decoded) => plain = Some(decoded),
+                            Err(err) => encoding_rejection = Some(err),
+                        }
+                    } else if plain_bytes > 0 {
+                        plain = Some(sample.slice(..sample.len().min(plain_bytes)));
+                    }
+                    if let Some(plain) = plain {
+                        if login {
+                            let field = resolved_service
+                                .as_deref()
+                                .and_then(|svc| svc.login_username_field.as_deref())
+                                .unwrap_or(&stuffing.username_field);
+                            ctx.login_user = self.pipeline.credential_stuffing.username_hash(
+                                &plain,
+                                content_type.unwrap_or(""),
+                                field,
+                            );
+                        }
+                        if inspect {
+                            ctx.body = Some(plain.slice(..plain.len().min(inspect_bytes)));
+                        }
+                    }
+                    if let Some(capture_bytes) = capture_bytes {
+                        captured_body = Some(sample.slice(..sample.len().min(capture_bytes.max(inspect_bytes))));
+                    }
+                    body = replay;
+                }
+                Ok(Err(err)) if is_oversized_body(err.as_ref()) => {
+                    early("blocked", service);
+                    return payload_too_large();
+                }
+                // The client stalled or went away before the sample was read.
+                Ok(Err(err)) => {
+                    debug!(client_ip = %real_ip, error = %err, "Failed to read request body sample");
+                    early("blocked", service);
+                    return request_timeout();
+                }
+                Err(_) => {
+                    debug!(client_ip = %real_ip, "Timed out reading request body sample");
+                    early("blocked", service);
+                    return request_timeout();
+                }
+            }
+        }
+
+        // --- Run protection pipeline (NOT async) ---
+        // CORS preflights cannot follow a challenge; when exempt they only
+        // get the blocklist, auto-ban and rate-limit checks.
+        let is_preflight = method == "OPTIONS";
+        let pipeline_result = if encoding_rejection.is_some() {
+            let result = PipelineResult::block(ThreatReason::EncodedBody, 0.0);
+            self.pipeline.publish_decision(&ctx, resolved_service.as_deref(), &result, None);
+            result
+        } else if is_preflight && settings.protection.exempt_cors_preflight {
+            self.pipeline.process_preflight(&mut ctx, &settings, resolved_service.as_deref())
+        } else {
+            self.pipeline.process(&mut ctx, &settings, resolved_service.as_deref())
         };
 
-        // Generate a unique ray ID for this request
-        let ray_id = format!(
-            "{:016x}",
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_nanos() as u64
-                ^ (conn_id << 32)
-        );
+        // --- Waiting room ---
+        // Passed visitors without clearance queue while the service's
+        // upstreams are saturated, and get clearance when let in.
+        let ticket = match resolved_service.as_deref() {
+            Some(svc) if pipeline_result.action == ThreatAction::Pass && !is_preflight => {
+                self.waiting_room_ticket(svc, real_ip, &host, conn_id, headers.get("cookie").map(String::as_str))
+            }
+            _ => Ticket::Pass,
+        };
+        let queue_page = match (&ticket, resolved_service.as_deref().and_then(|svc| svc.waiting_room.as_ref())) {
+            (Ticket::Wait { place, set_cookie }, Some(config)) => {
+                Some(waiting_room_response(*place, set_cookie.as_deref(), config))
+            }
+            _ => None,
+        };
 
         // --- Act on pipeline result ---
+        // Services with CORS origins get their preflights answered here
+        let cors_origins = resolved_service
+            .as_deref()
+            .map(|svc| svc.cors_allowed_origins.as_slice())
+            .filter(|origins| !origins.is_empty());
         let response = match pipeline_result.action {
+            ThreatAction::Block if encoding_rejection.is_some() => {
+                let err = encoding_rejection.unwrap_or(EncodedBodyError::Unsupported);
+                info!(client_ip = %real_ip, path = %path, ray_id = %ray_id, error = ?err, "Encoded request body blocked");
+                drop(body);
+                self.metrics.record_encoded_body(err.is_aborted());
+                encoded_body_rejected(err)
+            }
+            ThreatAction::Pass if is_preflight && cors_origins.is_some() => {
+                debug!(client_ip = %real_ip, path = %path, "Answering CORS preflight");
+                drop(body);
+                cors_preflight_response(cors_origins.unwrap_or_default(), &headers)
+            }
+            ThreatAction::Pass if body_limit.is_some_and(|limit| request_size > limit as u64) => {
+                debug!(client_ip = %real_ip, size = request_size, "Request body over the size limit");
+                drop(body);
+                payload_too_large()
+            }
+            ThreatAction::Pass if queue_page.is_some() => {
+                debug!(client_ip = %real_ip, ray_id = %ray_id, "Visitor queued in the waiting room");
+                drop(body);
+                queue_page.unwrap_or_else(forbidden)
+            }
             ThreatAction::Pass => {
-                debug!(client_ip = %real_ip, "Request passed protection pipeline");
-                self.forward_to_backend(
+                debug!(client_ip = %real_ip, ray_id = %ray_id, "Request passed protection pipeline");
+                let vars = HeaderVars {
+                    client_ip: real_ip,
+                    ray_id,
+                    country: ctx.country_code.as_deref(),
+                    host: &host,
+                };
+                let mut resp = self.forward_to_backend(
                     &method,
                     &path,
                     query_string.as_deref(),
                     &host,
-                    &headers,
-                    body_bytes.clone(),
-                    real_ip,
-                    &upstream_addr,
+                    &ctx.header_map,
+                    body,
+                    &vars,
+                    service_id.as_deref(),
+                    route.as_ref().map(|(index, _)| *index),
                 )
-                .await
+                .await;
+                if let Some(client_upgrade) = client_upgrade {
+                    self.start_websocket_relay(client_upgrade, &mut resp, conn_id, real_ip);
+                }
+                if ticket == Ticket::Admit {
+                    debug!(client_ip = %real_ip, "Visitor let in from the waiting room");
+                    let scope = self.clearance_scope(&host, resolved_service.as_deref(), conn_id);
+                    let clearance = self.challenge.generate_clearance_cookie(&real_ip, &scope);
+                    for cookie in [clearance, waiting_room::clear_queue_cookie()] {
+                        if let Ok(value) = hyper::header::HeaderValue::from_str(&cookie) {
+                            resp.headers_mut().append(hyper::header::SET_COOKIE, value);
+                        }
+                    }
+                }
+                resp
             }
             ThreatAction::Challenge => {
-                info!(client_ip = %real_ip, path = %path, "Challenge issued");
+                info!(client_ip = %real_ip, path = %path, ray_id = %ray_id, "Challenge issued");
+                // Unread, so hyper closes the connection after the response
+                drop(body);
                 // Detect API/webhook requests - return JSON instead of HTML challenge
-                let is_api = is_api_request(&path, &headers);
+                let is_api = is_api_request(&ctx.path, &headers);
                 if is_api {
                     info!(client_ip = %real_ip, path = %path, "API request challenged - returning JSON 403");
                     Response::builder()
@@ -270,45 +725,41 @@ impl HttpHandler {
                         .header("Content-Type", "application/json")
                         .header("Cache-Control", "no-store")
                         .header("X-Fortress-Protected", "true")
-                        .body(Full::new(Bytes::from(
+                        .body(full_body(
                             r#"{"error":"blocked","message":"Request blocked by security policy","code":1020}"#
-                        )))
+                        ))
                         .unwrap()
                 } else if let Some(html) = pipeline_result.challenge_html {
-                    Response::builder()
-                        .status(StatusCode::OK)
-                        .header("Content-Type", "text/html; charset=utf-8")
-                        .header("Cache-Control", "no-store")
-                        .header("X-Fortress-Protected", "true")
-                        .body(Full::new(Bytes::from(html)))
-                        .unwrap()
+                    self.metrics.challenges().record_issued(challenge_slot);
+                    challenge_page(html, headers.get("accept-encoding").map(String::as_str))
                 } else {
                     forbidden()
                 }
             }
             ThreatAction::Block => {
                 info!(client_ip = %real_ip, path = %path, ray_id = %ray_id, "Request blocked");
-                if is_api_request(&path, &headers) {
+                drop(body);
+                if is_api_request(&ctx.path, &headers) {
                     Response::builder()
                         .status(StatusCode::FORBIDDEN)
                         .header("Content-Type", "application/json")
                         .header("Cache-Control", "no-store")
                         .header("X-Fortress-Protected", "true")
-                        .header("X-Fortress-Ray", ray_id.as_str())
-                        .body(Full::new(Bytes::from(format!(
+                        .body(full_body(format!(
                             r#"{{"error":"blocked","message":"Request blocked by security policy","code":1020,"ray":"{}"}}"#,
                             ray_id
-                        ))))
+                        )))
                         .unwrap()
                 } else {
-                    forbidden_with_details(real_ip, &ray_id)
+                    forbidden_with_details(real_ip, ray_id, headers.get("accept-encoding").map(String::as_str))
                 }
             }
             ThreatAction::Tarpit => {
                 info!(client_ip = %real_ip, path = %path, ray_id = %ray_id, "Request tarpitted");
-                // Sleep before responding to waste the attacker's resources
-                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                forbidden_with_details(real_ip, &ray_id)
+                // Unread, so hyper closes the connection once the drip ends
+                drop(body);
+                let cfg = &settings.protection.tarpit;
+                tarpit_response(self.tarpit.body(cfg.drip_bytes(), cfg.max_concurrent))
             }
         };
 
@@ -320,35 +771,60 @@ impl HttpHandler {
             ThreatAction::Challenge => "challenged",
             ThreatAction::Block | ThreatAction::Tarpit => "blocked",
         };
+        // Bypasses are told apart in the metrics; the access log keeps "passed"
+        let metrics_action = pipeline_result.bypass.map_or(action_str, Bypass::as_str);
         self.metrics.record_request(
             real_ip,
             ctx.country_code.as_deref(),
             ctx.asn,
             ctx.ja3_hash.as_deref(),
-            action_str,
+            metrics_action,
             elapsed_us,
         );
+        if let Some(ref svc) = resolved_service {
+            self.metrics.record_service_request(&svc.id, metrics_action, elapsed_us);
+        }
 
-        // Track bytes (approximate).
-        let resp_size = body_bytes.len() as u64;
+        // Track request bytes; the response is counted as it is written,
+        // see `MeteredBody`. The log reports its size as far as it is known.
+        let resp_size = response.body().size_hint().lower();
         self.connections
-            .update_bytes(conn_id, resp_size, body_bytes.len() as u64);
-
-        // --- Access log ---
+            .update_bytes(conn_id, real_ip, 0, request_size);
+
+        // --- Access log and request samples ---
+        let entry = AccessLogEntry {
+            client_ip: real_ip,
+            method: &method,
+            path: &path,
+            host: &host,
+            protocol: &protocol,
+            status: response.status().as_u16(),
+            action: action_str,
+            reason: pipeline_result.reason,
+            latency_us: elapsed_us,
+            bytes: resp_size,
+            country: ctx.country_code.as_deref(),
+            asn: ctx.asn,
+            ja3: ctx.ja3_hash.as_deref(),
+            user_agent: &user_agent,
+            referer: ctx.headers.get("referer").map(|s| s.as_str()),
+            ray_id,
+            request_id: request_id.as_deref(),
+            service_id: service_id.as_deref(),
+            route: route.as_ref().map(|(_, id)| id.as_str()),
+        };
         if let Some(ref logger) = self.access_log {
-            logger.log(
-                real_ip,
-                &method,
-                &path,
-                &host,
-                response.status().as_u16(),
-                action_str,
-                elapsed_us,
-                &user_agent,
-                ctx.country_code.as_deref(),
-                &ray_id,
-            );
+            logger.log(&entry);
         }
+        self.live_tail.record(&entry, pipeline_result.score);
+        self.request_sampler.record(&entry);
+        self.capture.record(
+            &entry,
+            query_string.as_deref(),
+            &ctx.header_map,
+            captured_body.as_deref().or(ctx.body.as_deref()),
+            pipeline_result.reason,
+        );
 
         response
     }
@@ -357,21 +833,58 @@ impl HttpHandler {
     // Challenge verification
     // -----------------------------------------------------------------------
 
+    /// Clearance scope for a request; cookies issued on plain-HTTP
+    /// connections are not marked `Secure`.
+    fn clearance_scope(&self, host: &str, service: Option<&ServiceConfig>, conn_id: u64) -> ClearanceScope {
+        let scope = ClearanceScope::new(host, service);
+        if self.connections.is_tls(conn_id) {
+            scope
+        } else {
+            scope.over_plain_http()
+        }
+    }
+
+    /// Waiting room decision for a request to `svc` the pipeline passed.
+    /// Clients with clearance never queue.
+    fn waiting_room_ticket(
+        &self,
+        svc: &ServiceConfig,
+        ip: IpAddr,
+        host: &str,
+        conn_id: u64,
+        cookies: Option<&str>,
+    ) -> Ticket {
+        let level = self.pipeline.level_for(Some(svc));
+        if !self.waiting_room.is_active(svc, level) {
+            return Ticket::Pass;
+        }
+        let scope = self.clearance_scope(host, Some(svc), conn_id);
+        if self.challenge.has_valid_clearance(&ip, cookies, &scope) {
+            return Ticket::Pass;
+        }
+        let in_flight = self.service_router.upstream_load(&svc.id).map_or(0, |load| load.in_flight);
+        self.waiting_room.check(svc, level, in_flight, &ip, cookies, self.connections.is_tls(conn_id))
+    }
+
+    /// Handle the POSTed PoW solution. `form` is the
+    /// `application/x-www-form-urlencoded` request body.
     fn handle_challenge_verification(
         &self,
-        query: &str,
+        form: &str,
         client_ip: IpAddr,
-    ) -> Response<Full<Bytes>> {
+        scope: &ClearanceScope,
+        slot: ChallengeSlot<'_>,
+    ) -> Response<ProxyBody> {
         let mut challenge = None;
         let mut nonce = None;
         let mut redirect = String::from("/");
         let mut hl_score: u32 = 0;
 
-        for param in query.split('&') {
+        for param in form.split('&') {
             if let Some(val) = param.strip_prefix("challenge=") {
                 challenge = Some(url_decode(val));
             } else if let Some(val) = param.strip_prefix("nonce=") {
-                nonce = Some(val.to_string());
+                nonce = Some(url_decode(val));
             } else if let Some(val) = param.strip_prefix("redirect=") {
                 redirect = url_decode(val);
             } else if let Some(val) = param.strip_prefix("hl=") {
@@ -385,7 +898,7 @@ impl HttpHandler {
                 warn!(client_ip = %client_ip, "Challenge verification: missing challenge param");
                 return Response::builder()
                     .status(StatusCode::BAD_REQUEST)
-                    .body(Full::new(Bytes::from("Missing challenge")))
+                    .body(full_body("Missing challenge"))
                     .unwrap();
             }
         };
@@ -396,34 +909,100 @@ impl HttpHandler {
                 warn!(client_ip = %client_ip, "Challenge verification: missing nonce param");
                 return Response::builder()
                     .status(StatusCode::BAD_REQUEST)
-                    .body(Full::new(Bytes::from("Missing nonce")))
+                    .body(full_body("Missing nonce"))
                     .unwrap();
             }
         };
 
-        // Verify the PoW solution against the current protection level's difficulty
-        let protection_level = self.pipeline.escalation.current_level();
-        if !self.challenge.verify_solution(&challenge, &nonce, &protection_level) {
+        // Verify the signed, single-use PoW solution at its issued difficulty
+        if !self.challenge.verify_solution(&challenge, &nonce) {
             warn!(client_ip = %client_ip, "Challenge verification: invalid PoW solution");
+            self.metrics.challenges().record_verification(slot, ChallengeFlow::Pow, Verification::Invalid);
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(full_body("Verification failed"))
+                .unwrap();
+        }
+
+        let issued_at = ChallengeSystem::issued_at(&challenge).unwrap_or_default();
+        self.grant_clearance(client_ip, scope, hl_score, redirect, slot, ChallengeFlow::Pow, issued_at)
+    }
+
+    /// Handle the POSTed token of the interactive challenge page. `form` is
+    /// the `application/x-www-form-urlencoded` request body.
+    fn handle_interactive_verification(
+        &self,
+        form: &str,
+        client_ip: IpAddr,
+        scope: &ClearanceScope,
+        slot: ChallengeSlot<'_>,
+    ) -> Response<ProxyBody> {
+        let mut token = None;
+        let mut redirect = String::from("/");
+        let mut hl_score: u32 = 0;
+
+        for param in form.split('&') {
+            if let Some(val) = param.strip_prefix("token=") {
+                token = Some(url_decode(val));
+            } else if let Some(val) = param.strip_prefix("redirect=") {
+                redirect = url_decode(val);
+            } else if let Some(val) = param.strip_prefix("hl=") {
+                hl_score = val.parse::<u32>().unwrap_or(0);
+            }
+        }
+
+        let Some(token) = token.filter(|t| !t.is_empty()) else {
+            warn!(client_ip = %client_ip, "Interactive verification: missing token param");
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full_body("Missing token"))
+                .unwrap();
+        };
+
+        if !self.challenge.verify_interactive(&token, &client_ip) {
+            warn!(client_ip = %client_ip, "Interactive verification: invalid token");
+            self.metrics.challenges().record_verification(slot, ChallengeFlow::Interactive, Verification::Invalid);
             return Response::builder()
                 .status(StatusCode::FORBIDDEN)
                 .header("Content-Type", "text/html; charset=utf-8")
-                .body(Full::new(Bytes::from("Verification failed")))
+                .body(full_body("Verification failed"))
                 .unwrap();
         }
 
+        let issued_at = ChallengeSystem::issued_at(&token).unwrap_or_default();
+        self.grant_clearance(client_ip, scope, hl_score, redirect, slot, ChallengeFlow::Interactive, issued_at)
+    }
+
+    /// Issue the clearance cookie for a passed challenge and redirect back to
+    /// the challenged URL, unless the page's headless check flagged the
+    /// browser. `issued_at` is the time signed into the solved token.
+    #[allow(clippy::too_many_arguments)]
+    fn grant_clearance(
+        &self,
+        client_ip: IpAddr,
+        scope: &ClearanceScope,
+        hl_score: u32,
+        redirect: String,
+        slot: ChallengeSlot<'_>,
+        flow: ChallengeFlow,
+        issued_at: i64,
+    ) -> Response<ProxyBody> {
         // Headless browser detection check
         if hl_score >= 40 {
             warn!(client_ip = %client_ip, hl_score = hl_score, "Challenge verification: headless browser detected");
+            self.metrics.challenges().record_verification(slot, flow, Verification::Headless);
             return Response::builder()
                 .status(StatusCode::FORBIDDEN)
                 .header("Content-Type", "text/html; charset=utf-8")
-                .body(Full::new(Bytes::from("Verification failed")))
+                .body(full_body("Verification failed"))
                 .unwrap();
         }
 
         // Generate signed clearance cookie
-        let cookie = self.challenge.generate_clearance_cookie(&client_ip);
+        self.challenge.record_solved(&client_ip);
+        self.metrics.challenges().record_verification(slot, flow, Verification::Solved { issued_at });
+        let cookie = self.challenge.generate_clearance_cookie(&client_ip, scope);
 
         info!(client_ip = %client_ip, "Challenge verified, clearance cookie issued");
 
@@ -441,7 +1020,7 @@ impl HttpHandler {
             .header("Set-Cookie", cookie)
             .header("Cache-Control", "no-store")
             .header("X-Fortress-Protected", "true")
-            .body(Full::new(Bytes::from("Redirecting...")))
+            .body(full_body("Redirecting..."))
             .unwrap()
     }
 
@@ -454,7 +1033,9 @@ impl HttpHandler {
         &self,
         query: &str,
         client_ip: IpAddr,
-    ) -> Response<Full<Bytes>> {
+        scope: &ClearanceScope,
+        slot: ChallengeSlot<'_>,
+    ) -> Response<ProxyBody> {
         let mut token = None;
         let mut sig = None;
 
@@ -472,7 +1053,7 @@ impl HttpHandler {
                 warn!(client_ip = %client_ip, "Nojs verification: missing token param");
                 return Response::builder()
                     .status(StatusCode::FORBIDDEN)
-                    .body(Full::new(Bytes::from("Invalid token")))
+                    .body(full_body("Invalid token"))
                     .unwrap();
             }
         };
@@ -482,22 +1063,26 @@ impl HttpHandler {
                 warn!(client_ip = %client_ip, "Nojs verification: missing sig param");
                 return Response::builder()
                     .status(StatusCode::FORBIDDEN)
-                    .body(Full::new(Bytes::from("Missing signature")))
+                    .body(full_body("Missing signature"))
                     .unwrap();
             }
         };
 
         // Verify token and signature
-        if !self.challenge.verify_nojs_token(&token, &sig) {
+        if !self.challenge.verify_nojs_token(&token, &sig, &client_ip) {
             warn!(client_ip = %client_ip, "Nojs verification: invalid token or signature");
+            self.metrics.challenges().record_verification(slot, ChallengeFlow::Nojs, Verification::Invalid);
             return Response::builder()
                 .status(StatusCode::FORBIDDEN)
-                .body(Full::new(Bytes::from("Verification failed")))
+                .body(full_body("Verification failed"))
                 .unwrap();
         }
 
         // Issue clearance cookie and redirect to homepage
-        let cookie = self.challenge.generate_clearance_cookie(&client_ip);
+        self.challenge.record_solved(&client_ip);
+        let issued_at = ChallengeSystem::issued_at(&token).unwrap_or_default();
+        self.metrics.challenges().record_verification(slot, ChallengeFlow::Nojs, Verification::Solved { issued_at });
+        let cookie = self.challenge.generate_clearance_cookie(&client_ip, scope);
 
         info!(client_ip = %client_ip, "Nojs challenge verified, clearance cookie issued");
 
@@ -507,7 +1092,7 @@ impl HttpHandler {
             .header("Set-Cookie", cookie)
             .header("Cache-Control", "no-store")
             .header("X-Fortress-Protected", "true")
-            .body(Full::new(Bytes::from("Redirecting...")))
+            .body(full_body("Redirecting..."))
             .unwrap()
     }
 
@@ -515,21 +1100,113 @@ impl HttpHandler {
     // Backend forwarding (connection-pooled via hyper client)
     // -----------------------------------------------------------------------
 
+    /// Forward to the service's upstream. For `always_online` services a
+    /// failure (any 5xx, from the upstream or ours) is answered with the
+    /// last good copy of the page when there is one. `route` indexes the
+    /// service's routes.
+    #[allow(clippy::too_many_arguments)]
     async fn forward_to_backend(
         &self,
         method: &str,
         path: &str,
         query: Option<&str>,
         host: &str,
-        headers: &HashMap<String, String>,
-        body: Bytes,
-        client_ip: IpAddr,
-        upstream_addr: &str,
-    ) -> Response<Full<Bytes>> {
-        let uri = match query {
-            Some(q) => format!("http://{}{}?{}", upstream_addr, path, q),
-            None => format!("http://{}{}", upstream_addr, path),
+        headers: &HeaderMap,
+        body: ProxyBody,
+        vars: &HeaderVars<'_>,
+        service_id: Option<&str>,
+        route: Option<usize>,
+    ) -> Response<ProxyBody> {
+        let service = service_id
+            .and_then(|id| self.service_router.get_service(id))
+            .filter(|svc| svc.always_online);
+        let stale_key = service
+            .as_ref()
+            .and_then(|_| self.cache.stale_key_for(method, host, path, query, headers));
+        let resp = self
+            .forward_upstream(method, path, query, host, headers, body, vars, service_id, route, stale_key.as_ref())
+            .await;
+        if !resp.status().is_server_error() {
+            return resp;
+        }
+        let (Some(svc), Some(key)) = (service.as_deref(), stale_key.as_ref()) else {
+            return resp;
         };
+        let Some(mut stale) = self.cache.lookup_stale(key, svc.always_online_banner) else {
+            return resp;
+        };
+        debug!(service_id = %svc.id, host, path, status = resp.status().as_u16(), "Upstream failed, serving stale page");
+        header_rules::apply(
+            stale.headers_mut(),
+            &svc.remove_response_headers,
+            &svc.add_response_headers,
+            vars,
+        );
+        stale
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn forward_upstream(
+        &self,
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        host: &str,
+        headers: &HeaderMap,
+        body: ProxyBody,
+        vars: &HeaderVars<'_>,
+        service_id: Option<&str>,
+        route: Option<usize>,
+        stale_key: Option<&CacheKey>,
+    ) -> Response<ProxyBody> {
+        let settings = self.settings.load();
+        let service = service_id.and_then(|id| self.service_router.get_service(id));
+        let route_config = route.and_then(|i| service.as_deref()?.routes.get(i));
+
+        // Cache hits skip the upstream, its concurrency limit and circuit
+        // breaker entirely.
+        let cache_key = self.cache.key_for(method, host, path, query, headers);
+        if let Some(mut resp) = cache_key.as_ref().and_then(|key| self.cache.lookup(key)) {
+            if let Some(svc) = service.as_deref() {
+                header_rules::apply(
+                    resp.headers_mut(),
+                    &svc.remove_response_headers,
+                    &svc.add_response_headers,
+                    vars,
+                );
+            }
+            return resp;
+        }
+
+        let breaker = &settings.upstream.circuit_breaker;
+        let permit = match service_id {
+            Some(id) => match self.service_router.admit(id, breaker) {
+                Admission::Admitted(permit) => permit,
+                Admission::Saturated => {
+                    debug!(service_id = %id, "Upstream concurrency limit reached");
+                    return upstream_unavailable(Duration::from_secs(1));
+                }
+                Admission::CircuitOpen(retry_after) => {
+                    debug!(service_id = %id, "Upstream circuit open, failing fast");
+                    return upstream_unavailable(retry_after);
+                }
+            },
+            None => None,
+        };
+        // Final outcome of the request, for the service's circuit breaker.
+        let record = |ok: bool| {
+            if let Some(id) = service_id {
+                self.service_router.record_upstream_result(id, ok, breaker);
+            }
+        };
+        // Time to the response headers across all attempts, for the
+        // origin health the escalation engine watches.
+        let started = Instant::now();
+        let observe = |error: bool| {
+            self.metrics.record_upstream(started.elapsed().as_micros() as u64, error);
+        };
+
+        let mut lease = self.select_backend(service_id, route);
 
         let parsed_method = match hyper::Method::from_bytes(method.as_bytes()) {
             Ok(m) => m,
@@ -539,71 +1216,816 @@ impl HttpHandler {
             }
         };
 
-        let mut builder = Request::builder().method(parsed_method).uri(&uri);
-
-        // Set required headers
-        builder = builder.header("Host", host);
-        builder = builder.header("X-Forwarded-For", client_ip.to_string());
-        builder = builder.header("X-Real-IP", client_ip.to_string());
-        builder = builder.header("X-Fortress-Protected", "true");
-
-        // Forward original headers, skipping hop-by-hop, headers we override,
-        // and Cloudflare-injected headers that confuse backend apps.
-        let skip_headers: &[&str] = &[
-            "host",
-            "x-forwarded-for",
-            "x-real-ip",
-            "x-forwarded-proto",
-            "x-forwarded-host",
-            "x-forwarded-port",
-            "transfer-encoding",
-            "connection",
-            // Cloudflare-specific headers – already consumed by Fortress
-            "cf-connecting-ip",
-            "cf-ipcountry",
-            "cf-ray",
-            "cf-visitor",
-            "cf-request-id",
-            "cf-warp-tag-id",
-            "cdn-loop",
-            "true-client-ip",
-        ];
-        for (name, value) in headers {
-            let lower = name.to_lowercase();
-            if skip_headers.contains(&lower.as_str()) {
-                continue;
-            }
-            builder = builder.header(name.as_str(), value.as_str());
-        }
-
-        let upstream_req = match builder.body(Full::new(body)) {
-            Ok(r) => r,
-            Err(err) => {
-                error!("Failed to build upstream request: {}", err);
-                return bad_gateway();
+        // A connect failure means nothing reached the backend, so a request
+        // without a body can safely be replayed against another upstream of
+        // the service. A streamed body is consumed by the first attempt.
+        let replayable = body.is_end_stream();
+        let mut body = Some(body);
+        let max_attempts = service_id
+            .map(|id| self.service_router.backend_count(id, route))
+            .unwrap_or(1)
+            .max(1);
+        let mut attempt = 1;
+        // Bounds the wait for the response headers and every gap between
+        // body frames after that.
+        let response_timeout = Duration::from_millis(match service.as_deref() {
+            Some(svc) => route_config.and_then(|r| r.response_timeout_ms).unwrap_or(svc.response_timeout_ms),
+            None => settings.upstream.response_timeout_ms,
+        });
+        // All attempts together get one response timeout.
+        let deadline = (!response_timeout.is_zero()).then(|| Instant::now() + response_timeout);
+        let mut retries = 0;
+
+        let upstream_resp = loop {
+            let (upstream_client, protocol) =
+                self.upstream_clients
+                    .for_upstream(service.as_deref(), route_config, &settings.upstream, lease.address());
+            let base = upstream_base_url(lease.address());
+            let uri = match query {
+                Some(q) => format!("{}{}?{}", base, path, q),
+                None => format!("{}{}", base, path),
+            };
+            let upstream_req = match build_upstream_request(
+                parsed_method.clone(),
+                &uri,
+                host,
+                headers,
+                body.take().unwrap_or_else(empty_body),
+                service.as_deref(),
+                vars,
+            ) {
+                Ok(r) => r,
+                Err(err) => {
+                    error!("Failed to build upstream request: {}", err);
+                    return bad_gateway();
+                }
+            };
+
+            let remaining = deadline.map_or(Duration::ZERO, |d| {
+                d.saturating_duration_since(Instant::now()).max(Duration::from_millis(1))
+            });
+            match send_upstream(&upstream_client, upstream_req, remaining).await {
+                Ok(r) => {
+                    record(true);
+                    observe(r.status().is_server_error());
+                    break r;
+                }
+                Err(UpstreamError::Timeout) => {
+                    warn!(upstream = %lease.address(), timeout_ms = response_timeout.as_millis() as u64, "Backend response timed out");
+                    record(false);
+                    observe(true);
+                    return gateway_timeout();
+                }
+                Err(UpstreamError::Request(err)) if is_oversized_body(&err) => {
+                    return payload_too_large();
+                }
+                Err(UpstreamError::Request(err)) if is_slow_body(&err) => {
+                    // The client, not the backend, is at fault.
+                    return request_timeout();
+                }
+                Err(UpstreamError::Request(err)) if protocol == UpstreamProtocol::H2c && !err.is_connect() => {
+                    // The backend accepted the connection but not HTTP/2;
+                    // the next attempt speaks HTTP/1.1 to it.
+                    warn!(upstream = %lease.address(), error = %err, "HTTP/2 to backend failed, falling back to HTTP/1.1");
+                    self.upstream_clients.fall_back_to_http1(lease.address());
+                    if !replayable {
+                        return bad_gateway();
+                    }
+                }
+                Err(UpstreamError::Request(err)) => {
+                    error!(upstream = %lease.address(), error = %err, "Backend request failed");
+                    lease.mark_unhealthy();
+                    let failed = || if is_timeout(&err) { gateway_timeout() } else { bad_gateway() };
+                    if !replayable || !is_connection_error(&err) {
+                        record(false);
+                        observe(true);
+                        return failed();
+                    }
+                    if err.is_connect() && attempt < max_attempts {
+                        let next = self.select_backend(service_id, route);
+                        if next.address() != lease.address() {
+                            lease = next;
+                            attempt += 1;
+                            continue;
+                        }
+                    }
+
+                    // E.g. a pooled connection closed by a restarting
+                    // backend: retry idempotent requests after a backoff.
+                    let upstream = &settings.upstream;
+                    let backoff = Duration::from_millis(upstream.retry_backoff_ms)
+                        .saturating_mul(1 << retries.min(16));
+                    let in_budget = deadline.is_none_or(|d| Instant::now() + backoff < d);
+                    if retries >= upstream.retries || !upstream.may_retry(method) || !in_budget {
+                        record(false);
+                        observe(true);
+                        return failed();
+                    }
+                    retries += 1;
+                    self.upstream_clients.record_retry(lease.address());
+                    debug!(upstream = %lease.address(), retry = retries, backoff_ms = backoff.as_millis() as u64, "Retrying backend request");
+                    tokio::time::sleep(backoff).await;
+                    lease = self.select_backend(service_id, route);
+                }
             }
         };
 
-        let upstream_resp = match self.upstream_client.request(upstream_req).await {
-            Ok(r) => r,
-            Err(err) => {
-                error!(upstream = %upstream_addr, error = %err, "Backend request failed");
-                return bad_gateway();
+        // Stream the upstream body through frame by frame so large downloads
+        // stay out of memory and `text/event-stream` events are flushed as
+        // they arrive. The lease rides along so the backend counts as busy
+        // until the body finishes.
+        let (mut parts, incoming_body) = upstream_resp.into_parts();
+        let pending: Vec<_> = [
+            cache_key
+                .as_ref()
+                .and_then(|key| self.cache.admit(key, parts.status, &parts.headers)),
+            stale_key.and_then(|key| self.cache.admit_stale(key, parts.status, &parts.headers)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if let Some(svc) = service.as_deref() {
+            header_rules::apply(
+                &mut parts.headers,
+                &svc.remove_response_headers,
+                &svc.add_response_headers,
+                vars,
+            );
+        }
+        if cache_key.is_some() {
+            parts.headers.insert("x-fortress-cache", hyper::header::HeaderValue::from_static("MISS"));
+        }
+        let mut body = LeasedBody::new(incoming_body, lease, permit, response_timeout).boxed();
+        if !pending.is_empty() {
+            body = CachingBody::wrap(body, Arc::clone(&self.cache), pending);
+        }
+
+        Response::from_parts(parts, body)
+    }
+
+    /// Hand an upgraded connection over to the WebSocket relay once the
+    /// upstream has accepted the handshake with `101 Switching Protocols`.
+    fn start_websocket_relay(
+        &self,
+        client_upgrade: OnUpgrade,
+        resp: &mut Response<ProxyBody>,
+        conn_id: u64,
+        client_ip: IpAddr,
+    ) {
+        if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+            debug!(status = resp.status().as_u16(), "WebSocket: upstream declined upgrade");
+            return;
+        }
+        let upstream_upgrade = hyper::upgrade::on(resp);
+        self.connections.mark_upgraded(conn_id);
+        tokio::spawn(WebSocketProxy::relay(
+            client_upgrade,
+            upstream_upgrade,
+            Arc::clone(&self.connections),
+            conn_id,
+            client_ip,
+            self.bandwidth_cap(&client_ip, &self.settings.load()),
+        ));
+    }
+
+    /// Wrap a request body to enforce `server.min_body_rate_bytes_per_sec`
+    /// on it, whether it is streamed upstream, inspected or drained.
+    fn rate_checked_body(&self, body: Incoming, client_ip: IpAddr, settings: &Settings) -> ProxyBody {
+        let min_rate = settings.server.min_body_rate_bytes_per_sec;
+        if min_rate == 0 || body.is_end_stream() {
+            return body.map_err(Into::into).boxed();
+        }
+        MinRateBody::new(
+            body,
+            min_rate,
+            Duration::from_secs(settings.server.body_rate_grace_secs),
+            client_ip,
+            Arc::clone(&self.pipeline.slowloris),
+        )
+        .boxed()
+    }
+
+    /// Pick the upstream for a request: a backend of the resolved service
+    /// (of its `route`, if one matched), or the global default upstream
+    /// when no service matched.
+    fn select_backend(&self, service_id: Option<&str>, route: Option<usize>) -> BackendLease {
+        service_id
+            .and_then(|id| self.service_router.select_backend(id, route))
+            .unwrap_or_else(|| BackendLease::detached(self.service_router.default_upstream()))
+    }
+}
+
+/// A per-request ID shown on block pages and sent upstream and back to the
+/// client in `X-Fortress-Ray`.
+fn new_ray_id(conn_id: u64) -> String {
+    format!(
+        "{:016x}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+            ^ (conn_id << 32)
+    )
+}
+
+/// Why a request to the backend produced no response.
+enum UpstreamError {
+    /// No response headers within the response timeout.
+    Timeout,
+    Request(hyper_util::client::legacy::Error),
+}
+
+/// Send `req` and wait at most `timeout` (zero: no limit) for the response
+/// headers. The clock starts once the request body has been forwarded, so
+/// a slow upload – paced by [`MinRateBody`] – is not blamed on the backend.
+async fn send_upstream(
+    client: &UpstreamClient,
+    req: Request<ProxyBody>,
+    timeout: Duration,
+) -> Result<Response<Incoming>, UpstreamError> {
+    if timeout.is_zero() {
+        return client.request(req).await.map_err(UpstreamError::Request);
+    }
+    let (sent_tx, sent_rx) = oneshot::channel();
+    let req = if req.body().is_end_stream() {
+        let _ = sent_tx.send(());
+        req
+    } else {
+        req.map(|body| {
+            UploadBody {
+                inner: body,
+                sent: Some(sent_tx),
             }
+            .boxed()
+        })
+    };
+    let deadline = async {
+        // A dropped sender means the body failed, which fails the request.
+        let _ = sent_rx.await;
+        tokio::time::sleep(timeout).await;
+    };
+    tokio::select! {
+        result = client.request(req) => result.map_err(UpstreamError::Request),
+        _ = deadline => Err(UpstreamError::Timeout),
+    }
+}
+
+/// Build the request sent upstream, rewriting forwarding headers, dropping
+/// hop-by-hop and Cloudflare-specific ones and applying the service's
+/// header rules.
+fn build_upstream_request(
+    method: hyper::Method,
+    uri: &str,
+    host: &str,
+    headers: &HeaderMap,
+    body: ProxyBody,
+    service: Option<&ServiceConfig>,
+    vars: &HeaderVars<'_>,
+) -> Result<Request<ProxyBody>, hyper::http::Error> {
+    let client_ip = vars.client_ip;
+    let mut builder = Request::builder().method(method).uri(uri);
+
+    // Set required headers
+    builder = builder.header("Host", host);
+    builder = builder.header("X-Forwarded-For", client_ip.to_string());
+    builder = builder.header("X-Real-IP", client_ip.to_string());
+    builder = builder.header("X-Fortress-Protected", "true");
+    builder = builder.header("X-Fortress-Ray", vars.ray_id);
+    if !headers.contains_key("x-request-id") {
+        builder = builder.header("X-Request-Id", vars.ray_id);
+    }
+
+    // Forward original headers, skipping hop-by-hop, headers we override,
+    // and Cloudflare-injected headers that confuse backend apps.
+    let skip_headers: &[&str] = &[
+        "host",
+        "x-forwarded-for",
+        "x-real-ip",
+        "x-forwarded-proto",
+        "x-forwarded-host",
+        "x-forwarded-port",
+        "x-fortress-ray",
+        "transfer-encoding",
+        "connection",
+        // Cloudflare-specific headers – already consumed by Fortress
+        "cf-connecting-ip",
+        "cf-ipcountry",
+        "cf-ray",
+        "cf-visitor",
+        "cf-request-id",
+        "cf-warp-tag-id",
+        "cdn-loop",
+        "true-client-ip",
+    ];
+    // Every field is copied as received, repeats included, in order.
+    for (name, value) in headers {
+        if skip_headers.contains(&name.as_str()) {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+
+    // `Connection` is hop-by-hop and dropped above; restore it for WebSocket
+    // handshakes so the upstream sees the upgrade request.
+    if headers
+        .get_all(hyper::header::UPGRADE)
+        .iter()
+        .any(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+    {
+        builder = builder.header("Connection", "Upgrade");
+    }
+
+    let mut req = builder.body(body)?;
+    if let Some(svc) = service {
+        header_rules::apply(
+            req.headers_mut(),
+            &svc.remove_request_headers,
+            &svc.add_request_headers,
+            vars,
+        );
+    }
+    Ok(req)
+}
+
+// ---------------------------------------------------------------------------
+// Bodies
+// ---------------------------------------------------------------------------
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body type used for every proxied request and response.
+pub type ProxyBody = BoxBody<Bytes, BoxError>;
+
+/// A complete in-memory body (canned pages, challenge HTML, ...).
+pub fn full_body(data: impl Into<Bytes>) -> ProxyBody {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// An empty body.
+pub fn empty_body() -> ProxyBody {
+    full_body(Bytes::new())
+}
+
+/// Upstream response body that keeps its [`BackendLease`] and the service's
+/// in-flight slot while the client is still reading, and gives up when the
+/// backend goes quiet for longer than the response timeout.
+struct LeasedBody {
+    inner: Incoming,
+    lease: BackendLease,
+    _permit: Option<OwnedSemaphorePermit>,
+    idle_timeout: Duration,
+    /// `None` when `idle_timeout` is zero.
+    idle_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl LeasedBody {
+    fn new(
+        inner: Incoming,
+        lease: BackendLease,
+        permit: Option<OwnedSemaphorePermit>,
+        idle_timeout: Duration,
+    ) -> Self {
+        let idle_deadline =
+            (!idle_timeout.is_zero()).then(|| Box::pin(tokio::time::sleep(idle_timeout)));
+        Self {
+            inner,
+            lease,
+            _permit: permit,
+            idle_timeout,
+            idle_deadline,
+        }
+    }
+}
+
+impl Body for LeasedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                let next = Instant::now() + self.idle_timeout;
+                if let Some(deadline) = self.idle_deadline.as_mut() {
+                    deadline.as_mut().reset(next);
+                }
+                Poll::Ready(frame.map(|r| r.map_err(Into::into)))
+            }
+            Poll::Pending => {
+                let stalled = self
+                    .idle_deadline
+                    .as_mut()
+                    .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+                if !stalled {
+                    return Poll::Pending;
+                }
+                warn!(upstream = %self.lease.address(), timeout_ms = self.idle_timeout.as_millis() as u64, "Backend response body stalled");
+                Poll::Ready(Some(Err(UpstreamIdleError(self.idle_timeout).into())))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Response body that counts bytes against the connection and the client
+/// IP as frames are written and, with a non-zero `cap`, holds each frame
+/// back until the IP's token bucket allows it. Frames are never buffered
+/// beyond the one being delayed, so streamed responses stay streamed.
+struct MeteredBody {
+    inner: ProxyBody,
+    connections: Arc<ConnectionTracker>,
+    conn_id: u64,
+    client_ip: IpAddr,
+    cap: u64,
+    /// Frame waiting for `delay` to pass.
+    pending: Option<Frame<Bytes>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Body for MeteredBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => match Pin::new(&mut self.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => {
+                    let len = frame.data_ref().map_or(0, |data| data.len() as u64);
+                    let wait = self.connections.throttle_delay(self.client_ip, len, self.cap);
+                    if !wait.is_zero() {
+                        let mut delay = Box::pin(tokio::time::sleep(wait));
+                        if delay.as_mut().poll(cx).is_pending() {
+                            self.pending = Some(frame);
+                            self.delay = Some(delay);
+                            return Poll::Pending;
+                        }
+                    }
+                    frame
+                }
+                other => return other,
+            },
         };
+        if let Some(data) = frame.data_ref() {
+            self.connections
+                .update_bytes(self.conn_id, self.client_ip, data.len() as u64, 0);
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
 
-        // Convert Response<Incoming> to Response<Full<Bytes>>
-        let (parts, incoming_body) = upstream_resp.into_parts();
-        let body_bytes = match incoming_body.collect().await {
-            Ok(collected) => collected.to_bytes(),
-            Err(err) => {
-                error!("Failed to read backend response body: {}", err);
-                return bad_gateway();
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let pending = self
+            .pending
+            .as_ref()
+            .and_then(Frame::data_ref)
+            .map_or(0, |data| data.len() as u64);
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + pending);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + pending);
+        }
+        hint
+    }
+}
+
+/// Request body that fails once the client's average upload rate drops
+/// below `min_rate` bytes/s after the grace period. The violation is
+/// reported to the [`SlowlorisDetector`].
+struct MinRateBody {
+    inner: Incoming,
+    started: Instant,
+    received: u64,
+    min_rate: u64,
+    grace: Duration,
+    deadline: Pin<Box<Sleep>>,
+    client_ip: IpAddr,
+    slowloris: Arc<SlowlorisDetector>,
+}
+
+impl MinRateBody {
+    fn new(
+        inner: Incoming,
+        min_rate: u64,
+        grace: Duration,
+        client_ip: IpAddr,
+        slowloris: Arc<SlowlorisDetector>,
+    ) -> Self {
+        let started = Instant::now();
+        Self {
+            inner,
+            started,
+            received: 0,
+            min_rate,
+            grace,
+            deadline: Box::pin(tokio::time::sleep_until(started + grace)),
+            client_ip,
+            slowloris,
+        }
+    }
+
+    /// Latest time by which more data must arrive to stay above the rate.
+    fn next_deadline(&self) -> Instant {
+        self.started + self.grace + Duration::from_secs_f64(self.received as f64 / self.min_rate as f64)
+    }
+}
+
+impl Body for MinRateBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.received += data.len() as u64;
+                    let deadline = self.next_deadline();
+                    self.deadline.as_mut().reset(deadline);
+                }
+                Poll::Ready(Some(Ok(frame)))
             }
+            Poll::Ready(other) => Poll::Ready(other.map(|r| r.map_err(Into::into))),
+            Poll::Pending => {
+                if self.deadline.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                let err = SlowBodyError {
+                    received: self.received,
+                    elapsed: self.started.elapsed(),
+                };
+                debug!(client_ip = %self.client_ip, "{}", err);
+                self.slowloris.record_violation(self.client_ip, "slowloris_slow_body");
+                Poll::Ready(Some(Err(err.into())))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Decode a buffered request body off the async runtime, keeping the first
+/// `keep` decoded bytes for inspection. A body that filled the read limit is
+/// over the decoded size cap whatever it decodes to.
+async fn decode_request_body(
+    encoded: Bytes,
+    codings: Vec<Coding>,
+    inspection: &BodyInspectionConfig,
+    keep: usize,
+) -> Result<Bytes, EncodedBodyError> {
+    if encoded.len() > inspection.max_decompressed_bytes {
+        return Err(EncodedBodyError::TooLarge);
+    }
+    let limits = DecodeLimits {
+        max_output: inspection.max_decompressed_bytes,
+        max_ratio: inspection.max_expansion_ratio,
+        keep,
+    };
+    tokio::task::spawn_blocking(move || request_encoding::decode(&encoded, &codings, limits))
+        .await
+        .unwrap_or(Err(EncodedBodyError::Invalid))
+}
+
+/// Read `body` until `limit` bytes of data have arrived or it ends.
+/// Returns those bytes and a body that replays everything read so far
+/// before continuing with the rest.
+async fn read_body_sample(mut body: ProxyBody, limit: usize) -> Result<(Bytes, ProxyBody), BoxError> {
+    let mut sample = BytesMut::new();
+    let mut frames = VecDeque::new();
+    while sample.len() < limit {
+        let frame = match body.frame().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => return Err(err),
+            None => break,
+        };
+        if let Some(data) = frame.data_ref() {
+            let take = data.len().min(limit - sample.len());
+            sample.extend_from_slice(&data[..take]);
+        }
+        frames.push_back(frame);
+    }
+    Ok((sample.freeze(), ReplayBody { frames, rest: body }.boxed()))
+}
+
+/// Request body whose first frames were already read for inspection.
+struct ReplayBody {
+    frames: VecDeque<Frame<Bytes>>,
+    rest: ProxyBody,
+}
+
+impl Body for ReplayBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(frame) = self.frames.pop_front() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        Pin::new(&mut self.rest).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_empty() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered: u64 = self
+            .frames
+            .iter()
+            .filter_map(|f| f.data_ref())
+            .map(|d| d.len() as u64)
+            .sum();
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + buffered);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
+
+#[derive(Debug)]
+struct SlowBodyError {
+    received: u64,
+    elapsed: Duration,
+}
+
+impl std::fmt::Display for SlowBodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request body below minimum rate ({} bytes in {:.1}s)",
+            self.received,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for SlowBodyError {}
+
+/// Request body forwarded upstream that reports when it has been sent in
+/// full, which starts the response timeout.
+struct UploadBody {
+    inner: ProxyBody,
+    sent: Option<oneshot::Sender<()>>,
+}
+
+impl Body for UploadBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        // hyper stops polling once `is_end_stream` says so, so the end can
+        // arrive with the last data frame rather than as `None`.
+        let finished = match &frame {
+            Poll::Ready(None) => true,
+            Poll::Ready(Some(Ok(_))) => self.inner.is_end_stream(),
+            _ => false,
         };
+        if finished {
+            if let Some(sent) = self.sent.take() {
+                let _ = sent.send(());
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[derive(Debug)]
+struct UpstreamIdleError(Duration);
+
+impl std::fmt::Display for UpstreamIdleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "backend sent no response data for {}ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for UpstreamIdleError {}
 
-        Response::from_parts(parts, Full::new(body_bytes))
+/// Whether an upstream request failed on a timeout, such as the service's
+/// `connect_timeout_ms`.
+fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if e.downcast_ref::<std::io::Error>()
+            .is_some_and(|io| io.kind() == std::io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = e.source();
     }
+    false
+}
+
+/// Whether an upstream request failed at the connection level – refused,
+/// reset, or closed before a response arrived – rather than with a
+/// response or a timeout.
+fn is_connection_error(err: &hyper_util::client::legacy::Error) -> bool {
+    if err.is_connect() {
+        return true;
+    }
+    let mut source = std::error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(hyper_err) = e.downcast_ref::<hyper::Error>() {
+            if hyper_err.is_incomplete_message() || hyper_err.is_closed() || hyper_err.is_canceled() {
+                return true;
+            }
+        }
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind;
+            if matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Body limit in bytes for a request: the service's `max_body_size_mb`,
+/// else `server.max_body_size_mb`. `None` when unlimited.
+fn max_body_size(settings: &Settings, service: Option<&ServiceConfig>) -> Option<usize> {
+    let mb = service
+        .and_then(|svc| svc.max_body_size_mb)
+        .unwrap_or(settings.server.max_body_size_mb);
+    (mb > 0).then(|| usize::try_from(mb.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX))
+}
+
+/// Whether reading a request body failed because it went past the
+/// [`Limited`] wrapper set up from [`max_body_size`].
+fn is_oversized_body(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Whether an upstream request failed because [`MinRateBody`] gave up on
+/// the client.
+fn is_slow_body(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if e.is::<SlowBodyError>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
 }
 
 // ---------------------------------------------------------------------------
@@ -611,23 +2033,185 @@ impl HttpHandler {
 // ---------------------------------------------------------------------------
 
 /// Return a `502 Bad Gateway` response.
-pub fn bad_gateway() -> Response<Full<Bytes>> {
+pub fn bad_gateway() -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::BAD_GATEWAY)
         .header("Content-Type", "text/html; charset=utf-8")
         .header("X-Fortress-Protected", "true")
-        .body(Full::new(Bytes::from(
+        .body(full_body(
             "<!DOCTYPE html>\
             <html><head><title>502 Bad Gateway</title></head>\
             <body><h1>502 Bad Gateway</h1>\
             <p>The upstream server is not available. Please try again later.</p>\
             <hr><p>Fortress Anti-DDoS Proxy</p></body></html>",
-        )))
+        ))
+        .unwrap()
+}
+
+/// Return a `504 Gateway Timeout` for an upstream that did not answer within
+/// the service's `response_timeout_ms`.
+pub fn gateway_timeout() -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("X-Fortress-Protected", "true")
+        .body(full_body(
+            "<!DOCTYPE html>\
+            <html><head><title>504 Gateway Timeout</title></head>\
+            <body><h1>504 Gateway Timeout</h1>\
+            <p>The upstream server did not respond in time. Please try again later.</p>\
+            <hr><p>Fortress Anti-DDoS Proxy</p></body></html>",
+        ))
+        .unwrap()
+}
+
+/// Return a `503` for a service that is at its upstream concurrency limit or
+/// whose circuit is open.
+pub fn upstream_unavailable(retry_after: Duration) -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Retry-After", retry_after.as_secs_f64().ceil().max(1.0).to_string())
+        .header("X-Fortress-Protected", "true")
+        .body(full_body(
+            "<!DOCTYPE html>\
+            <html><head><title>503 Service Unavailable</title></head>\
+            <body><h1>503 Service Unavailable</h1>\
+            <p>The upstream server is overloaded. Please try again later.</p>\
+            <hr><p>Fortress Anti-DDoS Proxy</p></body></html>",
+        ))
+        .unwrap()
+}
+
+/// Return a `429` for a client over `protection.max_bytes_per_ip_per_sec`.
+pub fn bandwidth_exceeded() -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Retry-After", BANDWIDTH_WINDOW_SECS.to_string())
+        .header("X-Fortress-Protected", "true")
+        .body(full_body("Too Many Requests"))
+        .unwrap()
+}
+
+/// Return a `400 Bad Request` and close the connection, for requests that
+/// cannot be forwarded safely.
+pub fn bad_request() -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Connection", "close")
+        .header("X-Fortress-Protected", "true")
+        .body(full_body("Bad Request"))
+        .unwrap()
+}
+
+/// Return a `413 Payload Too Large` for a request body over the limit. The
+/// body is left unread, so the connection is not reused.
+pub fn payload_too_large() -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Connection", "close")
+        .header("X-Fortress-Protected", "true")
+        .body(full_body("Payload Too Large"))
+        .unwrap()
+}
+
+/// Return the answer to a request body blocked for its `Content-Encoding`:
+/// 415, 413 or 400. The body is not forwarded, so the connection is not
+/// reused.
+pub fn encoded_body_rejected(err: EncodedBodyError) -> Response<ProxyBody> {
+    let status = err.status();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Connection", "close")
+        .header("X-Fortress-Protected", "true")
+        .body(full_body(status.canonical_reason().unwrap_or("Bad Request")))
         .unwrap()
 }
 
-/// Return a `403 Forbidden` response with a professional block page.
-pub fn forbidden_with_details(client_ip: IpAddr, ray_id: &str) -> Response<Full<Bytes>> {
+/// Return a `408 Request Timeout` for a client that sent its body too slowly.
+pub fn request_timeout() -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::REQUEST_TIMEOUT)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Connection", "close")
+        .header("X-Fortress-Protected", "true")
+        .body(full_body("Request Timeout"))
+        .unwrap()
+}
+
+/// Return the `503` maintenance page: the service's own file when it can be
+/// read, the built-in page otherwise.
+pub async fn maintenance_page(html_path: Option<&str>) -> Response<ProxyBody> {
+    let html = match html_path {
+        Some(path) => match tokio::fs::read_to_string(path).await {
+            Ok(html) => html,
+            Err(e) => {
+                warn!(path, error = %e, "Failed to read maintenance page, using built-in");
+                MAINTENANCE_PAGE.to_string()
+            }
+        },
+        None => MAINTENANCE_PAGE.to_string(),
+    };
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Retry-After", MAINTENANCE_RETRY_AFTER_SECS.to_string())
+        .header("Cache-Control", "no-store")
+        .header("X-Fortress-Protected", "true")
+        .body(full_body(html))
+        .unwrap()
+}
+
+/// Return the `503` waiting room page for a visitor at `place` in line,
+/// setting their new position cookie if there is one.
+pub fn waiting_room_response(place: u64, set_cookie: Option<&str>, config: &WaitingRoomConfig) -> Response<ProxyBody> {
+    let mut builder = Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Retry-After", config.refresh_secs.to_string())
+        .header("Cache-Control", "no-store")
+        .header("X-Fortress-Protected", "true");
+    if let Some(cookie) = set_cookie {
+        builder = builder.header("Set-Cookie", cookie);
+    }
+    builder.body(full_body(waiting_room::queue_page(place, config))).unwrap()
+}
+
+/// Return the `200` challenge page, compressed if the client accepts it.
+pub fn challenge_page(html: String, accept_encoding: Option<&str>) -> Response<ProxyBody> {
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .header("X-Fortress-Protected", "true");
+    encoded_page(builder, html, accept_encoding)
+}
+
+/// Return the `403` for a tarpitted request: a body that drips one byte
+/// per tick, or an empty one with `Connection: close` when the tarpit is
+/// full.
+pub fn tarpit_response(body: Option<TarpitBody>) -> Response<ProxyBody> {
+    let builder = Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header("Content-Type", "text/plain")
+        .header("Cache-Control", "no-store");
+    match body {
+        Some(body) => builder.body(body.boxed()).unwrap(),
+        None => builder.header("Connection", "close").body(empty_body()).unwrap(),
+    }
+}
+
+/// Return a `403 Forbidden` response with a professional block page,
+/// compressed if the client accepts it.
+pub fn forbidden_with_details(
+    client_ip: IpAddr,
+    ray_id: &str,
+    accept_encoding: Option<&str>,
+) -> Response<ProxyBody> {
     let html = format!(
         r##"<!DOCTYPE html>
 <html lang="en">
@@ -685,26 +2269,60 @@ body{{background:#0a0a0a;color:#e4e4e7;font-family:-apple-system,system-ui,Blink
         ts = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
     );
 
-    Response::builder()
+    let builder = Response::builder()
         .status(StatusCode::FORBIDDEN)
         .header("Content-Type", "text/html; charset=utf-8")
         .header("X-Fortress-Protected", "true")
         .header("X-Fortress-Ray", ray_id)
-        .header("Cache-Control", "no-store")
-        .body(Full::new(Bytes::from(html)))
-        .unwrap()
+        .header("Cache-Control", "no-store");
+    encoded_page(builder, html, accept_encoding)
 }
 
 /// Simple 403 without details (for internal use).
-pub fn forbidden() -> Response<Full<Bytes>> {
+pub fn forbidden() -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::FORBIDDEN)
         .header("Content-Type", "text/html; charset=utf-8")
         .header("X-Fortress-Protected", "true")
-        .body(Full::new(Bytes::from("Forbidden")))
+        .body(full_body("Forbidden"))
         .unwrap()
 }
 
+/// Answer a CORS preflight for a service with `cors_allowed_origins`.
+///
+/// An allowed origin gets the requested method and headers echoed back;
+/// anything else gets a 403 without CORS headers, which the browser reports
+/// as a failed preflight. Credentials are only allowed for origins listed
+/// explicitly, never through `*`.
+pub fn cors_preflight_response(
+    allowed_origins: &[String],
+    headers: &HashMap<String, String>,
+) -> Response<ProxyBody> {
+    let origin = headers.get("origin").map(|o| o.trim_end_matches('/').to_ascii_lowercase());
+    let (Some(origin), Some(method)) = (origin, headers.get("access-control-request-method")) else {
+        return forbidden();
+    };
+    let explicit = allowed_origins.contains(&origin);
+    if !explicit && !allowed_origins.iter().any(|o| o == "*") {
+        return forbidden();
+    }
+
+    let mut builder = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Access-Control-Allow-Origin", origin.as_str())
+        .header("Access-Control-Allow-Methods", method.as_str())
+        .header("Access-Control-Max-Age", "600")
+        .header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers")
+        .header("X-Fortress-Protected", "true");
+    if let Some(req_headers) = headers.get("access-control-request-headers") {
+        builder = builder.header("Access-Control-Allow-Headers", req_headers.as_str());
+    }
+    if explicit {
+        builder = builder.header("Access-Control-Allow-Credentials", "true");
+    }
+    builder.body(empty_body()).unwrap()
+}
+
 // ---------------------------------------------------------------------------
 // Utilities
 // ---------------------------------------------------------------------------
@@ -836,3 +2454,260 @@ fn is_api_request(path: &str, headers: &HashMap<String, String>) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::config::defaults::{
+        default_challenge_config, default_protection_config, default_upstream_config,
+    };
+
+    fn challenge_html() -> String {
+        let mut config = default_challenge_config();
+        config.hmac_secret = "test-secret".to_string();
+        ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()))
+            .generate_challenge_page(&ProtectionLevel::L1, &"203.0.113.7".parse().unwrap())
+    }
+
+    async fn body_bytes(resp: Response<ProxyBody>) -> Bytes {
+        resp.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_challenge_page_gzip() {
+        let html = challenge_html();
+        let resp = challenge_page(html.clone(), Some("gzip, deflate"));
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+        assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+
+        let compressed = body_bytes(resp).await;
+        assert!(compressed.len() < html.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, html);
+    }
+
+    #[tokio::test]
+    async fn test_challenge_page_prefers_brotli() {
+        let html = challenge_html();
+        let resp = challenge_page(html.clone(), Some("gzip, deflate, br"));
+        assert_eq!(resp.headers()["content-encoding"], "br");
+        assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+
+        let compressed = body_bytes(resp).await;
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, html);
+    }
+
+    /// Upstream that accepts one connection, reads the request, writes
+    /// `response` and then stalls.
+    async fn stalling_upstream(response: &'static [u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            stream.write_all(response).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        addr
+    }
+
+    fn get(addr: &str) -> Request<ProxyBody> {
+        Request::get(format!("http://{}/", addr)).body(empty_body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stalled_backend_times_out_waiting_for_headers() {
+        let addr = stalling_upstream(b"").await;
+        let (client, _) = UpstreamClients::new().for_upstream(None, None, &default_upstream_config(), &addr);
+
+        let started = Instant::now();
+        let result = send_upstream(&client, get(&addr), Duration::from_millis(200)).await;
+        assert!(matches!(result, Err(UpstreamError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_backend_body_is_cut_off() {
+        let addr = stalling_upstream(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello").await;
+        let (client, _) = UpstreamClients::new().for_upstream(None, None, &default_upstream_config(), &addr);
+        let timeout = Duration::from_millis(200);
+
+        let resp = send_upstream(&client, get(&addr), timeout).await.ok().unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = LeasedBody::new(resp.into_body(), BackendLease::detached(addr), None, timeout);
+        let err = body.collect().await.err().unwrap();
+        assert!(err.is::<UpstreamIdleError>());
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_counts_as_connection_error() {
+        use tokio::io::AsyncReadExt;
+
+        // Reads the request and hangs up without a response, like a
+        // backend shutting down with the request in flight.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+        });
+        let (client, _) = UpstreamClients::new().for_upstream(None, None, &default_upstream_config(), &addr);
+        let Err(UpstreamError::Request(err)) = send_upstream(&client, get(&addr), Duration::from_secs(2)).await else {
+            panic!("expected a request error");
+        };
+        assert!(!err.is_connect());
+        assert!(is_connection_error(&err));
+
+        // Nothing listening any more
+        let Err(UpstreamError::Request(err)) = send_upstream(&client, get(&addr), Duration::from_secs(2)).await else {
+            panic!("expected a request error");
+        };
+        assert!(is_connection_error(&err));
+    }
+
+    /// Upstream that reads requests on one connection without answering.
+    async fn draining_upstream() -> String {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 65536];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+        addr
+    }
+
+    /// A body sent in chunks with no Content-Length.
+    fn chunked_body(chunks: usize, chunk_size: usize) -> ProxyBody {
+        let frames = (0..chunks).map(move |_| Ok::<_, BoxError>(Frame::data(Bytes::from(vec![b'x'; chunk_size]))));
+        http_body_util::StreamBody::new(futures_util::stream::iter(frames)).boxed()
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_over_the_limit_is_cut_off() {
+        let mut settings = Settings::default();
+        settings.server.max_body_size_mb = 100;
+        let mut service: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "upload", "name": "Upload", "domains": [], "upstream_address": "127.0.0.1:1",
+        }))
+        .unwrap();
+        assert_eq!(max_body_size(&settings, None), Some(100 * 1024 * 1024));
+        service.max_body_size_mb = Some(0);
+        assert_eq!(max_body_size(&settings, Some(&service)), None);
+        service.max_body_size_mb = Some(1);
+        let limit = max_body_size(&settings, Some(&service)).unwrap();
+
+        // 2 MiB in 64 KiB chunks against a 1 MiB limit
+        let body = chunked_body(32, 64 * 1024);
+        assert!(body.size_hint().exact().is_none());
+        let addr = draining_upstream().await;
+        let (client, _) = UpstreamClients::new().for_upstream(None, None, &default_upstream_config(), &addr);
+        let req = Request::post(format!("http://{}/upload", addr))
+            .body(Limited::new(body, limit).boxed())
+            .unwrap();
+        match send_upstream(&client, req, Duration::from_secs(5)).await {
+            Err(UpstreamError::Request(err)) => assert!(is_oversized_body(&err)),
+            _ => panic!("oversized upload was not cut off"),
+        }
+
+        // Inspection reads hit the same limit; bodies under it pass.
+        let sample = read_body_sample(Limited::new(chunked_body(32, 64 * 1024), limit).boxed(), 4 * 1024 * 1024).await;
+        assert!(is_oversized_body(sample.err().unwrap().as_ref()));
+        let (sample, _) = read_body_sample(Limited::new(chunked_body(8, 64 * 1024), limit).boxed(), 4 * 1024 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(sample.len(), 512 * 1024);
+
+        assert_eq!(payload_too_large().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_page_falls_back_to_builtin() {
+        for path in [None, Some("/nonexistent/maintenance.html")] {
+            let resp = maintenance_page(path).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(resp.headers()["retry-after"], "300");
+            assert_eq!(body_bytes(resp).await, MAINTENANCE_PAGE.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_cors_preflight_response() {
+        let allowed = vec!["https://app.example.com".to_string()];
+        let mut headers = HashMap::from([
+            ("origin".to_string(), "https://app.example.com".to_string()),
+            ("access-control-request-method".to_string(), "PUT".to_string()),
+        ]);
+        let resp = cors_preflight_response(&allowed, &headers);
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "https://app.example.com");
+        assert_eq!(resp.headers()["access-control-allow-methods"], "PUT");
+        assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+
+        headers.insert("origin".to_string(), "https://evil.example".to_string());
+        assert_eq!(cors_preflight_response(&allowed, &headers).status(), StatusCode::FORBIDDEN);
+
+        let resp = cors_preflight_response(&["*".to_string()], &headers);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "https://evil.example");
+        assert!(resp.headers().get("access-control-allow-credentials").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_challenge_page_uncompressed() {
+        let html = challenge_html();
+        for accept in [None, Some("identity"), Some("gzip;q=0, br;q=0")] {
+            let resp = challenge_page(html.clone(), accept);
+            assert!(resp.headers().get("content-encoding").is_none());
+            assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+            assert_eq!(body_bytes(resp).await, html.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_upstream_request_keeps_repeated_headers_and_drops_framing_overrides() {
+        let mut headers = HeaderMap::new();
+        headers.append("accept", "text/html".parse().unwrap());
+        headers.append("accept", "*/*".parse().unwrap());
+        headers.append("x-forwarded-for", "198.51.100.1".parse().unwrap());
+        headers.append("transfer-encoding", "chunked".parse().unwrap());
+        let vars = HeaderVars {
+            client_ip: "203.0.113.7".parse().unwrap(),
+            ray_id: "ray",
+            country: None,
+            host: "example.com",
+        };
+        let req = build_upstream_request(
+            hyper::Method::POST,
+            "http://127.0.0.1:8080/",
+            "example.com",
+            &headers,
+            empty_body(),
+            None,
+            &vars,
+        )
+        .unwrap();
+        let accept: Vec<_> = req.headers().get_all("accept").iter().collect();
+        assert_eq!(accept, ["text/html", "*/*"]);
+        assert_eq!(req.headers()["x-forwarded-for"], "203.0.113.7");
+        assert!(req.headers().get("transfer-encoding").is_none());
+    }
+}
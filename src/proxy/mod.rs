@@ -1,8 +1,16 @@
 pub mod access_log;
+pub mod compression;
 pub mod server;
 pub mod tls;
 pub mod http_handler;
 pub mod connection;
 pub mod websocket;
 pub mod service_router;
+pub mod header_rules;
 pub mod health_check;
+pub mod tarpit;
+pub mod upstream;
+pub mod circuit_breaker;
+pub mod response_cache;
+pub mod request_encoding;
+pub mod waiting_room;
@@ -0,0 +1,185 @@
+use std::io::Read;
+
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use hyper::StatusCode;
+
+/// Decoded output below this size is never held against the expansion
+/// ratio; small bodies of repetitive JSON compress very well.
+const RATIO_FLOOR: usize = 64 * 1024;
+
+/// Decompression is read in chunks of this size and discarded past what
+/// inspection keeps, so memory stays bounded whatever the body expands to.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A content coding Fortress can undo for inspection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+/// Why an encoded request body was not forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodedBodyError {
+    /// The service rejects encoded bodies, or the coding is unknown.
+    Unsupported,
+    /// Decoding went past the size cap or the expansion ratio.
+    TooLarge,
+    /// The body does not decode.
+    Invalid,
+}
+
+impl EncodedBodyError {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Unsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Invalid => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Whether decoding was started and then aborted, as opposed to the
+    /// body being refused outright.
+    pub fn is_aborted(self) -> bool {
+        !matches!(self, Self::Unsupported)
+    }
+}
+
+/// Limits for decoding one request body.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    /// Decoded size at which decoding is aborted.
+    pub max_output: usize,
+    /// Decoded size at which decoding is aborted, as a multiple of the
+    /// encoded size.
+    pub max_ratio: f64,
+    /// Decoded bytes kept for inspection.
+    pub keep: usize,
+}
+
+/// The codings listed in a `Content-Encoding` value, in the order they
+/// were applied; `identity` is skipped. An empty list means the body is
+/// not encoded.
+pub fn parse_codings(content_encoding: &str) -> Result<Vec<Coding>, EncodedBodyError> {
+    content_encoding
+        .split(',')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty() && !c.eq_ignore_ascii_case("identity"))
+        .map(|c| match c.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Ok(Coding::Gzip),
+            "deflate" => Ok(Coding::Deflate),
+            "br" => Ok(Coding::Brotli),
+            _ => Err(EncodedBodyError::Unsupported),
+        })
+        .collect()
+}
+
+/// Decode a complete request body, returning the first `limits.keep`
+/// decoded bytes. The whole body is decoded to check its size, but the
+/// rest of the output is dropped as it is produced.
+pub fn decode(body: &[u8], codings: &[Coding], limits: DecodeLimits) -> Result<Bytes, EncodedBodyError> {
+    let mut reader: Box<dyn Read + '_> = Box::new(body);
+    for coding in codings.iter().rev() {
+        reader = match coding {
+            Coding::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Coding::Deflate => deflate_reader(reader),
+            Coding::Brotli => Box::new(brotli::Decompressor::new(reader, CHUNK_SIZE)),
+        };
+    }
+
+    let max_ratio_output = (body.len() as f64 * limits.max_ratio).max(RATIO_FLOOR as f64);
+    let mut kept = Vec::with_capacity(limits.keep.min(CHUNK_SIZE));
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut total = 0usize;
+    loop {
+        let n = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return Err(EncodedBodyError::Invalid),
+        };
+        total += n;
+        if total > limits.max_output || total as f64 > max_ratio_output {
+            return Err(EncodedBodyError::TooLarge);
+        }
+        let take = n.min(limits.keep - kept.len());
+        kept.extend_from_slice(&chunk[..take]);
+    }
+    Ok(Bytes::from(kept))
+}
+
+/// `deflate` is meant to be zlib-wrapped, but some clients send a raw
+/// deflate stream; the first two bytes tell which.
+fn deflate_reader<'a>(mut inner: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+    let mut head = [0u8; 2];
+    let mut read = 0;
+    while read < head.len() {
+        match inner.read(&mut head[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n,
+        }
+    }
+    let zlib = read == 2 && head[0] & 0x0f == 8 && u16::from_be_bytes(head) % 31 == 0;
+    let replay = std::io::Cursor::new(head[..read].to_vec()).chain(inner);
+    if zlib {
+        Box::new(ZlibDecoder::new(replay))
+    } else {
+        Box::new(DeflateDecoder::new(replay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use super::*;
+
+    const LIMITS: DecodeLimits = DecodeLimits { max_output: 1024 * 1024, max_ratio: 100.0, keep: 16 };
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_bodies_are_decoded_for_inspection() {
+        let json = br#"{"q":"1' UNION SELECT password FROM users"}"#;
+        let decoded = decode(&gzip(json), &[Coding::Gzip], LIMITS).unwrap();
+        assert_eq!(&decoded[..], &json[..16]);
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::fast());
+        zlib.write_all(json).unwrap();
+        let stacked = gzip(&zlib.finish().unwrap());
+        let codings = parse_codings("deflate, gzip").unwrap();
+        let keep_all = DecodeLimits { keep: 1024, ..LIMITS };
+        assert_eq!(&decode(&stacked, &codings, keep_all).unwrap()[..], &json[..]);
+
+        assert_eq!(parse_codings("identity").unwrap(), vec![]);
+        assert_eq!(parse_codings("gzip, zstd"), Err(EncodedBodyError::Unsupported));
+        assert_eq!(decode(b"not gzip", &[Coding::Gzip], LIMITS), Err(EncodedBodyError::Invalid));
+    }
+
+    #[test]
+    fn test_bombs_are_aborted_early() {
+        // 64 MiB of zeros is about 64 KiB of gzip: far over both limits
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        let zeros = vec![0u8; 1024 * 1024];
+        for _ in 0..64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        let bomb = encoder.finish().unwrap();
+        assert_eq!(decode(&bomb, &[Coding::Gzip], LIMITS), Err(EncodedBodyError::TooLarge));
+
+        // Under the size cap, but over the expansion ratio
+        let small_bomb = gzip(&vec![b'a'; 512 * 1024]);
+        assert_eq!(decode(&small_bomb, &[Coding::Gzip], LIMITS), Err(EncodedBodyError::TooLarge));
+        let lenient = DecodeLimits { max_ratio: 10_000.0, ..LIMITS };
+        assert!(decode(&small_bomb, &[Coding::Gzip], lenient).is_ok());
+    }
+}
@@ -0,0 +1,868 @@
+//! Edge cache for upstream responses the origin marks cacheable, so floods
+//! of the same assets are answered without touching the backend.
+//!
+//! Services with `always_online` also keep the last good copy of their
+//! HTML pages in a separate store, served while the upstream is down.
+
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::config::settings::SharedSettings;
+use crate::storage::sweep::SweepStats;
+
+use super::http_handler::{empty_body, full_body, BoxError, ProxyBody};
+
+/// Statuses stored when the origin gives them a freshness lifetime.
+const CACHEABLE_STATUSES: [u16; 5] = [200, 203, 301, 404, 410];
+
+/// Content codings told apart for `Vary: Accept-Encoding`.
+const CODINGS: [&str; 4] = ["br", "gzip", "deflate", "zstd"];
+
+/// Response headers that describe the upstream connection, not the
+/// resource.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Identifies a cacheable request: host, path and query, plus the codings
+/// the client accepts for responses that vary on `Accept-Encoding`.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    host: String,
+    path: String,
+    base: String,
+    codings: String,
+    head: bool,
+}
+
+impl CacheKey {
+    pub fn new(method: &str, host: &str, path: &str, query: Option<&str>, accept_encoding: Option<&str>) -> Self {
+        let host = host.to_ascii_lowercase();
+        let base = match query {
+            Some(q) => format!("{}\n{}?{}", host, path, q),
+            None => format!("{}\n{}", host, path),
+        };
+        Self {
+            host,
+            path: path.to_string(),
+            base,
+            codings: accepted_codings(accept_encoding),
+            head: method == "HEAD",
+        }
+    }
+
+    fn variant(&self, varies: bool) -> String {
+        if varies {
+            format!("{}\n{}", self.base, self.codings)
+        } else {
+            self.base.clone()
+        }
+    }
+}
+
+/// The codings out of [`CODINGS`] an `Accept-Encoding` header accepts, in
+/// a fixed order, e.g. `br,gzip`.
+fn accepted_codings(accept_encoding: Option<&str>) -> String {
+    let accepted: Vec<String> = accept_encoding
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            let refused = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (!refused).then_some(coding)
+        })
+        .collect();
+    CODINGS
+        .iter()
+        .filter(|c| accepted.iter().any(|a| a == *c))
+        .copied()
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A response admitted for caching, stored once its body has been read in
+/// full.
+pub struct PendingEntry {
+    /// Bound for the always-online store rather than the cache.
+    stale: bool,
+    key: String,
+    host: String,
+    path: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    ttl: Duration,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    host: String,
+    path: String,
+    stored_at: Instant,
+    expires_at: Instant,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// Keys by last use, oldest first.
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// The entry for `key`, unless it has expired (it is dropped then).
+    /// Marks it as used.
+    fn get(&mut self, key: &CacheKey, now: Instant) -> Option<&Entry> {
+        let found = [key.variant(false), key.variant(true)]
+            .into_iter()
+            .find(|k| self.entries.contains_key(k))?;
+        if self.entries[&found].expires_at <= now {
+            self.remove(&found);
+            return None;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&found)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        self.lru.remove(&previous);
+        self.lru.insert(tick, found.clone());
+        self.entries.get(&found)
+    }
+
+    /// Store `entry`, evicting the least recently used ones to stay within
+    /// `max_bytes`. Returns how many were evicted.
+    fn insert(&mut self, key: String, mut entry: Entry, max_bytes: usize) -> u64 {
+        self.remove(&key);
+        let mut evicted = 0;
+        while self.bytes + entry.size > max_bytes {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(old) = self.entries.remove(&oldest) {
+                self.bytes -= old.size;
+                evicted += 1;
+            }
+        }
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.lru.insert(self.tick, key.clone());
+        self.bytes += entry.size;
+        self.entries.insert(key, entry);
+        evicted
+    }
+
+    /// Remove entries for `host` (any host when `None`) whose path starts
+    /// with `path_prefix`.
+    fn purge(&mut self, host: Option<&str>, path_prefix: Option<&str>) -> usize {
+        let keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| host.is_none_or(|h| e.host == h))
+            .filter(|(_, e)| path_prefix.is_none_or(|p| e.path.starts_with(p)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys.len()
+    }
+
+    fn remove_expired(&mut self, now: Instant) -> SweepStats {
+        let visited = self.entries.len();
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.expires_at <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &expired {
+            self.remove(key);
+        }
+        SweepStats { visited, removed: expired.len(), largest: self.entries.len() }
+    }
+}
+
+/// Counters reported by `GET /api/fortress/cache/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub stores: u64,
+    pub evictions: u64,
+    pub hit_ratio: Option<f64>,
+    /// Pages kept for `always_online` services.
+    pub stale_entries: usize,
+    pub stale_bytes: usize,
+    pub stale_max_bytes: usize,
+    pub stale_stores: u64,
+    /// Requests answered with a stale page during an upstream failure.
+    pub stale_served: u64,
+}
+
+/// Bounded in-memory cache of upstream responses, evicting the least
+/// recently used entries once `cache.max_size_mb` of bodies is held.
+pub struct ResponseCache {
+    settings: SharedSettings,
+    inner: Mutex<Inner>,
+    stale: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stores: AtomicU64,
+    evictions: AtomicU64,
+    stale_stores: AtomicU64,
+    stale_served: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(settings: SharedSettings) -> Self {
+        Self {
+            settings,
+            inner: Mutex::new(Inner::default()),
+            stale: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stores: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            stale_stores: AtomicU64::new(0),
+            stale_served: AtomicU64::new(0),
+        }
+    }
+
+    /// Key for a request that may be answered from the cache: a GET or
+    /// HEAD without credentials, a range or an upgrade. Any cookie but the
+    /// clearance cookie counts as a credential, since the page may be
+    /// rendered for that visitor.
+    pub fn key_for(
+        &self,
+        method: &str,
+        host: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Option<CacheKey> {
+        if !self.settings.load().cache.enabled {
+            return None;
+        }
+        self.stale_key_for(method, host, path, query, headers)
+    }
+
+    /// Key for a request to an `always_online` service, with the same
+    /// conditions as [`Self::key_for`] but whether or not the cache is
+    /// enabled.
+    pub fn stale_key_for(
+        &self,
+        method: &str,
+        host: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Option<CacheKey> {
+        if !matches!(method, "GET" | "HEAD") {
+            return None;
+        }
+        if ["authorization", "range", "upgrade"].iter().any(|h| headers.contains_key(*h)) {
+            return None;
+        }
+        let clearance = &self.settings.load().challenge.cookie_name;
+        if headers.get_all(header::COOKIE).iter().any(|v| has_visitor_cookie(v, clearance)) {
+            return None;
+        }
+        Some(CacheKey::new(
+            method,
+            host,
+            path,
+            query,
+            headers.get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()),
+        ))
+    }
+
+    /// A fresh copy of the response for `key`, with `Age` and
+    /// `X-Fortress-Cache: HIT` set.
+    pub fn lookup(&self, key: &CacheKey) -> Option<Response<ProxyBody>> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        let Some(entry) = inner.get(key, now) else {
+            drop(inner);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let (mut resp, body) = entry.response(now);
+        drop(inner);
+
+        resp.headers_mut().insert("x-fortress-cache", HeaderValue::from_static("HIT"));
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(resp.map(|_| if key.head { empty_body() } else { full_body(body) }))
+    }
+
+    /// The last good copy of the page for `key`, with
+    /// `X-Fortress-Served-Stale: true` set, and `cache.stale_banner_html`
+    /// after its `<body>` tag when `banner` is set (only for bodies without
+    /// a content coding).
+    pub fn lookup_stale(&self, key: &CacheKey, banner: bool) -> Option<Response<ProxyBody>> {
+        let now = Instant::now();
+        let mut stale = self.stale.lock();
+        let (mut resp, mut body) = stale.get(key, now)?.response(now);
+        drop(stale);
+
+        if banner && !resp.headers().contains_key(header::CONTENT_ENCODING) {
+            body = inject_banner(&body, &self.settings.load().cache.stale_banner_html);
+            resp.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+        resp.headers_mut().insert("x-fortress-served-stale", HeaderValue::from_static("true"));
+        self.stale_served.fetch_add(1, Ordering::Relaxed);
+        Some(resp.map(|_| if key.head { empty_body() } else { full_body(body) }))
+    }
+
+    /// Admit an upstream response to a GET for caching if its status and
+    /// headers allow it. The headers are taken before the service's
+    /// response header rules, which are applied again on every hit.
+    pub fn admit(&self, key: &CacheKey, status: StatusCode, headers: &HeaderMap) -> Option<PendingEntry> {
+        if key.head {
+            return None;
+        }
+        let config = &self.settings.load().cache;
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if content_length.is_some_and(|len| len > config.max_entry_bytes()) {
+            return None;
+        }
+        let (ttl, varies) = freshness(status, headers, Duration::from_secs(config.max_ttl_secs))?;
+        let mut stored = headers.clone();
+        for name in HOP_BY_HOP {
+            stored.remove(name);
+        }
+        Some(PendingEntry {
+            stale: false,
+            key: key.variant(varies),
+            host: key.host.clone(),
+            path: key.path.clone(),
+            status,
+            headers: stored,
+            ttl,
+        })
+    }
+
+    /// Admit a successful HTML response of an `always_online` service as
+    /// the page's last good copy, kept for `cache.stale_ttl_secs` whatever
+    /// freshness the origin gives it. Personalized responses (`private`,
+    /// `no-store`, `no-cache`, `Set-Cookie` or a `Vary` other than
+    /// `Accept-Encoding`) are not kept.
+    pub fn admit_stale(&self, key: &CacheKey, status: StatusCode, headers: &HeaderMap) -> Option<PendingEntry> {
+        if key.head || status != StatusCode::OK || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        let is_html = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim_start().to_ascii_lowercase().starts_with("text/html"));
+        if !is_html {
+            return None;
+        }
+        let config = &self.settings.load().cache;
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if content_length.is_some_and(|len| len > config.max_entry_bytes()) {
+            return None;
+        }
+
+        let mut varies = headers.contains_key(header::CONTENT_ENCODING);
+        for value in headers.get_all(header::VARY) {
+            for field in value.to_str().ok()?.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+                if !field.eq_ignore_ascii_case("accept-encoding") {
+                    return None;
+                }
+                varies = true;
+            }
+        }
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let value = value.to_str().ok()?.to_ascii_lowercase();
+            let name = |d: &str| d.split('=').next().unwrap_or_default().trim().to_string();
+            if value.split(',').any(|d| matches!(name(d).as_str(), "private" | "no-store" | "no-cache")) {
+                return None;
+            }
+        }
+
+        let mut stored = headers.clone();
+        for name in HOP_BY_HOP {
+            stored.remove(name);
+        }
+        Some(PendingEntry {
+            stale: true,
+            key: key.variant(varies),
+            host: key.host.clone(),
+            path: key.path.clone(),
+            status,
+            headers: stored,
+            ttl: Duration::from_secs(config.stale_ttl_secs),
+        })
+    }
+
+    fn insert(&self, pending: PendingEntry, body: Bytes) {
+        let config = &self.settings.load().cache;
+        let size = body.len()
+            + pending
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        let (store, max_bytes) = if pending.stale {
+            (&self.stale, config.stale_max_bytes())
+        } else if config.enabled {
+            (&self.inner, config.max_bytes())
+        } else {
+            return;
+        };
+        if body.len() > config.max_entry_bytes() || size > max_bytes {
+            return;
+        }
+
+        let now = Instant::now();
+        let entry = Entry {
+            status: pending.status,
+            headers: pending.headers,
+            body,
+            host: pending.host,
+            path: pending.path,
+            stored_at: now,
+            expires_at: now + pending.ttl,
+            size,
+            last_used: 0,
+        };
+        let evicted = store.lock().insert(pending.key, entry, max_bytes);
+
+        if pending.stale {
+            self.stale_stores.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stores.fetch_add(1, Ordering::Relaxed);
+            self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        }
+    }
+
+    /// Remove entries, stale copies included, for `host` (any host when
+    /// `None`) whose path starts with `path_prefix`. Returns how many were
+    /// removed.
+    pub fn purge(&self, host: Option<&str>, path_prefix: Option<&str>) -> usize {
+        let host = host.map(str::to_ascii_lowercase);
+        let host = host.as_deref();
+        self.inner.lock().purge(host, path_prefix) + self.stale.lock().purge(host, path_prefix)
+    }
+
+    /// Drop expired entries, and all cached ones once the cache is
+    /// disabled.
+    pub fn cleanup(&self) -> SweepStats {
+        let now = Instant::now();
+        let mut stats = if self.settings.load().cache.enabled {
+            self.inner.lock().remove_expired(now)
+        } else {
+            let dropped = std::mem::take(&mut *self.inner.lock()).entries.len();
+            SweepStats { visited: dropped, removed: dropped, largest: 0 }
+        };
+        stats += self.stale.lock().remove_expired(now);
+        stats
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let config = &self.settings.load().cache;
+        let (entries, bytes) = {
+            let inner = self.inner.lock();
+            (inner.entries.len(), inner.bytes)
+        };
+        let (stale_entries, stale_bytes) = {
+            let stale = self.stale.lock();
+            (stale.entries.len(), stale.bytes)
+        };
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            enabled: config.enabled,
+            entries,
+            bytes,
+            max_bytes: config.max_bytes(),
+            hits,
+            misses,
+            stores: self.stores.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            hit_ratio: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+            stale_entries,
+            stale_bytes,
+            stale_max_bytes: config.stale_max_bytes(),
+            stale_stores: self.stale_stores.load(Ordering::Relaxed),
+            stale_served: self.stale_served.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Entry {
+    /// The stored response with `Age` and `Content-Length` set, and its
+    /// body.
+    fn response(&self, now: Instant) -> (Response<()>, Bytes) {
+        let mut resp = Response::new(());
+        *resp.status_mut() = self.status;
+        let headers = resp.headers_mut();
+        *headers = self.headers.clone();
+        headers.insert(header::AGE, HeaderValue::from(now.duration_since(self.stored_at).as_secs()));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+        (resp, self.body.clone())
+    }
+}
+
+/// `html` with `banner` inserted right after the opening `<body>` tag, or
+/// in front when there is none.
+fn inject_banner(html: &[u8], banner: &str) -> Bytes {
+    let lower = html.to_ascii_lowercase();
+    let at = lower
+        .windows(5)
+        .position(|w| w == b"<body")
+        .and_then(|start| html[start..].iter().position(|&b| b == b'>').map(|end| start + end + 1))
+        .unwrap_or(0);
+    let mut out = BytesMut::with_capacity(html.len() + banner.len());
+    out.extend_from_slice(&html[..at]);
+    out.extend_from_slice(banner.as_bytes());
+    out.extend_from_slice(&html[at..]);
+    out.freeze()
+}
+
+/// Whether a `Cookie` header carries anything but the `clearance` cookie,
+/// which Fortress sets itself and says nothing about who the visitor is.
+fn has_visitor_cookie(value: &HeaderValue, clearance: &str) -> bool {
+    let Ok(value) = value.to_str() else {
+        return true;
+    };
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .any(|c| c.split('=').next() != Some(clearance))
+}
+
+/// How long a response may be served from the cache, capped at `max_ttl`,
+/// and whether it varies on `Accept-Encoding`. `None` when it must not be
+/// cached: an uncacheable status, `no-store` / `no-cache` / `private`,
+/// `Set-Cookie`, a `Vary` on anything but `Accept-Encoding`, or no
+/// freshness lifetime from the origin.
+fn freshness(status: StatusCode, headers: &HeaderMap, max_ttl: Duration) -> Option<(Duration, bool)> {
+    if !CACHEABLE_STATUSES.contains(&status.as_u16()) || headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+
+    let mut varies = false;
+    for value in headers.get_all(header::VARY) {
+        for field in value.to_str().ok()?.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !field.eq_ignore_ascii_case("accept-encoding") {
+                return None;
+            }
+            varies = true;
+        }
+    }
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for value in headers.get_all(header::CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = arg.and_then(|a| a.parse::<u64>().ok()),
+                "s-maxage" => s_maxage = arg.and_then(|a| a.parse::<u64>().ok()),
+                _ => {}
+            }
+        }
+    }
+
+    let lifetime = match s_maxage.or(max_age) {
+        Some(secs) => Duration::from_secs(secs),
+        None => {
+            let date = |name| {
+                headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+            };
+            let expires = date(header::EXPIRES)?;
+            let now = date(header::DATE).map_or_else(chrono::Utc::now, |d| d.to_utc());
+            (expires.to_utc() - now).to_std().ok()?
+        }
+    };
+    let ttl = lifetime.min(max_ttl);
+    (!ttl.is_zero()).then_some((ttl, varies))
+}
+
+/// Passes an upstream body through to the client and stores it in the
+/// cache (and the always-online store) once it has been read in full.
+/// Bodies over `cache.max_entry_size_kb`, or that fail, are not stored.
+pub struct CachingBody {
+    inner: ProxyBody,
+    pending: Option<(Arc<ResponseCache>, Vec<PendingEntry>, BytesMut)>,
+    max_bytes: usize,
+}
+
+impl CachingBody {
+    pub fn wrap(inner: ProxyBody, cache: Arc<ResponseCache>, pending: Vec<PendingEntry>) -> ProxyBody {
+        let max_bytes = cache.settings.load().cache.max_entry_bytes();
+        if inner.is_end_stream() {
+            for entry in pending {
+                cache.insert(entry, Bytes::new());
+            }
+            return inner;
+        }
+        Self {
+            inner,
+            pending: Some((cache, pending, BytesMut::new())),
+            max_bytes,
+        }
+        .boxed()
+    }
+
+    fn finish(&mut self) {
+        if let Some((cache, pending, body)) = self.pending.take() {
+            let body = body.freeze();
+            for entry in pending {
+                cache.insert(entry, body.clone());
+            }
+        }
+    }
+}
+
+impl Body for CachingBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    let max_bytes = self.max_bytes;
+                    if let Some((_, _, body)) = self.pending.as_mut() {
+                        if body.len() + data.len() > max_bytes {
+                            self.pending = None;
+                        } else {
+                            body.extend_from_slice(data);
+                        }
+                    }
+                }
+                // hyper stops polling once the body reports its end
+                if self.inner.is_end_stream() {
+                    self.finish();
+                }
+            }
+            Some(Err(_)) => self.pending = None,
+            None => self.finish(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::config::settings::Settings;
+
+    fn cache(max_size_mb: u64) -> Arc<ResponseCache> {
+        let mut settings = Settings::default();
+        settings.cache.enabled = true;
+        settings.cache.max_size_mb = max_size_mb;
+        settings.cache.max_ttl_secs = 60;
+        Arc::new(ResponseCache::new(Arc::new(ArcSwap::from_pointee(settings))))
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (hyper::header::HeaderName::from_static(k), HeaderValue::from_static(v)))
+            .collect()
+    }
+
+    async fn serve(cache: &Arc<ResponseCache>, key: &CacheKey, resp: &HeaderMap, body: &'static str) {
+        let pending = cache.admit(key, StatusCode::OK, resp).expect("cacheable");
+        let body = CachingBody::wrap(full_body(body), Arc::clone(cache), vec![pending]);
+        body.collect().await.unwrap();
+    }
+
+    #[test]
+    fn test_freshness_follows_the_origin() {
+        let max = Duration::from_secs(60);
+        let ok = StatusCode::OK;
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "public, max-age=30")]), max), Some((Duration::from_secs(30), false)));
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "max-age=600, s-maxage=5")]), max), Some((Duration::from_secs(5), false)));
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "max-age=86400")]), max), Some((max, false)));
+        assert_eq!(
+            freshness(ok, &headers(&[("cache-control", "max-age=30"), ("vary", "Accept-Encoding")]), max),
+            Some((Duration::from_secs(30), true))
+        );
+        assert_eq!(
+            freshness(ok, &headers(&[("date", "Wed, 21 Oct 2015 07:28:00 GMT"), ("expires", "Wed, 21 Oct 2015 07:28:20 GMT")]), max),
+            Some((Duration::from_secs(20), false))
+        );
+        for uncacheable in [
+            headers(&[]),
+            headers(&[("cache-control", "max-age=0")]),
+            headers(&[("cache-control", "private, max-age=30")]),
+            headers(&[("cache-control", "no-store")]),
+            headers(&[("cache-control", "max-age=30"), ("set-cookie", "a=b")]),
+            headers(&[("cache-control", "max-age=30"), ("vary", "Accept-Encoding, Cookie")]),
+        ] {
+            assert_eq!(freshness(ok, &uncacheable, max), None, "{:?}", uncacheable);
+        }
+        assert_eq!(freshness(StatusCode::FOUND, &headers(&[("cache-control", "max-age=30")]), max), None);
+    }
+
+    #[tokio::test]
+    async fn test_hits_vary_on_encoding_and_evict_least_recently_used() {
+        let cache = cache(1);
+        let gzip = CacheKey::new("GET", "Example.com", "/app.js", None, Some("gzip, deflate;q=0"));
+        let br = CacheKey::new("GET", "example.com", "/app.js", None, Some("br, gzip"));
+        assert!(cache.lookup(&gzip).is_none());
+
+        let varies = headers(&[("cache-control", "max-age=30"), ("vary", "accept-encoding"), ("content-encoding", "gzip")]);
+        serve(&cache, &gzip, &varies, "gzipped").await;
+        let hit = cache.lookup(&gzip).expect("hit");
+        assert_eq!(hit.headers()["x-fortress-cache"], "HIT");
+        assert_eq!(hit.into_body().collect().await.unwrap().to_bytes(), "gzipped");
+        assert!(cache.lookup(&br).is_none());
+
+        let head = CacheKey::new("HEAD", "example.com", "/app.js", None, Some("gzip"));
+        let hit = cache.lookup(&head).expect("HEAD is served from a GET");
+        assert_eq!(hit.headers()[header::CONTENT_LENGTH], "7");
+        assert!(hit.into_body().collect().await.unwrap().to_bytes().is_empty());
+
+        // Half the cache each: the least recently used one goes first
+        let half = "x".repeat(512 * 1024 - 10).leak();
+        let plain = headers(&[("cache-control", "max-age=30")]);
+        let a = CacheKey::new("GET", "example.com", "/a.css", None, None);
+        let b = CacheKey::new("GET", "example.com", "/b.css", None, None);
+        serve(&cache, &a, &plain, half).await;
+        assert!(cache.lookup(&gzip).is_some());
+        serve(&cache, &b, &plain, half).await;
+        assert!(cache.lookup(&a).is_none());
+        assert!(cache.lookup(&gzip).is_some());
+        assert_eq!(cache.stats().evictions, 1);
+
+        assert_eq!(cache.purge(Some("EXAMPLE.com"), Some("/b")), 1);
+        assert!(cache.lookup(&b).is_none());
+        assert_eq!(cache.purge(None, None), 1);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_pages_are_kept_without_the_cache() {
+        let mut settings = Settings::default();
+        settings.cache.stale_banner_html = "<p>saved copy</p>".to_string();
+        let cache = Arc::new(ResponseCache::new(Arc::new(ArcSwap::from_pointee(settings))));
+        let home = CacheKey::new("GET", "example.com", "/", None, Some("gzip"));
+        let other_host = CacheKey::new("GET", "other.example", "/", None, Some("gzip"));
+        assert!(cache.key_for("GET", "example.com", "/", None, &HeaderMap::new()).is_none());
+
+        let html = headers(&[("content-type", "text/html; charset=utf-8")]);
+        assert!(cache.admit(&home, StatusCode::OK, &html).is_none());
+        for skipped in [
+            headers(&[("content-type", "application/json")]),
+            headers(&[("content-type", "text/html"), ("set-cookie", "session=1")]),
+            headers(&[("content-type", "text/html"), ("cache-control", "private")]),
+            headers(&[("content-type", "text/html"), ("cache-control", "no-cache")]),
+        ] {
+            assert!(cache.admit_stale(&home, StatusCode::OK, &skipped).is_none(), "{:?}", skipped);
+        }
+        assert!(cache.admit_stale(&home, StatusCode::INTERNAL_SERVER_ERROR, &html).is_none());
+
+        let pending = cache.admit_stale(&home, StatusCode::OK, &html).expect("kept");
+        let body = CachingBody::wrap(full_body("<html><BODY class=x><h1>Home</h1></body></html>"), Arc::clone(&cache), vec![pending]);
+        body.collect().await.unwrap();
+        assert!(cache.lookup(&home).is_none());
+        assert!(cache.lookup_stale(&other_host, false).is_none());
+
+        let stale = cache.lookup_stale(&home, false).expect("stale copy");
+        assert_eq!(stale.headers()["x-fortress-served-stale"], "true");
+        assert_eq!(stale.into_body().collect().await.unwrap().to_bytes(), "<html><BODY class=x><h1>Home</h1></body></html>");
+        let stale = cache.lookup_stale(&home, true).expect("stale copy");
+        let expected = "<html><BODY class=x><p>saved copy</p><h1>Home</h1></body></html>";
+        assert_eq!(stale.headers()[header::CONTENT_LENGTH], expected.len().to_string().as_str());
+        assert_eq!(stale.into_body().collect().await.unwrap().to_bytes(), expected);
+
+        let stats = cache.stats();
+        assert_eq!((stats.stale_entries, stats.stale_stores, stats.stale_served), (1, 1, 2));
+        assert_eq!(cache.purge(Some("example.com"), None), 1);
+        assert!(cache.lookup_stale(&home, false).is_none());
+    }
+
+    #[test]
+    fn test_visitor_cookies_keep_requests_out_of_the_cache() {
+        let mut settings = Settings::default();
+        settings.cache.enabled = true;
+        let cache = ResponseCache::new(Arc::new(ArcSwap::from_pointee(settings)));
+
+        for visitor in [
+            headers(&[("cookie", "session=abc")]),
+            headers(&[("cookie", "__fortress_clearance=x; session=abc")]),
+            headers(&[("cookie", "__fortress_clearance_old=x")]),
+        ] {
+            assert!(cache.stale_key_for("GET", "example.com", "/", None, &visitor).is_none(), "{:?}", visitor);
+            assert!(cache.key_for("GET", "example.com", "/", None, &visitor).is_none(), "{:?}", visitor);
+        }
+        for anonymous in [headers(&[]), headers(&[("cookie", "__fortress_clearance=x")])] {
+            assert!(cache.stale_key_for("GET", "example.com", "/", None, &anonymous).is_some(), "{:?}", anonymous);
+        }
+    }
+}
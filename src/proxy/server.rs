@@ -1,45 +1,60 @@
+use std::io;
 use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 use hyper::body::Incoming;
 use hyper::Request;
 use socket2::{Domain, Protocol, Socket, Type};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
 use tokio_rustls::TlsAcceptor;
 use tracing::{debug, info, warn};
 
+use crate::analytics::collector::RedirectDrop;
 use crate::config::settings::Settings;
+use crate::protection::auto_ban::AutoBanManager;
+use crate::protection::firewall::FirewallOffload;
 use crate::protection::l4_tracker::{L4Action, L4Tracker};
 use crate::protection::slowloris::SlowlorisDetector;
 use crate::storage::sqlite::SqliteStore;
 
 use super::connection::ConnectionTracker;
 use super::http_handler::HttpHandler;
-use super::tls::extract_ja3_from_client_hello;
+use super::tls::{extract_ja3_from_client_hello, peek_client_hello};
 use super::websocket::WebSocketProxy;
 
 /// The main Fortress proxy server.
 pub struct ProxyServer {
     settings: Arc<Settings>,
-    tls_config: Arc<rustls::ServerConfig>,
+    /// `None` when `server.mode` is `http`.
+    tls_config: Option<Arc<rustls::ServerConfig>>,
     handler: Arc<HttpHandler>,
     connections: Arc<ConnectionTracker>,
     l4_tracker: Option<Arc<L4Tracker>>,
     sqlite: Arc<SqliteStore>,
     slowloris: Arc<SlowlorisDetector>,
+    auto_ban: Arc<AutoBanManager>,
+    /// Told about L4 drops, to offload IPs that keep being refused.
+    firewall: Arc<FirewallOffload>,
 }
 
 impl ProxyServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         settings: Arc<Settings>,
-        tls_config: Arc<rustls::ServerConfig>,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
         handler: Arc<HttpHandler>,
         connections: Arc<ConnectionTracker>,
         l4_tracker: Option<Arc<L4Tracker>>,
         sqlite: Arc<SqliteStore>,
         slowloris: Arc<SlowlorisDetector>,
+        auto_ban: Arc<AutoBanManager>,
+        firewall: Arc<FirewallOffload>,
     ) -> Self {
         Self {
             settings,
@@ -49,26 +64,36 @@ impl ProxyServer {
             l4_tracker,
             sqlite,
             slowloris,
+            auto_ban,
+            firewall,
         }
     }
 
     /// Start the proxy server.
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mode = self.settings.server.mode;
         let https_addr = &self.settings.server.bind_https;
         let http_addr = &self.settings.server.bind_http;
 
         // --- HTTPS listener ---
-        let https_listener = bind_tcp_listener(https_addr)?;
-        let https_listener = TcpListener::from_std(https_listener.into())?;
-        info!(addr = %https_addr, "HTTPS listener started");
+        let https = if mode.serves_https() {
+            let tls_config = self.tls_config.clone().ok_or("HTTPS listener has no TLS configuration")?;
+            let listener = bind_tcp_listener(https_addr)?;
+            let listener = TcpListener::from_std(listener.into())?;
+            info!(addr = %https_addr, "HTTPS listener started");
+            Some((listener, TlsAcceptor::from(tls_config)))
+        } else {
+            None
+        };
 
         // --- HTTP listener ---
         let http_listener = bind_tcp_listener(http_addr)?;
         let http_listener = TcpListener::from_std(http_listener.into())?;
-        info!(addr = %http_addr, "HTTP listener started (redirect-to-HTTPS)");
-
-        let tls_acceptor = TlsAcceptor::from(Arc::clone(&self.tls_config));
-        let max_connections = self.settings.server.max_connections;
+        if mode.serves_plain_http() {
+            info!(addr = %http_addr, "HTTP listener started");
+        } else {
+            info!(addr = %http_addr, "HTTP listener started (redirect-to-HTTPS)");
+        }
 
         // --- Stale-connection cleanup task ---
         let cleanup_connections = Arc::clone(&self.connections);
@@ -77,17 +102,39 @@ impl ProxyServer {
             loop {
                 interval.tick().await;
                 cleanup_connections.cleanup_stale(Duration::from_secs(3600));
+                cleanup_connections.prune_bandwidth();
             }
         });
 
-        // --- HTTP redirect task ---
-        let _http_redirect_handle = tokio::spawn(run_http_redirect(http_listener));
-
-        // --- Main HTTPS accept loop ---
         info!("Fortress proxy is ready to accept connections");
 
+        match https {
+            Some((https_listener, acceptor)) if mode.serves_plain_http() => {
+                tokio::join!(
+                    self.accept_loop(&https_listener, Some(acceptor)),
+                    self.accept_loop(&http_listener, None),
+                );
+            }
+            Some((https_listener, acceptor)) => {
+                tokio::join!(
+                    self.accept_loop(&https_listener, Some(acceptor)),
+                    self.redirect_loop(&http_listener),
+                );
+            }
+            None => self.accept_loop(&http_listener, None).await,
+        }
+        Ok(())
+    }
+
+    /// Accept connections on `listener`, over TLS when `tls_acceptor` is
+    /// set. Connection limits, auto-bans, L4 protection and slowloris
+    /// tracking apply the same to both.
+    async fn accept_loop(&self, listener: &TcpListener, tls_acceptor: Option<TlsAcceptor>) {
+        let max_connections = self.settings.server.max_connections;
+        let header_timeout = Duration::from_secs(self.settings.server.header_timeout_secs.max(1));
+
         loop {
-            let (stream, peer_addr) = match https_listener.accept().await {
+            let (stream, peer_addr) = match listener.accept().await {
                 Ok(conn) => conn,
                 Err(err) => {
                     warn!("Failed to accept TCP connection: {}", err);
@@ -104,44 +151,45 @@ impl ProxyServer {
                 continue;
             }
 
-            // L4 protection check (pre-TLS)
+            // Auto-banned IPs (e.g. repeat slowloris offenders) never reach
+            // the HTTP layer, so they have to be refused here.
+            if let Some(reason) = self.auto_ban.is_banned(&peer_ip) {
+                debug!(client_ip = %peer_ip, reason = %reason, "Auto-banned IP, dropping connection");
+                self.sqlite
+                    .insert_l4_event(&peer_ip.to_string(), "drop", Some("auto_banned"), None, None);
+                drop(stream);
+                continue;
+            }
+
+            // L4 protection check (before any TLS or HTTP work)
             let l4_tracker_clone = self.l4_tracker.clone();
-            let sqlite_clone = self.sqlite.clone();
             if let Some(ref l4) = l4_tracker_clone {
                 match l4.check_connection(peer_ip) {
                     L4Action::Allow => {
                         l4.register_connection(peer_ip);
                     }
-                    L4Action::Drop => {
-                        // Log L4 event asynchronously
-                        let ip_str = peer_ip.to_string();
-                        let sqlite = sqlite_clone.clone();
-                        let metrics = l4.get_metrics();
-                        tokio::spawn(async move {
-                            let _ = sqlite.insert_l4_event(
-                                &ip_str,
-                                "drop",
-                                Some("connection_limit_exceeded"),
-                                Some(metrics.total_allowed as i64),
-                                None,
-                            );
-                        });
+                    L4Action::Drop(reason) => {
+                        self.firewall.record_l4_drop(peer_ip);
+                        // Queued for the SQLite writer thread
+                        self.sqlite.insert_l4_event(
+                            &peer_ip.to_string(),
+                            "drop",
+                            Some(&reason.to_string()),
+                            Some(l4.total_allowed() as i64),
+                            None,
+                        );
                         drop(stream);
                         continue;
                     }
-                    L4Action::Tarpit => {
-                        // Log L4 event asynchronously
-                        let ip_str = peer_ip.to_string();
-                        let sqlite = sqlite_clone.clone();
-                        tokio::spawn(async move {
-                            let _ = sqlite.insert_l4_event(
-                                &ip_str,
-                                "tarpit",
-                                Some("rate_limit_exceeded"),
-                                None,
-                                None,
-                            );
-                        });
+                    L4Action::Tarpit(reason) => {
+                        // Queued for the SQLite writer thread
+                        self.sqlite.insert_l4_event(
+                            &peer_ip.to_string(),
+                            "tarpit",
+                            Some(&reason.to_string()),
+                            None,
+                            None,
+                        );
                         let delay = l4.tarpit_delay();
                         tokio::spawn(async move {
                             tokio::time::sleep(delay).await;
@@ -162,9 +210,16 @@ impl ProxyServer {
             let slowloris_check = self.slowloris.clone();
 
             tokio::spawn(async move {
-                // Check for slowloris before TLS handshake timeout
-                let result =
-                    handle_tls_connection(stream, acceptor, handler, connections, peer_ip).await;
+                let result = handle_connection(
+                    stream,
+                    acceptor,
+                    handler,
+                    connections,
+                    Arc::clone(&slowloris_check),
+                    peer_ip,
+                    header_timeout,
+                )
+                .await;
 
                 // Unregister L4 connection when done
                 if let Some(ref l4) = l4_tracker_clone {
@@ -172,6 +227,14 @@ impl ProxyServer {
                 }
 
                 if let Err(err) = result {
+                    if let (Some(failure), Some(l4)) =
+                        (err.downcast_ref::<HandshakeFailure>(), l4_tracker_clone.as_ref())
+                    {
+                        if l4.record_handshake_failure(peer_ip, failure.timed_out) {
+                            warn!(client_ip = %peer_ip, "L4: too many TLS handshake failures, refusing connections");
+                        }
+                    }
+
                     // Check if this was a slowloris attempt
                     if slowloris_check.is_slowloris(&peer_ip) {
                         warn!(
@@ -183,7 +246,7 @@ impl ProxyServer {
                     debug!(
                         client_ip = %peer_ip,
                         error = %err,
-                        "TLS connection handling ended with error"
+                        "Connection handling ended with error"
                     );
                 }
             });
@@ -220,25 +283,47 @@ fn bind_tcp_listener(addr: &str) -> Result<std::net::TcpListener, Box<dyn std::e
 }
 
 // ---------------------------------------------------------------------------
-// HTTPS connection handler
+// Connection handler
 // ---------------------------------------------------------------------------
 
-async fn handle_tls_connection(
+/// How long to wait for a complete ClientHello before fingerprinting
+/// whatever has arrived. The handshake itself has its own 10s limit.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A TLS handshake that failed or timed out, counted against the client by
+/// the L4 tracker.
+#[derive(Debug)]
+struct HandshakeFailure {
+    timed_out: bool,
+    detail: String,
+}
+
+impl std::fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.detail)
+    }
+}
+
+impl std::error::Error for HandshakeFailure {}
+
+async fn handle_connection(
     stream: TcpStream,
-    tls_acceptor: TlsAcceptor,
+    tls_acceptor: Option<TlsAcceptor>,
     handler: Arc<HttpHandler>,
     connections: Arc<ConnectionTracker>,
+    slowloris: Arc<SlowlorisDetector>,
     peer_ip: IpAddr,
+    header_timeout: Duration,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // 1. Peek at the ClientHello for JA3.
-    let mut peek_buf = [0u8; 1500];
-    let peek_len = stream.peek(&mut peek_buf).await.unwrap_or(0);
-    let ja3_hash = if peek_len > 0 {
-        extract_ja3_from_client_hello(&peek_buf[..peek_len])
-    } else {
-        None
+    let Some(tls_acceptor) = tls_acceptor else {
+        return serve_http(stream, false, None, handler, connections, slowloris, peer_ip, header_timeout).await;
     };
 
+    // 1. Peek at the ClientHello for JA3. The hash is stored on the
+    //    connection entry and passed with every request served on it.
+    let client_hello = peek_client_hello(&stream, CLIENT_HELLO_TIMEOUT).await;
+    let ja3_hash = extract_ja3_from_client_hello(&client_hello);
+
     if let Some(ref hash) = ja3_hash {
         debug!(client_ip = %peer_ip, ja3 = %hash, "JA3 fingerprint extracted");
     }
@@ -249,18 +334,38 @@ async fn handle_tls_connection(
         tls_acceptor.accept(stream),
     ).await {
         Ok(Ok(stream)) => stream,
+        // Closed before a ClientHello arrived, e.g. a TCP health check.
+        Ok(Err(err)) if client_hello.is_empty() => return Err(err.into()),
         Ok(Err(err)) => {
             debug!(client_ip = %peer_ip, error = %err, "TLS handshake failed");
-            return Err(err.into());
+            return Err(Box::new(HandshakeFailure { timed_out: false, detail: err.to_string() }));
         }
         Err(_) => {
             debug!(client_ip = %peer_ip, "TLS handshake timeout (10s)");
-            return Err("TLS handshake timeout".into());
+            return Err(Box::new(HandshakeFailure { timed_out: true, detail: "TLS handshake timeout".to_string() }));
         }
     };
 
+    serve_http(tls_stream, true, ja3_hash, handler, connections, slowloris, peer_ip, header_timeout).await
+}
+
+/// Serve HTTP/1 on an accepted connection, plain or after the TLS handshake.
+#[allow(clippy::too_many_arguments)]
+async fn serve_http<T>(
+    stream: T,
+    tls: bool,
+    ja3_hash: Option<String>,
+    handler: Arc<HttpHandler>,
+    connections: Arc<ConnectionTracker>,
+    slowloris: Arc<SlowlorisDetector>,
+    peer_ip: IpAddr,
+    header_timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     // Register the connection.
-    let conn_id = connections.register(peer_ip, ja3_hash.clone());
+    let conn_id = connections.register(peer_ip, ja3_hash.clone(), tls);
 
     // Wrap in a guard so the connection is always removed on drop.
     let _guard = ConnectionGuard {
@@ -268,40 +373,77 @@ async fn handle_tls_connection(
         id: conn_id,
     };
 
-    debug!(client_ip = %peer_ip, connection_id = conn_id, "TLS connection established");
+    debug!(client_ip = %peer_ip, connection_id = conn_id, tls = tls, "Connection established");
 
     // 3. Use hyper's HTTP/1 server connection to handle the stream properly.
     use hyper::server::conn::http1;
     use hyper::service::service_fn;
-    use hyper_util::rt::TokioIo;
+    use hyper_util::rt::{TokioIo, TokioTimer};
 
-    let io = TokioIo::new(tls_stream);
+    // Bytes read at the end of the last response. A header timeout with
+    // more bytes than that is a half-sent request head; without, it is just
+    // an idle keep-alive connection.
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let served_mark = Arc::new(AtomicU64::new(0));
+
+    let io = TokioIo::new(CountingIo {
+        inner: stream,
+        read: Arc::clone(&bytes_read),
+    });
     let handler = Arc::clone(&handler);
     let ja3 = ja3_hash.clone();
+    let service_slowloris = Arc::clone(&slowloris);
+    let service_bytes_read = Arc::clone(&bytes_read);
+    let service_served_mark = Arc::clone(&served_mark);
 
     let service = service_fn(move |req: Request<Incoming>| {
         let h = Arc::clone(&handler);
         let j = ja3.clone();
+        let slowloris = Arc::clone(&service_slowloris);
+        let bytes_read = Arc::clone(&service_bytes_read);
+        let served_mark = Arc::clone(&service_served_mark);
         async move {
-            // Check for WebSocket upgrade before passing to handler.
-            if WebSocketProxy::is_websocket_upgrade(&req) {
+            let is_websocket = WebSocketProxy::is_websocket_upgrade(&req);
+            if is_websocket {
                 info!(
                     client_ip = %peer_ip,
                     path = %req.uri().path(),
-                    "WebSocket upgrade detected (will be handled after response)"
+                    "WebSocket upgrade requested"
                 );
             }
 
             let resp = h.handle(req, peer_ip, j, conn_id).await;
+            served_mark.store(bytes_read.load(Ordering::Relaxed), Ordering::Relaxed);
+
+            // Upgraded connections are long-lived and idle by design.
+            if is_websocket && resp.status() == hyper::StatusCode::SWITCHING_PROTOCOLS {
+                slowloris.mark_upgraded(&peer_ip);
+            }
             Ok::<_, hyper::Error>(resp)
         }
     });
 
     let conn = http1::Builder::new()
         .keep_alive(true)
-        .serve_connection(io, service);
+        .timer(TokioTimer::new())
+        .header_read_timeout(header_timeout)
+        .serve_connection(io, service)
+        .with_upgrades();
+
+    // Dropping the connection future closes the socket mid-request.
+    let closed = connections.close_token(conn_id).unwrap_or_default();
+    let result = tokio::select! {
+        result = conn => result,
+        _ = closed.cancelled() => return Ok(()),
+    };
 
-    if let Err(err) = conn.await {
+    if let Err(err) = result {
+        if err.is_timeout()
+            && bytes_read.load(Ordering::Relaxed) > served_mark.load(Ordering::Relaxed)
+        {
+            slowloris.record_violation(peer_ip, "slowloris_header_timeout");
+            return Err(err.into());
+        }
         debug!(
             client_ip = %peer_ip,
             connection_id = conn_id,
@@ -313,101 +455,238 @@ async fn handle_tls_connection(
     Ok(())
 }
 
+/// Stream wrapper that counts the plaintext bytes read from the client.
+struct CountingIo<T> {
+    inner: T,
+    read: Arc<AtomicU64>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let n = (buf.filled().len() - before) as u64;
+            self.read.fetch_add(n, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
 // ---------------------------------------------------------------------------
 // HTTP -> HTTPS redirect server
 // ---------------------------------------------------------------------------
 
-async fn run_http_redirect(listener: TcpListener) {
-    loop {
-        let (mut stream, peer_addr) = match listener.accept().await {
-            Ok(conn) => conn,
-            Err(err) => {
-                warn!("HTTP redirect listener accept error: {}", err);
+/// Largest request head the redirect listener reads.
+const REDIRECT_HEAD_LIMIT: usize = 4096;
+
+const REDIRECT_BODY: &str = "<html><body><h1>301 Moved Permanently</h1></body></html>";
+
+const REDIRECT_BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+    Content-Type: text/plain\r\n\
+    Content-Length: 11\r\n\
+    Connection: close\r\n\
+    \r\n\
+    Bad Request";
+
+impl ProxyServer {
+    /// Answer plain HTTP with a redirect to HTTPS. Auto-bans, the IP
+    /// blocklist and L4 protection are applied on accept, before anything
+    /// is read, and at most `server.max_redirect_connections` are served
+    /// at once.
+    async fn redirect_loop(&self, listener: &TcpListener) {
+        let header_timeout = Duration::from_secs(self.settings.server.header_timeout_secs.max(1));
+        let permits = Arc::new(Semaphore::new(self.settings.server.max_redirect_connections.max(1)));
+
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!("HTTP redirect listener accept error: {}", err);
+                    continue;
+                }
+            };
+
+            let peer_ip = peer_addr.ip();
+            let metrics = self.handler.metrics();
+
+            if self.auto_ban.is_banned(&peer_ip).is_some() || self.handler.is_ip_blocked(&peer_ip) {
+                metrics.record_http_redirect_drop(RedirectDrop::Blocked);
                 continue;
             }
-        };
 
-        tokio::spawn(async move {
-            let mut buf = vec![0u8; 4096];
-            let mut total = 0usize;
-
-            loop {
-                let read_result = tokio::time::timeout(
-                    std::time::Duration::from_secs(5),
-                    stream.read(&mut buf[total..]),
-                ).await;
-                match read_result {
-                    Ok(Ok(0)) => return,
-                    Ok(Ok(n)) => {
-                        total += n;
-                        if total >= 4
-                            && buf[..total]
-                                .windows(4)
-                                .any(|w| w == b"\r\n\r\n")
-                        {
-                            break;
-                        }
-                        if total >= buf.len() {
-                            break;
-                        }
+            if let Some(ref l4) = self.l4_tracker {
+                match l4.check_connection(peer_ip) {
+                    L4Action::Allow => {}
+                    L4Action::Drop(reason) => {
+                        self.firewall.record_l4_drop(peer_ip);
+                        self.sqlite.insert_l4_event(&peer_ip.to_string(), "drop", Some(&reason.to_string()), Some(l4.total_allowed() as i64), None);
+                        metrics.record_http_redirect_drop(RedirectDrop::L4);
+                        continue;
                     }
-                    Ok(Err(_)) => return,
-                    Err(_) => {
-                        debug!(client_ip = %peer_addr.ip(), "HTTP redirect read timeout");
-                        return;
+                    L4Action::Tarpit(reason) => {
+                        self.sqlite.insert_l4_event(&peer_ip.to_string(), "tarpit", Some(&reason.to_string()), None, None);
+                        metrics.record_http_redirect_drop(RedirectDrop::L4);
+                        let delay = l4.tarpit_delay();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            drop(stream);
+                        });
+                        continue;
                     }
                 }
             }
 
-            let raw = String::from_utf8_lossy(&buf[..total]);
-            let mut host = String::new();
-            let mut path = String::from("/");
+            let Ok(permit) = Arc::clone(&permits).try_acquire_owned() else {
+                debug!(client_ip = %peer_ip, "HTTP redirect listener at capacity, dropping");
+                metrics.record_http_redirect_drop(RedirectDrop::Capacity);
+                continue;
+            };
 
-            for (i, line) in raw.lines().enumerate() {
-                if i == 0 {
-                    let mut parts = line.split_whitespace();
-                    let _method = parts.next();
-                    if let Some(p) = parts.next() {
-                        path = p.to_string();
-                    }
-                } else if let Some((name, value)) = line.split_once(':') {
-                    if name.trim().eq_ignore_ascii_case("host") {
-                        host = value.trim().to_string();
-                    }
+            let l4_tracker = self.l4_tracker.clone();
+            if let Some(ref l4) = l4_tracker {
+                l4.register_connection(peer_ip);
+            }
+            let handler = Arc::clone(&self.handler);
+            tokio::spawn(async move {
+                serve_redirect(stream, peer_ip, &handler, header_timeout).await;
+                if let Some(ref l4) = l4_tracker {
+                    l4.unregister_connection(peer_ip);
                 }
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Read one request head and answer it with a probe response or a 301 to
+/// the same host and path over HTTPS.
+async fn serve_redirect(mut stream: TcpStream, peer_ip: IpAddr, handler: &HttpHandler, header_timeout: Duration) {
+    let mut buf = [0u8; REDIRECT_HEAD_LIMIT];
+    let read_head = async {
+        let mut total = 0usize;
+        loop {
+            let n = stream.read(&mut buf[total..]).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            // Only the bytes just read and the three before them can
+            // complete the terminator
+            let start = total.saturating_sub(3);
+            total += n;
+            if let Some(end) = buf[start..total].windows(4).position(|w| w == b"\r\n\r\n") {
+                return Some(start + end);
             }
+            if total == buf.len() {
+                return None;
+            }
+        }
+    };
+    let head = match tokio::time::timeout(header_timeout, read_head).await {
+        Ok(Some(end)) => parse_redirect_head(&buf[..end]),
+        Ok(None) => None,
+        Err(_) => {
+            debug!(client_ip = %peer_ip, "HTTP redirect read timeout");
+            None
+        }
+    };
 
-            let redirect_host = host.split(':').next().unwrap_or(&host);
-            let location = format!("https://{}{}", redirect_host, path);
-            let body = format!(
-                "<html><body><h1>301 Moved Permanently</h1>\
-                 <p><a href=\"{loc}\">{loc}</a></p></body></html>",
-                loc = location
-            );
-
-            let response = format!(
-                "HTTP/1.1 301 Moved Permanently\r\n\
-                 Location: {location}\r\n\
-                 Content-Type: text/html; charset=utf-8\r\n\
-                 Content-Length: {len}\r\n\
-                 Connection: close\r\n\
-                 \r\n\
-                 {body}",
-                location = location,
-                len = body.len(),
-                body = body,
-            );
-
-            let _ = stream.write_all(response.as_bytes()).await;
-            let _ = stream.flush().await;
-
-            debug!(
-                client_ip = %peer_addr.ip(),
-                redirect_to = %location,
-                "HTTP -> HTTPS redirect"
-            );
-        });
+    let Some((path, host)) = head else {
+        handler.metrics().record_http_redirect_drop(RedirectDrop::Malformed);
+        let _ = stream.write_all(REDIRECT_BAD_REQUEST).await;
+        return;
+    };
+
+    // Load balancer probes get their answer instead of a redirect.
+    if let Some((status, body)) = handler.probe(path) {
+        let response = format!(
+            "HTTP/1.1 {status}\r\n\
+             Content-Type: application/json\r\n\
+             Cache-Control: no-store\r\n\
+             Content-Length: {len}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {body}",
+            status = status,
+            len = body.len(),
+            body = body,
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        return;
+    }
+
+    let response = format!(
+        "HTTP/1.1 301 Moved Permanently\r\n\
+         Location: https://{host}{path}\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {len}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        len = REDIRECT_BODY.len(),
+        body = REDIRECT_BODY,
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    handler.metrics().record_http_redirect();
+    debug!(client_ip = %peer_ip, host = %host, path = %path, "HTTP -> HTTPS redirect");
+}
+
+/// `(path, host)` of a request head, the host without its port. `None`
+/// unless the request line has an origin-form target and the `Host`
+/// header is a plain hostname or IP literal, so neither can smuggle
+/// anything into the `Location` header.
+fn parse_redirect_head(head: &[u8]) -> Option<(&str, &str)> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let (_method, path) = (request_line.next()?, request_line.next()?);
+    if !path.starts_with('/') || !path.bytes().all(|b| b.is_ascii_graphic()) {
+        return None;
     }
+
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))?
+        .1
+        .trim();
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal, keeping its brackets
+        Some(rest) => &host[..rest.find(']')? + 2],
+        None => host.split(':').next()?,
+    };
+    let valid = !host.is_empty()
+        && host.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'[' | b']' | b':'));
+    valid.then_some((path, host))
 }
 
 // ---------------------------------------------------------------------------
@@ -421,6 +700,31 @@ struct ConnectionGuard {
 
 impl Drop for ConnectionGuard {
     fn drop(&mut self) {
-        self.connections.remove(self.id);
+        // An upgraded connection outlives the HTTP connection future; the
+        // WebSocket relay removes it when it closes.
+        if !self.connections.is_upgraded(self.id) {
+            self.connections.remove(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect_head_yields_path_and_bare_host() {
+        let head = b"GET /shop?q=1 HTTP/1.1\r\nUser-Agent: curl\r\nHOST: Example.com:80";
+        assert_eq!(parse_redirect_head(head), Some(("/shop?q=1", "Example.com")));
+        let head = b"GET / HTTP/1.1\r\nHost: [2001:db8::1]:8080";
+        assert_eq!(parse_redirect_head(head), Some(("/", "[2001:db8::1]")));
+
+        // Nothing that could end up in the Location header unchecked
+        assert_eq!(parse_redirect_head(b"GET / HTTP/1.1\r\nX: y"), None);
+        assert_eq!(parse_redirect_head(b"GET / HTTP/1.1\r\nHost: a.com/evil"), None);
+        assert_eq!(parse_redirect_head(b"GET / HTTP/1.1\r\nHost: a.com\rSet-Cookie: x"), None);
+        assert_eq!(parse_redirect_head(b"GET http://a.com/ HTTP/1.1\r\nHost: a.com"), None);
+        assert_eq!(parse_redirect_head(b"GET /\x7fx HTTP/1.1\r\nHost: a.com"), None);
+        assert_eq!(parse_redirect_head(b"\xff\xfe"), None);
     }
 }
@@ -1,15 +1,213 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use tracing::info;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
 
-use crate::config::service::ServiceConfig;
-use crate::storage::sqlite::SqliteStore;
+use crate::config::service::{decode_json_column, decode_upstreams, normalize_routes, LoadBalanceStrategy, RequestEncodingPolicy, ServiceConfig};
+use crate::config::settings::CircuitBreakerConfig;
+use crate::protection::challenge::ExemptPath;
+use crate::storage::sqlite::{ServiceRow, SqliteStore};
+
+use super::circuit_breaker::{CircuitBreaker, CircuitStatus};
+
+/// A single upstream address belonging to a service.
+pub struct Backend {
+    pub address: String,
+    healthy: AtomicBool,
+    active: AtomicU64,
+    checks: Mutex<CheckState>,
+}
+
+/// Outcome of recent health checks of one backend.
+#[derive(Default)]
+struct CheckState {
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    last_error: Option<String>,
+    last_status: Option<u16>,
+    last_latency_ms: Option<u64>,
+    last_check: Option<DateTime<Utc>>,
+}
+
+/// Result of one active health check of a backend.
+pub struct CheckOutcome {
+    pub result: Result<(), String>,
+    /// Response status of an HTTP probe that got an answer.
+    pub status: Option<u16>,
+    pub latency: Duration,
+}
+
+/// Health of one backend, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendHealth {
+    pub address: String,
+    pub healthy: bool,
+    pub active_connections: u64,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub last_error: Option<String>,
+    /// Status the last HTTP probe got back.
+    pub last_status: Option<u16>,
+    pub last_latency_ms: Option<u64>,
+    pub last_check: Option<DateTime<Utc>>,
+}
+
+impl Backend {
+    fn new(address: String) -> Self {
+        Self {
+            address,
+            healthy: AtomicBool::new(true), // assume healthy until proven otherwise
+            active: AtomicU64::new(0),
+            checks: Mutex::new(CheckState::default()),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// The upstream chosen for one request. Holds an in-flight slot on the
+/// backend (for least-connections) until dropped.
+pub struct BackendLease {
+    address: String,
+    backend: Option<Arc<Backend>>,
+}
+
+impl BackendLease {
+    /// A lease for an address that is not managed by any service (the
+    /// global default upstream).
+    pub fn detached(address: String) -> Self {
+        Self { address, backend: None }
+    }
+
+    fn acquire(backend: Arc<Backend>) -> Self {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            address: backend.address.clone(),
+            backend: Some(backend),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Passive health check: take the backend out of rotation after a
+    /// connection-level failure. The `HealthChecker` restores it.
+    pub fn mark_unhealthy(&self) {
+        if let Some(ref b) = self.backend {
+            b.checks.lock().consecutive_successes = 0;
+            if b.healthy.swap(false, Ordering::Relaxed) {
+                warn!(upstream = %b.address, "Backend marked unhealthy after request failure");
+            }
+        }
+    }
+}
+
+impl Drop for BackendLease {
+    fn drop(&mut self) {
+        if let Some(ref b) = self.backend {
+            b.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Outcome of asking to send one request to a service's upstreams.
+pub enum Admission {
+    /// Holds one of the service's `max_connections` slots until dropped.
+    Admitted(Option<OwnedSemaphorePermit>),
+    /// `max_connections` requests are already in flight.
+    Saturated,
+    /// The service's circuit is open; retry after the given time.
+    CircuitOpen(Duration),
+}
+
+/// In-flight upstream requests and circuit state of a service.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamLoad {
+    pub in_flight: usize,
+    /// `None` when `max_connections` is 0 (unlimited).
+    pub max_connections: Option<usize>,
+    pub circuit: CircuitStatus,
+}
 
 /// Per-service health state.
 struct ServiceHealth {
     config: Arc<ServiceConfig>,
-    healthy: AtomicBool,
+    /// The service's own upstreams, then those of each route. A backend
+    /// used by several of them is shared, so it is checked once.
+    backends: BackendGroup,
+    routes: Vec<BackendGroup>,
+    /// Every distinct backend.
+    all_backends: Vec<Arc<Backend>>,
+    /// One permit per request allowed upstream at once (`max_connections`).
+    in_flight: Arc<Semaphore>,
+    circuit: CircuitBreaker,
+}
+
+/// Upstreams load balanced together.
+struct BackendGroup {
+    backends: Vec<Arc<Backend>>,
+    rr_counter: AtomicUsize,
+}
+
+impl ServiceHealth {
+    fn new(config: Arc<ServiceConfig>) -> Self {
+        let mut all_backends: Vec<Arc<Backend>> = Vec::new();
+        let mut group = |addresses: &[String]| {
+            let backends = addresses
+                .iter()
+                .map(|addr| match all_backends.iter().find(|b| &b.address == addr) {
+                    Some(b) => Arc::clone(b),
+                    None => {
+                        let b = Arc::new(Backend::new(addr.clone()));
+                        all_backends.push(Arc::clone(&b));
+                        b
+                    }
+                })
+                .collect();
+            BackendGroup { backends, rr_counter: AtomicUsize::new(0) }
+        };
+        let backends = group(&config.upstream_address);
+        let routes = config.routes.iter().map(|r| group(&r.upstream_address)).collect();
+        Self {
+            in_flight: Arc::new(Semaphore::new(in_flight_limit(config.max_connections))),
+            circuit: CircuitBreaker::new(),
+            config,
+            backends,
+            routes,
+            all_backends,
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.all_backends.iter().any(|b| b.is_healthy())
+    }
+
+    /// Backends for requests on `route` (an index into the service's
+    /// routes), or the service's own.
+    fn group(&self, route: Option<usize>) -> &BackendGroup {
+        route.and_then(|i| self.routes.get(i)).unwrap_or(&self.backends)
+    }
+}
+
+/// Permits for a service's `max_connections`; 0 means unlimited.
+fn in_flight_limit(max_connections: usize) -> usize {
+    match max_connections {
+        0 => Semaphore::MAX_PERMITS,
+        n => n.min(Semaphore::MAX_PERMITS),
+    }
 }
 
 /// Routes incoming requests to the correct backend service based on the Host header.
@@ -40,19 +238,170 @@ impl ServiceRouter {
         Some(health.config.clone())
     }
 
-    /// Check if a service is healthy.
+    /// Check if a service is healthy (at least one backend, of the service
+    /// or of one of its routes, is up).
     pub fn is_healthy(&self, service_id: &str) -> bool {
         self.services
             .get(service_id)
-            .map(|h| h.healthy.load(Ordering::Relaxed))
+            .map(|h| h.is_healthy())
             .unwrap_or(false)
     }
 
-    /// Set the health status of a service.
-    pub fn set_health(&self, service_id: &str, healthy: bool) {
+    /// Record the result of a health check of one backend. The backend is
+    /// taken out of rotation after `unhealthy_threshold` consecutive
+    /// failures and returns after `healthy_threshold` consecutive
+    /// successes. Returns `true` if the backend's status changed.
+    pub fn record_check(
+        &self,
+        service_id: &str,
+        address: &str,
+        outcome: &CheckOutcome,
+        healthy_threshold: u32,
+        unhealthy_threshold: u32,
+    ) -> bool {
+        let mut changed = false;
         if let Some(h) = self.services.get(service_id) {
-            h.healthy.store(healthy, Ordering::Relaxed);
+            for b in h.all_backends.iter().filter(|b| b.address == address) {
+                let mut checks = b.checks.lock();
+                checks.last_check = Some(Utc::now());
+                checks.last_status = outcome.status;
+                checks.last_latency_ms = Some(outcome.latency.as_millis() as u64);
+                let healthy = match &outcome.result {
+                    Ok(()) => {
+                        checks.consecutive_successes += 1;
+                        checks.consecutive_failures = 0;
+                        checks.last_error = None;
+                        b.is_healthy() || checks.consecutive_successes >= healthy_threshold.max(1)
+                    }
+                    Err(err) => {
+                        checks.consecutive_failures += 1;
+                        checks.consecutive_successes = 0;
+                        checks.last_error = Some(err.clone());
+                        b.is_healthy() && checks.consecutive_failures < unhealthy_threshold.max(1)
+                    }
+                };
+                let was = b.healthy.swap(healthy, Ordering::Relaxed);
+                if was != healthy {
+                    info!(service_id = %service_id, upstream = %address, healthy = healthy, "Backend health changed");
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Health check state and load of each distinct backend of a service,
+    /// its routes' included.
+    pub fn backend_status(&self, service_id: &str) -> Vec<BackendHealth> {
+        self.services
+            .get(service_id)
+            .map(|h| {
+                h.all_backends
+                    .iter()
+                    .map(|b| {
+                        let checks = b.checks.lock();
+                        BackendHealth {
+                            address: b.address.clone(),
+                            healthy: b.is_healthy(),
+                            active_connections: b.active_connections(),
+                            consecutive_failures: checks.consecutive_failures,
+                            consecutive_successes: checks.consecutive_successes,
+                            last_error: checks.last_error.clone(),
+                            last_status: checks.last_status,
+                            last_latency_ms: checks.last_latency_ms,
+                            last_check: checks.last_check,
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Admit one request to the upstreams of `service_id`: refused while the
+    /// service's circuit is open or `max_connections` requests are already
+    /// in flight. Unknown services are always admitted.
+    pub fn admit(&self, service_id: &str, breaker: &CircuitBreakerConfig) -> Admission {
+        let Some(h) = self.services.get(service_id) else {
+            return Admission::Admitted(None);
+        };
+        if let Err(retry_after) = h.circuit.try_acquire(breaker) {
+            return Admission::CircuitOpen(retry_after);
+        }
+        match Arc::clone(&h.in_flight).try_acquire_owned() {
+            Ok(permit) => Admission::Admitted(Some(permit)),
+            Err(_) => Admission::Saturated,
+        }
+    }
+
+    /// Feed the outcome of an upstream request into the service's circuit
+    /// breaker. `ok` means a response arrived, whatever its status.
+    pub fn record_upstream_result(&self, service_id: &str, ok: bool, breaker: &CircuitBreakerConfig) {
+        let Some(h) = self.services.get(service_id) else {
+            return;
+        };
+        if ok {
+            if h.circuit.record_success() {
+                info!(service_id = %service_id, "Upstream circuit closed");
+            }
+        } else if h.circuit.record_failure(breaker) {
+            warn!(
+                service_id = %service_id,
+                open_secs = breaker.open_secs,
+                "Upstream circuit opened after consecutive failures"
+            );
+        }
+    }
+
+    /// In-flight requests and circuit state of a service.
+    pub fn upstream_load(&self, service_id: &str) -> Option<UpstreamLoad> {
+        self.services.get(service_id).map(|h| {
+            let limit = in_flight_limit(h.config.max_connections);
+            UpstreamLoad {
+                in_flight: limit - h.in_flight.available_permits(),
+                max_connections: (h.config.max_connections > 0).then_some(limit),
+                circuit: h.circuit.status(),
+            }
+        })
+    }
+
+    /// Pick a backend for the next request to `service_id` according to the
+    /// service's load-balancing strategy, skipping unhealthy backends. If
+    /// every backend is down we fail open and choose among all of them
+    /// rather than refuse the request outright (requests only get here when
+    /// `upstream.health_check.when_unhealthy` is `try_anyway`, or for
+    /// health-check exempt paths). Requests on one of the service's
+    /// `route`s only go to that route's backends.
+    pub fn select_backend(&self, service_id: &str, route: Option<usize>) -> Option<BackendLease> {
+        let h = self.services.get(service_id)?;
+        let group = h.group(route);
+        let healthy: Vec<&Arc<Backend>> = group.backends.iter().filter(|b| b.is_healthy()).collect();
+        let candidates: Vec<&Arc<Backend>> = if healthy.is_empty() {
+            group.backends.iter().collect()
+        } else {
+            healthy
+        };
+        if candidates.is_empty() {
+            return None;
         }
+
+        let chosen = match h.config.lb_strategy {
+            LoadBalanceStrategy::RoundRobin => {
+                let n = group.rr_counter.fetch_add(1, Ordering::Relaxed);
+                candidates[n % candidates.len()]
+            }
+            LoadBalanceStrategy::LeastConnections => candidates
+                .iter()
+                .min_by_key(|b| b.active_connections())
+                .copied()
+                .unwrap_or(candidates[0]),
+        };
+
+        Some(BackendLease::acquire(Arc::clone(chosen)))
+    }
+
+    /// How many backends requests on `route` of `service_id` may go to.
+    pub fn backend_count(&self, service_id: &str, route: Option<usize>) -> usize {
+        self.services.get(service_id).map_or(0, |h| h.group(route).backends.len())
     }
 
     /// Get the default upstream address (used when no service matches).
@@ -60,13 +409,14 @@ impl ServiceRouter {
         self.default_upstream.read().unwrap().clone()
     }
 
-    /// Add a service and register all its domains.
-    pub fn add_service(&self, config: ServiceConfig) {
-        let arc = Arc::new(config.clone());
-        let health = Arc::new(ServiceHealth {
-            config: arc,
-            healthy: AtomicBool::new(true), // assume healthy until proven otherwise
-        });
+    /// Add a service and register all its domains. Invalid routes (the
+    /// admin API refuses them) are dropped.
+    pub fn add_service(&self, mut config: ServiceConfig) {
+        if let Err(e) = normalize_routes(&mut config.routes) {
+            warn!(service_id = %config.id, error = %e, "Ignoring the service's routes");
+            config.routes.clear();
+        }
+        let health = Arc::new(ServiceHealth::new(Arc::new(config.clone())));
         for domain in &config.domains {
             let clean = domain.to_lowercase();
             self.domain_map.insert(clean, config.id.clone());
@@ -103,30 +453,13 @@ impl ServiceRouter {
     }
 
     /// Load services from the SQLite database.
-    pub fn load_from_db(&self, sqlite: &SqliteStore) -> anyhow::Result<()> {
-        let rows = sqlite.get_services().map_err(|e| anyhow::anyhow!("Failed to load services: {}", e))?;
+    pub async fn load_from_db(&self, sqlite: &SqliteStore) -> anyhow::Result<()> {
+        let rows = sqlite
+            .get_services()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to load services: {}", e))?;
         for row in rows {
-            let domains: Vec<String> = serde_json::from_str(&row.domains).unwrap_or_default();
-            let exempt_paths: Vec<String> = row.exempt_paths
-                .as_deref()
-                .and_then(|s| serde_json::from_str(s).ok())
-                .unwrap_or_default();
-            let config = ServiceConfig {
-                id: row.id,
-                name: row.name,
-                domains,
-                upstream_address: row.upstream_address,
-                enabled: row.enabled,
-                protection_level_override: row.protection_level_override.map(|v| v as u8),
-                always_challenge: row.always_challenge,
-                rate_limit_multiplier: row.rate_limit_multiplier,
-                max_connections: row.max_connections as usize,
-                connect_timeout_ms: row.connect_timeout_ms as u64,
-                response_timeout_ms: row.response_timeout_ms as u64,
-                exempt_paths,
-                created_at: Some(row.created_at),
-                updated_at: Some(row.updated_at),
-            };
+            let config = service_from_row(row);
             if config.enabled {
                 self.add_service(config);
             }
@@ -134,6 +467,34 @@ impl ServiceRouter {
         Ok(())
     }
 
+    /// Re-read the services after the database was changed underneath the
+    /// router, as by a state import. Services from the TOML config are kept
+    /// and still take precedence over database rows with the same id.
+    pub async fn reload_from_db(&self, sqlite: &SqliteStore, config_services: &[ServiceConfig]) -> anyhow::Result<()> {
+        let rows = sqlite
+            .get_services()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to load services: {}", e))?;
+        let mut wanted: HashMap<String, ServiceConfig> = HashMap::new();
+        let configs = rows.into_iter().map(service_from_row).chain(config_services.iter().cloned());
+        for config in configs.filter(|c| c.enabled) {
+            wanted.insert(config.id.clone(), config);
+        }
+        let stale: Vec<String> = self
+            .services
+            .iter()
+            .map(|r| r.key().clone())
+            .filter(|id| !wanted.contains_key(id))
+            .collect();
+        for id in stale {
+            self.remove_service(&id);
+        }
+        for config in wanted.into_values() {
+            self.update_service(config);
+        }
+        Ok(())
+    }
+
     /// Load services from the TOML config.
     pub fn load_from_config(&self, services: &[ServiceConfig]) {
         for svc in services {
@@ -148,3 +509,107 @@ impl ServiceRouter {
         self.services.len()
     }
 }
+
+fn service_from_row(row: ServiceRow) -> ServiceConfig {
+    let domains: Vec<String> = serde_json::from_str(&row.domains).unwrap_or_default();
+    let exempt_paths: Vec<ExemptPath> = row.exempt_paths
+        .as_deref()
+        .and_then(|s| serde_json::from_str(s).ok())
+        .unwrap_or_default();
+    ServiceConfig {
+        id: row.id,
+        name: row.name,
+        domains,
+        upstream_address: decode_upstreams(&row.upstream_address),
+        lb_strategy: LoadBalanceStrategy::from_str_name(&row.lb_strategy),
+        enabled: row.enabled,
+        protection_level_override: row.protection_level_override.map(|v| v as u8),
+        always_challenge: row.always_challenge,
+        rate_limit_multiplier: row.rate_limit_multiplier,
+        max_requests_per_ip_10s: row.max_requests_per_ip_10s.map(|v| v.max(0) as u64),
+        max_connections: row.max_connections as usize,
+        connect_timeout_ms: row.connect_timeout_ms as u64,
+        response_timeout_ms: row.response_timeout_ms as u64,
+        exempt_paths,
+        upstream_tls_verify: row.upstream_tls_verify,
+        upstream_sni_host: row.upstream_sni_host,
+        upstream_http2: row.upstream_http2,
+        add_request_headers: decode_json_column(row.add_request_headers.as_deref()),
+        remove_request_headers: decode_json_column(row.remove_request_headers.as_deref()),
+        add_response_headers: decode_json_column(row.add_response_headers.as_deref()),
+        remove_response_headers: decode_json_column(row.remove_response_headers.as_deref()),
+        allowed_countries: decode_json_column(row.allowed_countries.as_deref()),
+        allowed_asns: decode_json_column(row.allowed_asns.as_deref()),
+        blocked_countries: decode_json_column(row.blocked_countries.as_deref()),
+        challenged_countries: decode_json_column(row.challenged_countries.as_deref()),
+        country_exceptions: decode_json_column(row.country_exceptions.as_deref()),
+        clearance_cookie_domain: row.clearance_cookie_domain,
+        clearance_ttl_secs: row.clearance_ttl_secs.map(|v| v.max(0) as u64),
+        cors_allowed_origins: decode_json_column(row.cors_allowed_origins.as_deref()),
+        maintenance_mode: row.maintenance_mode,
+        maintenance_html_path: row.maintenance_html_path,
+        body_inspection: row.body_inspection,
+        login_username_field: row.login_username_field,
+        max_body_size_mb: row.max_body_size_mb.map(|v| v.max(0) as u64),
+        always_online: row.always_online,
+        always_online_banner: row.always_online_banner,
+        request_encoding: RequestEncodingPolicy::from_str_name(&row.request_encoding),
+        routes: decode_json_column(row.routes.as_deref()),
+        waiting_room: decode_json_column(row.waiting_room.as_deref()),
+        health_check: decode_json_column(row.health_check.as_deref()),
+        created_at: Some(row.created_at),
+        updated_at: Some(row.updated_at),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_pick_their_own_upstreams() {
+        let config: ServiceConfig = serde_json::from_value(serde_json::json!({
+            "id": "app", "name": "App", "domains": ["app.test", "api.app.test"],
+            "upstream_address": "web:80",
+            "routes": [
+                {"path_prefix": "/api/", "upstream_address": ["api1:80", "api2:80"], "response_timeout_ms": 5000},
+                {"id": "admin", "host": "api.*", "path_glob": "/admin/*", "upstream_address": "web:80"},
+            ],
+        }))
+        .unwrap();
+        let router = ServiceRouter::new("127.0.0.1:8080");
+        router.add_service(config);
+        let svc = router.get_service("app").unwrap();
+
+        assert_eq!(svc.routes[0].id, "route-1");
+        assert_eq!(svc.route_for("app.test", "/api/users"), Some(0));
+        assert_eq!(svc.route_for("api.app.test:443", "/admin/x"), Some(1));
+        assert_eq!(svc.route_for("app.test", "/admin/x"), None);
+        assert_eq!(svc.all_upstreams(), ["web:80", "api1:80", "api2:80"]);
+
+        let picked: Vec<String> = (0..2)
+            .map(|_| router.select_backend("app", Some(0)).unwrap().address().to_string())
+            .collect();
+        assert_eq!(picked, ["api1:80", "api2:80"]);
+        assert_eq!(router.select_backend("app", None).unwrap().address(), "web:80");
+        assert_eq!(router.backend_count("app", Some(0)), 2);
+
+        // Each distinct upstream is checked once, and a backend shared by
+        // the service and a route shares its health
+        assert_eq!(router.backend_status("app").len(), 3);
+        let refused = CheckOutcome {
+            result: Err("refused".into()),
+            status: None,
+            latency: Duration::ZERO,
+        };
+        assert!(router.record_check("app", "web:80", &refused, 1, 1));
+        assert!(router.backend_status("app").iter().all(|b| b.healthy != (b.address == "web:80")));
+        assert!(router.is_healthy("app"));
+
+        // Invalid routes are dropped rather than half applied
+        let mut bad = (*svc).clone();
+        bad.routes[1].path_prefix = Some("/admin/".into());
+        router.update_service(bad);
+        assert!(router.get_service("app").unwrap().routes.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use parking_lot::Mutex;
+
+use super::http_handler::BoxError;
+
+/// Slow-drip responses for tarpitted requests.
+///
+/// A tarpitted response is a [`TarpitBody`] that sends one byte per tick.
+/// All bodies share a single ticker task, so each one costs a counter and
+/// a waker rather than a timer, and the request that triggered it can be
+/// dropped as soon as the response head is sent.
+pub struct Tarpit {
+    tick: AtomicU64,
+    wakers: Mutex<Vec<Waker>>,
+    active: AtomicUsize,
+}
+
+impl Tarpit {
+    pub fn new() -> Self {
+        Self {
+            tick: AtomicU64::new(0),
+            wakers: Mutex::new(Vec::new()),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start the shared ticker that releases one byte of every tarpitted
+    /// response per `interval`.
+    pub fn start(self: &Arc<Self>, interval: Duration) {
+        let tarpit = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval.max(Duration::from_millis(10)));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                tarpit.tick.fetch_add(1, Ordering::Release);
+                let wakers = std::mem::take(&mut *tarpit.wakers.lock());
+                for waker in wakers {
+                    waker.wake();
+                }
+            }
+        });
+    }
+
+    /// A body that drips `bytes` bytes, one per tick, or `None` when
+    /// `max_concurrent` responses are already being dripped.
+    pub fn body(self: &Arc<Self>, bytes: u64, max_concurrent: usize) -> Option<TarpitBody> {
+        if self.active.fetch_add(1, Ordering::AcqRel) >= max_concurrent {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        Some(TarpitBody {
+            tarpit: Arc::clone(self),
+            last_tick: self.tick.load(Ordering::Acquire),
+            remaining: bytes,
+        })
+    }
+
+    /// Responses currently being dripped.
+    pub fn active_count(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Tarpit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Response body that sends a single space per [`Tarpit`] tick.
+pub struct TarpitBody {
+    tarpit: Arc<Tarpit>,
+    last_tick: u64,
+    remaining: u64,
+}
+
+impl TarpitBody {
+    /// Take the next byte if a tick has passed since the last one.
+    fn next_byte(&mut self) -> Option<Frame<Bytes>> {
+        let tick = self.tarpit.tick.load(Ordering::Acquire);
+        if tick == self.last_tick {
+            return None;
+        }
+        self.last_tick = tick;
+        self.remaining -= 1;
+        Some(Frame::data(Bytes::from_static(b" ")))
+    }
+}
+
+impl Body for TarpitBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        if let Some(frame) = self.next_byte() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        self.tarpit.wakers.lock().push(cx.waker().clone());
+        // The ticker may have fired between the check and registering.
+        match self.next_byte() {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+impl Drop for TarpitBody {
+    fn drop(&mut self) {
+        self.tarpit.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_drips_one_byte_per_tick_up_to_the_cap() {
+        let tarpit = Arc::new(Tarpit::new());
+        tarpit.start(Duration::from_millis(20));
+
+        let body = tarpit.body(3, 1).unwrap();
+        assert!(tarpit.body(3, 1).is_none(), "over max_concurrent");
+        assert_eq!(tarpit.active_count(), 1);
+
+        let started = tokio::time::Instant::now();
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(&bytes[..], b"   ");
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(tarpit.active_count(), 0);
+        assert!(tarpit.body(3, 1).is_some());
+    }
+}
@@ -0,0 +1,496 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_rustls::{ConfigBuilderExt, FixedServerNameResolver, HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::config::service::{split_upstream, upstream_socket_addr, ServiceConfig, ServiceRoute};
+use crate::config::settings::UpstreamConfig;
+
+use super::http_handler::{BoxError, ProxyBody};
+
+/// Client for both `http://` and `https://` upstreams.
+pub type UpstreamClient = HyperClient<CountingConnector, ProxyBody>;
+
+/// How long an upstream that failed an h2c exchange is sent HTTP/1.1
+/// before HTTP/2 is tried again.
+const H2C_FALLBACK: Duration = Duration::from_secs(300);
+
+/// Streams opened on a new HTTP/2 connection before the upstream's
+/// SETTINGS frame says how many it allows. Requests beyond the limit wait
+/// for a stream instead of opening more connections.
+const H2_INITIAL_MAX_STREAMS: usize = 100;
+
+/// Protocol spoken to an upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamProtocol {
+    Http1,
+    /// HTTP/2 with prior knowledge, for plain `http://` upstreams.
+    H2c,
+    /// HTTP/2 when the upstream offers it in the TLS handshake, for
+    /// `https://` upstreams; HTTP/1.1 otherwise.
+    Alpn,
+}
+
+/// Upstream HTTP clients keyed by the connection options of the service.
+///
+/// Services with the same `upstream_tls_verify`, `upstream_sni_host`,
+/// `connect_timeout_ms` and protocol share one client and connection pool;
+/// each distinct combination gets its own, built on first use.
+pub struct UpstreamClients {
+    clients: DashMap<ClientOptions, UpstreamClient>,
+    pools: PoolCounterMap,
+    /// Upstreams sent HTTP/1.1 despite `upstream_http2` until the instant.
+    h2c_fallback: DashMap<String, Instant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientOptions {
+    verify: bool,
+    sni_host: Option<String>,
+    /// 0 waits for the OS to give up.
+    connect_timeout_ms: u64,
+    protocol: UpstreamProtocol,
+}
+
+/// Connection counters per upstream `host:port`, shared by all clients.
+type PoolCounterMap = Arc<DashMap<String, Arc<PoolCounters>>>;
+
+#[derive(Default)]
+struct PoolCounters {
+    open: AtomicU64,
+    opened: AtomicU64,
+    requests: AtomicU64,
+    retries: AtomicU64,
+}
+
+/// Connection reuse towards one upstream, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    pub open_connections: u64,
+    pub connections_opened: u64,
+    pub requests: u64,
+    /// Share of requests that went out on an already open connection.
+    pub reuse_ratio: Option<f64>,
+    /// Requests retried after a connection-level error.
+    pub retries: u64,
+    /// Seconds until HTTP/2 is tried again after the upstream failed it.
+    pub http1_fallback_secs: Option<u64>,
+}
+
+impl UpstreamClients {
+    pub fn new() -> Self {
+        Self {
+            clients: DashMap::new(),
+            pools: Arc::new(DashMap::new()),
+            h2c_fallback: DashMap::new(),
+        }
+    }
+
+    /// Client to send one request to `address`, an upstream of `service`
+    /// (of its `route`, if given) or the default upstream with the options
+    /// of the global `[upstream]` section, and the protocol it speaks.
+    pub fn for_upstream(
+        &self,
+        service: Option<&ServiceConfig>,
+        route: Option<&ServiceRoute>,
+        upstream: &UpstreamConfig,
+        address: &str,
+    ) -> (UpstreamClient, UpstreamProtocol) {
+        let key = upstream_socket_addr(address);
+        pool_counters(&self.pools, &key).requests.fetch_add(1, Ordering::Relaxed);
+        let protocol = if !service.is_some_and(|s| s.upstream_http2) {
+            UpstreamProtocol::Http1
+        } else if split_upstream(address).0 == "https" {
+            UpstreamProtocol::Alpn
+        } else if self.h2c_fallback_remaining(&key).is_some() {
+            UpstreamProtocol::Http1
+        } else {
+            UpstreamProtocol::H2c
+        };
+        let options = ClientOptions {
+            verify: service.is_none_or(|s| s.upstream_tls_verify),
+            sni_host: service.and_then(|s| s.upstream_sni_host.clone()),
+            connect_timeout_ms: service.map_or(upstream.connect_timeout_ms, |s| {
+                route.and_then(|r| r.connect_timeout_ms).unwrap_or(s.connect_timeout_ms)
+            }),
+            protocol,
+        };
+        if let Some(client) = self.clients.get(&options) {
+            return (client.clone(), protocol);
+        }
+        let client = self
+            .clients
+            .entry(options.clone())
+            .or_insert_with(|| build_client(&options, Arc::clone(&self.pools)))
+            .clone();
+        (client, protocol)
+    }
+
+    /// Send HTTP/1.1 to `address` for a while after it failed an h2c
+    /// exchange, e.g. because it does not speak HTTP/2 at all.
+    pub fn fall_back_to_http1(&self, address: &str) {
+        self.h2c_fallback
+            .insert(upstream_socket_addr(address), Instant::now() + H2C_FALLBACK);
+    }
+
+    fn h2c_fallback_remaining(&self, key: &str) -> Option<Duration> {
+        let until = *self.h2c_fallback.get(key)?;
+        let remaining = until.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            self.h2c_fallback.remove_if(key, |_, u| *u == until);
+        }
+        remaining
+    }
+
+    /// Count a retry of a request that failed against `address`.
+    pub fn record_retry(&self, address: &str) {
+        let key = upstream_socket_addr(address);
+        pool_counters(&self.pools, &key).retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Retries per upstream (`host:port`) since startup, sorted by upstream.
+    pub fn retry_counts(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self
+            .pools
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().retries.load(Ordering::Relaxed)))
+            .filter(|(_, retries)| *retries > 0)
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Connection counters for `address` since startup.
+    pub fn pool_stats(&self, address: &str) -> PoolStats {
+        let key = upstream_socket_addr(address);
+        let counters = self.pools.get(&key).map(|c| Arc::clone(&c)).unwrap_or_default();
+        let requests = counters.requests.load(Ordering::Relaxed);
+        let opened = counters.opened.load(Ordering::Relaxed);
+        PoolStats {
+            open_connections: counters.open.load(Ordering::Relaxed),
+            connections_opened: opened,
+            requests,
+            reuse_ratio: (requests > 0).then(|| 1.0 - opened.min(requests) as f64 / requests as f64),
+            retries: counters.retries.load(Ordering::Relaxed),
+            http1_fallback_secs: self.h2c_fallback_remaining(&key).map(|d| d.as_secs()),
+        }
+    }
+}
+
+impl Default for UpstreamClients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn build_client(options: &ClientOptions, pools: PoolCounterMap) -> UpstreamClient {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring provider supports the default protocol versions");
+    let tls = if options.verify {
+        builder.with_webpki_roots().with_no_client_auth()
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+            .with_no_client_auth()
+    };
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http();
+    let sni_host = options.sni_host.as_deref();
+    let connector = match sni_host.map(|h| ServerName::try_from(h.to_string())) {
+        Some(Ok(name)) => connector.with_server_name_resolver(FixedServerNameResolver::new(name)),
+        Some(Err(e)) => {
+            warn!(sni_host = ?sni_host, "Invalid upstream_sni_host, using the upstream host: {}", e);
+            connector
+        }
+        None => connector,
+    };
+    // Only the TCP connect is bounded here; a stalled TLS handshake runs
+    // into the response timeout.
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    if options.connect_timeout_ms > 0 {
+        http.set_connect_timeout(Some(Duration::from_millis(options.connect_timeout_ms)));
+    }
+    let connector = match options.protocol {
+        UpstreamProtocol::Http1 => connector.enable_http1().wrap_connector(http),
+        UpstreamProtocol::H2c | UpstreamProtocol::Alpn => connector.enable_all_versions().wrap_connector(http),
+    };
+
+    let mut builder = HyperClient::builder(TokioExecutor::new());
+    builder
+        .pool_idle_timeout(Duration::from_secs(30))
+        .pool_max_idle_per_host(128)
+        .pool_timer(TokioTimer::new());
+    if options.protocol != UpstreamProtocol::Http1 {
+        builder
+            .http2_only(options.protocol == UpstreamProtocol::H2c)
+            .http2_initial_max_send_streams(H2_INITIAL_MAX_STREAMS)
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_timeout(Duration::from_secs(10))
+            .timer(TokioTimer::new());
+    }
+    builder.build(CountingConnector { inner: connector, pools })
+}
+
+fn pool_counters(pools: &PoolCounterMap, key: &str) -> Arc<PoolCounters> {
+    if let Some(counters) = pools.get(key) {
+        return Arc::clone(&counters);
+    }
+    Arc::clone(&pools.entry(key.to_string()).or_default())
+}
+
+/// Connector that counts the connections it opens per upstream.
+#[derive(Clone)]
+pub struct CountingConnector {
+    inner: HttpsConnector<HttpConnector>,
+    pools: PoolCounterMap,
+}
+
+impl tower::Service<Uri> for CountingConnector {
+    type Response = CountedConnection<<HttpsConnector<HttpConnector> as tower::Service<Uri>>::Response>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let port = dst
+            .port_u16()
+            .unwrap_or(if dst.scheme_str() == Some("https") { 443 } else { 80 });
+        let counters = pool_counters(&self.pools, &format!("{}:{}", dst.host().unwrap_or_default(), port));
+        let connecting = self.inner.call(dst);
+        Box::pin(async move {
+            let inner = connecting.await?;
+            counters.opened.fetch_add(1, Ordering::Relaxed);
+            counters.open.fetch_add(1, Ordering::Relaxed);
+            Ok(CountedConnection { inner, counters })
+        })
+    }
+}
+
+/// Upstream connection that counts as open until dropped.
+pub struct CountedConnection<T> {
+    inner: T,
+    counters: Arc<PoolCounters>,
+}
+
+impl<T> Drop for CountedConnection<T> {
+    fn drop(&mut self) {
+        self.counters.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: Connection> Connection for CountedConnection<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl<T: Read + Unpin> Read for CountedConnection<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: Write + Unpin> Write for CountedConnection<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+}
+
+/// Certificate verifier for `upstream_tls_verify = false`: any certificate
+/// is accepted, but handshake signatures are still checked so the session
+/// keys belong to whoever presented it.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+
+    use super::*;
+    use crate::config::defaults::default_upstream_config;
+    use crate::proxy::http_handler::empty_body;
+
+    /// Upstream answering every request with 200, over HTTP/2 (h2c) or
+    /// HTTP/1.1 only.
+    async fn upstream(http2: bool) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|_req: Request<hyper::body::Incoming>| async {
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok"))))
+                });
+                let io = TokioIo::new(stream);
+                tokio::spawn(async move {
+                    if http2 {
+                        let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                            .serve_connection(io, service)
+                            .await;
+                    } else {
+                        let _ = hyper::server::conn::http1::Builder::new().serve_connection(io, service).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn h2_service() -> ServiceConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": "h2", "name": "H2", "domains": [], "upstream_address": "127.0.0.1:1",
+            "upstream_http2": true,
+        }))
+        .unwrap()
+    }
+
+    fn tls_service(verify: bool, sni: Option<&str>) -> ServiceConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": "tls", "name": "TLS", "domains": [], "upstream_address": "https://backend.internal",
+            "upstream_tls_verify": verify, "upstream_sni_host": sni,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_clients_are_shared_per_tls_options() {
+        let clients = UpstreamClients::new();
+        let upstream_config = default_upstream_config();
+        let addr = "https://backend.internal";
+
+        // A service with the default options uses the default client
+        clients.for_upstream(None, None, &upstream_config, addr);
+        clients.for_upstream(Some(&tls_service(true, None)), None, &upstream_config, addr);
+        assert_eq!(clients.clients.len(), 1);
+
+        // One more per (verify, sni) pair, reused for the same pair
+        for (verify, sni) in [(false, None), (true, Some("a.internal")), (false, Some("a.internal"))] {
+            clients.for_upstream(Some(&tls_service(verify, sni)), None, &upstream_config, addr);
+            clients.for_upstream(Some(&tls_service(verify, sni)), None, &upstream_config, "https://other.internal");
+        }
+        assert_eq!(clients.clients.len(), 4);
+        assert_eq!(clients.pool_stats(addr).requests, 5);
+    }
+
+    #[tokio::test]
+    async fn test_h2c_multiplexes_requests_and_falls_back_to_http1() {
+        let clients = UpstreamClients::new();
+        let service = h2_service();
+        let upstream_config = default_upstream_config();
+        let send = |addr: String| {
+            let (client, protocol) = clients.for_upstream(Some(&service), None, &upstream_config, &addr);
+            let req = Request::get(format!("http://{}/", addr)).body(empty_body()).unwrap();
+            async move { (client.request(req).await, protocol) }
+        };
+
+        // Concurrent requests share one HTTP/2 connection.
+        let h2 = upstream(true).await;
+        let results = futures_util::future::join_all((0..10).map(|_| send(h2.clone()))).await;
+        for (result, protocol) in results {
+            assert_eq!(protocol, UpstreamProtocol::H2c);
+            assert_eq!(result.unwrap().status(), 200);
+        }
+        let stats = clients.pool_stats(&h2);
+        assert_eq!((stats.requests, stats.connections_opened, stats.open_connections), (10, 1, 1));
+        assert_eq!(stats.reuse_ratio, Some(0.9));
+
+        // An HTTP/1.1-only upstream fails h2c without a connect error; once
+        // marked, it is spoken to over HTTP/1.1.
+        let h1 = upstream(false).await;
+        let (result, _) = send(h1.clone()).await;
+        assert!(!result.err().unwrap().is_connect());
+        clients.fall_back_to_http1(&h1);
+        let (result, protocol) = send(h1.clone()).await;
+        assert_eq!(protocol, UpstreamProtocol::Http1);
+        assert_eq!(result.unwrap().status(), 200);
+        assert!(clients.pool_stats(&h1).http1_fallback_secs.is_some());
+        assert_eq!(clients.pool_stats(&h2).http1_fallback_secs, None);
+    }
+}
@@ -0,0 +1,356 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::service::{ServiceConfig, WaitingRoomConfig};
+use crate::models::threat::ProtectionLevel;
+use crate::protection::challenge::ChallengeSystem;
+
+// ---------------------------------------------------------------------------
+// Waiting room – queue visitors while a service's upstreams are saturated
+// ---------------------------------------------------------------------------
+
+/// Cookie holding a visitor's signed queue position.
+const QUEUE_COOKIE: &str = "fortress_queue";
+
+/// A visitor who stays away longer than this queues again.
+const QUEUE_COOKIE_MAX_AGE_SECS: u64 = 3600;
+
+/// Signature purpose of the position cookie (see
+/// [`ChallengeSystem::sign_for_ip`]).
+const QUEUE_SIGNATURE_PURPOSE: &str = "queue";
+
+/// Whether a service's waiting room follows the protection level or was
+/// turned on or off through the admin API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueMode {
+    /// Open from the service's `waiting_room.activate_at_level`.
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+impl QueueMode {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => QueueMode::On,
+            2 => QueueMode::Off,
+            _ => QueueMode::Auto,
+        }
+    }
+}
+
+/// What to do with a request the pipeline passed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ticket {
+    /// Forward it: the room is closed, or nobody is queued and the
+    /// upstreams have room.
+    Pass,
+    /// Its position came up: forward it and issue the clearance cookie.
+    Admit,
+    /// Serve the queue page. `place` is 1 for the next visitor let in;
+    /// `set_cookie` carries a newly issued position.
+    Wait { place: u64, set_cookie: Option<String> },
+}
+
+/// Queue state of a service, for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStatus {
+    pub mode: QueueMode,
+    pub active: bool,
+    /// Positions handed out that have not come up yet.
+    pub queue_length: u64,
+    /// Positions handed out and let in since the queue was created.
+    pub issued: u64,
+    pub admitted: u64,
+}
+
+/// In-memory queue of one service. Positions are handed out from `issued`
+/// and let in up to `admitted`, which moves at `admit_per_sec`.
+struct ServiceQueue {
+    /// Random per queue and signed into position cookies, so positions
+    /// from before a restart are not honoured.
+    epoch: u64,
+    issued: AtomicU64,
+    admitted: AtomicU64,
+    /// Milliseconds after `created` up to which admissions were counted.
+    counted_until_ms: AtomicU64,
+    created: Instant,
+    mode: AtomicU8,
+}
+
+impl ServiceQueue {
+    fn new() -> Self {
+        Self {
+            epoch: rand::random(),
+            issued: AtomicU64::new(0),
+            admitted: AtomicU64::new(0),
+            counted_until_ms: AtomicU64::new(0),
+            created: Instant::now(),
+            mode: AtomicU8::new(QueueMode::Auto as u8),
+        }
+    }
+
+    fn mode(&self) -> QueueMode {
+        QueueMode::from_u8(self.mode.load(Ordering::Relaxed))
+    }
+
+    fn is_active(&self, config: &WaitingRoomConfig, level: ProtectionLevel) -> bool {
+        match self.mode() {
+            QueueMode::On => true,
+            QueueMode::Off => false,
+            QueueMode::Auto => config.activate_at_level.is_some_and(|at| level as u8 >= at),
+        }
+    }
+
+    /// Move `admitted` on by the positions due since the last call. While
+    /// nobody is waiting no admissions are banked, so a queue that forms
+    /// later is not let in all at once.
+    fn advance(&self, admit_per_sec: f64) {
+        let now_ms = self.created.elapsed().as_millis() as u64;
+        let issued = self.issued.load(Ordering::Acquire);
+        if self.admitted.load(Ordering::Acquire) >= issued {
+            self.counted_until_ms.fetch_max(now_ms, Ordering::AcqRel);
+            return;
+        }
+        let since = self.counted_until_ms.load(Ordering::Acquire);
+        let due = (now_ms.saturating_sub(since) as f64 * admit_per_sec / 1000.0) as u64;
+        if due == 0 {
+            return;
+        }
+        let counted_ms = (due as f64 * 1000.0 / admit_per_sec) as u64;
+        if self
+            .counted_until_ms
+            .compare_exchange(since, since + counted_ms, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            let _ = self.admitted.fetch_update(Ordering::AcqRel, Ordering::Acquire, |a| {
+                Some((a + due).min(self.issued.load(Ordering::Acquire)))
+            });
+        }
+    }
+}
+
+/// Waiting rooms of all services, keyed by service id. Queues are created
+/// on first use and live for the process.
+pub struct WaitingRoom {
+    queues: DashMap<String, Arc<ServiceQueue>>,
+    challenge: Arc<ChallengeSystem>,
+}
+
+impl WaitingRoom {
+    pub fn new(challenge: Arc<ChallengeSystem>) -> Self {
+        Self {
+            queues: DashMap::new(),
+            challenge,
+        }
+    }
+
+    fn queue(&self, service_id: &str) -> Arc<ServiceQueue> {
+        if let Some(queue) = self.queues.get(service_id) {
+            return Arc::clone(&queue);
+        }
+        Arc::clone(&self.queues.entry(service_id.to_string()).or_insert_with(|| Arc::new(ServiceQueue::new())))
+    }
+
+    /// Whether the service's room is open at protection level `level`.
+    pub fn is_active(&self, service: &ServiceConfig, level: ProtectionLevel) -> bool {
+        service.waiting_room.as_ref().is_some_and(|config| self.queue(&service.id).is_active(config, level))
+    }
+
+    /// Decide on a request from `ip` without clearance, with `in_flight`
+    /// requests to the service's upstreams. A valid position cookie keeps
+    /// the visitor's place; others are queued while the upstreams are over
+    /// `max_in_flight` or anyone is already waiting. `secure` marks a new
+    /// position cookie `Secure`.
+    pub fn check(
+        &self,
+        service: &ServiceConfig,
+        level: ProtectionLevel,
+        in_flight: usize,
+        ip: &IpAddr,
+        cookies: Option<&str>,
+        secure: bool,
+    ) -> Ticket {
+        let Some(config) = &service.waiting_room else {
+            return Ticket::Pass;
+        };
+        let queue = self.queue(&service.id);
+        if !queue.is_active(config, level) {
+            return Ticket::Pass;
+        }
+        queue.advance(config.admit_per_sec);
+        let admitted = queue.admitted.load(Ordering::Acquire);
+
+        if let Some(position) = cookies.and_then(|c| self.position(&service.id, &queue, ip, c)) {
+            if position <= admitted {
+                return Ticket::Admit;
+            }
+            return Ticket::Wait { place: position - admitted, set_cookie: None };
+        }
+        if in_flight <= config.max_in_flight && queue.issued.load(Ordering::Acquire) <= admitted {
+            return Ticket::Pass;
+        }
+        let position = queue.issued.fetch_add(1, Ordering::AcqRel) + 1;
+        let value = format!("{:x}.{}", queue.epoch, position);
+        let signature = self.challenge.sign_for_ip(&format!("{}:{}", service.id, value), ip, QUEUE_SIGNATURE_PURPOSE);
+        let set_cookie = format!(
+            "{}={}.{}; Path=/; Max-Age={}; SameSite=Lax; HttpOnly{}",
+            QUEUE_COOKIE,
+            value,
+            signature,
+            QUEUE_COOKIE_MAX_AGE_SECS,
+            if secure { "; Secure" } else { "" }
+        );
+        Ticket::Wait { place: position.saturating_sub(admitted), set_cookie: Some(set_cookie) }
+    }
+
+    /// The position in a valid position cookie for this service's current
+    /// queue, issued to `ip`.
+    fn position(&self, service_id: &str, queue: &ServiceQueue, ip: &IpAddr, cookies: &str) -> Option<u64> {
+        let value = cookies
+            .split(';')
+            .find_map(|c| c.trim().strip_prefix(QUEUE_COOKIE)?.strip_prefix('='))?;
+        let (value, signature) = value.rsplit_once('.')?;
+        let (epoch, position) = value.split_once('.')?;
+        if u64::from_str_radix(epoch, 16).ok()? != queue.epoch {
+            return None;
+        }
+        let signed = format!("{}:{}", service_id, value);
+        if !self.challenge.verify_for_ip(&signed, ip, QUEUE_SIGNATURE_PURPOSE, signature) {
+            return None;
+        }
+        position.parse().ok()
+    }
+
+    /// Switch a service's room between automatic, on and off.
+    pub fn set_mode(&self, service_id: &str, mode: QueueMode) {
+        self.queue(service_id).mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    /// Queue state of a service at protection level `level`.
+    pub fn status(&self, service: &ServiceConfig, level: ProtectionLevel) -> QueueStatus {
+        let queue = self.queue(&service.id);
+        if let Some(config) = &service.waiting_room {
+            queue.advance(config.admit_per_sec);
+        }
+        let issued = queue.issued.load(Ordering::Acquire);
+        let admitted = queue.admitted.load(Ordering::Acquire);
+        QueueStatus {
+            mode: queue.mode(),
+            active: self.is_active(service, level),
+            queue_length: issued.saturating_sub(admitted),
+            issued,
+            admitted,
+        }
+    }
+}
+
+/// Expire the position cookie once the visitor is let in.
+pub fn clear_queue_cookie() -> String {
+    format!("{}=; Path=/; Max-Age=0; SameSite=Lax; HttpOnly", QUEUE_COOKIE)
+}
+
+/// Queue page for a visitor at `place`, reloading every `refresh_secs`.
+pub fn queue_page(place: u64, config: &WaitingRoomConfig) -> String {
+    let wait_secs = (place as f64 / config.admit_per_sec).ceil() as u64;
+    let wait = match wait_secs {
+        0..=59 => "less than a minute".to_string(),
+        60..=119 => "about a minute".to_string(),
+        s => format!("about {} minutes", s / 60),
+    };
+    format!(
+        "<!DOCTYPE html>\
+         <html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"{refresh}\">\
+         <title>You are in line</title></head>\
+         <body><h1>You are in line</h1>\
+         <p>The site is busy. Your place in line: <strong>{place}</strong>.</p>\
+         <p>Estimated wait: {wait}. This page refreshes by itself; please keep it open.</p>\
+         <hr><p>Fortress Anti-DDoS Proxy</p></body></html>",
+        refresh = config.refresh_secs,
+        place = place,
+        wait = wait,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::defaults::default_protection_config;
+    use crate::config::settings::ChallengeConfig;
+    use crate::storage::memory::MemoryStore;
+
+    fn room() -> WaitingRoom {
+        let config: ChallengeConfig = toml::from_str("hmac_secret = \"test\"").unwrap();
+        let challenge = ChallengeSystem::new(&config, &default_protection_config(), Arc::new(MemoryStore::new()));
+        WaitingRoom::new(Arc::new(challenge))
+    }
+
+    fn service(admit_per_sec: f64) -> ServiceConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": "shop", "name": "Shop", "domains": ["shop.example"], "upstream_address": "127.0.0.1:1",
+            "waiting_room": {"max_in_flight": 10, "admit_per_sec": admit_per_sec},
+        }))
+        .unwrap()
+    }
+
+    /// The `name=value` part of a `Set-Cookie` value.
+    fn cookie_pair(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap()
+    }
+
+    #[test]
+    fn test_visitors_queue_past_max_in_flight_and_keep_their_place() {
+        let room = room();
+        let svc = service(1000.0);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let other: IpAddr = "198.51.100.2".parse().unwrap();
+
+        // Below L4 the room stays closed, whatever the load.
+        assert_eq!(room.check(&svc, ProtectionLevel::L3, 50, &ip, None, true), Ticket::Pass);
+        assert_eq!(room.check(&svc, ProtectionLevel::L4, 10, &ip, None, true), Ticket::Pass);
+
+        let Ticket::Wait { place: 1, set_cookie: Some(cookie) } =
+            room.check(&svc, ProtectionLevel::L4, 11, &ip, None, true)
+        else {
+            panic!("expected a new position");
+        };
+        // Anyone arriving behind a queue waits, even once load drops.
+        assert!(matches!(
+            room.check(&svc, ProtectionLevel::L4, 0, &other, None, true),
+            Ticket::Wait { place: 2, set_cookie: Some(_) }
+        ));
+
+        // The position is bound to the visitor's IP.
+        let pair = cookie_pair(&cookie);
+        assert!(matches!(
+            room.check(&svc, ProtectionLevel::L4, 0, &other, Some(pair), true),
+            Ticket::Wait { set_cookie: Some(_), .. }
+        ));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(room.check(&svc, ProtectionLevel::L4, 0, &ip, Some(pair), true), Ticket::Admit);
+
+        let status = room.status(&svc, ProtectionLevel::L4);
+        assert_eq!((status.issued, status.admitted, status.queue_length), (3, 3, 0));
+    }
+
+    #[test]
+    fn test_manual_mode_overrides_the_level() {
+        let room = room();
+        let svc = service(1.0);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        room.set_mode("shop", QueueMode::On);
+        assert!(matches!(room.check(&svc, ProtectionLevel::L0, 11, &ip, None, false), Ticket::Wait { .. }));
+        assert!(room.status(&svc, ProtectionLevel::L0).active);
+
+        room.set_mode("shop", QueueMode::Off);
+        assert_eq!(room.check(&svc, ProtectionLevel::L4, 11, &ip, None, false), Ticket::Pass);
+    }
+}
@@ -1,14 +1,22 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
 use hyper::body::Incoming;
+use hyper::upgrade::OnUpgrade;
 use hyper::Request;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, error, info, warn};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+use super::connection::ConnectionTracker;
 
-/// WebSocket upgrade detection and raw TCP proxying.
+/// WebSocket upgrade detection and raw byte relaying.
 ///
-/// Once a WebSocket handshake is detected the proxy forwards the initial
-/// upgrade request to the upstream backend and then performs a bidirectional
-/// byte-level copy for the lifetime of the connection.  No WebSocket frame
-/// parsing takes place -- the proxy is completely transparent.
+/// The upgrade request goes through the protection pipeline and is forwarded
+/// upstream like any other request. If the backend answers `101 Switching
+/// Protocols`, both connections are taken over from hyper and bytes are
+/// copied in both directions for the lifetime of the connection.  No
+/// WebSocket frame parsing takes place -- the proxy is completely transparent.
 pub struct WebSocketProxy;
 
 impl WebSocketProxy {
@@ -38,123 +46,57 @@ impl WebSocketProxy {
         has_upgrade_header && has_connection_upgrade
     }
 
-    /// Proxy a WebSocket connection by forwarding the initial upgrade request
-    /// bytes and then performing bidirectional I/O between the client and
-    /// upstream streams.
+    /// Relay bytes between an upgraded client connection and an upgraded
+    /// upstream connection until either side closes.
     ///
-    /// # Arguments
-    ///
-    /// * `client_stream` -- the raw TCP (or already-decrypted TLS) stream
-    ///   from the connecting client.  The initial HTTP request bytes should
-    ///   **not** have been consumed from this stream yet; instead they are
-    ///   passed separately via `req_bytes`.
-    /// * `upstream_addr` -- the `host:port` of the backend server.
-    /// * `req_bytes` -- the raw bytes of the HTTP upgrade request that were
-    ///   already read from `client_stream`.
-    pub async fn proxy_websocket<S>(
-        mut client_stream: S,
-        upstream_addr: &str,
-        req_bytes: &[u8],
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
-    where
-        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
-    {
-        // 1. Connect to the upstream backend.
-        let mut upstream_stream =
-            tokio::net::TcpStream::connect(upstream_addr).await.map_err(|err| {
-                error!(
-                    upstream = %upstream_addr,
-                    error = %err,
-                    "WebSocket: failed to connect to upstream"
-                );
-                err
-            })?;
-
-        info!(upstream = %upstream_addr, "WebSocket: connected to upstream");
-
-        // 2. Forward the original upgrade request to the upstream.
-        upstream_stream.write_all(req_bytes).await?;
-        upstream_stream.flush().await?;
-        debug!("WebSocket: forwarded upgrade request ({} bytes)", req_bytes.len());
-
-        // 3. Read the upstream's response to the upgrade request and forward
-        //    it back to the client.  We read until we see the end-of-headers
-        //    marker (\r\n\r\n).
-        let mut resp_buf = vec![0u8; 4096];
-        let mut resp_len: usize = 0;
-        let mut header_complete = false;
-
-        loop {
-            let n = upstream_stream.read(&mut resp_buf[resp_len..]).await?;
-            if n == 0 {
-                warn!("WebSocket: upstream closed before completing handshake");
-                return Err("Upstream closed during WebSocket handshake".into());
-            }
-            resp_len += n;
-
-            // Check for end-of-headers.
-            if resp_len >= 4 {
-                for i in 0..=(resp_len - 4) {
-                    if &resp_buf[i..i + 4] == b"\r\n\r\n" {
-                        header_complete = true;
-                        break;
-                    }
-                }
-            }
-
-            if header_complete {
-                break;
-            }
-
-            // Grow the buffer if needed.
-            if resp_len == resp_buf.len() {
-                if resp_buf.len() >= 65536 {
-                    return Err("WebSocket: upstream handshake response too large".into());
-                }
-                resp_buf.resize(resp_buf.len() * 2, 0);
+    /// Both sides come from hyper's upgrade mechanism: the client side from
+    /// the inbound request and the upstream side from the `101 Switching
+    /// Protocols` response. Byte counts are fed into the [`ConnectionTracker`]
+    /// against `client_ip` as they flow, and the tracker entry for `conn_id`
+    /// is removed once the relay ends. With a non-zero `bandwidth_cap`,
+    /// writes to the client are delayed to keep the IP under it.
+    pub async fn relay(
+        client: OnUpgrade,
+        upstream: OnUpgrade,
+        connections: Arc<ConnectionTracker>,
+        conn_id: u64,
+        client_ip: IpAddr,
+        bandwidth_cap: u64,
+    ) {
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Ok(pair) => pair,
+            Err(err) => {
+                warn!(connection_id = conn_id, error = %err, "WebSocket: upgrade failed");
+                connections.remove(conn_id);
+                return;
             }
-        }
-
-        // Forward the handshake response to the client.
-        client_stream.write_all(&resp_buf[..resp_len]).await?;
-        client_stream.flush().await?;
-        debug!(
-            "WebSocket: forwarded upstream handshake response ({} bytes)",
-            resp_len
-        );
-
-        // Verify we got a 101 Switching Protocols.
-        let resp_header = String::from_utf8_lossy(&resp_buf[..resp_len.min(64)]);
-        if !resp_header.contains("101") {
-            warn!(
-                "WebSocket: upstream did not return 101, got: {}",
-                resp_header.lines().next().unwrap_or("(empty)")
-            );
-            return Err("Upstream rejected WebSocket upgrade".into());
-        }
+        };
 
-        info!("WebSocket: handshake complete, starting bidirectional relay");
+        info!(connection_id = conn_id, "WebSocket: handshake complete, starting bidirectional relay");
 
-        // 4. Bidirectional byte copy.
-        //    We split both streams and copy in both directions concurrently.
-        let (mut client_read, mut client_write) = tokio::io::split(client_stream);
-        let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_stream);
+        // Split both streams and copy in both directions concurrently.
+        let (client_read, client_write) = tokio::io::split(TokioIo::new(client));
+        let (upstream_read, upstream_write) = tokio::io::split(TokioIo::new(upstream));
 
-        let client_to_upstream = async {
-            let result = tokio::io::copy(&mut client_read, &mut upstream_write).await;
-            // Shut down the write side so the upstream sees EOF.
-            let _ = upstream_write.shutdown().await;
-            result
+        let meter = Meter {
+            connections: &connections,
+            conn_id,
+            client_ip,
+            bandwidth_cap,
         };
+        let client_to_upstream = pump(client_read, upstream_write, &meter, Direction::FromClient);
+        let upstream_to_client = pump(upstream_read, client_write, &meter, Direction::ToClient);
 
-        let upstream_to_client = async {
-            let result = tokio::io::copy(&mut upstream_read, &mut client_write).await;
-            let _ = client_write.shutdown().await;
-            result
+        let closed = connections.close_token(conn_id).unwrap_or_default();
+        let (c2u, u2c) = tokio::select! {
+            pair = async { tokio::join!(client_to_upstream, upstream_to_client) } => pair,
+            _ = closed.cancelled() => {
+                info!(connection_id = conn_id, "WebSocket: closed by admin");
+                connections.remove(conn_id);
+                return;
+            }
         };
 
-        let (c2u, u2c) = tokio::join!(client_to_upstream, upstream_to_client);
-
         match (&c2u, &u2c) {
             (Ok(sent), Ok(received)) => {
                 info!(
@@ -173,6 +115,222 @@ impl WebSocketProxy {
             }
         }
 
-        Ok(())
+        connections.remove(conn_id);
+    }
+}
+
+/// Where a relay's byte counts go.
+struct Meter<'a> {
+    connections: &'a ConnectionTracker,
+    conn_id: u64,
+    client_ip: IpAddr,
+    bandwidth_cap: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    FromClient,
+    ToClient,
+}
+
+/// Copy one direction of a relay, recording bytes against the connection as
+/// each chunk is written. Shuts down the write side on EOF so the peer sees
+/// the close.
+async fn pump<R, W>(mut reader: R, mut writer: W, meter: &Meter<'_>, direction: Direction) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut total: u64 = 0;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        if let Direction::ToClient = direction {
+            let delay = meter
+                .connections
+                .throttle_delay(meter.client_ip, n as u64, meter.bandwidth_cap);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        writer.write_all(&buf[..n]).await?;
+        total += n as u64;
+
+        let (sent, received) = match direction {
+            Direction::FromClient => (0, n as u64),
+            Direction::ToClient => (n as u64, 0),
+        };
+        meter
+            .connections
+            .update_bytes(meter.conn_id, meter.client_ip, sent, received);
+    }
+
+    let _ = writer.shutdown().await;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use arc_swap::ArcSwap;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::analytics::capture::RequestCapture;
+    use crate::analytics::collector::MetricsCollector;
+    use crate::analytics::live_tail::LiveTail;
+    use crate::analytics::request_samples::RequestSampler;
+    use crate::config::settings::Settings;
+    use crate::protection::challenge::ChallengeSystem;
+    use crate::protection::pipeline::tests::test_pipeline;
+    use crate::proxy::http_handler::HttpHandler;
+    use crate::proxy::response_cache::ResponseCache;
+    use crate::proxy::service_router::ServiceRouter;
+    use crate::proxy::tarpit::Tarpit;
+    use crate::proxy::upstream::UpstreamClients;
+    use crate::proxy::waiting_room::WaitingRoom;
+    use crate::storage::memory::MemoryStore;
+    use crate::storage::sqlite::tests::TempDb;
+    use crate::storage::sqlite::SqliteStore;
+
+    const UPGRADE: &[u8] = b"GET /chat HTTP/1.1\r\nHost: ws.test\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    /// Unmasked text frame "hello" from the server.
+    const SERVER_FRAME: &[u8] = &[0x81, 0x05, b'h', b'e', b'l', b'l', b'o'];
+    /// Masked text frame "ping" from the client.
+    const CLIENT_FRAME: &[u8] = &[0x81, 0x84, 1, 2, 3, 4, b'p' ^ 1, b'i' ^ 2, b'n' ^ 3, b'g' ^ 4];
+
+    /// Read a response head, up to the blank line.
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "closed before the end of the head");
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Backend that answers one upgrade with `101` followed by
+    /// [`SERVER_FRAME`] and then echoes every byte back, or with a `403`
+    /// when `accept` is false.
+    async fn echo_backend(accept: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            if !accept {
+                let refusal = b"HTTP/1.1 403 Forbidden\r\ncontent-length: 6\r\n\r\nno ws\n";
+                stream.write_all(refusal).await.unwrap();
+                return;
+            }
+            let switching = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+            stream.write_all(switching).await.unwrap();
+            stream.write_all(SERVER_FRAME).await.unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        addr
+    }
+
+    /// An [`HttpHandler`] in front of `upstream`, served like the proxy
+    /// listener serves it. The client IP is whitelisted so the pipeline
+    /// lets the handshake through.
+    async fn proxy(upstream: &str, db_name: &str) -> (String, Arc<ConnectionTracker>, TempDb) {
+        let mut settings = Settings::default();
+        settings.challenge.hmac_secret = "test-secret".to_string();
+        settings.protection.whitelisted_ips = vec!["127.0.0.1".to_string()];
+        settings.protection.build_whitelist();
+        let (pipeline, db) = test_pipeline(&settings, db_name);
+        let shared = Arc::new(ArcSwap::from_pointee(settings.clone()));
+        let memory = Arc::new(MemoryStore::new());
+        let connections = Arc::new(ConnectionTracker::new());
+        let challenge = Arc::new(ChallengeSystem::new(&settings.challenge, &settings.protection, memory.clone()));
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let handler = Arc::new(HttpHandler::new(
+            Arc::new(pipeline),
+            Arc::new(ServiceRouter::new(upstream)),
+            memory,
+            connections.clone(),
+            Arc::new(MetricsCollector::new()),
+            shared.clone(),
+            challenge.clone(),
+            Arc::new(UpstreamClients::new()),
+            None,
+            Arc::new(RequestSampler::new(sqlite, shared.clone())),
+            Arc::new(RequestCapture::new(shared.clone())),
+            Arc::new(LiveTail::new(shared.clone())),
+            Arc::new(Tarpit::new()),
+            Arc::new(ResponseCache::new(shared)),
+            Arc::new(WaitingRoom::new(challenge)),
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let tracker = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let conn_id = tracker.register(peer.ip(), None, false);
+                let handler = handler.clone();
+                let service = service_fn(move |req| {
+                    let handler = handler.clone();
+                    async move { Ok::<_, hyper::Error>(handler.handle(req, peer.ip(), None, conn_id).await) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades());
+            }
+        });
+        (addr, connections, db)
+    }
+
+    #[tokio::test]
+    async fn test_websocket_frames_round_trip_through_the_proxy() {
+        let backend = echo_backend(true).await;
+        let (addr, connections, _db) = proxy(&backend, "ws-relay").await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(UPGRADE).await.unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.to_ascii_lowercase().contains("upgrade: websocket"));
+
+        // Backend to client, then client to backend and back
+        let mut frame = vec![0u8; SERVER_FRAME.len()];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut frame)).await.unwrap().unwrap();
+        assert_eq!(frame, SERVER_FRAME);
+        for _ in 0..3 {
+            client.write_all(CLIENT_FRAME).await.unwrap();
+            let mut echo = vec![0u8; CLIENT_FRAME.len()];
+            tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut echo)).await.unwrap().unwrap();
+            assert_eq!(echo, CLIENT_FRAME);
+        }
+        let (sent, received) = connections.bandwidth(&"127.0.0.1".parse().unwrap());
+        assert!(sent > 0 && received > 0);
+    }
+
+    #[tokio::test]
+    async fn test_declined_upgrade_is_passed_back() {
+        let backend = echo_backend(false).await;
+        let (addr, _, _db) = proxy(&backend, "ws-declined").await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(UPGRADE).await.unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 403"), "{}", head);
+        let mut body = [0u8; 6];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"no ws\n");
     }
 }
@@ -1,232 +1,1241 @@
+use std::collections::HashSet;
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use ipnet::IpNet;
-use serde::{Deserialize, Serialize};
+use parking_lot::RwLock;
 
+use crate::config::settings::BlocklistConfig;
+use crate::models::schedule::{Schedule, ScheduleFields};
+use crate::models::threat::ThreatAction;
+use crate::protection::geoip::GeoIpLookup;
+use serde::Serialize;
+use tracing::warn;
+
+use super::feeds::ImportEntry;
+use super::ip_ranges::{parse_ip_or_cidr, IpRangeMap};
 use super::memory::MemoryStore;
-use super::sqlite::SqliteStore;
+use super::sqlite::{BlockedIpGeo, BlockedIpRow, NewBlockedIp, NewBlocklistEntry, SqliteStore};
 
 // ---------------------------------------------------------------------------
-// ThreatAction – what to do with a matched request
+// BlocklistManager
 // ---------------------------------------------------------------------------
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub enum ThreatAction {
-    Block,
-    Challenge,
+/// `action` of ASN/country rows that belong to the allowlist rather than
+/// the blocklist.
+pub const ALLOW: &str = "allow";
+
+/// Parse a blocklist `action` (`block`, `challenge` or `tarpit`), or
+/// `None` for anything else, `pass` included.
+pub fn parse_list_action(value: &str) -> Option<ThreatAction> {
+    ThreatAction::from_str_name(value).filter(|action| *action != ThreatAction::Pass)
+}
+
+/// The action of a stored row. Rows are validated when added and migrated
+/// on startup, so anything unrecognised is treated as a block.
+fn row_action(value: &str) -> ThreatAction {
+    parse_list_action(value).unwrap_or(ThreatAction::Block)
+}
+
+/// Parse an `expires_at` column value (`YYYY-MM-DD HH:MM:SS`, UTC).
+fn parse_expiry(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(&format!("{} +0000", value), "%Y-%m-%d %H:%M:%S %z")
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// When an entry with this `expires_at` column lapses, or `Err` if it
+/// already has.
+fn row_expiry(value: Option<&str>) -> Result<Option<Instant>, ()> {
+    match value.and_then(parse_expiry) {
+        Some(exp) => match exp.signed_duration_since(Utc::now()).to_std() {
+            Ok(remaining) => Ok(Some(Instant::now() + remaining)),
+            Err(_) => Err(()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// `source` of ASN/country entries added by auto-ban escalation.
+pub const AUTO_ESCALATION: &str = "auto_escalation";
+
+/// Outcome of a bulk import or feed sync.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BulkResult {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// Outcome of [`BlocklistManager::backfill_geo`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GeoBackfill {
+    /// Blocked IPs that were missing a country or ASN.
+    pub scanned: usize,
+    /// Those GeoIP now had more data for.
+    pub updated: usize,
 }
 
-impl ThreatAction {
-    fn from_str_action(s: &str) -> Self {
-        match s {
-            "challenge" => ThreatAction::Challenge,
-            _ => ThreatAction::Block,
+/// Validate and normalise one entry of a bulk block request. `list_type`
+/// is `ip` (IP or CIDR), `asn`, `country` or `ja3`; `ttl` applies to IPs
+/// and JA3 hashes.
+pub fn parse_bulk_entry(
+    list_type: &str,
+    value: &str,
+    reason: &str,
+    ttl: Option<Duration>,
+) -> Result<NewBlocklistEntry, String> {
+    let value = value.trim();
+    let reason = reason.to_string();
+    let expires_at = ttl.map(|d| Utc::now() + chrono::Duration::seconds(d.as_secs() as i64));
+    match list_type {
+        "ip" => {
+            let net = match value.parse::<IpNet>() {
+                Ok(net) => net.trunc(),
+                Err(_) => IpNet::from(
+                    IpAddr::from_str(value).map_err(|_| format!("Invalid IP address: {}", value))?,
+                ),
+            };
+            let entry = ImportEntry { net, reason: Some(reason), ttl_secs: None };
+            Ok(NewBlocklistEntry::Ip(entry.to_row("", ttl)))
         }
+        "asn" => {
+            let asn = value
+                .trim_start_matches("AS")
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid ASN number: {}", value))?;
+            Ok(NewBlocklistEntry::Asn { asn, reason })
+        }
+        "country" => {
+            if value.len() != 2 || !value.bytes().all(|b| b.is_ascii_alphabetic()) {
+                return Err(format!("Invalid country code: {}", value));
+            }
+            Ok(NewBlocklistEntry::Country { code: value.to_ascii_uppercase(), reason })
+        }
+        "ja3" => {
+            let hash = normalize_ja3(value).ok_or_else(|| format!("Invalid JA3 hash: {}", value))?;
+            Ok(NewBlocklistEntry::Ja3 { hash, reason, expires_at })
+        }
+        other => Err(format!("Unknown list type: {}", other)),
     }
 }
 
-// ---------------------------------------------------------------------------
-// BlocklistManager
-// ---------------------------------------------------------------------------
+/// Normalise a JA3 hash (an MD5 hex digest) to lowercase, or `None` if it
+/// isn't 32 hex characters.
+pub fn normalize_ja3(value: &str) -> Option<String> {
+    let hash = value.trim().to_ascii_lowercase();
+    (hash.len() == 32 && hash.bytes().all(|b| b.is_ascii_hexdigit())).then_some(hash)
+}
+
+/// The network of a blocked-IP row and whether it is a CIDR entry.
+fn row_net(ip: &str, cidr: Option<&str>) -> Option<(IpNet, bool)> {
+    match cidr {
+        Some(cidr) => cidr.parse::<IpNet>().ok().map(|net| (net, true)),
+        None => IpAddr::from_str(ip).ok().map(|ip| (IpNet::from(ip), false)),
+    }
+}
+
+/// Parse a row's schedule columns, logging and skipping the row if they
+/// are invalid.
+fn row_schedule(fields: &ScheduleFields, what: &str) -> Result<Option<Schedule>, ()> {
+    fields.parse().map_err(|e| warn!("Ignoring {} with invalid schedule: {}", what, e))
+}
+
+/// `action` if an entry can be listed with it; `pass` can't.
+fn listable(action: ThreatAction) -> Result<ThreatAction, String> {
+    match action {
+        ThreatAction::Pass => Err(format!("Invalid blocklist action: {}", action)),
+        action => Ok(action),
+    }
+}
+
+/// A JA3 fingerprint on the block-, challenge- or allowlist.
+#[derive(Debug, Clone)]
+struct Ja3Entry {
+    action: String,
+    expires_at: Option<Instant>,
+    schedule: Option<Schedule>,
+}
+
+impl Ja3Entry {
+    fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|exp| Instant::now() < exp)
+            && self.schedule.is_none_or(|s| s.is_active())
+    }
+}
+
+/// A blocked, challenged or tarpitted ASN or country.
+#[derive(Debug, Clone)]
+struct ListedAction {
+    action: ThreatAction,
+    /// Set on temporary entries from auto-ban escalation.
+    expires_at: Option<Instant>,
+    /// Only in force inside this window.
+    schedule: Option<Schedule>,
+}
+
+impl ListedAction {
+    fn new(action: ThreatAction, schedule: Option<Schedule>) -> Self {
+        Self { action, expires_at: None, schedule }
+    }
+
+    fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|exp| Instant::now() < exp)
+            && self.schedule.is_none_or(|s| s.is_active())
+    }
+}
+
+/// A blocked CIDR range held in the prefix table.
+#[derive(Debug, Clone)]
+struct BlockedRange {
+    action: ThreatAction,
+    reason: String,
+    expires_at: Option<Instant>,
+}
+
+impl BlockedRange {
+    fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|exp| Instant::now() < exp)
+    }
+}
 
 pub struct BlocklistManager {
     memory: Arc<MemoryStore>,
     sqlite: Arc<SqliteStore>,
-    blocked_cidrs: DashMap<String, String>,    // CIDR string -> reason
-    blocked_asns: DashMap<u32, String>,        // ASN -> action (block/challenge)
-    blocked_countries: DashMap<String, String>, // Country code -> action
+    /// Resolves the country and ASN stored with blocked IPs.
+    geoip: Arc<GeoIpLookup>,
+    blocked_cidrs: RwLock<IpRangeMap<BlockedRange>>,
+    /// Scheduled IPs and CIDRs, kept apart so a dormant entry never
+    /// shadows an always-on range in the longest-prefix lookup.
+    scheduled_ips: RwLock<IpRangeMap<(BlockedRange, Schedule)>>,
+    blocked_asns: DashMap<u32, ListedAction>,        // ASN -> action (block/challenge/tarpit)
+    blocked_countries: DashMap<String, ListedAction>, // Country code -> action
+    allowed_asns: DashSet<u32>,                 // rows with action "allow"
+    allowed_countries: DashSet<String>,
+    ja3: DashMap<String, Ja3Entry>,             // JA3 hash -> block/challenge/tarpit/allow
 }
 
 impl BlocklistManager {
-    pub fn new(memory: Arc<MemoryStore>, sqlite: Arc<SqliteStore>) -> Self {
+    pub fn new(memory: Arc<MemoryStore>, sqlite: Arc<SqliteStore>, geoip: Arc<GeoIpLookup>) -> Self {
         Self {
             memory,
             sqlite,
-            blocked_cidrs: DashMap::new(),
+            geoip,
+            blocked_cidrs: RwLock::new(IpRangeMap::new()),
+            scheduled_ips: RwLock::new(IpRangeMap::new()),
             blocked_asns: DashMap::new(),
             blocked_countries: DashMap::new(),
+            allowed_asns: DashSet::new(),
+            allowed_countries: DashSet::new(),
+            ja3: DashMap::new(),
         }
     }
 
     /// Load all blocklist data from SQLite into the in-memory caches.
-    pub fn load_from_db(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn load_from_db(&self) -> Result<(), Box<dyn std::error::Error>> {
         // --- Blocked IPs ---
-        let ips = self.sqlite.get_blocked_ips()?;
+        let ips = self.sqlite.get_blocked_ips().await?;
         for row in &ips {
             // Skip entries that have already expired.
-            if let Some(ref exp) = row.expires_at {
-                if let Ok(exp_dt) = DateTime::parse_from_str(
-                    &format!("{} +0000", exp),
-                    "%Y-%m-%d %H:%M:%S %z",
-                ) {
-                    if exp_dt < Utc::now() {
-                        continue;
-                    }
-                }
-            }
-
-            // If it is a CIDR entry, store in the CIDR map.
-            if row.cidr.is_some() {
-                self.blocked_cidrs
-                    .insert(row.ip.clone(), row.reason.clone());
-            } else if let Ok(ip) = IpAddr::from_str(&row.ip) {
-                // Compute optional duration from expires_at.
-                let duration = row.expires_at.as_ref().and_then(|exp| {
-                    DateTime::parse_from_str(
-                        &format!("{} +0000", exp),
-                        "%Y-%m-%d %H:%M:%S %z",
-                    )
-                    .ok()
-                    .and_then(|exp_dt| {
-                        let diff = exp_dt.signed_duration_since(Utc::now());
-                        if diff.num_seconds() > 0 {
-                            Some(Duration::from_secs(diff.num_seconds() as u64))
-                        } else {
-                            None
-                        }
-                    })
-                });
-
-                self.memory
-                    .block_ip(ip, row.reason.clone(), duration);
+            let duration = match row.expires_at.as_deref().and_then(parse_expiry) {
+                Some(exp) => match exp.signed_duration_since(Utc::now()).to_std() {
+                    Ok(remaining) => Some(remaining),
+                    Err(_) => continue,
+                },
+                None => None,
+            };
+            let Ok(schedule) = row_schedule(&row.schedule, &row.ip) else {
+                continue;
+            };
+            if let Some((net, is_cidr)) = row_net(&row.ip, row.cidr.as_deref()) {
+                self.cache_ip(net, is_cidr, row_action(&row.action), &row.reason, duration, schedule);
             }
         }
 
         // --- Blocked ASNs ---
-        let asns = self.sqlite.get_blocked_asns()?;
+        let asns = self.sqlite.get_blocked_asns().await?;
         for row in &asns {
-            self.blocked_asns.insert(row.asn, row.action.clone());
+            if row.action == ALLOW {
+                self.allowed_asns.insert(row.asn);
+                continue;
+            }
+            let Ok(expires_at) = row_expiry(row.expires_at.as_deref()) else {
+                continue;
+            };
+            if let Ok(schedule) = row_schedule(&row.schedule, &format!("ASN {}", row.asn)) {
+                let action = row_action(&row.action);
+                self.blocked_asns.insert(row.asn, ListedAction { action, expires_at, schedule });
+            }
         }
 
         // --- Blocked countries ---
-        let countries = self.sqlite.get_blocked_countries()?;
+        let countries = self.sqlite.get_blocked_countries().await?;
         for row in &countries {
-            self.blocked_countries
-                .insert(row.country_code.clone(), row.action.clone());
+            if row.action == ALLOW {
+                self.allowed_countries.insert(row.country_code.clone());
+                continue;
+            }
+            let Ok(expires_at) = row_expiry(row.expires_at.as_deref()) else {
+                continue;
+            };
+            if let Ok(schedule) = row_schedule(&row.schedule, &row.country_code) {
+                let action = row_action(&row.action);
+                self.blocked_countries
+                    .insert(row.country_code.clone(), ListedAction { action, expires_at, schedule });
+            }
+        }
+
+        // --- JA3 fingerprints ---
+        for row in self.sqlite.get_blocked_ja3().await? {
+            let Ok(expires_at) = row_expiry(row.expires_at.as_deref()) else {
+                continue;
+            };
+            let Ok(schedule) = row_schedule(&row.schedule, &row.ja3) else {
+                continue;
+            };
+            self.ja3.insert(row.ja3, Ja3Entry { action: row.action, expires_at, schedule });
         }
 
         Ok(())
     }
 
+    /// Bring the caches in line with the database after it was changed
+    /// underneath the manager, as by a state import: entries whose rows
+    /// are gone are dropped and the rest reloaded.
+    pub async fn reload_from_db(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let nets: HashSet<IpNet> = self
+            .sqlite
+            .get_blocked_ips()
+            .await?
+            .iter()
+            .filter_map(|row| row_net(&row.ip, row.cidr.as_deref()).map(|(net, _)| net.trunc()))
+            .collect();
+        self.memory.retain_blocked_ips(|ip| nets.contains(&IpNet::from(*ip)));
+        self.blocked_cidrs.write().retain(|net, _| nets.contains(net));
+        self.scheduled_ips.write().retain(|net, _| nets.contains(net));
+
+        let asns = self.sqlite.get_blocked_asns().await?;
+        let listed = |asn: u32, allow: bool| asns.iter().any(|r| r.asn == asn && (r.action == ALLOW) == allow);
+        self.blocked_asns.retain(|asn, _| listed(*asn, false));
+        self.allowed_asns.retain(|asn| listed(*asn, true));
+
+        let countries = self.sqlite.get_blocked_countries().await?;
+        let listed = |code: &str, allow: bool| {
+            countries.iter().any(|r| r.country_code == code && (r.action == ALLOW) == allow)
+        };
+        self.blocked_countries.retain(|code, _| listed(code, false));
+        self.allowed_countries.retain(|code| listed(code, true));
+
+        let ja3: HashSet<String> = self.sqlite.get_blocked_ja3().await?.into_iter().map(|r| r.ja3).collect();
+        self.ja3.retain(|hash, _| ja3.contains(hash));
+
+        self.load_from_db().await
+    }
+
+    /// Sync the `[blocklist]` config section into SQLite and the in-memory
+    /// caches. Entries previously added from config (reason `"config"`) that
+    /// are no longer listed are removed; entries added via the API are left
+    /// alone. Additions and removals are written to the audit log with actor
+    /// `"config"`.
+    pub async fn apply_config(&self, config: &BlocklistConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let existing_countries = self.sqlite.get_blocked_countries().await?;
+        let existing_asns = self.sqlite.get_blocked_asns().await?;
+
+        for row in &existing_countries {
+            if row.reason.as_deref() != Some("config") {
+                continue;
+            }
+            let listed = match row.action.as_str() {
+                "challenge" => &config.challenged_countries,
+                _ => &config.blocked_countries,
+            };
+            if !listed.iter().any(|c| c == &row.country_code) {
+                self.sqlite.remove_blocked_country(row.id).await?;
+                self.blocked_countries.remove(&row.country_code);
+                self.sqlite.audit("config", "unblock", "country", &row.country_code, None);
+            }
+        }
+        for row in &existing_asns {
+            if row.reason.as_deref() == Some("config") && !config.blocked_asns.contains(&row.asn) {
+                self.sqlite.remove_blocked_asn(row.id).await?;
+                self.blocked_asns.remove(&row.asn);
+                self.sqlite.audit("config", "unblock", "asn", &row.asn.to_string(), None);
+            }
+        }
+
+        let country_listed = |code: &str, action: &str| {
+            existing_countries
+                .iter()
+                .any(|r| r.country_code == code && r.action == action)
+        };
+        for (countries, action) in [
+            (&config.blocked_countries, "block"),
+            (&config.challenged_countries, "challenge"),
+        ] {
+            for country in countries {
+                match self
+                    .sqlite
+                    .add_blocked_country(country, None, action, Some("config"), "config", None, &ScheduleFields::default())
+                    .await {
+                    Ok(_) if !country_listed(country, action) => {
+                        self.sqlite.audit("config", action, "country", country, None);
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to load {} country {}: {}", action, country, e),
+                }
+            }
+        }
+        for asn in &config.blocked_asns {
+            match self
+                .sqlite
+                .add_blocked_asn(*asn, None, "block", Some("config"), "config", None, &ScheduleFields::default())
+                .await {
+                Ok(_) if !existing_asns.iter().any(|r| r.asn == *asn) => {
+                    self.sqlite.audit("config", "block", "asn", &asn.to_string(), None);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to load blocked ASN {}: {}", asn, e),
+            }
+        }
+
+        self.load_from_db().await
+    }
+
     // -----------------------------------------------------------------------
     // Checks
     // -----------------------------------------------------------------------
 
-    /// Check whether `ip` is blocked (exact match or CIDR match).
-    /// Returns `(action, reason)` if the IP should be acted upon.
+    /// Check whether `ip` is listed (exact match or CIDR match).
+    /// Returns `(action, reason)` if the IP should be acted upon; the
+    /// action is never [`ThreatAction::Pass`].
     pub fn check_ip(&self, ip: &IpAddr) -> Option<(ThreatAction, String)> {
         // 1. Exact IP match via in-memory blocked_ips cache.
         if let Some(entry) = self.memory.is_blocked(ip) {
-            return Some((ThreatAction::Block, entry.reason));
+            return Some((entry.action, entry.reason));
         }
 
-        // 2. CIDR match.
-        for entry in self.blocked_cidrs.iter() {
-            let cidr_str = entry.key();
-            if let Ok(network) = cidr_str.parse::<IpNet>() {
-                if network.contains(ip) {
-                    return Some((ThreatAction::Block, entry.value().clone()));
-                }
-            }
+        // 2. CIDR match: the longest active prefix, so an expired range
+        // doesn't hide a broader one around it.
+        if let Some((_, range)) = self.blocked_cidrs.read().lookup_where(ip, |r| r.is_active()) {
+            return Some((range.action, range.reason.clone()));
         }
 
-        None
+        // 3. Scheduled IPs and CIDRs, only inside their window.
+        let scheduled = self.scheduled_ips.read();
+        let (_, (range, _)) = scheduled.lookup_where(ip, |(r, s)| r.is_active() && s.is_active())?;
+        Some((range.action, range.reason.clone()))
     }
 
-    /// Check whether `asn` is blocked/challenged.
+    /// Check whether `asn` is blocked/challenged/tarpitted.
     pub fn check_asn(&self, asn: u32) -> Option<(ThreatAction, String)> {
-        self.blocked_asns.get(&asn).map(|entry| {
-            let action = ThreatAction::from_str_action(entry.value());
-            let reason = format!("ASN {} is {}", asn, entry.value());
-            (action, reason)
-        })
+        let entry = self.blocked_asns.get(&asn).filter(|e| e.is_active())?;
+        Some((entry.action, format!("ASN {} is {}", asn, entry.action)))
     }
 
-    /// Check whether `country` code is blocked/challenged.
+    /// Check whether `country` code is blocked/challenged/tarpitted.
     pub fn check_country(&self, country: &str) -> Option<(ThreatAction, String)> {
-        self.blocked_countries.get(country).map(|entry| {
-            let action = ThreatAction::from_str_action(entry.value());
-            let reason = format!("Country {} is {}", country, entry.value());
-            (action, reason)
-        })
+        let entry = self.blocked_countries.get(country).filter(|e| e.is_active())?;
+        Some((entry.action, format!("Country {} is {}", country, entry.action)))
+    }
+
+    /// Check whether a JA3 fingerprint is blocked/challenged/tarpitted.
+    /// Allowlisted and expired entries return `None`.
+    pub fn check_ja3(&self, ja3: &str) -> Option<(ThreatAction, String)> {
+        let entry = self.ja3.get(ja3)?;
+        if !entry.is_active() || entry.action == ALLOW {
+            return None;
+        }
+        Some((row_action(&entry.action), format!("JA3 {} is {}", ja3, entry.action)))
+    }
+
+    /// Whether a JA3 fingerprint is on the allowlist (never challenged).
+    pub fn is_ja3_allowed(&self, ja3: &str) -> bool {
+        self.ja3
+            .get(ja3)
+            .is_some_and(|entry| entry.action == ALLOW && entry.is_active())
+    }
+
+    /// Whether any ASN / country allowlist entries were added via the API.
+    pub fn has_allowed_asns(&self) -> bool {
+        !self.allowed_asns.is_empty()
+    }
+
+    pub fn has_allowed_countries(&self) -> bool {
+        !self.allowed_countries.is_empty()
+    }
+
+    /// Country entries (blocked, challenged or allowed), which only match
+    /// when a country is known for the client.
+    pub fn country_entry_count(&self) -> usize {
+        self.blocked_countries.len() + self.allowed_countries.len()
+    }
+
+    /// ASN entries (blocked, challenged or allowed).
+    pub fn asn_entry_count(&self) -> usize {
+        self.blocked_asns.len() + self.allowed_asns.len()
+    }
+
+    pub fn is_asn_allowed(&self, asn: u32) -> bool {
+        self.allowed_asns.contains(&asn)
+    }
+
+    pub fn is_country_allowed(&self, country: &str) -> bool {
+        self.allowed_countries.contains(country)
     }
 
     // -----------------------------------------------------------------------
     // Mutations
     // -----------------------------------------------------------------------
 
-    /// Block an IP (or CIDR) persistently and in memory.
-    pub fn add_ip(
+    /// List an IP (or CIDR) with `action` persistently and in memory, only
+    /// inside `schedule` if one is given. `actor` is recorded in the audit
+    /// log. Returns the normalised IP or CIDR that was stored.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_ip(
         &self,
         ip: &str,
+        action: ThreatAction,
         reason: &str,
         source: &str,
+        actor: &str,
         duration: Option<Duration>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        schedule: Option<Schedule>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         let expires_at: Option<DateTime<Utc>> = duration.map(|d| {
             Utc::now() + chrono::Duration::seconds(d.as_secs() as i64)
         });
+        let action = listable(action)?;
+        let fields = schedule.map(|s| s.fields()).unwrap_or_default();
 
         // Determine if this is a CIDR or a single IP.
-        let is_cidr = ip.contains('/');
-        let cidr_param: Option<&str> = if is_cidr { Some(ip) } else { None };
+        if ip.contains('/') {
+            let network = ip
+                .trim()
+                .parse::<IpNet>()
+                .map_err(|_| format!("Invalid CIDR range: {}", ip))?
+                .trunc();
+            let canonical = network.to_string();
 
-        // Persist to SQLite.
-        self.sqlite
-            .add_blocked_ip(ip, cidr_param, reason, source, expires_at)?;
+            let geo = self.locate(&canonical);
+            self.sqlite
+                .add_blocked_ip(&canonical, Some(&canonical), &action.to_string(), reason, source, expires_at, &fields, &geo)
+                .await?;
+            self.cache_ip(network, true, action, reason, duration, schedule);
+            self.sqlite.audit(actor, &action.to_string(), "cidr", &canonical, Some(reason));
+            Ok(canonical)
+        } else {
+            let parsed = IpAddr::from_str(ip.trim())
+                .map_err(|_| format!("Invalid IP address: {}", ip))?;
 
-        // Update in-memory caches.
-        if is_cidr {
-            self.blocked_cidrs
-                .insert(ip.to_string(), reason.to_string());
-        } else if let Ok(parsed) = IpAddr::from_str(ip) {
-            self.memory
-                .block_ip(parsed, reason.to_string(), duration);
+            let geo = self.locate(&parsed.to_string());
+            self.sqlite
+                .add_blocked_ip(&parsed.to_string(), None, &action.to_string(), reason, source, expires_at, &fields, &geo)
+                .await?;
+            self.cache_ip(IpNet::from(parsed), false, action, reason, duration, schedule);
+            self.sqlite
+                .audit(actor, &action.to_string(), "ip", &parsed.to_string(), Some(reason));
+            Ok(parsed.to_string())
         }
+    }
 
-        Ok(())
+    /// List an ASN with `action` persistently and in memory, only inside
+    /// `schedule` if one is given. Returns the row ID.
+    pub async fn add_asn(
+        &self,
+        asn: u32,
+        action: ThreatAction,
+        reason: &str,
+        actor: &str,
+        schedule: Option<Schedule>,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        let action = listable(action)?;
+        let fields = schedule.map(|s| s.fields()).unwrap_or_default();
+        let id = self
+            .sqlite
+            .add_blocked_asn(asn, None, &action.to_string(), Some(reason), "admin_api", None, &fields)
+            .await?;
+        self.allowed_asns.remove(&asn);
+        self.blocked_asns.insert(asn, ListedAction::new(action, schedule));
+        self.sqlite.audit(actor, &action.to_string(), "asn", &asn.to_string(), Some(reason));
+        Ok(id)
+    }
+
+    /// Add an ASN to the allowlist (replacing any blocklist entry for it).
+    /// Returns the row ID.
+    pub async fn allow_asn(&self, asn: u32, reason: &str, actor: &str) -> Result<i64, Box<dyn std::error::Error>> {
+        let id = self
+            .sqlite
+            .add_blocked_asn(asn, None, ALLOW, Some(reason), "admin_api", None, &ScheduleFields::default())
+            .await?;
+        self.blocked_asns.remove(&asn);
+        self.allowed_asns.insert(asn);
+        self.sqlite.audit(actor, "allow", "asn", &asn.to_string(), Some(reason));
+        Ok(id)
+    }
+
+    /// List a country with `action` persistently and in memory, only
+    /// inside `schedule` if one is given. Returns the row ID.
+    pub async fn add_country(
+        &self,
+        code: &str,
+        action: ThreatAction,
+        reason: &str,
+        actor: &str,
+        schedule: Option<Schedule>,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        let action = listable(action)?;
+        let fields = schedule.map(|s| s.fields()).unwrap_or_default();
+        let id = self
+            .sqlite
+            .add_blocked_country(code, None, &action.to_string(), Some(reason), "admin_api", None, &fields)
+            .await?;
+        self.allowed_countries.remove(code);
+        self.blocked_countries.insert(code.to_string(), ListedAction::new(action, schedule));
+        self.sqlite.audit(actor, &action.to_string(), "country", code, Some(reason));
+        Ok(id)
+    }
+
+    /// Add a country to the allowlist (replacing any blocklist entry for
+    /// it). Returns the row ID.
+    pub async fn allow_country(&self, code: &str, reason: &str, actor: &str) -> Result<i64, Box<dyn std::error::Error>> {
+        let id = self
+            .sqlite
+            .add_blocked_country(code, None, ALLOW, Some(reason), "admin_api", None, &ScheduleFields::default())
+            .await?;
+        self.blocked_countries.remove(code);
+        self.allowed_countries.insert(code.to_string());
+        self.sqlite.audit(actor, "allow", "country", code, Some(reason));
+        Ok(id)
     }
 
-    /// Remove a blocked-IP entry by its database row ID.
-    pub fn remove_ip(&self, id: i64) -> Result<(), Box<dyn std::error::Error>> {
+    /// List an ASN with `action` for `ttl`, with source
+    /// [`AUTO_ESCALATION`]. Does nothing and returns `None` if the ASN is
+    /// allowlisted or already listed.
+    pub async fn escalate_asn(
+        &self,
+        asn: u32,
+        action: ThreatAction,
+        reason: &str,
+        ttl: Duration,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        let action = listable(action)?;
+        let listed = self.blocked_asns.get(&asn).is_some_and(|e| e.is_active());
+        if listed || self.allowed_asns.contains(&asn) {
+            return Ok(None);
+        }
+        let expires_at = Utc::now() + chrono::Duration::seconds(ttl.as_secs() as i64);
+        let id = self
+            .sqlite
+            .add_blocked_asn(
+                asn, None, &action.to_string(), Some(reason), AUTO_ESCALATION, Some(expires_at),
+                &ScheduleFields::default(),
+            )
+            .await?;
+        self.blocked_asns.insert(
+            asn,
+            ListedAction { action, expires_at: Some(Instant::now() + ttl), schedule: None },
+        );
+        self.sqlite.audit(AUTO_ESCALATION, &action.to_string(), "asn", &asn.to_string(), Some(reason));
+        Ok(Some(id))
+    }
+
+    /// As [`escalate_asn`](Self::escalate_asn), for a country code.
+    pub async fn escalate_country(
+        &self,
+        code: &str,
+        action: ThreatAction,
+        reason: &str,
+        ttl: Duration,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        let action = listable(action)?;
+        let listed = self.blocked_countries.get(code).is_some_and(|e| e.is_active());
+        if listed || self.allowed_countries.contains(code) {
+            return Ok(None);
+        }
+        let expires_at = Utc::now() + chrono::Duration::seconds(ttl.as_secs() as i64);
+        let id = self
+            .sqlite
+            .add_blocked_country(
+                code, None, &action.to_string(), Some(reason), AUTO_ESCALATION, Some(expires_at),
+                &ScheduleFields::default(),
+            )
+            .await?;
+        self.blocked_countries.insert(
+            code.to_string(),
+            ListedAction { action, expires_at: Some(Instant::now() + ttl), schedule: None },
+        );
+        self.sqlite.audit(AUTO_ESCALATION, &action.to_string(), "country", code, Some(reason));
+        Ok(Some(id))
+    }
+
+    /// Add a JA3 fingerprint with action `block`, `challenge`, `tarpit` or
+    /// `allow`,
+    /// replacing any existing entry for it. A `schedule` limits when it
+    /// applies. Returns the row ID.
+    pub async fn add_ja3(
+        &self,
+        ja3: &str,
+        action: &str,
+        reason: &str,
+        actor: &str,
+        duration: Option<Duration>,
+        schedule: Option<Schedule>,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        let hash = normalize_ja3(ja3).ok_or_else(|| format!("Invalid JA3 hash: {}", ja3))?;
+        if action != ALLOW && parse_list_action(action).is_none() {
+            return Err(format!("Invalid JA3 action: {}", action).into());
+        }
+        let expires_at = duration.map(|d| Utc::now() + chrono::Duration::seconds(d.as_secs() as i64));
+        let fields = schedule.map(|s| s.fields()).unwrap_or_default();
+
+        let id = self.sqlite.add_blocked_ja3(&hash, action, Some(reason), expires_at, &fields).await?;
+        self.ja3.insert(
+            hash.clone(),
+            Ja3Entry {
+                action: action.to_string(),
+                expires_at: duration.map(|d| Instant::now() + d),
+                schedule,
+            },
+        );
+        self.sqlite.audit(actor, action, "ja3", &hash, Some(reason));
+        Ok(id)
+    }
+
+    /// Block many IPs/CIDRs in a single SQLite transaction. Entries without
+    /// their own reason or TTL use `default_reason` / `default_ttl`. Existing
+    /// rows for the same IP are replaced, as with [`add_ip`](Self::add_ip).
+    pub async fn import_ips(
+        &self,
+        entries: &[ImportEntry],
+        default_reason: &str,
+        default_ttl: Option<Duration>,
+        source: &str,
+        actor: &str,
+    ) -> Result<BulkResult, Box<dyn std::error::Error>> {
+        let rows: Vec<NewBlockedIp> = entries
+            .iter()
+            .map(|e| self.located(e.to_row(default_reason, default_ttl)))
+            .collect();
+        let written = self.sqlite.add_blocked_ips(&rows, source, true).await?;
+        self.cache_rows(written.iter().map(|&i| &rows[i]));
+
+        let result = BulkResult {
+            added: written.len(),
+            removed: 0,
+            unchanged: rows.len() - written.len(),
+        };
+        self.sqlite.audit(
+            actor,
+            "import",
+            "blocklist",
+            source,
+            Some(&format!("{} entries", result.added)),
+        );
+        Ok(result)
+    }
+
+    /// Block a mixed batch of entries (see [`parse_bulk_entry`]) in a single
+    /// SQLite transaction and update the in-memory lists. Returns the row ID
+    /// of each entry.
+    pub async fn add_entries(
+        &self,
+        entries: &[NewBlocklistEntry],
+        source: &str,
+        actor: &str,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+        let entries: Vec<NewBlocklistEntry> = entries
+            .iter()
+            .cloned()
+            .map(|entry| match entry {
+                NewBlocklistEntry::Ip(row) => NewBlocklistEntry::Ip(self.located(row)),
+                other => other,
+            })
+            .collect();
+        let ids = self.sqlite.add_blocklist_entries(&entries, source).await?;
+
+        let mut ip_rows = Vec::new();
+        for entry in &entries {
+            match entry {
+                NewBlocklistEntry::Ip(row) => ip_rows.push(row),
+                NewBlocklistEntry::Asn { asn, .. } => {
+                    self.allowed_asns.remove(asn);
+                    self.blocked_asns.insert(*asn, ListedAction::new(ThreatAction::Block, None));
+                }
+                NewBlocklistEntry::Country { code, .. } => {
+                    self.allowed_countries.remove(code);
+                    self.blocked_countries.insert(code.clone(), ListedAction::new(ThreatAction::Block, None));
+                }
+                NewBlocklistEntry::Ja3 { hash, expires_at, .. } => {
+                    let duration = expires_at.and_then(|exp| exp.signed_duration_since(Utc::now()).to_std().ok());
+                    self.ja3.insert(
+                        hash.clone(),
+                        Ja3Entry {
+                            action: "block".to_string(),
+                            expires_at: duration.map(|d| Instant::now() + d),
+                            schedule: None,
+                        },
+                    );
+                }
+            }
+        }
+        self.cache_rows(ip_rows.into_iter());
+
+        self.sqlite.audit(
+            actor,
+            "bulk_block",
+            "blocklist",
+            source,
+            Some(&format!("{} entries", entries.len())),
+        );
+        Ok(ids)
+    }
+
+    /// Make the `feed:<name>` entries match `entries`: rows missing from the
+    /// feed are removed and new ones added. Rows already blocked from another
+    /// source are left alone.
+    pub async fn sync_feed(
+        &self,
+        name: &str,
+        entries: &[ImportEntry],
+        reason: &str,
+    ) -> Result<BulkResult, Box<dyn std::error::Error>> {
+        let source = format!("feed:{}", name);
+        let existing = self.sqlite.get_blocked_ips_by_source(&source).await?;
+        let rows: Vec<NewBlockedIp> = entries.iter().map(|e| e.to_row(reason, None)).collect();
+
+        let wanted: HashSet<&str> = rows.iter().map(|r| r.ip.as_str()).collect();
+        let have: HashSet<&str> = existing.iter().map(|r| r.ip.as_str()).collect();
+
+        let stale: Vec<&BlockedIpRow> = existing
+            .iter()
+            .filter(|r| !wanted.contains(r.ip.as_str()))
+            .collect();
+        let new_rows: Vec<NewBlockedIp> = rows
+            .iter()
+            .filter(|r| !have.contains(r.ip.as_str()))
+            .cloned()
+            .map(|row| self.located(row))
+            .collect();
+
+        let stale_ids: Vec<i64> = stale.iter().map(|r| r.id).collect();
+        let removed = self.sqlite.remove_blocked_ips(&stale_ids).await?;
+        self.evict_rows(stale.iter().copied());
+
+        let written = self.sqlite.add_blocked_ips(&new_rows, &source, false).await?;
+        self.cache_rows(written.iter().map(|&i| &new_rows[i]));
+
+        let result = BulkResult {
+            added: written.len(),
+            removed,
+            unchanged: rows.len() - new_rows.len(),
+        };
+        if result.added > 0 || result.removed > 0 {
+            self.sqlite.audit(
+                &source,
+                "feed_sync",
+                "feed",
+                name,
+                Some(&format!("+{} -{}", result.added, result.removed)),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Remove entries of feeds that are no longer configured.
+    pub async fn prune_feeds(&self, configured: &[String]) -> Result<usize, Box<dyn std::error::Error>> {
+        let stale: Vec<BlockedIpRow> = self
+            .sqlite
+            .get_blocked_ips().await?
+            .into_iter()
+            .filter(|r| {
+                r.source
+                    .strip_prefix("feed:")
+                    .is_some_and(|name| !configured.iter().any(|c| c == name))
+            })
+            .collect();
+        if stale.is_empty() {
+            return Ok(0);
+        }
+        let ids: Vec<i64> = stale.iter().map(|r| r.id).collect();
+        let removed = self.sqlite.remove_blocked_ips(&ids).await?;
+        self.evict_rows(stale.iter());
+        self.sqlite.audit(
+            "config",
+            "feed_prune",
+            "blocklist",
+            "feed",
+            Some(&format!("{} entries", removed)),
+        );
+        Ok(removed)
+    }
+
+    /// Blocked IP/CIDR rows that have not expired, for export.
+    pub async fn active_ips(&self) -> Result<Vec<BlockedIpRow>, Box<dyn std::error::Error>> {
+        let now = Utc::now();
+        Ok(self
+            .sqlite
+            .get_blocked_ips().await?
+            .into_iter()
+            .filter(|r| {
+                r.expires_at
+                    .as_deref()
+                    .and_then(parse_expiry)
+                    .is_none_or(|exp| exp > now)
+            })
+            .collect())
+    }
+
+    fn cache_rows<'a>(&self, rows: impl Iterator<Item = &'a NewBlockedIp>) {
+        for row in rows {
+            let duration = row
+                .expires_at
+                .and_then(|exp| exp.signed_duration_since(Utc::now()).to_std().ok());
+            if let Some((net, is_cidr)) = row_net(&row.ip, row.cidr.as_deref()) {
+                self.cache_ip(net, is_cidr, ThreatAction::Block, &row.reason, duration, None);
+            }
+        }
+    }
+
+    fn evict_rows<'a>(&self, rows: impl Iterator<Item = &'a BlockedIpRow>) {
+        for row in rows {
+            if let Some((net, is_cidr)) = row_net(&row.ip, row.cidr.as_deref()) {
+                self.evict_ip(&net, is_cidr);
+            }
+        }
+    }
+
+    /// Country and ASN of a blocked IP, or of a range's network address.
+    fn locate(&self, value: &str) -> BlockedIpGeo {
+        let Some(net) = parse_ip_or_cidr(value) else {
+            return BlockedIpGeo::default();
+        };
+        let addr = net.network();
+        BlockedIpGeo {
+            country: self.geoip.lookup_country(addr),
+            asn: self.geoip.lookup_asn(addr).map(|(asn, _)| asn),
+        }
+    }
+
+    fn located(&self, row: NewBlockedIp) -> NewBlockedIp {
+        NewBlockedIp { geo: self.locate(&row.ip), ..row }
+    }
+
+    /// Resolve the country and ASN of blocked IPs stored without them, e.g.
+    /// added before they were recorded or while GeoIP was unavailable.
+    pub async fn backfill_geo(&self, actor: &str) -> Result<GeoBackfill, Box<dyn std::error::Error>> {
+        let missing = self.sqlite.get_blocked_ips_without_geo().await?;
+        let updates: Vec<(i64, BlockedIpGeo)> = missing
+            .iter()
+            .map(|(id, ip)| (*id, self.locate(ip)))
+            .filter(|(_, geo)| *geo != BlockedIpGeo::default())
+            .collect();
+        let result = GeoBackfill { scanned: missing.len(), updated: updates.len() };
+        self.sqlite.set_blocked_ip_geo(updates).await?;
+        self.sqlite.audit(actor, "backfill_geo", "blocklist", "ip", Some(&format!("{} of {} entries", result.updated, result.scanned)));
+        Ok(result)
+    }
+
+    /// Cache a listed IP or CIDR, replacing whatever was cached for it.
+    /// Scheduled entries go to their own table.
+    fn cache_ip(
+        &self,
+        net: IpNet,
+        is_cidr: bool,
+        action: ThreatAction,
+        reason: &str,
+        duration: Option<Duration>,
+        schedule: Option<Schedule>,
+    ) {
+        self.evict_ip(&net, is_cidr);
+        let range = BlockedRange {
+            action,
+            reason: reason.to_string(),
+            expires_at: duration.map(|d| Instant::now() + d),
+        };
+        match schedule {
+            Some(schedule) => self.scheduled_ips.write().insert(net, (range, schedule)),
+            None if is_cidr => self.blocked_cidrs.write().insert(net, range),
+            None => self.memory.block_ip(net.addr(), action, range.reason, duration),
+        }
+    }
+
+    fn evict_ip(&self, net: &IpNet, is_cidr: bool) {
+        self.scheduled_ips.write().remove(net);
+        if is_cidr {
+            self.blocked_cidrs.write().remove(net);
+        } else {
+            self.memory.unblock_ip(&net.addr());
+        }
+    }
+
+    /// Remove a blocked-IP entry by its database row ID. Returns the IP or
+    /// CIDR that was removed, if the row existed.
+    pub async fn remove_ip(&self, id: i64, actor: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
         // Look up the row first so we can evict the memory cache.
-        let rows = self.sqlite.get_blocked_ips()?;
-        if let Some(row) = rows.iter().find(|r| r.id == id) {
-            if row.cidr.is_some() {
-                self.blocked_cidrs.remove(&row.ip);
-            } else if let Ok(ip) = std::net::IpAddr::from_str(&row.ip) {
-                self.memory.unblock_ip(&ip);
+        let rows = self.sqlite.get_blocked_ips().await?;
+        let row = rows.iter().find(|r| r.id == id);
+        if let Some(row) = row {
+            if let Some((net, is_cidr)) = row_net(&row.ip, row.cidr.as_deref()) {
+                self.evict_ip(&net, is_cidr);
+            }
+            match row.cidr {
+                Some(ref cidr) => self.sqlite.audit(actor, "unblock", "cidr", cidr, None),
+                None => self.sqlite.audit(actor, "unblock", "ip", &row.ip, None),
             }
         }
 
-        self.sqlite.remove_blocked_ip(id)?;
-        Ok(())
+        self.sqlite.remove_blocked_ip(id).await?;
+        Ok(row.map(|r| r.cidr.clone().unwrap_or_else(|| r.ip.clone())))
+    }
+
+    /// Remove a blocked IP or CIDR by value rather than row ID. Returns
+    /// false if it wasn't blocked.
+    pub async fn remove_ip_value(&self, value: &str, actor: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let rows = self.sqlite.get_blocked_ips().await?;
+        match rows.iter().find(|r| r.cidr.as_deref().unwrap_or(&r.ip) == value) {
+            Some(row) => self.remove_ip(row.id, actor).await.map(|_| true),
+            None => Ok(false),
+        }
     }
 
     /// Remove a blocked ASN entry by its database row ID.
-    pub fn remove_asn(&self, id: i64) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn remove_asn(&self, id: i64, actor: &str) -> Result<(), Box<dyn std::error::Error>> {
         // Look up the row to get the ASN number for cache eviction
-        let rows = self.sqlite.get_blocked_asns()?;
+        let rows = self.sqlite.get_blocked_asns().await?;
         if let Some(row) = rows.iter().find(|r| r.id == id) {
             self.blocked_asns.remove(&row.asn);
+            self.allowed_asns.remove(&row.asn);
+            let action = if row.action == ALLOW { "disallow" } else { "unblock" };
+            self.sqlite.audit(actor, action, "asn", &row.asn.to_string(), None);
+        }
+        self.sqlite.remove_blocked_asn(id).await?;
+        Ok(())
+    }
+
+    /// Remove a JA3 entry by its database row ID.
+    pub async fn remove_ja3(&self, id: i64, actor: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = self.sqlite.get_blocked_ja3().await?;
+        if let Some(row) = rows.iter().find(|r| r.id == id) {
+            self.ja3.remove(&row.ja3);
+            let action = if row.action == ALLOW { "disallow" } else { "unblock" };
+            self.sqlite.audit(actor, action, "ja3", &row.ja3, None);
         }
-        self.sqlite.remove_blocked_asn(id)?;
+        self.sqlite.remove_blocked_ja3(id).await?;
         Ok(())
     }
 
     /// Remove a blocked country entry by its database row ID.
-    pub fn remove_country(&self, id: i64) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn remove_country(&self, id: i64, actor: &str) -> Result<(), Box<dyn std::error::Error>> {
         // Look up the row to get the country code for cache eviction
-        let rows = self.sqlite.get_blocked_countries()?;
+        let rows = self.sqlite.get_blocked_countries().await?;
         if let Some(row) = rows.iter().find(|r| r.id == id) {
             self.blocked_countries.remove(&row.country_code);
+            self.allowed_countries.remove(&row.country_code);
+            let action = if row.action == ALLOW { "disallow" } else { "unblock" };
+            self.sqlite.audit(actor, action, "country", &row.country_code, None);
         }
-        self.sqlite.remove_blocked_country(id)?;
+        self.sqlite.remove_blocked_country(id).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::tests::TempDb;
+
+    fn manager(sqlite: &Arc<SqliteStore>) -> BlocklistManager {
+        let geoip = GeoIpLookup::new(&crate::config::defaults::default_geoip_config());
+        BlocklistManager::new(Arc::new(MemoryStore::new()), sqlite.clone(), Arc::new(geoip))
+    }
+
+    #[tokio::test]
+    async fn test_ja3_lists_respect_action_and_ttl() {
+        let db = TempDb::new("ja3");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let blocklist = manager(&sqlite);
+
+        let bad = "E7D705A3286E19EA42F587B344EE6865";
+        let app = "b32309a26951912be7dba376398abc3b";
+        blocklist.add_ja3(bad, "challenge", "scraper", "test", None, None).await.unwrap();
+        blocklist.add_ja3(app, ALLOW, "mobile app", "test", None, None).await.unwrap();
+        assert!(blocklist.add_ja3("not-a-hash", "block", "x", "test", None, None).await.is_err());
+
+        let bad = normalize_ja3(bad).unwrap();
+        assert_eq!(blocklist.check_ja3(&bad).map(|(a, _)| a), Some(ThreatAction::Challenge));
+        assert!(blocklist.check_ja3(app).is_none());
+        assert!(blocklist.is_ja3_allowed(app));
+
+        // Expired entries are ignored in memory and skipped on reload.
+        blocklist.add_ja3(&bad, "block", "scraper", "test", Some(Duration::ZERO), None).await.unwrap();
+        assert!(blocklist.check_ja3(&bad).is_none());
+        let reloaded = manager(&sqlite);
+        reloaded.load_from_db().await.unwrap();
+        assert!(reloaded.is_ja3_allowed(app));
+        assert!(reloaded.check_ja3(&bad).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_entries_update_memory() {
+        let db = TempDb::new("bulk");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let blocklist = manager(&sqlite);
+
+        assert!(parse_bulk_entry("ip", "999.1.1.1", "x", None).is_err());
+        assert!(parse_bulk_entry("country", "USA", "x", None).is_err());
+        assert!(parse_bulk_entry("mac", "aa:bb", "x", None).is_err());
+
+        let entries: Vec<NewBlocklistEntry> = [
+            ("ip", "203.0.113.7"),
+            ("ip", "198.51.100.9/24"),
+            ("asn", "AS64500"),
+            ("country", "kp"),
+        ]
+        .iter()
+        .map(|(t, v)| parse_bulk_entry(t, v, "incident", None).unwrap())
+        .collect();
+        let ids = blocklist.add_entries(&entries, "admin_api", "test").await.unwrap();
+        assert_eq!(ids.len(), 4);
+
+        assert!(blocklist.check_ip(&"203.0.113.7".parse().unwrap()).is_some());
+        assert!(blocklist.check_ip(&"198.51.100.200".parse().unwrap()).is_some());
+        assert!(blocklist.check_asn(64500).is_some());
+        assert!(blocklist.check_country("KP").is_some());
+        assert_eq!(sqlite.get_blocked_ips().await.unwrap().len(), 2);
+    }
+
+    /// A schedule whose window starts `offset_mins` from now and lasts an hour.
+    fn schedule_from_now(offset_mins: i64) -> Schedule {
+        let start = Utc::now() + chrono::Duration::minutes(offset_mins);
+        let end = start + chrono::Duration::hours(1);
+        let fields = ScheduleFields {
+            active_from: Some(start.format("%H:%M").to_string()),
+            active_to: Some(end.format("%H:%M").to_string()),
+            active_days: None,
+        };
+        fields.parse().unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_entries_are_dormant_outside_their_window() {
+        let db = TempDb::new("sched");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let blocklist = manager(&sqlite);
+        let (active, dormant) = (schedule_from_now(-30), schedule_from_now(120));
+
+        blocklist.add_asn(64500, ThreatAction::Block, "nightly", "test", Some(active)).await.unwrap();
+        blocklist.add_asn(64501, ThreatAction::Block, "nightly", "test", Some(dormant)).await.unwrap();
+        blocklist.add_country("KP", ThreatAction::Block, "nightly", "test", Some(dormant)).await.unwrap();
+        blocklist.add_ip("198.51.100.0/24", ThreatAction::Block, "always", "admin_api", "test", None, None).await.unwrap();
+        blocklist.add_ip("198.51.100.7", ThreatAction::Block, "nightly", "admin_api", "test", None, Some(dormant)).await.unwrap();
+        blocklist.add_ip("203.0.113.0/24", ThreatAction::Block, "nightly", "admin_api", "test", None, Some(active)).await.unwrap();
+
+        assert!(blocklist.check_asn(64500).is_some());
+        assert!(blocklist.check_asn(64501).is_none());
+        assert!(blocklist.check_country("KP").is_none());
+        assert!(blocklist.check_ip(&"203.0.113.9".parse().unwrap()).is_some());
+        // A dormant scheduled IP doesn't hide the always-on range around it.
+        let (_, reason) = blocklist.check_ip(&"198.51.100.7".parse().unwrap()).unwrap();
+        assert_eq!(reason, "always");
+
+        // Dormant entries survive a reload with their schedule intact.
+        let reloaded = manager(&sqlite);
+        reloaded.load_from_db().await.unwrap();
+        assert!(reloaded.check_asn(64500).is_some());
+        assert!(reloaded.check_asn(64501).is_none());
+        assert!(reloaded.blocked_asns.contains_key(&64501));
+        assert!(reloaded.check_ip(&"203.0.113.9".parse().unwrap()).is_some());
+
+        // Re-adding without a schedule makes the entry always-on.
+        blocklist.add_asn(64501, ThreatAction::Block, "incident", "test", None).await.unwrap();
+        assert!(blocklist.check_asn(64501).is_some());
+        let rows = sqlite.get_blocked_asns().await.unwrap();
+        assert!(rows.iter().find(|r| r.asn == 64501).unwrap().schedule.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_range_does_not_hide_broader_ones() {
+        let db = TempDb::new("nested");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let blocklist = manager(&sqlite);
+        let (active, dormant) = (schedule_from_now(-30), schedule_from_now(120));
+
+        blocklist.add_ip("10.1.0.0/16", ThreatAction::Block, "wide", "admin_api", "test", None, None).await.unwrap();
+        blocklist
+            .add_ip("10.1.2.0/24", ThreatAction::Challenge, "narrow", "admin_api", "test", Some(Duration::ZERO), None)
+            .await
+            .unwrap();
+        let (action, reason) = blocklist.check_ip(&"10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!((action, reason.as_str()), (ThreatAction::Block, "wide"));
+
+        // Same for a scheduled /32 outside its window inside an active one
+        blocklist.add_ip("192.0.2.0/24", ThreatAction::Block, "day", "admin_api", "test", None, Some(active)).await.unwrap();
+        blocklist.add_ip("192.0.2.5", ThreatAction::Block, "night", "admin_api", "test", None, Some(dormant)).await.unwrap();
+        let (_, reason) = blocklist.check_ip(&"192.0.2.5".parse().unwrap()).unwrap();
+        assert_eq!(reason, "day");
+    }
+
+    #[tokio::test]
+    async fn test_tarpit_entries_survive_a_reload_and_unknown_actions_block() {
+        let db = TempDb::new("actions");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let blocklist = manager(&sqlite);
+        blocklist.add_ip("198.51.100.0/24", ThreatAction::Tarpit, "scan", "admin_api", "test", None, None).await.unwrap();
+        blocklist.add_asn(64500, ThreatAction::Tarpit, "hosting", "test", None).await.unwrap();
+        blocklist.add_country("KP", ThreatAction::Tarpit, "manual", "test", None).await.unwrap();
+        assert!(blocklist.add_asn(64501, ThreatAction::Pass, "x", "test", None).await.is_err());
+        assert_eq!(blocklist.check_ip(&"198.51.100.7".parse().unwrap()).map(|(a, _)| a), Some(ThreatAction::Tarpit));
+        drop((blocklist, sqlite));
+
+        // A row from before actions were validated.
+        rusqlite::Connection::open(db.path())
+            .unwrap()
+            .execute_batch("INSERT INTO blocked_countries (country_code, action) VALUES ('RU', 'Challenge');")
+            .unwrap();
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let rows = sqlite.get_blocked_countries().await.unwrap();
+        assert_eq!(rows.iter().find(|r| r.country_code == "RU").unwrap().action, "block");
+        let reloaded = manager(&sqlite);
+        reloaded.load_from_db().await.unwrap();
+        assert_eq!(reloaded.check_ip(&"198.51.100.7".parse().unwrap()).map(|(a, _)| a), Some(ThreatAction::Tarpit));
+        assert_eq!(reloaded.check_asn(64500).map(|(a, _)| a), Some(ThreatAction::Tarpit));
+        assert_eq!(reloaded.check_country("KP").map(|(a, _)| a), Some(ThreatAction::Tarpit));
+        assert_eq!(reloaded.check_country("RU").map(|(a, _)| a), Some(ThreatAction::Block));
+    }
+
+    #[tokio::test]
+    async fn test_escalated_entries_expire_and_skip_listed_values() {
+        let db = TempDb::new("escalate");
+        let sqlite = Arc::new(SqliteStore::new(db.path()).unwrap());
+        let blocklist = manager(&sqlite);
+        let hour = Duration::from_secs(3600);
+
+        let id = blocklist.escalate_asn(64500, ThreatAction::Challenge, "20 IPs", hour).await.unwrap();
+        assert!(id.is_some());
+        assert_eq!(blocklist.check_asn(64500).map(|(a, _)| a), Some(ThreatAction::Challenge));
+        let rows = sqlite.get_blocked_asns().await.unwrap();
+        let row = rows.iter().find(|r| r.asn == 64500).unwrap();
+        assert_eq!(row.source, AUTO_ESCALATION);
+        assert!(row.expires_at.is_some());
+
+        // Allowlisted or already listed values are left alone.
+        blocklist.allow_asn(64501, "partner", "test").await.unwrap();
+        blocklist.add_country("KP", ThreatAction::Block, "manual", "test", None).await.unwrap();
+        assert!(blocklist.escalate_asn(64501, ThreatAction::Block, "20 IPs", hour).await.unwrap().is_none());
+        assert!(blocklist.escalate_asn(64500, ThreatAction::Block, "20 IPs", hour).await.unwrap().is_none());
+        assert!(blocklist.escalate_country("KP", ThreatAction::Challenge, "200 IPs", hour).await.unwrap().is_none());
+        assert_eq!(blocklist.check_country("KP").map(|(a, _)| a), Some(ThreatAction::Block));
+
+        // Expired entries stop matching and are skipped on reload.
+        blocklist.escalate_country("BR", ThreatAction::Block, "200 IPs", Duration::ZERO).await.unwrap();
+        assert!(blocklist.check_country("BR").is_none());
+        let reloaded = manager(&sqlite);
+        reloaded.load_from_db().await.unwrap();
+        assert!(reloaded.check_asn(64500).is_some());
+        assert!(!reloaded.blocked_countries.contains_key("BR"));
+    }
+}
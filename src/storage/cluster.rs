@@ -0,0 +1,373 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::config::settings::SharedSettings;
+use crate::models::schedule::ScheduleFields;
+use crate::models::threat::{ProtectionLevel, ThreatAction};
+use crate::protection::auto_ban::AutoBanManager;
+use crate::protection::escalation::EscalationEngine;
+
+use super::blocklist::BlocklistManager;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header carrying the sending node's ID.
+pub const NODE_HEADER: &str = "X-Fortress-Node";
+/// Header carrying the unix timestamp (seconds) the batch was signed at.
+pub const TIMESTAMP_HEADER: &str = "X-Fortress-Timestamp";
+/// Header carrying the hex HMAC-SHA256 of `<timestamp>.<body>`.
+pub const SIGNATURE_HEADER: &str = "X-Fortress-Signature";
+
+/// Batches signed further than this from the receiver's clock are rejected.
+pub const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+/// Delay after a change before pushing, so bursts go out as one batch.
+const BATCH_DELAY: Duration = Duration::from_millis(200);
+/// How often undelivered batches are retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Undelivered events kept per peer; the oldest are dropped beyond this.
+const MAX_PENDING_PER_PEER: usize = 10_000;
+
+// ---------------------------------------------------------------------------
+// Wire format
+// ---------------------------------------------------------------------------
+
+/// A replicated state change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterOp {
+    Ban {
+        ip: IpAddr,
+        reason: String,
+        duration_secs: u64,
+    },
+    Unban {
+        ip: IpAddr,
+    },
+    /// Manual blocklist entry; `value` is an IP or canonical CIDR.
+    Block {
+        value: String,
+        /// Set for a challenge or tarpit entry; absent for a block.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<ThreatAction>,
+        reason: String,
+        expires_at: Option<DateTime<Utc>>,
+        /// Set for a scheduled entry; absent for an always-on one.
+        #[serde(default, skip_serializing_if = "ScheduleFields::is_empty")]
+        schedule: ScheduleFields,
+    },
+    Unblock {
+        value: String,
+    },
+    Level {
+        level: u8,
+        /// Set for a per-service level; absent for the global one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service: Option<String>,
+    },
+}
+
+impl ClusterOp {
+    /// Ops sharing a key overwrite each other; last write wins.
+    fn key(&self) -> String {
+        match self {
+            ClusterOp::Ban { ip, .. } | ClusterOp::Unban { ip } => format!("ban:{}", ip),
+            ClusterOp::Block { value, .. } | ClusterOp::Unblock { value } => format!("block:{}", value),
+            ClusterOp::Level { service: None, .. } => "level".to_string(),
+            ClusterOp::Level { service: Some(id), .. } => format!("level:{}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterEvent {
+    /// Node the change originated on.
+    pub origin: String,
+    /// Milliseconds since the unix epoch on the origin node.
+    pub timestamp_ms: i64,
+    #[serde(flatten)]
+    pub op: ClusterOp,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncBatch {
+    pub node_id: String,
+    pub events: Vec<ClusterEvent>,
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// ---------------------------------------------------------------------------
+// ClusterSync
+// ---------------------------------------------------------------------------
+
+/// Replicates bans, manual blocks and protection level changes between
+/// Fortress nodes.
+///
+/// Local changes are queued with [`publish`](Self::publish) and pushed to
+/// every peer by [`run`](Self::run). Incoming events go through
+/// [`accept`](Self::accept), which resolves conflicts last-write-wins on the
+/// origin timestamp (ties broken by node ID), before being applied with
+/// [`apply`]. Applying a remote event never re-publishes it, so changes don't
+/// bounce between nodes.
+pub struct ClusterSync {
+    node_id: String,
+    settings: SharedSettings,
+    outbox: Mutex<Vec<ClusterEvent>>,
+    notify: Notify,
+    /// Latest (timestamp, origin) seen per op key.
+    versions: DashMap<String, (i64, String)>,
+}
+
+impl ClusterSync {
+    pub fn new(settings: SharedSettings) -> Self {
+        let configured = settings.load().cluster.node_id.clone();
+        let node_id = if configured.is_empty() {
+            format!("node-{:08x}", rand::random::<u32>())
+        } else {
+            configured
+        };
+        Self {
+            node_id,
+            settings,
+            outbox: Mutex::new(Vec::new()),
+            notify: Notify::new(),
+            versions: DashMap::new(),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Queue a local change for the peers. A no-op while clustering is
+    /// disabled.
+    pub fn publish(&self, op: ClusterOp) {
+        if !self.settings.load().cluster.enabled {
+            return;
+        }
+        let timestamp_ms = Utc::now().timestamp_millis();
+        self.versions
+            .insert(op.key(), (timestamp_ms, self.node_id.clone()));
+        self.outbox.lock().push(ClusterEvent {
+            origin: self.node_id.clone(),
+            timestamp_ms,
+            op,
+        });
+        self.notify.notify_one();
+    }
+
+    /// Whether a remote event is newer than anything seen for its key.
+    /// Records it as the latest if so.
+    pub fn accept(&self, event: &ClusterEvent) -> bool {
+        if event.origin == self.node_id {
+            return false;
+        }
+        let version = (event.timestamp_ms, event.origin.clone());
+        let mut entry = self.versions.entry(event.op.key()).or_insert((i64::MIN, String::new()));
+        if version <= *entry {
+            return false;
+        }
+        *entry = version;
+        true
+    }
+
+    /// Push queued changes to the configured peers until the task is
+    /// aborted. Undelivered events are retried every few seconds; peers
+    /// removed by a config reload are dropped.
+    pub async fn run(self: Arc<Self>) {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new()).build(https);
+        let mut pending: HashMap<String, VecDeque<ClusterEvent>> = HashMap::new();
+
+        loop {
+            tokio::select! {
+                _ = self.notify.notified() => tokio::time::sleep(BATCH_DELAY).await,
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+            }
+
+            let config = self.settings.load().cluster.clone();
+            let events = std::mem::take(&mut *self.outbox.lock());
+            if !config.enabled {
+                pending.clear();
+                continue;
+            }
+
+            pending.retain(|peer, _| config.peers.contains(peer));
+            for peer in &config.peers {
+                let queue = pending.entry(peer.clone()).or_default();
+                queue.extend(events.iter().cloned());
+                while queue.len() > MAX_PENDING_PER_PEER {
+                    queue.pop_front();
+                }
+            }
+
+            for (peer, queue) in pending.iter_mut() {
+                if queue.is_empty() {
+                    continue;
+                }
+                let batch = SyncBatch {
+                    node_id: self.node_id.clone(),
+                    events: queue.iter().cloned().collect(),
+                };
+                match push(&client, peer, &config.shared_secret, &batch).await {
+                    Ok(()) => {
+                        debug!(peer = %peer, events = queue.len(), "Cluster sync pushed");
+                        queue.clear();
+                    }
+                    Err(e) => warn!(peer = %peer, pending = queue.len(), "Cluster sync push failed: {}", e),
+                }
+            }
+        }
+    }
+}
+
+async fn push<C>(client: &Client<C, Full<Bytes>>, peer: &str, secret: &str, batch: &SyncBatch) -> Result<(), String>
+where
+    C: hyper_util::client::legacy::connect::Connect + Clone + Send + Sync + 'static,
+{
+    let body = serde_json::to_vec(batch).map_err(|e| e.to_string())?;
+    let timestamp = Utc::now().timestamp();
+    let req = hyper::Request::post(format!("{}/api/fortress/cluster/sync", peer.trim_end_matches('/')))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(NODE_HEADER, &batch.node_id)
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign(secret, timestamp, &body))
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|e| format!("invalid request: {}", e))?;
+
+    let resp = tokio::time::timeout(PUSH_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| "request timed out".to_string())?
+        .map_err(|e| format!("request failed: {}", e))?;
+    if !resp.status().is_success() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Applying remote events
+// ---------------------------------------------------------------------------
+
+/// Apply an event that [`ClusterSync::accept`] let through. Changes are
+/// audited as `cluster:<origin>`.
+pub async fn apply(
+    event: &ClusterEvent,
+    auto_ban: &AutoBanManager,
+    blocklist: &BlocklistManager,
+    escalation: &EscalationEngine,
+) -> Result<(), String> {
+    let actor = format!("cluster:{}", event.origin);
+    match &event.op {
+        ClusterOp::Ban { ip, reason, duration_secs } => {
+            // Count the ban from when it was issued, not when it arrived.
+            let age_ms = (Utc::now().timestamp_millis() - event.timestamp_ms).max(0) as u64;
+            let remaining = Duration::from_secs(*duration_secs).saturating_sub(Duration::from_millis(age_ms));
+            if !remaining.is_zero() {
+                auto_ban.apply_remote_ban(ip, reason, remaining, &actor);
+            }
+        }
+        ClusterOp::Unban { ip } => {
+            auto_ban.remove_ban(ip, &actor);
+        }
+        ClusterOp::Block { value, action, reason, expires_at, schedule } => {
+            let duration = match expires_at {
+                Some(at) => match (*at - Utc::now()).to_std() {
+                    Ok(d) => Some(d),
+                    Err(_) => return Ok(()), // already expired
+                },
+                None => None,
+            };
+            let schedule = schedule.parse()?;
+            blocklist
+                .add_ip(value, action.unwrap_or(ThreatAction::Block), reason, "cluster", &actor, duration, schedule)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        ClusterOp::Unblock { value } => {
+            blocklist
+                .remove_ip_value(value, &actor)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        ClusterOp::Level { level, service } => {
+            let level = ProtectionLevel::from_u8(*level).ok_or_else(|| format!("invalid level {}", level))?;
+            info!(origin = %event.origin, level = %level, service = ?service, "Protection level set by cluster peer");
+            match service {
+                Some(id) => {
+                    escalation.set_service_level(id, level);
+                }
+                None => escalation.set_level(level),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arc_swap::ArcSwap;
+    use crate::config::settings::Settings;
+
+    fn event(origin: &str, timestamp_ms: i64, op: ClusterOp) -> ClusterEvent {
+        ClusterEvent { origin: origin.to_string(), timestamp_ms, op }
+    }
+
+    #[test]
+    fn test_accept_last_write_wins() {
+        let sync = ClusterSync::new(Arc::new(ArcSwap::from_pointee(Settings::default())));
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        assert!(sync.accept(&event("a", 100, ClusterOp::Ban { ip, reason: "x".into(), duration_secs: 60 })));
+        // Older and duplicate events for the same key are ignored.
+        assert!(!sync.accept(&event("b", 50, ClusterOp::Unban { ip })));
+        assert!(!sync.accept(&event("a", 100, ClusterOp::Unban { ip })));
+        assert!(sync.accept(&event("b", 200, ClusterOp::Unban { ip })));
+        // Own events are never applied.
+        assert!(!sync.accept(&event(sync.node_id(), 300, ClusterOp::Unban { ip })));
+    }
+
+    #[test]
+    fn test_event_wire_format() {
+        let e = event("a", 1, ClusterOp::Level { level: 2, service: None });
+        let json = serde_json::to_value(&e).unwrap();
+        assert_eq!(json["type"], "level");
+        assert_eq!(json["level"], 2);
+        assert!(json.get("service").is_none());
+        let back: ClusterEvent = serde_json::from_value(json).unwrap();
+        assert!(matches!(back.op, ClusterOp::Level { level: 2, service: None }));
+    }
+}
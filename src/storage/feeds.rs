@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use ipnet::IpNet;
+use tracing::{debug, info, warn};
+
+use crate::config::settings::{BlocklistFeedConfig, SharedSettings};
+
+use super::blocklist::BlocklistManager;
+use super::ip_ranges::parse_ip_or_cidr;
+use super::sqlite::NewBlockedIp;
+
+/// Largest feed body accepted. Spamhaus DROP is ~30 KB, full FireHOL
+/// aggregates are a few MB.
+const MAX_FEED_SIZE: usize = 64 * 1024 * 1024;
+const FEED_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the fetcher wakes up to look for feeds that are due.
+const FEED_TICK: Duration = Duration::from_secs(30);
+
+// ---------------------------------------------------------------------------
+// Parsing
+// ---------------------------------------------------------------------------
+
+/// One IP or CIDR from an import body or feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportEntry {
+    pub net: IpNet,
+    pub reason: Option<String>,
+    pub ttl_secs: Option<u64>,
+}
+
+impl ImportEntry {
+    /// Convert to a row for `blocked_ips`. Single hosts are stored as a bare
+    /// IP; ranges use the canonical network as both `ip` and `cidr`, as
+    /// [`BlocklistManager::add_ip`] does.
+    pub fn to_row(&self, default_reason: &str, default_ttl: Option<Duration>) -> NewBlockedIp {
+        let is_host = self.net.prefix_len() == self.net.max_prefix_len();
+        let (ip, cidr) = if is_host {
+            (self.net.addr().to_string(), None)
+        } else {
+            let canonical = self.net.to_string();
+            (canonical.clone(), Some(canonical))
+        };
+        let ttl = self.ttl_secs.map(Duration::from_secs).or(default_ttl);
+        NewBlockedIp {
+            ip,
+            cidr,
+            reason: self.reason.clone().unwrap_or_else(|| default_reason.to_string()),
+            expires_at: ttl.map(|d| Utc::now() + chrono::Duration::seconds(d.as_secs() as i64)),
+            geo: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedEntries {
+    pub entries: Vec<ImportEntry>,
+    /// Line-numbered descriptions of lines that could not be parsed.
+    pub errors: Vec<String>,
+}
+
+/// Parse a newline-delimited or CSV list of IPs/CIDRs.
+///
+/// Each line is `ip[,reason[,ttl_secs]]`. Blank lines, `#` comments, and a
+/// leading `ip,...` header row are skipped. Anything after whitespace or
+/// `;` in the first column is ignored, so feeds in the Spamhaus DROP style
+/// (`1.2.3.0/24 ; SBL123`) parse as-is.
+pub fn parse_entries(text: &str) -> ParsedEntries {
+    let mut parsed = ParsedEntries::default();
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        let mut columns = line.split(',').map(str::trim);
+        let first = columns.next().unwrap_or("");
+        let addr = first
+            .split(|c: char| c.is_whitespace() || c == ';')
+            .next()
+            .unwrap_or("");
+        if idx == 0 && addr.eq_ignore_ascii_case("ip") {
+            continue;
+        }
+
+        let Some(net) = parse_ip_or_cidr(addr) else {
+            parsed
+                .errors
+                .push(format!("line {}: invalid IP or CIDR '{}'", idx + 1, addr));
+            continue;
+        };
+
+        let reason = columns
+            .next()
+            .map(|r| r.trim_matches('"'))
+            .filter(|r| !r.is_empty())
+            .map(str::to_string);
+        let ttl_secs = match columns.next().filter(|t| !t.is_empty()) {
+            Some(t) => match t.parse::<u64>() {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    parsed
+                        .errors
+                        .push(format!("line {}: invalid TTL '{}'", idx + 1, t));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        parsed.entries.push(ImportEntry {
+            net: net.trunc(),
+            reason,
+            ttl_secs,
+        });
+    }
+
+    parsed
+}
+
+// ---------------------------------------------------------------------------
+// Background fetcher
+// ---------------------------------------------------------------------------
+
+pub type FeedClient = Client<hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>, Empty<Bytes>>;
+
+pub fn feed_client() -> FeedClient {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder(TokioExecutor::new()).build(https)
+}
+
+/// `ETag` and `Last-Modified` of the last response, sent back as
+/// `If-None-Match` and `If-Modified-Since`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub enum FetchOutcome {
+    /// 304: the copy from the last fetch is still current.
+    NotModified,
+    Fetched { text: String, validators: Validators },
+}
+
+/// GET `url`, conditional on `validators` from the previous fetch.
+pub async fn fetch_conditional(
+    client: &FeedClient,
+    url: &str,
+    validators: &Validators,
+) -> Result<FetchOutcome, String> {
+    let uri: hyper::Uri = url.parse().map_err(|e| format!("invalid URL: {}", e))?;
+    let mut req = hyper::Request::get(uri);
+    if let Some(etag) = &validators.etag {
+        req = req.header(hyper::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        req = req.header(hyper::header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let req = req.body(Empty::new()).map_err(|e| format!("invalid request: {}", e))?;
+    let resp = tokio::time::timeout(FEED_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| "request timed out".to_string())?
+        .map_err(|e| format!("request failed: {}", e))?;
+    if resp.status() == hyper::StatusCode::NOT_MODIFIED {
+        return Ok(FetchOutcome::NotModified);
+    }
+    if !resp.status().is_success() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v: &hyper::header::HeaderValue| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(hyper::header::ETAG),
+        last_modified: header(hyper::header::LAST_MODIFIED),
+    };
+    let body = tokio::time::timeout(FEED_TIMEOUT, Limited::new(resp.into_body(), MAX_FEED_SIZE).collect())
+        .await
+        .map_err(|_| "body read timed out".to_string())?
+        .map_err(|e| format!("body read failed: {}", e))?
+        .to_bytes();
+    Ok(FetchOutcome::Fetched {
+        text: String::from_utf8_lossy(&body).into_owned(),
+        validators,
+    })
+}
+
+/// Where a list is loaded from.
+pub enum FeedLocation {
+    Url(String),
+    File(String),
+    /// Offline mode, but no `path` configured for the list.
+    Missing,
+}
+
+impl FeedLocation {
+    /// An `http://` or `https://` address is downloaded, anything else is
+    /// read from disk.
+    pub fn parse(source: &str) -> Self {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            Self::Url(source.to_string())
+        } else {
+            Self::File(source.to_string())
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Url(url) => url.clone(),
+            Self::File(path) => path.clone(),
+            Self::Missing => String::new(),
+        }
+    }
+}
+
+/// Download `location`, or read it from disk; a file whose modification
+/// time has not changed counts as not modified.
+pub async fn load_feed(
+    client: &FeedClient,
+    location: &FeedLocation,
+    validators: &Validators,
+) -> Result<FetchOutcome, String> {
+    match location {
+        FeedLocation::Url(url) => fetch_conditional(client, url, validators).await,
+        FeedLocation::File(path) => {
+            let modified = tokio::fs::metadata(path)
+                .await
+                .and_then(|m| m.modified())
+                .map_err(|e| format!("{}: {}", path, e))?;
+            let stamp = DateTime::<Utc>::from(modified).to_rfc3339();
+            if validators.last_modified.as_deref() == Some(stamp.as_str()) {
+                return Ok(FetchOutcome::NotModified);
+            }
+            let text = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| format!("{}: {}", path, e))?;
+            Ok(FetchOutcome::Fetched {
+                text,
+                validators: Validators {
+                    etag: None,
+                    last_modified: Some(stamp),
+                },
+            })
+        }
+        FeedLocation::Missing => Err("offline mode and no path configured".to_string()),
+    }
+}
+
+async fn fetch_feed(client: &FeedClient, url: &str) -> Result<String, String> {
+    match fetch_conditional(client, url, &Validators::default()).await? {
+        FetchOutcome::Fetched { text, .. } => Ok(text),
+        FetchOutcome::NotModified => Err("unexpected 304 Not Modified".to_string()),
+    }
+}
+
+async fn refresh_feed(client: &FeedClient, blocklist: &Arc<BlocklistManager>, feed: &BlocklistFeedConfig) {
+    let text = match fetch_feed(client, &feed.url).await {
+        Ok(text) => text,
+        Err(e) => {
+            warn!(feed = %feed.name, url = %feed.url, "Blocklist feed fetch failed: {}", e);
+            return;
+        }
+    };
+
+    let parsed = parse_entries(&text);
+    if !parsed.errors.is_empty() {
+        debug!(feed = %feed.name, invalid = parsed.errors.len(), "Skipped invalid feed lines");
+    }
+    // An empty or unparseable response is far more likely to be a broken
+    // upstream than a feed that really emptied; keep the current entries.
+    if parsed.entries.is_empty() {
+        warn!(feed = %feed.name, "Blocklist feed returned no entries, keeping current list");
+        return;
+    }
+
+    let reason = feed.reason.clone().unwrap_or_else(|| format!("feed:{}", feed.name));
+    let result = blocklist
+        .sync_feed(&feed.name, &parsed.entries, &reason)
+        .await
+        .map_err(|e| e.to_string());
+
+    match result {
+        Ok(r) => info!(
+            feed = %feed.name,
+            added = r.added,
+            removed = r.removed,
+            unchanged = r.unchanged,
+            "Blocklist feed synced"
+        ),
+        Err(e) => warn!(feed = %feed.name, "Blocklist feed sync failed: {}", e),
+    }
+}
+
+/// Periodically pull the feeds listed in `[[blocklist.feeds]]` and sync
+/// them into the blocklist. The feed list is re-read from the live settings
+/// on every tick, so feeds added or removed by a config reload take effect
+/// without a restart; entries of removed feeds are pruned.
+pub async fn run_feed_fetcher(blocklist: Arc<BlocklistManager>, settings: SharedSettings) {
+    let client = feed_client();
+
+    let mut last_fetch: HashMap<String, Instant> = HashMap::new();
+    let mut configured: Option<Vec<String>> = None;
+
+    loop {
+        let feeds: Vec<BlocklistFeedConfig> = settings
+            .load()
+            .blocklist
+            .feeds
+            .iter()
+            .filter(|f| f.enabled)
+            .cloned()
+            .collect();
+
+        let names: Vec<String> = feeds.iter().map(|f| f.name.clone()).collect();
+        if configured.as_ref() != Some(&names) {
+            match blocklist.prune_feeds(&names).await.map_err(|e| e.to_string()) {
+                Ok(n) if n > 0 => info!(removed = n, "Pruned entries of removed blocklist feeds"),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to prune blocklist feeds: {}", e),
+            }
+            last_fetch.retain(|name, _| names.contains(name));
+            configured = Some(names);
+        }
+
+        for feed in &feeds {
+            let interval = Duration::from_secs(feed.refresh_interval_secs.max(60));
+            let due = last_fetch
+                .get(&feed.name)
+                .is_none_or(|at| at.elapsed() >= interval);
+            if due {
+                last_fetch.insert(feed.name.clone(), Instant::now());
+                refresh_feed(&client, &blocklist, feed).await;
+            }
+        }
+
+        tokio::time::sleep(FEED_TICK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_and_csv() {
+        let text = "ip,reason,ttl\n\
+                    # comment\n\
+                    1.2.3.4\n\
+                    10.0.0.7/8,scanner,3600\n\
+                    2001:db8::/32 ; SBL123\n\
+                    not-an-ip\n\
+                    5.6.7.8,,abc\n";
+        let parsed = parse_entries(text);
+        assert_eq!(parsed.entries.len(), 3);
+        assert_eq!(parsed.errors.len(), 2);
+
+        assert_eq!(parsed.entries[0].net, "1.2.3.4/32".parse::<IpNet>().unwrap());
+        assert_eq!(parsed.entries[1].net, "10.0.0.0/8".parse::<IpNet>().unwrap());
+        assert_eq!(parsed.entries[1].reason.as_deref(), Some("scanner"));
+        assert_eq!(parsed.entries[1].ttl_secs, Some(3600));
+        assert_eq!(parsed.entries[2].net, "2001:db8::/32".parse::<IpNet>().unwrap());
+    }
+
+    #[test]
+    fn test_to_row_host_vs_range() {
+        let host = ImportEntry { net: "1.2.3.4/32".parse().unwrap(), reason: None, ttl_secs: None };
+        let row = host.to_row("import", None);
+        assert_eq!(row.ip, "1.2.3.4");
+        assert!(row.cidr.is_none());
+        assert_eq!(row.reason, "import");
+
+        let range = ImportEntry { net: "10.0.0.0/8".parse().unwrap(), reason: None, ttl_secs: Some(60) };
+        let row = range.to_row("import", None);
+        assert_eq!(row.cidr.as_deref(), Some("10.0.0.0/8"));
+        assert!(row.expires_at.is_some());
+    }
+}
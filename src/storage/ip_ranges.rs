@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Longest-prefix-match table for IPv4 and IPv6 CIDR ranges.
+///
+/// Ranges are bucketed by prefix length, one hash table per length. A lookup
+/// masks the address once per distinct prefix length present (at most 33 for
+/// IPv4 and 129 for IPv6), so its cost does not grow with the number of
+/// stored ranges.
+#[derive(Debug, Clone)]
+pub struct IpRangeMap<V> {
+    v4: BTreeMap<u8, HashMap<u32, (IpNet, V)>>,
+    v6: BTreeMap<u8, HashMap<u128, (IpNet, V)>>,
+}
+
+impl<V> Default for IpRangeMap<V> {
+    fn default() -> Self {
+        Self {
+            v4: BTreeMap::new(),
+            v6: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> IpRangeMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a range, replacing any value stored for the same network.
+    /// Host bits are ignored (`10.0.0.7/8` is stored as `10.0.0.0/8`).
+    pub fn insert(&mut self, net: IpNet, value: V) {
+        let net = net.trunc();
+        let prefix = net.prefix_len();
+        match net {
+            IpNet::V4(n) => self
+                .v4
+                .entry(prefix)
+                .or_default()
+                .insert(u32::from(n.network()), (net, value)),
+            IpNet::V6(n) => self
+                .v6
+                .entry(prefix)
+                .or_default()
+                .insert(u128::from(n.network()), (net, value)),
+        };
+    }
+
+    /// Remove a range. Returns the stored value if it was present.
+    pub fn remove(&mut self, net: &IpNet) -> Option<V> {
+        let net = net.trunc();
+        let prefix = net.prefix_len();
+        let removed = match net {
+            IpNet::V4(n) => {
+                let bucket = self.v4.get_mut(&prefix)?;
+                let removed = bucket.remove(&u32::from(n.network()));
+                if bucket.is_empty() {
+                    self.v4.remove(&prefix);
+                }
+                removed
+            }
+            IpNet::V6(n) => {
+                let bucket = self.v6.get_mut(&prefix)?;
+                let removed = bucket.remove(&u128::from(n.network()));
+                if bucket.is_empty() {
+                    self.v6.remove(&prefix);
+                }
+                removed
+            }
+        };
+        removed.map(|(_, v)| v)
+    }
+
+    /// Find the most specific range containing `ip`.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<(&IpNet, &V)> {
+        self.lookup_where(ip, |_| true)
+    }
+
+    /// Find the most specific range containing `ip` whose value passes
+    /// `keep`, e.g. skipping expired entries.
+    pub fn lookup_where(&self, ip: &IpAddr, keep: impl Fn(&V) -> bool) -> Option<(&IpNet, &V)> {
+        match ip {
+            IpAddr::V4(v4) => {
+                let addr = u32::from(*v4);
+                self.v4.iter().rev().find_map(|(prefix, bucket)| {
+                    bucket.get(&(addr & mask_v4(*prefix))).filter(|(_, v)| keep(v)).map(|(n, v)| (n, v))
+                })
+            }
+            IpAddr::V6(v6) => {
+                let addr = u128::from(*v6);
+                self.v6.iter().rev().find_map(|(prefix, bucket)| {
+                    bucket.get(&(addr & mask_v6(*prefix))).filter(|(_, v)| keep(v)).map(|(n, v)| (n, v))
+                })
+            }
+        }
+    }
+
+    /// Drop every range whose value fails `keep`. Returns how many went.
+    pub fn retain(&mut self, keep: impl Fn(&IpNet, &V) -> bool) -> usize {
+        let mut removed = 0;
+        for bucket in self.v4.values_mut() {
+            let before = bucket.len();
+            bucket.retain(|_, (n, v)| keep(n, v));
+            removed += before - bucket.len();
+        }
+        for bucket in self.v6.values_mut() {
+            let before = bucket.len();
+            bucket.retain(|_, (n, v)| keep(n, v));
+            removed += before - bucket.len();
+        }
+        self.v4.retain(|_, bucket| !bucket.is_empty());
+        self.v6.retain(|_, bucket| !bucket.is_empty());
+        removed
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.lookup(ip).is_some()
+    }
+}
+
+/// Parse an IP or CIDR string. A bare address becomes a /32 or /128.
+pub fn parse_ip_or_cidr(s: &str) -> Option<IpNet> {
+    let s = s.trim();
+    if s.contains('/') {
+        s.parse::<IpNet>().ok()
+    } else {
+        s.parse::<IpAddr>().ok().map(IpNet::from)
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        !0u32 << (32 - prefix as u32)
+    }
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        !0u128 << (128 - prefix as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_prefix_match() {
+        let mut map = IpRangeMap::new();
+        map.insert("203.0.113.0/28".parse().unwrap(), "small");
+        assert!(map.contains(&ip("203.0.113.15")));
+        assert!(!map.contains(&ip("203.0.113.16")));
+    }
+
+    #[test]
+    fn test_ipv6_prefix_match() {
+        let mut map = IpRangeMap::new();
+        map.insert("2001:db8::/32".parse().unwrap(), ());
+        assert!(map.contains(&ip("2001:db8:ffff::1")));
+        assert!(!map.contains(&ip("2001:db9::1")));
+        assert!(!map.contains(&ip("32.1.13.184")));
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let mut map = IpRangeMap::new();
+        map.insert("10.0.0.0/8".parse().unwrap(), "wide");
+        map.insert("10.1.2.0/24".parse().unwrap(), "narrow");
+        assert_eq!(map.lookup(&ip("10.1.2.3")).map(|(_, v)| *v), Some("narrow"));
+        assert_eq!(map.lookup(&ip("10.9.9.9")).map(|(_, v)| *v), Some("wide"));
+    }
+
+    #[test]
+    fn test_insert_truncates_and_remove() {
+        let mut map = IpRangeMap::new();
+        map.insert("192.168.1.77/24".parse().unwrap(), ());
+        assert!(map.contains(&ip("192.168.1.1")));
+        assert!(map.remove(&"192.168.1.0/24".parse().unwrap()).is_some());
+        assert!(!map.contains(&ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn test_parse_ip_or_cidr() {
+        assert_eq!(parse_ip_or_cidr("1.2.3.4"), Some("1.2.3.4/32".parse().unwrap()));
+        assert_eq!(parse_ip_or_cidr("::1"), Some("::1/128".parse().unwrap()));
+        assert!(parse_ip_or_cidr("1.2.3.4/33").is_none());
+        assert!(parse_ip_or_cidr("nope").is_none());
+    }
+}
@@ -1,11 +1,25 @@
+use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
 use std::net::IpAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use ipnet::IpNet;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 
+use crate::config::defaults;
+use crate::config::settings::{BehavioralConfig, ProtectionConfig};
+use crate::models::threat::ThreatAction;
+use crate::protection::ip_policies::IpPolicy;
+use crate::storage::ip_ranges::IpRangeMap;
+use crate::storage::sweep::{Sweep, SweepStats};
+
 // ---------------------------------------------------------------------------
 // Rate-limit configuration (expected to be defined elsewhere; redeclared here
 // so the module is self-contained).
@@ -19,6 +33,19 @@ pub struct RateLimitConfig {
     pub country_per_second: u64,
 }
 
+impl RateLimitConfig {
+    /// Every tier multiplied by `factor`, at least 1 req/s each.
+    pub fn scaled(&self, factor: f64) -> Self {
+        let scale = |v: u64| ((v as f64 * factor).round() as u64).max(1);
+        Self {
+            ip_per_second: scale(self.ip_per_second),
+            subnet_per_second: scale(self.subnet_per_second),
+            asn_per_second: scale(self.asn_per_second),
+            country_per_second: scale(self.country_per_second),
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // SlidingWindow – per-key counter that only keeps data inside the window.
 // ---------------------------------------------------------------------------
@@ -38,6 +65,9 @@ impl SlidingWindow {
     }
 
     pub fn increment(&mut self) {
+        // Drop expired buckets as we go so a hot key doesn't grow until the
+        // next periodic cleanup.
+        self.cleanup();
         let now = Instant::now();
         if let Some(last) = self.counts.back_mut() {
             // Coalesce increments that arrive within 1 ms of each other.
@@ -71,6 +101,119 @@ impl SlidingWindow {
     }
 }
 
+// ---------------------------------------------------------------------------
+// WindowMap – sliding windows per key, capped in size.
+// ---------------------------------------------------------------------------
+
+/// How often a full map may be swept for idle windows.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Countries are two-letter codes, so this is only a backstop.
+const MAX_TRACKED_COUNTRIES: usize = 1024;
+
+/// Sliding windows keyed by IP, subnet, ASN or country, holding at most
+/// `max` keys. A new key arriving at a full map first triggers a sweep of
+/// idle windows (at most once per [`SWEEP_INTERVAL`]); if the map is still
+/// full the key is not tracked and counted as an overflow.
+struct WindowMap<K: Eq + Hash> {
+    windows: DashMap<K, SlidingWindow>,
+    max: AtomicUsize,
+    overflowed: AtomicU64,
+    last_sweep: Mutex<Instant>,
+    cursor: Sweep,
+}
+
+impl<K: Eq + Hash + Clone> WindowMap<K> {
+    fn new(max: usize) -> Self {
+        Self {
+            windows: DashMap::new(),
+            max: AtomicUsize::new(max),
+            overflowed: AtomicU64::new(0),
+            last_sweep: Mutex::new(Instant::now()),
+            cursor: Sweep::new(),
+        }
+    }
+
+    /// Count a hit for `key`. Returns `false` if the map is full and the
+    /// key was not tracked.
+    fn increment<Q>(&self, key: &Q, window_secs: u64) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(mut window) = self.windows.get_mut(key) {
+            window.increment();
+            return true;
+        }
+        if self.windows.len() >= self.max.load(Ordering::Relaxed) && !self.sweep() {
+            self.overflowed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.windows
+            .entry(key.to_owned())
+            .or_insert_with(|| SlidingWindow::new(window_secs))
+            .increment();
+        true
+    }
+
+    /// Evict idle windows. Returns `true` if there is room afterwards.
+    fn sweep(&self) -> bool {
+        let Some(mut last) = self.last_sweep.try_lock() else {
+            return false;
+        };
+        if last.elapsed() < SWEEP_INTERVAL {
+            return false;
+        }
+        *last = Instant::now();
+        drop(last);
+        self.cleanup(usize::MAX);
+        self.windows.len() < self.max.load(Ordering::Relaxed)
+    }
+
+    fn count<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.windows.get(key).map(|w| w.count())
+    }
+
+    /// Drop windows left empty, visiting about `budget` of them.
+    fn cleanup(&self, budget: usize) -> SweepStats {
+        self.cursor.retain(&self.windows, budget, |_, w| {
+            w.cleanup();
+            !w.counts.is_empty()
+        })
+    }
+
+    fn stats(&self) -> MapStats {
+        MapStats {
+            tracked: self.windows.len(),
+            max: self.max.load(Ordering::Relaxed),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Size of one tracking map, for `/api/fortress/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MapStats {
+    pub tracked: usize,
+    pub max: usize,
+    /// New keys turned away because the map was full, since startup.
+    pub overflowed: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackingStats {
+    pub ips: MapStats,
+    pub subnets: MapStats,
+    pub asns: MapStats,
+    pub countries: MapStats,
+    pub behavior_profiles: MapStats,
+    pub challenges_issued: MapStats,
+}
+
 // ---------------------------------------------------------------------------
 // BehaviorProfile
 // ---------------------------------------------------------------------------
@@ -103,6 +246,121 @@ impl BehaviorProfile {
             consistency_violations: 0,
         }
     }
+
+    /// Record one request.
+    fn observe(
+        &mut self,
+        path: &str,
+        method: &str,
+        ja3: Option<&str>,
+        ua: Option<&str>,
+        now: Instant,
+        config: &BehavioralConfig,
+    ) {
+        let interval = now.duration_since(self.last_seen);
+        if interval > Duration::from_secs(config.scoring_window_secs) {
+            *self = Self::new();
+        }
+        self.last_seen = now;
+        self.total_requests += 1;
+
+        // Record inter-request interval (keep the last `interval_history`).
+        while self.request_intervals.len() >= config.interval_history.max(1) {
+            self.request_intervals.pop_front();
+        }
+        self.request_intervals.push_back(interval);
+
+        // Record path and method
+        self.paths_visited.insert(hash_path(path));
+        *self.methods_used.entry(method.to_string()).or_insert(0) += 1;
+
+        // Check JA3 / UA consistency
+        if let Some(j) = ja3 {
+            match &self.ja3_hash {
+                Some(prev) if prev != j => {
+                    self.consistency_violations += 1;
+                    self.ja3_hash = Some(j.to_string());
+                }
+                None => {
+                    self.ja3_hash = Some(j.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(u) = ua {
+            match &self.user_agent {
+                Some(prev) if prev != u => {
+                    self.consistency_violations += 1;
+                    self.user_agent = Some(u.to_string());
+                }
+                None => {
+                    self.user_agent = Some(u.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Suspicion score in `[0.0, 1.0]`, see [`BehavioralConfig`].
+    fn suspicion(&self, now: Instant, config: &BehavioralConfig) -> f64 {
+        let mut score: f64 = 0.0;
+
+        // 1. Request-interval regularity: very uniform intervals are suspicious
+        //    (bots often fire at fixed intervals).
+        if self.request_intervals.len() >= 5 {
+            let intervals: Vec<f64> = self
+                .request_intervals
+                .iter()
+                .map(|d| d.as_secs_f64())
+                .collect();
+            let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+            if mean > 0.0 {
+                let variance = intervals
+                    .iter()
+                    .map(|v| (v - mean).powi(2))
+                    .sum::<f64>()
+                    / intervals.len() as f64;
+                let cv = variance.sqrt() / mean; // coefficient of variation
+                // Very low CV => regular intervals => mildly suspicious
+                // (Monitoring systems and health checks are legitimate,
+                // hence the low default weight)
+                if cv < config.regular_cv {
+                    score += 0.3 * config.regularity_weight;
+                } else if cv < config.near_regular_cv {
+                    score += 0.1 * config.regularity_weight;
+                }
+            }
+        }
+
+        // 2. Very high request rate
+        if self.total_requests > config.rate_min_requests {
+            let elapsed = now.duration_since(self.first_seen).as_secs_f64();
+            if elapsed > 0.0 {
+                let rps = self.total_requests as f64 / elapsed;
+                if rps > config.high_rps {
+                    score += 0.3 * config.rate_weight;
+                } else if rps > config.elevated_rps {
+                    score += 0.15 * config.rate_weight;
+                }
+            }
+        }
+
+        // 3. Path diversity: very few distinct paths with many requests
+        // (the request floor keeps single-endpoint APIs out)
+        if self.total_requests > config.path_diversity_min_requests
+            && self.paths_visited.len() <= config.path_diversity_max_paths
+        {
+            score += 0.1 * config.path_diversity_weight;
+        }
+
+        // 4. Consistency violations
+        if self.consistency_violations > 0 {
+            score += (self.consistency_violations as f64 * 0.1).min(0.25) * config.consistency_weight;
+        }
+
+        score.clamp(0.0, 1.0)
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -111,36 +369,55 @@ impl BehaviorProfile {
 
 #[derive(Debug, Clone)]
 pub struct BlockedEntry {
+    pub action: ThreatAction,
     pub reason: String,
     pub expires_at: Option<Instant>,
     pub source: String,
 }
 
+/// A cached `ip_policies` row.
+#[derive(Debug, Clone, Serialize)]
+pub struct IpPolicyEntry {
+    pub id: i64,
+    pub policy: IpPolicy,
+    pub reason: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl IpPolicyEntry {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|exp| now < exp)
+    }
+}
+
 // ---------------------------------------------------------------------------
-// Helper: map IPv4 to /24 represented as u32
+// Helper: map an IP to its subnet prefix
 // ---------------------------------------------------------------------------
 
-pub fn ip_to_subnet(ip: IpAddr, mask_bits: u8) -> u32 {
-    match ip {
-        IpAddr::V4(v4) => {
-            let ip_u32 = u32::from(v4);
-            let mask = if mask_bits >= 32 {
-                0xFFFFFFFFu32
-            } else {
-                !((1u32 << (32 - mask_bits)) - 1)
-            };
-            ip_u32 & mask
-        }
-        IpAddr::V6(v6) => {
-            let segments = v6.segments();
-            // Use first 4 segments (/64 prefix) hashed to u32
-            let mut hash: u32 = 0;
-            for i in 0..4 {
-                hash = hash.wrapping_mul(31).wrapping_add(segments[i] as u32);
-            }
-            hash
-        }
-    }
+/// A subnet prefix as a masked 128-bit address. IPv4 subnets live in the
+/// IPv4-mapped range (`::ffff:a.b.c.0`), so they never collide with IPv6.
+pub type SubnetKey = u128;
+
+/// Mask `ip` to `ipv4_mask` or `ipv6_mask` bits (clamped to the address
+/// width) and return the prefix as a [`SubnetKey`].
+pub fn ip_to_subnet(ip: IpAddr, ipv4_mask: u8, ipv6_mask: u8) -> SubnetKey {
+    let (addr, bits) = match ip {
+        IpAddr::V4(v4) => (v4.to_ipv6_mapped(), 96 + ipv4_mask.min(32) as u32),
+        IpAddr::V6(v6) => (v6, ipv6_mask.min(128) as u32),
+    };
+    let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+    u128::from(addr) & mask
+}
+
+/// The subnet containing `ip`, for display and for grouping bans. Uses the
+/// same masks as [`ip_to_subnet`].
+pub fn subnet_network(ip: IpAddr, ipv4_mask: u8, ipv6_mask: u8) -> IpNet {
+    let prefix = match ip {
+        IpAddr::V4(_) => ipv4_mask.min(32),
+        IpAddr::V6(_) => ipv6_mask.min(128),
+    };
+    // The prefix is clamped to the address width, so this cannot fail.
+    IpNet::new(ip, prefix).map(|net| net.trunc()).unwrap_or_else(|_| IpNet::from(ip))
 }
 
 // ---------------------------------------------------------------------------
@@ -162,20 +439,41 @@ fn hash_path(path: &str) -> u64 {
 
 pub struct MemoryStore {
     // Rate limiting
-    ip_requests: DashMap<IpAddr, SlidingWindow>,
-    subnet_requests: DashMap<u32, SlidingWindow>,
-    asn_requests: DashMap<u32, SlidingWindow>,
-    country_requests: DashMap<String, SlidingWindow>,
+    ip_requests: WindowMap<IpAddr>,
+    subnet_requests: WindowMap<SubnetKey>,
+    asn_requests: WindowMap<u32>,
+    country_requests: WindowMap<String>,
 
-    // Behavioral profiles
+    // Behavioral profiles, capped at `behavioral.max_profiles`
     behavior_profiles: DashMap<IpAddr, BehaviorProfile>,
+    behavior_config: ArcSwap<BehavioralConfig>,
+    profiles_evicted: AtomicU64,
+    evicting_profiles: AtomicBool,
 
     // Blocked IPs (runtime cache from SQLite)
     blocked_ips: DashMap<IpAddr, BlockedEntry>,
 
+    // IP policies (runtime cache from SQLite), most specific range first
+    ip_policies: RwLock<IpRangeMap<IpPolicyEntry>>,
+
     // Challenge clearances
     clearances: DashMap<IpAddr, Instant>, // IP -> expiry
 
+    // Redeemed PoW challenge ids, kept until the challenge would expire
+    used_challenges: DashMap<String, Instant>,
+
+    // Challenge pages served per IP since its last solve
+    challenges_issued: WindowMap<IpAddr>,
+
+    // Nojs verification attempts per IP
+    nojs_attempts: WindowMap<IpAddr>,
+
+    // Where the periodic cleanup resumes in each map
+    profiles_sweep: Sweep,
+    blocked_sweep: Sweep,
+    clearances_sweep: Sweep,
+    used_challenges_sweep: Sweep,
+
     // Active connections
     active_connections: AtomicU64,
 
@@ -184,23 +482,72 @@ pub struct MemoryStore {
     passed_requests: AtomicU64,
     blocked_requests: AtomicU64,
     challenged_requests: AtomicU64,
+    challenge_pages_issued: AtomicU64,
+    challenges_solved: AtomicU64,
+    challenges_suppressed: AtomicU64,
 }
 
 impl MemoryStore {
     pub fn new() -> Self {
         Self {
-            ip_requests: DashMap::new(),
-            subnet_requests: DashMap::new(),
-            asn_requests: DashMap::new(),
-            country_requests: DashMap::new(),
+            ip_requests: WindowMap::new(defaults::default_max_tracked_ips()),
+            subnet_requests: WindowMap::new(defaults::default_max_tracked_subnets()),
+            asn_requests: WindowMap::new(defaults::default_max_tracked_asns()),
+            country_requests: WindowMap::new(MAX_TRACKED_COUNTRIES),
             behavior_profiles: DashMap::new(),
+            behavior_config: ArcSwap::from_pointee(defaults::default_behavioral_config()),
+            profiles_evicted: AtomicU64::new(0),
+            evicting_profiles: AtomicBool::new(false),
             blocked_ips: DashMap::new(),
+            ip_policies: RwLock::new(IpRangeMap::new()),
             clearances: DashMap::new(),
+            used_challenges: DashMap::new(),
+            challenges_issued: WindowMap::new(defaults::default_max_tracked_ips()),
+            nojs_attempts: WindowMap::new(defaults::default_max_tracked_ips()),
+            profiles_sweep: Sweep::new(),
+            blocked_sweep: Sweep::new(),
+            clearances_sweep: Sweep::new(),
+            used_challenges_sweep: Sweep::new(),
             active_connections: AtomicU64::new(0),
             total_requests: AtomicU64::new(0),
             passed_requests: AtomicU64::new(0),
             blocked_requests: AtomicU64::new(0),
             challenged_requests: AtomicU64::new(0),
+            challenge_pages_issued: AtomicU64::new(0),
+            challenges_solved: AtomicU64::new(0),
+            challenges_suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply the `max_tracked_*` caps from the protection config. Maps
+    /// already above a lowered cap shrink as idle entries are evicted.
+    pub fn set_tracking_limits(&self, config: &ProtectionConfig) {
+        self.ip_requests.max.store(config.max_tracked_ips, Ordering::Relaxed);
+        self.challenges_issued.max.store(config.max_tracked_ips, Ordering::Relaxed);
+        self.nojs_attempts.max.store(config.max_tracked_ips, Ordering::Relaxed);
+        self.subnet_requests.max.store(config.max_tracked_subnets, Ordering::Relaxed);
+        self.asn_requests.max.store(config.max_tracked_asns, Ordering::Relaxed);
+    }
+
+    /// Apply the `[behavioral]` scoring settings and profile cap. Profiles
+    /// above a lowered cap are evicted as new clients arrive.
+    pub fn set_behavior_config(&self, config: &BehavioralConfig) {
+        self.behavior_config.store(Arc::new(config.clone()));
+    }
+
+    /// Current size, cap and overflow count of each per-key map.
+    pub fn tracking_stats(&self) -> TrackingStats {
+        TrackingStats {
+            ips: self.ip_requests.stats(),
+            subnets: self.subnet_requests.stats(),
+            asns: self.asn_requests.stats(),
+            countries: self.country_requests.stats(),
+            behavior_profiles: MapStats {
+                tracked: self.behavior_profiles.len(),
+                max: self.behavior_config.load().max_profiles,
+                overflowed: self.profiles_evicted.load(Ordering::Relaxed),
+            },
+            challenges_issued: self.challenges_issued.stats(),
         }
     }
 
@@ -208,42 +555,28 @@ impl MemoryStore {
     // Rate limiting
     // -----------------------------------------------------------------------
 
-    /// Increment sliding-window counters for every dimension.
-    pub fn record_request(&self, ip: IpAddr, subnet: u32, asn: u32, country: &str) {
+    /// Increment sliding-window counters for every dimension. Once a map is
+    /// full, new keys are left out of it; an IP that can't be tracked is
+    /// still counted against its subnet.
+    pub fn record_request(&self, ip: IpAddr, subnet: SubnetKey, asn: u32, country: &str) {
         self.total_requests.fetch_add(1, Ordering::Relaxed);
 
-        self.ip_requests
-            .entry(ip)
-            .or_insert_with(|| SlidingWindow::new(1))
-            .increment();
-
-        self.subnet_requests
-            .entry(subnet)
-            .or_insert_with(|| SlidingWindow::new(1))
-            .increment();
-
-        self.asn_requests
-            .entry(asn)
-            .or_insert_with(|| SlidingWindow::new(1))
-            .increment();
-
-        self.country_requests
-            .entry(country.to_string())
-            .or_insert_with(|| SlidingWindow::new(1))
-            .increment();
+        self.ip_requests.increment(&ip, 1);
+        self.subnet_requests.increment(&subnet, 1);
+        self.asn_requests.increment(&asn, 1);
+        self.country_requests.increment(country, 1);
     }
 
     /// Returns `Some(reason)` if any rate limit is exceeded.
     pub fn check_rate_limit(
         &self,
         ip: IpAddr,
-        subnet: u32,
+        subnet: SubnetKey,
         asn: u32,
         country: &str,
         limits: &RateLimitConfig,
     ) -> Option<String> {
-        if let Some(window) = self.ip_requests.get(&ip) {
-            let count = window.count();
+        if let Some(count) = self.ip_requests.count(&ip) {
             if count > limits.ip_per_second {
                 return Some(format!(
                     "IP rate limit exceeded: {} req/s (limit {})",
@@ -252,18 +585,16 @@ impl MemoryStore {
             }
         }
 
-        if let Some(window) = self.subnet_requests.get(&subnet) {
-            let count = window.count();
+        if let Some(count) = self.subnet_requests.count(&subnet) {
             if count > limits.subnet_per_second {
                 return Some(format!(
-                    "Subnet /24 rate limit exceeded: {} req/s (limit {})",
+                    "Subnet rate limit exceeded: {} req/s (limit {})",
                     count, limits.subnet_per_second
                 ));
             }
         }
 
-        if let Some(window) = self.asn_requests.get(&asn) {
-            let count = window.count();
+        if let Some(count) = self.asn_requests.count(&asn) {
             if count > limits.asn_per_second {
                 return Some(format!(
                     "ASN {} rate limit exceeded: {} req/s (limit {})",
@@ -274,8 +605,7 @@ impl MemoryStore {
             }
         }
 
-        if let Some(window) = self.country_requests.get(country) {
-            let count = window.count();
+        if let Some(count) = self.country_requests.count(country) {
             if count > limits.country_per_second {
                 return Some(format!(
                     "Country {} rate limit exceeded: {} req/s (limit {})",
@@ -307,11 +637,12 @@ impl MemoryStore {
         None
     }
 
-    pub fn block_ip(&self, ip: IpAddr, reason: String, duration: Option<Duration>) {
+    pub fn block_ip(&self, ip: IpAddr, action: ThreatAction, reason: String, duration: Option<Duration>) {
         let expires_at = duration.map(|d| Instant::now() + d);
         self.blocked_ips.insert(
             ip,
             BlockedEntry {
+                action,
                 reason,
                 expires_at,
                 source: "auto".to_string(),
@@ -323,6 +654,46 @@ impl MemoryStore {
         self.blocked_ips.remove(ip);
     }
 
+    /// Drop every cached block whose IP fails `keep`.
+    pub fn retain_blocked_ips(&self, keep: impl Fn(&IpAddr) -> bool) {
+        self.blocked_ips.retain(|ip, _| keep(ip));
+    }
+
+    // -----------------------------------------------------------------------
+    // IP policies
+    // -----------------------------------------------------------------------
+
+    /// Policy of the most specific unexpired range containing `ip`.
+    pub fn ip_policy(&self, ip: &IpAddr) -> Option<IpPolicy> {
+        let now = Utc::now();
+        self.ip_policies.read().lookup_where(ip, |e| e.is_active(now)).map(|(_, e)| e.policy)
+    }
+
+    /// Like [`ip_policy`](Self::ip_policy), with the range and its row.
+    pub fn ip_policy_entry(&self, ip: &IpAddr) -> Option<(IpNet, IpPolicyEntry)> {
+        let now = Utc::now();
+        self.ip_policies.read().lookup_where(ip, |e| e.is_active(now)).map(|(n, e)| (*n, e.clone()))
+    }
+
+    pub fn set_ip_policy(&self, net: IpNet, entry: IpPolicyEntry) {
+        self.ip_policies.write().insert(net, entry);
+    }
+
+    pub fn remove_ip_policy(&self, net: &IpNet) {
+        self.ip_policies.write().remove(net);
+    }
+
+    /// Replace every cached policy, e.g. after loading them from SQLite.
+    pub fn replace_ip_policies(&self, policies: IpRangeMap<IpPolicyEntry>) {
+        *self.ip_policies.write() = policies;
+    }
+
+    /// Evict the policies whose TTL has run out. Returns how many went.
+    pub fn purge_expired_ip_policies(&self) -> usize {
+        let now = Utc::now();
+        self.ip_policies.write().retain(|_, e| e.is_active(now))
+    }
+
     // -----------------------------------------------------------------------
     // Challenge clearances
     // -----------------------------------------------------------------------
@@ -342,12 +713,85 @@ impl MemoryStore {
         self.clearances.insert(ip, Instant::now() + duration);
     }
 
+    /// Count a challenge page served to `ip` in a `window_secs` sliding window.
+    pub fn record_challenge_issued(&self, ip: IpAddr, window_secs: u64) {
+        self.challenges_issued.increment(&ip, window_secs);
+        self.challenge_pages_issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Challenge pages served to `ip` inside the window and not yet solved.
+    pub fn unanswered_challenges(&self, ip: &IpAddr) -> u64 {
+        self.challenges_issued.count(ip).unwrap_or(0)
+    }
+
+    /// Reset the unanswered counter after `ip` solved a challenge.
+    pub fn record_challenge_solved(&self, ip: &IpAddr) {
+        self.challenges_issued.windows.remove(ip);
+        self.challenges_solved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request that got a 403/tarpit instead of a challenge page.
+    pub fn record_challenge_suppressed(&self) {
+        self.challenges_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns `(issued, solved, suppressed)` since startup.
+    pub fn challenge_counters(&self) -> (u64, u64, u64) {
+        (
+            self.challenge_pages_issued.load(Ordering::Relaxed),
+            self.challenges_solved.load(Ordering::Relaxed),
+            self.challenges_suppressed.load(Ordering::Relaxed),
+        )
+    }
+
+    /// IPs with unanswered challenges, highest count first.
+    pub fn top_unanswered_challenges(&self, limit: usize) -> Vec<(IpAddr, u64)> {
+        let mut ips: Vec<(IpAddr, u64)> = self
+            .challenges_issued
+            .windows
+            .iter()
+            .map(|e| (*e.key(), e.value().count()))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        ips.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        ips.truncate(limit);
+        ips
+    }
+
+    /// Count a nojs verification attempt by `ip` and return the attempts
+    /// inside the last `window_secs`, this one included. Like the other
+    /// windows, an IP left out of a full map is not counted.
+    pub fn record_nojs_attempt(&self, ip: IpAddr, window_secs: u64) -> u64 {
+        self.nojs_attempts.increment(&ip, window_secs);
+        self.nojs_attempts.count(&ip).unwrap_or(0)
+    }
+
+    /// Record a PoW challenge as redeemed. Returns `false` if it was already
+    /// redeemed and has not yet aged out.
+    pub fn redeem_challenge(&self, challenge_id: &str, ttl: Duration) -> bool {
+        let now = Instant::now();
+        match self.used_challenges.entry(challenge_id.to_string()) {
+            dashmap::Entry::Occupied(mut entry) => {
+                if now < *entry.get() {
+                    return false;
+                }
+                entry.insert(now + ttl);
+                true
+            }
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(now + ttl);
+                true
+            }
+        }
+    }
+
     // -----------------------------------------------------------------------
     // Behavioral profiling
     // -----------------------------------------------------------------------
 
     /// Update the behavioral profile for `ip` and return a suspicion score
-    /// in the range `[0.0, 1.0]`.  Higher means more suspicious.
+    /// in the range `[0.0, 1.0]`.  Higher means more suspicious. A new IP
+    /// beyond `max_profiles` evicts the least recently seen profiles.
     pub fn update_behavior(
         &self,
         ip: IpAddr,
@@ -356,141 +800,99 @@ impl MemoryStore {
         ja3: Option<&str>,
         ua: Option<&str>,
     ) -> f64 {
+        let config = self.behavior_config.load();
+        if config.max_profiles == 0 {
+            return 0.0;
+        }
+        if !self.behavior_profiles.contains_key(&ip) && self.behavior_profiles.len() >= config.max_profiles {
+            self.evict_oldest_profiles(config.max_profiles);
+        }
         let mut profile = self
             .behavior_profiles
             .entry(ip)
             .or_insert_with(BehaviorProfile::new);
 
         let now = Instant::now();
-        let interval = now.duration_since(profile.last_seen);
-        profile.last_seen = now;
-        profile.total_requests += 1;
-
-        // Record inter-request interval (keep last 100).
-        if profile.request_intervals.len() >= 100 {
-            profile.request_intervals.pop_front();
-        }
-        profile.request_intervals.push_back(interval);
-
-        // Record path and method
-        profile.paths_visited.insert(hash_path(path));
-        *profile.methods_used.entry(method.to_string()).or_insert(0) += 1;
-
-        // Check JA3 / UA consistency
-        if let Some(j) = ja3 {
-            match &profile.ja3_hash {
-                Some(prev) if prev != j => {
-                    profile.consistency_violations += 1;
-                    profile.ja3_hash = Some(j.to_string());
-                }
-                None => {
-                    profile.ja3_hash = Some(j.to_string());
-                }
-                _ => {}
-            }
-        }
-
-        if let Some(u) = ua {
-            match &profile.user_agent {
-                Some(prev) if prev != u => {
-                    profile.consistency_violations += 1;
-                    profile.user_agent = Some(u.to_string());
-                }
-                None => {
-                    profile.user_agent = Some(u.to_string());
-                }
-                _ => {}
-            }
-        }
-
-        // --- Compute suspicion score ---
-        let mut score: f64 = 0.0;
-
-        // 1. Request-interval regularity: very uniform intervals are suspicious
-        //    (bots often fire at fixed intervals).
-        if profile.request_intervals.len() >= 5 {
-            let intervals: Vec<f64> = profile
-                .request_intervals
-                .iter()
-                .map(|d| d.as_secs_f64())
-                .collect();
-            let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
-            if mean > 0.0 {
-                let variance = intervals
-                    .iter()
-                    .map(|v| (v - mean).powi(2))
-                    .sum::<f64>()
-                    / intervals.len() as f64;
-                let cv = variance.sqrt() / mean; // coefficient of variation
-                // Very low CV => regular intervals => mildly suspicious
-                // (Reduced: monitoring systems and health checks are legitimate)
-                if cv < 0.05 {
-                    score += 0.15;
-                } else if cv < 0.15 {
-                    score += 0.05;
-                }
-            }
-        }
+        profile.observe(path, method, ja3, ua, now, &config);
+        profile.suspicion(now, &config)
+    }
 
-        // 2. Very high request rate
-        if profile.total_requests > 100 {
-            let elapsed = now.duration_since(profile.first_seen).as_secs_f64();
-            if elapsed > 0.0 {
-                let rps = profile.total_requests as f64 / elapsed;
-                if rps > 50.0 {
-                    score += 0.3;
-                } else if rps > 20.0 {
-                    score += 0.15;
-                }
-            }
+    /// Make room below `max` by dropping the least recently seen profiles,
+    /// 1% of `max` at a time so the scan is amortised over many new
+    /// clients. Concurrent callers skip it while one thread evicts.
+    fn evict_oldest_profiles(&self, max: usize) {
+        if self.evicting_profiles.swap(true, Ordering::Acquire) {
+            return;
         }
-
-        // 3. Path diversity: very few distinct paths with many requests
-        // (Raised from 20 to 50 to avoid penalizing single-endpoint APIs)
-        if profile.total_requests > 50 && profile.paths_visited.len() <= 2 {
-            score += 0.10;
+        let excess = (self.behavior_profiles.len() + 1).saturating_sub(max);
+        let mut last_seen: Vec<Instant> = self.behavior_profiles.iter().map(|p| p.last_seen).collect();
+        let evict = excess.max(max / 100).clamp(1, last_seen.len().max(1));
+        if !last_seen.is_empty() {
+            let (_, cutoff, _) = last_seen.select_nth_unstable(evict - 1);
+            let cutoff = *cutoff;
+            let before = self.behavior_profiles.len();
+            self.behavior_profiles.retain(|_, p| p.last_seen > cutoff);
+            let evicted = before.saturating_sub(self.behavior_profiles.len());
+            self.profiles_evicted.fetch_add(evicted as u64, Ordering::Relaxed);
         }
+        self.evicting_profiles.store(false, Ordering::Release);
+    }
 
-        // 4. Consistency violations
-        if profile.consistency_violations > 0 {
-            score += (profile.consistency_violations as f64 * 0.1).min(0.25);
+    /// The score [`update_behavior`](Self::update_behavior) would return
+    /// for this request, without recording it.
+    pub fn peek_behavior(
+        &self,
+        ip: IpAddr,
+        path: &str,
+        method: &str,
+        ja3: Option<&str>,
+        ua: Option<&str>,
+    ) -> f64 {
+        let config = self.behavior_config.load();
+        if config.max_profiles == 0 {
+            return 0.0;
         }
-
-        score.min(1.0)
+        let mut profile = match self.behavior_profiles.get(&ip) {
+            Some(profile) => profile.clone(),
+            None => BehaviorProfile::new(),
+        };
+        let now = Instant::now();
+        profile.observe(path, method, ja3, ua, now, &config);
+        profile.suspicion(now, &config)
     }
 
     // -----------------------------------------------------------------------
     // Cleanup
     // -----------------------------------------------------------------------
 
-    /// Remove expired entries from every map.
-    pub fn cleanup(&self) {
+    /// Remove expired entries, visiting about `budget` entries of each map
+    /// (see [`Sweep`]).
+    pub fn cleanup(&self, budget: usize) -> SweepStats {
         let now = Instant::now();
 
-        // Sliding windows
-        self.ip_requests.iter_mut().for_each(|mut entry| entry.value_mut().cleanup());
-        self.subnet_requests.iter_mut().for_each(|mut entry| entry.value_mut().cleanup());
-        self.asn_requests.iter_mut().for_each(|mut entry| entry.value_mut().cleanup());
-        self.country_requests.iter_mut().for_each(|mut entry| entry.value_mut().cleanup());
-
-        // Remove empty sliding windows
-        self.ip_requests.retain(|_, v| !v.counts.is_empty());
-        self.subnet_requests.retain(|_, v| !v.counts.is_empty());
-        self.asn_requests.retain(|_, v| !v.counts.is_empty());
-        self.country_requests.retain(|_, v| !v.counts.is_empty());
+        // Sliding windows, dropping the ones left empty
+        let mut stats = self.ip_requests.cleanup(budget);
+        stats += self.subnet_requests.cleanup(budget);
+        stats += self.asn_requests.cleanup(budget);
+        stats += self.country_requests.cleanup(budget);
+        stats += self.challenges_issued.cleanup(budget);
+        stats += self.nojs_attempts.cleanup(budget);
 
         // Expired blocked IPs
-        self.blocked_ips.retain(|_, v| {
+        stats += self.blocked_sweep.retain(&self.blocked_ips, budget, |_, v| {
             v.expires_at.map_or(true, |exp| now < exp)
         });
 
         // Expired clearances
-        self.clearances.retain(|_, exp| now < *exp);
+        stats += self.clearances_sweep.retain(&self.clearances, budget, |_, exp| now < *exp);
+        stats += self.used_challenges_sweep.retain(&self.used_challenges, budget, |_, exp| now < *exp);
 
-        // Stale behavior profiles (no activity in the last 10 minutes)
-        let stale_cutoff = now - Duration::from_secs(600);
-        self.behavior_profiles
-            .retain(|_, v| v.last_seen >= stale_cutoff);
+        // Stale behavior profiles, which would start over anyway
+        let window = Duration::from_secs(self.behavior_config.load().scoring_window_secs);
+        if let Some(stale_cutoff) = now.checked_sub(window) {
+            stats += self.profiles_sweep.retain(&self.behavior_profiles, budget, |_, v| v.last_seen >= stale_cutoff);
+        }
+        stats
     }
 
     // -----------------------------------------------------------------------
@@ -548,3 +950,156 @@ impl Default for MemoryStore {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_spoofed_source_flood_stays_bounded() {
+        let memory = MemoryStore::new();
+        let mut config = defaults::default_protection_config();
+        config.max_tracked_ips = 10_000;
+        config.max_tracked_subnets = 1_000;
+        config.max_tracked_asns = 100;
+        memory.set_tracking_limits(&config);
+
+        // 1M unique sources spread over 4096 /24s and 4096 ASNs.
+        for i in 0..1_000_000u32 {
+            let ip = IpAddr::V4(Ipv4Addr::from(0x0A00_0000 | (i & 0x00FF_FFFF)));
+            let subnet = ip_to_subnet(ip, 24, 64);
+            memory.record_request(ip, subnet, i % 4096, "US");
+        }
+
+        let stats = memory.tracking_stats();
+        assert!(stats.ips.tracked <= 10_000, "{:?}", stats.ips);
+        assert!(stats.subnets.tracked <= 1_000, "{:?}", stats.subnets);
+        assert!(stats.asns.tracked <= 100, "{:?}", stats.asns);
+        assert_eq!(stats.countries.tracked, 1);
+        assert!(stats.ips.overflowed > 900_000);
+    }
+
+    #[test]
+    fn test_untracked_ip_is_limited_by_subnet() {
+        let memory = MemoryStore::new();
+        let mut config = defaults::default_protection_config();
+        config.max_tracked_ips = 1;
+        memory.set_tracking_limits(&config);
+
+        let first = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let flooder = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let subnet = ip_to_subnet(flooder, 24, 64);
+        memory.record_request(first, subnet, 0, "US");
+        for _ in 0..20 {
+            memory.record_request(flooder, subnet, 0, "US");
+        }
+
+        let limits = RateLimitConfig {
+            ip_per_second: 1,
+            subnet_per_second: 10,
+            asn_per_second: u64::MAX,
+            country_per_second: u64::MAX,
+        };
+        let reason = memory.check_rate_limit(flooder, subnet, 0, "US", &limits).unwrap();
+        assert!(reason.starts_with("Subnet"), "{}", reason);
+        assert_eq!(memory.tracking_stats().ips.overflowed, 20);
+    }
+
+    #[test]
+    fn test_ipv6_subnets_are_isolated_from_each_other_and_ipv4() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let key = |s: &str| ip_to_subnet(ip(s), 24, 64);
+
+        assert_eq!(key("2001:db8:0:1::1"), key("2001:db8:0:1:ffff::2"));
+        assert_ne!(key("2001:db8:0:1::1"), key("2001:db8:0:2::1"));
+        assert_ne!(key("2001:db8::1"), key("2001:db9::1"));
+        assert_eq!(key("192.0.2.1"), key("192.0.2.200"));
+        assert_ne!(key("192.0.2.1"), key("192.0.3.1"));
+        // IPv4 keys sit in the mapped range, apart from any native IPv6 prefix.
+        assert_ne!(key("0.0.0.1"), key("::1"));
+        assert_eq!(ip_to_subnet(ip("2001:db8:0:1::1"), 24, 56), ip_to_subnet(ip("2001:db8:0:ff::1"), 24, 56));
+        assert_eq!(subnet_network(ip("2001:db8:0:1::9"), 24, 64).to_string(), "2001:db8:0:1::/64");
+        assert_eq!(subnet_network(ip("192.0.2.9"), 24, 64).to_string(), "192.0.2.0/24");
+
+        // An abusive IPv6 /64 trips its own subnet limit only.
+        let memory = MemoryStore::new();
+        let limits = RateLimitConfig {
+            ip_per_second: u64::MAX,
+            subnet_per_second: 10,
+            asn_per_second: u64::MAX,
+            country_per_second: u64::MAX,
+        };
+        for i in 0..20u16 {
+            let rotating = IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, i));
+            memory.record_request(rotating, key("2001:db8:0:1::"), 0, "US");
+        }
+        let neighbours = ["2001:db8:0:2::1", "2001:db8:1:1::1", "192.0.2.1"];
+        for client in neighbours {
+            memory.record_request(ip(client), key(client), 0, "US");
+        }
+
+        let flooder = ip("2001:db8:0:1::99");
+        let reason = memory.check_rate_limit(flooder, key("2001:db8:0:1::99"), 0, "US", &limits).unwrap();
+        assert!(reason.starts_with("Subnet"), "{}", reason);
+        for client in neighbours {
+            assert_eq!(memory.check_rate_limit(ip(client), key(client), 0, "US", &limits), None, "{}", client);
+        }
+    }
+
+    /// A bot hammering one path at a fixed ~100 rps from a changing UA.
+    fn bot_profile() -> (BehaviorProfile, Instant) {
+        let now = Instant::now();
+        let mut profile = BehaviorProfile::new();
+        profile.first_seen = now - Duration::from_secs(2);
+        profile.total_requests = 200;
+        profile.request_intervals = std::iter::repeat_n(Duration::from_millis(10), 100).collect();
+        profile.paths_visited.insert(hash_path("/login"));
+        profile.consistency_violations = 1;
+        (profile, now)
+    }
+
+    #[test]
+    fn test_behavior_weights_change_the_score() {
+        let (profile, now) = bot_profile();
+        let mut config = defaults::default_behavioral_config();
+        // 0.15 regularity + 0.3 rate + 0.1 paths + 0.1 UA change
+        let baseline = profile.suspicion(now, &config);
+        assert!((baseline - 0.65).abs() < 1e-9, "{}", baseline);
+
+        config.rate_weight = 0.0;
+        assert!((profile.suspicion(now, &config) - 0.35).abs() < 1e-9);
+        config.regularity_weight = 2.0;
+        assert!((profile.suspicion(now, &config) - 0.8).abs() < 1e-9);
+
+        // Raised thresholds make the same traffic unremarkable
+        let mut config = defaults::default_behavioral_config();
+        config.high_rps = 1_000.0;
+        config.elevated_rps = 500.0;
+        config.path_diversity_min_requests = 1_000;
+        assert!((profile.suspicion(now, &config) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_max_profiles_evicts_least_recently_seen() {
+        let memory = MemoryStore::new();
+        let mut config = defaults::default_behavioral_config();
+        config.max_profiles = 3;
+        memory.set_behavior_config(&config);
+
+        let ip = |n: u8| IpAddr::V4(Ipv4Addr::new(192, 0, 2, n));
+        for n in 1..=3 {
+            memory.update_behavior(ip(n), "/", "GET", None, None);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        // Seeing the first client again makes the second the oldest
+        memory.update_behavior(ip(1), "/", "GET", None, None);
+        memory.update_behavior(ip(4), "/", "GET", None, None);
+
+        assert!(memory.behavior_profiles.contains_key(&ip(1)));
+        assert!(!memory.behavior_profiles.contains_key(&ip(2)));
+        assert!(memory.behavior_profiles.contains_key(&ip(4)));
+        let stats = memory.tracking_stats().behavior_profiles;
+        assert_eq!((stats.tracked, stats.max, stats.overflowed), (3, 3, 1));
+    }
+}
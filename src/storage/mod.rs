@@ -1,3 +1,7 @@
 pub mod memory;
+pub mod sweep;
 pub mod sqlite;
 pub mod blocklist;
+pub mod ip_ranges;
+pub mod feeds;
+pub mod cluster;
@@ -1,8 +1,16 @@
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection, Result};
+use ipnet::IpNet;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, Result};
+use tokio::sync::oneshot;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::models::schedule::ScheduleFields;
 
 // ---------------------------------------------------------------------------
 // Row structs
@@ -13,10 +21,44 @@ pub struct BlockedIpRow {
     pub id: i64,
     pub ip: String,
     pub cidr: Option<String>,
+    /// `block`, `challenge` or `tarpit`.
+    pub action: String,
     pub reason: String,
     pub source: String,
     pub created_at: String,
     pub expires_at: Option<String>,
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
+    #[serde(flatten)]
+    pub geo: BlockedIpGeo,
+}
+
+/// Country and ASN of a blocked IP, or of a range's network address,
+/// resolved when it was added.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockedIpGeo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// A blocked IP/CIDR to insert with [`SqliteStore::add_blocked_ips`].
+#[derive(Debug, Clone)]
+pub struct NewBlockedIp {
+    pub ip: String,
+    pub cidr: Option<String>,
+    pub reason: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub geo: BlockedIpGeo,
+}
+
+/// One entry of a mixed batch written with
+/// [`SqliteStore::add_blocklist_entries`]. Values are already normalised.
+#[derive(Debug, Clone)]
+pub enum NewBlocklistEntry {
+    Ip(NewBlockedIp),
+    Asn { asn: u32, reason: String },
+    Country { code: String, reason: String },
+    Ja3 { hash: String, reason: String, expires_at: Option<DateTime<Utc>> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +69,24 @@ pub struct BlockedAsnRow {
     pub action: String,
     pub reason: Option<String>,
     pub created_at: String,
+    /// `config`, `admin_api` or `auto_escalation`.
+    pub source: String,
+    pub expires_at: Option<String>,
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
+}
+
+/// A JA3 fingerprint on the block-, challenge- or allowlist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockedJa3Row {
+    pub id: i64,
+    pub ja3: String,
+    pub action: String,
+    pub reason: Option<String>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +97,10 @@ pub struct BlockedCountryRow {
     pub action: String,
     pub reason: Option<String>,
     pub created_at: String,
+    pub source: String,
+    pub expires_at: Option<String>,
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,7 +111,19 @@ pub struct RuleRow {
     pub conditions_json: String,
     pub action: String,
     pub enabled: bool,
+    pub log_only: bool,
     pub created_at: String,
+    /// Only enforced inside this UTC window, if set.
+    #[serde(flatten)]
+    pub schedule: ScheduleFields,
+}
+
+/// Saved enabled flag and parameter overrides of a managed rule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagedRuleSettingRow {
+    pub rule_id: u32,
+    pub enabled: bool,
+    pub params_json: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,6 +138,96 @@ pub struct MetricsRow {
     pub protection_level: u8,
     pub top_countries_json: Option<String>,
     pub top_asns_json: Option<String>,
+    /// `None` for the row covering all traffic.
+    #[serde(default)]
+    pub service_id: Option<String>,
+    /// Challenge pages served and challenges solved in the hour.
+    #[serde(default)]
+    pub challenges_issued: u64,
+    #[serde(default)]
+    pub challenges_solved: u64,
+    /// The share of `passed_requests` let through without inspection.
+    #[serde(default)]
+    pub bypassed_static: u64,
+    #[serde(default)]
+    pub bypassed_preflight: u64,
+    #[serde(default)]
+    pub whitelisted: u64,
+}
+
+/// Request counts for one minute; `timestamp` is the start of the minute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinuteMetricsRow {
+    pub timestamp: String,
+    pub total_requests: u64,
+    pub passed_requests: u64,
+    pub blocked_requests: u64,
+    pub challenged_requests: u64,
+    /// The share of `passed_requests` let through without inspection.
+    #[serde(default)]
+    pub bypassed_static: u64,
+    #[serde(default)]
+    pub bypassed_preflight: u64,
+    #[serde(default)]
+    pub whitelisted: u64,
+}
+
+/// One request recorded by the request sampler.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestSampleRow {
+    pub timestamp: String,
+    pub client_ip: String,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub action: String,
+    pub status: u16,
+    pub service_id: Option<String>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// A client API token. `allowed_hosts` and `allowed_paths` are JSON arrays.
+#[derive(Debug, Clone)]
+pub struct ApiTokenRow {
+    pub id: String,
+    pub name: String,
+    pub secret: Vec<u8>,
+    pub allowed_hosts: String,
+    pub allowed_paths: String,
+    pub action: String,
+    pub exempt_rate_limit: bool,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+    pub usage_count: u64,
+    pub last_used_at: Option<String>,
+}
+
+/// A per-IP or per-CIDR policy, `network` being the canonical CIDR.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpPolicyRow {
+    pub id: i64,
+    pub network: String,
+    pub policy: String,
+    pub reason: Option<String>,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+/// A manual ASN classification, taking precedence over the ASN dataset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AsnOverrideRow {
+    pub asn: u32,
+    pub category: String,
+    pub reason: Option<String>,
+    pub created_at: String,
+}
+
+/// Number of sampled requests sharing one value of a column.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleCountRow {
+    pub value: String,
+    pub count: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -88,10 +254,47 @@ pub struct ServiceRow {
     pub protection_level_override: Option<i32>,
     pub always_challenge: bool,
     pub rate_limit_multiplier: f64,
+    pub max_requests_per_ip_10s: Option<i64>,
     pub max_connections: i64,
     pub connect_timeout_ms: i64,
     pub response_timeout_ms: i64,
     pub exempt_paths: Option<String>,
+    pub lb_strategy: String,
+    pub upstream_tls_verify: bool,
+    pub upstream_sni_host: Option<String>,
+    pub upstream_http2: bool,
+    /// Header rules, each a JSON object (`add_*`) or array (`remove_*`).
+    pub add_request_headers: Option<String>,
+    pub remove_request_headers: Option<String>,
+    pub add_response_headers: Option<String>,
+    pub remove_response_headers: Option<String>,
+    /// JSON arrays; NULL means no service allowlist.
+    pub allowed_countries: Option<String>,
+    pub allowed_asns: Option<String>,
+    pub clearance_cookie_domain: Option<String>,
+    pub clearance_ttl_secs: Option<i64>,
+    /// JSON array of origins whose CORS preflights Fortress answers.
+    pub cors_allowed_origins: Option<String>,
+    pub maintenance_mode: bool,
+    pub maintenance_html_path: Option<String>,
+    pub body_inspection: bool,
+    /// JSON arrays of country codes; NULL means none.
+    pub blocked_countries: Option<String>,
+    pub challenged_countries: Option<String>,
+    pub country_exceptions: Option<String>,
+    /// NULL uses `server.max_body_size_mb`; 0 means unlimited.
+    pub max_body_size_mb: Option<i64>,
+    pub request_encoding: String,
+    /// JSON array of path/host routes to other upstreams; NULL means none.
+    pub routes: Option<String>,
+    /// NULL uses `protection.credential_stuffing.username_field`.
+    pub login_username_field: Option<String>,
+    pub always_online: bool,
+    pub always_online_banner: bool,
+    /// JSON waiting room settings; NULL means no waiting room.
+    pub waiting_room: Option<String>,
+    /// JSON health check settings; NULL uses `upstream.health_check`.
+    pub health_check: Option<String>,
     pub created_at: String,
     pub updated_at: String,
 }
@@ -107,12 +310,406 @@ pub struct L4EventRow {
     pub connection_rate: Option<i64>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRow {
+    pub id: i64,
+    pub timestamp: String,
+    pub actor: String,
+    pub action: String,
+    pub target_type: String,
+    pub target: String,
+    pub reason: Option<String>,
+}
+
+/// Filters for [`SqliteStore::get_audit`]. `from`/`to` bound the entry
+/// timestamp inclusively; string filters match exactly.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target: Option<String>,
+}
+
+/// Address search for blocklist and L4 event listings.
+#[derive(Debug, Clone)]
+pub enum IpFilter {
+    /// Entries whose address (a range's network address) is inside.
+    Range(IpNet),
+    /// Entries whose address text starts with this, e.g. `10.1.`.
+    Prefix(String),
+}
+
+impl IpFilter {
+    /// An address or CIDR is a range; anything else is a text prefix.
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim();
+        if let Ok(net) = value.parse::<IpNet>() {
+            Self::Range(net.trunc())
+        } else if let Ok(ip) = value.parse::<IpAddr>() {
+            Self::Range(IpNet::from(ip))
+        } else {
+            Self::Prefix(value.to_string())
+        }
+    }
+}
+
+/// Whether a blocklist entry's `expires_at` has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryStatus {
+    Active,
+    Expired,
+}
+
+/// Filters for the paged blocklist listings. `reason` matches a substring;
+/// `from`/`to` bound `created_at` inclusively. `ip` only applies to IPs and
+/// `allow` (allowlist vs blocklist) only to ASNs and countries.
+#[derive(Debug, Clone, Default)]
+pub struct BlocklistFilter {
+    pub ip: Option<IpFilter>,
+    pub source: Option<String>,
+    pub reason: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub status: Option<ExpiryStatus>,
+    pub allow: Option<bool>,
+    /// Blocked IPs only: stored country code and ASN.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// Filters for [`SqliteStore::get_l4_events_page`]. `ip` matches the client
+/// IP exactly or as a text prefix; CIDR ranges are not supported here.
+#[derive(Debug, Clone, Default)]
+pub struct L4EventFilter {
+    pub ip: Option<String>,
+    pub action: Option<String>,
+    pub reason: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Sortable text key of an address, or of a range's network address: the
+/// 16 bytes of its IPv6 form (IPv4 mapped) in hex, so a CIDR search is a
+/// range scan on an index.
+fn ip_key(ip: &str) -> Option<String> {
+    let addr = match ip.parse::<IpNet>() {
+        Ok(net) => net.network(),
+        Err(_) => ip.parse::<IpAddr>().ok()?,
+    };
+    Some(addr_key(addr))
+}
+
+fn addr_key(addr: IpAddr) -> String {
+    let v6 = match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    v6.octets().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `column >= prefix AND column < next` lets a text prefix search use the
+/// column's index, unlike `LIKE`.
+fn push_prefix(clauses: &mut Vec<&'static str>, values: &mut Vec<String>, clause: &'static str, prefix: &str) {
+    let mut upper = prefix.to_string();
+    if let Some(last) = upper.pop() {
+        upper.push(char::from_u32(last as u32 + 1).unwrap_or(char::MAX));
+        clauses.push(clause);
+        values.push(prefix.to_string());
+        values.push(upper);
+    }
+}
+
+/// `LIKE` pattern matching `needle` anywhere, with wildcards escaped.
+fn contains_pattern(needle: &str) -> String {
+    let escaped = needle.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// `WHERE` clause and values for `filter` over a blocklist table.
+fn blocklist_where(filter: &BlocklistFilter) -> (String, Vec<String>) {
+    let mut clauses: Vec<&str> = Vec::new();
+    let mut values: Vec<String> = Vec::new();
+    match &filter.ip {
+        Some(IpFilter::Range(net)) => {
+            clauses.push("ip_key BETWEEN ? AND ?");
+            values.push(addr_key(net.network()));
+            values.push(addr_key(net.broadcast()));
+        }
+        Some(IpFilter::Prefix(prefix)) => push_prefix(&mut clauses, &mut values, "ip >= ? AND ip < ?", prefix),
+        None => {}
+    }
+    if let Some(ref source) = filter.source {
+        clauses.push("source = ?");
+        values.push(source.clone());
+    }
+    if let Some(ref reason) = filter.reason {
+        clauses.push("reason LIKE ? ESCAPE '\\'");
+        values.push(contains_pattern(reason));
+    }
+    if let Some(from) = filter.from {
+        clauses.push("created_at >= ?");
+        values.push(from.format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    if let Some(to) = filter.to {
+        clauses.push("created_at <= ?");
+        values.push(to.format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    match filter.status {
+        Some(ExpiryStatus::Active) => clauses.push("(expires_at IS NULL OR expires_at > datetime('now'))"),
+        Some(ExpiryStatus::Expired) => clauses.push("expires_at <= datetime('now')"),
+        None => {}
+    }
+    match filter.allow {
+        Some(true) => clauses.push("action = 'allow'"),
+        Some(false) => clauses.push("action != 'allow'"),
+        None => {}
+    }
+    if let Some(ref country) = filter.country {
+        clauses.push("country = ?");
+        values.push(country.clone());
+    }
+    if let Some(asn) = filter.asn {
+        clauses.push("asn = ?");
+        values.push(asn.to_string());
+    }
+    (where_sql(&clauses), values)
+}
+
+fn where_sql(clauses: &[&str]) -> String {
+    if clauses.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", clauses.join(" AND "))
+    }
+}
+
+/// Page through `table` with `select`ed columns, newest first. Returns the
+/// page and the total number of matching rows.
+fn query_page<T>(
+    conn: &Connection,
+    select: &str,
+    table: &str,
+    (where_sql, values): &(String, Vec<String>),
+    limit: usize,
+    offset: usize,
+    map: impl FnMut(&rusqlite::Row<'_>) -> Result<T>,
+) -> Result<(Vec<T>, u64)> {
+    let total: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {} {}", table, where_sql),
+        params_from_iter(values.iter()),
+        |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} {} ORDER BY id DESC LIMIT {} OFFSET {}",
+        select, table, where_sql, limit, offset
+    ))?;
+    let rows = stmt.query_map(params_from_iter(values.iter()), map)?;
+    Ok((rows.collect::<Result<Vec<_>>>()?, total as u64))
+}
+
 // ---------------------------------------------------------------------------
 // SqliteStore
 // ---------------------------------------------------------------------------
 
+const BLOCKED_IP_COLUMNS: &str =
+    "id, ip, cidr, reason, source, created_at, expires_at, active_from, active_to, active_days, country, asn, action";
+
+const BLOCKED_ASN_COLUMNS: &str =
+    "id, asn, name, action, reason, created_at, source, expires_at, active_from, active_to, active_days";
+
+const BLOCKED_COUNTRY_COLUMNS: &str =
+    "id, country_code, country_name, action, reason, created_at, source, expires_at, active_from, active_to, active_days";
+
+const L4_EVENT_COLUMNS: &str =
+    "id, timestamp, client_ip, action, reason, concurrent_connections, connection_rate";
+
+fn blocked_asn_from_row(row: &rusqlite::Row<'_>) -> Result<BlockedAsnRow> {
+    Ok(BlockedAsnRow {
+        id: row.get(0)?,
+        asn: row.get::<_, u32>(1)?,
+        name: row.get(2)?,
+        action: row.get(3)?,
+        reason: row.get(4)?,
+        created_at: row.get(5)?,
+        source: row.get(6)?,
+        expires_at: row.get(7)?,
+        schedule: schedule_from_row(row, 8)?,
+    })
+}
+
+fn blocked_country_from_row(row: &rusqlite::Row<'_>) -> Result<BlockedCountryRow> {
+    Ok(BlockedCountryRow {
+        id: row.get(0)?,
+        country_code: row.get(1)?,
+        country_name: row.get(2)?,
+        action: row.get(3)?,
+        reason: row.get(4)?,
+        created_at: row.get(5)?,
+        source: row.get(6)?,
+        expires_at: row.get(7)?,
+        schedule: schedule_from_row(row, 8)?,
+    })
+}
+
+fn l4_event_from_row(row: &rusqlite::Row<'_>) -> Result<L4EventRow> {
+    Ok(L4EventRow {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        client_ip: row.get(2)?,
+        action: row.get(3)?,
+        reason: row.get(4)?,
+        concurrent_connections: row.get(5)?,
+        connection_rate: row.get(6)?,
+    })
+}
+
+fn blocked_ip_from_row(row: &rusqlite::Row<'_>) -> Result<BlockedIpRow> {
+    Ok(BlockedIpRow {
+        id: row.get(0)?,
+        ip: row.get(1)?,
+        cidr: row.get(2)?,
+        reason: row.get(3)?,
+        source: row.get(4)?,
+        created_at: row.get(5)?,
+        expires_at: row.get(6)?,
+        schedule: schedule_from_row(row, 7)?,
+        geo: BlockedIpGeo { country: row.get(10)?, asn: row.get(11)? },
+        action: row.get(12)?,
+    })
+}
+
+/// Read `active_from`, `active_to`, `active_days` starting at column `first`.
+fn schedule_from_row(row: &rusqlite::Row<'_>, first: usize) -> Result<ScheduleFields> {
+    Ok(ScheduleFields {
+        active_from: row.get(first)?,
+        active_to: row.get(first + 1)?,
+        active_days: row.get(first + 2)?,
+    })
+}
+
+
+/// Number of idle read connections kept open.
+const READ_POOL_SIZE: usize = 4;
+
+/// A unit of work for the writer thread.
+type WriteJob = Box<dyn FnOnce(&mut Connection) + Send>;
+
+/// SQLite access that never does file I/O on an async worker thread.
+///
+/// All writes run on one dedicated `sqlite-writer` thread that owns the
+/// write connection and takes jobs from a channel, so writers never contend
+/// on a lock and a WAL checkpoint only stalls the writer thread. Reads run on
+/// the blocking pool against a small pool of read-only connections, which
+/// WAL lets proceed alongside the writer.
 pub struct SqliteStore {
-    conn: Mutex<Connection>,
+    writer: mpsc::Sender<WriteJob>,
+    readers: Arc<ReadPool>,
+}
+
+struct ReadPool {
+    path: String,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl ReadPool {
+    fn with<R>(&self, f: impl FnOnce(&Connection) -> Result<R>) -> Result<R> {
+        let pooled = self.idle.lock().expect("sqlite pool mutex poisoned").pop();
+        let conn = match pooled {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::open_with_flags(
+                    &self.path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY
+                        | OpenFlags::SQLITE_OPEN_URI
+                        | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?;
+                conn.busy_timeout(Duration::from_secs(5))?;
+                conn
+            }
+        };
+        let result = f(&conn);
+        let mut idle = self.idle.lock().expect("sqlite pool mutex poisoned");
+        if idle.len() < READ_POOL_SIZE {
+            idle.push(conn);
+        }
+        result
+    }
+}
+
+/// Error for a query that never got to run (or whose result was lost).
+fn unavailable(what: &str) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+        Some(format!("sqlite {} unavailable", what)),
+    )
+}
+
+/// Version of the [`SqliteStore::export_state`] document format.
+pub const STATE_SCHEMA_VERSION: u32 = 1;
+
+/// Tables in a state export, as `(section, table, key column)`. API tokens
+/// are left out: they are credentials, not configuration.
+pub const STATE_SECTIONS: [(&str, &str, &str); 8] = [
+    ("services", "services", "id"),
+    ("custom_rules", "protection_rules", "id"),
+    ("blocked_ips", "blocked_ips", "ip"),
+    ("blocked_asns", "blocked_asns", "asn"),
+    ("blocked_ja3", "blocked_ja3", "ja3"),
+    ("blocked_countries", "blocked_countries", "country_code"),
+    ("managed_rules", "managed_rule_settings", "rule_id"),
+    ("config", "config", "key"),
+];
+
+/// One table row, column name to value.
+pub type StateRow = serde_json::Map<String, serde_json::Value>;
+
+/// Rows per section of a state export.
+pub type StateSections = BTreeMap<String, Vec<StateRow>>;
+
+/// How [`SqliteStore::import_state`] treats rows already in the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    #[default]
+    Merge,
+    Replace,
+}
+
+fn table_columns(conn: &Connection, table: &str) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    columns.collect()
+}
+
+fn sql_to_json(value: rusqlite::types::Value, column: usize) -> Result<serde_json::Value> {
+    use rusqlite::types::{Type, Value};
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => i.into(),
+        Value::Real(f) => serde_json::Number::from_f64(f).map_or(serde_json::Value::Null, Into::into),
+        Value::Text(s) => s.into(),
+        Value::Blob(_) => return Err(rusqlite::Error::InvalidColumnType(column, "blob".into(), Type::Blob)),
+    })
+}
+
+fn json_to_sql(value: &serde_json::Value) -> Result<rusqlite::types::Value> {
+    use rusqlite::types::Value;
+    Ok(match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        other => {
+            return Err(rusqlite::Error::ToSqlConversionFailure(
+                format!("nested value {} is not a column value", other).into(),
+            ))
+        }
+    })
 }
 
 impl SqliteStore {
@@ -129,10 +726,17 @@ impl SqliteStore {
                 id          INTEGER PRIMARY KEY AUTOINCREMENT,
                 ip          TEXT NOT NULL,
                 cidr        TEXT,
+                ip_key      TEXT,
                 reason      TEXT NOT NULL,
                 source      TEXT NOT NULL DEFAULT 'auto',
                 created_at  TEXT DEFAULT (datetime('now')),
                 expires_at  TEXT,
+                active_from TEXT,
+                active_to   TEXT,
+                active_days TEXT,
+                country     TEXT,
+                asn         INTEGER,
+                action      TEXT NOT NULL DEFAULT 'block',
                 UNIQUE(ip)
             );
 
@@ -142,7 +746,24 @@ impl SqliteStore {
                 name        TEXT,
                 action      TEXT NOT NULL DEFAULT 'block',
                 reason      TEXT,
-                created_at  TEXT DEFAULT (datetime('now'))
+                created_at  TEXT DEFAULT (datetime('now')),
+                source      TEXT NOT NULL DEFAULT 'admin_api',
+                expires_at  TEXT,
+                active_from TEXT,
+                active_to   TEXT,
+                active_days TEXT
+            );
+
+            CREATE TABLE IF NOT EXISTS blocked_ja3 (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                ja3         TEXT NOT NULL UNIQUE,
+                action      TEXT NOT NULL DEFAULT 'block',
+                reason      TEXT,
+                created_at  TEXT DEFAULT (datetime('now')),
+                expires_at  TEXT,
+                active_from TEXT,
+                active_to   TEXT,
+                active_days TEXT
             );
 
             CREATE TABLE IF NOT EXISTS blocked_countries (
@@ -151,7 +772,12 @@ impl SqliteStore {
                 country_name  TEXT,
                 action        TEXT NOT NULL DEFAULT 'block',
                 reason        TEXT,
-                created_at    TEXT DEFAULT (datetime('now'))
+                created_at    TEXT DEFAULT (datetime('now')),
+                source        TEXT NOT NULL DEFAULT 'admin_api',
+                expires_at    TEXT,
+                active_from   TEXT,
+                active_to     TEXT,
+                active_days   TEXT
             );
 
             CREATE TABLE IF NOT EXISTS protection_rules (
@@ -161,8 +787,19 @@ impl SqliteStore {
                 conditions_json TEXT NOT NULL,
                 action          TEXT NOT NULL,
                 enabled         INTEGER NOT NULL DEFAULT 1,
+                log_only        INTEGER NOT NULL DEFAULT 0,
                 created_at      TEXT DEFAULT (datetime('now')),
-                updated_at      TEXT DEFAULT (datetime('now'))
+                updated_at      TEXT DEFAULT (datetime('now')),
+                active_from     TEXT,
+                active_to       TEXT,
+                active_days     TEXT
+            );
+
+            CREATE TABLE IF NOT EXISTS managed_rule_settings (
+                rule_id     INTEGER PRIMARY KEY,
+                enabled     INTEGER NOT NULL,
+                params_json TEXT NOT NULL DEFAULT '{}',
+                updated_at  TEXT DEFAULT (datetime('now'))
             );
 
             CREATE TABLE IF NOT EXISTS metrics_hourly (
@@ -177,7 +814,23 @@ impl SqliteStore {
                 protection_level    INTEGER DEFAULT 0,
                 top_countries_json  TEXT,
                 top_asns_json       TEXT,
-                UNIQUE(timestamp)
+                service_id          TEXT,
+                challenges_issued   INTEGER DEFAULT 0,
+                challenges_solved   INTEGER DEFAULT 0,
+                bypassed_static     INTEGER DEFAULT 0,
+                bypassed_preflight  INTEGER DEFAULT 0,
+                whitelisted         INTEGER DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS metrics_minutely (
+                timestamp           TEXT PRIMARY KEY,
+                total_requests      INTEGER DEFAULT 0,
+                passed_requests     INTEGER DEFAULT 0,
+                blocked_requests    INTEGER DEFAULT 0,
+                challenged_requests INTEGER DEFAULT 0,
+                bypassed_static     INTEGER DEFAULT 0,
+                bypassed_preflight  INTEGER DEFAULT 0,
+                whitelisted         INTEGER DEFAULT 0
             );
 
             CREATE TABLE IF NOT EXISTS attacks (
@@ -211,6 +864,12 @@ impl SqliteStore {
                 connect_timeout_ms      INTEGER NOT NULL DEFAULT 5000,
                 response_timeout_ms     INTEGER NOT NULL DEFAULT 60000,
                 exempt_paths            TEXT,
+                lb_strategy             TEXT NOT NULL DEFAULT 'round_robin',
+                request_encoding        TEXT NOT NULL DEFAULT 'forward',
+                routes                  TEXT,
+                login_username_field    TEXT,
+                waiting_room            TEXT,
+                health_check            TEXT,
                 created_at              TEXT DEFAULT (datetime('now')),
                 updated_at              TEXT DEFAULT (datetime('now'))
             );
@@ -224,6 +883,64 @@ impl SqliteStore {
                 concurrent_connections  INTEGER,
                 connection_rate         INTEGER
             );
+
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp               TEXT DEFAULT (datetime('now')),
+                actor                   TEXT NOT NULL,
+                action                  TEXT NOT NULL,
+                target_type             TEXT NOT NULL,
+                target                  TEXT NOT NULL,
+                reason                  TEXT
+            );
+            CREATE INDEX IF NOT EXISTS idx_audit_log_timestamp ON audit_log(timestamp);
+            CREATE INDEX IF NOT EXISTS idx_audit_log_target ON audit_log(target);
+
+            CREATE TABLE IF NOT EXISTS request_samples (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp   TEXT NOT NULL,
+                client_ip   TEXT NOT NULL,
+                method      TEXT NOT NULL,
+                host        TEXT NOT NULL,
+                path        TEXT NOT NULL,
+                action      TEXT NOT NULL,
+                status      INTEGER NOT NULL,
+                service_id  TEXT,
+                country     TEXT,
+                asn         INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS idx_request_samples_timestamp ON request_samples(timestamp);
+            CREATE INDEX IF NOT EXISTS idx_request_samples_ip ON request_samples(client_ip, timestamp);
+
+            CREATE TABLE IF NOT EXISTS api_tokens (
+                id                  TEXT PRIMARY KEY,
+                name                TEXT NOT NULL,
+                secret              BLOB NOT NULL,
+                allowed_hosts       TEXT NOT NULL DEFAULT '[]',
+                allowed_paths       TEXT NOT NULL DEFAULT '[]',
+                action              TEXT NOT NULL DEFAULT 'bypass_challenge',
+                exempt_rate_limit   INTEGER NOT NULL DEFAULT 0,
+                expires_at          TEXT,
+                created_at          TEXT DEFAULT (datetime('now')),
+                usage_count         INTEGER NOT NULL DEFAULT 0,
+                last_used_at        TEXT
+            );
+
+            CREATE TABLE IF NOT EXISTS ip_policies (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                network     TEXT NOT NULL UNIQUE,
+                policy      TEXT NOT NULL,
+                reason      TEXT,
+                expires_at  TEXT,
+                created_at  TEXT DEFAULT (datetime('now'))
+            );
+
+            CREATE TABLE IF NOT EXISTS asn_overrides (
+                asn         INTEGER PRIMARY KEY,
+                category    TEXT NOT NULL,
+                reason      TEXT,
+                created_at  TEXT DEFAULT (datetime('now'))
+            );
             ",
         )?;
 
@@ -231,168 +948,703 @@ impl SqliteStore {
         let _ = conn.execute_batch(
             "ALTER TABLE services ADD COLUMN always_challenge INTEGER NOT NULL DEFAULT 0;"
         );
+        // Migration: add lb_strategy column for multi-upstream services
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN lb_strategy TEXT NOT NULL DEFAULT 'round_robin';"
+        );
+        // Migration: add per-service absolute per-IP rate limit
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN max_requests_per_ip_10s INTEGER;"
+        );
+        // Migration: add TLS options for https:// upstreams
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN upstream_tls_verify INTEGER NOT NULL DEFAULT 1;"
+        );
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN upstream_sni_host TEXT;"
+        );
+        // Migration: add per-service header rewriting rules
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN add_request_headers TEXT;
+             ALTER TABLE services ADD COLUMN remove_request_headers TEXT;
+             ALTER TABLE services ADD COLUMN add_response_headers TEXT;
+             ALTER TABLE services ADD COLUMN remove_response_headers TEXT;"
+        );
+        // Migration: add per-service country/ASN allowlists
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN allowed_countries TEXT;
+             ALTER TABLE services ADD COLUMN allowed_asns TEXT;"
+        );
+        // Migration: add per-service clearance cookie scope and lifetime
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN clearance_cookie_domain TEXT;
+             ALTER TABLE services ADD COLUMN clearance_ttl_secs INTEGER;"
+        );
+        // Migration: add per-service CORS preflight origins
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN cors_allowed_origins TEXT;"
+        );
+        // Migration: add per-service maintenance mode
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN maintenance_mode INTEGER NOT NULL DEFAULT 0;
+             ALTER TABLE services ADD COLUMN maintenance_html_path TEXT;"
+        );
+        // Migration: add per-service request body inspection
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN body_inspection INTEGER NOT NULL DEFAULT 0;"
+        );
+        // Migration: add per-service country block/challenge lists
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN blocked_countries TEXT;
+             ALTER TABLE services ADD COLUMN challenged_countries TEXT;
+             ALTER TABLE services ADD COLUMN country_exceptions TEXT;"
+        );
+        // Migration: add per-service request body size limit
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN max_body_size_mb INTEGER;"
+        );
+        // Migration: add HTTP/2 to upstreams
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN upstream_http2 INTEGER NOT NULL DEFAULT 0;"
+        );
+        // Migration: add always-online stale pages
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN always_online INTEGER NOT NULL DEFAULT 0;
+             ALTER TABLE services ADD COLUMN always_online_banner INTEGER NOT NULL DEFAULT 0;"
+        );
+        // Migration: add per-service Content-Encoding policy for request bodies
+        let _ = conn.execute_batch(
+            "ALTER TABLE services ADD COLUMN request_encoding TEXT NOT NULL DEFAULT 'forward';"
+        );
+        // Migration: add per-service path/host routes
+        let _ = conn.execute_batch("ALTER TABLE services ADD COLUMN routes TEXT;");
+        // Migration: add per-service username field for credential stuffing
+        let _ = conn.execute_batch("ALTER TABLE services ADD COLUMN login_username_field TEXT;");
+        // Migration: add per-service waiting room
+        let _ = conn.execute_batch("ALTER TABLE services ADD COLUMN waiting_room TEXT;");
+        // Migration: add per-service health check settings
+        let _ = conn.execute_batch("ALTER TABLE services ADD COLUMN health_check TEXT;");
+        // Migration: add log_only (dry-run) column to protection rules
+        let _ = conn.execute_batch(
+            "ALTER TABLE protection_rules ADD COLUMN log_only INTEGER NOT NULL DEFAULT 0;"
+        );
+        // Migration: add UTC schedules to rules and blocklist entries
+        for table in ["protection_rules", "blocked_ips", "blocked_asns", "blocked_countries", "blocked_ja3"] {
+            let _ = conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN active_from TEXT;
+                 ALTER TABLE {table} ADD COLUMN active_to TEXT;
+                 ALTER TABLE {table} ADD COLUMN active_days TEXT;"
+            ));
+        }
+        // Migration: add source and expiry to ASN/country entries
+        for table in ["blocked_asns", "blocked_countries"] {
+            let _ = conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN source TEXT NOT NULL DEFAULT 'admin_api';
+                 ALTER TABLE {table} ADD COLUMN expires_at TEXT;"
+            ));
+        }
+        // Migration: per-service hourly rows. The hour alone was unique, so
+        // the table is rebuilt rather than altered.
+        if conn.prepare("SELECT service_id FROM metrics_hourly LIMIT 0").is_err() {
+            conn.execute_batch(
+                "BEGIN;
+                 ALTER TABLE metrics_hourly RENAME TO metrics_hourly_old;
+                 CREATE TABLE metrics_hourly (
+                     id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+                     timestamp           TEXT NOT NULL,
+                     total_requests      INTEGER DEFAULT 0,
+                     passed_requests     INTEGER DEFAULT 0,
+                     blocked_requests    INTEGER DEFAULT 0,
+                     challenged_requests INTEGER DEFAULT 0,
+                     unique_ips          INTEGER DEFAULT 0,
+                     avg_latency_ms      REAL    DEFAULT 0,
+                     protection_level    INTEGER DEFAULT 0,
+                     top_countries_json  TEXT,
+                     top_asns_json       TEXT,
+                     service_id          TEXT
+                 );
+                 INSERT INTO metrics_hourly
+                     (timestamp, total_requests, passed_requests, blocked_requests, challenged_requests,
+                      unique_ips, avg_latency_ms, protection_level, top_countries_json, top_asns_json)
+                 SELECT timestamp, total_requests, passed_requests, blocked_requests, challenged_requests,
+                        unique_ips, avg_latency_ms, protection_level, top_countries_json, top_asns_json
+                 FROM metrics_hourly_old;
+                 DROP TABLE metrics_hourly_old;
+                 COMMIT;",
+            )?;
+        }
+        conn.execute_batch(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_metrics_hourly_slot
+             ON metrics_hourly(timestamp, IFNULL(service_id, ''));",
+        )?;
+        // Migration: challenge counts in the hourly rows
+        let _ = conn.execute_batch(
+            "ALTER TABLE metrics_hourly ADD COLUMN challenges_issued INTEGER DEFAULT 0;
+             ALTER TABLE metrics_hourly ADD COLUMN challenges_solved INTEGER DEFAULT 0;",
+        );
+        // Migration: bypassed request counts in the hourly and minute rows
+        for table in ["metrics_hourly", "metrics_minutely"] {
+            let _ = conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN bypassed_static INTEGER DEFAULT 0;
+                 ALTER TABLE {table} ADD COLUMN bypassed_preflight INTEGER DEFAULT 0;
+                 ALTER TABLE {table} ADD COLUMN whitelisted INTEGER DEFAULT 0;",
+            ));
+        }
+        // Migration: sortable address keys for CIDR searches of blocked IPs
+        if conn.execute_batch("ALTER TABLE blocked_ips ADD COLUMN ip_key TEXT;").is_ok() {
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut select = tx.prepare("SELECT id, ip FROM blocked_ips")?;
+                let mut update = tx.prepare("UPDATE blocked_ips SET ip_key = ?1 WHERE id = ?2")?;
+                let rows = select.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+                for row in rows {
+                    let (id, ip) = row?;
+                    update.execute(params![ip_key(&ip), id])?;
+                }
+            }
+            tx.commit()?;
+        }
+        // Migration: country and ASN of blocked IPs, for filtering
+        let _ = conn.execute_batch(
+            "ALTER TABLE blocked_ips ADD COLUMN country TEXT;
+             ALTER TABLE blocked_ips ADD COLUMN asn INTEGER;",
+        );
+        // Migration: actions on blocked IPs. Unknown actions on the other
+        // lists were always treated as a block, so store them as one
+        let _ = conn.execute_batch("ALTER TABLE blocked_ips ADD COLUMN action TEXT NOT NULL DEFAULT 'block';");
+        for table in ["blocked_asns", "blocked_countries", "blocked_ja3"] {
+            conn.execute_batch(&format!(
+                "UPDATE {table} SET action = 'block'
+                 WHERE action NOT IN ('block', 'challenge', 'tarpit', 'allow');"
+            ))?;
+        }
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_blocked_ips_ip_key ON blocked_ips(ip_key);
+             CREATE INDEX IF NOT EXISTS idx_blocked_ips_country ON blocked_ips(country);
+             CREATE INDEX IF NOT EXISTS idx_blocked_ips_asn ON blocked_ips(asn);
+             CREATE INDEX IF NOT EXISTS idx_blocked_ips_source ON blocked_ips(source);
+             CREATE INDEX IF NOT EXISTS idx_blocked_ips_created_at ON blocked_ips(created_at);
+             CREATE INDEX IF NOT EXISTS idx_blocked_ips_expires_at ON blocked_ips(expires_at);
+             CREATE INDEX IF NOT EXISTS idx_l4_events_timestamp ON l4_events(timestamp);
+             CREATE INDEX IF NOT EXISTS idx_l4_events_client_ip ON l4_events(client_ip);",
+        )?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        let (writer, jobs) = mpsc::channel::<WriteJob>();
+        std::thread::Builder::new()
+            .name("sqlite-writer".to_string())
+            .spawn(move || {
+                let mut conn = conn;
+                for job in jobs {
+                    job(&mut conn);
+                }
+            })
+            .expect("failed to spawn sqlite writer thread");
 
         Ok(Self {
-            conn: Mutex::new(conn),
+            writer,
+            readers: Arc::new(ReadPool {
+                path: path.to_string(),
+                idle: Mutex::new(Vec::new()),
+            }),
         })
     }
 
+    /// Run `f` on the writer thread and wait for its result.
+    async fn write<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.submit(move |conn| {
+            let _ = tx.send(f(conn));
+        })?;
+        rx.await.map_err(|_| unavailable("writer"))?
+    }
+
+    /// Queue `f` on the writer thread without waiting for it.
+    fn submit(&self, f: impl FnOnce(&mut Connection) + Send + 'static) -> Result<()> {
+        self.writer.send(Box::new(f)).map_err(|_| unavailable("writer"))
+    }
+
+    /// Run `f` against a pooled read connection on the blocking pool.
+    async fn read<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Connection) -> Result<R> + Send + 'static,
+    {
+        let readers = Arc::clone(&self.readers);
+        tokio::task::spawn_blocking(move || readers.with(f))
+            .await
+            .map_err(|_| unavailable("reader"))?
+    }
+
+    /// Wait until every write queued so far has been applied.
+    pub async fn flush(&self) {
+        let _ = self.write(|_| Ok(())).await;
+    }
+
     // -----------------------------------------------------------------------
     // Blocked IPs
     // -----------------------------------------------------------------------
 
-    pub fn add_blocked_ip(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_blocked_ip(
         &self,
         ip: &str,
         cidr: Option<&str>,
+        action: &str,
         reason: &str,
         source: &str,
         expires_at: Option<DateTime<Utc>>,
+        schedule: &ScheduleFields,
+        geo: &BlockedIpGeo,
     ) -> Result<i64> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
+        let (ip, cidr, action, reason, source) =
+            (ip.to_string(), cidr.map(str::to_string), action.to_string(), reason.to_string(), source.to_string());
         let expires_str = expires_at.map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
-        conn.execute(
-            "INSERT OR REPLACE INTO blocked_ips (ip, cidr, reason, source, expires_at)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![ip, cidr, reason, source, expires_str],
-        )?;
-        Ok(conn.last_insert_rowid())
+        let s = schedule.clone();
+        let geo = geo.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO blocked_ips
+                     (ip, cidr, reason, source, expires_at, active_from, active_to, active_days, ip_key,
+                      country, asn, action)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    ip, cidr, reason, source, expires_str, s.active_from, s.active_to, s.active_days,
+                    ip_key(&ip), geo.country, geo.asn, action
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
     }
 
-    pub fn remove_blocked_ip(&self, id: i64) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute("DELETE FROM blocked_ips WHERE id = ?1", params![id])?;
-        Ok(())
+    pub async fn remove_blocked_ip(&self, id: i64) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute("DELETE FROM blocked_ips WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_blocked_ips(&self) -> Result<Vec<BlockedIpRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare(
-            "SELECT id, ip, cidr, reason, source, created_at, expires_at FROM blocked_ips",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(BlockedIpRow {
-                id: row.get(0)?,
-                ip: row.get(1)?,
-                cidr: row.get(2)?,
-                reason: row.get(3)?,
-                source: row.get(4)?,
-                created_at: row.get(5)?,
-                expires_at: row.get(6)?,
-            })
-        })?;
-        rows.collect()
+    pub async fn get_blocked_ips(&self) -> Result<Vec<BlockedIpRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM blocked_ips", BLOCKED_IP_COLUMNS))?;
+            let rows = stmt.query_map([], blocked_ip_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Page through blocked IPs and ranges, newest first, with the total
+    /// number of matching rows.
+    pub async fn get_blocked_ips_page(
+        &self,
+        filter: &BlocklistFilter,
+        limit: usize,
+        offset: usize,
+    ) -> Result<(Vec<BlockedIpRow>, u64)> {
+        let clause = blocklist_where(filter);
+        self.read(move |conn| {
+            query_page(conn, BLOCKED_IP_COLUMNS, "blocked_ips", &clause, limit, offset, blocked_ip_from_row)
+        })
+        .await
+    }
+
+    pub async fn get_blocked_ips_by_source(&self, source: &str) -> Result<Vec<BlockedIpRow>> {
+        let source = source.to_string();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM blocked_ips WHERE source = ?1", BLOCKED_IP_COLUMNS))?;
+            let rows = stmt.query_map(params![source], blocked_ip_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Insert many blocked IPs in one transaction. With `overwrite` an
+    /// existing row for the same IP is replaced; without it the existing row
+    /// is kept. Returns the indices of `entries` that were written.
+    pub async fn add_blocked_ips(
+        &self,
+        entries: &[NewBlockedIp],
+        source: &str,
+        overwrite: bool,
+    ) -> Result<Vec<usize>> {
+        let entries = entries.to_vec();
+        let source = source.to_string();
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let mut written = Vec::with_capacity(entries.len());
+            {
+                let sql = if overwrite {
+                    "INSERT OR REPLACE INTO blocked_ips (ip, cidr, reason, source, expires_at, ip_key, country, asn)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+                } else {
+                    "INSERT OR IGNORE INTO blocked_ips (ip, cidr, reason, source, expires_at, ip_key, country, asn)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+                };
+                let mut stmt = tx.prepare(sql)?;
+                for (i, entry) in entries.iter().enumerate() {
+                    let expires_str = entry
+                        .expires_at
+                        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+                    let changed = stmt.execute(params![
+                        entry.ip,
+                        entry.cidr,
+                        entry.reason,
+                        source,
+                        expires_str,
+                        ip_key(&entry.ip),
+                        entry.geo.country,
+                        entry.geo.asn
+                    ])?;
+                    if changed > 0 {
+                        written.push(i);
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(written)
+        })
+        .await
+    }
+
+    /// `(id, ip)` of the blocked IPs missing a country or ASN.
+    pub async fn get_blocked_ips_without_geo(&self) -> Result<Vec<(i64, String)>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare("SELECT id, ip FROM blocked_ips WHERE country IS NULL OR asn IS NULL")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Store the country and ASN of many blocked IPs, by row ID.
+    pub async fn set_blocked_ip_geo(&self, updates: Vec<(i64, BlockedIpGeo)>) -> Result<()> {
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare("UPDATE blocked_ips SET country = ?1, asn = ?2 WHERE id = ?3")?;
+                for (id, geo) in &updates {
+                    stmt.execute(params![geo.country, geo.asn, id])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    /// Delete many blocked-IP rows by ID in one transaction.
+    pub async fn remove_blocked_ips(&self, ids: &[i64]) -> Result<usize> {
+        let ids = ids.to_vec();
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let mut removed = 0;
+            {
+                let mut stmt = tx.prepare("DELETE FROM blocked_ips WHERE id = ?1")?;
+                for id in &ids {
+                    removed += stmt.execute(params![id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(removed)
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // Blocked ASNs
     // -----------------------------------------------------------------------
 
-    pub fn add_blocked_asn(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_blocked_asn(
         &self,
         asn: u32,
         name: Option<&str>,
         action: &str,
         reason: Option<&str>,
+        source: &str,
+        expires_at: Option<DateTime<Utc>>,
+        schedule: &ScheduleFields,
     ) -> Result<i64> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "INSERT OR REPLACE INTO blocked_asns (asn, name, action, reason)
-             VALUES (?1, ?2, ?3, ?4)",
-            params![asn, name, action, reason],
-        )?;
-        Ok(conn.last_insert_rowid())
+        let (name, action, reason, source) = (
+            name.map(str::to_string),
+            action.to_string(),
+            reason.map(str::to_string),
+            source.to_string(),
+        );
+        let expires_str = expires_at.map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+        let s = schedule.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO blocked_asns
+                     (asn, name, action, reason, source, expires_at, active_from, active_to, active_days)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    asn, name, action, reason, source, expires_str,
+                    s.active_from, s.active_to, s.active_days
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
     }
 
-    pub fn remove_blocked_asn(&self, id: i64) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute("DELETE FROM blocked_asns WHERE id = ?1", params![id])?;
-        Ok(())
+    pub async fn remove_blocked_asn(&self, id: i64) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute("DELETE FROM blocked_asns WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_blocked_asns(&self) -> Result<Vec<BlockedAsnRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt =
-            conn.prepare("SELECT id, asn, name, action, reason, created_at FROM blocked_asns")?;
-        let rows = stmt.query_map([], |row| {
-            Ok(BlockedAsnRow {
-                id: row.get(0)?,
-                asn: row.get::<_, u32>(1)?,
-                name: row.get(2)?,
-                action: row.get(3)?,
-                reason: row.get(4)?,
-                created_at: row.get(5)?,
-            })
-        })?;
-        rows.collect()
+    pub async fn get_blocked_asns(&self) -> Result<Vec<BlockedAsnRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM blocked_asns", BLOCKED_ASN_COLUMNS))?;
+            let rows = stmt.query_map([], blocked_asn_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Page through blocked (or allowed) ASNs, newest first, with the total
+    /// number of matching rows.
+    pub async fn get_blocked_asns_page(
+        &self,
+        filter: &BlocklistFilter,
+        limit: usize,
+        offset: usize,
+    ) -> Result<(Vec<BlockedAsnRow>, u64)> {
+        let clause = blocklist_where(filter);
+        self.read(move |conn| {
+            query_page(conn, BLOCKED_ASN_COLUMNS, "blocked_asns", &clause, limit, offset, blocked_asn_from_row)
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // Blocked JA3 fingerprints
+    // -----------------------------------------------------------------------
+
+    pub async fn add_blocked_ja3(
+        &self,
+        ja3: &str,
+        action: &str,
+        reason: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        schedule: &ScheduleFields,
+    ) -> Result<i64> {
+        let (ja3, action, reason) = (ja3.to_string(), action.to_string(), reason.map(str::to_string));
+        let expires_str = expires_at.map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+        let s = schedule.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO blocked_ja3
+                     (ja3, action, reason, expires_at, active_from, active_to, active_days)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![ja3, action, reason, expires_str, s.active_from, s.active_to, s.active_days],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    pub async fn remove_blocked_ja3(&self, id: i64) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute("DELETE FROM blocked_ja3 WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn get_blocked_ja3(&self) -> Result<Vec<BlockedJa3Row>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, ja3, action, reason, created_at, expires_at, active_from, active_to, active_days
+                 FROM blocked_ja3",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(BlockedJa3Row {
+                    id: row.get(0)?,
+                    ja3: row.get(1)?,
+                    action: row.get(2)?,
+                    reason: row.get(3)?,
+                    created_at: row.get(4)?,
+                    expires_at: row.get(5)?,
+                    schedule: schedule_from_row(row, 6)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // Blocked countries
     // -----------------------------------------------------------------------
 
-    pub fn add_blocked_country(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_blocked_country(
         &self,
         code: &str,
         name: Option<&str>,
         action: &str,
         reason: Option<&str>,
+        source: &str,
+        expires_at: Option<DateTime<Utc>>,
+        schedule: &ScheduleFields,
     ) -> Result<i64> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "INSERT OR REPLACE INTO blocked_countries (country_code, country_name, action, reason)
-             VALUES (?1, ?2, ?3, ?4)",
-            params![code, name, action, reason],
-        )?;
-        Ok(conn.last_insert_rowid())
+        let (code, name, action, reason, source) = (
+            code.to_string(),
+            name.map(str::to_string),
+            action.to_string(),
+            reason.map(str::to_string),
+            source.to_string(),
+        );
+        let expires_str = expires_at.map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+        let s = schedule.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO blocked_countries
+                     (country_code, country_name, action, reason, source, expires_at,
+                      active_from, active_to, active_days)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    code, name, action, reason, source, expires_str,
+                    s.active_from, s.active_to, s.active_days
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
     }
 
-    pub fn remove_blocked_country(&self, id: i64) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute("DELETE FROM blocked_countries WHERE id = ?1", params![id])?;
-        Ok(())
+    pub async fn remove_blocked_country(&self, id: i64) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute("DELETE FROM blocked_countries WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_blocked_countries(&self) -> Result<Vec<BlockedCountryRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare(
-            "SELECT id, country_code, country_name, action, reason, created_at
-             FROM blocked_countries",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(BlockedCountryRow {
-                id: row.get(0)?,
-                country_code: row.get(1)?,
-                country_name: row.get(2)?,
-                action: row.get(3)?,
-                reason: row.get(4)?,
-                created_at: row.get(5)?,
-            })
-        })?;
-        rows.collect()
+    pub async fn get_blocked_countries(&self) -> Result<Vec<BlockedCountryRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM blocked_countries", BLOCKED_COUNTRY_COLUMNS))?;
+            let rows = stmt.query_map([], blocked_country_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Page through blocked (or allowed) countries, newest first, with the
+    /// total number of matching rows.
+    pub async fn get_blocked_countries_page(
+        &self,
+        filter: &BlocklistFilter,
+        limit: usize,
+        offset: usize,
+    ) -> Result<(Vec<BlockedCountryRow>, u64)> {
+        let clause = blocklist_where(filter);
+        self.read(move |conn| {
+            query_page(conn, BLOCKED_COUNTRY_COLUMNS, "blocked_countries", &clause, limit, offset, blocked_country_from_row)
+        })
+        .await
+    }
+
+    /// Block IPs, ASNs, countries and JA3 hashes in one transaction,
+    /// replacing existing rows for the same value. Returns the row ID of
+    /// each entry, in order.
+    pub async fn add_blocklist_entries(
+        &self,
+        entries: &[NewBlocklistEntry],
+        source: &str,
+    ) -> Result<Vec<i64>> {
+        let entries = entries.to_vec();
+        let source = source.to_string();
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let mut ids = Vec::with_capacity(entries.len());
+            for entry in &entries {
+                match entry {
+                    NewBlocklistEntry::Ip(row) => {
+                        let expires_str = row
+                            .expires_at
+                            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+                        tx.prepare_cached(
+                            "INSERT OR REPLACE INTO blocked_ips
+                                 (ip, cidr, reason, source, expires_at, ip_key, country, asn)
+                             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        )?
+                        .execute(params![
+                            row.ip, row.cidr, row.reason, source, expires_str, ip_key(&row.ip),
+                            row.geo.country, row.geo.asn,
+                        ])?;
+                    }
+                    NewBlocklistEntry::Asn { asn, reason } => {
+                        tx.prepare_cached(
+                            "INSERT OR REPLACE INTO blocked_asns (asn, name, action, reason, source)
+                             VALUES (?1, NULL, 'block', ?2, ?3)",
+                        )?
+                        .execute(params![asn, reason, source])?;
+                    }
+                    NewBlocklistEntry::Country { code, reason } => {
+                        tx.prepare_cached(
+                            "INSERT OR REPLACE INTO blocked_countries
+                                 (country_code, country_name, action, reason, source)
+                             VALUES (?1, NULL, 'block', ?2, ?3)",
+                        )?
+                        .execute(params![code, reason, source])?;
+                    }
+                    NewBlocklistEntry::Ja3 { hash, reason, expires_at } => {
+                        let expires_str =
+                            expires_at.map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+                        tx.prepare_cached(
+                            "INSERT OR REPLACE INTO blocked_ja3 (ja3, action, reason, expires_at)
+                             VALUES (?1, 'block', ?2, ?3)",
+                        )?
+                        .execute(params![hash, reason, expires_str])?;
+                    }
+                }
+                ids.push(tx.last_insert_rowid());
+            }
+            tx.commit()?;
+            Ok(ids)
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // Protection rules
     // -----------------------------------------------------------------------
 
-    pub fn add_rule(
+    pub async fn add_rule(
         &self,
         name: &str,
         priority: i32,
         conditions: &str,
         action: &str,
+        log_only: bool,
+        schedule: &ScheduleFields,
     ) -> Result<i64> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "INSERT INTO protection_rules (name, priority, conditions_json, action)
-             VALUES (?1, ?2, ?3, ?4)",
-            params![name, priority, conditions, action],
-        )?;
-        Ok(conn.last_insert_rowid())
+        let (name, conditions, action) = (name.to_string(), conditions.to_string(), action.to_string());
+        let s = schedule.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO protection_rules
+                     (name, priority, conditions_json, action, log_only, active_from, active_to, active_days)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![name, priority, conditions, action, log_only as i32, s.active_from, s.active_to, s.active_days],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
     }
 
-    pub fn update_rule(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn update_rule(
         &self,
         id: i64,
         name: &str,
@@ -400,56 +1652,148 @@ impl SqliteStore {
         conditions: &str,
         action: &str,
         enabled: bool,
+        log_only: bool,
+        schedule: &ScheduleFields,
     ) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "UPDATE protection_rules
-             SET name = ?1, priority = ?2, conditions_json = ?3, action = ?4,
-                 enabled = ?5, updated_at = datetime('now')
-             WHERE id = ?6",
-            params![name, priority, conditions, action, enabled as i32, id],
-        )?;
-        Ok(())
+        let (name, conditions, action) = (name.to_string(), conditions.to_string(), action.to_string());
+        let s = schedule.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "UPDATE protection_rules
+                 SET name = ?1, priority = ?2, conditions_json = ?3, action = ?4,
+                     enabled = ?5, log_only = ?6, active_from = ?7, active_to = ?8,
+                     active_days = ?9, updated_at = datetime('now')
+                 WHERE id = ?10",
+                params![
+                    name,
+                    priority,
+                    conditions,
+                    action,
+                    enabled as i32,
+                    log_only as i32,
+                    s.active_from,
+                    s.active_to,
+                    s.active_days,
+                    id
+                ],
+            )?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn delete_rule(&self, id: i64) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute("DELETE FROM protection_rules WHERE id = ?1", params![id])?;
-        Ok(())
+    pub async fn delete_rule(&self, id: i64) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute("DELETE FROM protection_rules WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_rules(&self) -> Result<Vec<RuleRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare(
-            "SELECT id, name, priority, conditions_json, action, enabled, created_at
-             FROM protection_rules ORDER BY priority ASC",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(RuleRow {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                priority: row.get(2)?,
-                conditions_json: row.get(3)?,
-                action: row.get(4)?,
-                enabled: row.get::<_, i32>(5)? != 0,
-                created_at: row.get(6)?,
-            })
-        })?;
-        rows.collect()
+    pub async fn get_rules(&self) -> Result<Vec<RuleRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, name, priority, conditions_json, action, enabled, log_only, created_at,
+                        active_from, active_to, active_days
+                 FROM protection_rules ORDER BY priority ASC",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(RuleRow {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    priority: row.get(2)?,
+                    conditions_json: row.get(3)?,
+                    action: row.get(4)?,
+                    enabled: row.get::<_, i32>(5)? != 0,
+                    log_only: row.get::<_, i32>(6)? != 0,
+                    created_at: row.get(7)?,
+                    schedule: schedule_from_row(row, 8)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // Managed rule settings
+    // -----------------------------------------------------------------------
+
+    pub async fn set_managed_rule_setting(&self, rule_id: u32, enabled: bool, params_json: &str) -> Result<()> {
+        let params_json = params_json.to_string();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO managed_rule_settings (rule_id, enabled, params_json)
+                 VALUES (?1, ?2, ?3)
+                 ON CONFLICT(rule_id) DO UPDATE SET
+                     enabled = excluded.enabled,
+                     params_json = excluded.params_json,
+                     updated_at = datetime('now')",
+                params![rule_id, enabled as i32, params_json],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn get_managed_rule_settings(&self) -> Result<Vec<ManagedRuleSettingRow>> {
+        self.read(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT rule_id, enabled, params_json FROM managed_rule_settings ORDER BY rule_id")?;
+            let rows = stmt.query_map([], |row| {
+                Ok(ManagedRuleSettingRow {
+                    rule_id: row.get(0)?,
+                    enabled: row.get::<_, i32>(1)? != 0,
+                    params_json: row.get(2)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
-    // Metrics (hourly snapshots)
+    // Metrics (hourly and minute rollups)
     // -----------------------------------------------------------------------
 
-    pub fn insert_metrics_hourly(&self, snapshot: &MetricsRow) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
+    /// Store the counts for one hour: the global row and one per service.
+    /// A second flush for the same hour (after a restart) adds to the
+    /// stored counts.
+    pub async fn insert_metrics_hourly(&self, rows: Vec<MetricsRow>) -> Result<()> {
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            for snapshot in &rows {
+                Self::upsert_metrics_hourly(&tx, snapshot)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn upsert_metrics_hourly(conn: &Connection, snapshot: &MetricsRow) -> Result<()> {
         conn.execute(
-            "INSERT OR REPLACE INTO metrics_hourly
+            "INSERT INTO metrics_hourly
              (timestamp, total_requests, passed_requests, blocked_requests,
               challenged_requests, unique_ips, avg_latency_ms, protection_level,
-              top_countries_json, top_asns_json)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+              top_countries_json, top_asns_json, service_id, challenges_issued, challenges_solved,
+              bypassed_static, bypassed_preflight, whitelisted)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+             ON CONFLICT(timestamp, IFNULL(service_id, '')) DO UPDATE SET
+                total_requests = total_requests + excluded.total_requests,
+                passed_requests = passed_requests + excluded.passed_requests,
+                blocked_requests = blocked_requests + excluded.blocked_requests,
+                challenged_requests = challenged_requests + excluded.challenged_requests,
+                unique_ips = MAX(unique_ips, excluded.unique_ips),
+                avg_latency_ms = excluded.avg_latency_ms,
+                protection_level = MAX(protection_level, excluded.protection_level),
+                top_countries_json = excluded.top_countries_json,
+                top_asns_json = excluded.top_asns_json,
+                challenges_issued = challenges_issued + excluded.challenges_issued,
+                challenges_solved = challenges_solved + excluded.challenges_solved,
+                bypassed_static = bypassed_static + excluded.bypassed_static,
+                bypassed_preflight = bypassed_preflight + excluded.bypassed_preflight,
+                whitelisted = whitelisted + excluded.whitelisted",
             params![
                 snapshot.timestamp,
                 snapshot.total_requests as i64,
@@ -461,266 +1805,791 @@ impl SqliteStore {
                 snapshot.protection_level as i32,
                 snapshot.top_countries_json,
                 snapshot.top_asns_json,
+                snapshot.service_id,
+                snapshot.challenges_issued as i64,
+                snapshot.challenges_solved as i64,
+                snapshot.bypassed_static as i64,
+                snapshot.bypassed_preflight as i64,
+                snapshot.whitelisted as i64,
             ],
         )?;
         Ok(())
     }
 
-    pub fn get_metrics_history(
+    /// Hourly rows covering all traffic.
+    pub async fn get_metrics_history(
         &self,
         from: DateTime<Utc>,
         to: DateTime<Utc>,
     ) -> Result<Vec<MetricsRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
+        self.metrics_hourly(from, to, None).await
+    }
+
+    /// Hourly rows of one service.
+    pub async fn get_service_metrics_history(
+        &self,
+        service_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<MetricsRow>> {
+        self.metrics_hourly(from, to, Some(service_id.to_string())).await
+    }
+
+    async fn metrics_hourly(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        service_id: Option<String>,
+    ) -> Result<Vec<MetricsRow>> {
         let from_str = from.format("%Y-%m-%d %H:%M:%S").to_string();
         let to_str = to.format("%Y-%m-%d %H:%M:%S").to_string();
-        let mut stmt = conn.prepare(
-            "SELECT timestamp, total_requests, passed_requests, blocked_requests,
-                    challenged_requests, unique_ips, avg_latency_ms, protection_level,
-                    top_countries_json, top_asns_json
-             FROM metrics_hourly
-             WHERE timestamp >= ?1 AND timestamp <= ?2
-             ORDER BY timestamp ASC",
-        )?;
-        let rows = stmt.query_map(params![from_str, to_str], |row| {
-            Ok(MetricsRow {
-                timestamp: row.get(0)?,
-                total_requests: row.get::<_, i64>(1)? as u64,
-                passed_requests: row.get::<_, i64>(2)? as u64,
-                blocked_requests: row.get::<_, i64>(3)? as u64,
-                challenged_requests: row.get::<_, i64>(4)? as u64,
-                unique_ips: row.get::<_, i64>(5)? as u64,
-                avg_latency_ms: row.get(6)?,
-                protection_level: row.get::<_, i32>(7)? as u8,
-                top_countries_json: row.get(8)?,
-                top_asns_json: row.get(9)?,
-            })
-        })?;
-        rows.collect()
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT timestamp, total_requests, passed_requests, blocked_requests,
+                        challenged_requests, unique_ips, avg_latency_ms, protection_level,
+                        top_countries_json, top_asns_json, service_id, challenges_issued, challenges_solved,
+                        bypassed_static, bypassed_preflight, whitelisted
+                 FROM metrics_hourly
+                 WHERE timestamp >= ?1 AND timestamp <= ?2 AND service_id IS ?3
+                 ORDER BY timestamp ASC",
+            )?;
+            let rows = stmt.query_map(params![from_str, to_str, service_id], |row| {
+                Ok(MetricsRow {
+                    timestamp: row.get(0)?,
+                    total_requests: row.get::<_, i64>(1)? as u64,
+                    passed_requests: row.get::<_, i64>(2)? as u64,
+                    blocked_requests: row.get::<_, i64>(3)? as u64,
+                    challenged_requests: row.get::<_, i64>(4)? as u64,
+                    unique_ips: row.get::<_, i64>(5)? as u64,
+                    avg_latency_ms: row.get(6)?,
+                    protection_level: row.get::<_, i32>(7)? as u8,
+                    top_countries_json: row.get(8)?,
+                    top_asns_json: row.get(9)?,
+                    service_id: row.get(10)?,
+                    challenges_issued: row.get::<_, Option<i64>>(11)?.unwrap_or(0) as u64,
+                    challenges_solved: row.get::<_, Option<i64>>(12)?.unwrap_or(0) as u64,
+                    bypassed_static: row.get::<_, Option<i64>>(13)?.unwrap_or(0) as u64,
+                    bypassed_preflight: row.get::<_, Option<i64>>(14)?.unwrap_or(0) as u64,
+                    whitelisted: row.get::<_, Option<i64>>(15)?.unwrap_or(0) as u64,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Add minute rollups and drop those older than `keep_from`.
+    pub async fn insert_metrics_minutely(&self, rows: Vec<MinuteMetricsRow>, keep_from: DateTime<Utc>) -> Result<()> {
+        let keep_from = keep_from.format("%Y-%m-%d %H:%M:%S").to_string();
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO metrics_minutely
+                     (timestamp, total_requests, passed_requests, blocked_requests, challenged_requests,
+                      bypassed_static, bypassed_preflight, whitelisted)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                     ON CONFLICT(timestamp) DO UPDATE SET
+                        total_requests = total_requests + excluded.total_requests,
+                        passed_requests = passed_requests + excluded.passed_requests,
+                        blocked_requests = blocked_requests + excluded.blocked_requests,
+                        challenged_requests = challenged_requests + excluded.challenged_requests,
+                        bypassed_static = bypassed_static + excluded.bypassed_static,
+                        bypassed_preflight = bypassed_preflight + excluded.bypassed_preflight,
+                        whitelisted = whitelisted + excluded.whitelisted",
+                )?;
+                for row in &rows {
+                    stmt.execute(params![
+                        row.timestamp,
+                        row.total_requests as i64,
+                        row.passed_requests as i64,
+                        row.blocked_requests as i64,
+                        row.challenged_requests as i64,
+                        row.bypassed_static as i64,
+                        row.bypassed_preflight as i64,
+                        row.whitelisted as i64,
+                    ])?;
+                }
+            }
+            tx.execute("DELETE FROM metrics_minutely WHERE timestamp < ?1", params![keep_from])?;
+            tx.commit()
+        })
+        .await
+    }
+
+    pub async fn get_metrics_minutely(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<MinuteMetricsRow>> {
+        let from_str = from.format("%Y-%m-%d %H:%M:%S").to_string();
+        let to_str = to.format("%Y-%m-%d %H:%M:%S").to_string();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT timestamp, total_requests, passed_requests, blocked_requests, challenged_requests,
+                        bypassed_static, bypassed_preflight, whitelisted
+                 FROM metrics_minutely
+                 WHERE timestamp >= ?1 AND timestamp <= ?2
+                 ORDER BY timestamp ASC",
+            )?;
+            let rows = stmt.query_map(params![from_str, to_str], |row| {
+                Ok(MinuteMetricsRow {
+                    timestamp: row.get(0)?,
+                    total_requests: row.get::<_, i64>(1)? as u64,
+                    passed_requests: row.get::<_, i64>(2)? as u64,
+                    blocked_requests: row.get::<_, i64>(3)? as u64,
+                    challenged_requests: row.get::<_, i64>(4)? as u64,
+                    bypassed_static: row.get::<_, Option<i64>>(5)?.unwrap_or(0) as u64,
+                    bypassed_preflight: row.get::<_, Option<i64>>(6)?.unwrap_or(0) as u64,
+                    whitelisted: row.get::<_, Option<i64>>(7)?.unwrap_or(0) as u64,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Timestamp of the oldest stored minute rollup.
+    pub async fn oldest_metrics_minute(&self) -> Result<Option<String>> {
+        self.read(|conn| conn.query_row("SELECT MIN(timestamp) FROM metrics_minutely", [], |row| row.get(0)))
+            .await
+    }
+
+    // -----------------------------------------------------------------------
+    // Request samples
+    // -----------------------------------------------------------------------
+
+    pub async fn insert_request_samples(&self, rows: Vec<RequestSampleRow>) -> Result<()> {
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO request_samples
+                     (timestamp, client_ip, method, host, path, action, status, service_id, country, asn)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                )?;
+                for row in &rows {
+                    stmt.execute(params![
+                        row.timestamp, row.client_ip, row.method, row.host, row.path,
+                        row.action, row.status, row.service_id, row.country, row.asn,
+                    ])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    /// Delete samples older than `keep_from`. Returns how many were removed.
+    pub async fn prune_request_samples(&self, keep_from: DateTime<Utc>) -> Result<usize> {
+        let keep_from = keep_from.format("%Y-%m-%d %H:%M:%S").to_string();
+        self.write(move |conn| conn.execute("DELETE FROM request_samples WHERE timestamp < ?1", params![keep_from]))
+            .await
+    }
+
+    /// Sampled requests of one client between `from` and `to`, newest first.
+    pub async fn get_request_samples(
+        &self,
+        client_ip: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<RequestSampleRow>> {
+        let client_ip = client_ip.to_string();
+        let from_str = from.format("%Y-%m-%d %H:%M:%S").to_string();
+        let to_str = to.format("%Y-%m-%d %H:%M:%S").to_string();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT timestamp, client_ip, method, host, path, action, status, service_id, country, asn
+                 FROM request_samples
+                 WHERE client_ip = ?1 AND timestamp >= ?2 AND timestamp <= ?3
+                 ORDER BY id DESC LIMIT ?4",
+            )?;
+            let rows = stmt.query_map(params![client_ip, from_str, to_str, limit as i64], |row| {
+                Ok(RequestSampleRow {
+                    timestamp: row.get(0)?,
+                    client_ip: row.get(1)?,
+                    method: row.get(2)?,
+                    host: row.get(3)?,
+                    path: row.get(4)?,
+                    action: row.get(5)?,
+                    status: row.get(6)?,
+                    service_id: row.get(7)?,
+                    country: row.get(8)?,
+                    asn: row.get(9)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Most frequent values of `column` (`client_ip`, `country`, `asn` or
+    /// `path`) among the samples between `from` and `to`.
+    pub async fn get_top_request_samples(
+        &self,
+        column: &'static str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<SampleCountRow>> {
+        let from_str = from.format("%Y-%m-%d %H:%M:%S").to_string();
+        let to_str = to.format("%Y-%m-%d %H:%M:%S").to_string();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT CAST({col} AS TEXT), COUNT(*) AS n FROM request_samples
+                 WHERE timestamp >= ?1 AND timestamp <= ?2 AND {col} IS NOT NULL
+                 GROUP BY {col} ORDER BY n DESC LIMIT ?3",
+                col = column
+            ))?;
+            let rows = stmt.query_map(params![from_str, to_str, limit as i64], |row| {
+                Ok(SampleCountRow {
+                    value: row.get(0)?,
+                    count: row.get::<_, i64>(1)? as u64,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // API tokens
+    // -----------------------------------------------------------------------
+
+    pub async fn insert_api_token(&self, row: ApiTokenRow) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO api_tokens
+                 (id, name, secret, allowed_hosts, allowed_paths, action, exempt_rate_limit, expires_at, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    row.id, row.name, row.secret, row.allowed_hosts, row.allowed_paths,
+                    row.action, row.exempt_rate_limit as i32, row.expires_at, row.created_at,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Update everything but the secret and usage. Returns false if the
+    /// token does not exist.
+    pub async fn update_api_token(&self, row: ApiTokenRow) -> Result<bool> {
+        self.write(move |conn| {
+            let n = conn.execute(
+                "UPDATE api_tokens SET name=?1, allowed_hosts=?2, allowed_paths=?3, action=?4,
+                 exempt_rate_limit=?5, expires_at=?6 WHERE id=?7",
+                params![
+                    row.name, row.allowed_hosts, row.allowed_paths, row.action,
+                    row.exempt_rate_limit as i32, row.expires_at, row.id,
+                ],
+            )?;
+            Ok(n > 0)
+        })
+        .await
+    }
+
+    pub async fn delete_api_token(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.write(move |conn| Ok(conn.execute("DELETE FROM api_tokens WHERE id = ?1", params![id])? > 0))
+            .await
+    }
+
+    pub async fn get_api_tokens(&self) -> Result<Vec<ApiTokenRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, name, secret, allowed_hosts, allowed_paths, action, exempt_rate_limit,
+                        expires_at, created_at, usage_count, last_used_at
+                 FROM api_tokens ORDER BY created_at",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(ApiTokenRow {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    secret: row.get(2)?,
+                    allowed_hosts: row.get(3)?,
+                    allowed_paths: row.get(4)?,
+                    action: row.get(5)?,
+                    exempt_rate_limit: row.get::<_, i32>(6)? != 0,
+                    expires_at: row.get(7)?,
+                    created_at: row.get(8)?,
+                    usage_count: row.get::<_, i64>(9)? as u64,
+                    last_used_at: row.get(10)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Store the usage totals of tokens, as `(id, count, last_used_at)`.
+    pub async fn update_api_token_usage(&self, usage: Vec<(String, u64, Option<String>)>) -> Result<()> {
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt =
+                    tx.prepare("UPDATE api_tokens SET usage_count = ?1, last_used_at = ?2 WHERE id = ?3")?;
+                for (id, count, last_used_at) in &usage {
+                    stmt.execute(params![*count as i64, last_used_at, id])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // IP policies
+    // -----------------------------------------------------------------------
+
+    /// Set the policy of a network, replacing the one it had. Returns the
+    /// row ID, which is kept on replacement.
+    pub async fn upsert_ip_policy(
+        &self,
+        network: &str,
+        policy: &str,
+        reason: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<i64> {
+        let network = network.to_string();
+        let policy = policy.to_string();
+        let reason = reason.map(|s| s.to_string());
+        let expires_str = expires_at.map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string());
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO ip_policies (network, policy, reason, expires_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(network) DO UPDATE SET
+                    policy = excluded.policy, reason = excluded.reason, expires_at = excluded.expires_at,
+                    created_at = datetime('now')",
+                params![network, policy, reason, expires_str],
+            )?;
+            conn.query_row("SELECT id FROM ip_policies WHERE network = ?1", params![network], |row| row.get(0))
+        })
+        .await
+    }
+
+    pub async fn delete_ip_policy(&self, id: i64) -> Result<bool> {
+        self.write(move |conn| Ok(conn.execute("DELETE FROM ip_policies WHERE id = ?1", params![id])? > 0))
+            .await
+    }
+
+    /// Delete the policies whose TTL has run out. Returns how many went.
+    pub async fn delete_expired_ip_policies(&self) -> Result<usize> {
+        self.write(|conn| {
+            conn.execute(
+                "DELETE FROM ip_policies WHERE expires_at IS NOT NULL AND expires_at <= datetime('now')",
+                [],
+            )
+        })
+        .await
+    }
+
+    pub async fn get_ip_policies(&self) -> Result<Vec<IpPolicyRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, network, policy, reason, expires_at, created_at FROM ip_policies ORDER BY id",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(IpPolicyRow {
+                    id: row.get(0)?,
+                    network: row.get(1)?,
+                    policy: row.get(2)?,
+                    reason: row.get(3)?,
+                    expires_at: row.get(4)?,
+                    created_at: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // ASN overrides
+    // -----------------------------------------------------------------------
+
+    pub async fn upsert_asn_override(&self, row: AsnOverrideRow) -> Result<()> {
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO asn_overrides (asn, category, reason, created_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(asn) DO UPDATE SET
+                    category = excluded.category, reason = excluded.reason, created_at = excluded.created_at",
+                params![row.asn, row.category, row.reason, row.created_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn delete_asn_override(&self, asn: u32) -> Result<bool> {
+        self.write(move |conn| Ok(conn.execute("DELETE FROM asn_overrides WHERE asn = ?1", params![asn])? > 0))
+            .await
+    }
+
+    pub async fn get_asn_overrides(&self) -> Result<Vec<AsnOverrideRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare("SELECT asn, category, reason, created_at FROM asn_overrides ORDER BY asn")?;
+            let rows = stmt.query_map([], |row| {
+                Ok(AsnOverrideRow {
+                    asn: row.get(0)?,
+                    category: row.get(1)?,
+                    reason: row.get(2)?,
+                    created_at: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                })
+            })?;
+            rows.collect()
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // Attacks
     // -----------------------------------------------------------------------
 
-    pub fn insert_attack(&self, attack: &AttackRow) -> Result<i64> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "INSERT INTO attacks
-             (started_at, ended_at, peak_rps, total_requests, unique_ips,
-              max_level, top_countries_json, top_ips_json, severity)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-            params![
-                attack.started_at,
-                attack.ended_at,
-                attack.peak_rps as i64,
-                attack.total_requests as i64,
-                attack.unique_ips as i64,
-                attack.max_level as i32,
-                attack.top_countries_json,
-                attack.top_ips_json,
-                attack.severity,
-            ],
-        )?;
-        Ok(conn.last_insert_rowid())
+    pub async fn insert_attack(&self, attack: &AttackRow) -> Result<i64> {
+        let attack = attack.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO attacks
+                 (started_at, ended_at, peak_rps, total_requests, unique_ips,
+                  max_level, top_countries_json, top_ips_json, severity)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    attack.started_at,
+                    attack.ended_at,
+                    attack.peak_rps as i64,
+                    attack.total_requests as i64,
+                    attack.unique_ips as i64,
+                    attack.max_level as i32,
+                    attack.top_countries_json,
+                    attack.top_ips_json,
+                    attack.severity,
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
     }
 
-    pub fn update_attack(&self, id: i64, attack: &AttackRow) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "UPDATE attacks
-             SET started_at = ?1, ended_at = ?2, peak_rps = ?3, total_requests = ?4,
-                 unique_ips = ?5, max_level = ?6, top_countries_json = ?7,
-                 top_ips_json = ?8, severity = ?9
-             WHERE id = ?10",
-            params![
-                attack.started_at,
-                attack.ended_at,
-                attack.peak_rps as i64,
-                attack.total_requests as i64,
-                attack.unique_ips as i64,
-                attack.max_level as i32,
-                attack.top_countries_json,
-                attack.top_ips_json,
-                attack.severity,
-                id,
-            ],
-        )?;
-        Ok(())
+    pub async fn update_attack(&self, id: i64, attack: &AttackRow) -> Result<()> {
+        let attack = attack.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "UPDATE attacks
+                 SET started_at = ?1, ended_at = ?2, peak_rps = ?3, total_requests = ?4,
+                     unique_ips = ?5, max_level = ?6, top_countries_json = ?7,
+                     top_ips_json = ?8, severity = ?9
+                 WHERE id = ?10",
+                params![
+                    attack.started_at,
+                    attack.ended_at,
+                    attack.peak_rps as i64,
+                    attack.total_requests as i64,
+                    attack.unique_ips as i64,
+                    attack.max_level as i32,
+                    attack.top_countries_json,
+                    attack.top_ips_json,
+                    attack.severity,
+                    id,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_attacks(
+    pub async fn get_attacks(
         &self,
         from: DateTime<Utc>,
         to: DateTime<Utc>,
     ) -> Result<Vec<AttackRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
         let from_str = from.format("%Y-%m-%d %H:%M:%S").to_string();
         let to_str = to.format("%Y-%m-%d %H:%M:%S").to_string();
-        let mut stmt = conn.prepare(
-            "SELECT id, started_at, ended_at, peak_rps, total_requests, unique_ips,
-                    max_level, top_countries_json, top_ips_json, severity
-             FROM attacks
-             WHERE started_at >= ?1 AND started_at <= ?2
-             ORDER BY started_at DESC",
-        )?;
-        let rows = stmt.query_map(params![from_str, to_str], |row| {
-            Ok(AttackRow {
-                id: row.get(0)?,
-                started_at: row.get(1)?,
-                ended_at: row.get(2)?,
-                peak_rps: row.get::<_, i64>(3)? as u64,
-                total_requests: row.get::<_, i64>(4)? as u64,
-                unique_ips: row.get::<_, i64>(5)? as u64,
-                max_level: row.get::<_, i32>(6)? as u8,
-                top_countries_json: row.get(7)?,
-                top_ips_json: row.get(8)?,
-                severity: row.get(9)?,
-            })
-        })?;
-        rows.collect()
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, started_at, ended_at, peak_rps, total_requests, unique_ips,
+                        max_level, top_countries_json, top_ips_json, severity
+                 FROM attacks
+                 WHERE started_at >= ?1 AND started_at <= ?2
+                 ORDER BY started_at DESC",
+            )?;
+            let rows = stmt.query_map(params![from_str, to_str], |row| {
+                Ok(AttackRow {
+                    id: row.get(0)?,
+                    started_at: row.get(1)?,
+                    ended_at: row.get(2)?,
+                    peak_rps: row.get::<_, i64>(3)? as u64,
+                    total_requests: row.get::<_, i64>(4)? as u64,
+                    unique_ips: row.get::<_, i64>(5)? as u64,
+                    max_level: row.get::<_, i32>(6)? as u8,
+                    top_countries_json: row.get(7)?,
+                    top_ips_json: row.get(8)?,
+                    severity: row.get(9)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // Key-value config
     // -----------------------------------------------------------------------
 
-    pub fn get_config(&self, key: &str) -> Result<Option<String>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare("SELECT value FROM config WHERE key = ?1")?;
-        let mut rows = stmt.query_map(params![key], |row| row.get::<_, String>(0))?;
-        match rows.next() {
-            Some(Ok(value)) => Ok(Some(value)),
-            Some(Err(e)) => Err(e),
-            None => Ok(None),
-        }
+    pub async fn get_config(&self, key: &str) -> Result<Option<String>> {
+        let key = key.to_string();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare("SELECT value FROM config WHERE key = ?1")?;
+            let mut rows = stmt.query_map(params![key], |row| row.get::<_, String>(0))?;
+            match rows.next() {
+                Some(Ok(value)) => Ok(Some(value)),
+                Some(Err(e)) => Err(e),
+                None => Ok(None),
+            }
+        })
+        .await
     }
 
-    pub fn set_config(&self, key: &str, value: &str) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "INSERT INTO config (key, value, updated_at)
-             VALUES (?1, ?2, datetime('now'))
-             ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
-            params![key, value],
-        )?;
-        Ok(())
+    pub async fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO config (key, value, updated_at)
+                 VALUES (?1, ?2, datetime('now'))
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
+                params![key, value],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // State export / import
+    // -----------------------------------------------------------------------
+
+    /// Every configured-state table, as rows of column name to value keyed
+    /// by section name. Rows are ordered by each table's key column.
+    /// `persistent_only` leaves out expiring and auto-ban blocklist entries.
+    pub async fn export_state(&self, persistent_only: bool) -> Result<StateSections> {
+        self.read(move |conn| {
+            // One read transaction, so the sections are a consistent snapshot
+            let tx = conn.unchecked_transaction()?;
+            let mut sections = StateSections::new();
+            for &(section, table, key) in STATE_SECTIONS.iter() {
+                let columns = table_columns(&tx, table)?;
+                let mut filters = Vec::new();
+                if persistent_only && columns.contains("expires_at") {
+                    filters.push("expires_at IS NULL");
+                }
+                if persistent_only && columns.contains("source") {
+                    filters.push("source != 'auto'");
+                }
+                let filter = if filters.is_empty() { String::new() } else { format!(" WHERE {}", filters.join(" AND ")) };
+                let mut stmt = tx.prepare(&format!("SELECT * FROM {}{} ORDER BY {}", table, filter, key))?;
+                let names: Vec<String> = stmt.column_names().into_iter().map(str::to_string).collect();
+                let rows = stmt.query_map([], |row| {
+                    let mut out = StateRow::new();
+                    for (i, name) in names.iter().enumerate() {
+                        out.insert(name.clone(), sql_to_json(row.get(i)?, i)?);
+                    }
+                    Ok(out)
+                })?;
+                sections.insert(section.to_string(), rows.collect::<Result<_>>()?);
+            }
+            Ok(sections)
+        })
+        .await
+    }
+
+    /// Write `sections` from [`export_state`](Self::export_state) in one
+    /// transaction and return the rows written per section. `Replace`
+    /// empties every table with a section in the document first; `Merge`
+    /// overwrites rows with the same key and keeps the rest.
+    pub async fn import_state(&self, sections: StateSections, mode: ImportMode) -> Result<BTreeMap<String, usize>> {
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let mut written = BTreeMap::new();
+            for (section, rows) in &sections {
+                let (table, key) = STATE_SECTIONS
+                    .iter()
+                    .find(|(s, _, _)| s == section)
+                    .map(|&(_, table, key)| (table, key))
+                    .ok_or_else(|| rusqlite::Error::InvalidParameterName(section.clone()))?;
+                let columns = table_columns(&tx, table)?;
+                if mode == ImportMode::Replace {
+                    tx.execute(&format!("DELETE FROM {}", table), [])?;
+                }
+                for row in rows {
+                    if let Some(unknown) = row.keys().find(|c| !columns.contains(c.as_str())) {
+                        return Err(rusqlite::Error::InvalidColumnName(format!("{}.{}", table, unknown)));
+                    }
+                    let mut row = row.clone();
+                    if mode == ImportMode::Merge {
+                        if let Some(value) = row.get(key) {
+                            tx.execute(&format!("DELETE FROM {} WHERE {} = ?1", table, key), [json_to_sql(value)?])?;
+                        }
+                        // Blocklist ids are only surrogate keys; let SQLite
+                        // pick a new one when another entry already has it
+                        if key != "id" {
+                            if let Some(id) = row.get("id") {
+                                let taken: bool = tx.query_row(
+                                    &format!("SELECT EXISTS(SELECT 1 FROM {} WHERE id = ?1)", table),
+                                    [json_to_sql(id)?],
+                                    |r| r.get(0),
+                                )?;
+                                if taken {
+                                    row.remove("id");
+                                }
+                            }
+                        }
+                    }
+                    let names: Vec<&str> = row.keys().map(String::as_str).collect();
+                    let values = row.values().map(json_to_sql).collect::<Result<Vec<_>>>()?;
+                    let sql = if names.is_empty() {
+                        format!("INSERT INTO {} DEFAULT VALUES", table)
+                    } else {
+                        let placeholders: Vec<String> = (1..=names.len()).map(|i| format!("?{}", i)).collect();
+                        format!("INSERT INTO {} ({}) VALUES ({})", table, names.join(", "), placeholders.join(", "))
+                    };
+                    tx.execute(&sql, params_from_iter(values))?;
+                }
+                written.insert(section.clone(), rows.len());
+            }
+            tx.commit()?;
+            Ok(written)
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // Services
     // -----------------------------------------------------------------------
 
-    pub fn add_service(&self, svc: &ServiceRow) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "INSERT OR REPLACE INTO services
-             (id, name, domains, upstream_address, enabled, protection_level_override,
-              always_challenge, rate_limit_multiplier, max_connections, connect_timeout_ms,
-              response_timeout_ms, exempt_paths)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
-            params![
-                svc.id, svc.name, svc.domains, svc.upstream_address,
-                svc.enabled as i32, svc.protection_level_override,
-                svc.always_challenge as i32, svc.rate_limit_multiplier,
-                svc.max_connections, svc.connect_timeout_ms,
-                svc.response_timeout_ms, svc.exempt_paths,
-            ],
-        )?;
-        Ok(())
+    pub async fn add_service(&self, svc: &ServiceRow) -> Result<()> {
+        let svc = svc.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO services
+                 (id, name, domains, upstream_address, enabled, protection_level_override,
+                  always_challenge, rate_limit_multiplier, max_connections, connect_timeout_ms,
+                  response_timeout_ms, exempt_paths, lb_strategy, max_requests_per_ip_10s,
+                  upstream_tls_verify, upstream_sni_host, add_request_headers,
+                  remove_request_headers, add_response_headers, remove_response_headers,
+                  allowed_countries, allowed_asns, clearance_cookie_domain, clearance_ttl_secs,
+                  cors_allowed_origins, maintenance_mode, maintenance_html_path, body_inspection,
+                  blocked_countries, challenged_countries, country_exceptions, max_body_size_mb,
+                  upstream_http2, always_online, always_online_banner, request_encoding, routes,
+                  login_username_field, waiting_room, health_check)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+                         ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30,
+                         ?31, ?32, ?33, ?34, ?35, ?36, ?37, ?38, ?39, ?40)",
+                params![
+                    svc.id, svc.name, svc.domains, svc.upstream_address,
+                    svc.enabled as i32, svc.protection_level_override,
+                    svc.always_challenge as i32, svc.rate_limit_multiplier,
+                    svc.max_connections, svc.connect_timeout_ms,
+                    svc.response_timeout_ms, svc.exempt_paths, svc.lb_strategy,
+                    svc.max_requests_per_ip_10s, svc.upstream_tls_verify as i32,
+                    svc.upstream_sni_host, svc.add_request_headers,
+                    svc.remove_request_headers, svc.add_response_headers,
+                    svc.remove_response_headers, svc.allowed_countries, svc.allowed_asns,
+                    svc.clearance_cookie_domain, svc.clearance_ttl_secs,
+                    svc.cors_allowed_origins, svc.maintenance_mode as i32,
+                    svc.maintenance_html_path, svc.body_inspection as i32,
+                    svc.blocked_countries, svc.challenged_countries, svc.country_exceptions,
+                    svc.max_body_size_mb, svc.upstream_http2 as i32, svc.always_online as i32,
+                    svc.always_online_banner as i32, svc.request_encoding, svc.routes,
+                    svc.login_username_field, svc.waiting_room, svc.health_check,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn update_service(&self, svc: &ServiceRow) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute(
-            "UPDATE services SET name=?1, domains=?2, upstream_address=?3, enabled=?4,
-             protection_level_override=?5, always_challenge=?6, rate_limit_multiplier=?7,
-             max_connections=?8, connect_timeout_ms=?9, response_timeout_ms=?10,
-             exempt_paths=?11, updated_at=datetime('now')
-             WHERE id=?12",
-            params![
-                svc.name, svc.domains, svc.upstream_address, svc.enabled as i32,
-                svc.protection_level_override, svc.always_challenge as i32,
-                svc.rate_limit_multiplier, svc.max_connections,
-                svc.connect_timeout_ms, svc.response_timeout_ms,
-                svc.exempt_paths, svc.id,
-            ],
-        )?;
-        Ok(())
+    pub async fn update_service(&self, svc: &ServiceRow) -> Result<()> {
+        let svc = svc.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "UPDATE services SET name=?1, domains=?2, upstream_address=?3, enabled=?4,
+                 protection_level_override=?5, always_challenge=?6, rate_limit_multiplier=?7,
+                 max_connections=?8, connect_timeout_ms=?9, response_timeout_ms=?10,
+                 exempt_paths=?11, lb_strategy=?12, max_requests_per_ip_10s=?13,
+                 upstream_tls_verify=?14, upstream_sni_host=?15, add_request_headers=?16,
+                 remove_request_headers=?17, add_response_headers=?18,
+                 remove_response_headers=?19, allowed_countries=?20, allowed_asns=?21,
+                 clearance_cookie_domain=?22, clearance_ttl_secs=?23,
+                 cors_allowed_origins=?24, maintenance_mode=?25, maintenance_html_path=?26,
+                 body_inspection=?27, blocked_countries=?28, challenged_countries=?29,
+                 country_exceptions=?30, max_body_size_mb=?31, upstream_http2=?32,
+                 always_online=?33, always_online_banner=?34, request_encoding=?35,
+                 routes=?36, login_username_field=?37, waiting_room=?38, health_check=?39,
+                 updated_at=datetime('now')
+                 WHERE id=?40",
+                params![
+                    svc.name, svc.domains, svc.upstream_address, svc.enabled as i32,
+                    svc.protection_level_override, svc.always_challenge as i32,
+                    svc.rate_limit_multiplier, svc.max_connections,
+                    svc.connect_timeout_ms, svc.response_timeout_ms,
+                    svc.exempt_paths, svc.lb_strategy, svc.max_requests_per_ip_10s,
+                    svc.upstream_tls_verify as i32, svc.upstream_sni_host,
+                    svc.add_request_headers, svc.remove_request_headers,
+                    svc.add_response_headers, svc.remove_response_headers,
+                    svc.allowed_countries, svc.allowed_asns,
+                    svc.clearance_cookie_domain, svc.clearance_ttl_secs,
+                    svc.cors_allowed_origins, svc.maintenance_mode as i32,
+                    svc.maintenance_html_path, svc.body_inspection as i32,
+                    svc.blocked_countries, svc.challenged_countries, svc.country_exceptions,
+                    svc.max_body_size_mb, svc.upstream_http2 as i32, svc.always_online as i32,
+                    svc.always_online_banner as i32, svc.request_encoding, svc.routes,
+                    svc.login_username_field, svc.waiting_room, svc.health_check, svc.id,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn delete_service(&self, id: &str) -> Result<()> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        conn.execute("DELETE FROM services WHERE id = ?1", params![id])?;
-        Ok(())
+    pub async fn delete_service(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.write(move |conn| {
+            conn.execute("DELETE FROM services WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_services(&self) -> Result<Vec<ServiceRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare(
-            "SELECT id, name, domains, upstream_address, enabled, protection_level_override,
-                    always_challenge, rate_limit_multiplier, max_connections, connect_timeout_ms,
-                    response_timeout_ms, exempt_paths, created_at, updated_at
-             FROM services ORDER BY name ASC",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(ServiceRow {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                domains: row.get(2)?,
-                upstream_address: row.get(3)?,
-                enabled: row.get::<_, i32>(4)? != 0,
-                protection_level_override: row.get(5)?,
-                always_challenge: row.get::<_, i32>(6)? != 0,
-                rate_limit_multiplier: row.get(7)?,
-                max_connections: row.get(8)?,
-                connect_timeout_ms: row.get(9)?,
-                response_timeout_ms: row.get(10)?,
-                exempt_paths: row.get(11)?,
-                created_at: row.get(12)?,
-                updated_at: row.get(13)?,
-            })
-        })?;
-        rows.collect()
+    pub async fn get_services(&self) -> Result<Vec<ServiceRow>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!("{} ORDER BY name ASC", SERVICE_SELECT))?;
+            let rows = stmt.query_map([], service_from_row)?;
+            rows.collect()
+        })
+        .await
     }
 
-    pub fn get_service(&self, id: &str) -> Result<Option<ServiceRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare(
-            "SELECT id, name, domains, upstream_address, enabled, protection_level_override,
-                    always_challenge, rate_limit_multiplier, max_connections, connect_timeout_ms,
-                    response_timeout_ms, exempt_paths, created_at, updated_at
-             FROM services WHERE id = ?1",
-        )?;
-        let mut rows = stmt.query_map(params![id], |row| {
-            Ok(ServiceRow {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                domains: row.get(2)?,
-                upstream_address: row.get(3)?,
-                enabled: row.get::<_, i32>(4)? != 0,
-                protection_level_override: row.get(5)?,
-                always_challenge: row.get::<_, i32>(6)? != 0,
-                rate_limit_multiplier: row.get(7)?,
-                max_connections: row.get(8)?,
-                connect_timeout_ms: row.get(9)?,
-                response_timeout_ms: row.get(10)?,
-                exempt_paths: row.get(11)?,
-                created_at: row.get(12)?,
-                updated_at: row.get(13)?,
-            })
-        })?;
-        match rows.next() {
-            Some(Ok(row)) => Ok(Some(row)),
-            Some(Err(e)) => Err(e),
-            None => Ok(None),
-        }
+    pub async fn get_service(&self, id: &str) -> Result<Option<ServiceRow>> {
+        let id = id.to_string();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(&format!("{} WHERE id = ?1", SERVICE_SELECT))?;
+            let mut rows = stmt.query_map(params![id], service_from_row)?;
+            match rows.next() {
+                Some(Ok(row)) => Ok(Some(row)),
+                Some(Err(e)) => Err(e),
+                None => Ok(None),
+            }
+        })
+        .await
     }
 
     // -----------------------------------------------------------------------
     // L4 Events
     // -----------------------------------------------------------------------
 
+    /// Queue an L4 event. Called from the accept loop, so it never waits
+    /// for the write; failures are logged by the writer thread.
     pub fn insert_l4_event(
         &self,
         client_ip: &str,
@@ -728,33 +2597,489 @@ impl SqliteStore {
         reason: Option<&str>,
         concurrent: Option<i64>,
         rate: Option<i64>,
-    ) -> Result<i64> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
+    ) {
+        let (client_ip, action, reason) =
+            (client_ip.to_string(), action.to_string(), reason.map(str::to_string));
+        let queued = self.submit(move |conn| {
+            if let Err(e) = conn.execute(
+                "INSERT INTO l4_events (client_ip, action, reason, concurrent_connections, connection_rate)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![client_ip, action, reason, concurrent, rate],
+            ) {
+                warn!(client_ip = %client_ip, "Failed to write L4 event: {}", e);
+            }
+        });
+        if let Err(e) = queued {
+            warn!("Failed to queue L4 event: {}", e);
+        }
+    }
+
+    /// Page through L4 events, newest first, with the total number of
+    /// matching events.
+    pub async fn get_l4_events_page(
+        &self,
+        filter: &L4EventFilter,
+        limit: usize,
+        offset: usize,
+    ) -> Result<(Vec<L4EventRow>, u64)> {
+        let mut clauses: Vec<&str> = Vec::new();
+        let mut values: Vec<String> = Vec::new();
+        if let Some(ref ip) = filter.ip {
+            if ip.parse::<IpAddr>().is_ok() {
+                clauses.push("client_ip = ?");
+                values.push(ip.clone());
+            } else {
+                push_prefix(&mut clauses, &mut values, "client_ip >= ? AND client_ip < ?", ip);
+            }
+        }
+        if let Some(ref action) = filter.action {
+            clauses.push("action = ?");
+            values.push(action.clone());
+        }
+        if let Some(ref reason) = filter.reason {
+            clauses.push("reason LIKE ? ESCAPE '\\'");
+            values.push(contains_pattern(reason));
+        }
+        if let Some(from) = filter.from {
+            clauses.push("timestamp >= ?");
+            values.push(from.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+        if let Some(to) = filter.to {
+            clauses.push("timestamp <= ?");
+            values.push(to.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+        let clause = (where_sql(&clauses), values);
+        self.read(move |conn| {
+            query_page(conn, L4_EVENT_COLUMNS, "l4_events", &clause, limit, offset, l4_event_from_row)
+        })
+        .await
+    }
+
+    // -----------------------------------------------------------------------
+    // Audit log
+    // -----------------------------------------------------------------------
+
+    /// Record an audit entry without waiting for the write, logging instead
+    /// of failing so that a write error never blocks the action being
+    /// audited.
+    pub fn audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target_type: &str,
+        target: &str,
+        reason: Option<&str>,
+    ) {
+        let entry = AuditEntry::new(actor, action, target_type, target, reason);
+        let queued = self.submit(move |conn| {
+            if let Err(e) = entry.insert(conn) {
+                warn!(actor = %entry.actor, action = %entry.action, target = %entry.target,
+                    "Failed to write audit log entry: {}", e);
+            }
+        });
+        if let Err(e) = queued {
+            warn!(actor, action, target, "Failed to queue audit log entry: {}", e);
+        }
+    }
+
+    /// Page through the audit log, newest first. Returns the requested page
+    /// together with the total number of matching entries.
+    pub async fn get_audit(
+        &self,
+        filter: &AuditFilter,
+        limit: usize,
+        offset: usize,
+    ) -> Result<(Vec<AuditRow>, u64)> {
+        let mut clauses: Vec<&str> = Vec::new();
+        let mut values: Vec<String> = Vec::new();
+        if let Some(from) = filter.from {
+            clauses.push("timestamp >= ?");
+            values.push(from.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+        if let Some(to) = filter.to {
+            clauses.push("timestamp <= ?");
+            values.push(to.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+        if let Some(ref action) = filter.action {
+            clauses.push("action = ?");
+            values.push(action.clone());
+        }
+        if let Some(ref target_type) = filter.target_type {
+            clauses.push("target_type = ?");
+            values.push(target_type.clone());
+        }
+        if let Some(ref target) = filter.target {
+            clauses.push("target = ?");
+            values.push(target.clone());
+        }
+        let where_sql = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+
+        self.read(move |conn| {
+            let total: i64 = conn.query_row(
+                &format!("SELECT COUNT(*) FROM audit_log {}", where_sql),
+                params_from_iter(values.iter()),
+                |row| row.get(0),
+            )?;
+
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, timestamp, actor, action, target_type, target, reason
+                 FROM audit_log {} ORDER BY id DESC LIMIT {} OFFSET {}",
+                where_sql, limit, offset
+            ))?;
+            let rows = stmt.query_map(params_from_iter(values.iter()), |row| {
+                Ok(AuditRow {
+                    id: row.get(0)?,
+                    timestamp: row.get(1)?,
+                    actor: row.get(2)?,
+                    action: row.get(3)?,
+                    target_type: row.get(4)?,
+                    target: row.get(5)?,
+                    reason: row.get(6)?,
+                })
+            })?;
+            Ok((rows.collect::<Result<Vec<_>>>()?, total as u64))
+        })
+        .await
+    }
+}
+
+const SERVICE_SELECT: &str =
+    "SELECT id, name, domains, upstream_address, enabled, protection_level_override,
+            always_challenge, rate_limit_multiplier, max_connections, connect_timeout_ms,
+            response_timeout_ms, exempt_paths, created_at, updated_at, lb_strategy,
+            max_requests_per_ip_10s, upstream_tls_verify, upstream_sni_host,
+            add_request_headers, remove_request_headers, add_response_headers,
+            remove_response_headers, allowed_countries, allowed_asns,
+            clearance_cookie_domain, clearance_ttl_secs, cors_allowed_origins,
+            maintenance_mode, maintenance_html_path, body_inspection,
+            blocked_countries, challenged_countries, country_exceptions, max_body_size_mb,
+            upstream_http2, always_online, always_online_banner, request_encoding, routes,
+            login_username_field, waiting_room, health_check
+     FROM services";
+
+fn service_from_row(row: &rusqlite::Row<'_>) -> Result<ServiceRow> {
+    Ok(ServiceRow {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        domains: row.get(2)?,
+        upstream_address: row.get(3)?,
+        enabled: row.get::<_, i32>(4)? != 0,
+        protection_level_override: row.get(5)?,
+        always_challenge: row.get::<_, i32>(6)? != 0,
+        rate_limit_multiplier: row.get(7)?,
+        max_requests_per_ip_10s: row.get(15)?,
+        max_connections: row.get(8)?,
+        connect_timeout_ms: row.get(9)?,
+        response_timeout_ms: row.get(10)?,
+        exempt_paths: row.get(11)?,
+        lb_strategy: row.get(14)?,
+        upstream_tls_verify: row.get::<_, i32>(16)? != 0,
+        upstream_sni_host: row.get(17)?,
+        upstream_http2: row.get::<_, i32>(34)? != 0,
+        add_request_headers: row.get(18)?,
+        remove_request_headers: row.get(19)?,
+        add_response_headers: row.get(20)?,
+        remove_response_headers: row.get(21)?,
+        allowed_countries: row.get(22)?,
+        allowed_asns: row.get(23)?,
+        clearance_cookie_domain: row.get(24)?,
+        clearance_ttl_secs: row.get(25)?,
+        cors_allowed_origins: row.get(26)?,
+        maintenance_mode: row.get::<_, i32>(27)? != 0,
+        maintenance_html_path: row.get(28)?,
+        body_inspection: row.get::<_, i32>(29)? != 0,
+        blocked_countries: row.get(30)?,
+        challenged_countries: row.get(31)?,
+        country_exceptions: row.get(32)?,
+        max_body_size_mb: row.get(33)?,
+        always_online: row.get::<_, i32>(35)? != 0,
+        always_online_banner: row.get::<_, i32>(36)? != 0,
+        request_encoding: row.get(37)?,
+        routes: row.get(38)?,
+        login_username_field: row.get(39)?,
+        waiting_room: row.get(40)?,
+        health_check: row.get(41)?,
+        created_at: row.get(12)?,
+        updated_at: row.get(13)?,
+    })
+}
+
+/// An audit entry owned by the writer job that inserts it.
+struct AuditEntry {
+    actor: String,
+    action: String,
+    target_type: String,
+    target: String,
+    reason: Option<String>,
+}
+
+impl AuditEntry {
+    fn new(actor: &str, action: &str, target_type: &str, target: &str, reason: Option<&str>) -> Self {
+        Self {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target_type: target_type.to_string(),
+            target: target.to_string(),
+            reason: reason.map(str::to_string),
+        }
+    }
+
+    fn insert(&self, conn: &Connection) -> Result<()> {
         conn.execute(
-            "INSERT INTO l4_events (client_ip, action, reason, concurrent_connections, connection_rate)
+            "INSERT INTO audit_log (actor, action, target_type, target, reason)
              VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![client_ip, action, reason, concurrent, rate],
+            params![self.actor, self.action, self.target_type, self.target, self.reason],
         )?;
-        Ok(conn.last_insert_rowid())
+        Ok(())
     }
+}
 
-    pub fn get_l4_events(&self, limit: usize) -> Result<Vec<L4EventRow>> {
-        let conn = self.conn.lock().expect("sqlite mutex poisoned");
-        let mut stmt = conn.prepare(
-            "SELECT id, timestamp, client_ip, action, reason, concurrent_connections, connection_rate
-             FROM l4_events ORDER BY id DESC LIMIT ?1",
-        )?;
-        let rows = stmt.query_map(params![limit as i64], |row| {
-            Ok(L4EventRow {
-                id: row.get(0)?,
-                timestamp: row.get(1)?,
-                client_ip: row.get(2)?,
-                action: row.get(3)?,
-                reason: row.get(4)?,
-                concurrent_connections: row.get(5)?,
-                connection_rate: row.get(6)?,
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// A SQLite file in the temp directory, removed together with its
+    /// `-wal` and `-shm` files when dropped, so a failing test leaves
+    /// nothing behind. Declare it before the stores that open it.
+    pub(crate) struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        pub(crate) fn new(name: &str) -> Self {
+            let db = Self(std::env::temp_dir().join(format!("fortress-{}-{}.db", name, std::process::id())));
+            // Left over by an aborted run
+            db.remove();
+            db
+        }
+
+        pub(crate) fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0.display(), suffix));
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    /// Hammer the store with blocklist writes the way a busy admin API
+    /// would, while a probe on the same runtime measures how late a 1 ms
+    /// timer fires. Proxy requests share these worker threads, so the
+    /// probe's p99 stands in for added proxy latency.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocklist_writes_do_not_stall_the_runtime() {
+        let db = TempDb::new("stress");
+        let store = Arc::new(SqliteStore::new(db.path()).unwrap());
+
+        let writers: Vec<_> = (0..8u32)
+            .map(|w| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move {
+                    for i in 0..200u32 {
+                        let ip = format!("10.{}.{}.{}", w, i / 256, i % 256);
+                        store
+                            .add_blocked_ip(&ip, None, "block", "stress", "api", None, &ScheduleFields::default(), &BlockedIpGeo::default())
+                            .await
+                            .unwrap();
+                        store.audit("test", "block", "ip", &ip, None);
+                        store.insert_l4_event(&ip, "drop", Some("stress"), None, None);
+                    }
+                })
             })
-        })?;
-        rows.collect()
+            .collect();
+
+        let mut lags = Vec::new();
+        loop {
+            let started = Instant::now();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            lags.push(started.elapsed());
+            if writers.iter().all(|w| w.is_finished()) {
+                break;
+            }
+        }
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        store.flush().await;
+
+        lags.sort();
+        let p99 = lags[lags.len() * 99 / 100];
+        assert!(p99 < Duration::from_millis(50), "p99 timer lag {:?} under write load", p99);
+        assert_eq!(store.get_blocked_ips().await.unwrap().len(), 1600);
+        assert_eq!(store.get_l4_events_page(&L4EventFilter::default(), 10, 0).await.unwrap().1, 1600);
+    }
+
+    #[tokio::test]
+    async fn test_hourly_rows_are_kept_per_service() {
+        let db = TempDb::new("hourly");
+        // A database from before per-service rows
+        Connection::open(db.path())
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE metrics_hourly (
+                     id INTEGER PRIMARY KEY AUTOINCREMENT, timestamp TEXT NOT NULL,
+                     total_requests INTEGER DEFAULT 0, passed_requests INTEGER DEFAULT 0,
+                     blocked_requests INTEGER DEFAULT 0, challenged_requests INTEGER DEFAULT 0,
+                     unique_ips INTEGER DEFAULT 0, avg_latency_ms REAL DEFAULT 0,
+                     protection_level INTEGER DEFAULT 0, top_countries_json TEXT, top_asns_json TEXT,
+                     UNIQUE(timestamp));
+                 INSERT INTO metrics_hourly (timestamp, total_requests) VALUES ('2026-01-01 10:00:00', 7);",
+            )
+            .unwrap();
+        let store = SqliteStore::new(db.path()).unwrap();
+
+        let row = |service: Option<&str>, total: u64| MetricsRow {
+            timestamp: "2026-01-01 10:00:00".to_string(),
+            total_requests: total,
+            passed_requests: total,
+            blocked_requests: 0,
+            challenged_requests: 0,
+            unique_ips: 0,
+            avg_latency_ms: 1.0,
+            protection_level: 0,
+            top_countries_json: None,
+            top_asns_json: None,
+            service_id: service.map(str::to_string),
+            challenges_issued: total,
+            challenges_solved: 1,
+            bypassed_static: 0,
+            bypassed_preflight: 0,
+            whitelisted: total,
+        };
+        store.insert_metrics_hourly(vec![row(None, 3), row(Some("shop"), 2), row(Some("blog"), 1)]).await.unwrap();
+        store.insert_metrics_hourly(vec![row(None, 1), row(Some("shop"), 5)]).await.unwrap();
+
+        let from = "2026-01-01T00:00:00Z".parse().unwrap();
+        let to = "2026-01-02T00:00:00Z".parse().unwrap();
+        let global = store.get_metrics_history(from, to).await.unwrap();
+        assert_eq!(global.len(), 1);
+        assert_eq!(global[0].total_requests, 11);
+        let shop = store.get_service_metrics_history("shop", from, to).await.unwrap();
+        assert_eq!(shop.len(), 1);
+        assert_eq!(shop[0].total_requests, 7);
+        assert_eq!((shop[0].challenges_issued, shop[0].challenges_solved), (7, 2));
+        assert_eq!(shop[0].whitelisted, 7);
+        assert_eq!(shop[0].service_id.as_deref(), Some("shop"));
+        assert!(store.get_service_metrics_history("api", from, to).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_pages_filter_in_sql() {
+        let db = TempDb::new("blocklist-page");
+        // A database from before address keys
+        Connection::open(db.path())
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE blocked_ips (
+                     id INTEGER PRIMARY KEY AUTOINCREMENT, ip TEXT NOT NULL, cidr TEXT,
+                     reason TEXT NOT NULL, source TEXT NOT NULL DEFAULT 'auto',
+                     created_at TEXT DEFAULT (datetime('now')), expires_at TEXT, UNIQUE(ip));
+                 INSERT INTO blocked_ips (ip, reason, expires_at) VALUES ('10.1.2.3', 'old_scan', '2000-01-01 00:00:00');",
+            )
+            .unwrap();
+        let store = SqliteStore::new(db.path()).unwrap();
+
+        let entry = |ip: &str, reason: &str| NewBlockedIp {
+            ip: ip.to_string(),
+            cidr: ip.contains('/').then(|| ip.to_string()),
+            reason: reason.to_string(),
+            expires_at: None,
+            geo: BlockedIpGeo::default(),
+        };
+        let located = NewBlockedIp {
+            geo: BlockedIpGeo { country: Some("DE".to_string()), asn: Some(64500) },
+            ..entry("2001:db8::1", "rate_limit")
+        };
+        let entries: Vec<_> = (0..30)
+            .map(|i| entry(&format!("10.1.{}.1", 100 + i), "rate_limit"))
+            .chain([entry("10.2.0.0/16", "Manual_block"), located])
+            .collect();
+        store.add_blocked_ips(&entries, "auto_ban", false).await.unwrap();
+
+        let page = |filter: BlocklistFilter, limit, offset| {
+            let store = &store;
+            async move { store.get_blocked_ips_page(&filter, limit, offset).await.unwrap() }
+        };
+        let cidr = |value: &str| BlocklistFilter { ip: Some(IpFilter::parse(value)), ..Default::default() };
+
+        let (rows, total) = page(BlocklistFilter::default(), 10, 0).await;
+        assert_eq!((rows.len(), total), (10, 33));
+        assert_eq!(rows[0].ip, "2001:db8::1");
+        assert_eq!(page(BlocklistFilter::default(), 10, 30).await.0.len(), 3);
+
+        assert_eq!(page(cidr("10.1.0.0/16"), 100, 0).await.1, 31);
+        assert_eq!(page(cidr("10.1.2.3"), 100, 0).await.1, 1);
+        assert_eq!(page(cidr("10.0.0.0/8"), 100, 0).await.1, 32);
+        assert_eq!(page(cidr("2001:db8::/32"), 100, 0).await.1, 1);
+        assert_eq!(page(cidr("10.1.10"), 100, 0).await.1, 10);
+
+        let reason = BlocklistFilter { reason: Some("manual".to_string()), ..Default::default() };
+        assert_eq!(page(reason, 100, 0).await.0[0].ip, "10.2.0.0/16");
+        let literal = BlocklistFilter { reason: Some("rate%".to_string()), ..Default::default() };
+        assert_eq!(page(literal, 100, 0).await.1, 0);
+        let expired = BlocklistFilter { status: Some(ExpiryStatus::Expired), ..Default::default() };
+        assert_eq!(page(expired, 100, 0).await.0[0].ip, "10.1.2.3");
+        let active = BlocklistFilter {
+            status: Some(ExpiryStatus::Active),
+            source: Some("auto_ban".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(page(active, 100, 0).await.1, 32);
+
+        let country = BlocklistFilter { country: Some("DE".to_string()), ..Default::default() };
+        let (rows, total) = page(country, 100, 0).await;
+        assert_eq!((total, rows[0].geo.asn), (1, Some(64500)));
+        assert_eq!(page(BlocklistFilter { asn: Some(64500), ..Default::default() }, 100, 0).await.1, 1);
+        assert_eq!(store.get_blocked_ips_without_geo().await.unwrap().len(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_state_export_round_trips_through_import() {
+        let db = TempDb::new("state");
+        let store = SqliteStore::new(db.path()).unwrap();
+        let geo = BlockedIpGeo::default();
+        let no_schedule = ScheduleFields::default();
+        store.add_blocked_ip("10.0.0.1", None, "block", "manual", "admin_api", None, &no_schedule, &geo).await.unwrap();
+        store.add_blocked_ip("10.0.0.2", None, "tarpit", "flood", "auto", None, &no_schedule, &geo).await.unwrap();
+        store.add_blocked_asn(64500, Some("Example"), "block", None, "admin_api", None, &no_schedule).await.unwrap();
+        store.set_managed_rule_setting(3, false, "{}").await.unwrap();
+        store.set_config("escalation_level", "2").await.unwrap();
+
+        let exported = store.export_state(false).await.unwrap();
+        assert_eq!(exported["blocked_ips"].len(), 2);
+        assert_eq!(exported["config"][0]["value"], "2");
+        let persistent = store.export_state(true).await.unwrap();
+        assert_eq!(persistent["blocked_ips"].len(), 1);
+
+        let empty: StateSections = exported.keys().map(|s| (s.clone(), Vec::new())).collect();
+        store.import_state(empty, ImportMode::Replace).await.unwrap();
+        assert!(store.export_state(false).await.unwrap().values().all(Vec::is_empty));
+        let written = store.import_state(exported.clone(), ImportMode::Replace).await.unwrap();
+        assert_eq!(written["blocked_ips"], 2);
+        assert_eq!(store.export_state(false).await.unwrap(), exported);
+
+        // Merging an entry whose id is taken by another IP gets a fresh id
+        let mut row = exported["blocked_ips"][0].clone();
+        row.insert("ip".to_string(), "10.0.0.9".into());
+        let merge = StateSections::from([("blocked_ips".to_string(), vec![row.clone()])]);
+        store.import_state(merge, ImportMode::Merge).await.unwrap();
+        assert_eq!(store.export_state(false).await.unwrap()["blocked_ips"].len(), 3);
+
+        row.insert("no_such_column".to_string(), 1.into());
+        let bad = StateSections::from([("blocked_ips".to_string(), vec![row])]);
+        let err = store.import_state(bad, ImportMode::Replace).await.unwrap_err();
+        assert!(matches!(err, rusqlite::Error::InvalidColumnName(_)));
+        assert_eq!(store.export_state(false).await.unwrap()["blocked_ips"].len(), 3);
     }
 }
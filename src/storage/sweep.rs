@@ -0,0 +1,125 @@
+//! Incremental expiry for the in-memory `DashMap`s.
+//!
+//! `DashMap::retain` write-locks every shard in turn, so with millions of
+//! tracked IPs one pass holds up the request path for long enough to show
+//! as latency jitter. A [`Sweep`] covers a map a few shards per call and
+//! picks up at the next shard on the following call, so the periodic
+//! cleanup spreads the same work over several ticks.
+
+use std::hash::{BuildHasher, Hash};
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+
+/// What one cleanup call did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SweepStats {
+    /// Entries looked at.
+    pub visited: usize,
+    /// Entries removed.
+    pub removed: usize,
+    /// Size of the largest map swept, after the sweep. The cleanup loop
+    /// sizes its interval from this.
+    pub largest: usize,
+}
+
+impl AddAssign for SweepStats {
+    fn add_assign(&mut self, other: Self) {
+        self.visited += other.visited;
+        self.removed += other.removed;
+        self.largest = self.largest.max(other.largest);
+    }
+}
+
+/// Where the next sweep of a map starts.
+#[derive(Debug, Default)]
+pub struct Sweep {
+    next_shard: AtomicUsize,
+}
+
+impl Sweep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove the entries of `map` for which `keep` returns false, like
+    /// `DashMap::retain`, but only for whole shards starting where the last
+    /// call stopped, until `budget` entries were visited or every shard was
+    /// swept once.
+    ///
+    /// Each shard is write-locked only while it is swept, so `keep` always
+    /// sees the current value; entries inserted into a shard already swept
+    /// are looked at on the next pass. As with `retain`, `keep` must not
+    /// touch `map` itself.
+    pub fn retain<K, V, S>(
+        &self,
+        map: &DashMap<K, V, S>,
+        budget: usize,
+        mut keep: impl FnMut(&K, &mut V) -> bool,
+    ) -> SweepStats
+    where
+        K: Eq + Hash,
+        S: BuildHasher + Clone,
+    {
+        let shards = map.shards();
+        let start = self.next_shard.load(Ordering::Relaxed) % shards.len();
+        let mut stats = SweepStats::default();
+        let mut swept = 0;
+        while swept < shards.len() && stats.visited < budget {
+            let mut shard = shards[(start + swept) % shards.len()].write();
+            // SAFETY: as in `DashMap::retain`: the write guard is held for
+            // the whole iteration and only the bucket just yielded is erased.
+            unsafe {
+                for bucket in shard.iter() {
+                    stats.visited += 1;
+                    let (key, value) = bucket.as_mut();
+                    if !keep(key, value.get_mut()) {
+                        shard.erase(bucket);
+                        stats.removed += 1;
+                    }
+                }
+            }
+            swept += 1;
+        }
+        self.next_shard.store((start + swept) % shards.len(), Ordering::Relaxed);
+        stats.largest = map.len();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweeps_resume_and_cover_the_map() {
+        let map: DashMap<u32, u32> = DashMap::with_shard_amount(8);
+        for i in 0..8000 {
+            map.insert(i, i);
+        }
+        let sweep = Sweep::new();
+        let even = |_: &u32, v: &mut u32| v.is_multiple_of(2);
+
+        // A budget of one entry sweeps one shard per call
+        let first = sweep.retain(&map, 1, even);
+        assert!(first.visited > 0 && first.visited < 8000);
+        assert_eq!(first.removed, 8000 - map.len());
+        let mut pass = first;
+        for _ in 1..8 {
+            pass += sweep.retain(&map, 1, even);
+        }
+        assert_eq!(pass.visited, 8000);
+        assert_eq!(pass.removed, 4000);
+        assert_eq!(map.len(), 4000);
+
+        // Entries added or changed after their shard was swept are handled
+        // on the next pass
+        map.insert(9001, 9001);
+        map.insert(2, 3);
+        let next = sweep.retain(&map, usize::MAX, even);
+        assert_eq!(next.visited, 4001);
+        assert_eq!(next.removed, 2);
+        assert!(map.iter().all(|e| e.value().is_multiple_of(2)));
+    }
+}